@@ -0,0 +1,19 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use mochi_lua::bench;
+
+// Only the time spent running each workload is measured:
+//   cargo bench --bench workloads -- --save-baseline before
+//   cargo bench --bench workloads -- --baseline before
+fn workloads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("workloads");
+    group.sample_size(10);
+    for workload in bench::WORKLOADS {
+        group.bench_function(workload.name, |b| {
+            b.iter_custom(|iters| (0..iters).map(|_| bench::run(workload).unwrap()).sum())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, workloads);
+criterion_main!(benches);
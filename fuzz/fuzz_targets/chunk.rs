@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mochi_lua::{binary_chunk, runtime::Runtime, sandbox::Sandbox};
+
+// Bytes either fail to load as a binary chunk or load to code that runs
+// without panicking, however it ends
+fuzz_target!(|chunk: &[u8]| {
+    let mut runtime = Runtime::new();
+    let sandbox = Sandbox {
+        fuel: Some(100_000),
+        ..Default::default()
+    };
+    runtime
+        .heap()
+        .with(|gc, vm| sandbox.apply(gc, &mut vm.borrow_mut(gc)));
+    let _ = runtime.execute(|gc, vm| {
+        let proto = binary_chunk::load(gc, &mut &chunk[..])?;
+        Ok(gc.allocate(vm.borrow().load_proto(gc, proto)).into())
+    });
+});
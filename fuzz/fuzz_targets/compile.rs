@@ -0,0 +1,29 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mochi_lua::{binary_chunk, gc::GcHeap, runtime, CompileOptions};
+
+// Source text either fails to compile or compiles to code that passes the
+// verifier, optimized or not, and survives a round trip through a binary
+// chunk
+fuzz_target!(|source: &[u8]| {
+    if binary_chunk::is_binary_chunk(source) {
+        return;
+    }
+    let mut heap = GcHeap::new();
+    heap.with(|gc, _| {
+        for optimize in [false, true] {
+            let options = CompileOptions {
+                optimize,
+                ..Default::default()
+            };
+            let Ok(proto) = mochi_lua::load_with_options(gc, source, "=fuzz", &options) else {
+                return;
+            };
+            runtime::verify(&proto).unwrap();
+            let mut chunk = Vec::new();
+            binary_chunk::dump(&mut chunk, &proto).unwrap();
+            mochi_lua::load(gc, &chunk, "=fuzz").unwrap();
+        }
+    });
+});
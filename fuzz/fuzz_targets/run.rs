@@ -0,0 +1,27 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mochi_lua::{runtime::Runtime, sandbox::Sandbox, CompileOptions};
+
+// Source text either fails to compile or runs without panicking in a
+// sandbox, optimized or not
+fuzz_target!(|source: &[u8]| {
+    for optimize in [false, true] {
+        let mut runtime = Runtime::new();
+        let sandbox = Sandbox {
+            fuel: Some(100_000),
+            ..Default::default()
+        };
+        runtime
+            .heap()
+            .with(|gc, vm| sandbox.apply(gc, &mut vm.borrow_mut(gc)));
+        let options = CompileOptions {
+            optimize,
+            ..Default::default()
+        };
+        let _ = runtime.execute(|gc, vm| {
+            let proto = mochi_lua::load_with_options(gc, source, "=fuzz", &options)?;
+            Ok(gc.allocate(vm.borrow().load_proto(gc, proto)).into())
+        });
+    }
+});
@@ -0,0 +1 @@
+Z.a, b, c = f"x"
@@ -0,0 +1 @@
+goto continue
@@ -0,0 +1 @@
+return 0xcp3333333333333
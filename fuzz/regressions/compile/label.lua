@@ -0,0 +1 @@
+::top::
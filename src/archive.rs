@@ -0,0 +1,127 @@
+use crate::prelude::*;
+use alloc::collections::BTreeMap;
+use bstr::ByteSlice;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("invalid tar header at offset {0}")]
+    InvalidHeader(usize),
+
+    #[error("truncated tar archive")]
+    Truncated,
+}
+
+// In-memory files that require can load modules from, for hosts that embed
+// their scripts in the binary. Modules are looked up as "a/b.lua" and
+// "a/b/init.lua" for the module name "a.b".
+#[derive(Debug, Clone, Default)]
+pub struct Archive {
+    files: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Archive {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Reads an uncompressed tar archive. Directories, links and other
+    // special entries are skipped.
+    pub fn from_tar(bytes: &[u8]) -> Result<Self, ArchiveError> {
+        const BLOCK_SIZE: usize = 512;
+
+        let mut archive = Self::new();
+        let mut long_name = None;
+        let mut offset = 0;
+        while offset + BLOCK_SIZE <= bytes.len() {
+            let header = &bytes[offset..offset + BLOCK_SIZE];
+            if header.iter().all(|&b| b == 0) {
+                return Ok(archive);
+            }
+
+            let size = core::str::from_utf8(&header[124..136])
+                .ok()
+                .map(|size| size.trim_matches(|c: char| c == '\0' || c == ' '))
+                .and_then(|size| usize::from_str_radix(size, 8).ok())
+                .ok_or(ArchiveError::InvalidHeader(offset))?;
+            let data_start = offset + BLOCK_SIZE;
+            let data = bytes
+                .get(data_start..data_start + size)
+                .ok_or(ArchiveError::Truncated)?;
+            offset = data_start + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+
+            match header[156] {
+                // GNU long name of the next entry
+                b'L' => long_name = Some(until_nul(data).to_vec()),
+                b'0' | b'\0' => {
+                    let name = long_name.take().unwrap_or_else(|| {
+                        let name = until_nul(&header[..100]);
+                        let prefix = until_nul(&header[345..500]);
+                        if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                            bstr::join("/", [prefix, name])
+                        } else {
+                            name.to_vec()
+                        }
+                    });
+                    let name = name.strip_prefix(b"./").unwrap_or(&name);
+                    archive.insert(name, data);
+                }
+                _ => long_name = None,
+            }
+        }
+        Err(ArchiveError::Truncated)
+    }
+
+    pub fn insert<P, B>(&mut self, path: P, contents: B)
+    where
+        P: AsRef<[u8]>,
+        B: Into<Vec<u8>>,
+    {
+        self.files.insert(path.as_ref().to_vec(), contents.into());
+    }
+
+    pub fn get<P: AsRef<[u8]>>(&self, path: P) -> Option<&[u8]> {
+        self.files.get(path.as_ref()).map(Vec::as_slice)
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &[u8]> {
+        self.files.keys().map(Vec::as_slice)
+    }
+
+    // Returns the path and contents of the file the module `name`
+    // resolves to
+    #[cfg(feature = "std")]
+    pub(crate) fn find_module(&self, name: &[u8]) -> Option<(&[u8], &[u8])> {
+        Self::module_paths(name).iter().find_map(|path| {
+            self.files
+                .get_key_value(path)
+                .map(|(path, contents)| (path.as_slice(), contents.as_slice()))
+        })
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn module_paths(name: &[u8]) -> [Vec<u8>; 2] {
+        let name = name.replace(".", "/");
+        [
+            bstr::concat([&name[..], b".lua"]),
+            bstr::concat([&name[..], b"/init.lua"]),
+        ]
+    }
+}
+
+impl<P, B> FromIterator<(P, B)> for Archive
+where
+    P: AsRef<[u8]>,
+    B: Into<Vec<u8>>,
+{
+    fn from_iter<I: IntoIterator<Item = (P, B)>>(iter: I) -> Self {
+        let mut archive = Self::new();
+        for (path, contents) in iter {
+            archive.insert(path, contents);
+        }
+        archive
+    }
+}
+
+fn until_nul(bytes: &[u8]) -> &[u8] {
+    bytes.split_str("\0").next().unwrap_or(bytes)
+}
@@ -0,0 +1,76 @@
+// Workloads to measure the interpreter with, run by the benches and usable
+// from other harnesses. Each one checks its own results, so a workload that
+// runs fast because it computes the wrong thing fails instead.
+use crate::{
+    runtime::{Action, Continuation, Runtime, RuntimeError},
+    types::{Integer, NativeClosure, Value},
+};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Workload {
+    pub name: &'static str,
+    pub source: &'static str,
+    // Passed to the script as `...`, which scales the work it does
+    pub size: Integer,
+}
+
+pub const WORKLOADS: &[Workload] = &[
+    Workload {
+        name: "fib",
+        source: include_str!("bench/fib.lua"),
+        size: 27,
+    },
+    Workload {
+        name: "nbody",
+        source: include_str!("bench/nbody.lua"),
+        size: 20_000,
+    },
+    Workload {
+        name: "binary-trees",
+        source: include_str!("bench/binary_trees.lua"),
+        size: 12,
+    },
+    Workload {
+        name: "tables",
+        source: include_str!("bench/tables.lua"),
+        size: 50_000,
+    },
+    Workload {
+        name: "strings",
+        source: include_str!("bench/strings.lua"),
+        size: 20_000,
+    },
+];
+
+pub fn workload(name: &str) -> Option<&'static Workload> {
+    WORKLOADS.iter().find(|workload| workload.name == name)
+}
+
+// Runs `workload` on a new runtime with the standard library loaded and
+// returns how long it ran for, leaving out setting up and compiling it
+pub fn run(workload: &Workload) -> Result<Duration, RuntimeError> {
+    let mut runtime = Runtime::new();
+    runtime
+        .heap()
+        .with(|gc, vm| vm.borrow_mut(gc).load_stdlib(gc));
+    let chunk_name = format!("={}", workload.name);
+    let size = workload.size;
+    runtime.start(|gc, vm| {
+        let chunk = vm.borrow().load(gc, workload.source, chunk_name)?;
+        let chunk = gc.allocate(chunk).into();
+        let call = NativeClosure::with_upvalue(chunk, move |_, _, &chunk, _| {
+            Ok(Action::Call {
+                callee: chunk,
+                args: vec![Value::Integer(size)],
+                continuation: Continuation::new(|_, _, _: Vec<Value>| {
+                    Ok(Action::Return(Vec::new()))
+                }),
+            })
+        });
+        Ok(gc.allocate(call).into())
+    })?;
+    let start = Instant::now();
+    runtime.finish()?;
+    Ok(start.elapsed())
+}
@@ -0,0 +1,22 @@
+-- Allocation of many short-lived tables, which keeps the collector busy
+local depth = ...
+
+local function tree(depth)
+  if depth == 0 then return {} end
+  depth = depth - 1
+  return { tree(depth), tree(depth) }
+end
+
+local function check(tree)
+  if tree[1] then return 1 + check(tree[1]) + check(tree[2]) end
+  return 1
+end
+
+local long_lived = tree(depth)
+for d = 4, depth, 2 do
+  local iterations = 1 << (depth - d + 4)
+  local nodes = 0
+  for _ = 1, iterations do nodes = nodes + check(tree(d)) end
+  assert(nodes == iterations * ((1 << (d + 1)) - 1))
+end
+assert(check(long_lived) == (1 << (depth + 1)) - 1)
@@ -0,0 +1,11 @@
+-- Calls and returns, with little else going on
+local n = ...
+
+local function fib(n)
+  if n < 2 then return n end
+  return fib(n - 1) + fib(n - 2)
+end
+
+local a, b = 0, 1
+for _ = 1, n do a, b = b, a + b end
+assert(fib(n) == a)
@@ -0,0 +1,30 @@
+-- Building, formatting and scanning strings
+local n = ...
+
+local parts = {}
+for i = 1, n do
+  parts[#parts + 1] = string.format("%d:%s;", i, string.rep("x", i % 16))
+end
+local s = table.concat(parts)
+
+local count, total, pos = 0, 0, 1
+while pos <= #s do
+  local colon = s:find(":", pos, true)
+  local semicolon = s:find(";", colon, true)
+  local i = tonumber(s:sub(pos, colon - 1))
+  assert(semicolon - colon - 1 == i % 16)
+  count, total, pos = count + 1, total + semicolon - colon - 1, semicolon + 1
+end
+assert(count == n)
+
+local upper = s:upper()
+local xs = 0
+for i = 1, #upper do
+  if upper:byte(i) == 88 then xs = xs + 1 end
+end
+assert(#upper == #s and xs == total)
+assert(s:find(n .. ":", 1, true))
+
+local joined = ""
+for i = 1, n // 10 do joined = joined .. i .. "," end
+assert(#joined > n // 10)
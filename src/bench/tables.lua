@@ -0,0 +1,43 @@
+-- Array and hash parts growing, shrinking and being looked up
+local n = ...
+
+local array, hash = {}, {}
+for i = 1, n do
+  array[#array + 1] = i
+  hash["k" .. i] = i
+  hash[-i] = i
+end
+
+local sum = 0
+for i = 1, n do
+  sum = sum + array[i] + hash["k" .. i] + hash[-i]
+end
+assert(sum == 3 * n * (n + 1) // 2)
+
+local count = 0
+for _ in pairs(hash) do count = count + 1 end
+assert(count == 2 * n)
+
+for _ = 1, n // 2 do table.remove(array) end
+for i = 1, n, 2 do hash["k" .. i] = nil end
+assert(#array == n - n // 2)
+
+-- Heapsort, for the swaps
+local heap = {}
+for i = 1, n do heap[i] = (i * 7919) % n end
+local function sift(start, stop)
+  local root = start
+  while 2 * root <= stop do
+    local child = 2 * root
+    if child < stop and heap[child] < heap[child + 1] then child = child + 1 end
+    if heap[root] >= heap[child] then return end
+    heap[root], heap[child] = heap[child], heap[root]
+    root = child
+  end
+end
+for i = n // 2, 1, -1 do sift(i, n) end
+for stop = n, 2, -1 do
+  heap[1], heap[stop] = heap[stop], heap[1]
+  sift(1, stop - 1)
+end
+for i = 2, n do assert(heap[i - 1] <= heap[i]) end
@@ -2,7 +2,7 @@ mod deserialize;
 mod serialize;
 
 pub use deserialize::{load, DeserializeError};
-pub use serialize::dump;
+pub use serialize::{dump, dump_with_options, ByteOrder, DumpOptions};
 
 use crate::{
     types::{Integer, Number},
@@ -11,6 +11,12 @@ use crate::{
 
 const LUA_SIGNATURE: [u8; 4] = *b"\x1bLua";
 
+// Only the first byte is checked, like the reference implementation does, so
+// that a chunk can be told apart from its first piece
+pub fn is_binary_chunk(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&LUA_SIGNATURE[0])
+}
+
 const LUA_TNIL: u8 = 0;
 const LUA_TBOOLEAN: u8 = 1;
 const LUA_TNUMBER: u8 = 3;
@@ -1,34 +1,113 @@
 use crate::{
     gc::GcContext,
-    runtime::Instruction,
+    io::{Read, ReadBytesExt},
+    prelude::*,
+    runtime::{verify, Instruction, VerifyError},
     types::{
-        AbsLineInfo, Integer, LineRange, LocalVariable, LuaClosureProto, LuaString, Number,
-        RegisterIndex, UpvalueDescription, UpvalueIndex, Value,
+        AbsLineInfo, InlineCaches, Integer, LineRange, LocalVariable, LuaClosureProto, LuaString,
+        Number, RegisterIndex, UpvalueDescription, UpvalueIndex, Value,
     },
 };
 use bstr::B;
-use byteorder::{NativeEndian, ReadBytesExt};
-use std::{io::Read, mem::size_of};
+use byteorder::{BigEndian, LittleEndian, NativeEndian};
+use core::mem::size_of;
+use lua53::Lua53Format;
+
+mod lua53;
 
 #[derive(thiserror::Error, Debug)]
 pub enum DeserializeError {
-    #[error("bad magic")]
+    #[error("bad binary format (not a binary chunk)")]
     BadMagic,
 
-    #[error("unsupported bytecode Lua version")]
+    #[error("bad binary format (version mismatch)")]
     UnsupportedVersion,
 
-    #[error("unsupported bytecode format")]
+    #[error("bad binary format (format mismatch)")]
     UnsupportedFormat,
 
-    #[error("integer overflow")]
+    #[error("bad binary format ({0} size mismatch)")]
+    SizeMismatch(&'static str),
+
+    #[error("bad binary format (integer format mismatch)")]
+    IntegerFormatMismatch,
+
+    #[error("bad binary format (float format mismatch)")]
+    FloatFormatMismatch,
+
+    #[error("bad binary format (truncated chunk)")]
+    Truncated,
+
+    #[error("bad binary format (corrupted chunk)")]
+    Corrupted,
+
+    #[error("bad binary format (integer overflow)")]
     IntegerOverflow,
 
-    #[error("bad format for constant string")]
+    #[error("bad binary format (bad format for constant string)")]
     BadStringConstant,
 
+    #[error("bad binary format (function needs too many registers)")]
+    TooManyRegisters,
+
+    #[error("bad binary format ({0})")]
+    InvalidCode(#[from] VerifyError),
+
     #[error(transparent)]
-    Io(#[from] std::io::Error),
+    Io(crate::io::Error),
+}
+
+impl From<crate::io::Error> for DeserializeError {
+    fn from(err: crate::io::Error) -> Self {
+        if err.kind() == crate::io::ErrorKind::UnexpectedEof {
+            Self::Truncated
+        } else {
+            Self::Io(err)
+        }
+    }
+}
+
+// Counts are read from the chunk, so vectors don't reserve more than this up
+// front, and a corrupted count runs out of input instead of memory
+const MAX_PREALLOCATION: usize = 1 << 12;
+
+fn with_capacity<T>(n: u32) -> Vec<T> {
+    Vec::with_capacity((n as usize).min(MAX_PREALLOCATION))
+}
+
+#[derive(Clone, Copy)]
+struct Format {
+    big_endian: bool,
+    integer_size: u8,
+    number_size: u8,
+}
+
+impl Format {
+    fn read_u32<R: Read>(&self, reader: &mut R) -> crate::io::Result<u32> {
+        if self.big_endian {
+            reader.read_u32::<BigEndian>()
+        } else {
+            reader.read_u32::<LittleEndian>()
+        }
+    }
+
+    fn read_integer<R: Read>(&self, reader: &mut R) -> crate::io::Result<Integer> {
+        match (self.integer_size, self.big_endian) {
+            (4, true) => reader.read_i32::<BigEndian>().map(Integer::from),
+            (4, false) => reader.read_i32::<LittleEndian>().map(Integer::from),
+            (_, true) => reader.read_i64::<BigEndian>(),
+            (_, false) => reader.read_i64::<LittleEndian>(),
+        }
+    }
+
+    fn read_number<R: Read>(&self, reader: &mut R) -> crate::io::Result<Number> {
+        match (self.number_size, self.big_endian) {
+            (4, true) => reader.read_f32::<BigEndian>().map(Number::from),
+            (4, false) => reader.read_f32::<LittleEndian>().map(Number::from),
+            (_, true) => reader.read_f64::<BigEndian>(),
+            (_, false) => reader.read_f64::<LittleEndian>(),
+        }
+    }
 }
 
 pub fn load<'gc, R: Read>(
@@ -39,7 +118,9 @@ pub fn load<'gc, R: Read>(
         return Err(DeserializeError::BadMagic);
     }
 
-    if reader.read_u8()? != super::LUAC_VERSION {
+    // Chunks from Lua 5.3 are translated as they're loaded
+    let version = reader.read_u8()?;
+    if version != super::LUAC_VERSION && version != lua53::LUAC_VERSION {
         return Err(DeserializeError::UnsupportedVersion);
     }
     if reader.read_u8()? != super::LUAC_FORMAT {
@@ -49,26 +130,67 @@ pub fn load<'gc, R: Read>(
     let mut data = [0u8; 6];
     reader.read_exact(&mut data)?;
     if data != super::LUAC_DATA {
-        return Err(DeserializeError::UnsupportedFormat);
+        return Err(DeserializeError::Corrupted);
     }
 
-    if reader.read_u8()? as usize != size_of::<Instruction>()
-        || reader.read_u8()? as usize != size_of::<Integer>()
-        || reader.read_u8()? as usize != size_of::<Number>()
-    {
-        return Err(DeserializeError::UnsupportedFormat);
+    let mut size_t_size = 0;
+    if version == lua53::LUAC_VERSION {
+        if reader.read_u8()? != 4 {
+            return Err(DeserializeError::SizeMismatch("int"));
+        }
+        size_t_size = reader.read_u8()?;
+        if size_t_size != 4 && size_t_size != 8 {
+            return Err(DeserializeError::SizeMismatch("size_t"));
+        }
+    }
+    if reader.read_u8()? as usize != size_of::<Instruction>() {
+        return Err(DeserializeError::SizeMismatch("Instruction"));
+    }
+    let integer_size = reader.read_u8()?;
+    if integer_size != 4 && integer_size != 8 {
+        return Err(DeserializeError::SizeMismatch("lua_Integer"));
+    }
+    let number_size = reader.read_u8()?;
+    if number_size != 4 && number_size != 8 {
+        return Err(DeserializeError::SizeMismatch("lua_Number"));
     }
 
-    if reader.read_i64::<NativeEndian>()? != super::LUAC_INT
-        || reader.read_f64::<NativeEndian>()? != super::LUAC_NUM
-    {
-        return Err(DeserializeError::UnsupportedFormat);
+    // The byte order is told by how LUAC_INT was written, and narrower
+    // integers and floats from other builds are widened as they're read
+    let mut int = vec![0; integer_size as usize];
+    reader.read_exact(&mut int)?;
+    let big_endian = match int.iter().position(|&b| b == 0x78) {
+        Some(0) => false,
+        Some(i) if i + 1 == int.len() => true,
+        _ => return Err(DeserializeError::IntegerFormatMismatch),
+    };
+    let format = Format {
+        big_endian,
+        integer_size,
+        number_size,
+    };
+    if format.read_integer(&mut int.as_slice())? != super::LUAC_INT {
+        return Err(DeserializeError::IntegerFormatMismatch);
+    }
+    if format.read_number(reader)? != super::LUAC_NUM {
+        return Err(DeserializeError::FloatFormatMismatch);
     }
 
     let num_upvalues = reader.read_u8()?;
     let default_source = gc.allocate_string(B("=?"));
-    let proto = load_function(gc, reader, default_source)?;
-    assert_eq!(num_upvalues as usize, proto.upvalues.len());
+    let proto = if version == lua53::LUAC_VERSION {
+        let format = Lua53Format {
+            format,
+            size_t_size,
+        };
+        format.load_function(gc, reader, default_source)?
+    } else {
+        load_function(gc, reader, format, default_source)?
+    };
+    if num_upvalues as usize != proto.upvalues.len() {
+        return Err(DeserializeError::Corrupted);
+    }
+    verify(&proto)?;
 
     Ok(proto)
 }
@@ -76,6 +198,7 @@ pub fn load<'gc, R: Read>(
 fn load_function<'gc, R: Read>(
     gc: &'gc GcContext,
     reader: &mut R,
+    format: Format,
     parent_source: LuaString<'gc>,
 ) -> Result<LuaClosureProto<'gc>, DeserializeError> {
     let source = load_nullable_str(gc, reader)?.unwrap_or(parent_source);
@@ -85,18 +208,17 @@ fn load_function<'gc, R: Read>(
     reader.read_u8()?; // is_vararg
     let max_stack_size = reader.read_u8()?;
 
-    let code = load_code(reader)?;
-    let constants = load_constants(gc, reader)?;
+    let code = load_code(reader, format)?;
+    let constants = load_constants(gc, reader, format)?;
     let upvalues = load_upvalues(reader)?;
-    let protos = load_protos(gc, reader, source)?;
+    let protos = load_protos(gc, reader, format, source)?;
 
     let n = load_int(reader)?;
-    let mut line_info = vec![0u8; n as usize];
-    reader.read_exact(&mut line_info)?;
+    let line_info = load_bytes(reader, n as usize)?;
 
     // Absolute LineInfo
     let n = load_int(reader)?;
-    let mut abs_line_info = Vec::with_capacity(n as _);
+    let mut abs_line_info = with_capacity(n);
     for _ in 0..n {
         let pc = load_int(reader)?; // pc
         let line = load_int(reader)?; // line
@@ -105,9 +227,9 @@ fn load_function<'gc, R: Read>(
 
     // Local varialbes
     let n = load_int(reader)?;
-    let mut local_variables = Vec::with_capacity(n as _);
+    let mut local_variables = with_capacity(n);
     for _ in 0..n {
-        let name = load_str(gc, reader)?; // varname
+        let name = load_debug_name(gc, reader)?; // varname
         let start = load_int(reader)?; // startpc
         let end = load_int(reader)?; // endpc
         local_variables.push(LocalVariable {
@@ -118,8 +240,9 @@ fn load_function<'gc, R: Read>(
 
     // Upvalue
     let n = load_int(reader)?;
+    let mut upvalue_names = with_capacity(n);
     for _ in 0..n {
-        load_nullable_str(gc, reader)?; // name
+        upvalue_names.push(load_debug_name(gc, reader)?); // name
     }
 
     Ok(LuaClosureProto {
@@ -130,6 +253,9 @@ fn load_function<'gc, R: Read>(
             LineRange::File
         },
         constants: constants.into(),
+        inline_caches: InlineCaches::new(code.len()),
+        #[cfg(feature = "jit")]
+        jit: Default::default(),
         code: code.into(),
         protos: protos.into_iter().map(|proto| gc.allocate(proto)).collect(),
         upvalues: upvalues.into(),
@@ -149,18 +275,24 @@ fn load_function<'gc, R: Read>(
         } else {
             Some(local_variables.into_boxed_slice())
         },
+        upvalue_names: if upvalue_names.is_empty() {
+            None
+        } else {
+            Some(upvalue_names.into_boxed_slice())
+        },
     })
 }
 
 fn load_protos<'gc, T: Read>(
     gc: &'gc GcContext,
     reader: &mut T,
+    format: Format,
     parent_source: LuaString<'gc>,
 ) -> Result<Vec<LuaClosureProto<'gc>>, DeserializeError> {
     let n = load_int(reader)?;
-    let mut protos = Vec::with_capacity(n as usize);
+    let mut protos = with_capacity(n);
     for _ in 0..n {
-        protos.push(load_function(gc, reader, parent_source)?);
+        protos.push(load_function(gc, reader, format, parent_source)?);
     }
     Ok(protos)
 }
@@ -192,11 +324,28 @@ fn load_nullable_str<'gc, R: Read>(
     if size == 0 {
         return Ok(None);
     }
-    let mut buf = vec![0u8; size - 1];
-    reader.read_exact(&mut buf)?;
+    let buf = load_bytes(reader, size - 1)?;
     Ok(Some(gc.allocate_string(buf)))
 }
 
+// Reads `n` bytes without trusting `n` to allocate them up front
+fn load_bytes<R: Read>(reader: &mut R, n: usize) -> Result<Vec<u8>, DeserializeError> {
+    let mut buf = Vec::new();
+    reader.take(n as u64).read_to_end(&mut buf)?;
+    if buf.len() != n {
+        return Err(DeserializeError::Truncated);
+    }
+    Ok(buf)
+}
+
+// Names missing from debug information are shown as "?"
+fn load_debug_name<'gc, R: Read>(
+    gc: &'gc GcContext,
+    reader: &mut R,
+) -> Result<LuaString<'gc>, DeserializeError> {
+    Ok(load_nullable_str(gc, reader)?.unwrap_or_else(|| gc.allocate_string(B("?"))))
+}
+
 fn load_str<'gc, R: Read>(
     gc: &'gc GcContext,
     reader: &mut R,
@@ -209,17 +358,18 @@ fn load_str<'gc, R: Read>(
 }
 
 fn load_int<R: Read>(reader: &mut R) -> Result<u32, DeserializeError> {
-    let int = load_unsigned(reader, u32::MAX as usize)?
-        .try_into()
-        .unwrap();
-    Ok(int)
+    let int = load_unsigned(reader, i32::MAX as usize)?;
+    Ok(int as u32)
 }
 
-fn load_code<R: Read>(reader: &mut R) -> Result<Vec<Instruction>, DeserializeError> {
+fn load_code<R: Read>(
+    reader: &mut R,
+    format: Format,
+) -> Result<Vec<Instruction>, DeserializeError> {
     let n = load_int(reader)?;
-    let mut code = Vec::<Instruction>::with_capacity(n as usize);
+    let mut code = with_capacity(n);
     for _ in 0..n {
-        code.push(Instruction(reader.read_u32::<NativeEndian>()?));
+        code.push(Instruction(format.read_u32(reader)?));
     }
     Ok(code)
 }
@@ -227,19 +377,20 @@ fn load_code<R: Read>(reader: &mut R) -> Result<Vec<Instruction>, DeserializeErr
 fn load_constants<'gc, R: Read>(
     gc: &'gc GcContext,
     reader: &mut R,
+    format: Format,
 ) -> Result<Vec<Value<'gc>>, DeserializeError> {
     let n = load_int(reader)?;
-    let mut constants = Vec::with_capacity(n as usize);
+    let mut constants = with_capacity(n);
     for _ in 0..n {
         let ty = reader.read_u8()?;
         let value = match ty {
             super::LUA_VNIL => Value::Nil,
             super::LUA_VFALSE => Value::Boolean(false),
             super::LUA_VTRUE => Value::Boolean(true),
-            super::LUA_VNUMFLT => Value::Number(reader.read_f64::<NativeEndian>()?),
-            super::LUA_VNUMINT => Value::Integer(reader.read_i64::<NativeEndian>()?),
+            super::LUA_VNUMFLT => Value::Number(format.read_number(reader)?),
+            super::LUA_VNUMINT => Value::Integer(format.read_integer(reader)?),
             super::LUA_VSHRSHR | super::LUA_VLNGSHR => Value::String(load_str(gc, reader)?),
-            _ => unreachable!(),
+            _ => return Err(DeserializeError::Corrupted),
         };
         constants.push(value);
     }
@@ -248,7 +399,7 @@ fn load_constants<'gc, R: Read>(
 
 fn load_upvalues<R: Read>(reader: &mut R) -> Result<Vec<UpvalueDescription>, DeserializeError> {
     let n = load_int(reader)?;
-    let mut upvalues = Vec::with_capacity(n as usize);
+    let mut upvalues = with_capacity(n);
     for _ in 0..n {
         let in_stack = reader.read_u8()? != 0;
         let index = reader.read_u8()?;
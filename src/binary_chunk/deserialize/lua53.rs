@@ -0,0 +1,641 @@
+// Binary chunks precompiled by Lua 5.3, whose code is translated to the 5.4
+// instruction set as it's loaded.
+//
+// Most 5.3 instructions have a 5.4 counterpart with the same operands. Where
+// 5.3 takes either a register or a constant (RK) and 5.4 only takes a
+// register, the constant is loaded into a scratch register above the ones
+// the function uses. Generic for loops keep their state in one register
+// fewer than in 5.4, so their calls and loops are spelled out with CALL and
+// jumps.
+use super::{load_bytes, with_capacity, DeserializeError, Format};
+use crate::{
+    gc::GcContext,
+    io::{Read, ReadBytesExt},
+    prelude::*,
+    runtime::{
+        instruction::{OFFSET_SJ, UINT17_MAX, UINT25_MAX},
+        Instruction, Metamethod, OpCode,
+    },
+    types::{
+        InlineCaches, LineRange, LocalVariable, LuaClosureProto, LuaString, RegisterIndex,
+        UpvalueDescription, UpvalueIndex, Value,
+    },
+};
+use bstr::B;
+
+pub(super) const LUAC_VERSION: u8 = 0x53;
+
+const LUA_TNIL: u8 = 0;
+const LUA_TBOOLEAN: u8 = 1;
+const LUA_TNUMFLT: u8 = 3;
+const LUA_TNUMINT: u8 = 3 | (1 << 4);
+const LUA_TSHRSTR: u8 = 4;
+const LUA_TLNGSTR: u8 = 4 | (1 << 4);
+
+const OP_MOVE: u32 = 0;
+const OP_LOADK: u32 = 1;
+const OP_LOADKX: u32 = 2;
+const OP_LOADBOOL: u32 = 3;
+const OP_LOADNIL: u32 = 4;
+const OP_GETUPVAL: u32 = 5;
+const OP_GETTABUP: u32 = 6;
+const OP_GETTABLE: u32 = 7;
+const OP_SETTABUP: u32 = 8;
+const OP_SETUPVAL: u32 = 9;
+const OP_SETTABLE: u32 = 10;
+const OP_NEWTABLE: u32 = 11;
+const OP_SELF: u32 = 12;
+const OP_ADD: u32 = 13;
+const OP_SHR: u32 = 24;
+const OP_UNM: u32 = 25;
+const OP_BNOT: u32 = 26;
+const OP_NOT: u32 = 27;
+const OP_LEN: u32 = 28;
+const OP_CONCAT: u32 = 29;
+const OP_JMP: u32 = 30;
+const OP_EQ: u32 = 31;
+const OP_LT: u32 = 32;
+const OP_LE: u32 = 33;
+const OP_TEST: u32 = 34;
+const OP_TESTSET: u32 = 35;
+const OP_CALL: u32 = 36;
+const OP_TAILCALL: u32 = 37;
+const OP_RETURN: u32 = 38;
+const OP_FORLOOP: u32 = 39;
+const OP_FORPREP: u32 = 40;
+const OP_TFORCALL: u32 = 41;
+const OP_TFORLOOP: u32 = 42;
+const OP_SETLIST: u32 = 43;
+const OP_CLOSURE: u32 = 44;
+const OP_VARARG: u32 = 45;
+const OP_EXTRAARG: u32 = 46;
+
+const MAXARG_SBX: i64 = (1 << 17) - 1;
+const BITRK: u32 = 1 << 8;
+const LFIELDS_PER_FLUSH: u32 = 50;
+
+#[derive(Clone, Copy)]
+pub(super) struct Lua53Format {
+    pub format: Format,
+    pub size_t_size: u8,
+}
+
+impl Lua53Format {
+    fn read_int<R: Read>(&self, reader: &mut R) -> Result<u32, DeserializeError> {
+        let int = self.format.read_u32(reader)?;
+        if int > i32::MAX as u32 {
+            return Err(DeserializeError::IntegerOverflow);
+        }
+        Ok(int)
+    }
+
+    fn read_size<R: Read>(&self, reader: &mut R) -> Result<usize, DeserializeError> {
+        let mut bytes = [0; 8];
+        let bytes = &mut bytes[..self.size_t_size as usize];
+        reader.read_exact(bytes)?;
+        if !self.format.big_endian {
+            bytes.reverse();
+        }
+        let size = bytes.iter().fold(0u64, |size, &b| size << 8 | b as u64);
+        usize::try_from(size).map_err(|_| DeserializeError::IntegerOverflow)
+    }
+
+    fn load_nullable_str<'gc, R: Read>(
+        &self,
+        gc: &'gc GcContext,
+        reader: &mut R,
+    ) -> Result<Option<LuaString<'gc>>, DeserializeError> {
+        let mut size = reader.read_u8()? as usize;
+        if size == 0xff {
+            size = self.read_size(reader)?;
+        }
+        if size == 0 {
+            return Ok(None);
+        }
+        let buf = load_bytes(reader, size - 1)?;
+        Ok(Some(gc.allocate_string(buf)))
+    }
+
+    fn load_debug_name<'gc, R: Read>(
+        &self,
+        gc: &'gc GcContext,
+        reader: &mut R,
+    ) -> Result<LuaString<'gc>, DeserializeError> {
+        Ok(self
+            .load_nullable_str(gc, reader)?
+            .unwrap_or_else(|| gc.allocate_string(B("?"))))
+    }
+
+    pub(super) fn load_function<'gc, R: Read>(
+        &self,
+        gc: &'gc GcContext,
+        reader: &mut R,
+        parent_source: LuaString<'gc>,
+    ) -> Result<LuaClosureProto<'gc>, DeserializeError> {
+        let source = self.load_nullable_str(gc, reader)?.unwrap_or(parent_source);
+        let line_defined = self.read_int(reader)?;
+        let last_line_defined = self.read_int(reader)?;
+        let num_params = reader.read_u8()?;
+        let is_vararg = reader.read_u8()? != 0;
+        let max_stack_size = reader.read_u8()?;
+
+        let n = self.read_int(reader)?;
+        let mut code = with_capacity(n);
+        for _ in 0..n {
+            code.push(self.format.read_u32(reader)?);
+        }
+
+        let n = self.read_int(reader)?;
+        let mut constants = with_capacity(n);
+        for _ in 0..n {
+            let value = match reader.read_u8()? {
+                LUA_TNIL => Value::Nil,
+                LUA_TBOOLEAN => Value::Boolean(reader.read_u8()? != 0),
+                LUA_TNUMFLT => Value::Number(self.format.read_number(reader)?),
+                LUA_TNUMINT => Value::Integer(self.format.read_integer(reader)?),
+                LUA_TSHRSTR | LUA_TLNGSTR => Value::String(
+                    self.load_nullable_str(gc, reader)?
+                        .ok_or(DeserializeError::BadStringConstant)?,
+                ),
+                _ => return Err(DeserializeError::Corrupted),
+            };
+            constants.push(value);
+        }
+
+        let n = self.read_int(reader)?;
+        let mut upvalues = with_capacity(n);
+        for _ in 0..n {
+            let in_stack = reader.read_u8()? != 0;
+            let index = reader.read_u8()?;
+            upvalues.push(if in_stack {
+                UpvalueDescription::Register(RegisterIndex(index))
+            } else {
+                UpvalueDescription::Upvalue(UpvalueIndex(index))
+            });
+        }
+
+        let n = self.read_int(reader)?;
+        let mut protos = with_capacity(n);
+        for _ in 0..n {
+            protos.push(gc.allocate(self.load_function(gc, reader, source)?));
+        }
+
+        // Lines are absolute, one for each instruction
+        let n = self.read_int(reader)?;
+        let mut lines = with_capacity(n);
+        for _ in 0..n {
+            lines.push(self.read_int(reader)?);
+        }
+        if !lines.is_empty() && lines.len() != code.len() {
+            return Err(DeserializeError::Corrupted);
+        }
+
+        let n = self.read_int(reader)?;
+        let mut local_variables = with_capacity(n);
+        for _ in 0..n {
+            let name = self.load_debug_name(gc, reader)?;
+            let start = self.read_int(reader)?;
+            let end = self.read_int(reader)?;
+            local_variables.push((name, start, end));
+        }
+
+        let n = self.read_int(reader)?;
+        let mut upvalue_names = with_capacity(n);
+        for _ in 0..n {
+            upvalue_names.push(self.load_debug_name(gc, reader)?);
+        }
+
+        let mut translator = Translator {
+            constants: &constants,
+            scratch: max_stack_size,
+            num_scratch: 0,
+            line: line_defined,
+            code: Vec::with_capacity(code.len()),
+            lines: Vec::with_capacity(lines.len()),
+            pcs: Vec::with_capacity(code.len() + 1),
+            jumps: Vec::new(),
+            trampolines: Vec::new(),
+        };
+        let translated =
+            translator.translate(&code, &lines, num_params, is_vararg, !protos.is_empty())?;
+        let max_stack_size = max_stack_size
+            .checked_add(translator.num_scratch)
+            .ok_or(DeserializeError::TooManyRegisters)?;
+
+        let local_vars: Vec<_> = local_variables
+            .into_iter()
+            .map(|(name, start, end)| LocalVariable {
+                name,
+                pc: translator.new_pc(start) as u32..translator.new_pc(end) as u32,
+            })
+            .collect();
+        let translated_lines = translator.lines;
+
+        let mut proto = LuaClosureProto {
+            max_stack_size,
+            lines_defined: if line_defined > 0 {
+                LineRange::Lines(line_defined..=last_line_defined)
+            } else {
+                LineRange::File
+            },
+            constants: constants.into(),
+            inline_caches: InlineCaches::new(translated.len()),
+            #[cfg(feature = "jit")]
+            jit: Default::default(),
+            code: translated.into(),
+            protos: protos.into(),
+            upvalues: upvalues.into(),
+            source,
+            abs_line_info: None,
+            line_info: None,
+            local_vars: (!local_vars.is_empty()).then(|| local_vars.into_boxed_slice()),
+            upvalue_names: (!upvalue_names.is_empty()).then(|| upvalue_names.into_boxed_slice()),
+        };
+        if !lines.is_empty() {
+            proto.set_lines(&translated_lines);
+        }
+        Ok(proto)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum JumpKind {
+    Jmp,
+    ForPrep,
+    ForLoop,
+}
+
+#[derive(Clone, Copy)]
+enum Target {
+    // The 5.3 pc of an instruction
+    Pc(usize),
+    Trampoline(usize),
+}
+
+struct Translator<'a, 'gc> {
+    constants: &'a [Value<'gc>],
+    scratch: u8,
+    num_scratch: u8,
+    line: u32,
+    code: Vec<Instruction>,
+    lines: Vec<u32>,
+    // The translated pc of each 5.3 pc, and of the end of the code
+    pcs: Vec<usize>,
+    jumps: Vec<(usize, JumpKind, Target)>,
+    // Jumps that close upvalues are made in two instructions out of line, so
+    // that a test can still skip them as one instruction
+    trampolines: Vec<(u8, usize, u32)>,
+}
+
+impl Translator<'_, '_> {
+    fn translate(
+        &mut self,
+        code: &[u32],
+        lines: &[u32],
+        num_params: u8,
+        is_vararg: bool,
+        has_protos: bool,
+    ) -> Result<Vec<Instruction>, DeserializeError> {
+        if is_vararg {
+            self.line = lines.first().copied().unwrap_or(self.line);
+            self.emit(abc(OpCode::VarArgPrep, num_params, 0, 0, false));
+        }
+        // RETURN and TAILCALL close upvalues if closures may have captured
+        // any, and tell vararg functions their number of parameters like in
+        // 5.4
+        let return_c = if is_vararg { num_params + 1 } else { 0 };
+
+        let mut pc = 0;
+        while pc < code.len() {
+            // An EXTRAARG skipped with the instruction before it maps to the
+            // next one
+            while self.pcs.len() <= pc {
+                self.pcs.push(self.code.len());
+            }
+            if let Some(line) = lines.get(pc) {
+                self.line = *line;
+            }
+
+            let insn = code[pc];
+            let a = (insn >> 6 & 0xff) as u8;
+            let b = insn >> 23;
+            let c = insn >> 14 & 0x1ff;
+            let bx = insn >> 14;
+            let sbx = bx as i64 - MAXARG_SBX;
+            let jump_target = usize::try_from(pc as i64 + 1 + sbx).ok();
+
+            match insn & 0x3f {
+                OP_MOVE => self.emit(abc(OpCode::Move, a, b as u8, 0, false)),
+                OP_LOADK => self.emit(abx(OpCode::LoadK, a, bx)),
+                OP_LOADKX => {
+                    let ax = extra_arg(code, &mut pc)?;
+                    self.emit(abc(OpCode::LoadKX, a, 0, 0, false));
+                    self.emit(ax_insn(ax));
+                }
+                OP_LOADBOOL => {
+                    let op = if b != 0 {
+                        OpCode::LoadTrue
+                    } else {
+                        OpCode::LoadFalse
+                    };
+                    self.emit(abc(op, a, 0, 0, false));
+                    if c != 0 {
+                        self.jump(JumpKind::Jmp, 0, Target::Pc(pc + 2));
+                    }
+                }
+                OP_LOADNIL => self.emit(abc(OpCode::LoadNil, a, b as u8, 0, false)),
+                OP_GETUPVAL => self.emit(abc(OpCode::GetUpval, a, b as u8, 0, false)),
+                OP_GETTABUP => {
+                    if let Some(key) = self.string_constant(c) {
+                        self.emit(abc(OpCode::GetTabUp, a, b as u8, key, false));
+                    } else {
+                        let table = self.scratch(0)?;
+                        self.emit(abc(OpCode::GetUpval, table, b as u8, 0, false));
+                        let key = self.register(c, 1)?;
+                        self.emit(abc(OpCode::GetTable, a, table, key, false));
+                    }
+                }
+                OP_GETTABLE => {
+                    if let Some(key) = self.string_constant(c) {
+                        self.emit(abc(OpCode::GetField, a, b as u8, key, false));
+                    } else {
+                        let key = self.register(c, 0)?;
+                        self.emit(abc(OpCode::GetTable, a, b as u8, key, false));
+                    }
+                }
+                OP_SETTABUP => {
+                    let (value, k) = rk(c);
+                    if let Some(key) = self.string_constant(b) {
+                        self.emit(abc(OpCode::SetTabUp, a, key, value, k));
+                    } else {
+                        let table = self.scratch(0)?;
+                        self.emit(abc(OpCode::GetUpval, table, a, 0, false));
+                        let key = self.register(b, 1)?;
+                        self.emit(abc(OpCode::SetTable, table, key, value, k));
+                    }
+                }
+                OP_SETUPVAL => self.emit(abc(OpCode::SetUpval, a, b as u8, 0, false)),
+                OP_SETTABLE => {
+                    let (value, k) = rk(c);
+                    if let Some(key) = self.string_constant(b) {
+                        self.emit(abc(OpCode::SetField, a, key, value, k));
+                    } else {
+                        let key = self.register(b, 0)?;
+                        self.emit(abc(OpCode::SetTable, a, key, value, k));
+                    }
+                }
+                // The sizes are only hints
+                OP_NEWTABLE => {
+                    self.emit(abc(OpCode::NewTable, a, 0, 0, false));
+                    self.emit(ax_insn(0));
+                }
+                OP_SELF => {
+                    if let Some(key) = self.string_constant(c) {
+                        self.emit(abc(OpCode::Self_, a, b as u8, key, true));
+                    } else {
+                        // SELF only takes string keys in 5.4
+                        let object = self.scratch(0)?;
+                        self.emit(abc(OpCode::Move, object, b as u8, 0, false));
+                        let key = self.register(c, 1)?;
+                        self.emit(abc(OpCode::GetTable, a, object, key, false));
+                        let self_register = a.checked_add(1).ok_or(DeserializeError::Corrupted)?;
+                        self.emit(abc(OpCode::Move, self_register, object, 0, false));
+                    }
+                }
+                // The arithmetic opcodes and their metamethods are in the
+                // same order as in 5.4
+                op @ OP_ADD..=OP_SHR => {
+                    let lhs = self.register(b, 0)?;
+                    let rhs = self.register(c, 1)?;
+                    let offset = (op - OP_ADD) as u8;
+                    let op = OpCode::from(OpCode::Add as u8 + offset);
+                    let event = Metamethod::Add as u8 + offset;
+                    self.emit(abc(op, a, lhs, rhs, false));
+                    self.emit(abc(OpCode::MmBin, lhs, rhs, event, false));
+                }
+                OP_UNM => self.emit(abc(OpCode::Unm, a, b as u8, 0, false)),
+                OP_BNOT => self.emit(abc(OpCode::BNot, a, b as u8, 0, false)),
+                OP_NOT => self.emit(abc(OpCode::Not, a, b as u8, 0, false)),
+                OP_LEN => self.emit(abc(OpCode::Len, a, b as u8, 0, false)),
+                // 5.4 concatenates in place
+                OP_CONCAT => {
+                    let n = c.checked_sub(b).ok_or(DeserializeError::Corrupted)? + 1;
+                    self.emit(abc(OpCode::Concat, b as u8, n as u8, 0, false));
+                    if a as u32 != b {
+                        self.emit(abc(OpCode::Move, a, b as u8, 0, false));
+                    }
+                }
+                OP_JMP => {
+                    let target = jump_target.ok_or(DeserializeError::Corrupted)?;
+                    if a == 0 {
+                        self.jump(JumpKind::Jmp, 0, Target::Pc(target));
+                    } else {
+                        let trampoline = self.trampolines.len();
+                        self.trampolines.push((a - 1, target, self.line));
+                        self.jump(JumpKind::Jmp, 0, Target::Trampoline(trampoline));
+                    }
+                }
+                op @ (OP_EQ | OP_LT | OP_LE) => {
+                    let lhs = self.register(b, 0)?;
+                    let rhs = self.register(c, 1)?;
+                    let op = match op {
+                        OP_EQ => OpCode::Eq,
+                        OP_LT => OpCode::Lt,
+                        _ => OpCode::Le,
+                    };
+                    self.emit(abc(op, lhs, rhs, 0, a != 0));
+                }
+                OP_TEST => self.emit(abc(OpCode::Test, a, 0, 0, c != 0)),
+                OP_TESTSET => self.emit(abc(OpCode::TestSet, a, b as u8, 0, c != 0)),
+                OP_CALL => self.emit(abc(OpCode::Call, a, b as u8, c as u8, false)),
+                OP_TAILCALL => {
+                    self.emit(abc(OpCode::TailCall, a, b as u8, return_c, has_protos));
+                }
+                OP_RETURN => self.emit(abc(OpCode::Return, a, b as u8, return_c, has_protos)),
+                // FORPREP jumps to FORLOOP in 5.3, but past it when the loop
+                // doesn't run in 5.4. Both are at the same places.
+                OP_FORLOOP => {
+                    let target = jump_target.ok_or(DeserializeError::Corrupted)?;
+                    self.jump(JumpKind::ForLoop, a, Target::Pc(target));
+                }
+                OP_FORPREP => {
+                    let target = jump_target.ok_or(DeserializeError::Corrupted)?;
+                    self.jump(JumpKind::ForPrep, a, Target::Pc(target));
+                }
+                OP_TFORCALL => {
+                    if a as u32 + 3 + c > u8::MAX as u32 {
+                        return Err(DeserializeError::Corrupted);
+                    }
+                    let callee = self.scratch(0)?;
+                    self.scratch(2.max(c.saturating_sub(1)) as u8)?;
+                    for i in 0..3 {
+                        self.emit(abc(OpCode::Move, callee + i, a + i, 0, false));
+                    }
+                    self.emit(abc(OpCode::Call, callee, 3, c as u8 + 1, false));
+                    for i in 0..c as u8 {
+                        self.emit(abc(OpCode::Move, a + 3 + i, callee + i, 0, false));
+                    }
+                }
+                // Loops back unless the first variable is nil
+                OP_TFORLOOP => {
+                    let target = jump_target.ok_or(DeserializeError::Corrupted)?;
+                    if a == u8::MAX {
+                        return Err(DeserializeError::Corrupted);
+                    }
+                    let nil = self.scratch(0)?;
+                    self.emit(abc(OpCode::LoadNil, nil, 0, 0, false));
+                    self.emit(abc(OpCode::Eq, a + 1, nil, 0, true));
+                    self.jump(JumpKind::Jmp, 0, Target::Pc(pc + 1));
+                    self.emit(abc(OpCode::Move, a, a + 1, 0, false));
+                    self.jump(JumpKind::Jmp, 0, Target::Pc(target));
+                }
+                OP_SETLIST => {
+                    let c = if c == 0 { extra_arg(code, &mut pc)? } else { c };
+                    let offset = c
+                        .checked_sub(1)
+                        .ok_or(DeserializeError::Corrupted)?
+                        .checked_mul(LFIELDS_PER_FLUSH)
+                        .ok_or(DeserializeError::Corrupted)?;
+                    if offset <= u8::MAX as u32 {
+                        self.emit(abc(OpCode::SetList, a, b as u8, offset as u8, false));
+                    } else {
+                        self.emit(abc(OpCode::SetList, a, b as u8, offset as u8, true));
+                        self.emit(ax_insn(offset >> 8));
+                    }
+                }
+                OP_CLOSURE => self.emit(abx(OpCode::Closure, a, bx)),
+                OP_VARARG => self.emit(abc(OpCode::VarArg, a, 0, b as u8, false)),
+                _ => return Err(DeserializeError::Corrupted),
+            }
+            pc += 1;
+        }
+        while self.pcs.len() <= code.len() {
+            self.pcs.push(self.code.len());
+        }
+
+        let trampolines: Vec<_> = core::mem::take(&mut self.trampolines)
+            .into_iter()
+            .map(|(level, target, line)| {
+                let pc = self.code.len();
+                self.line = line;
+                self.emit(abc(OpCode::Close, level, 0, 0, false));
+                self.jump(JumpKind::Jmp, 0, Target::Pc(target));
+                pc
+            })
+            .collect();
+
+        for (pc, kind, target) in core::mem::take(&mut self.jumps) {
+            let target = match target {
+                Target::Pc(target) if target < code.len() => self.pcs[target],
+                Target::Pc(_) => return Err(DeserializeError::Corrupted),
+                Target::Trampoline(i) => trampolines[i],
+            } as i64;
+            let pc_i = pc as i64;
+            let insn = &mut self.code[pc];
+            match kind {
+                JumpKind::Jmp => {
+                    let sj = target - (pc_i + 1) + OFFSET_SJ as i64;
+                    if !(0..=UINT25_MAX as i64).contains(&sj) {
+                        return Err(DeserializeError::Corrupted);
+                    }
+                    insn.0 |= (sj as u32) << 7;
+                }
+                JumpKind::ForPrep | JumpKind::ForLoop => {
+                    let bx = match kind {
+                        JumpKind::ForPrep => target - pc_i - 1,
+                        _ => pc_i + 1 - target,
+                    };
+                    if !(0..=UINT17_MAX as i64).contains(&bx) {
+                        return Err(DeserializeError::Corrupted);
+                    }
+                    insn.0 |= (bx as u32) << 15;
+                }
+            }
+        }
+
+        Ok(core::mem::take(&mut self.code))
+    }
+
+    fn emit(&mut self, insn: Instruction) {
+        self.code.push(insn);
+        self.lines.push(self.line);
+    }
+
+    // Emits a jump whose offset is filled in once the target is translated
+    fn jump(&mut self, kind: JumpKind, a: u8, target: Target) {
+        let op = match kind {
+            JumpKind::Jmp => OpCode::Jmp,
+            JumpKind::ForPrep => OpCode::ForPrep,
+            JumpKind::ForLoop => OpCode::ForLoop,
+        };
+        self.jumps.push((self.code.len(), kind, target));
+        self.emit(abc(op, a, 0, 0, false));
+    }
+
+    fn new_pc(&self, pc: u32) -> usize {
+        self.pcs
+            .get(pc as usize)
+            .copied()
+            .unwrap_or_else(|| self.pcs.last().copied().unwrap_or_default())
+    }
+
+    fn scratch(&mut self, i: u8) -> Result<u8, DeserializeError> {
+        let register = self
+            .scratch
+            .checked_add(i)
+            .filter(|&register| register < u8::MAX)
+            .ok_or(DeserializeError::TooManyRegisters)?;
+        self.num_scratch = self.num_scratch.max(i + 1);
+        Ok(register)
+    }
+
+    // The register of an RK operand, loading a constant into the i-th
+    // scratch register
+    fn register(&mut self, rk: u32, i: u8) -> Result<u8, DeserializeError> {
+        if rk & BITRK == 0 {
+            return Ok(rk as u8);
+        }
+        let register = self.scratch(i)?;
+        self.emit(abx(OpCode::LoadK, register, rk & 0xff));
+        Ok(register)
+    }
+
+    // The index of an RK operand that is a string constant, which 5.4 takes
+    // as field names
+    fn string_constant(&self, rk: u32) -> Option<u8> {
+        let index = (rk & BITRK != 0).then_some(rk & 0xff)?;
+        match self.constants.get(index as usize) {
+            Some(Value::String(_)) => Some(index as u8),
+            _ => None,
+        }
+    }
+}
+
+// The argument of the EXTRAARG following the instruction at `pc`, which is
+// then skipped
+fn extra_arg(code: &[u32], pc: &mut usize) -> Result<u32, DeserializeError> {
+    *pc += 1;
+    match code.get(*pc) {
+        Some(next) if next & 0x3f == OP_EXTRAARG => Ok(next >> 6),
+        _ => Err(DeserializeError::Corrupted),
+    }
+}
+
+// An RK operand as the C operand and k flag of a 5.4 instruction
+fn rk(rk: u32) -> (u8, bool) {
+    ((rk & 0xff) as u8, rk & BITRK != 0)
+}
+
+fn abc(op: OpCode, a: u8, b: u8, c: u8, k: bool) -> Instruction {
+    Instruction(
+        op as u32 | (a as u32) << 7 | (k as u32) << 15 | (b as u32) << 16 | (c as u32) << 24,
+    )
+}
+
+fn abx(op: OpCode, a: u8, bx: u32) -> Instruction {
+    Instruction(op as u32 | (a as u32) << 7 | bx << 15)
+}
+
+fn ax_insn(ax: u32) -> Instruction {
+    Instruction(OpCode::ExtraArg as u32 | ax << 7)
+}
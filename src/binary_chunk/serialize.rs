@@ -1,152 +1,269 @@
 use crate::{
     gc::Gc,
+    io::{Write, WriteBytesExt},
+    prelude::*,
     runtime::Instruction,
-    types::{Integer, LineRange, LuaClosureProto, LuaString, Number, UpvalueDescription, Value},
+    types::{
+        Constants, Integer, LineRange, LuaClosureProto, LuaString, Number, UpvalueDescription,
+        Value,
+    },
 };
-use byteorder::{NativeEndian, WriteBytesExt};
-use std::io::Write;
+use byteorder::{BigEndian, LittleEndian, NativeEndian};
 
-pub fn dump<W: Write>(writer: &mut W, proto: &LuaClosureProto) -> std::io::Result<()> {
-    writer.write_all(&super::LUA_SIGNATURE)?;
-    writer.write_u8(super::LUAC_VERSION)?;
-    writer.write_u8(super::LUAC_FORMAT)?;
-
-    writer.write_all(&super::LUAC_DATA)?;
-
-    writer.write_u8(std::mem::size_of::<Instruction>() as u8)?;
-    writer.write_u8(std::mem::size_of::<Integer>() as u8)?;
-    writer.write_u8(std::mem::size_of::<Number>() as u8)?;
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ByteOrder {
+    #[default]
+    Native,
+    Little,
+    Big,
+}
 
-    writer.write_i64::<NativeEndian>(super::LUAC_INT)?;
-    writer.write_f64::<NativeEndian>(super::LUAC_NUM)?;
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DumpOptions {
+    // Leaves out the source name, line information and the names of local
+    // variables and upvalues
+    pub strip: bool,
 
-    writer.write_u8(proto.upvalues.len() as u8)?;
-    dump_function(writer, proto)?;
+    // The byte order of instructions and numbers. The loader accepts either,
+    // so chunks can be dumped for another platform.
+    pub byte_order: ByteOrder,
+}
 
-    Ok(())
+pub fn dump<W: Write>(writer: &mut W, proto: &LuaClosureProto) -> crate::io::Result<()> {
+    dump_with_options(writer, proto, &DumpOptions::default())
 }
 
-fn dump_function<W: Write>(writer: &mut W, proto: &LuaClosureProto) -> std::io::Result<()> {
-    let (line_defined, last_line_defined) = match &proto.lines_defined {
-        LineRange::File => (0, 0),
-        LineRange::Lines(range) => (*range.start(), *range.end()),
+pub fn dump_with_options<W: Write>(
+    writer: &mut W,
+    proto: &LuaClosureProto,
+    options: &DumpOptions,
+) -> crate::io::Result<()> {
+    let mut dumper = Dumper {
+        writer,
+        strip: options.strip,
+        byte_order: options.byte_order,
     };
 
-    dump_string(writer, proto.source)?; // source
-    dump_int(writer, line_defined)?;
-    dump_int(writer, last_line_defined)?;
-    writer.write_u8(0)?; // numparams
-    writer.write_u8(0)?; // is_vararg
-    writer.write_u8(proto.max_stack_size)?;
+    dumper.writer.write_all(&super::LUA_SIGNATURE)?;
+    dumper.writer.write_u8(super::LUAC_VERSION)?;
+    dumper.writer.write_u8(super::LUAC_FORMAT)?;
+
+    dumper.writer.write_all(&super::LUAC_DATA)?;
+
+    dumper
+        .writer
+        .write_u8(core::mem::size_of::<Instruction>() as u8)?;
+    dumper
+        .writer
+        .write_u8(core::mem::size_of::<Integer>() as u8)?;
+    dumper
+        .writer
+        .write_u8(core::mem::size_of::<Number>() as u8)?;
 
-    dump_code(writer, &proto.code)?;
-    dump_constants(writer, &proto.constants)?;
-    dump_upvalues(writer, &proto.upvalues)?;
-    dump_protos(writer, &proto.protos)?;
+    dumper.dump_integer(super::LUAC_INT)?;
+    dumper.dump_number(super::LUAC_NUM)?;
 
-    dump_int(writer, 0)?; // lineinfo
-    dump_int(writer, 0)?; // abslineinfo
-    dump_int(writer, 0)?; // locvar
-    dump_int(writer, 0)?; // upvalue
+    dumper.writer.write_u8(proto.upvalues.len() as u8)?;
+    dumper.dump_function(proto, None)?;
 
     Ok(())
 }
 
-fn dump_protos<W: Write>(writer: &mut W, protos: &[Gc<LuaClosureProto>]) -> std::io::Result<()> {
-    dump_size(writer, protos.len())?;
-    for proto in protos {
-        dump_function(writer, proto)?;
-    }
-    Ok(())
+struct Dumper<'a, W> {
+    writer: &'a mut W,
+    strip: bool,
+    byte_order: ByteOrder,
 }
 
-fn dump_string<'gc, W, S>(writer: &mut W, string: S) -> std::io::Result<()>
-where
-    W: Write,
-    S: Into<Option<LuaString<'gc>>>,
-{
-    if let Some(string) = string.into() {
-        dump_size(writer, string.len() + 1)?;
-        writer.write_all(string.as_ref())?;
-    } else {
-        dump_size(writer, 0)?;
+impl<W: Write> Dumper<'_, W> {
+    fn dump_function<'gc>(
+        &mut self,
+        proto: &LuaClosureProto<'gc>,
+        parent_source: Option<LuaString<'gc>>,
+    ) -> crate::io::Result<()> {
+        let (line_defined, last_line_defined) = match &proto.lines_defined {
+            LineRange::File => (0, 0),
+            LineRange::Lines(range) => (*range.start(), *range.end()),
+        };
+
+        // Nested functions share the source of their parent
+        let source = if self.strip || parent_source == Some(proto.source) {
+            None
+        } else {
+            Some(proto.source)
+        };
+        self.dump_string(source)?;
+        self.dump_int(line_defined)?;
+        self.dump_int(last_line_defined)?;
+        self.writer.write_u8(0)?; // numparams
+        self.writer.write_u8(0)?; // is_vararg
+        self.writer.write_u8(proto.max_stack_size)?;
+
+        self.dump_code(&proto.code)?;
+        self.dump_constants(&proto.constants)?;
+        self.dump_upvalues(&proto.upvalues)?;
+        self.dump_protos(&proto.protos, proto.source)?;
+        self.dump_debug(proto)?;
+
+        Ok(())
     }
-    Ok(())
-}
 
-fn dump_size<W: Write>(writer: &mut W, mut x: usize) -> std::io::Result<()> {
-    let mut buf = Vec::new();
-    loop {
-        buf.push((x & 0x7f) as u8);
-        x >>= 7;
-        if x == 0 {
-            break;
+    fn dump_debug(&mut self, proto: &LuaClosureProto) -> crate::io::Result<()> {
+        if self.strip {
+            self.dump_int(0)?; // lineinfo
+            self.dump_int(0)?; // abslineinfo
+            self.dump_int(0)?; // locvar
+            self.dump_int(0)?; // upvalue
+            return Ok(());
+        }
+
+        let line_info = proto.line_info.as_deref().unwrap_or_default();
+        self.dump_size(line_info.len())?;
+        self.writer.write_all(line_info)?;
+
+        let abs_line_info = proto.abs_line_info.as_deref().unwrap_or_default();
+        self.dump_size(abs_line_info.len())?;
+        for info in abs_line_info {
+            self.dump_int(info.pc)?;
+            self.dump_int(info.line)?;
+        }
+
+        let local_vars = proto.local_vars.as_deref().unwrap_or_default();
+        self.dump_size(local_vars.len())?;
+        for var in local_vars {
+            self.dump_string(var.name)?;
+            self.dump_int(var.pc.start)?;
+            self.dump_int(var.pc.end)?;
         }
+
+        let upvalue_names = proto.upvalue_names.as_deref().unwrap_or_default();
+        self.dump_size(upvalue_names.len())?;
+        for name in upvalue_names {
+            self.dump_string(*name)?;
+        }
+
+        Ok(())
     }
-    buf.reverse();
-    *buf.last_mut().unwrap() |= 0x80;
-    writer.write_all(&buf)?;
-    Ok(())
-}
 
-fn dump_int<W: Write>(writer: &mut W, int: u32) -> std::io::Result<()> {
-    dump_size(writer, int as usize)
-}
+    fn dump_protos<'gc>(
+        &mut self,
+        protos: &[Gc<LuaClosureProto<'gc>>],
+        source: LuaString<'gc>,
+    ) -> crate::io::Result<()> {
+        self.dump_size(protos.len())?;
+        for proto in protos {
+            self.dump_function(proto, Some(source))?;
+        }
+        Ok(())
+    }
 
-fn dump_code<W: Write>(writer: &mut W, instructions: &[Instruction]) -> std::io::Result<()> {
-    dump_size(writer, instructions.len())?;
-    for insn in instructions {
-        writer.write_u32::<NativeEndian>(insn.0)?;
+    fn dump_string<'gc, S>(&mut self, string: S) -> crate::io::Result<()>
+    where
+        S: Into<Option<LuaString<'gc>>>,
+    {
+        if let Some(string) = string.into() {
+            self.dump_size(string.len() + 1)?;
+            self.writer.write_all(string.as_ref())?;
+        } else {
+            self.dump_size(0)?;
+        }
+        Ok(())
     }
-    Ok(())
-}
 
-fn dump_constants<W: Write>(writer: &mut W, constants: &[Value]) -> std::io::Result<()> {
-    dump_size(writer, constants.len())?;
-    for constant in constants {
-        match constant {
-            Value::Nil => {
-                writer.write_u8(super::LUA_VNIL)?;
-            }
-            Value::Boolean(false) => {
-                writer.write_u8(super::LUA_VFALSE)?;
-            }
-            Value::Boolean(true) => {
-                writer.write_u8(super::LUA_VTRUE)?;
-            }
-            Value::Integer(i) => {
-                writer.write_u8(super::LUA_VNUMINT)?;
-                writer.write_i64::<NativeEndian>(*i)?;
+    fn dump_size(&mut self, mut x: usize) -> crate::io::Result<()> {
+        let mut buf = Vec::new();
+        loop {
+            buf.push((x & 0x7f) as u8);
+            x >>= 7;
+            if x == 0 {
+                break;
             }
-            Value::Number(x) => {
-                writer.write_u8(super::LUA_VNUMFLT)?;
-                writer.write_f64::<NativeEndian>(*x)?;
-            }
-            Value::String(s) => {
-                writer.write_u8(super::LUA_VLNGSHR)?;
-                dump_string(writer, *s)?;
-            }
-            _ => unreachable!(),
-        };
+        }
+        buf.reverse();
+        *buf.last_mut().unwrap() |= 0x80;
+        self.writer.write_all(&buf)?;
+        Ok(())
+    }
+
+    fn dump_int(&mut self, int: u32) -> crate::io::Result<()> {
+        self.dump_size(int as usize)
+    }
+
+    fn dump_integer(&mut self, i: Integer) -> crate::io::Result<()> {
+        match self.byte_order {
+            ByteOrder::Native => self.writer.write_i64::<NativeEndian>(i),
+            ByteOrder::Little => self.writer.write_i64::<LittleEndian>(i),
+            ByteOrder::Big => self.writer.write_i64::<BigEndian>(i),
+        }
+    }
+
+    fn dump_number(&mut self, x: Number) -> crate::io::Result<()> {
+        match self.byte_order {
+            ByteOrder::Native => self.writer.write_f64::<NativeEndian>(x),
+            ByteOrder::Little => self.writer.write_f64::<LittleEndian>(x),
+            ByteOrder::Big => self.writer.write_f64::<BigEndian>(x),
+        }
     }
-    Ok(())
-}
 
-fn dump_upvalues<W: Write>(writer: &mut W, upvalues: &[UpvalueDescription]) -> std::io::Result<()> {
-    dump_size(writer, upvalues.len())?;
-    for upvalue in upvalues {
-        match upvalue {
-            UpvalueDescription::Register(index) => {
-                writer.write_u8(1)?; // instack
-                writer.write_u8(index.0)?; // idx
-                writer.write_u8(0)?; // kind
+    fn dump_code(&mut self, instructions: &[Instruction]) -> crate::io::Result<()> {
+        self.dump_size(instructions.len())?;
+        for insn in instructions {
+            match self.byte_order {
+                ByteOrder::Native => self.writer.write_u32::<NativeEndian>(insn.0)?,
+                ByteOrder::Little => self.writer.write_u32::<LittleEndian>(insn.0)?,
+                ByteOrder::Big => self.writer.write_u32::<BigEndian>(insn.0)?,
             }
-            UpvalueDescription::Upvalue(index) => {
-                writer.write_u8(0)?; // instack
-                writer.write_u8(index.0)?; // idx
-                writer.write_u8(0)?; // kind
+        }
+        Ok(())
+    }
+
+    fn dump_constants(&mut self, constants: &Constants) -> crate::io::Result<()> {
+        self.dump_size(constants.len())?;
+        for constant in constants.iter() {
+            match constant {
+                Value::Nil => {
+                    self.writer.write_u8(super::LUA_VNIL)?;
+                }
+                Value::Boolean(false) => {
+                    self.writer.write_u8(super::LUA_VFALSE)?;
+                }
+                Value::Boolean(true) => {
+                    self.writer.write_u8(super::LUA_VTRUE)?;
+                }
+                Value::Integer(i) => {
+                    self.writer.write_u8(super::LUA_VNUMINT)?;
+                    self.dump_integer(i)?;
+                }
+                Value::Number(x) => {
+                    self.writer.write_u8(super::LUA_VNUMFLT)?;
+                    self.dump_number(x)?;
+                }
+                Value::String(s) => {
+                    self.writer.write_u8(super::LUA_VLNGSHR)?;
+                    self.dump_string(s)?;
+                }
+                _ => unreachable!(),
+            };
+        }
+        Ok(())
+    }
+
+    fn dump_upvalues(&mut self, upvalues: &[UpvalueDescription]) -> crate::io::Result<()> {
+        self.dump_size(upvalues.len())?;
+        for upvalue in upvalues {
+            match upvalue {
+                UpvalueDescription::Register(index) => {
+                    self.writer.write_u8(1)?; // instack
+                    self.writer.write_u8(index.0)?; // idx
+                    self.writer.write_u8(0)?; // kind
+                }
+                UpvalueDescription::Upvalue(index) => {
+                    self.writer.write_u8(0)?; // instack
+                    self.writer.write_u8(index.0)?; // idx
+                    self.writer.write_u8(0)?; // kind
+                }
             }
         }
+        Ok(())
     }
-    Ok(())
 }
@@ -0,0 +1,117 @@
+use crate::{
+    gc::GcContext,
+    runtime::collect_requires,
+    stdlib::package::{search_path, LUA_LSUBSEP},
+    Error,
+};
+use bstr::ByteVec;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone)]
+pub struct BundledModule {
+    pub name: Vec<u8>,
+    pub path: PathBuf,
+    source: Vec<u8>,
+}
+
+// A script together with the Lua modules it requires, found by following
+// the requires with a literal name through `package_path`.
+// Modules that aren't found (e.g. the standard library or native modules)
+// are left to be required at run time.
+#[derive(Debug, Clone)]
+pub struct Bundle {
+    main: PathBuf,
+    main_source: Vec<u8>,
+    modules: Vec<BundledModule>,
+    unresolved: Vec<Vec<u8>>,
+}
+
+impl Bundle {
+    pub fn new<P, S>(gc: &GcContext, main: P, package_path: S) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+        S: AsRef<[u8]>,
+    {
+        let main = main.as_ref().to_path_buf();
+        let main_source = crate::read_chunk_file(&main)?;
+
+        let mut names = Vec::new();
+        collect_requires(&crate::load_file(gc, &main)?, &mut names);
+
+        let mut seen = HashSet::new();
+        let mut modules = Vec::new();
+        let mut unresolved = Vec::new();
+        let mut i = 0;
+        while i < names.len() {
+            let name = names[i].clone();
+            i += 1;
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+            let Ok(filename) = search_path(&name, package_path.as_ref(), b".", LUA_LSUBSEP) else {
+                unresolved.push(name);
+                continue;
+            };
+            let path = filename
+                .into_path_buf()
+                .map_err(|e| Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)))?;
+            collect_requires(&crate::load_file(gc, &path)?, &mut names);
+            modules.push(BundledModule {
+                name,
+                source: crate::read_chunk_file(&path)?,
+                path,
+            });
+        }
+
+        Ok(Self {
+            main,
+            main_source,
+            modules,
+            unresolved,
+        })
+    }
+
+    pub fn main(&self) -> &Path {
+        &self.main
+    }
+
+    pub fn modules(&self) -> &[BundledModule] {
+        &self.modules
+    }
+
+    // Names of the required modules that aren't part of the bundle
+    pub fn unresolved(&self) -> &[Vec<u8>] {
+        &self.unresolved
+    }
+
+    // Single chunk that registers the modules in package.preload and then
+    // runs the main script
+    pub fn to_source(&self) -> Vec<u8> {
+        let mut source = Vec::new();
+        for module in &self.modules {
+            source.extend_from_slice(b"package.preload[");
+            push_quoted(&mut source, &module.name);
+            source.extend_from_slice(b"] = function(...)\n");
+            source.extend_from_slice(&module.source);
+            // the module may end with a comment
+            source.extend_from_slice(b"\nend\n");
+        }
+        source.extend_from_slice(&self.main_source);
+        source
+    }
+}
+
+fn push_quoted(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.push(b'"');
+    for &ch in s {
+        match ch {
+            b'"' | b'\\' => buf.extend_from_slice(&[b'\\', ch]),
+            b' '..=b'~' => buf.push(ch),
+            _ => buf.extend_from_slice(format!("\\{ch:03}").as_bytes()),
+        }
+    }
+    buf.push(b'"');
+}
@@ -0,0 +1,1663 @@
+// Subset of the C API of Lua 5.4 (lua.h and lauxlib.h), so that hosts and
+// bindings written against it can link with this crate instead of liblua,
+// using the headers of Lua 5.4 as they are. Build the crate as a static or
+// dynamic library with the "capi" feature, e.g.
+// `cargo rustc --release --config 'profile.release.panic="unwind"' --features capi
+// --crate-type staticlib`.
+//
+// Functions that raise an error don't return, as in Lua. Inside a C
+// function they unwind to where it was called, and the error is raised in
+// Lua from there, so the C code has to be built with unwind tables (the
+// default of GCC and Clang on most targets), and the crate with
+// panic = "unwind". With panic = "abort", and outside of C functions, errors
+// go to the panic handler as in Lua, which prints the message and aborts.
+// C functions have to use the state they are given, not the one from
+// luaL_newstate.
+//
+// Not provided: coroutines, continuations (the k arguments are ignored),
+// light userdata, user values, the debug interface, lua_gc, and the
+// variadic lua_pushfstring and luaL_error, which Rust can't define.
+//
+// The requirements on arguments are those of the Lua manual.
+#![allow(non_camel_case_types, clippy::missing_safety_doc)]
+
+use crate::{
+    binary_chunk,
+    gc::{GcCell, GcContext, RegistryKey},
+    runtime::{Action, Continuation, ErrorKind, Runtime, Vm},
+    types::{Integer, LuaString, LuaThread, NativeClosure, Table, Type, Value},
+};
+use bstr::B;
+use std::{
+    alloc::Layout,
+    collections::BTreeMap,
+    ffi::{c_char, c_int, c_void, CStr},
+    io::Read,
+    panic::AssertUnwindSafe,
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc,
+    },
+};
+
+pub type lua_Integer = i64;
+pub type lua_Number = f64;
+pub type lua_KContext = isize;
+pub type lua_CFunction = unsafe extern "C-unwind" fn(state: *mut lua_State) -> c_int;
+pub type lua_KFunction =
+    unsafe extern "C-unwind" fn(state: *mut lua_State, status: c_int, ctx: lua_KContext) -> c_int;
+
+#[repr(C)]
+pub struct luaL_Reg {
+    pub name: *const c_char,
+    pub func: Option<lua_CFunction>,
+}
+
+pub const LUA_OK: c_int = 0;
+pub const LUA_ERRRUN: c_int = 2;
+pub const LUA_ERRSYNTAX: c_int = 3;
+pub const LUA_ERRERR: c_int = 5;
+pub const LUA_ERRFILE: c_int = 6;
+
+pub const LUA_TNONE: c_int = -1;
+pub const LUA_TNIL: c_int = 0;
+pub const LUA_TBOOLEAN: c_int = 1;
+pub const LUA_TNUMBER: c_int = 3;
+pub const LUA_TSTRING: c_int = 4;
+pub const LUA_TTABLE: c_int = 5;
+pub const LUA_TFUNCTION: c_int = 6;
+pub const LUA_TUSERDATA: c_int = 7;
+pub const LUA_TTHREAD: c_int = 8;
+
+pub const LUA_MULTRET: c_int = -1;
+pub const LUA_REGISTRYINDEX: c_int = -1_000_000 - 1000;
+pub const LUA_OPEQ: c_int = 0;
+pub const LUA_OPLT: c_int = 1;
+pub const LUA_OPLE: c_int = 2;
+pub const LUA_NOREF: c_int = -2;
+pub const LUA_REFNIL: c_int = -1;
+
+pub const fn lua_upvalueindex(i: c_int) -> c_int {
+    LUA_REGISTRYINDEX - i
+}
+
+pub struct lua_State {
+    kind: StateKind,
+    // Error raised by the C function the state was passed to
+    error: Option<ErrorKind>,
+    // NUL-terminated copies of the strings handed out, by the stack slot of
+    // the string (or the pseudo-index of an upvalue), which are dropped once
+    // the string is popped
+    strings: BTreeMap<isize, Box<[u8]>>,
+}
+
+enum StateKind {
+    // Created by luaL_newstate. The stack is that of a thread in the
+    // registry, so that the values on it are kept alive.
+    Owned {
+        runtime: Box<Runtime>,
+        stack: RegistryKey,
+    },
+    // Passed to a C function, whose arguments are the stack. The pointers
+    // are valid until the function returns, and the heap isn't collected
+    // meanwhile.
+    Call {
+        gc: *const GcContext,
+        vm: *mut Vm<'static>,
+        stack: *mut Vec<Value<'static>>,
+        upvalues: *const GcCell<'static, Table<'static>>,
+    },
+}
+
+struct Api<'a, 'gc> {
+    gc: &'gc GcContext,
+    vm: &'a mut Vm<'gc>,
+    stack: &'a mut Vec<Value<'gc>>,
+    // Upvalues of the C function, by their index
+    upvalues: Option<GcCell<'gc, Table<'gc>>>,
+    // None outside of C functions, where errors go to the panic handler
+    error: Option<&'a mut Option<ErrorKind>>,
+    strings: &'a mut BTreeMap<isize, Box<[u8]>>,
+}
+
+unsafe fn with<F, R>(state: *mut lua_State, f: F) -> R
+where
+    F: for<'a, 'gc> FnOnce(&mut Api<'a, 'gc>) -> R,
+{
+    let lua_State {
+        kind,
+        error,
+        strings,
+    } = &mut *state;
+    match kind {
+        StateKind::Owned { runtime, stack } => {
+            let result = runtime.heap().with(|gc, vm| {
+                let mut vm = vm.borrow_mut(gc);
+                let thread = gc.registry_value(stack).as_thread().unwrap();
+                let mut thread = thread.borrow_mut(gc);
+                let mut api = Api {
+                    gc,
+                    vm: &mut vm,
+                    stack: &mut thread.stack,
+                    upvalues: None,
+                    error: None,
+                    strings,
+                };
+                let result = f(&mut api);
+                api.release_strings();
+                result
+            });
+            // Metamethods called by the API run in call_value, which doesn't
+            // collect
+            if runtime.heap().with(|gc, _| gc.should_perform_gc()) {
+                runtime.heap().step();
+            }
+            result
+        }
+        StateKind::Call {
+            gc,
+            vm,
+            stack,
+            upvalues,
+        } => {
+            let mut api = Api {
+                gc: &**gc,
+                vm: &mut **vm,
+                stack: &mut **stack,
+                upvalues: Some(**upvalues),
+                error: Some(error),
+                strings,
+            };
+            let result = f(&mut api);
+            api.release_strings();
+            result
+        }
+    }
+}
+
+// The payload of the unwinding started by raise
+#[cfg(panic = "unwind")]
+struct Raised;
+
+#[derive(Clone, Copy)]
+enum Helper {
+    Index,
+    NewIndex,
+    Len,
+    Eq,
+    Lt,
+    Le,
+    Concat,
+}
+
+// Operations that can call metamethods, in the order of Helper
+const HELPERS: &str = "return
+    function(t, k) return t[k] end,
+    function(t, k, v) t[k] = v end,
+    function(v) return #v end,
+    function(a, b) return a == b end,
+    function(a, b) return a < b end,
+    function(a, b) return a <= b end,
+    function(a, b) return a .. b end";
+
+impl<'gc> Api<'_, 'gc> {
+    fn index(&self, idx: c_int) -> Option<Value<'gc>> {
+        if idx > 0 {
+            self.stack.get(idx as usize - 1).copied()
+        } else if idx > LUA_REGISTRYINDEX {
+            let i = self.stack.len() as isize + idx as isize;
+            usize::try_from(i)
+                .ok()
+                .and_then(|i| self.stack.get(i))
+                .copied()
+        } else if idx == LUA_REGISTRYINDEX {
+            Some(self.vm.registry().into())
+        } else {
+            let upvalues = self.upvalues?;
+            let value = upvalues
+                .borrow()
+                .get_integer_key((LUA_REGISTRYINDEX - idx) as Integer);
+            Some(value)
+        }
+    }
+
+    fn get(&self, idx: c_int) -> Value<'gc> {
+        self.index(idx).unwrap_or_default()
+    }
+
+    // The stack slot at `idx`, if it isn't a pseudo-index
+    fn slot(&self, idx: c_int) -> Option<isize> {
+        if idx > 0 {
+            Some(idx as isize - 1)
+        } else if idx > LUA_REGISTRYINDEX {
+            Some(self.stack.len() as isize + idx as isize)
+        } else {
+            None
+        }
+    }
+
+    fn set(&mut self, idx: c_int, value: Value<'gc>) {
+        match self.slot(idx) {
+            Some(i) => self.stack[i as usize] = value,
+            None if idx < LUA_REGISTRYINDEX => {
+                if let Some(upvalues) = self.upvalues {
+                    let i = (LUA_REGISTRYINDEX - idx) as Integer;
+                    upvalues.borrow_mut(self.gc).set_integer_key(i, value);
+                }
+            }
+            None => (),
+        }
+    }
+
+    fn push<V: Into<Value<'gc>>>(&mut self, value: V) {
+        self.stack.push(value.into());
+    }
+
+    fn pop(&mut self) -> Value<'gc> {
+        self.stack.pop().unwrap_or_default()
+    }
+
+    fn push_string(&mut self, bytes: &[u8]) -> *const c_char {
+        let string = self.gc.allocate_string(bytes);
+        self.push(string);
+        self.c_str(-1, string)
+    }
+
+    // A NUL-terminated copy of `string`, which is at `idx`
+    fn c_str(&mut self, idx: c_int, string: LuaString<'gc>) -> *const c_char {
+        let bytes = string.as_bytes();
+        let key = self.slot(idx).unwrap_or(idx as isize);
+        let copy = self.strings.entry(key).or_default();
+        if copy.len() != bytes.len() + 1 || copy[..bytes.len()] != *bytes {
+            *copy = [bytes, &[0]].concat().into();
+        }
+        copy.as_ptr().cast()
+    }
+
+    // Drops the copies of strings in slots that were popped
+    fn release_strings(&mut self) {
+        self.strings.split_off(&(self.stack.len() as isize));
+    }
+
+    // Inside a C function, unwinds to where the function was called, which
+    // raises the error in Lua. Elsewhere, or when unwinding isn't
+    // available, the error goes to the panic handler.
+    fn raise(&mut self, kind: ErrorKind) -> ! {
+        #[cfg(panic = "unwind")]
+        if let Some(error) = &mut self.error {
+            **error = Some(kind);
+            std::panic::resume_unwind(Box::new(Raised));
+        }
+        let value = self.vm.error_value(self.gc, &kind);
+        let message = match value {
+            Value::String(s) => String::from_utf8_lossy(s.as_bytes()).into_owned(),
+            _ => format!("error object is a {} value", value.ty().name()),
+        };
+        eprintln!("PANIC: unprotected error in call to Lua API ({message})");
+        std::process::abort();
+    }
+
+    fn call(&mut self, callee: Value<'gc>, args: Vec<Value<'gc>>) -> Vec<Value<'gc>> {
+        match self.vm.call_value(self.gc, callee, args) {
+            Ok(results) => results,
+            Err(kind) => self.raise(kind),
+        }
+    }
+
+    fn call_helper(&mut self, helper: Helper, args: Vec<Value<'gc>>) -> Value<'gc> {
+        let key = self.gc.allocate_string(B("_CAPI"));
+        let registry = self.vm.registry();
+        let helpers = registry.borrow().get_field(key).as_table();
+        let helpers = match helpers {
+            Some(helpers) => helpers,
+            None => {
+                let closure = self.vm.load(self.gc, HELPERS, "=capi").unwrap();
+                let closure = self.gc.allocate(closure).into();
+                let functions = self.vm.call_value(self.gc, closure, Vec::new()).unwrap();
+                let helpers = self.gc.allocate_cell(Table::from(functions));
+                registry.borrow_mut(self.gc).set_field(key, helpers);
+                helpers
+            }
+        };
+        let function = helpers.borrow().get_integer_key(helper as Integer + 1);
+        self.call(function, args)
+            .first()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn get_table(&mut self, table: Value<'gc>, key: Value<'gc>) -> Value<'gc> {
+        if let Value::Table(t) = table {
+            let t = t.borrow();
+            let value = t.get(key);
+            if !value.is_nil() || t.metatable().is_none() {
+                return value;
+            }
+        }
+        self.call_helper(Helper::Index, vec![table, key])
+    }
+
+    fn set_table(&mut self, table: Value<'gc>, key: Value<'gc>, value: Value<'gc>) {
+        match table {
+            Value::Table(t) if t.borrow().metatable().is_none() => {
+                if let Err(err) = t.borrow_mut(self.gc).set(key, value) {
+                    self.raise(err.into());
+                }
+            }
+            _ => {
+                self.call_helper(Helper::NewIndex, vec![table, key, value]);
+            }
+        }
+    }
+
+    fn raw_table(&mut self, idx: c_int) -> GcCell<'gc, Table<'gc>> {
+        match self.get(idx).as_table() {
+            Some(table) => table,
+            None => self.raise(ErrorKind::other("table expected")),
+        }
+    }
+
+    // Pops the `n` values on top of the stack, raising an error instead of
+    // panicking if there aren't that many
+    fn pop_values(&mut self, n: c_int) -> Vec<Value<'gc>> {
+        match usize::try_from(n) {
+            Ok(n) if n <= self.stack.len() => self.stack.split_off(self.stack.len() - n),
+            _ => self.raise(ErrorKind::other("not enough elements in the stack")),
+        }
+    }
+
+    // Pops `nargs` arguments and the function below them
+    fn pop_call(&mut self, nargs: c_int) -> (Value<'gc>, Vec<Value<'gc>>) {
+        if usize::try_from(nargs).is_ok_and(|n| n >= self.stack.len()) {
+            self.raise(ErrorKind::other("not enough elements in the stack"));
+        }
+        let args = self.pop_values(nargs);
+        (self.pop(), args)
+    }
+
+    fn call_from_stack(&mut self, nargs: c_int) -> Vec<Value<'gc>> {
+        let (callee, args) = self.pop_call(nargs);
+        self.call(callee, args)
+    }
+
+    fn push_results(&mut self, results: Vec<Value<'gc>>, nresults: c_int) {
+        push_results(self.stack, results, nresults);
+    }
+
+    fn load(&mut self, chunk: &[u8], name: &[u8], mode: &[u8]) -> c_int {
+        let (kind, allowed) = if binary_chunk::is_binary_chunk(chunk) {
+            ("binary", mode.contains(&b'b'))
+        } else {
+            ("text", mode.contains(&b't'))
+        };
+        if !allowed {
+            let message = format!(
+                "attempt to load a {kind} chunk (mode is '{}')",
+                String::from_utf8_lossy(mode)
+            );
+            self.push_string(message.as_bytes());
+            return LUA_ERRSYNTAX;
+        }
+        match self.vm.load(self.gc, chunk, name) {
+            Ok(closure) => {
+                self.push(self.gc.allocate(closure));
+                LUA_OK
+            }
+            Err(err) => {
+                self.push_string(err.to_string().as_bytes());
+                LUA_ERRSYNTAX
+            }
+        }
+    }
+
+    fn type_name(&self, value: Value<'gc>) -> String {
+        let name = self.vm.metatable_of_object(value).map(|metatable| {
+            metatable
+                .borrow()
+                .get_field(self.gc.allocate_string(B("__name")))
+        });
+        match name {
+            Some(Value::String(name)) => String::from_utf8_lossy(name.as_bytes()).into_owned(),
+            _ => value.ty().name().to_owned(),
+        }
+    }
+
+    fn arg_error(&mut self, arg: c_int, message: &str) -> ! {
+        self.raise(ErrorKind::other(format!("bad argument #{arg} ({message})")));
+    }
+
+    fn type_error(&mut self, arg: c_int, expected: &str) -> ! {
+        let got = match self.index(arg) {
+            Some(value) => self.type_name(value),
+            None => "no value".to_owned(),
+        };
+        self.arg_error(arg, &format!("{expected} expected, got {got}"));
+    }
+
+    fn test_userdata(&self, idx: c_int, name: &[u8]) -> *mut c_void {
+        let Value::UserData(ud) = self.get(idx) else {
+            return std::ptr::null_mut();
+        };
+        let metatable = self
+            .vm
+            .registry()
+            .borrow()
+            .get_field(self.gc.allocate_string(name));
+        let metatable_of_ud = ud.borrow().metatable();
+        match (metatable_of_ud, metatable) {
+            (Some(a), Value::Table(b)) if GcCell::ptr_eq(&a, &b) => to_userdata(self.get(idx)),
+            _ => std::ptr::null_mut(),
+        }
+    }
+}
+
+// Memory of a userdata created with lua_newuserdatauv
+struct CUserData {
+    ptr: NonNull<u8>,
+    size: usize,
+}
+
+unsafe impl Send for CUserData {}
+
+impl CUserData {
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size.max(1), 16).unwrap()
+    }
+}
+
+impl Drop for CUserData {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.size)) };
+    }
+}
+
+fn push_results<'gc>(stack: &mut Vec<Value<'gc>>, mut results: Vec<Value<'gc>>, nresults: c_int) {
+    if nresults != LUA_MULTRET {
+        results.resize(nresults as usize, Value::Nil);
+    }
+    stack.append(&mut results);
+}
+
+// Calls the function below `nargs` arguments on the stack of a state from
+// luaL_newstate on its runtime, which collects the heap during the call
+// unlike Vm::call_value. With `msgh`, the call is protected as in lua_pcallk.
+unsafe fn call_on_runtime(
+    state: *mut lua_State,
+    nargs: c_int,
+    nresults: c_int,
+    msgh: Option<c_int>,
+) -> c_int {
+    let status = Arc::new(AtomicI32::new(LUA_OK));
+    let result = {
+        let lua_State { kind, strings, .. } = &mut *state;
+        let StateKind::Owned { runtime, stack } = kind else {
+            unreachable!()
+        };
+        let sink = status.clone();
+        let started = runtime.start(|gc, vm| {
+            let mut vm = vm.borrow_mut(gc);
+            let thread = gc.registry_value(stack).as_thread().unwrap();
+            let mut thread_ref = thread.borrow_mut(gc);
+            let mut api = Api {
+                gc,
+                vm: &mut vm,
+                stack: &mut thread_ref.stack,
+                upvalues: None,
+                error: None,
+                strings,
+            };
+            let handler = msgh.filter(|&msgh| msgh != 0).map(|msgh| api.get(msgh));
+            let (callee, args) = api.pop_call(nargs);
+            api.release_strings();
+
+            let upvalue = ((callee, args), (handler, thread));
+            let call = NativeClosure::with_upvalue(upvalue, move |_, _, upvalue, _| {
+                let ((callee, args), (handler, thread)) = upvalue;
+                let (callee, args) = (*callee, args.clone());
+                if msgh.is_none() {
+                    let continuation =
+                        Continuation::with_context(*thread, move |gc, _, thread, results| {
+                            push_results(&mut thread.borrow_mut(gc).stack, results, nresults);
+                            Ok(Action::Return(Vec::new()))
+                        });
+                    return Ok(Action::Call {
+                        callee,
+                        args,
+                        continuation,
+                    });
+                }
+                let sink = sink.clone();
+                let context = (*handler, *thread);
+                let continuation = Continuation::with_context(
+                    context,
+                    move |gc, vm, (handler, thread), result| {
+                        let kind = match result {
+                            Ok(results) => {
+                                push_results(&mut thread.borrow_mut(gc).stack, results, nresults);
+                                return Ok(Action::Return(Vec::new()));
+                            }
+                            Err(kind) => kind,
+                        };
+                        let value = vm.error_value(gc, &kind);
+                        let Some(handler) = handler else {
+                            sink.store(LUA_ERRRUN, Ordering::Relaxed);
+                            thread.borrow_mut(gc).stack.push(value);
+                            return Ok(Action::Return(Vec::new()));
+                        };
+                        let sink = sink.clone();
+                        let continuation = Continuation::with_context(
+                            thread,
+                            move |gc, vm, thread, result: Result<Vec<Value>, _>| {
+                                let (value, status) = match result {
+                                    Ok(results) => {
+                                        (results.first().copied().unwrap_or_default(), LUA_ERRRUN)
+                                    }
+                                    Err(kind) => (vm.error_value(gc, &kind), LUA_ERRERR),
+                                };
+                                sink.store(status, Ordering::Relaxed);
+                                thread.borrow_mut(gc).stack.push(value);
+                                Ok(Action::Return(Vec::new()))
+                            },
+                        );
+                        Ok(Action::ProtectedCall {
+                            callee: handler,
+                            args: vec![value],
+                            continuation,
+                        })
+                    },
+                );
+                Ok(Action::ProtectedCall {
+                    callee,
+                    args,
+                    continuation,
+                })
+            });
+            Ok(gc.allocate(call).into())
+        });
+        started.and_then(|()| runtime.finish())
+    };
+
+    // Errors the call can't catch, like internal errors
+    match result {
+        Ok(()) => status.load(Ordering::Relaxed),
+        Err(err) if msgh.is_some() => with(state, |api| {
+            let value = api.vm.error_value(api.gc, &err.kind);
+            api.push(value);
+            LUA_ERRRUN
+        }),
+        Err(err) => with(state, |api| api.raise(err.kind)),
+    }
+}
+
+fn to_userdata(value: Value) -> *mut c_void {
+    match value {
+        Value::UserData(ud) => ud
+            .borrow()
+            .get::<CUserData>()
+            .map_or(std::ptr::null_mut(), |data| data.ptr.as_ptr().cast()),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+fn type_of(value: Value) -> c_int {
+    match value.ty() {
+        Type::Nil => LUA_TNIL,
+        Type::Boolean => LUA_TBOOLEAN,
+        Type::Number => LUA_TNUMBER,
+        Type::String => LUA_TSTRING,
+        Type::Table => LUA_TTABLE,
+        Type::Function => LUA_TFUNCTION,
+        Type::UserData => LUA_TUSERDATA,
+        Type::Thread => LUA_TTHREAD,
+    }
+}
+
+unsafe fn bytes<'a>(s: *const c_char) -> &'a [u8] {
+    CStr::from_ptr(s).to_bytes()
+}
+
+fn c_function<'gc>(gc: &'gc GcContext, f: lua_CFunction, upvalues: Vec<Value<'gc>>) -> Value<'gc> {
+    let upvalues = gc.allocate_cell(Table::from(upvalues));
+    let closure = NativeClosure::with_upvalue(upvalues, move |gc, vm, upvalues, mut args| {
+        let mut stack = args.split_off(1);
+        let mut state = lua_State {
+            kind: StateKind::Call {
+                gc,
+                vm: (vm as *mut Vm<'_>).cast(),
+                stack: (&mut stack as *mut Vec<Value<'_>>).cast(),
+                upvalues: (upvalues as *const GcCell<'_, Table<'_>>).cast(),
+            },
+            error: None,
+            strings: BTreeMap::new(),
+        };
+        #[cfg(panic = "unwind")]
+        let n = match std::panic::catch_unwind(AssertUnwindSafe(|| unsafe { f(&mut state) })) {
+            Ok(n) => n,
+            Err(payload) if payload.is::<Raised>() => return Err(state.error.unwrap()),
+            Err(payload) => std::panic::resume_unwind(payload),
+        };
+        #[cfg(not(panic = "unwind"))]
+        let n = unsafe { f(&mut state) };
+        let n = (n.max(0) as usize).min(stack.len());
+        Ok(crate::runtime::Action::Return(
+            stack.split_off(stack.len() - n),
+        ))
+    });
+    gc.allocate(closure).into()
+}
+
+// State manipulation
+
+#[no_mangle]
+pub extern "C-unwind" fn luaL_newstate() -> *mut lua_State {
+    let mut runtime = Box::new(Runtime::new());
+    let stack = runtime
+        .heap()
+        .with(|gc, _| gc.create_registry_value(gc.allocate_cell(LuaThread::new()).into()));
+    Box::into_raw(Box::new(lua_State {
+        kind: StateKind::Owned { runtime, stack },
+        error: None,
+        strings: BTreeMap::new(),
+    }))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_close(state: *mut lua_State) {
+    drop(Box::from_raw(state));
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_openlibs(state: *mut lua_State) {
+    with(state, |api| api.vm.load_stdlib(api.gc));
+}
+
+#[no_mangle]
+pub extern "C-unwind" fn lua_version(_: *mut lua_State) -> lua_Number {
+    504.0
+}
+
+#[no_mangle]
+pub extern "C-unwind" fn luaL_checkversion_(_: *mut lua_State, _: lua_Number, _: usize) {}
+
+// Basic stack manipulation
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_absindex(state: *mut lua_State, idx: c_int) -> c_int {
+    with(state, |api| {
+        if idx > 0 || idx <= LUA_REGISTRYINDEX {
+            idx
+        } else {
+            api.stack.len() as c_int + idx + 1
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_gettop(state: *mut lua_State) -> c_int {
+    with(state, |api| api.stack.len() as c_int)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_settop(state: *mut lua_State, idx: c_int) {
+    with(state, |api| {
+        let len = if idx >= 0 {
+            idx as usize
+        } else {
+            let len = api.stack.len() as isize + idx as isize + 1;
+            match usize::try_from(len) {
+                Ok(len) => len,
+                Err(_) => api.raise(ErrorKind::other("invalid new top")),
+            }
+        };
+        api.stack.resize(len, Value::Nil);
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushvalue(state: *mut lua_State, idx: c_int) {
+    with(state, |api| api.push(api.get(idx)));
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rotate(state: *mut lua_State, idx: c_int, n: c_int) {
+    with(state, |api| {
+        let start = if idx > 0 {
+            idx as usize - 1
+        } else {
+            (api.stack.len() as isize + idx as isize) as usize
+        };
+        let segment = &mut api.stack[start..];
+        if !segment.is_empty() {
+            let n = (n as isize).rem_euclid(segment.len() as isize) as usize;
+            segment.rotate_right(n);
+        }
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_copy(state: *mut lua_State, from: c_int, to: c_int) {
+    with(state, |api| api.set(to, api.get(from)));
+}
+
+#[no_mangle]
+pub extern "C-unwind" fn lua_checkstack(_: *mut lua_State, _: c_int) -> c_int {
+    1
+}
+
+// Access functions
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_isnumber(state: *mut lua_State, idx: c_int) -> c_int {
+    with(state, |api| api.get(idx).coerce_to_float().is_some().into())
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_isstring(state: *mut lua_State, idx: c_int) -> c_int {
+    with(state, |api| {
+        matches!(
+            api.get(idx),
+            Value::String(_) | Value::Integer(_) | Value::Number(_)
+        )
+        .into()
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_iscfunction(state: *mut lua_State, idx: c_int) -> c_int {
+    with(state, |api| {
+        matches!(
+            api.get(idx),
+            Value::NativeFunction(_) | Value::NativeClosure(_)
+        )
+        .into()
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_isinteger(state: *mut lua_State, idx: c_int) -> c_int {
+    with(state, |api| {
+        matches!(api.get(idx), Value::Integer(_)).into()
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_isuserdata(state: *mut lua_State, idx: c_int) -> c_int {
+    with(state, |api| {
+        matches!(api.get(idx), Value::UserData(_)).into()
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_type(state: *mut lua_State, idx: c_int) -> c_int {
+    with(state, |api| api.index(idx).map_or(LUA_TNONE, type_of))
+}
+
+#[no_mangle]
+pub extern "C-unwind" fn lua_typename(_: *mut lua_State, tp: c_int) -> *const c_char {
+    let name = match tp {
+        LUA_TNIL => c"nil",
+        LUA_TBOOLEAN => c"boolean",
+        2 | LUA_TUSERDATA => c"userdata",
+        LUA_TNUMBER => c"number",
+        LUA_TSTRING => c"string",
+        LUA_TTABLE => c"table",
+        LUA_TFUNCTION => c"function",
+        LUA_TTHREAD => c"thread",
+        _ => c"no value",
+    };
+    name.as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_tonumberx(
+    state: *mut lua_State,
+    idx: c_int,
+    isnum: *mut c_int,
+) -> lua_Number {
+    let n = with(state, |api| api.get(idx).coerce_to_float());
+    if !isnum.is_null() {
+        *isnum = n.is_some().into();
+    }
+    n.unwrap_or_default()
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_tointegerx(
+    state: *mut lua_State,
+    idx: c_int,
+    isnum: *mut c_int,
+) -> lua_Integer {
+    let n = with(state, |api| api.get(idx).coerce_to_integer());
+    if !isnum.is_null() {
+        *isnum = n.is_some().into();
+    }
+    n.unwrap_or_default()
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_toboolean(state: *mut lua_State, idx: c_int) -> c_int {
+    with(state, |api| api.get(idx).is_truthy().into())
+}
+
+// Numbers are converted to strings in place
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_tolstring(
+    state: *mut lua_State,
+    idx: c_int,
+    len: *mut usize,
+) -> *const c_char {
+    let (ptr, n) = with(state, |api| {
+        let string = match api.get(idx) {
+            Value::String(s) => s,
+            value @ (Value::Integer(_) | Value::Number(_)) => {
+                let bytes = value.coerce_to_string_with(api.vm.float_format()).unwrap();
+                let string = api.gc.allocate_string(bytes);
+                api.set(idx, string.into());
+                string
+            }
+            _ => return (std::ptr::null(), 0),
+        };
+        (api.c_str(idx, string), string.as_bytes().len())
+    });
+    if !len.is_null() {
+        *len = n;
+    }
+    ptr
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawlen(state: *mut lua_State, idx: c_int) -> u64 {
+    with(state, |api| match api.get(idx) {
+        Value::String(s) => s.as_bytes().len() as u64,
+        Value::Table(t) => t.borrow().lua_len() as u64,
+        Value::UserData(ud) => ud
+            .borrow()
+            .get::<CUserData>()
+            .map_or(0, |data| data.size as u64),
+        _ => 0,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_touserdata(state: *mut lua_State, idx: c_int) -> *mut c_void {
+    with(state, |api| to_userdata(api.get(idx)))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_topointer(state: *mut lua_State, idx: c_int) -> *const c_void {
+    with(state, |api| {
+        let value = api.get(idx);
+        match to_userdata(value) {
+            ptr if !ptr.is_null() => ptr.cast_const(),
+            _ => value.as_ptr().map_or(std::ptr::null(), |ptr| ptr.cast()),
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawequal(
+    state: *mut lua_State,
+    idx1: c_int,
+    idx2: c_int,
+) -> c_int {
+    with(state, |api| match (api.index(idx1), api.index(idx2)) {
+        (Some(a), Some(b)) => (a == b).into(),
+        _ => 0,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_compare(
+    state: *mut lua_State,
+    idx1: c_int,
+    idx2: c_int,
+    op: c_int,
+) -> c_int {
+    with(state, |api| {
+        let (Some(a), Some(b)) = (api.index(idx1), api.index(idx2)) else {
+            return 0;
+        };
+        let helper = match op {
+            LUA_OPEQ => Helper::Eq,
+            LUA_OPLT => Helper::Lt,
+            LUA_OPLE => Helper::Le,
+            _ => return 0,
+        };
+        api.call_helper(helper, vec![a, b]).is_truthy().into()
+    })
+}
+
+// Push functions
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushnil(state: *mut lua_State) {
+    with(state, |api| api.push(Value::Nil));
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushnumber(state: *mut lua_State, n: lua_Number) {
+    with(state, |api| api.push(Value::Number(n)));
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushinteger(state: *mut lua_State, n: lua_Integer) {
+    with(state, |api| api.push(Value::Integer(n)));
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushlstring(
+    state: *mut lua_State,
+    s: *const c_char,
+    len: usize,
+) -> *const c_char {
+    let bytes = if len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(s.cast(), len)
+    };
+    with(state, |api| api.push_string(bytes))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushstring(
+    state: *mut lua_State,
+    s: *const c_char,
+) -> *const c_char {
+    with(state, |api| {
+        if s.is_null() {
+            api.push(Value::Nil);
+            std::ptr::null()
+        } else {
+            api.push_string(bytes(s))
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushcclosure(
+    state: *mut lua_State,
+    f: lua_CFunction,
+    n: c_int,
+) {
+    with(state, |api| {
+        let upvalues = api.pop_values(n);
+        api.push(c_function(api.gc, f, upvalues));
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushboolean(state: *mut lua_State, b: c_int) {
+    with(state, |api| api.push(b != 0));
+}
+
+// Get functions
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_getglobal(state: *mut lua_State, name: *const c_char) -> c_int {
+    with(state, |api| {
+        let key = api.gc.allocate_string(bytes(name));
+        let value = api.get_table(api.vm.globals().into(), key.into());
+        api.push(value);
+        type_of(value)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_gettable(state: *mut lua_State, idx: c_int) -> c_int {
+    with(state, |api| {
+        let table = api.get(idx);
+        let key = api.pop();
+        let value = api.get_table(table, key);
+        api.push(value);
+        type_of(value)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_getfield(
+    state: *mut lua_State,
+    idx: c_int,
+    k: *const c_char,
+) -> c_int {
+    with(state, |api| {
+        let key = api.gc.allocate_string(bytes(k));
+        let value = api.get_table(api.get(idx), key.into());
+        api.push(value);
+        type_of(value)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_geti(
+    state: *mut lua_State,
+    idx: c_int,
+    n: lua_Integer,
+) -> c_int {
+    with(state, |api| {
+        let value = api.get_table(api.get(idx), n.into());
+        api.push(value);
+        type_of(value)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawget(state: *mut lua_State, idx: c_int) -> c_int {
+    with(state, |api| {
+        let key = api.pop();
+        let value = api.raw_table(idx).borrow().get(key);
+        api.push(value);
+        type_of(value)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawgeti(
+    state: *mut lua_State,
+    idx: c_int,
+    n: lua_Integer,
+) -> c_int {
+    with(state, |api| {
+        let value = api.raw_table(idx).borrow().get_integer_key(n);
+        api.push(value);
+        type_of(value)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_createtable(state: *mut lua_State, narr: c_int, nrec: c_int) {
+    with(state, |api| {
+        let table = Table::with_capacity(narr.max(0) as usize, nrec.max(0) as usize);
+        api.push(api.gc.allocate_cell(table));
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_newuserdatauv(
+    state: *mut lua_State,
+    size: usize,
+    _nuvalue: c_int,
+) -> *mut c_void {
+    let Some(ptr) = NonNull::new(std::alloc::alloc_zeroed(CUserData::layout(size))) else {
+        std::alloc::handle_alloc_error(CUserData::layout(size));
+    };
+    with(state, |api| {
+        let userdata = api.gc.allocate_userdata(CUserData { ptr, size });
+        api.push(userdata);
+    });
+    ptr.as_ptr().cast()
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_getmetatable(state: *mut lua_State, idx: c_int) -> c_int {
+    with(state, |api| {
+        match api.vm.metatable_of_object(api.get(idx)) {
+            Some(metatable) => {
+                api.push(metatable);
+                1
+            }
+            None => 0,
+        }
+    })
+}
+
+// Set functions
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_setglobal(state: *mut lua_State, name: *const c_char) {
+    with(state, |api| {
+        let value = api.pop();
+        let key = api.gc.allocate_string(bytes(name));
+        api.set_table(api.vm.globals().into(), key.into(), value);
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_settable(state: *mut lua_State, idx: c_int) {
+    with(state, |api| {
+        let table = api.get(idx);
+        let value = api.pop();
+        let key = api.pop();
+        api.set_table(table, key, value);
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_setfield(state: *mut lua_State, idx: c_int, k: *const c_char) {
+    with(state, |api| {
+        let table = api.get(idx);
+        let value = api.pop();
+        let key = api.gc.allocate_string(bytes(k));
+        api.set_table(table, key.into(), value);
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_seti(state: *mut lua_State, idx: c_int, n: lua_Integer) {
+    with(state, |api| {
+        let table = api.get(idx);
+        let value = api.pop();
+        api.set_table(table, n.into(), value);
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawset(state: *mut lua_State, idx: c_int) {
+    with(state, |api| {
+        let table = api.raw_table(idx);
+        let value = api.pop();
+        let key = api.pop();
+        let result = table.borrow_mut(api.gc).set(key, value);
+        if let Err(err) = result {
+            api.raise(err.into());
+        }
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawseti(state: *mut lua_State, idx: c_int, n: lua_Integer) {
+    with(state, |api| {
+        let table = api.raw_table(idx);
+        let value = api.pop();
+        let result = table.borrow_mut(api.gc).set(n, value);
+        if let Err(err) = result {
+            api.raise(err.into());
+        }
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_setmetatable(state: *mut lua_State, idx: c_int) -> c_int {
+    with(state, |api| {
+        let object = api.get(idx);
+        let metatable = api.pop().as_table();
+        match object {
+            Value::Table(table) => table.borrow_mut(api.gc).set_metatable(metatable),
+            Value::UserData(ud) => ud.borrow_mut(api.gc).set_metatable(metatable),
+            _ => api.vm.set_metatable_of_type(object.ty(), metatable),
+        }
+        1
+    })
+}
+
+// Load and call functions
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_callk(
+    state: *mut lua_State,
+    nargs: c_int,
+    nresults: c_int,
+    _ctx: lua_KContext,
+    _k: Option<lua_KFunction>,
+) {
+    if let StateKind::Owned { .. } = (*state).kind {
+        call_on_runtime(state, nargs, nresults, None);
+        return;
+    }
+    with(state, |api| {
+        let results = api.call_from_stack(nargs);
+        api.push_results(results, nresults);
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pcallk(
+    state: *mut lua_State,
+    nargs: c_int,
+    nresults: c_int,
+    msgh: c_int,
+    _ctx: lua_KContext,
+    _k: Option<lua_KFunction>,
+) -> c_int {
+    if let StateKind::Owned { .. } = (*state).kind {
+        return call_on_runtime(state, nargs, nresults, Some(msgh));
+    }
+    with(state, |api| {
+        let handler = (msgh != 0).then(|| api.get(msgh));
+        let (callee, args) = api.pop_call(nargs);
+        let kind = match api.vm.call_value(api.gc, callee, args) {
+            Ok(results) => {
+                api.push_results(results, nresults);
+                return LUA_OK;
+            }
+            Err(kind) => kind,
+        };
+        let value = api.vm.error_value(api.gc, &kind);
+        let (value, status) = match handler {
+            Some(handler) => match api.vm.call_value(api.gc, handler, vec![value]) {
+                Ok(results) => (results.first().copied().unwrap_or_default(), LUA_ERRRUN),
+                Err(kind) => (api.vm.error_value(api.gc, &kind), LUA_ERRERR),
+            },
+            None => (value, LUA_ERRRUN),
+        };
+        api.push(value);
+        status
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_loadbufferx(
+    state: *mut lua_State,
+    buff: *const c_char,
+    size: usize,
+    name: *const c_char,
+    mode: *const c_char,
+) -> c_int {
+    let chunk = if size == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(buff.cast(), size)
+    };
+    let name = if name.is_null() { chunk } else { bytes(name) };
+    let mode = if mode.is_null() { B("bt") } else { bytes(mode) };
+    with(state, |api| api.load(chunk, name, mode))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_loadstring(state: *mut lua_State, s: *const c_char) -> c_int {
+    luaL_loadbufferx(state, s, bytes(s).len(), s, std::ptr::null())
+}
+
+// Reads standard input if `filename` is NULL. A first line starting with #
+// is skipped.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_loadfilex(
+    state: *mut lua_State,
+    filename: *const c_char,
+    mode: *const c_char,
+) -> c_int {
+    let mode = if mode.is_null() { B("bt") } else { bytes(mode) };
+    let (name, contents) = if filename.is_null() {
+        let mut contents = Vec::new();
+        let result = std::io::stdin().read_to_end(&mut contents);
+        (b"=stdin".to_vec(), result.map(|_| contents))
+    } else {
+        let filename = bytes(filename);
+        let path = String::from_utf8_lossy(filename).into_owned();
+        ([B("@"), filename].concat(), std::fs::read(path))
+    };
+    with(state, |api| {
+        let contents = match contents {
+            Ok(contents) => contents,
+            Err(err) => {
+                let message = format!("cannot open {}: {err}", String::from_utf8_lossy(&name[1..]));
+                api.push_string(message.as_bytes());
+                return LUA_ERRFILE;
+            }
+        };
+        let chunk = match contents.first() {
+            Some(b'#') => {
+                let start = contents.iter().position(|&b| b == b'\n');
+                &contents[start.unwrap_or(contents.len())..]
+            }
+            _ => &contents[..],
+        };
+        api.load(chunk, &name, mode)
+    })
+}
+
+// Miscellaneous functions
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_error(state: *mut lua_State) -> c_int {
+    with(state, |api| {
+        let value = api.pop();
+        let kind = api.vm.error_from_object(api.gc, value);
+        api.raise(kind)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_next(state: *mut lua_State, idx: c_int) -> c_int {
+    with(state, |api| {
+        let key = api.pop();
+        let table = api.raw_table(idx);
+        let next = table.borrow().next(key);
+        match next {
+            Ok(Some((key, value))) => {
+                api.push(key);
+                api.push(value);
+                1
+            }
+            Ok(None) => 0,
+            Err(err) => api.raise(err.into()),
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_concat(state: *mut lua_State, n: c_int) {
+    with(state, |api| {
+        if n == 0 {
+            api.push_string(b"");
+            return;
+        }
+        let mut values = api.pop_values(n);
+        let mut result = values.pop().unwrap();
+        let float_format = api.vm.float_format();
+        for value in values.into_iter().rev() {
+            let strings = (
+                value.coerce_to_string_with(float_format),
+                result.coerce_to_string_with(float_format),
+            );
+            result = match strings {
+                (Some(a), Some(b)) => api.gc.allocate_string([a, b].concat()).into(),
+                _ => api.call_helper(Helper::Concat, vec![value, result]),
+            };
+        }
+        api.push(result);
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_len(state: *mut lua_State, idx: c_int) {
+    with(state, |api| {
+        let value = api.get(idx);
+        let len = match value {
+            Value::String(s) => (s.as_bytes().len() as Integer).into(),
+            Value::Table(t) if t.borrow().metatable().is_none() => t.borrow().lua_len().into(),
+            _ => api.call_helper(Helper::Len, vec![value]),
+        };
+        api.push(len);
+    });
+}
+
+// Auxiliary library
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_argerror(
+    state: *mut lua_State,
+    arg: c_int,
+    extramsg: *const c_char,
+) -> c_int {
+    let message = String::from_utf8_lossy(bytes(extramsg));
+    with(state, |api| api.arg_error(arg, &message))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_typeerror(
+    state: *mut lua_State,
+    arg: c_int,
+    tname: *const c_char,
+) -> c_int {
+    let tname = String::from_utf8_lossy(bytes(tname));
+    with(state, |api| api.type_error(arg, &tname))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checktype(state: *mut lua_State, arg: c_int, t: c_int) {
+    with(state, |api| {
+        if api.index(arg).map_or(LUA_TNONE, type_of) != t {
+            let expected = CStr::from_ptr(lua_typename(state, t)).to_string_lossy();
+            api.type_error(arg, &expected);
+        }
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkany(state: *mut lua_State, arg: c_int) {
+    with(state, |api| {
+        if api.index(arg).is_none() {
+            api.arg_error(arg, "value expected");
+        }
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkinteger(
+    state: *mut lua_State,
+    arg: c_int,
+) -> lua_Integer {
+    with(state, |api| {
+        let value = api.get(arg);
+        match value.coerce_to_integer() {
+            Some(n) => n,
+            None if value.coerce_to_float().is_some() => {
+                api.arg_error(arg, "number has no integer representation")
+            }
+            None => api.type_error(arg, "number"),
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_optinteger(
+    state: *mut lua_State,
+    arg: c_int,
+    def: lua_Integer,
+) -> lua_Integer {
+    if lua_type(state, arg) <= LUA_TNIL {
+        def
+    } else {
+        luaL_checkinteger(state, arg)
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checknumber(state: *mut lua_State, arg: c_int) -> lua_Number {
+    with(state, |api| match api.get(arg).coerce_to_float() {
+        Some(n) => n,
+        None => api.type_error(arg, "number"),
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_optnumber(
+    state: *mut lua_State,
+    arg: c_int,
+    def: lua_Number,
+) -> lua_Number {
+    if lua_type(state, arg) <= LUA_TNIL {
+        def
+    } else {
+        luaL_checknumber(state, arg)
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checklstring(
+    state: *mut lua_State,
+    arg: c_int,
+    len: *mut usize,
+) -> *const c_char {
+    let s = lua_tolstring(state, arg, len);
+    if s.is_null() {
+        with::<_, ()>(state, |api| api.type_error(arg, "string"));
+    }
+    s
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_optlstring(
+    state: *mut lua_State,
+    arg: c_int,
+    def: *const c_char,
+    len: *mut usize,
+) -> *const c_char {
+    if lua_type(state, arg) > LUA_TNIL {
+        return luaL_checklstring(state, arg, len);
+    }
+    if !len.is_null() {
+        *len = if def.is_null() { 0 } else { bytes(def).len() };
+    }
+    def
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_newmetatable(
+    state: *mut lua_State,
+    tname: *const c_char,
+) -> c_int {
+    with(state, |api| {
+        let name = api.gc.allocate_string(bytes(tname));
+        let registry = api.vm.registry();
+        let existing = registry.borrow().get_field(name);
+        if !existing.is_nil() {
+            api.push(existing);
+            return 0;
+        }
+        let mut metatable = Table::new();
+        metatable.set_field(api.gc.allocate_string(B("__name")), name);
+        let metatable = api.gc.allocate_cell(metatable);
+        registry.borrow_mut(api.gc).set_field(name, metatable);
+        api.push(metatable);
+        1
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_setmetatable(state: *mut lua_State, tname: *const c_char) {
+    lua_getfield(state, LUA_REGISTRYINDEX, tname);
+    lua_setmetatable(state, -2);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_testudata(
+    state: *mut lua_State,
+    ud: c_int,
+    tname: *const c_char,
+) -> *mut c_void {
+    with(state, |api| api.test_userdata(ud, bytes(tname)))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkudata(
+    state: *mut lua_State,
+    ud: c_int,
+    tname: *const c_char,
+) -> *mut c_void {
+    with(state, |api| {
+        let ptr = api.test_userdata(ud, bytes(tname));
+        if ptr.is_null() {
+            api.type_error(ud, &String::from_utf8_lossy(bytes(tname)));
+        }
+        ptr
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_getmetafield(
+    state: *mut lua_State,
+    obj: c_int,
+    e: *const c_char,
+) -> c_int {
+    with(state, |api| {
+        let Some(metatable) = api.vm.metatable_of_object(api.get(obj)) else {
+            return LUA_TNIL;
+        };
+        let value = metatable
+            .borrow()
+            .get_field(api.gc.allocate_string(bytes(e)));
+        if !value.is_nil() {
+            api.push(value);
+        }
+        type_of(value)
+    })
+}
+
+// Pushes the value at `idx` as a string, converted like tostring does
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_tolstring(
+    state: *mut lua_State,
+    idx: c_int,
+    len: *mut usize,
+) -> *const c_char {
+    if luaL_getmetafield(state, idx, c"__tostring".as_ptr()) != LUA_TNIL {
+        lua_pushvalue(state, idx);
+        lua_callk(state, 1, 1, 0, None);
+        if lua_isstring(state, -1) == 0 {
+            with::<_, ()>(state, |api| {
+                api.raise(ErrorKind::other("'__tostring' must return a string"))
+            });
+        }
+    } else {
+        with(state, |api| {
+            let value = api.get(idx);
+            let string = match value {
+                Value::Nil => B("nil").to_vec(),
+                Value::Boolean(b) => b.to_string().into_bytes(),
+                Value::String(_) | Value::Integer(_) | Value::Number(_) => value
+                    .coerce_to_string_with(api.vm.float_format())
+                    .unwrap()
+                    .into_owned(),
+                _ => {
+                    let ptr = value.as_ptr().unwrap_or(std::ptr::null());
+                    format!("{}: {ptr:p}", api.type_name(value)).into_bytes()
+                }
+            };
+            api.push_string(&string);
+        });
+    }
+    lua_tolstring(state, -1, len)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_len(state: *mut lua_State, idx: c_int) -> lua_Integer {
+    lua_len(state, idx);
+    with(state, |api| match api.pop() {
+        Value::Integer(n) => n,
+        _ => api.raise(ErrorKind::other("object length is not an integer")),
+    })
+}
+
+// References are kept in the table at t[1], t[2] and so on, with t[0] the
+// first of the freed ones
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_ref(state: *mut lua_State, t: c_int) -> c_int {
+    with(state, |api| {
+        let value = api.pop();
+        if value.is_nil() {
+            return LUA_REFNIL;
+        }
+        let table = api.raw_table(t);
+        let mut table = table.borrow_mut(api.gc);
+        let reference = match table.get_integer_key(0) {
+            Value::Integer(free) if free > 0 => {
+                let next = table.get_integer_key(free);
+                table.set_integer_key(0, next);
+                free
+            }
+            _ => table.lua_len() + 1,
+        };
+        table.set_integer_key(reference, value);
+        reference as c_int
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_unref(state: *mut lua_State, t: c_int, reference: c_int) {
+    if reference < 0 {
+        return;
+    }
+    with(state, |api| {
+        let table = api.raw_table(t);
+        let mut table = table.borrow_mut(api.gc);
+        let free = table.get_integer_key(0);
+        table.set_integer_key(reference as Integer, free);
+        table.set_integer_key(0, reference as Integer);
+    });
+}
+
+// Sets the functions in `l`, which ends with a NULL name, in the table below
+// `nup` upvalues shared by all of them, and pops the upvalues
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_setfuncs(
+    state: *mut lua_State,
+    mut l: *const luaL_Reg,
+    nup: c_int,
+) {
+    while !(*l).name.is_null() {
+        match (*l).func {
+            Some(func) => {
+                for _ in 0..nup {
+                    lua_pushvalue(state, -nup);
+                }
+                lua_pushcclosure(state, func, nup);
+            }
+            None => lua_pushboolean(state, 0),
+        }
+        lua_setfield(state, -(nup + 2), (*l).name);
+        l = l.add(1);
+    }
+    lua_settop(state, -nup - 1);
+}
@@ -0,0 +1,316 @@
+use crate::{
+    gc::{GcCell, GcContext},
+    lua::Lua,
+    runtime::{Action, ErrorKind, Vm},
+    stdlib::helpers::ArgumentsExt,
+    types::{DetachedValue, NativeFunction, Number, Table, Value},
+};
+use bstr::B;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+// Queue of messages between Lua states on different threads. Each state has
+// a heap of its own and messages are copied into the receiving one, so
+// states never share objects. A message is the values given to one send,
+// which can contain Lua functions but lose their upvalues on the way.
+#[derive(Clone, Default)]
+pub struct Channel(Arc<Shared>);
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<VecDeque<Vec<DetachedValue>>>,
+    not_empty: Condvar,
+}
+
+impl Channel {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn send(&self, message: Vec<DetachedValue>) {
+        self.queue().push_back(message);
+        self.0.not_empty.notify_one();
+    }
+
+    // Waits until there is a message
+    pub fn receive(&self) -> Vec<DetachedValue> {
+        let queue = self.queue();
+        let mut queue = self
+            .0
+            .not_empty
+            .wait_while(queue, |queue| queue.is_empty())
+            .unwrap();
+        queue.pop_front().unwrap()
+    }
+
+    // None if no message arrives before `timeout`
+    pub fn receive_timeout(&self, timeout: Duration) -> Option<Vec<DetachedValue>> {
+        let queue = self.queue();
+        let (mut queue, _) = self
+            .0
+            .not_empty
+            .wait_timeout_while(queue, timeout, |queue| queue.is_empty())
+            .unwrap();
+        queue.pop_front()
+    }
+
+    pub fn try_receive(&self) -> Option<Vec<DetachedValue>> {
+        self.queue().pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue().is_empty()
+    }
+
+    // The channel as a value scripts can call send and receive on
+    pub fn to_lua<'gc>(&self, gc: &'gc GcContext, vm: &mut Vm<'gc>) -> Value<'gc> {
+        if vm.userdata_metatable::<Channel>().is_none() {
+            let metatable = create_metatable(
+                gc,
+                "channel",
+                &[
+                    (B("send"), channel_send),
+                    (B("receive"), channel_receive),
+                    (B("len"), channel_len),
+                ],
+            );
+            vm.set_userdata_metatable::<Channel>(metatable);
+        }
+        vm.create_userdata(gc, self.clone()).into()
+    }
+
+    fn queue(&self) -> MutexGuard<'_, VecDeque<Vec<DetachedValue>>> {
+        self.0.queue.lock().unwrap()
+    }
+}
+
+// Runs `f` on a new thread with a Lua state of its own, which has the
+// standard library and the channel library
+pub fn spawn<F, R>(f: F) -> JoinHandle<R>
+where
+    F: 'static + Send + FnOnce(&mut Lua) -> R,
+    R: 'static + Send,
+{
+    thread::spawn(move || {
+        let mut lua = Lua::new();
+        lua.with(open);
+        f(&mut lua)
+    })
+}
+
+// Sets the global `channel` table. channel.new() creates a channel, and
+// channel.spawn(f, ...) calls the Lua function f with the rest of the
+// arguments in a new state on another thread. Channels given as arguments
+// are shared with it, and other arguments are copied.
+pub fn open<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) {
+    let mut table = Table::new();
+    table.set_field(
+        gc.allocate_string(B("new")),
+        NativeFunction::new(channel_new),
+    );
+    table.set_field(
+        gc.allocate_string(B("spawn")),
+        NativeFunction::new(channel_spawn),
+    );
+    vm.globals()
+        .borrow_mut(gc)
+        .set_field(gc.allocate_string(B("channel")), gc.allocate_cell(table));
+}
+
+type NativeFunctionPtr =
+    for<'gc> fn(&'gc GcContext, &mut Vm<'gc>, Vec<Value<'gc>>) -> Result<Action<'gc>, ErrorKind>;
+
+fn create_metatable<'gc>(
+    gc: &'gc GcContext,
+    name: &str,
+    methods: &[(&[u8], NativeFunctionPtr)],
+) -> GcCell<'gc, Table<'gc>> {
+    let mut index = Table::new();
+    for (name, method) in methods {
+        index.set_field(gc.allocate_string(*name), NativeFunction::new(*method));
+    }
+    let mut metatable = Table::new();
+    metatable.set_field(gc.allocate_string(B("__index")), gc.allocate_cell(index));
+    metatable.set_field(
+        gc.allocate_string(B("__name")),
+        gc.allocate_string(name.as_bytes()),
+    );
+    gc.allocate_cell(metatable)
+}
+
+fn channel_new<'gc>(
+    gc: &'gc GcContext,
+    vm: &mut Vm<'gc>,
+    _: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    Ok(Action::Return(vec![Channel::new().to_lua(gc, vm)]))
+}
+
+fn channel_send<'gc>(
+    _: &'gc GcContext,
+    _: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let channel = args.nth(1).borrow_as_userdata::<Channel>()?.clone();
+    let message = args
+        .without_callee()
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(i, value)| {
+            DetachedValue::detach_with_functions(*value).ok_or(ErrorKind::ArgumentError {
+                nth: i + 1,
+                message: "value cannot be sent over a channel",
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    channel.send(message);
+    Ok(Action::Return(Vec::new()))
+}
+
+// Returns the values of the next message, or nothing once `timeout` seconds
+// pass without one
+fn channel_receive<'gc>(
+    gc: &'gc GcContext,
+    vm: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let channel = args.nth(1).borrow_as_userdata::<Channel>()?.clone();
+    let timeout = args.nth(2);
+    let message = if timeout.is_present() {
+        let timeout: Number = timeout.to_number()?;
+        Duration::try_from_secs_f64(timeout.max(0.0))
+            .map(|timeout| channel.receive_timeout(timeout))
+            .unwrap_or_else(|_| Some(channel.receive()))
+    } else {
+        Some(channel.receive())
+    };
+    let env = vm.globals().into();
+    Ok(Action::Return(
+        message
+            .unwrap_or_default()
+            .iter()
+            .map(|value| value.attach_with_env(gc, env))
+            .collect(),
+    ))
+}
+
+fn channel_len<'gc>(
+    _: &'gc GcContext,
+    _: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let channel = args.nth(1).borrow_as_userdata::<Channel>()?.clone();
+    Ok(Action::Return(vec![(channel.len() as i64).into()]))
+}
+
+enum SpawnArgument {
+    Channel(Channel),
+    Value(DetachedValue),
+}
+
+// A state started by channel.spawn, whose results are taken by join
+struct SpawnedState(Option<JoinHandle<Result<Vec<DetachedValue>, String>>>);
+
+fn channel_spawn<'gc>(
+    gc: &'gc GcContext,
+    vm: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let function = match args.nth(1).get() {
+        Some(value @ Value::LuaClosure(_)) => DetachedValue::detach_with_functions(value).unwrap(),
+        value => {
+            return Err(ErrorKind::ArgumentTypeError {
+                nth: 1,
+                expected_type: "Lua function",
+                got_type: value.map(|value| value.ty().name()),
+            })
+        }
+    };
+    let spawn_args = args
+        .without_callee()
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(i, value)| {
+            if let Some(channel) = value.borrow_as_userdata::<Channel>() {
+                return Ok(SpawnArgument::Channel(channel.clone()));
+            }
+            DetachedValue::detach_with_functions(*value)
+                .map(SpawnArgument::Value)
+                .ok_or(ErrorKind::ArgumentError {
+                    nth: i + 1,
+                    message: "value cannot be sent to another state",
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let handle = spawn(move |lua| {
+        lua.with(|gc, vm| {
+            let env = vm.globals().into();
+            let function = function.attach_with_env(gc, env);
+            let args = spawn_args
+                .iter()
+                .map(|arg| match arg {
+                    SpawnArgument::Channel(channel) => channel.to_lua(gc, vm),
+                    SpawnArgument::Value(value) => value.attach_with_env(gc, env),
+                })
+                .collect();
+            let results = vm
+                .call_value(gc, function, args)
+                .map_err(|err| err.to_string())?;
+            results
+                .into_iter()
+                .map(|value| {
+                    DetachedValue::detach_with_functions(value).ok_or_else(|| {
+                        format!("cannot return {} value from a state", value.ty().name())
+                    })
+                })
+                .collect()
+        })
+    });
+
+    if vm.userdata_metatable::<SpawnedState>().is_none() {
+        let metatable = create_metatable(gc, "spawned state", &[(B("join"), spawned_join)]);
+        vm.set_userdata_metatable::<SpawnedState>(metatable);
+    }
+    Ok(Action::Return(vec![vm
+        .create_userdata(gc, SpawnedState(Some(handle)))
+        .into()]))
+}
+
+// Waits for the state to finish and returns true and the results of its
+// function, or false and the error it raised
+fn spawned_join<'gc>(
+    gc: &'gc GcContext,
+    vm: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let handle = args
+        .nth(1)
+        .borrow_as_userdata_mut::<SpawnedState>(gc)?
+        .0
+        .take()
+        .ok_or_else(|| ErrorKind::other("state was already joined"))?;
+    let result = handle
+        .join()
+        .unwrap_or_else(|_| Err("state panicked".to_owned()));
+    let env = vm.globals().into();
+    Ok(Action::Return(match result {
+        Ok(results) => std::iter::once(Value::Boolean(true))
+            .chain(results.iter().map(|value| value.attach_with_env(gc, env)))
+            .collect(),
+        Err(message) => vec![
+            false.into(),
+            gc.allocate_string(message.into_bytes()).into(),
+        ],
+    }))
+}
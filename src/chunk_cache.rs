@@ -0,0 +1,92 @@
+use crate::{
+    binary_chunk, file_chunk_name, gc::GcContext, load_buffered, open_chunk_file, remote::sha256,
+    types::LuaClosureProto, CompileOptions, Error,
+};
+use std::{
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+};
+
+// Binary chunks of compiled files, kept in a directory under the hash of the
+// source, its chunk name, the options it was compiled with and the version
+// of mochi, so that a changed file or option compiles it again. Stale chunks
+// are never removed; clearing the directory is always safe.
+#[derive(Debug, Clone)]
+pub struct ChunkCache {
+    dir: PathBuf,
+}
+
+impl ChunkCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Like load_file_with_options, loading the cached chunk of the file if
+    // there is one and caching the compiled chunk otherwise
+    pub fn load_file<'gc, P: AsRef<Path>>(
+        &self,
+        gc: &'gc GcContext,
+        path: P,
+        options: &CompileOptions,
+    ) -> Result<LuaClosureProto<'gc>, Error> {
+        let chunk_name = file_chunk_name(&path);
+        let mut source = Vec::new();
+        open_chunk_file(&path)?.read_to_end(&mut source)?;
+        if binary_chunk::is_binary_chunk(&source) {
+            return load_buffered(gc, Cursor::new(source), &chunk_name, options);
+        }
+
+        let cached = self.chunk_path(&source, &chunk_name, options);
+        if let Ok(chunk) = std::fs::read(&cached) {
+            // A chunk that doesn't load is compiled and written again
+            if let Ok(proto) = binary_chunk::load(gc, &mut chunk.as_slice()) {
+                return Ok(proto);
+            }
+        }
+        let proto = load_buffered(gc, Cursor::new(source), &chunk_name, options)?;
+        let mut chunk = Vec::new();
+        binary_chunk::dump(&mut chunk, &proto)?;
+        write_chunk(&cached, &chunk);
+        Ok(proto)
+    }
+
+    fn chunk_path(&self, source: &[u8], chunk_name: &[u8], options: &CompileOptions) -> PathBuf {
+        let mut key = Vec::new();
+        for part in [
+            env!("CARGO_PKG_VERSION").as_bytes(),
+            chunk_name,
+            &options.env_name,
+            &[
+                options.strip as u8,
+                options.forbid_goto as u8,
+                options.optimize as u8,
+            ],
+            source,
+        ] {
+            key.extend_from_slice(&(part.len() as u64).to_le_bytes());
+            key.extend_from_slice(part);
+        }
+        let name: String = sha256::digest(&key)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        self.dir.join(name + ".luac")
+    }
+}
+
+// Failing to cache a chunk only means compiling it again next time. It's
+// written under another name first, so that runs sharing the directory never
+// read a partly written chunk.
+fn write_chunk(path: &Path, chunk: &[u8]) {
+    let partial = path.with_extension(format!("{}.tmp", std::process::id()));
+    let _ = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(&partial, chunk))
+        .and_then(|_| std::fs::rename(&partial, path));
+    let _ = std::fs::remove_file(partial);
+}
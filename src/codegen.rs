@@ -3,21 +3,22 @@ mod instruction;
 mod ir;
 
 use crate::{
+    collections::{hash_map, HashMap},
     gc::GcContext,
     number_is_valid_integer,
     parser::ast::{
         BinaryOp, Block, Chunk, Expression, FunctionArguments, FunctionExpression, UnaryOp,
     },
+    prelude::*,
     runtime::Metamethod,
     types::{
         Integer, LuaClosureProto, LuaString, RegisterIndex, UpvalueDescription, UpvalueIndex, Value,
     },
+    CompileOptions,
 };
+use bstr::ByteSlice;
+use core::{num::NonZeroU8, ops::RangeInclusive};
 use ir::{ConstantIndex25, ConstantIndex8, IrAddress, IrInstruction, Label, ProtoIndex, RkIndex};
-use std::{
-    collections::{hash_map, HashMap},
-    num::NonZeroU8,
-};
 
 #[derive(Debug, thiserror::Error)]
 pub enum CodegenError {
@@ -45,8 +46,26 @@ pub enum CodegenError {
     #[error("mismatched block")]
     MismatchedBlock,
 
+    #[error("label '{0}' already defined")]
+    DuplicateLabel(String),
+
+    #[error("no visible label '{0}' for goto")]
+    UndefinedLabel(String),
+
+    #[error("goto '{label}' jumps into the scope of local '{local}'")]
+    JumpIntoScope { label: String, local: String },
+
+    #[error("attempt to assign to const variable '{0}'")]
+    AssignToConst(String),
+
+    #[error("unknown attribute '{0}'")]
+    UnknownAttribute(String),
+
+    #[error("{0} not supported")]
+    Unsupported(&'static str),
+
     #[error(transparent)]
-    Io(#[from] std::io::Error),
+    Io(#[from] crate::io::Error),
 }
 
 pub fn codegen<'gc>(
@@ -54,7 +73,17 @@ pub fn codegen<'gc>(
     source: LuaString<'gc>,
     chunk: Chunk<'gc>,
 ) -> Result<LuaClosureProto<'gc>, CodegenError> {
-    let mut generator = CodeGenerator::new(gc, source);
+    codegen_with_options(gc, source, chunk, &CompileOptions::default())
+}
+
+pub fn codegen_with_options<'gc>(
+    gc: &'gc GcContext,
+    source: LuaString<'gc>,
+    chunk: Chunk<'gc>,
+    options: &CompileOptions,
+) -> Result<LuaClosureProto<'gc>, CodegenError> {
+    let env_name = gc.allocate_string(options.env_name.as_slice());
+    let mut generator = CodeGenerator::new(gc, source, env_name);
     generator.enter_frame();
     generator.current_frame().is_vararg = true;
     generator.codegen_chunk(chunk)?;
@@ -63,8 +92,6 @@ pub fn codegen<'gc>(
     Ok(proto)
 }
 
-const LUA_ENV: &[u8] = b"_ENV";
-
 #[must_use]
 #[derive(Debug)]
 enum LazyLValue {
@@ -205,6 +232,49 @@ impl LazyRValue<'_> {
             _ => false,
         }
     }
+
+    // Whether discharging emits a call, which overwrites the registers above
+    // the one it's discharged to
+    fn has_call(&self) -> bool {
+        match self {
+            Self::FunctionCall { .. } | Self::MethodCall { .. } | Self::ShortCircuit { .. } => true,
+            Self::UnaryOp { inner, .. } => inner.has_call(),
+            Self::BinaryOp { lhs, rhs, .. } | Self::Comparison { lhs, rhs, .. } => {
+                lhs.has_call() || rhs.has_call()
+            }
+            _ => false,
+        }
+    }
+}
+
+// A <const> local variable whose value is known at compile time takes no
+// register, and its uses are replaced with the value
+#[derive(Debug, Clone, Copy)]
+enum LocalVariable<'gc> {
+    Register(RegisterIndex),
+    Const(RegisterIndex),
+    Constant(Value<'gc>),
+}
+
+impl From<RegisterIndex> for LocalVariable<'_> {
+    fn from(r: RegisterIndex) -> Self {
+        Self::Register(r)
+    }
+}
+
+impl LocalVariable<'_> {
+    fn register(&self) -> Option<RegisterIndex> {
+        match self {
+            Self::Register(r) | Self::Const(r) => Some(*r),
+            Self::Constant(_) => None,
+        }
+    }
+}
+
+// What a name refers to
+enum NameBinding<'gc> {
+    Variable { lvalue: LValue, is_const: bool },
+    Constant(Value<'gc>),
 }
 
 #[derive(Default)]
@@ -213,23 +283,40 @@ struct Frame<'gc> {
     max_stack_size: u8,
 
     ir_code: Vec<IrInstruction>,
+    // Line of each instruction in ir_code
+    ir_lines: Vec<u32>,
+    current_line: u32,
+    lines_defined: Option<RangeInclusive<u32>>,
     label_ir_addresses: Vec<Option<IrAddress>>,
 
     constants: HashMap<Value<'gc>, usize>,
     upvalues: HashMap<UpvalueDescription, UpvalueIndex>,
+    upvalue_names: Vec<LuaString<'gc>>,
     protos: Vec<LuaClosureProto<'gc>>,
 
-    local_variable_stack: Vec<(Option<LuaString<'gc>>, RegisterIndex)>,
+    local_variable_stack: Vec<(Option<LuaString<'gc>>, LocalVariable<'gc>)>,
+    // Names of the locals in registers and the IR addresses where their scope
+    // starts and ends, for debug info, and the index into it of each local in
+    // local_variable_stack
+    local_var_scopes: Vec<(LuaString<'gc>, IrAddress, Option<IrAddress>)>,
+    local_var_scope_indices: Vec<Option<usize>>,
+    // Indices into local_variable_stack of the locals captured by closures
+    captured_locals: Vec<usize>,
+
+    blocks: Vec<BlockScope>,
+    labels: Vec<LabelScope<'gc>>,
+    pending_gotos: Vec<PendingGoto<'gc>>,
 
     num_fixed_args: u8,
     is_vararg: bool,
     needs_to_close_upvalues: bool,
 }
 
-impl Frame<'_> {
+impl<'gc> Frame<'gc> {
     fn allocate_upvalue(
         &mut self,
         upvalue: UpvalueDescription,
+        name: LuaString<'gc>,
     ) -> Result<UpvalueIndex, CodegenError> {
         let i = self.upvalues.len();
         match self.upvalues.entry(upvalue) {
@@ -238,6 +325,7 @@ impl Frame<'_> {
                 if let Ok(i) = i.try_into() {
                     let i = UpvalueIndex(i);
                     entry.insert(i);
+                    self.upvalue_names.push(name);
                     Ok(i)
                 } else {
                     Err(CodegenError::TooManyUpvalues)
@@ -245,27 +333,69 @@ impl Frame<'_> {
             }
         }
     }
+
+    fn capture_local(&mut self, register: RegisterIndex) {
+        if let Some(i) = self
+            .local_variable_stack
+            .iter()
+            .rposition(|(_, var)| var.register() == Some(register))
+        {
+            if !self.captured_locals.contains(&i) {
+                self.captured_locals.push(i);
+            }
+        }
+    }
+
+    // The first register above the first `num_local_vars` local variables
+    fn register_level(&self, num_local_vars: usize) -> RegisterIndex {
+        let level = self.local_variable_stack[..num_local_vars]
+            .iter()
+            .filter_map(|(_, var)| var.register())
+            .map(|r| r.0 + 1)
+            .max()
+            .unwrap_or_default();
+        RegisterIndex(level)
+    }
+}
+
+struct BlockScope {
+    num_local_vars: usize,
+    first_label: usize,
+    first_goto: usize,
+    is_loop: bool,
+}
+
+struct LabelScope<'gc> {
+    name: LuaString<'gc>,
+    label: Label,
+    num_local_vars: usize,
 }
 
-#[derive(Debug, Default, Clone)]
-struct LoopInfo {
-    break_label: Option<Label>,
+// A forward goto, or a break if it has no name, waiting for its label to be
+// declared
+struct PendingGoto<'gc> {
+    name: Option<LuaString<'gc>>,
+    label: Label,
+    num_local_vars: usize,
+    // Whether the goto leaves the scope of a local variable captured by a
+    // closure, so the upvalue has to be closed at the label
+    needs_close: bool,
 }
 
 struct CodeGenerator<'gc> {
     gc: &'gc GcContext,
     source: LuaString<'gc>,
+    env_name: LuaString<'gc>,
     frames: Vec<Frame<'gc>>,
-    loops: Vec<LoopInfo>,
 }
 
 impl<'gc> CodeGenerator<'gc> {
-    fn new(gc: &'gc GcContext, source: LuaString<'gc>) -> Self {
+    fn new(gc: &'gc GcContext, source: LuaString<'gc>, env_name: LuaString<'gc>) -> Self {
         Self {
             gc,
             source,
+            env_name,
             frames: Default::default(),
-            loops: Default::default(),
         }
     }
 
@@ -282,7 +412,30 @@ impl<'gc> CodeGenerator<'gc> {
     }
 
     fn emit(&mut self, insn: IrInstruction) {
-        self.current_frame().ir_code.push(insn);
+        let current = self.current_frame();
+        current.ir_code.push(insn);
+        current.ir_lines.push(current.current_line);
+    }
+
+    // Brings a local into scope. Locals without names are internal ones, like
+    // the state of for loops.
+    fn declare_local(&mut self, name: Option<LuaString<'gc>>, var: LocalVariable<'gc>) {
+        let internal_name = self.gc.allocate_string(b"(for state)".as_slice());
+        let current = self.current_frame();
+        // Compile-time constants don't take registers
+        let scope_index = var.register().map(|_| {
+            let start = IrAddress(current.ir_code.len());
+            current
+                .local_var_scopes
+                .push((name.unwrap_or(internal_name), start, None));
+            current.local_var_scopes.len() - 1
+        });
+        current.local_var_scope_indices.push(scope_index);
+        current.local_variable_stack.push((name, var));
+    }
+
+    fn set_line(&mut self, line: usize) {
+        self.current_frame().current_line = line.try_into().unwrap_or(u32::MAX);
     }
 
     fn declare_label(&mut self) -> Label {
@@ -297,91 +450,209 @@ impl<'gc> CodeGenerator<'gc> {
         current.label_ir_addresses[label.0] = Some(IrAddress(current.ir_code.len()));
     }
 
-    fn break_label(&mut self) -> Result<Label, CodegenError> {
-        if let Some(label) = self
-            .loops
-            .last_mut()
-            .ok_or(CodegenError::BreakOutsideLoop)?
-            .break_label
-            .clone()
+    fn enter_block(&mut self, is_loop: bool) {
+        let current = self.current_frame();
+        let block = BlockScope {
+            num_local_vars: current.local_variable_stack.len(),
+            first_label: current.labels.len(),
+            first_goto: current.pending_gotos.len(),
+            is_loop,
+        };
+        current.blocks.push(block);
+    }
+
+    // Ends the scope of the local variables and labels declared in the
+    // block, closing the upvalues of its locals, and makes the gotos left
+    // pending leave the block. Breaks out of a loop block jump to here.
+    fn leave_block(&mut self) -> Result<(), CodegenError> {
+        let has_captured_locals = self.block_has_captured_locals();
+        let current = self.current_frame();
+        let block = current.blocks.pop().ok_or(CodegenError::MismatchedBlock)?;
+        let level = current.register_level(block.num_local_vars);
+        current.local_variable_stack.truncate(block.num_local_vars);
+        let end = IrAddress(current.ir_code.len());
+        for i in current
+            .local_var_scope_indices
+            .drain(block.num_local_vars..)
+            .flatten()
         {
-            Ok(label)
-        } else {
-            let label = self.declare_label();
-            self.loops
-                .last_mut()
-                .expect("loops")
-                .break_label
-                .replace(label);
-            Ok(label)
+            current.local_var_scopes[i].2 = Some(end);
         }
+        current
+            .captured_locals
+            .retain(|&i| i < block.num_local_vars);
+        current.labels.truncate(block.first_label);
+
+        let mut closed = false;
+        if block.is_loop {
+            closed = self.resolve_gotos(None, block.num_local_vars, block.first_goto)?;
+        }
+        let current = self.current_frame();
+        // Returning from the function closes the upvalues of the outermost
+        // block
+        if !closed && has_captured_locals && !current.blocks.is_empty() {
+            self.emit(IrInstruction::Close { level });
+        }
+
+        let current = self.current_frame();
+        for goto in &mut current.pending_gotos[block.first_goto..] {
+            if goto.num_local_vars > block.num_local_vars {
+                goto.needs_close |= has_captured_locals;
+                goto.num_local_vars = block.num_local_vars;
+            }
+        }
+        if current.blocks.is_empty() {
+            if let Some(goto) = current.pending_gotos.first() {
+                return Err(match goto.name {
+                    Some(name) => CodegenError::UndefinedLabel(name.to_str_lossy().into_owned()),
+                    None => CodegenError::BreakOutsideLoop,
+                });
+            }
+        }
+        Ok(())
     }
 
-    fn push_loop(&mut self) {
-        self.loops.push(Default::default());
+    fn block_has_captured_locals(&mut self) -> bool {
+        let current = self.current_frame();
+        let num_local_vars = current
+            .blocks
+            .last()
+            .map_or(0, |block| block.num_local_vars);
+        current.captured_locals.iter().any(|&i| i >= num_local_vars)
     }
 
-    fn pop_loop(&mut self) -> Result<(), CodegenError> {
-        self.loops
-            .pop()
-            .ok_or(CodegenError::MismatchedBlock)?
-            .break_label
-            .map(|l| self.place_label_here(l));
-        Ok(())
+    // Points the gotos pending since `first_goto` that go to the label
+    // `name` here, where `num_local_vars` local variables are in scope.
+    // Returns whether upvalues had to be closed for them.
+    fn resolve_gotos(
+        &mut self,
+        name: Option<LuaString<'gc>>,
+        num_local_vars: usize,
+        first_goto: usize,
+    ) -> Result<bool, CodegenError> {
+        let mut needs_close = false;
+        let mut i = first_goto;
+        while i < self.current_frame().pending_gotos.len() {
+            let current = self.current_frame();
+            if current.pending_gotos[i].name != name {
+                i += 1;
+                continue;
+            }
+            let goto = current.pending_gotos.remove(i);
+            if goto.num_local_vars < num_local_vars {
+                let local = current.local_variable_stack[goto.num_local_vars].0;
+                return Err(CodegenError::JumpIntoScope {
+                    label: name
+                        .map(|name| name.to_str_lossy().into_owned())
+                        .unwrap_or_default(),
+                    local: local
+                        .map(|name| name.to_str_lossy().into_owned())
+                        .unwrap_or_default(),
+                });
+            }
+            needs_close |= goto.needs_close;
+            self.place_label_here(goto.label);
+        }
+        if needs_close {
+            let current = self.current_frame();
+            let level = current.register_level(current.local_variable_stack.len());
+            self.emit(IrInstruction::Close { level });
+        }
+        Ok(needs_close)
     }
 
     fn resolve_name(&mut self, name: LuaString<'gc>) -> Result<LazyLValue, CodegenError> {
         match self.try_resolve_name(name)? {
-            Some(LValue::Register(r)) => Ok(r.into()),
-            Some(LValue::Upvalue(u)) => Ok(u.into()),
+            Some(NameBinding::Variable { lvalue, .. }) => Ok(lvalue.into()),
+            Some(NameBinding::Constant(value)) => Ok(self.discharge_to_new_register(value)?.into()),
             None => {
-                let env = match self.try_resolve_name(self.gc.allocate_string(LUA_ENV))? {
-                    Some(env) => env,
-                    None => unreachable!(),
-                };
+                let env = self.resolve_name(self.env_name)?;
                 self.resolve_table_field(env, name)
             }
         }
     }
 
-    fn try_resolve_name(&mut self, name: LuaString) -> Result<Option<LValue>, CodegenError> {
+    fn resolve_assigned_name(&mut self, name: LuaString<'gc>) -> Result<LazyLValue, CodegenError> {
+        match self.try_resolve_name(name)? {
+            Some(NameBinding::Variable {
+                is_const: false, ..
+            })
+            | None => self.resolve_name(name),
+            Some(_) => Err(CodegenError::AssignToConst(
+                name.to_str_lossy().into_owned(),
+            )),
+        }
+    }
+
+    fn try_resolve_name(
+        &mut self,
+        name: LuaString<'gc>,
+    ) -> Result<Option<NameBinding<'gc>>, CodegenError> {
         self.try_resolve_name_at_level(name, self.frames.len() - 1)
     }
 
     fn try_resolve_name_at_level(
         &mut self,
-        name: LuaString,
+        name: LuaString<'gc>,
         level: usize,
-    ) -> Result<Option<LValue>, CodegenError> {
-        if let Some((_, register)) = self.frames[level]
+    ) -> Result<Option<NameBinding<'gc>>, CodegenError> {
+        if let Some((_, var)) = self.frames[level]
             .local_variable_stack
             .iter()
             .rfind(|(n, _)| *n == Some(name))
         {
-            return Ok(Some((*register).into()));
+            let binding = match *var {
+                LocalVariable::Register(register) => NameBinding::Variable {
+                    lvalue: register.into(),
+                    is_const: false,
+                },
+                LocalVariable::Const(register) => NameBinding::Variable {
+                    lvalue: register.into(),
+                    is_const: true,
+                },
+                LocalVariable::Constant(value) => NameBinding::Constant(value),
+            };
+            return Ok(Some(binding));
         }
 
         if level > 0 {
             return match self.try_resolve_name_at_level(name, level - 1)? {
-                Some(LValue::Register(index)) => {
-                    let desc = UpvalueDescription::Register(index);
-                    let index = self.frames[level].allocate_upvalue(desc)?;
-                    self.frames[level - 1].needs_to_close_upvalues = true;
-                    Ok(Some(LValue::Upvalue(index)))
+                Some(NameBinding::Variable {
+                    lvalue: LValue::Register(register),
+                    is_const,
+                }) => {
+                    let desc = UpvalueDescription::Register(register);
+                    let index = self.frames[level].allocate_upvalue(desc, name)?;
+                    let parent = &mut self.frames[level - 1];
+                    parent.needs_to_close_upvalues = true;
+                    parent.capture_local(register);
+                    Ok(Some(NameBinding::Variable {
+                        lvalue: index.into(),
+                        is_const,
+                    }))
                 }
-                Some(LValue::Upvalue(index)) => {
+                Some(NameBinding::Variable {
+                    lvalue: LValue::Upvalue(index),
+                    is_const,
+                }) => {
                     let desc = UpvalueDescription::Upvalue(index);
-                    let index = self.frames[level].allocate_upvalue(desc)?;
-                    Ok(Some(LValue::Upvalue(index)))
+                    let index = self.frames[level].allocate_upvalue(desc, name)?;
+                    Ok(Some(NameBinding::Variable {
+                        lvalue: index.into(),
+                        is_const,
+                    }))
                 }
-                None => Ok(None),
+                binding => Ok(binding),
             };
         }
 
-        if name.as_ref() == LUA_ENV {
+        if name == self.env_name {
             let desc = UpvalueDescription::Upvalue(UpvalueIndex(0));
-            let index = self.frames[0].allocate_upvalue(desc)?;
-            Ok(Some(LValue::Upvalue(index)))
+            let index = self.frames[0].allocate_upvalue(desc, name)?;
+            Ok(Some(NameBinding::Variable {
+                lvalue: index.into(),
+                is_const: false,
+            }))
         } else {
             Ok(None)
         }
@@ -498,9 +769,13 @@ impl<'gc> CodeGenerator<'gc> {
 
     fn emit_function(&mut self, expr: FunctionExpression<'gc>) -> Result<ProtoIndex, CodegenError> {
         self.enter_frame();
+        self.set_line(expr.line_defined);
+        let line_defined = self.current_frame().current_line;
+        let last_line_defined = expr.last_line_defined.try_into().unwrap_or(u32::MAX);
 
         let num_fixed_args = expr.params.len().try_into().unwrap();
         let current = self.current_frame();
+        current.lines_defined = Some(line_defined..=last_line_defined);
         current.num_fixed_args = num_fixed_args;
         current.is_vararg = expr.is_vararg;
         if expr.is_vararg {
@@ -509,14 +784,13 @@ impl<'gc> CodeGenerator<'gc> {
 
         for param in expr.params {
             let register = self.allocate_register()?;
-            self.current_frame()
-                .local_variable_stack
-                .push((Some(param), register));
+            self.declare_local(Some(param), register.into());
         }
 
         let has_return = expr.body.return_statement.is_some();
         self.codegen_block(expr.body)?;
         if !has_return {
+            self.set_line(expr.last_line_defined);
             let Frame {
                 num_fixed_args,
                 is_vararg,
@@ -542,13 +816,16 @@ impl<'gc> CodeGenerator<'gc> {
         args: FunctionArguments<'gc>,
         dest: RegisterIndex,
     ) -> Result<(), CodegenError> {
+        let top = self.current_frame().register_top;
         self.discharge_to_register(callee, dest)?;
         let num_fixed_args = self.emit_func_args(args, RegisterIndex(dest.0 + 1))?;
         self.emit(IrInstruction::Call {
             callee: dest,
             num_fixed_args,
         });
-        self.current_frame().register_top = RegisterIndex(dest.0 + 1);
+        // Registers above the result that were in use before the call, such
+        // as the rest of an argument list, stay in use
+        self.current_frame().register_top = RegisterIndex(top.0.max(dest.0 + 1));
         Ok(())
     }
 
@@ -559,6 +836,7 @@ impl<'gc> CodeGenerator<'gc> {
         args: FunctionArguments<'gc>,
         dest: RegisterIndex,
     ) -> Result<(), CodegenError> {
+        let top = self.current_frame().register_top;
         let table = self.discharge_to_any_register(table)?;
         let key = self.discharge_to_rk(name)?;
         self.ensure_register_window(dest, 2)?;
@@ -570,7 +848,7 @@ impl<'gc> CodeGenerator<'gc> {
             num_fixed_args: num_fixed_args.map(|n| n + 1),
         });
 
-        self.current_frame().register_top = RegisterIndex(dest.0 + 1);
+        self.current_frame().register_top = RegisterIndex(top.0.max(dest.0 + 1));
         Ok(())
     }
 
@@ -685,6 +963,8 @@ impl<'gc> CodeGenerator<'gc> {
             if value.may_have_multiple_values() {
                 let base = self.discharge_to_new_register(value)?;
                 if let Some(num_extra) = num_expected.checked_sub(num_values) {
+                    // The extra values stay in use until they are assigned
+                    self.ensure_register_window(base, num_extra + 1)?;
                     for i in 0..=num_extra {
                         let i: u8 = i.try_into().unwrap();
                         let register = RegisterIndex(base.0 + i);
@@ -847,32 +1127,29 @@ impl<'gc> CodeGenerator<'gc> {
                                             lhs,
                                             rhs,
                                             metamethod: op.metamethod(),
+                                            metamethod_rhs: rhs,
                                             flipped,
                                         });
                                         return Ok(());
                                     }
                                 }
-                                BinaryOp::Sub => {
-                                    if let Ok(rhs) = (-i).try_into() {
+                                // Done with the negated operand, while the
+                                // metamethod still gets the original one
+                                BinaryOp::Sub | BinaryOp::Shl => {
+                                    if let (Ok(rhs), Ok(metamethod_rhs)) =
+                                        (i.wrapping_neg().try_into(), i.try_into())
+                                    {
                                         self.emit(IrInstruction::BinaryOpImmediate {
-                                            op: BinaryOp::Add,
+                                            op: if op == BinaryOp::Sub {
+                                                BinaryOp::Add
+                                            } else {
+                                                BinaryOp::Shr
+                                            },
                                             dest,
                                             lhs,
                                             rhs,
-                                            metamethod: Metamethod::Sub,
-                                            flipped,
-                                        });
-                                        return Ok(());
-                                    }
-                                }
-                                BinaryOp::Shl => {
-                                    if let Ok(rhs) = (-i).try_into() {
-                                        self.emit(IrInstruction::BinaryOpImmediate {
-                                            op: BinaryOp::Shr,
-                                            dest,
-                                            lhs,
-                                            rhs,
-                                            metamethod: Metamethod::Shl,
+                                            metamethod: op.metamethod(),
+                                            metamethod_rhs,
                                             flipped,
                                         });
                                         return Ok(());
@@ -1,6 +1,7 @@
 use super::{
     ir::{IrInstruction, RkIndex},
-    CodeGenerator, CodegenError, Frame, LValue, LazyLValue, LazyRValue,
+    CodeGenerator, CodegenError, Frame, LValue, LabelScope, LazyLValue, LazyRValue, LocalVariable,
+    NameBinding, PendingGoto,
 };
 use crate::{
     parser::ast::{
@@ -10,10 +11,11 @@ use crate::{
         TableConstructorExpression, TableField, TableRecordKey, UnaryOpExpression, Variable,
         WhileStatement,
     },
+    prelude::*,
     types::{Integer, LuaString, RegisterIndex, Value},
 };
-use bstr::B;
-use std::num::NonZeroU8;
+use bstr::{ByteSlice, B};
+use core::num::NonZeroU8;
 
 impl<'gc> CodeGenerator<'gc> {
     pub fn codegen_chunk(&mut self, chunk: Chunk<'gc>) -> Result<(), CodegenError> {
@@ -43,10 +45,38 @@ impl<'gc> CodeGenerator<'gc> {
     }
 
     pub fn codegen_block(&mut self, block: Block<'gc>) -> Result<(), CodegenError> {
-        for statement in block.statements {
-            self.codegen_statement(statement)?;
+        self.enter_block(false);
+        self.codegen_block_in_scope(block, false)?;
+        self.leave_block()
+    }
+
+    // Leaves the block's local variables in scope, for repeat-until
+    // conditions that use them
+    fn codegen_block_in_scope(
+        &mut self,
+        block: Block<'gc>,
+        is_repeat_body: bool,
+    ) -> Result<(), CodegenError> {
+        // Labels at the end of a block are out of the scope of its locals,
+        // so that gotos can jump over local declarations to them, unless
+        // the until condition of a repeat-until may still see the locals
+        let trailing_labels = block
+            .statements
+            .iter()
+            .rposition(|(_, statement)| !matches!(statement, Statement::Label(_)))
+            .map_or(0, |i| i + 1);
+        let labels_end_scope = block.return_statement.is_none() && !is_repeat_body;
+        for (i, (line, statement)) in block.statements.into_iter().enumerate() {
+            self.set_line(line);
+            match statement {
+                Statement::Label(name) => {
+                    self.codegen_label(name, labels_end_scope && i >= trailing_labels)?
+                }
+                statement => self.codegen_statement(statement)?,
+            }
         }
-        if let Some(mut return_statement) = block.return_statement {
+        if let Some((line, mut return_statement)) = block.return_statement {
+            self.set_line(line);
             let (base, count) = match return_statement.0.len() {
                 0 => (RegisterIndex(0), Some(0)),
                 1 => {
@@ -103,15 +133,23 @@ impl<'gc> CodeGenerator<'gc> {
         mut expr: TableConstructorExpression<'gc>,
     ) -> Result<LazyRValue<'gc>, CodegenError> {
         let table = self.allocate_register()?;
-        self.emit(IrInstruction::CreateTable { dest: table });
+        let create_table_address = self.current_frame().ir_code.len();
+        self.emit(IrInstruction::CreateTable {
+            dest: table,
+            array_len: 0,
+            hash_len: 0,
+        });
 
         const MAX_NUM_FIELDS_PER_FLUSH: u8 = 50;
         let mut next_index_offset = 0;
         let mut num_pending_fields = 0;
+        let mut array_len = 0;
+        let mut hash_len = 0;
 
         let mut emit_field = |field| -> Result<(), CodegenError> {
             match field {
                 TableField::List(expr) => {
+                    array_len += 1;
                     num_pending_fields += 1;
                     self.ensure_register_window(table, num_pending_fields as usize + 1)?;
                     let value = self.evaluate_expr(expr)?;
@@ -129,6 +167,7 @@ impl<'gc> CodeGenerator<'gc> {
                     }
                 }
                 TableField::Record { key, value } => {
+                    hash_len += 1;
                     let lhs = match key {
                         TableRecordKey::Name(name) => self.resolve_table_field(table, name)?,
                         TableRecordKey::Index(index) => self.resolve_table_index(table, index)?,
@@ -159,8 +198,15 @@ impl<'gc> CodeGenerator<'gc> {
                         count: None,
                         index_offset: next_index_offset,
                     });
+                    self.current_frame().ir_code[create_table_address] =
+                        IrInstruction::CreateTable {
+                            dest: table,
+                            array_len,
+                            hash_len,
+                        };
                     return Ok(LazyLValue::Register(table).into());
                 }
+                array_len += 1;
             }
             Some(field) => emit_field(field)?,
             None => (),
@@ -174,6 +220,11 @@ impl<'gc> CodeGenerator<'gc> {
             });
         }
 
+        self.current_frame().ir_code[create_table_address] = IrInstruction::CreateTable {
+            dest: table,
+            array_len,
+            hash_len,
+        };
         Ok(LazyLValue::Register(table).into())
     }
 
@@ -217,9 +268,9 @@ impl<'gc> CodeGenerator<'gc> {
             Statement::Function(s) => self.codegen_func_statement(s)?,
             Statement::LocalFunction(s) => self.codegen_local_func_statement(s)?,
             Statement::LocalVariable(s) => self.codegen_local_variable_statement(s)?,
-            Statement::Label(_) => todo!("label"),
+            Statement::Label(name) => self.codegen_label(name, false)?,
             Statement::Break => self.codegen_break_statement()?,
-            Statement::Goto(_) => todo!("goto"),
+            Statement::Goto(name) => self.codegen_goto_statement(name)?,
             Statement::FunctionCall(s) => self.codegen_func_call_statement(s)?,
             Statement::Assignment(s) => self.codegen_assignment_statement(s)?,
         };
@@ -228,7 +279,8 @@ impl<'gc> CodeGenerator<'gc> {
         current.register_top.0 = current
             .local_variable_stack
             .iter()
-            .map(|(_, i)| i.0)
+            .filter_map(|(_, var)| var.register())
+            .map(|r| r.0)
             .max()
             .map(|i| i + 1)
             .unwrap_or_default();
@@ -236,12 +288,79 @@ impl<'gc> CodeGenerator<'gc> {
         Ok(())
     }
 
+    fn codegen_label(
+        &mut self,
+        name: LuaString<'gc>,
+        ends_scope: bool,
+    ) -> Result<(), CodegenError> {
+        let current = self.current_frame();
+        if current.labels.iter().any(|label| label.name == name) {
+            return Err(CodegenError::DuplicateLabel(
+                name.to_str_lossy().into_owned(),
+            ));
+        }
+        let block = current.blocks.last().ok_or(CodegenError::MismatchedBlock)?;
+        let first_goto = block.first_goto;
+        let num_local_vars = if ends_scope {
+            block.num_local_vars
+        } else {
+            current.local_variable_stack.len()
+        };
+
+        let label = self.declare_label();
+        self.place_label_here(label);
+        self.current_frame().labels.push(LabelScope {
+            name,
+            label,
+            num_local_vars,
+        });
+        self.resolve_gotos(Some(name), num_local_vars, first_goto)?;
+        Ok(())
+    }
+
+    fn codegen_goto_statement(&mut self, name: LuaString<'gc>) -> Result<(), CodegenError> {
+        let current = self.current_frame();
+        let Some(label) = current.labels.iter().find(|label| label.name == name) else {
+            self.emit_pending_goto(Some(name));
+            return Ok(());
+        };
+
+        // Backward jumps close the upvalues of the locals they leave
+        let (target, num_local_vars) = (label.label, label.num_local_vars);
+        if current.local_variable_stack.len() > num_local_vars {
+            let level = current.register_level(num_local_vars);
+            self.emit(IrInstruction::Close { level });
+        }
+        self.emit(IrInstruction::Jump { target });
+        Ok(())
+    }
+
     fn codegen_break_statement(&mut self) -> Result<(), CodegenError> {
-        let label = self.break_label()?;
-        self.emit(IrInstruction::Jump { target: label });
+        if !self
+            .current_frame()
+            .blocks
+            .iter()
+            .any(|block| block.is_loop)
+        {
+            return Err(CodegenError::BreakOutsideLoop);
+        }
+        self.emit_pending_goto(None);
         Ok(())
     }
 
+    fn emit_pending_goto(&mut self, name: Option<LuaString<'gc>>) {
+        let label = self.declare_label();
+        self.emit(IrInstruction::Jump { target: label });
+        let current = self.current_frame();
+        let goto = PendingGoto {
+            name,
+            label,
+            num_local_vars: current.local_variable_stack.len(),
+            needs_close: false,
+        };
+        current.pending_gotos.push(goto);
+    }
+
     fn codegen_if_statement(
         &mut self,
         mut statement: IfStatement<'gc>,
@@ -285,22 +404,22 @@ impl<'gc> CodeGenerator<'gc> {
         let start_label = self.declare_label();
         self.place_label_here(start_label);
 
-        self.push_loop();
-        let result = self.emit_test_then_block_else_fallthrough(
+        self.enter_block(true);
+        self.emit_test_then_block_else_fallthrough(
             statement.condition,
             statement.body,
             start_label,
-        );
-        self.pop_loop()?;
-
-        result
+        )?;
+        self.leave_block()
     }
 
     fn codegen_for_statement(&mut self, statement: ForStatement<'gc>) -> Result<(), CodegenError> {
-        let prev_num_local_vars = self.current_frame().local_variable_stack.len();
+        self.enter_block(true);
         let base = self.allocate_register()?;
 
-        let (is_generic, body) = match statement {
+        // The variables declared by the loop are in the scope of the body,
+        // which closes their upvalues on each iteration
+        let (is_generic, variables, body) = match statement {
             ForStatement::Numerical {
                 control,
                 initial_value,
@@ -309,25 +428,19 @@ impl<'gc> CodeGenerator<'gc> {
                 body,
             } => {
                 let init_register = base;
-                self.current_frame()
-                    .local_variable_stack
-                    .push((None, init_register));
+                self.declare_local(None, init_register.into());
                 let initial_value = self.evaluate_expr(*initial_value)?;
                 self.discharge_to_register(initial_value, init_register)?;
 
                 self.ensure_register_window(base, 2)?;
                 let limit_register = RegisterIndex(base.0 + 1);
-                self.current_frame()
-                    .local_variable_stack
-                    .push((None, limit_register));
+                self.declare_local(None, limit_register.into());
                 let limit = self.evaluate_expr(*limit)?;
                 self.discharge_to_register(limit, limit_register)?;
 
                 self.ensure_register_window(base, 3)?;
                 let step_register = RegisterIndex(base.0 + 2);
-                self.current_frame()
-                    .local_variable_stack
-                    .push((None, step_register));
+                self.declare_local(None, step_register.into());
                 let step = if let Some(step) = step {
                     self.evaluate_expr(*step)?
                 } else {
@@ -336,12 +449,8 @@ impl<'gc> CodeGenerator<'gc> {
                 self.discharge_to_register(step, step_register)?;
 
                 self.ensure_register_window(base, 4)?;
-                let control_register = RegisterIndex(base.0 + 3);
-                self.current_frame()
-                    .local_variable_stack
-                    .push((Some(control), control_register));
 
-                (false, body)
+                (false, vec![control], body)
             }
             ForStatement::Generic {
                 variables,
@@ -358,19 +467,12 @@ impl<'gc> CodeGenerator<'gc> {
                     self.ensure_register_window(base, i as usize + 1)?;
                     let register = RegisterIndex(base.0 + i);
                     self.discharge_to_register(expr_rvalue, register)?;
-                    self.current_frame()
-                        .local_variable_stack
-                        .push((None, register));
+                    self.declare_local(None, register.into());
                 }
 
                 self.ensure_register_window(base, 4 + variables.len())?;
-                for (i, variable) in variables.into_iter().enumerate() {
-                    self.current_frame()
-                        .local_variable_stack
-                        .push((Some(variable), RegisterIndex(base.0 + 4 + i as u8)));
-                }
 
-                (true, body)
+                (true, variables, body)
             }
         };
 
@@ -384,8 +486,16 @@ impl<'gc> CodeGenerator<'gc> {
         let start_label = self.declare_label();
         self.place_label_here(start_label);
 
-        self.push_loop();
-        self.codegen_block(body)?;
+        self.enter_block(false);
+        let first_variable = if is_generic { 4 } else { 3 };
+        for (i, variable) in variables.into_iter().enumerate() {
+            self.declare_local(
+                Some(variable),
+                RegisterIndex(base.0 + first_variable + i as u8).into(),
+            );
+        }
+        self.codegen_block_in_scope(body, false)?;
+        self.leave_block()?;
         self.place_label_here(end_label);
 
         if is_generic {
@@ -397,13 +507,7 @@ impl<'gc> CodeGenerator<'gc> {
             next_target: start_label,
             is_generic,
         });
-        self.pop_loop()?;
-
-        self.current_frame()
-            .local_variable_stack
-            .truncate(prev_num_local_vars);
-
-        Ok(())
+        self.leave_block()
     }
 
     fn codegen_repeat_statement(
@@ -413,38 +517,52 @@ impl<'gc> CodeGenerator<'gc> {
         let start_label = self.declare_label();
         self.place_label_here(start_label);
 
-        self.push_loop();
-        let result = self.codegen_block(statement.body);
-        self.pop_loop()?;
-        result?;
+        self.enter_block(true);
+        self.enter_block(false);
+        self.codegen_block_in_scope(statement.body, true)?;
 
+        // The condition can use the locals of the body, whose upvalues are
+        // closed once it's evaluated
         let condition = self.evaluate_expr(statement.condition)?;
         match condition {
-            LazyRValue::Constant(Value::Nil | Value::Boolean(false)) => (),
-            LazyRValue::Constant(_) | LazyRValue::Proto(_) => return Ok(()),
-            LazyRValue::Comparison { op, lhs, rhs } => {
+            LazyRValue::Constant(Value::Nil | Value::Boolean(false)) => {
+                self.leave_block()?;
+                self.emit(IrInstruction::Jump {
+                    target: start_label,
+                });
+            }
+            LazyRValue::Constant(_) | LazyRValue::Proto(_) => self.leave_block()?,
+            LazyRValue::Comparison { op, lhs, rhs } if !self.block_has_captured_locals() => {
                 self.emit_comparison(op, *lhs, *rhs, false)?;
+                self.leave_block()?;
+                self.emit(IrInstruction::Jump {
+                    target: start_label,
+                });
             }
             condition => {
                 let condition = self.discharge_to_any_register(condition)?;
+                self.leave_block()?;
                 self.emit(IrInstruction::Test {
                     condition,
                     jump_on: false,
                 });
+                self.emit(IrInstruction::Jump {
+                    target: start_label,
+                });
             }
         }
-        self.emit(IrInstruction::Jump {
-            target: start_label,
-        });
-
-        Ok(())
+        self.leave_block()
     }
 
     fn codegen_func_statement(
         &mut self,
         mut statement: FunctionStatement<'gc>,
     ) -> Result<(), CodegenError> {
-        let mut lvalue = self.resolve_name(statement.name)?;
+        let mut lvalue = if statement.fields.is_empty() && statement.method.is_none() {
+            self.resolve_assigned_name(statement.name)?
+        } else {
+            self.resolve_name(statement.name)?
+        };
         for field in statement.fields {
             lvalue = self.resolve_table_field(lvalue, field)?;
         }
@@ -467,37 +585,61 @@ impl<'gc> CodeGenerator<'gc> {
         statement: FunctionStatement<'gc>,
     ) -> Result<(), CodegenError> {
         let register = self.allocate_register()?;
-        self.current_frame()
-            .local_variable_stack
-            .push((Some(statement.name), register));
+        self.declare_local(Some(statement.name), register.into());
         self.codegen_func_statement(statement)
     }
 
     fn codegen_local_variable_statement(
         &mut self,
-        statement: LocalVariableStatement<'gc>,
+        mut statement: LocalVariableStatement<'gc>,
     ) -> Result<(), CodegenError> {
-        if statement
-            .variables
-            .iter()
-            .any(|var| var.attribute.is_some())
-        {
-            todo!("attribute")
+        let mut is_const = Vec::with_capacity(statement.variables.len());
+        for variable in &statement.variables {
+            match variable.attribute.as_ref().map(|attr| attr.as_bytes()) {
+                None => is_const.push(false),
+                Some(b"const") => is_const.push(true),
+                Some(b"close") => return Err(CodegenError::Unsupported("to-be-closed variables")),
+                Some(attr) => {
+                    return Err(CodegenError::UnknownAttribute(
+                        attr.to_str_lossy().into_owned(),
+                    ))
+                }
+            }
         }
 
+        // Like in the reference implementation, only the last variable can
+        // be a compile-time constant, when there is a value for each variable
+        let mut last_value = None;
+        if is_const.last() == Some(&true) && statement.values.len() == statement.variables.len() {
+            last_value = statement.values.pop();
+        }
+        let num_values = statement.variables.len() - last_value.is_some() as usize;
         let mut value_registers = self
-            .emit_assigned_values(statement.values, statement.variables.len())?
+            .emit_assigned_values(statement.values, num_values)?
             .into_iter();
+        let mut last_variable = None;
+        if let Some(last_value) = last_value {
+            last_variable = match self.evaluate_expr(last_value)? {
+                LazyRValue::Constant(value) => Some(LocalVariable::Constant(value)),
+                value => Some(LocalVariable::Const(self.discharge_to_new_register(value)?)),
+            };
+        }
 
-        for variable in statement.variables {
+        for (variable, is_const) in statement.variables.into_iter().zip(is_const) {
             let register = if let Some(register) = value_registers.next() {
                 register
+            } else if let Some(last_variable) = last_variable.take() {
+                self.declare_local(Some(variable.name), last_variable);
+                continue;
             } else {
                 self.discharge_to_new_register(Value::Nil)?
             };
-            self.current_frame()
-                .local_variable_stack
-                .push((Some(variable.name), register));
+            let variable_kind = if is_const {
+                LocalVariable::Const(register)
+            } else {
+                register.into()
+            };
+            self.declare_local(Some(variable.name), variable_kind);
         }
 
         Ok(())
@@ -584,10 +726,10 @@ impl<'gc> CodeGenerator<'gc> {
 
     fn evaluate_primary(&mut self, primary: Primary<'gc>) -> Result<LazyRValue<'gc>, CodegenError> {
         match primary {
-            Primary::Name(name) => {
-                let name = self.resolve_name(name)?;
-                Ok(name.into())
-            }
+            Primary::Name(name) => match self.try_resolve_name(name)? {
+                Some(NameBinding::Constant(value)) => Ok(value.into()),
+                _ => Ok(self.resolve_name(name)?.into()),
+            },
             Primary::Expression(expr) => {
                 let value = self.evaluate_expr(*expr)?;
                 let value = match value {
@@ -641,6 +783,11 @@ impl<'gc> CodeGenerator<'gc> {
             });
         }
 
+        // The right operand can take registers that a call discharged later
+        // would overwrite
+        if lhs.has_call() {
+            lhs = LazyLValue::from(self.discharge_to_new_register(lhs)?).into();
+        }
         let mut rhs = self.evaluate_expr(*expr.rhs)?;
 
         let op_can_be_flipped = matches!(
@@ -659,7 +806,7 @@ impl<'gc> CodeGenerator<'gc> {
         if op_can_be_flipped {
             flipped = match (&lhs, &rhs) {
                 (LazyRValue::Constant(lhs), LazyRValue::Constant(rhs))
-                    if std::mem::discriminant(lhs) == std::mem::discriminant(rhs) =>
+                    if core::mem::discriminant(lhs) == core::mem::discriminant(rhs) =>
                 {
                     false
                 }
@@ -680,7 +827,7 @@ impl<'gc> CodeGenerator<'gc> {
                     BinaryOp::Ge => op = BinaryOp::Le,
                     _ => (),
                 };
-                std::mem::swap(&mut lhs, &mut rhs);
+                core::mem::swap(&mut lhs, &mut rhs);
             }
         }
 
@@ -746,7 +893,7 @@ impl<'gc> CodeGenerator<'gc> {
 
     fn resolve_variable(&mut self, variable: Variable<'gc>) -> Result<LazyLValue, CodegenError> {
         match variable {
-            Variable::Name(name) => self.resolve_name(name),
+            Variable::Name(name) => self.resolve_assigned_name(name),
             Variable::TableIndex { table, index } => {
                 let table = self.resolve_suffixed_expr(table)?;
                 self.resolve_table_index(table, index)
@@ -2,13 +2,17 @@ use super::{instruction::Address, CodegenError, Frame};
 use crate::{
     gc::GcContext,
     parser::ast::{BinaryOp, UnaryOp},
+    prelude::*,
     runtime::{
         instruction::{OFFSET_SBX, OFFSET_SC, OFFSET_SJ, UINT17_MAX, UINT25_MAX},
         Instruction, Metamethod, OpCode,
     },
-    types::{Integer, LuaClosureProto, LuaString, RegisterIndex, UpvalueIndex},
+    types::{
+        InlineCaches, Integer, LineRange, LocalVariable, LuaClosureProto, LuaString, RegisterIndex,
+        UpvalueIndex,
+    },
 };
-use std::num::NonZeroU8;
+use core::num::NonZeroU8;
 
 #[derive(Debug, Clone, Copy)]
 pub struct ConstantIndex25(u32);
@@ -193,6 +197,8 @@ pub enum IrInstruction {
     },
     CreateTable {
         dest: RegisterIndex,
+        array_len: usize,
+        hash_len: usize,
     },
     GetSelf {
         dest: RegisterIndex,
@@ -205,6 +211,7 @@ pub enum IrInstruction {
         lhs: RegisterIndex,
         rhs: ImmediateI8,
         metamethod: Metamethod,
+        metamethod_rhs: ImmediateI8,
         flipped: bool,
     },
     BinaryOpConstant {
@@ -259,6 +266,9 @@ pub enum IrInstruction {
     Jump {
         target: Label,
     },
+    Close {
+        level: RegisterIndex,
+    },
     Call {
         callee: RegisterIndex,
         num_fixed_args: Option<u8>,
@@ -313,7 +323,13 @@ pub(super) fn lower_ir<'gc>(
     let mut pending_instructions = Vec::new();
 
     let mut code = Vec::with_capacity(frame.ir_code.len());
+    let mut lines = Vec::with_capacity(frame.ir_code.len());
+    let mut code_addresses = Vec::with_capacity(frame.ir_code.len());
     for (ir_addr, insn) in frame.ir_code.into_iter().enumerate() {
+        code_addresses.push(code.len());
+        // Instructions merged into the previous one keep its line
+        lines.resize(code.len(), frame.ir_lines[ir_addr.saturating_sub(1)]);
+
         for (label, _) in frame
             .label_ir_addresses
             .iter()
@@ -482,15 +498,30 @@ pub(super) fn lower_ir<'gc>(
                     k,
                 ));
             }
-            IrInstruction::CreateTable { dest } => {
+            IrInstruction::CreateTable {
+                dest,
+                array_len,
+                hash_len,
+            } => {
+                // refer to "luaK_settablesize" in lcode.c
+                let b = if hash_len > 0 {
+                    hash_len.next_power_of_two().trailing_zeros() as u8 + 1
+                } else {
+                    0
+                };
+                let extra = array_len / (u8::MAX as usize + 1);
+                let c = (array_len % (u8::MAX as usize + 1)) as u8;
                 code.push(Instruction::from_a_b_c_k(
                     OpCode::NewTable,
                     dest.0,
-                    0,
-                    0,
-                    false,
+                    b,
+                    c,
+                    extra > 0,
+                ));
+                code.push(Instruction::from_ax(
+                    OpCode::ExtraArg,
+                    extra.min(UINT25_MAX as usize) as u32,
                 ));
-                code.push(Instruction::from_ax(OpCode::ExtraArg, 0));
             }
             IrInstruction::GetSelf { dest, table, key } => {
                 let (c, k) = key.to_c_and_k();
@@ -508,6 +539,7 @@ pub(super) fn lower_ir<'gc>(
                 lhs,
                 rhs,
                 metamethod,
+                metamethod_rhs,
                 flipped,
             } => {
                 let opcode = op.immdiate_opcode();
@@ -518,7 +550,7 @@ pub(super) fn lower_ir<'gc>(
                 code.push(Instruction::from_a_sb_c_k(
                     OpCode::MmBinI,
                     lhs.0,
-                    rhs.0,
+                    metamethod_rhs.0,
                     metamethod as u8,
                     flipped,
                 ));
@@ -593,13 +625,15 @@ pub(super) fn lower_ir<'gc>(
                 rhs,
                 jump_on,
             } => {
-                let (opcode, k) = match op {
-                    BinaryOp::Lt => (OpCode::Lt, jump_on),
-                    BinaryOp::Le => (OpCode::Le, jump_on),
-                    BinaryOp::Gt => (OpCode::Le, !jump_on),
-                    BinaryOp::Ge => (OpCode::Lt, !jump_on),
-                    BinaryOp::Eq => (OpCode::Eq, jump_on),
-                    BinaryOp::Ne => (OpCode::Eq, !jump_on),
+                // a > b is b < a rather than not a <= b, which differs for
+                // NaN and for the metamethod called
+                let (opcode, lhs, rhs, k) = match op {
+                    BinaryOp::Lt => (OpCode::Lt, lhs, rhs, jump_on),
+                    BinaryOp::Le => (OpCode::Le, lhs, rhs, jump_on),
+                    BinaryOp::Gt => (OpCode::Lt, rhs, lhs, jump_on),
+                    BinaryOp::Ge => (OpCode::Le, rhs, lhs, jump_on),
+                    BinaryOp::Eq => (OpCode::Eq, lhs, rhs, jump_on),
+                    BinaryOp::Ne => (OpCode::Eq, lhs, rhs, !jump_on),
                     _ => unreachable!(),
                 };
                 code.push(Instruction::from_a_b_c_k(opcode, lhs.0, rhs.0, 0, k));
@@ -650,6 +684,15 @@ pub(super) fn lower_ir<'gc>(
                     jump_on,
                 ));
             }
+            IrInstruction::Close { level } => {
+                code.push(Instruction::from_a_b_c_k(
+                    OpCode::Close,
+                    level.0,
+                    0,
+                    0,
+                    false,
+                ));
+            }
             IrInstruction::ConditionalMove {
                 dest,
                 source,
@@ -770,6 +813,10 @@ pub(super) fn lower_ir<'gc>(
             }
         }
     }
+    lines.resize(
+        code.len(),
+        frame.ir_lines.last().copied().unwrap_or_default(),
+    );
 
     for (addr, insn) in pending_instructions {
         let patched_insn = match insn {
@@ -824,25 +871,47 @@ pub(super) fn lower_ir<'gc>(
     upvalues.sort_unstable_by_key(|(_, i)| *i);
     let upvalues: Vec<_> = upvalues.into_iter().map(|(u, _)| u).collect();
 
+    let code_address =
+        |addr: IrAddress| code_addresses.get(addr.0).copied().unwrap_or(code.len()) as u32;
+    let local_vars: Vec<_> = frame
+        .local_var_scopes
+        .into_iter()
+        .map(|(name, start, end)| LocalVariable {
+            name,
+            pc: code_address(start)..end.map_or(code.len() as u32, code_address),
+        })
+        .collect();
+
     let protos: Vec<_> = frame
         .protos
         .into_iter()
         .map(|proto| gc.allocate(proto))
         .collect();
 
-    Ok(LuaClosureProto {
-        max_stack_size: frame.max_stack_size,
+    let mut proto = LuaClosureProto {
+        // Registers 0 and 1 are always valid, as in the reference
+        // implementation, so that operands of RETURN are in range even in
+        // functions without locals
+        max_stack_size: frame.max_stack_size.max(2),
+        inline_caches: InlineCaches::new(code.len()),
+        #[cfg(feature = "jit")]
+        jit: Default::default(),
         code: code.into(),
         constants: constants.into(),
         upvalues: upvalues.into(),
         protos: protos.into(),
-        lines_defined: crate::types::LineRange::File,
+        lines_defined: match frame.lines_defined {
+            Some(range) => LineRange::Lines(range),
+            None => LineRange::File,
+        },
         source,
-        // TODO: generate debug info
         abs_line_info: None,
         line_info: None,
-        local_vars: None,
-    })
+        local_vars: Some(local_vars.into()),
+        upvalue_names: Some(frame.upvalue_names.into()),
+    };
+    proto.set_lines(&lines);
+    Ok(proto)
 }
 
 impl UnaryOp {
@@ -0,0 +1,19 @@
+// std's hash maps and sets, or without the "std" feature hashbrown's with the
+// hasher that tables use
+#[cfg(feature = "std")]
+pub(crate) use std::collections::{HashMap, HashSet};
+
+// Only codegen uses entries, and it isn't built with "luac"
+#[cfg(all(feature = "std", not(feature = "luac")))]
+pub(crate) use std::collections::hash_map;
+
+#[cfg(not(feature = "std"))]
+pub(crate) use hashbrown::hash_map;
+
+#[cfg(not(feature = "std"))]
+pub(crate) type HashMap<K, V> =
+    hashbrown::HashMap<K, V, core::hash::BuildHasherDefault<rustc_hash::FxHasher>>;
+
+#[cfg(not(feature = "std"))]
+pub(crate) type HashSet<T> =
+    hashbrown::HashSet<T, core::hash::BuildHasherDefault<rustc_hash::FxHasher>>;
@@ -0,0 +1,183 @@
+use crate::{
+    collections::HashMap,
+    gc::{GarbageCollect, Gc, GcCell, Tracer},
+    runtime::Vm,
+    types::{LuaClosureProto, LuaThread},
+};
+use std::{collections::BTreeMap, io::Write};
+
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    // Times each line with code was run, by chunk name (the path of chunks
+    // loaded from files)
+    pub files: BTreeMap<String, BTreeMap<u32, u64>>,
+}
+
+impl Coverage {
+    pub fn write_lcov(&self, w: &mut impl Write) -> std::io::Result<()> {
+        for (file, lines) in &self.files {
+            writeln!(w, "TN:")?;
+            writeln!(w, "SF:{file}")?;
+            for (line, hits) in lines {
+                writeln!(w, "DA:{line},{hits}")?;
+            }
+            let hit = lines.values().filter(|hits| **hits > 0).count();
+            writeln!(w, "LH:{hit}")?;
+            writeln!(w, "LF:{}", lines.len())?;
+            writeln!(w, "end_of_record")?;
+        }
+        Ok(())
+    }
+
+    // Writes a chunk returning a table of the hits of each line, by chunk
+    // name
+    pub fn write_lua(&self, w: &mut impl Write) -> std::io::Result<()> {
+        writeln!(w, "return {{")?;
+        for (file, lines) in &self.files {
+            write!(w, "  [{file:?}] = {{")?;
+            for (line, hits) in lines {
+                write!(w, " [{line}] = {hits},")?;
+            }
+            writeln!(w, " }},")?;
+        }
+        writeln!(w, "}}")
+    }
+}
+
+struct ProtoLines<'gc> {
+    proto: Gc<'gc, LuaClosureProto<'gc>>,
+    file: String,
+    lines: Box<[u32]>,
+}
+
+// Instruction run last, to tell when a line starts again
+struct Position {
+    thread: usize,
+    bottom: usize,
+    proto: usize,
+    pc: usize,
+}
+
+#[derive(Default)]
+pub(crate) struct Collector<'gc> {
+    coverage: Coverage,
+    protos: Vec<ProtoLines<'gc>>,
+    indices: HashMap<*const LuaClosureProto<'gc>, usize>,
+    current: Option<(*const LuaClosureProto<'gc>, usize)>,
+    last: Option<Position>,
+}
+
+unsafe impl GarbageCollect for Collector<'_> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for proto in &self.protos {
+            proto.proto.trace(tracer);
+        }
+    }
+}
+
+impl<'gc> Collector<'gc> {
+    fn proto_index(&mut self, proto: Gc<'gc, LuaClosureProto<'gc>>) -> usize {
+        if let Some((current, index)) = self.current {
+            if current == proto.as_ptr() {
+                return index;
+            }
+        }
+        let index = match self.indices.get(&proto.as_ptr()) {
+            Some(index) => *index,
+            None => {
+                let file = chunk_file(&proto);
+                self.add_lines(&file, proto);
+                self.protos.push(ProtoLines {
+                    proto,
+                    file,
+                    lines: proto.lines().unwrap_or_default().into(),
+                });
+                self.indices.insert(proto.as_ptr(), self.protos.len() - 1);
+                self.protos.len() - 1
+            }
+        };
+        self.current = Some((proto.as_ptr(), index));
+        index
+    }
+
+    // Adds the lines of the function and the functions it defines with no
+    // hits, so that lines never run are reported
+    fn add_lines(&mut self, file: &str, proto: Gc<'gc, LuaClosureProto<'gc>>) {
+        let lines = self.coverage.files.entry(file.to_owned()).or_default();
+        let mut protos = vec![proto];
+        while let Some(proto) = protos.pop() {
+            // line 0 is where main chunks start, before their first line
+            for line in proto.lines().unwrap_or_default() {
+                if line > 0 {
+                    lines.entry(line).or_default();
+                }
+            }
+            protos.extend(proto.protos.iter().copied());
+        }
+    }
+}
+
+impl<'gc> Vm<'gc> {
+    // Starts counting the times each line is run. Functions run are kept
+    // alive until coverage stops.
+    pub fn start_coverage(&mut self) {
+        self.coverage = Some(Default::default());
+    }
+
+    pub fn stop_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take().map(|collector| collector.coverage)
+    }
+
+    // Called before running the instruction at `pc`. A line is counted when
+    // it's entered from another line, or again by a backward jump.
+    pub(crate) fn record_coverage(
+        &mut self,
+        thread: GcCell<'gc, LuaThread<'gc>>,
+        bottom: usize,
+        proto: Gc<'gc, LuaClosureProto<'gc>>,
+        pc: usize,
+    ) {
+        let Some(collector) = &mut self.coverage else {
+            return;
+        };
+        let index = collector.proto_index(proto);
+        let position = Position {
+            thread: thread.as_ptr() as usize,
+            bottom,
+            proto: proto.as_ptr() as usize,
+            pc,
+        };
+        let ProtoLines { file, lines, .. } = &collector.protos[index];
+        let Some(&line) = lines.get(pc) else {
+            return;
+        };
+        let new_line = match &collector.last {
+            Some(last)
+                if (last.thread, last.bottom, last.proto)
+                    == (position.thread, bottom, position.proto) =>
+            {
+                pc <= last.pc || lines.get(last.pc) != Some(&line)
+            }
+            _ => pc == 0 || lines.get(pc - 1) != Some(&line),
+        };
+        collector.last = Some(position);
+        if new_line {
+            if let Some(hits) = collector
+                .coverage
+                .files
+                .get_mut(file)
+                .and_then(|lines| lines.get_mut(&line))
+            {
+                *hits += 1;
+            }
+        }
+    }
+}
+
+fn chunk_file(proto: &LuaClosureProto) -> String {
+    let source = String::from_utf8_lossy(&proto.source);
+    match source.strip_prefix('@') {
+        Some(path) => path.to_owned(),
+        None => crate::chunk_id_from_source(&source).into_owned(),
+    }
+}
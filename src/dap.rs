@@ -0,0 +1,507 @@
+// A Debug Adapter Protocol server for one client, over stdio or TCP, on top
+// of the debugger. Messages are read on a thread of their own, and handled
+// while the script is stopped or before it's run. A request that comes while
+// the script is running interrupts it at the next line to be handled.
+use crate::{
+    debugger::{DebugHandler, Resume, Stop, StopReason},
+    gc::{GcCell, GcContext},
+    runtime::Vm,
+    types::{Table, Value},
+};
+use serde_json::{json, Value as Json};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+};
+
+const THREAD_ID: i64 = 1;
+
+#[derive(Debug)]
+pub struct Request {
+    pub seq: i64,
+    pub command: String,
+    pub arguments: Json,
+}
+
+pub struct Connection {
+    writer: Mutex<Box<dyn Write + Send>>,
+    requests: Mutex<Receiver<Request>>,
+    // Requests sent by the reader and not received yet
+    queued: AtomicUsize,
+    seq: AtomicI64,
+    // Interrupt flag of the debugger, set for each request
+    interrupt: Mutex<Option<Arc<AtomicBool>>>,
+}
+
+// What the client asked to run with launch or attach
+#[derive(Debug, Clone, Default)]
+pub struct Launch {
+    // None for attach, which debugs the script given to mochi
+    pub program: Option<String>,
+    pub args: Vec<String>,
+    pub stop_on_entry: bool,
+}
+
+impl Connection {
+    pub fn new<R, W>(reader: R, writer: W) -> Arc<Self>
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let connection = Arc::new(Self {
+            writer: Mutex::new(Box::new(writer)),
+            requests: Mutex::new(receiver),
+            queued: AtomicUsize::new(0),
+            seq: AtomicI64::new(1),
+            interrupt: Mutex::new(None),
+        });
+        let weak = Arc::downgrade(&connection);
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            while let Ok(Some(message)) = read_message(&mut reader) {
+                if message["type"] != "request" {
+                    continue;
+                }
+                let request = Request {
+                    seq: message["seq"].as_i64().unwrap_or(0),
+                    command: message["command"].as_str().unwrap_or("").to_owned(),
+                    arguments: message["arguments"].clone(),
+                };
+                if sender.send(request).is_err() {
+                    break;
+                }
+                let Some(connection) = weak.upgrade() else {
+                    break;
+                };
+                connection.queued.fetch_add(1, Ordering::SeqCst);
+                let interrupt = connection.interrupt.lock().unwrap().clone();
+                if let Some(interrupt) = interrupt {
+                    interrupt.store(true, Ordering::SeqCst);
+                }
+            }
+        });
+        connection
+    }
+
+    pub fn stdio() -> Arc<Self> {
+        Self::new(io::stdin(), io::stdout())
+    }
+
+    // Waits for a client to connect
+    pub fn listen(addr: impl ToSocketAddrs) -> io::Result<Arc<Self>> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        Ok(Self::new(stream.try_clone()?, stream))
+    }
+
+    // None once the client has closed the connection
+    pub fn next_request(&self) -> Option<Request> {
+        let request = self.requests.lock().unwrap().recv().ok()?;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        Some(request)
+    }
+
+    fn try_next_request(&self) -> Option<Request> {
+        let request = self.requests.lock().unwrap().try_recv().ok()?;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        Some(request)
+    }
+
+    // Before resuming, as requests that came while stopped have set it
+    fn reset_interrupt(&self) {
+        if let Some(interrupt) = &*self.interrupt.lock().unwrap() {
+            interrupt.store(self.queued.load(Ordering::SeqCst) > 0, Ordering::SeqCst);
+        }
+    }
+
+    fn send(&self, mut message: Json) {
+        message["seq"] = self.seq.fetch_add(1, Ordering::Relaxed).into();
+        // A client that has gone is noticed by the reader
+        write_message(&mut *self.writer.lock().unwrap(), &message).ok();
+    }
+
+    pub fn respond(&self, request: &Request, body: Json) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request.seq,
+            "success": true,
+            "command": request.command,
+            "body": body,
+        }));
+    }
+
+    pub fn respond_error(&self, request: &Request, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request.seq,
+            "success": false,
+            "command": request.command,
+            "message": message,
+        }));
+    }
+
+    pub fn event(&self, event: &str, body: Json) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    // Shows `text` in the debug console of the client, with a category like
+    // "stdout" or "stderr"
+    pub fn output(&self, category: &str, text: &str) {
+        self.event("output", json!({ "category": category, "output": text }));
+    }
+
+    // Handles the requests that configure the session, up to
+    // configurationDone and launch or attach, with `vm` having a DapHandler
+    // of this connection attached
+    pub fn configure(&self, vm: &mut Vm) -> io::Result<Launch> {
+        let mut launch = None;
+        let mut configured = false;
+        while launch.is_none() || !configured {
+            let Some(request) = self.next_request() else {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            };
+            match request.command.as_str() {
+                "initialize" => {
+                    self.respond(
+                        &request,
+                        json!({
+                            "supportsConfigurationDoneRequest": true,
+                            "supportsEvaluateForHovers": true,
+                        }),
+                    );
+                    self.event("initialized", json!({}));
+                }
+                "launch" | "attach" => {
+                    let arguments = &request.arguments;
+                    launch = Some(Launch {
+                        program: arguments["program"].as_str().map(str::to_owned),
+                        args: arguments["args"]
+                            .as_array()
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|arg| arg.as_str().map(str::to_owned))
+                            .collect(),
+                        stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+                    });
+                    self.respond(&request, Json::Null);
+                }
+                "configurationDone" => {
+                    configured = true;
+                    self.respond(&request, Json::Null);
+                }
+                "setBreakpoints" => self.set_breakpoints(vm, &request),
+                "threads" => self.respond_threads(&request),
+                "disconnect" => {
+                    self.respond(&request, Json::Null);
+                    return Err(io::ErrorKind::ConnectionAborted.into());
+                }
+                _ => self.respond_error(&request, "not running"),
+            }
+        }
+        let launch = launch.unwrap();
+        if let Some(debugger) = vm.debugger() {
+            if launch.stop_on_entry {
+                debugger.set_resume(Resume::Step);
+            }
+            *self.interrupt.lock().unwrap() = Some(debugger.interrupt_flag());
+        }
+        Ok(launch)
+    }
+
+    // Tells the client that the script has ended, and waits for it to
+    // disconnect
+    pub fn finish(&self, exit_code: i32) {
+        *self.interrupt.lock().unwrap() = None;
+        self.event("exited", json!({ "exitCode": exit_code }));
+        self.event("terminated", json!({}));
+        while let Some(request) = self.next_request() {
+            if request.command == "disconnect" {
+                self.respond(&request, Json::Null);
+                break;
+            }
+            self.respond_error(&request, "not running");
+        }
+    }
+
+    // Replaces the breakpoints in a file
+    fn set_breakpoints(&self, vm: &mut Vm, request: &Request) {
+        let Some(debugger) = vm.debugger() else {
+            return self.respond_error(request, "not debugging");
+        };
+        let Some(path) = request.arguments["source"]["path"].as_str() else {
+            return self.respond_error(request, "no source path");
+        };
+        let old: Vec<_> = debugger
+            .breakpoints()
+            .iter()
+            .filter(|breakpoint| breakpoint.file == path)
+            .map(|breakpoint| breakpoint.id)
+            .collect();
+        for id in old {
+            debugger.remove_breakpoint(id);
+        }
+        let breakpoints: Vec<_> = request.arguments["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|breakpoint| breakpoint["line"].as_u64())
+            .map(|line| {
+                let id = debugger.add_breakpoint(path, line as u32);
+                json!({ "id": id, "verified": true, "line": line })
+            })
+            .collect();
+        self.respond(request, json!({ "breakpoints": breakpoints }));
+    }
+
+    fn respond_threads(&self, request: &Request) {
+        self.respond(
+            request,
+            json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+        );
+    }
+}
+
+pub struct DapHandler {
+    connection: Arc<Connection>,
+    stopped_before: bool,
+}
+
+impl DapHandler {
+    pub fn new(connection: Arc<Connection>) -> Self {
+        Self {
+            connection,
+            stopped_before: false,
+        }
+    }
+}
+
+// What a variablesReference refers to, numbered from 1 at each stop
+enum Scope<'gc> {
+    Locals(usize),
+    Upvalues(usize),
+    Table(GcCell<'gc, Table<'gc>>),
+}
+
+impl DebugHandler for DapHandler {
+    fn stopped<'gc>(&mut self, gc: &'gc GcContext, vm: &mut Vm<'gc>, stop: &Stop) -> Resume {
+        let connection = self.connection.clone();
+        let reason = match stop.reason {
+            StopReason::Breakpoint(_) => "breakpoint",
+            StopReason::Step if !self.stopped_before => "entry",
+            StopReason::Step => "step",
+            StopReason::Interrupt => {
+                // Requests other than pause are handled without stopping
+                let mut pause = false;
+                while let Some(request) = connection.try_next_request() {
+                    match request.command.as_str() {
+                        "pause" => {
+                            pause = true;
+                            connection.respond(&request, Json::Null);
+                        }
+                        "setBreakpoints" => connection.set_breakpoints(vm, &request),
+                        "threads" => connection.respond_threads(&request),
+                        "disconnect" => {
+                            connection.respond(&request, Json::Null);
+                            vm.detach_debugger();
+                            return Resume::Continue;
+                        }
+                        _ => connection.respond_error(&request, "not stopped"),
+                    }
+                }
+                if !pause {
+                    return vm.debugger().map_or(Resume::Continue, |d| d.resume());
+                }
+                "pause"
+            }
+        };
+        self.stopped_before = true;
+        connection.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        );
+
+        let mut scopes = Vec::new();
+        loop {
+            let Some(request) = connection.next_request() else {
+                vm.detach_debugger();
+                return Resume::Continue;
+            };
+            let arguments = &request.arguments;
+            let resume = match request.command.as_str() {
+                "continue" => Resume::Continue,
+                "next" => Resume::Next,
+                "stepIn" => Resume::Step,
+                "stepOut" => Resume::Finish,
+                "disconnect" => {
+                    connection.respond(&request, Json::Null);
+                    vm.detach_debugger();
+                    return Resume::Continue;
+                }
+                "pause" => {
+                    connection.respond(&request, Json::Null);
+                    continue;
+                }
+                "setBreakpoints" => {
+                    connection.set_breakpoints(vm, &request);
+                    continue;
+                }
+                "threads" => {
+                    connection.respond_threads(&request);
+                    continue;
+                }
+                "stackTrace" => {
+                    let frames: Vec<_> = vm
+                        .debug_frames()
+                        .into_iter()
+                        .enumerate()
+                        .map(|(level, frame)| {
+                            let source = match frame.path {
+                                Some(path) => json!({ "name": frame.source, "path": path }),
+                                None => json!({ "name": frame.source }),
+                            };
+                            json!({
+                                "id": level + 1,
+                                "name": frame.name.unwrap_or_else(|| "?".to_owned()),
+                                "source": source,
+                                "line": frame.line.unwrap_or(0),
+                                "column": 1,
+                            })
+                        })
+                        .collect();
+                    connection.respond(
+                        &request,
+                        json!({ "stackFrames": frames, "totalFrames": frames.len() }),
+                    );
+                    continue;
+                }
+                "scopes" => {
+                    let level = frame_level(arguments);
+                    scopes.push(Scope::Locals(level));
+                    scopes.push(Scope::Upvalues(level));
+                    let n = scopes.len();
+                    connection.respond(
+                        &request,
+                        json!({ "scopes": [
+                            { "name": "Locals", "variablesReference": n - 1, "expensive": false },
+                            { "name": "Upvalues", "variablesReference": n, "expensive": false },
+                        ] }),
+                    );
+                    continue;
+                }
+                "variables" => {
+                    let reference = arguments["variablesReference"].as_u64().unwrap_or(0);
+                    let vars = match scopes.get((reference as usize).wrapping_sub(1)) {
+                        Some(Scope::Locals(level)) => vm.debug_locals(*level),
+                        Some(Scope::Upvalues(level)) => vm.debug_upvalues(*level),
+                        Some(Scope::Table(table)) => table
+                            .borrow()
+                            .iter()
+                            .map(|(key, value)| {
+                                let name = match key {
+                                    Value::String(s) => {
+                                        String::from_utf8_lossy(s.as_bytes()).into_owned()
+                                    }
+                                    key => format!("[{key:?}]"),
+                                };
+                                (name, value)
+                            })
+                            .collect(),
+                        None => Vec::new(),
+                    };
+                    let variables: Vec<_> = vars
+                        .into_iter()
+                        .map(|(name, value)| {
+                            let mut variable = describe_value(&mut scopes, "value", value);
+                            variable["name"] = name.into();
+                            variable
+                        })
+                        .collect();
+                    connection.respond(&request, json!({ "variables": variables }));
+                    continue;
+                }
+                "evaluate" => {
+                    let expression = arguments["expression"].as_str().unwrap_or("");
+                    match vm.debug_evaluate(gc, frame_level(arguments), expression) {
+                        Ok(values) => {
+                            let value = values.first().copied().unwrap_or_default();
+                            let result = describe_value(&mut scopes, "result", value);
+                            connection.respond(&request, result);
+                        }
+                        Err(err) => connection.respond_error(&request, &err.to_string()),
+                    }
+                    continue;
+                }
+                _ => {
+                    connection.respond_error(&request, "unsupported request");
+                    continue;
+                }
+            };
+            connection.reset_interrupt();
+            let body = match resume {
+                Resume::Continue => json!({ "allThreadsContinued": true }),
+                _ => Json::Null,
+            };
+            connection.respond(&request, body);
+            return resume;
+        }
+    }
+}
+
+// Frames are numbered from 1 in stack traces
+fn frame_level(arguments: &Json) -> usize {
+    (arguments["frameId"].as_u64().unwrap_or(1) as usize).saturating_sub(1)
+}
+
+// Tables can be expanded through a variablesReference
+fn describe_value<'gc>(scopes: &mut Vec<Scope<'gc>>, key: &str, value: Value<'gc>) -> Json {
+    let reference = match value {
+        Value::Table(table) => {
+            scopes.push(Scope::Table(table));
+            scopes.len()
+        }
+        _ => 0,
+    };
+    json!({
+        key: format!("{value:?}"),
+        "type": value.ty().name(),
+        "variablesReference": reference,
+    })
+}
+
+pub fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Json>> {
+    let mut length = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let Some(length) = length else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "missing Content-Length",
+        ));
+    };
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+pub fn write_message(writer: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    writer.flush()
+}
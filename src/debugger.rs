@@ -0,0 +1,459 @@
+// Breakpoints and stepping. Before each instruction that starts a line, the
+// VM checks whether to stop there. A stop leaves the frame as a pause does,
+// and the DebugHandler is called before the frame is entered again, with
+// the stopped frame as the innermost Lua frame (level 0).
+use crate::{
+    gc::{GcCell, GcContext, MaybeSend},
+    prelude::*,
+    runtime::{ErrorKind, Frame, LuaFrame, Metamethod, Vm},
+    types::{LineRange, LuaClosureProto, LuaThread, Table, Upvalue, Value},
+};
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    // Runs until a breakpoint
+    Continue,
+    // Stops at the next line, entering calls
+    Step,
+    // Stops at the next line of the function or of its callers
+    Next,
+    // Stops once the function has returned
+    Finish,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Breakpoint(usize),
+    Step,
+    // Asked for with the interrupt flag
+    Interrupt,
+}
+
+#[derive(Debug, Clone)]
+pub struct Stop {
+    pub reason: StopReason,
+    // Chunk id of the source, like "script.lua"
+    pub source: String,
+    pub line: u32,
+}
+
+pub trait DebugHandler: MaybeSend {
+    fn stopped<'gc>(&mut self, gc: &'gc GcContext, vm: &mut Vm<'gc>, stop: &Stop) -> Resume;
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub id: usize,
+    // Path of the script, or its trailing components
+    pub file: String,
+    pub line: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct DebugFrame {
+    // Name the function was called by, or "main chunk"
+    pub name: Option<String>,
+    pub source: String,
+    // File of the chunk, if it was loaded from one
+    pub path: Option<String>,
+    pub line: Option<u32>,
+}
+
+// Depth of a frame, as the number of running threads and the number of
+// frames of its thread
+type Depth = (usize, usize);
+
+#[derive(PartialEq)]
+struct Position {
+    thread: usize,
+    depth: Depth,
+    bottom: usize,
+    proto: usize,
+    pc: usize,
+    line: u32,
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    next_id: usize,
+    resume: Resume,
+    // Depth of the frame that Next or Finish were given in
+    step_depth: Depth,
+    last: Option<Position>,
+    pending: Option<(Stop, Depth)>,
+    // Taken while it's called, so that code it evaluates doesn't stop
+    handler: Option<Box<dyn DebugHandler>>,
+    interrupt: Arc<AtomicBool>,
+}
+
+impl Debugger {
+    pub fn add_breakpoint(&mut self, file: impl Into<String>, line: u32) -> usize {
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint {
+            id: self.next_id,
+            file: file.into(),
+            line,
+        });
+        self.next_id
+    }
+
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+        self.breakpoints.len() < len
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    // Setting it, from any thread, stops before the next instruction that
+    // can stop
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    pub fn resume(&self) -> Resume {
+        self.resume
+    }
+
+    // How to run until the next stop. Outside a stop, Next and Finish stop
+    // at the next line like Step.
+    pub fn set_resume(&mut self, resume: Resume) {
+        self.resume = resume;
+        self.step_depth = (usize::MAX, usize::MAX);
+    }
+}
+
+impl<'gc> Vm<'gc> {
+    // Replaces any debugger attached before. It runs until a breakpoint,
+    // unless told otherwise with set_resume.
+    pub fn attach_debugger<H: DebugHandler + 'static>(&mut self, handler: H) -> &mut Debugger {
+        self.debugger.insert(Debugger {
+            breakpoints: Vec::new(),
+            next_id: 0,
+            resume: Resume::Continue,
+            step_depth: (usize::MAX, usize::MAX),
+            last: None,
+            pending: None,
+            handler: Some(Box::new(handler)),
+            interrupt: Default::default(),
+        })
+    }
+
+    pub fn detach_debugger(&mut self) {
+        self.debugger = None;
+    }
+
+    pub fn debugger(&mut self) -> Option<&mut Debugger> {
+        self.debugger.as_mut()
+    }
+
+    // Whether to stop before the instruction at `pc`, which starts a stop
+    // if so
+    pub(crate) fn check_debug_stop(
+        &mut self,
+        thread: GcCell<'gc, LuaThread<'gc>>,
+        depth: Depth,
+        bottom: usize,
+        proto: &LuaClosureProto<'gc>,
+        pc: usize,
+    ) -> bool {
+        let Some(debugger) = &mut self.debugger else {
+            return false;
+        };
+        if debugger.handler.is_none() {
+            return false;
+        }
+        let source = proto.source.as_bytes();
+        let interrupted = debugger.interrupt.load(Ordering::Relaxed);
+        if debugger.resume == Resume::Continue
+            && !interrupted
+            && !debugger
+                .breakpoints
+                .iter()
+                .any(|breakpoint| source_matches(source, &breakpoint.file))
+        {
+            return false;
+        }
+        let Some(line) = proto.get_funcline(pc as u32) else {
+            return false;
+        };
+
+        let position = Position {
+            thread: thread.as_ptr() as usize,
+            depth,
+            bottom,
+            proto: proto as *const _ as usize,
+            pc,
+            line,
+        };
+        // Backward jumps start the line again, as in loops on a single line
+        let new_line = match &debugger.last {
+            Some(last)
+                if (last.thread, last.depth, last.bottom, last.proto)
+                    == (position.thread, depth, bottom, position.proto) =>
+            {
+                line != last.line || pc < last.pc
+            }
+            _ => pc == 0 || proto.get_funcline(pc as u32 - 1) != Some(line),
+        };
+        debugger.last = Some(position);
+        if interrupted {
+            debugger.interrupt.store(false, Ordering::Relaxed);
+        } else if !new_line {
+            return false;
+        }
+
+        let breakpoint = debugger
+            .breakpoints
+            .iter()
+            .find(|breakpoint| breakpoint.line == line && source_matches(source, &breakpoint.file));
+        let reason = match (breakpoint, debugger.resume) {
+            _ if interrupted => StopReason::Interrupt,
+            (Some(breakpoint), _) => StopReason::Breakpoint(breakpoint.id),
+            (None, Resume::Step) => StopReason::Step,
+            (None, Resume::Next) if depth <= debugger.step_depth => StopReason::Step,
+            (None, Resume::Finish) if depth < debugger.step_depth => StopReason::Step,
+            _ => return false,
+        };
+        let source = String::from_utf8_lossy(source);
+        let stop = Stop {
+            reason,
+            source: crate::chunk_id_from_source(&source).into_owned(),
+            line,
+        };
+        debugger.pending = Some((stop, depth));
+        true
+    }
+
+    pub(crate) fn run_debug_handler(&mut self, gc: &'gc GcContext) {
+        let Some(debugger) = &mut self.debugger else {
+            return;
+        };
+        let Some((stop, depth)) = debugger.pending.take() else {
+            return;
+        };
+        let Some(mut handler) = debugger.handler.take() else {
+            return;
+        };
+        let resume = handler.stopped(gc, self, &stop);
+        // The handler may have detached the debugger or attached another
+        if let Some(debugger) = &mut self.debugger {
+            debugger.handler.get_or_insert(handler);
+            // Resuming from an interrupt as before carries on with the same
+            // step
+            if stop.reason != StopReason::Interrupt || resume != debugger.resume {
+                debugger.step_depth = depth;
+            }
+            debugger.resume = resume;
+        }
+    }
+
+    // The Lua functions running on the current thread, from the innermost
+    pub fn debug_frames(&self) -> Vec<DebugFrame> {
+        let thread = self.current_thread();
+        let thread = thread.borrow();
+        let frames: Vec<_> = thread
+            .frames
+            .iter()
+            .filter_map(Frame::as_lua)
+            .rev()
+            .collect();
+        frames
+            .iter()
+            .enumerate()
+            .map(|(level, frame)| {
+                let proto = thread.stack_closure(frame.bottom).unwrap().proto;
+                let name = match proto.lines_defined {
+                    LineRange::File => Some("main chunk".to_owned()),
+                    LineRange::Lines(_) => frames.get(level + 1).and_then(|caller| {
+                        let caller_proto = thread.stack_closure(caller.bottom)?.proto;
+                        caller_proto
+                            .funcname_from_code(caller.last_pc())
+                            .map(|info| info.name.to_string())
+                    }),
+                };
+                let source = String::from_utf8_lossy(proto.source.as_bytes());
+                DebugFrame {
+                    name,
+                    source: crate::chunk_id_from_source(&source).into_owned(),
+                    path: source.strip_prefix('@').map(str::to_owned),
+                    line: proto.get_funcline(frame_pc(level, frame) as u32),
+                }
+            })
+            .collect()
+    }
+
+    pub fn debug_locals(&self, level: usize) -> Vec<(String, Value<'gc>)> {
+        let vars = self.frame_variables(level);
+        vars.into_iter()
+            .filter(|(_, var)| matches!(var, Variable::Local(_)))
+            .map(|(name, var)| (name, self.variable_value(var)))
+            .collect()
+    }
+
+    pub fn debug_upvalues(&self, level: usize) -> Vec<(String, Value<'gc>)> {
+        let vars = self.frame_variables(level);
+        vars.into_iter()
+            .filter(|(_, var)| matches!(var, Variable::Upvalue(_)))
+            .map(|(name, var)| (name, self.variable_value(var)))
+            .collect()
+    }
+
+    // Runs `source`, an expression or else a block, where the locals and
+    // upvalues of the frame at `level` are visible and can be assigned.
+    // Other names are looked up in the _ENV of the frame.
+    pub fn debug_evaluate(
+        &mut self,
+        gc: &'gc GcContext,
+        level: usize,
+        source: &str,
+    ) -> Result<Vec<Value<'gc>>, ErrorKind> {
+        const SCOPE: &str = "local vars, names, env = ...
+return function(_, k) if names[k] then return vars[k] end return env[k] end,
+    function(_, k, v) if names[k] then vars[k] = v else env[k] = v end end";
+
+        let chunk = match self.load(gc, format!("return {source}"), "=(debug)") {
+            Ok(chunk) => chunk,
+            Err(_) => self
+                .load(gc, source, "=(debug)")
+                .map_err(|err| ErrorKind::other(err.to_string()))?,
+        };
+
+        // Inner locals shadow outer ones and upvalues
+        let mut visible = BTreeMap::new();
+        for (name, var) in self.frame_variables(level) {
+            visible.insert(name, var);
+        }
+        let mut vars = Table::new();
+        let mut names = Table::new();
+        for (name, var) in &visible {
+            let name = gc.allocate_string(name.as_bytes());
+            vars.set_field(name, self.variable_value(*var));
+            names.set_field(name, true);
+        }
+        let env = vars.get_field(gc.allocate_string(b"_ENV".as_slice()));
+        let env = if env.is_nil() {
+            self.globals().into()
+        } else {
+            env
+        };
+        let vars = gc.allocate_cell(vars);
+
+        let scope = self.load(gc, SCOPE, "=(debug)").unwrap();
+        let scope = self.call_value(
+            gc,
+            gc.allocate(scope).into(),
+            vec![vars.into(), gc.allocate_cell(names).into(), env],
+        )?;
+        let mut metatable = Table::new();
+        metatable.set_field(self.metamethod_name(Metamethod::Index), scope[0]);
+        metatable.set_field(self.metamethod_name(Metamethod::NewIndex), scope[1]);
+        let mut proxy = Table::new();
+        proxy.set_metatable(gc.allocate_cell(metatable));
+        chunk.set_environment(gc, gc.allocate_cell(proxy).into());
+
+        let result = self.call_value(gc, gc.allocate(chunk).into(), Vec::new());
+        for (name, var) in visible {
+            let value = vars
+                .borrow()
+                .get_field(gc.allocate_string(name.into_bytes()));
+            self.set_variable(gc, var, value);
+        }
+        result
+    }
+
+    // Locals and upvalues of the frame at `level`, upvalues first and
+    // locals from the outermost block
+    fn frame_variables(&self, level: usize) -> Vec<(String, Variable<'gc>)> {
+        let thread = self.current_thread();
+        let thread = thread.borrow();
+        let Some(frame) = thread
+            .frames
+            .iter()
+            .filter_map(Frame::as_lua)
+            .rev()
+            .nth(level)
+        else {
+            return Vec::new();
+        };
+        let closure = thread.stack_closure(frame.bottom).unwrap();
+        let proto = closure.proto;
+        let mut vars: Vec<_> = closure
+            .upvalues
+            .iter()
+            .enumerate()
+            .filter_map(|(i, upvalue)| {
+                let name = proto.upvalname(i)?;
+                Some((name.to_string(), Variable::Upvalue(*upvalue)))
+            })
+            .collect();
+        let pc = frame_pc(level, frame) as u32;
+        for n in 1.. {
+            let Some(name) = proto.get_localname(n, pc) else {
+                break;
+            };
+            // Internal locals, like "(for state)"
+            if !name.starts_with('(') {
+                let index = frame.base + n as usize - 1;
+                vars.push((name.to_string(), Variable::Local(index)));
+            }
+        }
+        vars
+    }
+
+    fn variable_value(&self, var: Variable<'gc>) -> Value<'gc> {
+        match var {
+            Variable::Local(index) => self.current_thread().borrow().stack[index],
+            Variable::Upvalue(upvalue) => match *upvalue.borrow() {
+                Upvalue::Open { thread, index } => thread.borrow().stack[index],
+                Upvalue::Closed(value) => value,
+            },
+        }
+    }
+
+    fn set_variable(&self, gc: &'gc GcContext, var: Variable<'gc>, value: Value<'gc>) {
+        match var {
+            Variable::Local(index) => self.current_thread().borrow_mut(gc).stack[index] = value,
+            Variable::Upvalue(upvalue) => match &mut *upvalue.borrow_mut(gc) {
+                Upvalue::Open { thread, index } => thread.borrow_mut(gc).stack[*index] = value,
+                Upvalue::Closed(closed) => *closed = value,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Variable<'gc> {
+    // Index in the stack of the current thread
+    Local(usize),
+    Upvalue(GcCell<'gc, Upvalue<'gc>>),
+}
+
+// The innermost frame is stopped before the instruction at its pc, and the
+// others are in a call
+fn frame_pc(level: usize, frame: &LuaFrame) -> usize {
+    if level == 0 {
+        frame.pc
+    } else {
+        frame.last_pc()
+    }
+}
+
+// Whether a chunk loaded from a file, with a source like "@dir/file.lua", is
+// `file`, as one path or the trailing components of the other
+fn source_matches(source: &[u8], file: &str) -> bool {
+    let Some(path) = source.strip_prefix(b"@") else {
+        return false;
+    };
+    let path = path.strip_prefix(b"./").unwrap_or(path);
+    let file = file.strip_prefix("./").unwrap_or(file).as_bytes();
+    let ends_with = |path: &[u8], suffix: &[u8]| match path.strip_suffix(suffix) {
+        Some(dir) => dir.is_empty() || dir.ends_with(b"/"),
+        None => false,
+    };
+    ends_with(path, file) || ends_with(file, path)
+}
@@ -0,0 +1,213 @@
+// Listings of functions in the format of `luac -l`: a header with the
+// counts, then one instruction per line with its decoded operands. Full
+// listings add constants, locals and upvalues.
+use crate::{
+    io::{self, Write},
+    prelude::*,
+    runtime::{Instruction, OpCode},
+    types::{LineRange, LuaClosureProto, UpvalueDescription, Value},
+};
+use bstr::ByteSlice;
+
+pub fn disassemble<W: Write>(w: &mut W, proto: &LuaClosureProto, full: bool) -> io::Result<()> {
+    fn format_counter(word: &str, n: usize) -> String {
+        format!("{n} {word}{}", if n == 1 { "" } else { "s" })
+    }
+
+    let source = proto.source.as_bstr();
+    let source = if let Some(b'@' | b'=') = source.first() {
+        &source[1..]
+    } else {
+        source
+    };
+    match &proto.lines_defined {
+        LineRange::File => write!(w, "main <{source}:0,0> ")?,
+        LineRange::Lines(range) => write!(
+            w,
+            "function <{}:{},{}> ",
+            source,
+            range.start(),
+            range.end()
+        )?,
+    };
+    writeln!(
+        w,
+        "({})\n{}, {}, {}, {}",
+        format_counter("instruction", proto.code.len()),
+        format_counter("slot", proto.max_stack_size as usize),
+        format_counter("upvalue", proto.upvalues.len()),
+        format_counter("constant", proto.constants.len()),
+        format_counter("function", proto.protos.len())
+    )?;
+
+    for (i, insn) in proto.code.iter().enumerate() {
+        write!(w, "\t{}\t{:9}\t", i + 1, insn.opcode())?;
+        writeln!(w, "{}", Operands(*insn))?;
+    }
+
+    if full {
+        writeln!(w, "constants ({}):", proto.constants.len())?;
+        for (i, constant) in proto.constants.iter().enumerate() {
+            write!(w, "\t{i}\t")?;
+            w.write_all(match constant {
+                Value::Nil => b"N",
+                Value::Boolean(_) => b"B",
+                Value::Integer(_) => b"I",
+                Value::Number(_) => b"F",
+                Value::String(_) => b"S",
+                _ => b"?",
+            })?;
+            match constant {
+                Value::Nil | Value::Boolean(_) | Value::Integer(_) | Value::Number(_) => {
+                    w.write_all(b"\t")?;
+                    constant.fmt_bytes(w)?;
+                    w.write_all(b"\n")?;
+                }
+                Value::String(s) => writeln!(w, "\t{s:?}")?,
+                _ => w.write_all(b"\t?\n")?,
+            };
+        }
+
+        let local_vars = proto.local_vars.as_deref().unwrap_or_default();
+        writeln!(w, "locals ({}):", local_vars.len())?;
+        for (i, var) in local_vars.iter().enumerate() {
+            writeln!(
+                w,
+                "\t{i}\t{}\t{}\t{}",
+                var.name.as_bstr(),
+                var.pc.start + 1,
+                var.pc.end + 1
+            )?;
+        }
+
+        writeln!(w, "upvalues ({}):", proto.upvalues.len())?;
+        for (i, desc) in proto.upvalues.iter().enumerate() {
+            let name = proto.upvalue_names.as_ref().and_then(|names| names.get(i));
+            match name {
+                Some(name) => write!(w, "\t{i}\t{}\t", name.as_bstr())?,
+                None => write!(w, "\t{i}\t-\t")?,
+            }
+            match desc {
+                UpvalueDescription::Register(i) => writeln!(w, "1\t{}", i.0)?,
+                UpvalueDescription::Upvalue(i) => writeln!(w, "0\t{}", i.0)?,
+            }
+        }
+    }
+
+    writeln!(w)?;
+
+    for proto in proto.protos.iter() {
+        disassemble(w, proto, full)?;
+    }
+
+    Ok(())
+}
+
+// The operands of an instruction as luac -l lists them
+pub(crate) struct Operands(pub Instruction);
+
+impl core::fmt::Display for Operands {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let insn = self.0;
+        match insn.opcode() {
+            OpCode::Return0 => Ok(()),
+            OpCode::LoadKX
+            | OpCode::LoadFalse
+            | OpCode::LFalseSkip
+            | OpCode::LoadTrue
+            | OpCode::Close
+            | OpCode::Tbc
+            | OpCode::Return1
+            | OpCode::VarArgPrep => write!(f, "{}", insn.a()),
+            OpCode::Jmp => write!(f, "{}", insn.sj()),
+            OpCode::ExtraArg => write!(f, "{}", insn.ax()),
+            OpCode::Test => write!(f, "{} {}", insn.a(), insn.k() as u8),
+            OpCode::Move
+            | OpCode::LoadNil
+            | OpCode::GetUpval
+            | OpCode::SetUpval
+            | OpCode::Unm
+            | OpCode::BNot
+            | OpCode::Not
+            | OpCode::Len
+            | OpCode::Concat => write!(f, "{} {}", insn.a(), insn.b()),
+            OpCode::LoadK
+            | OpCode::Closure
+            | OpCode::ForLoop
+            | OpCode::ForPrep
+            | OpCode::TForPrep
+            | OpCode::TForLoop => write!(f, "{} {}", insn.a(), insn.bx()),
+            OpCode::LoadI | OpCode::LoadF => write!(f, "{} {}", insn.a(), insn.sbx()),
+            OpCode::TForCall | OpCode::VarArg => write!(f, "{} {}", insn.a(), insn.c()),
+            OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::EqK | OpCode::TestSet => {
+                write!(f, "{} {} {}", insn.a(), insn.b(), insn.k() as u8)
+            }
+            OpCode::EqI | OpCode::LtI | OpCode::LeI | OpCode::GtI | OpCode::GeI => {
+                write!(f, "{} {} {}", insn.a(), insn.sb(), insn.k() as u8)
+            }
+            OpCode::GetTabUp
+            | OpCode::GetTable
+            | OpCode::GetI
+            | OpCode::GetField
+            | OpCode::NewTable
+            | OpCode::AddK
+            | OpCode::SubK
+            | OpCode::MulK
+            | OpCode::ModK
+            | OpCode::PowK
+            | OpCode::DivK
+            | OpCode::IDivK
+            | OpCode::BAndK
+            | OpCode::BOrK
+            | OpCode::BXorK
+            | OpCode::Add
+            | OpCode::Sub
+            | OpCode::Mul
+            | OpCode::Mod
+            | OpCode::Pow
+            | OpCode::Div
+            | OpCode::IDiv
+            | OpCode::BAnd
+            | OpCode::BOr
+            | OpCode::BXor
+            | OpCode::Shl
+            | OpCode::Shr
+            | OpCode::MmBin
+            | OpCode::Call
+            | OpCode::SetList => write!(f, "{} {} {}", insn.a(), insn.b(), insn.c()),
+            OpCode::MmBinK => write!(
+                f,
+                "{} {} {} {}",
+                insn.a(),
+                insn.b(),
+                insn.c(),
+                insn.k() as u8
+            ),
+            OpCode::MmBinI => write!(
+                f,
+                "{} {} {} {}",
+                insn.a(),
+                insn.sb(),
+                insn.c(),
+                insn.k() as u8
+            ),
+            OpCode::AddI | OpCode::ShrI | OpCode::ShlI => {
+                write!(f, "{} {} {}", insn.a(), insn.b(), insn.sc())
+            }
+            OpCode::SetTabUp
+            | OpCode::SetTable
+            | OpCode::SetI
+            | OpCode::SetField
+            | OpCode::Self_
+            | OpCode::TailCall
+            | OpCode::Return => write!(
+                f,
+                "{} {} {}{}",
+                insn.a(),
+                insn.b(),
+                insn.c(),
+                if insn.k() { "k" } else { "" }
+            ),
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use crate::{
+    gc::{GcCell, GcContext},
+    runtime::{Action, ErrorKind, Runtime, RuntimeError, Vm},
+    stdlib::helpers::ArgumentsExt,
+    types::{DetachedValue, Integer, NativeClosure, Table, Value},
+};
+use bstr::{ByteSlice, B};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+const EVENTS_TABLE: &[u8] = b"_EVENTS";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub channel: Vec<u8>,
+    pub args: Vec<DetachedValue>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EventError {
+    #[error("unknown event channel '{}'", .0.as_bstr())]
+    UnknownChannel(Vec<u8>),
+}
+
+type Listener = Arc<dyn Fn(&Event) + Send + Sync>;
+
+#[derive(Default)]
+struct Inner {
+    channels: HashMap<Vec<u8>, Vec<Listener>>,
+    queue: VecDeque<Event>,
+    next_subscription_id: Integer,
+}
+
+// Channels of events between the host and scripts. Events can be published
+// from any thread by the host and from scripts with events.publish. They are
+// queued until Runtime::pump_events delivers them to host listeners and to
+// the callbacks scripts subscribed with events.subscribe.
+#[derive(Clone, Default)]
+pub struct EventBus(Arc<Mutex<Inner>>);
+
+impl EventBus {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn register_channel<C: AsRef<[u8]>>(&self, channel: C) {
+        self.inner()
+            .channels
+            .entry(channel.as_ref().to_vec())
+            .or_default();
+    }
+
+    pub fn has_channel<C: AsRef<[u8]>>(&self, channel: C) -> bool {
+        self.inner().channels.contains_key(channel.as_ref())
+    }
+
+    pub fn publish<C: AsRef<[u8]>>(
+        &self,
+        channel: C,
+        args: Vec<DetachedValue>,
+    ) -> Result<(), EventError> {
+        let channel = channel.as_ref();
+        let mut inner = self.inner();
+        if !inner.channels.contains_key(channel) {
+            return Err(EventError::UnknownChannel(channel.to_vec()));
+        }
+        inner.queue.push_back(Event {
+            channel: channel.to_vec(),
+            args,
+        });
+        Ok(())
+    }
+
+    // Calls `f` on the host for every event of `channel` when it is pumped,
+    // before the script callbacks
+    pub fn listen<C, F>(&self, channel: C, f: F) -> Result<(), EventError>
+    where
+        C: AsRef<[u8]>,
+        F: Fn(&Event) + Send + Sync + 'static,
+    {
+        let channel = channel.as_ref();
+        match self.inner().channels.get_mut(channel) {
+            Some(listeners) => {
+                listeners.push(Arc::new(f));
+                Ok(())
+            }
+            None => Err(EventError::UnknownChannel(channel.to_vec())),
+        }
+    }
+
+    pub fn num_queued(&self) -> usize {
+        self.inner().queue.len()
+    }
+
+    // Sets the global `events` table for scripts to use the bus. Opening it
+    // again keeps the callbacks already subscribed.
+    pub fn open<'gc>(&self, gc: &'gc GcContext, vm: &mut Vm<'gc>) {
+        let key = gc.allocate_string(EVENTS_TABLE);
+        if vm.registry().borrow().get_field(key).as_table().is_none() {
+            vm.registry()
+                .borrow_mut(gc)
+                .set_field(key, gc.allocate_cell(Table::new()));
+        }
+
+        let mut table = Table::new();
+        let bus = self.clone();
+        table.set_field(
+            gc.allocate_string(B("subscribe")),
+            gc.allocate(NativeClosure::new(move |gc, vm, args| {
+                events_subscribe(&bus, gc, vm, args)
+            })),
+        );
+        table.set_field(
+            gc.allocate_string(B("unsubscribe")),
+            gc.allocate(NativeClosure::new(events_unsubscribe)),
+        );
+        let bus = self.clone();
+        table.set_field(
+            gc.allocate_string(B("publish")),
+            gc.allocate(NativeClosure::new(move |_, _, args| {
+                events_publish(&bus, args)
+            })),
+        );
+        vm.globals()
+            .borrow_mut(gc)
+            .set_field(gc.allocate_string(B("events")), gc.allocate_cell(table));
+    }
+
+    fn inner(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.0.lock().unwrap()
+    }
+}
+
+impl Runtime {
+    // Delivers the events queued in `bus`, in the order they were published.
+    // Events published while pumping are left for the next pump.
+    // This runs script callbacks to completion, so it must be called when
+    // the runtime isn't running anything else. If a callback fails, the rest
+    // of the event's callbacks are skipped and the error is returned.
+    pub fn pump_events(&mut self, bus: &EventBus) -> Result<usize, RuntimeError> {
+        if self.is_running() {
+            return Err(RuntimeError {
+                kind: ErrorKind::other("cannot pump events while the runtime is running"),
+                traceback: Vec::new(),
+            });
+        }
+
+        let num_events = bus.num_queued();
+        for _ in 0..num_events {
+            let (event, listeners) = {
+                let mut inner = bus.inner();
+                let Some(event) = inner.queue.pop_front() else {
+                    break;
+                };
+                let listeners = inner.channels.get(&event.channel).cloned();
+                (event, listeners.unwrap_or_default())
+            };
+            for listener in listeners {
+                listener(&event);
+            }
+
+            let ids = self.heap().with(|gc, vm| {
+                let mut ids: Vec<_> = subscriptions(gc, &vm.borrow(), &event.channel)
+                    .into_iter()
+                    .map(|(id, _)| id)
+                    .collect();
+                ids.sort_unstable();
+                ids
+            });
+            for id in ids {
+                // earlier callbacks may have unsubscribed this one
+                let subscribed = self.heap().with(|gc, vm| {
+                    subscription_table(gc, &vm.borrow(), &event.channel)
+                        .is_some_and(|table| !table.borrow().get(id).is_nil())
+                });
+                if !subscribed {
+                    continue;
+                }
+                self.execute(|gc, vm| {
+                    let callback = subscription_table(gc, &vm.borrow(), &event.channel)
+                        .map(|table| table.borrow().get(id))
+                        .unwrap_or_default();
+                    let args: Vec<_> = event.args.iter().map(|arg| arg.attach(gc)).collect();
+                    let call = NativeClosure::with_upvalue((callback, args), |_, _, upvalue, _| {
+                        let (callee, args) = upvalue;
+                        Ok(Action::TailCall {
+                            callee: *callee,
+                            args: args.clone(),
+                        })
+                    });
+                    Ok(gc.allocate(call).into())
+                })?;
+            }
+        }
+        Ok(num_events)
+    }
+}
+
+fn subscription_table<'gc>(
+    gc: &'gc GcContext,
+    vm: &Vm<'gc>,
+    channel: &[u8],
+) -> Option<GcCell<'gc, Table<'gc>>> {
+    vm.registry()
+        .borrow()
+        .get_field(gc.allocate_string(EVENTS_TABLE))
+        .borrow_as_table()?
+        .get_field(gc.allocate_string(channel))
+        .as_table()
+}
+
+// Subscriptions by channel, then by id
+fn events_table<'gc>(
+    gc: &'gc GcContext,
+    vm: &Vm<'gc>,
+) -> Result<GcCell<'gc, Table<'gc>>, ErrorKind> {
+    vm.registry()
+        .borrow()
+        .get_field(gc.allocate_string(EVENTS_TABLE))
+        .as_table()
+        .ok_or_else(|| ErrorKind::other("event bus is not open"))
+}
+
+fn subscriptions<'gc>(
+    gc: &'gc GcContext,
+    vm: &Vm<'gc>,
+    channel: &[u8],
+) -> Vec<(Integer, Value<'gc>)> {
+    let Some(table) = subscription_table(gc, vm, channel) else {
+        return Vec::new();
+    };
+    let table = table.borrow();
+    let mut subscriptions = Vec::new();
+    let mut key = Value::Nil;
+    while let Ok(Some((k, v))) = table.next(key) {
+        if let Value::Integer(id) = k {
+            subscriptions.push((id, v));
+        }
+        key = k;
+    }
+    subscriptions
+}
+
+fn events_subscribe<'gc>(
+    bus: &EventBus,
+    gc: &'gc GcContext,
+    vm: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let channel = args.nth(1);
+    let channel = channel.to_string()?;
+    let callback = args.nth(2).ensure_function()?;
+
+    let id = {
+        let mut inner = bus.inner();
+        if !inner.channels.contains_key(channel.as_ref()) {
+            return Err(ErrorKind::Other(
+                EventError::UnknownChannel(channel.to_vec()).to_string(),
+            ));
+        }
+        inner.next_subscription_id += 1;
+        inner.next_subscription_id
+    };
+
+    let events = events_table(gc, vm)?;
+    let channel = gc.allocate_string(channel.as_ref());
+    let table = events.borrow().get_field(channel).as_table();
+    let table = table.unwrap_or_else(|| {
+        let table = gc.allocate_cell(Table::new());
+        events.borrow_mut(gc).set_field(channel, table);
+        table
+    });
+    table.borrow_mut(gc).set(id, callback)?;
+
+    Ok(Action::Return(vec![id.into()]))
+}
+
+fn events_unsubscribe<'gc>(
+    gc: &'gc GcContext,
+    vm: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let id = args.nth(1).to_integer()?;
+
+    let events = events_table(gc, vm)?;
+    let events = events.borrow();
+    let mut key = Value::Nil;
+    while let Some((k, table)) = events.next(key)? {
+        let table = table
+            .as_table()
+            .ok_or_else(|| ErrorKind::other("invalid subscriptions of event channel"))?;
+        if !table.borrow().get(id).is_nil() {
+            table.borrow_mut(gc).set(id, Value::Nil)?;
+            return Ok(Action::Return(vec![true.into()]));
+        }
+        key = k;
+    }
+    Ok(Action::Return(vec![false.into()]))
+}
+
+fn events_publish<'gc>(bus: &EventBus, args: Vec<Value<'gc>>) -> Result<Action<'gc>, ErrorKind> {
+    let channel = args.nth(1);
+    let channel = channel.to_string()?;
+
+    let mut event_args = Vec::with_capacity(args.len().saturating_sub(2));
+    for (i, arg) in args.iter().enumerate().skip(2) {
+        match DetachedValue::detach(*arg) {
+            Some(arg) => event_args.push(arg),
+            None => {
+                return Err(ErrorKind::ArgumentError {
+                    nth: i,
+                    message: "value cannot be sent as an event argument",
+                })
+            }
+        }
+    }
+
+    bus.publish(channel, event_args)
+        .map_err(|err| ErrorKind::Other(err.to_string()))?;
+    Ok(Action::Return(Vec::new()))
+}
@@ -1,23 +1,35 @@
+mod allocator;
+mod interned;
+mod registry;
 mod string;
 mod traits;
+#[cfg(feature = "verify-heap")]
+mod verify;
 
+use crate::prelude::*;
+pub use allocator::{GcAllocator, ObjectKind, SystemAllocator};
+pub use interned::InternedStrings;
+pub use registry::RegistryKey;
 pub(crate) use string::BoxedString;
 pub use traits::{Finalizer, GarbageCollect, Tracer};
 
 use crate::{
+    collections::HashSet,
     runtime::Vm,
-    types::{LuaString, Value},
+    types::{LuaString, UserData, Value},
 };
-use hashbrown::hash_map::RawEntryMut;
-use std::{
-    borrow::Cow,
-    cell::{Cell, Ref, RefCell, RefMut},
+use alloc::{alloc::Layout, borrow::Cow};
+use core::{
+    any::Any,
+    cell::{BorrowError, Cell, Ref, RefCell, RefMut},
     fmt::Debug,
     hash::Hash,
     marker::PhantomData,
     ops::Deref,
     ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
 };
+use hashbrown::hash_map::RawEntryMut;
 use string::StringPool;
 
 pub struct GcHeap {
@@ -25,9 +37,45 @@ pub struct GcHeap {
     vm: GcCell<'static, Vm<'static>>,
 }
 
+// Objects are only reachable through GcHeap::with, so a heap can move to
+// another thread as a whole as long as the Rust state it owns can. That is
+// native closures, continuations, userdata and the allocator, which are
+// required to be MaybeSend, and the rest of what GarbageCollect types own,
+// which their safety contract requires to be Send.
+#[cfg(feature = "send")]
+unsafe impl Send for GcHeap {}
+
+// Send with the "send" feature, and implemented by every type without it
+#[cfg(feature = "send")]
+pub trait MaybeSend: Send {}
+#[cfg(feature = "send")]
+impl<T: Send> MaybeSend for T {}
+
+#[cfg(not(feature = "send"))]
+pub trait MaybeSend {}
+#[cfg(not(feature = "send"))]
+impl<T> MaybeSend for T {}
+
 impl Default for GcHeap {
     fn default() -> Self {
+        Self::with_allocator(SystemAllocator)
+    }
+}
+
+impl GcHeap {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_allocator<A: GcAllocator + 'static>(allocator: A) -> Self {
+        static NEXT_HEAP_ID: AtomicUsize = AtomicUsize::new(0);
+
         let mut gc = GcContext {
+            id: NEXT_HEAP_ID.fetch_add(1, Ordering::Relaxed) as u64,
+            allocator: Box::new(allocator),
+            fallback_allocations: Default::default(),
+            allocation_failed: Cell::new(false),
+
             pause: Cell::new(200),
             step_multiplier: Cell::new(100),
             step_size: Cell::new(13),
@@ -48,26 +96,24 @@ impl Default for GcHeap {
             gray_again: Default::default(),
 
             string_pool: Default::default(),
+            pinned_strings: Default::default(),
+            registry: Default::default(),
         };
 
         let vm = gc.allocate_cell(Vm::new(&gc));
-        let vm: GcCell<Vm> = unsafe { std::mem::transmute(vm) };
+        let vm: GcCell<Vm> = unsafe { core::mem::transmute(vm) };
         gc.root = Some(vm);
 
         Self { gc, vm }
     }
-}
-
-impl GcHeap {
-    pub fn new() -> Self {
-        Default::default()
-    }
 
     pub fn with<F, R>(&mut self, f: F) -> R
     where
         F: for<'gc> FnOnce(&'gc GcContext, GcCell<'gc, Vm<'gc>>) -> R,
     {
-        f(&mut self.gc, unsafe { std::mem::transmute(self.vm) })
+        f(&mut self.gc, unsafe {
+            core::mem::transmute::<GcCell<'static, Vm<'static>>, GcCell<'_, Vm<'_>>>(self.vm)
+        })
     }
 
     pub fn step(&mut self) {
@@ -80,6 +126,17 @@ impl GcHeap {
         self.gc.full_gc();
     }
 
+    // Panics if an invariant of the collector doesn't hold, which with the
+    // "verify-heap" feature is also checked after every step
+    #[cfg(feature = "verify-heap")]
+    pub fn verify(&self) {
+        self.gc.verify();
+    }
+
+    pub fn intern_static<S: AsRef<[u8]>>(&mut self, strings: &[S]) -> InternedStrings {
+        self.gc.intern_static(strings)
+    }
+
     pub fn force_step(&mut self, kbytes: isize) -> bool {
         let did_step = if kbytes == 0 {
             self.gc.set_debt(0);
@@ -101,11 +158,18 @@ impl GcHeap {
 
 const GCSWEEPMAX: i32 = 100;
 const PAUSEADJ: usize = 100;
-const WORK2MEM: usize = std::mem::size_of::<GcBox<Value>>();
+const WORK2MEM: usize = core::mem::size_of::<GcBox<Value>>();
 
 type GcPtr<T> = NonNull<GcBox<T>>;
 
 pub struct GcContext {
+    id: u64,
+    allocator: Box<dyn GcAllocator>,
+    // Objects the allocator failed to allocate, which the global allocator
+    // did
+    fallback_allocations: RefCell<HashSet<usize>>,
+    allocation_failed: Cell<bool>,
+
     pause: Cell<usize>,
     step_multiplier: Cell<usize>,
     step_size: Cell<usize>,
@@ -126,6 +190,8 @@ pub struct GcContext {
     gray_again: RefCell<Vec<GcPtr<dyn GarbageCollect>>>,
 
     string_pool: RefCell<StringPool>,
+    pinned_strings: RefCell<Vec<GcPtr<BoxedString>>>,
+    registry: RefCell<registry::Registry>,
 }
 
 impl Drop for GcContext {
@@ -134,7 +200,7 @@ impl Drop for GcContext {
         while let Some(ptr) = it {
             let gc_box = unsafe { ptr.as_ref() };
             it = gc_box.next;
-            let _ = unsafe { Box::from_raw(ptr.as_ptr()) };
+            unsafe { self.free(ptr) };
         }
     }
 }
@@ -186,30 +252,53 @@ impl GcContext {
     }
 
     pub fn should_perform_gc(&self) -> bool {
-        self.is_running() && self.debt() > 0
+        self.allocation_failed.get() || self.is_running() && self.debt() > 0
+    }
+
+    pub(crate) fn take_allocation_failure(&self) -> bool {
+        self.allocation_failed.replace(false)
     }
 
-    pub fn allocate<T: GarbageCollect>(&self, value: T) -> Gc<T> {
+    pub fn allocate<T: GarbageCollect>(&self, value: T) -> Gc<'_, T> {
         let color = Color::White(self.current_white);
-        let mut gc_box = Box::new(std::mem::MaybeUninit::uninit());
-        gc_box.write(GcBox {
-            color: Cell::new(color),
-            next: self.all.get(),
-            value,
+        let layout = Layout::new::<GcBox<T>>();
+        let size = layout.size() + value.extra_size();
+        let ptr = self.allocator.allocate(layout, value.object_kind()) as *mut GcBox<T>;
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| {
+            // The caller can't handle failure, so the object comes from the
+            // global allocator instead and the runtime raises an error at its
+            // next GC step (see GcAllocator)
+            let ptr = unsafe { alloc::alloc::alloc(layout) } as *mut GcBox<T>;
+            let Some(ptr) = NonNull::new(ptr) else {
+                alloc::alloc::handle_alloc_error(layout);
+            };
+            self.fallback_allocations
+                .borrow_mut()
+                .insert(ptr.as_ptr() as *mut u8 as usize);
+            self.allocation_failed.set(true);
+            ptr
         });
-        let ptr = Box::into_raw(gc_box) as *mut GcBox<T>;
-        let ptr = unsafe { NonNull::new_unchecked(ptr) };
+        unsafe {
+            ptr.as_ptr().write(GcBox {
+                color: Cell::new(color),
+                next: self.all.get(),
+                value,
+            })
+        };
         self.all.set(Some(into_ptr_to_static(ptr)));
-        self.debt
-            .set(self.debt.get() + std::mem::size_of::<GcBox<T>>() as isize);
+        self.debt.set(self.debt.get() + size as isize);
         Gc::new(ptr)
     }
 
-    pub fn allocate_cell<T: GarbageCollect>(&self, value: T) -> GcCell<T> {
+    pub fn allocate_cell<T: GarbageCollect>(&self, value: T) -> GcCell<'_, T> {
         GcCell(self.allocate(GcRefCell::new(value)))
     }
 
-    pub fn allocate_string<'a, T>(&self, string: T) -> LuaString
+    pub fn allocate_userdata<T: Any + MaybeSend>(&self, data: T) -> GcCell<'_, UserData<'_>> {
+        self.allocate_cell(UserData::new(data))
+    }
+
+    pub fn allocate_string<'a, T>(&self, string: T) -> LuaString<'_>
     where
         T: Into<Cow<'a, [u8]>>,
     {
@@ -234,12 +323,36 @@ impl GcContext {
         LuaString(Gc::new(interned))
     }
 
-    fn full_gc(&mut self) {
-        if matches!(self.phase, Phase::Propagate | Phase::Atomic) {
-            self.phase = Phase::Sweep;
-            self.sweep = self.all.get();
-            self.prev_sweep.take();
+    // Allocates `strings` and keeps them alive until the heap is dropped
+    pub fn intern_static<S: AsRef<[u8]>>(&self, strings: &[S]) -> InternedStrings {
+        let strings: Vec<_> = strings
+            .iter()
+            .map(|s| self.allocate_string(s.as_ref()).0.ptr)
+            .collect();
+        self.pinned_strings.borrow_mut().extend_from_slice(&strings);
+        InternedStrings::new(self.id, strings)
+    }
+
+    unsafe fn free(&self, ptr: GcPtr<dyn GarbageCollect>) {
+        let gc_box = ptr.as_ref();
+        let layout = Layout::for_value(gc_box);
+        let kind = gc_box.value.object_kind();
+        core::ptr::drop_in_place(ptr.as_ptr());
+        let ptr = ptr.as_ptr() as *mut u8;
+        if self
+            .fallback_allocations
+            .borrow_mut()
+            .remove(&(ptr as usize))
+        {
+            alloc::alloc::dealloc(ptr, layout);
+        } else {
+            self.allocator.deallocate(ptr, layout, kind);
         }
+    }
+
+    fn full_gc(&mut self) {
+        // Finish the cycle in progress, since marking can't be abandoned
+        // with objects still gray
         while self.phase != Phase::Pause {
             self.do_single_step();
         }
@@ -257,6 +370,8 @@ impl GcContext {
             }
         }
         self.set_debt_for_pause_phase();
+        #[cfg(feature = "verify-heap")]
+        self.verify();
     }
 
     fn step(&mut self) {
@@ -268,13 +383,15 @@ impl GcContext {
             debt -= work as isize;
             if self.phase == Phase::Pause {
                 self.set_debt_for_pause_phase();
-                return;
+                break;
             }
             if debt <= -step_size {
                 self.set_debt(debt);
-                return;
+                break;
             }
         }
+        #[cfg(feature = "verify-heap")]
+        self.verify();
     }
 
     fn set_debt(&self, debt: isize) {
@@ -305,11 +422,9 @@ impl GcContext {
     fn propagate_gray(&mut self, ptr: GcPtr<dyn GarbageCollect>) -> usize {
         let gc_box = unsafe { ptr.as_ref() };
         debug_assert_eq!(gc_box.color.get(), Color::Gray);
-        gc_box.value.trace(&mut Tracer {
-            gray: &mut self.gray,
-        });
+        gc_box.value.trace(&mut Tracer::new(&mut self.gray));
         gc_box.color.set(Color::Black);
-        std::mem::size_of_val(gc_box)
+        core::mem::size_of_val(gc_box)
     }
 
     fn do_single_step(&mut self) -> usize {
@@ -347,9 +462,16 @@ impl GcContext {
     fn do_pause(&mut self) {
         debug_assert!(self.gray.is_empty());
         debug_assert!(self.gray_again.borrow().is_empty());
-        self.root.unwrap().trace(&mut Tracer {
-            gray: &mut self.gray,
-        });
+        self.trace_roots();
+    }
+
+    fn trace_roots(&mut self) {
+        let mut tracer = Tracer::new(&mut self.gray);
+        self.root.unwrap().trace(&mut tracer);
+        for ptr in self.pinned_strings.borrow().iter() {
+            Gc::new(*ptr).trace(&mut tracer);
+        }
+        self.registry.borrow_mut().trace(&mut tracer);
     }
 
     fn do_propagate(&mut self) -> usize {
@@ -361,20 +483,20 @@ impl GcContext {
     }
 
     fn do_atomic(&mut self) -> usize {
-        self.root.unwrap().trace(&mut Tracer {
-            gray: &mut self.gray,
-        });
+        self.trace_roots();
 
         let mut work = 0;
         while let Some(ptr) = self.gray.pop() {
             work += self.propagate_gray(ptr);
         }
-        std::mem::swap(&mut self.gray, &mut self.gray_again.borrow_mut());
+        core::mem::swap(&mut self.gray, &mut self.gray_again.borrow_mut());
         while let Some(ptr) = self.gray.pop() {
             work += self.propagate_gray(ptr);
         }
 
         self.current_white = !self.current_white;
+        #[cfg(feature = "verify-heap")]
+        self.verify_marked();
         work
     }
 
@@ -393,7 +515,8 @@ impl GcContext {
 
         while let Some(ptr) = self.sweep {
             let gc_box = unsafe { ptr.as_ref() };
-            work += std::mem::size_of_val(gc_box);
+            let size = core::mem::size_of_val(gc_box) + gc_box.value.extra_size();
+            work += size;
             if gc_box.color.get() == other_white {
                 if let Some(prev) = &mut self.prev_sweep {
                     let prev = unsafe { prev.as_mut() };
@@ -402,10 +525,10 @@ impl GcContext {
                     self.all.set(gc_box.next);
                 }
                 self.sweep = gc_box.next;
-                debt -= std::mem::size_of_val(gc_box) as isize;
+                debt -= size as isize;
 
                 gc_box.value.finalize(&mut finalizer);
-                let _ = unsafe { Box::from_raw(ptr.as_ptr()) };
+                unsafe { self.free(ptr) };
             } else {
                 debug_assert_eq!(gc_box.color.get(), Color::Black);
                 gc_box.color.set(current_white);
@@ -425,7 +548,7 @@ impl GcContext {
 }
 
 fn into_ptr_to_static<'a>(ptr: GcPtr<dyn GarbageCollect + 'a>) -> GcPtr<dyn GarbageCollect> {
-    unsafe { std::mem::transmute(ptr) }
+    unsafe { core::mem::transmute(ptr) }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -473,7 +596,7 @@ impl<T: GarbageCollect> Deref for Gc<'_, T> {
 }
 
 impl<T: GarbageCollect + Debug> Debug for Gc<'_, T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         f.debug_tuple("Gc").field(self.deref()).finish()
     }
 }
@@ -487,13 +610,13 @@ impl<T: GarbageCollect + PartialEq> PartialEq for Gc<'_, T> {
 impl<T: GarbageCollect + Eq> Eq for Gc<'_, T> {}
 
 impl<T: GarbageCollect + PartialOrd> PartialOrd for Gc<'_, T> {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
         self.deref().partial_cmp(other.deref())
     }
 }
 
 impl<T: GarbageCollect + Hash> Hash for Gc<'_, T> {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
         self.deref().hash(state);
     }
 }
@@ -506,6 +629,11 @@ impl<T: GarbageCollect> AsRef<T> for Gc<'_, T> {
 
 unsafe impl<T: GarbageCollect> GarbageCollect for Gc<'_, T> {
     fn trace(&self, tracer: &mut Tracer) {
+        #[cfg(feature = "verify-heap")]
+        if tracer.verifying {
+            tracer.gray.push(into_ptr_to_static(self.ptr));
+            return;
+        }
         let gc_box = unsafe { self.ptr.as_ref() };
         let color = &gc_box.color;
         if matches!(color.get(), Color::White(_)) {
@@ -547,6 +675,17 @@ unsafe impl<T: GarbageCollect> GarbageCollect for GcRefCell<T> {
     fn trace(&self, tracer: &mut Tracer) {
         self.0.borrow().trace(tracer);
     }
+
+    fn object_kind(&self) -> ObjectKind {
+        self.0
+            .try_borrow()
+            .map_or(ObjectKind::Other, |value| value.object_kind())
+    }
+
+    #[cfg(feature = "verify-heap")]
+    fn type_name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
 }
 
 impl<T: GarbageCollect> GcRefCell<T> {
@@ -566,7 +705,7 @@ impl<T: GarbageCollect> Clone for GcCell<'_, T> {
 impl<T: GarbageCollect> Copy for GcCell<'_, T> {}
 
 impl<T: GarbageCollect + Debug> Debug for GcCell<'_, T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         f.debug_tuple("GcCell").field(&self.0 .0).finish()
     }
 }
@@ -587,11 +726,15 @@ impl<T: GarbageCollect> GcCell<'_, T> {
         gc_box.value.0.as_ptr()
     }
 
-    pub fn borrow(&self) -> Ref<T> {
+    pub fn borrow(&self) -> Ref<'_, T> {
         self.0 .0.borrow()
     }
 
-    pub fn borrow_mut(&self, gc: &GcContext) -> RefMut<T> {
+    pub fn try_borrow(&self) -> Result<Ref<'_, T>, BorrowError> {
+        self.0 .0.try_borrow()
+    }
+
+    pub fn borrow_mut(&self, gc: &GcContext) -> RefMut<'_, T> {
         let b = self.0 .0.borrow_mut();
         gc.write_barrier(self.0.ptr);
         b
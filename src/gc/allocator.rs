@@ -0,0 +1,47 @@
+use super::MaybeSend;
+use alloc::alloc::Layout;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjectKind {
+    String,
+    Table,
+    Function,
+    Proto,
+    Upvalue,
+    UserData,
+    Thread,
+    Other,
+}
+
+// Memory of the objects of a heap, with the same sizes the collector uses
+// for its accounting. The buffers objects own, like the bytes of strings and
+// the parts of tables, come from the global allocator.
+// Callers of GcContext::allocate can't handle failure, so a refused
+// allocation doesn't fail there: the object comes from the global allocator
+// instead, and the script running gets a "not enough memory" error at the
+// next GC step, as with Vm::set_memory_limit. Until then, native code can go
+// on allocating, so the hook can't enforce a hard limit on memory or make a
+// particular allocation fail.
+/// # Safety
+/// `allocate` must return null or a block of memory fitting `layout` that
+/// stays valid until it is passed to `deallocate`, as with `GlobalAlloc`.
+pub unsafe trait GcAllocator: MaybeSend {
+    fn allocate(&self, layout: Layout, kind: ObjectKind) -> *mut u8;
+
+    /// # Safety
+    /// `ptr` must have been returned by `allocate` with the same `layout`.
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout, kind: ObjectKind);
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemAllocator;
+
+unsafe impl GcAllocator for SystemAllocator {
+    fn allocate(&self, layout: Layout, _: ObjectKind) -> *mut u8 {
+        unsafe { alloc::alloc::alloc(layout) }
+    }
+
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout, _: ObjectKind) {
+        alloc::alloc::dealloc(ptr, layout);
+    }
+}
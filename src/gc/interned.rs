@@ -0,0 +1,57 @@
+use super::{BoxedString, Gc, GcContext, GcPtr};
+use crate::{
+    prelude::*,
+    types::{LuaString, Value},
+};
+use alloc::sync::Arc;
+
+// Strings that stay allocated for as long as their heap, so that values can
+// be matched against them by address instead of by contents
+#[derive(Debug, Clone)]
+pub struct InternedStrings {
+    heap_id: u64,
+    strings: Arc<[GcPtr<BoxedString>]>,
+}
+
+// The pointers are only followed through the GcContext they came from
+#[cfg(feature = "send")]
+unsafe impl Send for InternedStrings {}
+#[cfg(feature = "send")]
+unsafe impl Sync for InternedStrings {}
+
+impl InternedStrings {
+    pub(super) fn new(heap_id: u64, strings: Vec<GcPtr<BoxedString>>) -> Self {
+        Self {
+            heap_id,
+            strings: strings.into(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+
+    // Panics if the strings weren't interned in `gc`
+    pub fn get<'gc>(&self, gc: &'gc GcContext, index: usize) -> Option<LuaString<'gc>> {
+        assert_eq!(self.heap_id, gc.id, "strings were interned in another heap");
+        self.strings.get(index).map(|ptr| LuaString(Gc::new(*ptr)))
+    }
+
+    // Index of the string equal to `value`
+    pub fn position(&self, value: Value) -> Option<usize> {
+        match value {
+            Value::String(s) => self.strings.iter().position(|ptr| *ptr == s.0.ptr),
+            _ => None,
+        }
+    }
+}
+
+impl<'gc> Value<'gc> {
+    pub fn matches_interned(&self, strings: &InternedStrings) -> Option<usize> {
+        strings.position(*self)
+    }
+}
@@ -0,0 +1,95 @@
+use super::{GarbageCollect, GcContext, Tracer};
+use crate::{prelude::*, sync::Mutex, types::Value};
+use alloc::sync::Arc;
+use core::fmt;
+
+// Handle to a value kept alive by its heap until the handle is dropped.
+// Unlike Value it isn't tied to the 'gc lifetime, so hosts can hold on to it
+// between calls into the heap.
+pub struct RegistryKey {
+    heap_id: u64,
+    index: usize,
+    dropped: Arc<Mutex<Vec<usize>>>,
+}
+
+impl fmt::Debug for RegistryKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegistryKey")
+            .field("heap_id", &self.heap_id)
+            .field("index", &self.index)
+            .finish()
+    }
+}
+
+impl Drop for RegistryKey {
+    fn drop(&mut self) {
+        // The slot is freed the next time the heap touches the registry,
+        // as the key may be dropped outside of GcHeap::with
+        self.dropped.lock().unwrap().push(self.index);
+    }
+}
+
+#[derive(Default)]
+pub(super) struct Registry {
+    values: Vec<Value<'static>>,
+    free: Vec<usize>,
+    dropped: Arc<Mutex<Vec<usize>>>,
+}
+
+impl Registry {
+    fn reclaim(&mut self) {
+        for index in self.dropped.lock().unwrap().drain(..) {
+            self.values[index] = Value::Nil;
+            self.free.push(index);
+        }
+    }
+
+    fn insert(&mut self, heap_id: u64, value: Value) -> RegistryKey {
+        self.reclaim();
+        let value = unsafe { core::mem::transmute::<Value, Value<'static>>(value) };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.values[index] = value;
+                index
+            }
+            None => {
+                self.values.push(value);
+                self.values.len() - 1
+            }
+        };
+        RegistryKey {
+            heap_id,
+            index,
+            dropped: self.dropped.clone(),
+        }
+    }
+
+    pub(super) fn trace(&mut self, tracer: &mut Tracer) {
+        self.reclaim();
+        self.values.trace(tracer);
+    }
+}
+
+impl GcContext {
+    pub fn create_registry_value(&self, value: Value) -> RegistryKey {
+        self.registry.borrow_mut().insert(self.id, value)
+    }
+
+    // Panics if `key` was created by another heap
+    pub fn registry_value<'gc>(&'gc self, key: &RegistryKey) -> Value<'gc> {
+        assert_eq!(key.heap_id, self.id, "key was created by another heap");
+        let value = self.registry.borrow().values[key.index];
+        unsafe { core::mem::transmute::<Value<'static>, Value<'gc>>(value) }
+    }
+
+    pub fn replace_registry_value(&self, key: &RegistryKey, value: Value) {
+        assert_eq!(key.heap_id, self.id, "key was created by another heap");
+        self.registry.borrow_mut().values[key.index] =
+            unsafe { core::mem::transmute::<Value, Value<'static>>(value) };
+    }
+
+    // Same as dropping the key
+    pub fn remove_registry_value(&self, key: RegistryKey) {
+        drop(key);
+    }
+}
@@ -1,10 +1,11 @@
-use super::{Finalizer, GarbageCollect, GcPtr};
-use hashbrown::HashMap;
-use rustc_hash::FxHasher;
-use std::{
+use super::{Finalizer, GarbageCollect, GcPtr, ObjectKind};
+use crate::prelude::*;
+use core::{
     hash::{Hash, Hasher},
     ops::Deref,
 };
+use hashbrown::HashMap;
+use rustc_hash::FxHasher;
 
 pub(super) type StringPool = HashMap<GcPtr<BoxedString>, (), ()>;
 
@@ -41,6 +42,14 @@ unsafe impl GarbageCollect for BoxedString {
             .unwrap();
         unsafe { table.remove(bucket) };
     }
+
+    fn object_kind(&self) -> ObjectKind {
+        ObjectKind::String
+    }
+
+    fn extra_size(&self) -> usize {
+        self.0.len()
+    }
 }
 
 impl BoxedString {
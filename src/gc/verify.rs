@@ -0,0 +1,124 @@
+use super::{Color, GarbageCollect, Gc, GcContext, GcPtr, Phase, Tracer};
+use crate::prelude::*;
+
+// Checks of the invariants of the collector, for the "verify-heap" feature.
+// They catch a container that doesn't trace some of its objects or that is
+// changed without going through the write barrier, which otherwise shows up
+// as a use after free long after the fact.
+impl GcContext {
+    // What can be checked in the current phase. Edges can't be followed
+    // while sweeping, as they may lead to objects that were freed already;
+    // verify_marked checks them before sweeping starts instead.
+    pub(super) fn verify(&self) {
+        let current_white = Color::White(self.current_white);
+        self.for_each_object(|ptr| {
+            let gc_box = unsafe { ptr.as_ref() };
+            let color = gc_box.color.get();
+            match self.phase {
+                Phase::Pause if color != current_white => self.verify_failed(format_args!(
+                    "{} is {} between cycles",
+                    gc_box.value.type_name(),
+                    self.color_name(color)
+                )),
+                // A black object is never traced again in this cycle, so its
+                // objects must have been marked already, or it must have
+                // been made gray again by the write barrier
+                Phase::Propagate | Phase::Atomic if color == Color::Black => {
+                    for child in children(&gc_box.value) {
+                        let child_color = unsafe { child.as_ref() }.color.get();
+                        if matches!(child_color, Color::White(_)) {
+                            self.verify_edge_failed(gc_box.value.type_name(), color, child);
+                        }
+                    }
+                }
+                Phase::Sweep if color == Color::Gray => self.verify_failed(format_args!(
+                    "{} is still gray while sweeping",
+                    gc_box.value.type_name()
+                )),
+                _ => (),
+            }
+        });
+    }
+
+    // Called once marking is done: everything reachable from the roots must
+    // be black, or it would be freed while still in use
+    pub(super) fn verify_marked(&self) {
+        let mut roots = Vec::new();
+        let mut tracer = Tracer {
+            gray: &mut roots,
+            verifying: true,
+        };
+        self.root.unwrap().trace(&mut tracer);
+        for ptr in self.pinned_strings.borrow().iter() {
+            Gc::new(*ptr).trace(&mut tracer);
+        }
+        self.registry.borrow_mut().trace(&mut tracer);
+        for root in roots {
+            if unsafe { root.as_ref() }.color.get() != Color::Black {
+                self.verify_edge_failed("root", Color::Black, root);
+            }
+        }
+
+        self.for_each_object(|ptr| {
+            let gc_box = unsafe { ptr.as_ref() };
+            let color = gc_box.color.get();
+            if color == Color::White(!self.current_white) {
+                return;
+            }
+            for child in children(&gc_box.value) {
+                if unsafe { child.as_ref() }.color.get() != Color::Black {
+                    self.verify_edge_failed(gc_box.value.type_name(), color, child);
+                }
+            }
+        });
+    }
+
+    fn for_each_object<F: FnMut(GcPtr<dyn GarbageCollect>)>(&self, mut f: F) {
+        let mut it = self.all.get();
+        while let Some(ptr) = it {
+            it = unsafe { ptr.as_ref() }.next;
+            f(ptr);
+        }
+    }
+
+    fn verify_edge_failed(
+        &self,
+        container: &str,
+        container_color: Color,
+        child: GcPtr<dyn GarbageCollect>,
+    ) -> ! {
+        let child = unsafe { child.as_ref() };
+        self.verify_failed(format_args!(
+            "{container} ({}) refers to {} ({})",
+            self.color_name(container_color),
+            child.value.type_name(),
+            self.color_name(child.color.get())
+        ))
+    }
+
+    fn color_name(&self, color: Color) -> &'static str {
+        match color {
+            Color::Black => "black",
+            Color::Gray => "gray",
+            Color::White(white) if white == self.current_white => "white",
+            // Objects that weren't marked in the cycle being swept
+            Color::White(_) => "unmarked",
+        }
+    }
+
+    fn verify_failed(&self, message: core::fmt::Arguments) -> ! {
+        panic!(
+            "heap verification failed in the {:?} phase: {message}",
+            self.phase
+        )
+    }
+}
+
+fn children(value: &dyn GarbageCollect) -> Vec<GcPtr<dyn GarbageCollect>> {
+    let mut children = Vec::new();
+    value.trace(&mut Tracer {
+        gray: &mut children,
+        verifying: true,
+    });
+    children
+}
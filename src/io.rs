@@ -0,0 +1,375 @@
+// The parts of std::io that the compiler, binary chunks and value formatting
+// use. Without the "std" feature they are implemented here for byte slices,
+// cursors and vectors, with errors that only carry a kind and a message.
+#[cfg(feature = "std")]
+pub use byteorder::{ReadBytesExt, WriteBytesExt};
+#[cfg(feature = "std")]
+pub use std::io::{BufRead, BufReader, Bytes, Cursor, Error, ErrorKind, Read, Result, Take, Write};
+
+#[cfg(not(feature = "std"))]
+pub use no_std::*;
+
+#[cfg(not(feature = "std"))]
+mod no_std {
+    use crate::prelude::*;
+    use byteorder::ByteOrder;
+    use core::fmt;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ErrorKind {
+        InvalidInput,
+        InvalidData,
+        UnexpectedEof,
+        WriteZero,
+        Other,
+    }
+
+    impl ErrorKind {
+        fn as_str(self) -> &'static str {
+            match self {
+                Self::InvalidInput => "invalid input parameter",
+                Self::InvalidData => "invalid data",
+                Self::UnexpectedEof => "unexpected end of file",
+                Self::WriteZero => "write zero",
+                Self::Other => "other error",
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct Error {
+        kind: ErrorKind,
+        message: Option<String>,
+    }
+
+    impl Error {
+        pub fn new<M: Into<String>>(kind: ErrorKind, message: M) -> Self {
+            Self {
+                kind,
+                message: Some(message.into()),
+            }
+        }
+
+        pub fn kind(&self) -> ErrorKind {
+            self.kind
+        }
+    }
+
+    impl From<ErrorKind> for Error {
+        fn from(kind: ErrorKind) -> Self {
+            Self {
+                kind,
+                message: None,
+            }
+        }
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(self.message.as_deref().unwrap_or(self.kind.as_str()))
+        }
+    }
+
+    impl core::error::Error for Error {}
+
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    pub trait Read {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+        fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.read(buf)? {
+                    0 => return Err(ErrorKind::UnexpectedEof.into()),
+                    n => buf = &mut buf[n..],
+                }
+            }
+            Ok(())
+        }
+
+        fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+            let start = buf.len();
+            let mut chunk = [0; 256];
+            loop {
+                match self.read(&mut chunk)? {
+                    0 => return Ok(buf.len() - start),
+                    n => buf.extend_from_slice(&chunk[..n]),
+                }
+            }
+        }
+
+        fn bytes(self) -> Bytes<Self>
+        where
+            Self: Sized,
+        {
+            Bytes(self)
+        }
+
+        fn take(self, limit: u64) -> Take<Self>
+        where
+            Self: Sized,
+        {
+            Take { inner: self, limit }
+        }
+    }
+
+    impl<R: Read + ?Sized> Read for &mut R {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            (**self).read(buf)
+        }
+    }
+
+    impl Read for &[u8] {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let n = buf.len().min(self.len());
+            let (read, rest) = self.split_at(n);
+            buf[..n].copy_from_slice(read);
+            *self = rest;
+            Ok(n)
+        }
+    }
+
+    pub struct Take<R> {
+        inner: R,
+        limit: u64,
+    }
+
+    impl<R: Read> Read for Take<R> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let max = buf
+                .len()
+                .min(usize::try_from(self.limit).unwrap_or(usize::MAX));
+            let n = self.inner.read(&mut buf[..max])?;
+            self.limit -= n as u64;
+            Ok(n)
+        }
+    }
+
+    pub struct Bytes<R>(R);
+
+    impl<R: Read> Iterator for Bytes<R> {
+        type Item = Result<u8>;
+
+        fn next(&mut self) -> Option<Result<u8>> {
+            let mut byte = 0;
+            match self.0.read(core::slice::from_mut(&mut byte)) {
+                Ok(0) => None,
+                Ok(_) => Some(Ok(byte)),
+                Err(err) => Some(Err(err)),
+            }
+        }
+    }
+
+    pub trait BufRead: Read {
+        fn fill_buf(&mut self) -> Result<&[u8]>;
+
+        fn consume(&mut self, amt: usize);
+    }
+
+    impl<B: BufRead + ?Sized> BufRead for &mut B {
+        fn fill_buf(&mut self) -> Result<&[u8]> {
+            (**self).fill_buf()
+        }
+
+        fn consume(&mut self, amt: usize) {
+            (**self).consume(amt)
+        }
+    }
+
+    impl BufRead for &[u8] {
+        fn fill_buf(&mut self) -> Result<&[u8]> {
+            Ok(self)
+        }
+
+        fn consume(&mut self, amt: usize) {
+            *self = &self[amt..];
+        }
+    }
+
+    #[derive(Debug, Clone, Default)]
+    pub struct Cursor<T> {
+        inner: T,
+        pos: u64,
+    }
+
+    impl<T> Cursor<T> {
+        pub fn new(inner: T) -> Self {
+            Self { inner, pos: 0 }
+        }
+
+        pub fn into_inner(self) -> T {
+            self.inner
+        }
+
+        pub fn get_ref(&self) -> &T {
+            &self.inner
+        }
+
+        pub fn position(&self) -> u64 {
+            self.pos
+        }
+
+        pub fn set_position(&mut self, pos: u64) {
+            self.pos = pos;
+        }
+    }
+
+    impl<T: AsRef<[u8]>> Cursor<T> {
+        fn remaining(&self) -> &[u8] {
+            let inner = self.inner.as_ref();
+            &inner[(self.pos as usize).min(inner.len())..]
+        }
+    }
+
+    impl<T: AsRef<[u8]>> Read for Cursor<T> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let n = self.remaining().read(buf)?;
+            self.pos += n as u64;
+            Ok(n)
+        }
+    }
+
+    impl<T: AsRef<[u8]>> BufRead for Cursor<T> {
+        fn fill_buf(&mut self) -> Result<&[u8]> {
+            Ok(self.remaining())
+        }
+
+        fn consume(&mut self, amt: usize) {
+            self.pos += amt as u64;
+        }
+    }
+
+    pub trait Write {
+        fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.write(buf)? {
+                    0 => return Err(ErrorKind::WriteZero.into()),
+                    n => buf = &buf[n..],
+                }
+            }
+            Ok(())
+        }
+
+        fn write_fmt(&mut self, args: fmt::Arguments) -> Result<()> {
+            struct Adapter<'a, W: ?Sized> {
+                writer: &'a mut W,
+                error: Option<Error>,
+            }
+
+            impl<W: Write + ?Sized> fmt::Write for Adapter<'_, W> {
+                fn write_str(&mut self, s: &str) -> fmt::Result {
+                    self.writer.write_all(s.as_bytes()).map_err(|err| {
+                        self.error = Some(err);
+                        fmt::Error
+                    })
+                }
+            }
+
+            let mut adapter = Adapter {
+                writer: self,
+                error: None,
+            };
+            fmt::write(&mut adapter, args).map_err(|_| {
+                adapter
+                    .error
+                    .unwrap_or_else(|| Error::new(ErrorKind::Other, "formatter error"))
+            })
+        }
+    }
+
+    impl<W: Write + ?Sized> Write for &mut W {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            (**self).write(buf)
+        }
+    }
+
+    impl Write for Vec<u8> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    impl Write for Cursor<&mut [u8]> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            let pos = (self.pos as usize).min(self.inner.len());
+            let n = buf.len().min(self.inner.len() - pos);
+            self.inner[pos..pos + n].copy_from_slice(&buf[..n]);
+            self.pos += n as u64;
+            Ok(n)
+        }
+    }
+
+    // Like the extension traits of byteorder, which need std::io
+    pub trait ReadBytesExt: Read {
+        fn read_u8(&mut self) -> Result<u8> {
+            let mut buf = [0; 1];
+            self.read_exact(&mut buf)?;
+            Ok(buf[0])
+        }
+
+        fn read_u32<T: ByteOrder>(&mut self) -> Result<u32> {
+            let mut buf = [0; 4];
+            self.read_exact(&mut buf)?;
+            Ok(T::read_u32(&buf))
+        }
+
+        fn read_u64<T: ByteOrder>(&mut self) -> Result<u64> {
+            let mut buf = [0; 8];
+            self.read_exact(&mut buf)?;
+            Ok(T::read_u64(&buf))
+        }
+
+        fn read_i32<T: ByteOrder>(&mut self) -> Result<i32> {
+            self.read_u32::<T>().map(|x| x as i32)
+        }
+
+        fn read_i64<T: ByteOrder>(&mut self) -> Result<i64> {
+            self.read_u64::<T>().map(|x| x as i64)
+        }
+
+        fn read_f32<T: ByteOrder>(&mut self) -> Result<f32> {
+            self.read_u32::<T>().map(f32::from_bits)
+        }
+
+        fn read_f64<T: ByteOrder>(&mut self) -> Result<f64> {
+            self.read_u64::<T>().map(f64::from_bits)
+        }
+    }
+
+    impl<R: Read + ?Sized> ReadBytesExt for R {}
+
+    pub trait WriteBytesExt: Write {
+        fn write_u8(&mut self, n: u8) -> Result<()> {
+            self.write_all(&[n])
+        }
+
+        fn write_u32<T: ByteOrder>(&mut self, n: u32) -> Result<()> {
+            let mut buf = [0; 4];
+            T::write_u32(&mut buf, n);
+            self.write_all(&buf)
+        }
+
+        fn write_u64<T: ByteOrder>(&mut self, n: u64) -> Result<()> {
+            let mut buf = [0; 8];
+            T::write_u64(&mut buf, n);
+            self.write_all(&buf)
+        }
+
+        fn write_i64<T: ByteOrder>(&mut self, n: i64) -> Result<()> {
+            self.write_u64::<T>(n as u64)
+        }
+
+        fn write_f64<T: ByteOrder>(&mut self, n: f64) -> Result<()> {
+            self.write_u64::<T>(n.to_bits())
+        }
+    }
+
+    impl<W: Write + ?Sized> WriteBytesExt for W {}
+}
@@ -1,12 +1,14 @@
 mod token;
 
+use crate::prelude::*;
 pub use token::Token;
 
-use crate::{gc::GcContext, string};
-use std::{
-    collections::VecDeque,
+use crate::{
+    gc::GcContext,
     io::{Bytes, Read},
+    string::{self, Numeral},
 };
+use alloc::collections::VecDeque;
 
 #[derive(Debug, thiserror::Error)]
 pub enum LexerError {
@@ -38,7 +40,7 @@ pub enum LexerError {
     Utf8ValueTooLarge,
 
     #[error(transparent)]
-    Io(#[from] std::io::Error),
+    Io(#[from] crate::io::Error),
 }
 
 pub struct Lexer<'gc, R: Read> {
@@ -65,7 +67,7 @@ impl<'gc, R: Read> Lexer<'gc, R> {
     pub fn consume_if(
         &mut self,
         func: impl Fn(&Token) -> bool,
-    ) -> Result<Option<Token>, LexerError> {
+    ) -> Result<Option<Token<'_>>, LexerError> {
         if let Some(token) = self.peek()? {
             if func(token) {
                 return self.consume();
@@ -87,7 +89,7 @@ impl<'gc, R: Read> Lexer<'gc, R> {
         Ok(self.peeked.front())
     }
 
-    pub fn peek2(&mut self) -> Result<Option<&Token>, LexerError> {
+    pub fn peek2(&mut self) -> Result<Option<&Token<'_>>, LexerError> {
         if self.peeked.len() < 2 {
             if let Some(token) = self.inner.consume_token()? {
                 self.peeked.push_back(token);
@@ -109,6 +111,7 @@ struct LexerInner<'gc, R: Read> {
 }
 
 impl<'gc, R: Read> LexerInner<'gc, R> {
+    #[allow(clippy::unbuffered_bytes)]
     fn new(gc: &'gc GcContext, reader: R) -> LexerInner<'gc, R> {
         Self {
             gc,
@@ -254,7 +257,7 @@ impl<'gc, R: Read> LexerInner<'gc, R> {
         Ok(None)
     }
 
-    fn consume_newline(&mut self) -> std::io::Result<()> {
+    fn consume_newline(&mut self) -> crate::io::Result<()> {
         let ch = self.consume_if(is_newline)?.unwrap();
         self.consume_if(|next| is_newline(next) && next != ch)?;
         self.lineno += 1;
@@ -263,15 +266,15 @@ impl<'gc, R: Read> LexerInner<'gc, R> {
 
     fn consume_numeral(&mut self) -> Result<Token<'gc>, LexerError> {
         let first_ch = self.consume()?.unwrap();
-        let mut bytes = Vec::new();
-        let is_hex = if first_ch == b'0'
-            && self
-                .consume_if(|ch| ch.eq_ignore_ascii_case(&b'x'))?
-                .is_some()
-        {
-            true
+        let mut bytes = vec![first_ch];
+        let is_hex = if first_ch == b'0' {
+            if let Some(ch) = self.consume_if(|ch| ch.eq_ignore_ascii_case(&b'x'))? {
+                bytes.push(ch);
+                true
+            } else {
+                false
+            }
         } else {
-            bytes.push(first_ch);
             false
         };
         let exp_ch = if is_hex { b'p' } else { b'e' };
@@ -290,23 +293,11 @@ impl<'gc, R: Read> LexerInner<'gc, R> {
         if let Some(ch) = self.consume_if(is_lua_alphabetic)? {
             bytes.push(ch);
         }
-        if is_hex {
-            if let Some(i) = string::parse_positive_integer_with_base(&bytes, 16) {
-                return Ok(Token::Integer(i));
-            }
-            if let Some(x) = string::parse_positive_hex_float(&bytes) {
-                return Ok(Token::Float(x));
-            }
-        } else {
-            let string = String::from_utf8(bytes).map_err(|_| LexerError::MalformedNumber)?;
-            if let Ok(i) = string.parse() {
-                return Ok(Token::Integer(i));
-            }
-            if let Ok(x) = string.parse() {
-                return Ok(Token::Float(x));
-            }
+        match string::parse_numeral(&bytes) {
+            Some(Numeral::Integer(i)) => Ok(Token::Integer(i)),
+            Some(Numeral::Float(x)) => Ok(Token::Float(x)),
+            None => Err(LexerError::MalformedNumber),
         }
-        Err(LexerError::MalformedNumber)
     }
 
     fn consume_string(&mut self) -> Result<Token<'gc>, LexerError> {
@@ -431,7 +422,7 @@ impl<'gc, R: Read> LexerInner<'gc, R> {
         Ok(())
     }
 
-    fn consume_zap(&mut self) -> std::io::Result<()> {
+    fn consume_zap(&mut self) -> crate::io::Result<()> {
         loop {
             match self.peek()? {
                 Some(ch) if is_newline(ch) => self.consume_newline()?,
@@ -519,7 +510,7 @@ impl<'gc, R: Read> LexerInner<'gc, R> {
         Err(LexerError::UnfinishedToken("long comment"))
     }
 
-    fn peek(&mut self) -> std::io::Result<Option<u8>> {
+    fn peek(&mut self) -> crate::io::Result<Option<u8>> {
         if self.peeked.is_empty() {
             if let Some(ch) = self.bytes.next().transpose()? {
                 self.peeked.push_back(ch);
@@ -528,7 +519,7 @@ impl<'gc, R: Read> LexerInner<'gc, R> {
         Ok(self.peeked.front().copied())
     }
 
-    fn peek2(&mut self) -> std::io::Result<Option<u8>> {
+    fn peek2(&mut self) -> crate::io::Result<Option<u8>> {
         if self.peeked.len() < 2 {
             if let Some(ch) = self.bytes.next().transpose()? {
                 self.peeked.push_back(ch);
@@ -537,7 +528,7 @@ impl<'gc, R: Read> LexerInner<'gc, R> {
         Ok(self.peeked.get(1).copied())
     }
 
-    fn consume(&mut self) -> std::io::Result<Option<u8>> {
+    fn consume(&mut self) -> crate::io::Result<Option<u8>> {
         if let Some(peeked) = self.peeked.pop_front() {
             Ok(Some(peeked))
         } else {
@@ -545,7 +536,7 @@ impl<'gc, R: Read> LexerInner<'gc, R> {
         }
     }
 
-    fn consume_if(&mut self, func: impl Fn(u8) -> bool) -> std::io::Result<Option<u8>> {
+    fn consume_if(&mut self, func: impl Fn(u8) -> bool) -> crate::io::Result<Option<u8>> {
         if let Some(ch) = self.peek()? {
             if func(ch) {
                 return self.consume();
@@ -554,7 +545,7 @@ impl<'gc, R: Read> LexerInner<'gc, R> {
         Ok(None)
     }
 
-    fn consume_if_eq(&mut self, expected: u8) -> std::io::Result<bool> {
+    fn consume_if_eq(&mut self, expected: u8) -> crate::io::Result<bool> {
         Ok(self.consume_if(|ch| ch == expected)?.is_some())
     }
 
@@ -562,7 +553,7 @@ impl<'gc, R: Read> LexerInner<'gc, R> {
         &mut self,
         func: impl Fn(u8) -> bool,
         buf: &mut Vec<u8>,
-    ) -> std::io::Result<()> {
+    ) -> crate::io::Result<()> {
         while let Some(ch) = self.consume_if(&func)? {
             buf.push(ch);
         }
@@ -67,8 +67,8 @@ pub enum Token<'gc> {
     String(LuaString<'gc>),
 }
 
-impl std::fmt::Display for Token<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Self::Len => f.write_str("#"),
             Self::Mod => f.write_str("%"),
@@ -1,7 +1,43 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod archive;
+#[cfg(feature = "std")]
+pub mod bench;
 pub mod binary_chunk;
+#[cfg(feature = "std")]
+pub mod bundle;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "std")]
+pub mod channel;
+#[cfg(feature = "std")]
+pub mod chunk_cache;
+#[cfg(feature = "coverage")]
+pub mod coverage;
+#[cfg(feature = "dap")]
+pub mod dap;
+#[cfg(feature = "debugger")]
+pub mod debugger;
+pub mod disasm;
+#[cfg(feature = "std")]
+pub mod events;
 pub mod gc;
+pub mod io;
+#[cfg(feature = "std")]
+pub mod lua;
+#[cfg(feature = "profiler")]
+pub mod profiler;
+#[cfg(feature = "std")]
+pub mod remote;
 pub mod runtime;
+pub mod sandbox;
+#[cfg(feature = "std")]
+pub mod scheduler;
 pub mod types;
+#[cfg(all(feature = "wasm", target_arch = "wasm32", target_os = "unknown"))]
+pub mod wasm;
 
 #[cfg(not(feature = "luac"))]
 pub mod codegen;
@@ -10,13 +46,26 @@ mod lexer;
 #[cfg(not(feature = "luac"))]
 pub mod parser;
 
+mod collections;
 mod math;
+mod prelude;
 mod stdlib;
 mod string;
+mod sync;
 
-use bstr::{ByteSlice, ByteVec};
+use crate::prelude::*;
+use alloc::borrow::Cow;
+#[cfg(feature = "std")]
+use bstr::ByteSlice;
+use core::fmt::Debug;
 use gc::GcContext;
-use std::{borrow::Cow, fmt::Debug, io::Cursor, path::Path};
+use io::{BufRead, Cursor};
+#[cfg(feature = "std")]
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
 use types::{Integer, LuaClosure, LuaClosureProto, Number};
 
 pub const LUA_VERSION: (u8, u8) = (5, 4);
@@ -38,59 +87,227 @@ pub enum Error {
     Runtime(#[from] runtime::RuntimeError),
 
     #[error(transparent)]
-    Io(#[from] std::io::Error),
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Conversion(#[from] types::ConversionError),
 
     #[cfg(feature = "luac")]
     #[error(transparent)]
     RLua(#[from] rlua::Error),
 }
 
-pub fn load<B, S>(gc: &GcContext, bytes: B, source: S) -> Result<LuaClosureProto, Error>
+// How source chunks are compiled. The chunk name, which error messages use
+// as the source, is given to the load functions. "=name" gives "name",
+// "@path" names a file (see `file_chunk_name`) and anything else is shown
+// as [string "..."].
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    // Drops line information and local variable names from the compiled
+    // functions, binary chunks included
+    pub strip: bool,
+    // Rejects goto statements and labels with a syntax error
+    pub forbid_goto: bool,
+    // Name of the upvalue that global names are looked up in, which stays
+    // _ENV for the environment given by load
+    pub env_name: Vec<u8>,
+    // Runs runtime::optimize over the compiled functions
+    pub optimize: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            strip: false,
+            forbid_goto: false,
+            env_name: b"_ENV".to_vec(),
+            optimize: false,
+        }
+    }
+}
+
+pub const STDIN_CHUNK_NAME: &[u8] = b"=stdin";
+
+#[cfg(feature = "std")]
+pub fn file_chunk_name<P: AsRef<Path>>(path: P) -> Vec<u8> {
+    use bstr::ByteVec;
+    let mut name = b"@".to_vec();
+    name.extend_from_slice(&Vec::from_path_lossy(path.as_ref()));
+    name
+}
+
+pub fn load<B, S>(gc: &GcContext, bytes: B, source: S) -> Result<LuaClosureProto<'_>, Error>
 where
     B: AsRef<[u8]>,
     S: AsRef<[u8]>,
 {
-    let mut reader = Cursor::new(&bytes);
-    if let Ok(closure) = binary_chunk::load(gc, &mut reader) {
-        return Ok(closure);
-    }
+    load_with_options(gc, bytes, source, &CompileOptions::default())
+}
 
-    #[cfg(feature = "luac")]
-    {
-        let bin_bytes = rlua::Lua::new()
-            .context(|ctx| ctx.load(&bytes).set_name(&source)?.into_function()?.dump())?;
-        let mut reader = Cursor::new(bin_bytes);
-        let proto = binary_chunk::load(gc, &mut reader)?;
-        Ok(proto)
-    }
+pub fn load_with_options<'gc, B, S>(
+    gc: &'gc GcContext,
+    bytes: B,
+    source: S,
+    options: &CompileOptions,
+) -> Result<LuaClosureProto<'gc>, Error>
+where
+    B: AsRef<[u8]>,
+    S: AsRef<[u8]>,
+{
+    load_buffered(gc, Cursor::new(bytes.as_ref()), source.as_ref(), options)
+}
 
-    #[cfg(not(feature = "luac"))]
-    {
-        let reader = Cursor::new(&bytes);
-        let chunk = parser::parse(gc, String::from_utf8_lossy(source.as_ref()), reader)?;
-        let source = gc.allocate_string(source.as_ref());
-        let proto = codegen::codegen(gc, source, chunk)?;
-        Ok(proto)
+// Loads a chunk from a reader without reading it all first, such as a large
+// generated chunk written to a pipe
+#[cfg(feature = "std")]
+pub fn load_from_reader<R, S>(
+    gc: &GcContext,
+    reader: R,
+    source: S,
+) -> Result<LuaClosureProto<'_>, Error>
+where
+    R: Read,
+    S: AsRef<[u8]>,
+{
+    load_from_reader_with_options(gc, reader, source, &CompileOptions::default())
+}
+
+#[cfg(feature = "std")]
+pub fn load_from_reader_with_options<'gc, R, S>(
+    gc: &'gc GcContext,
+    reader: R,
+    source: S,
+    options: &CompileOptions,
+) -> Result<LuaClosureProto<'gc>, Error>
+where
+    R: Read,
+    S: AsRef<[u8]>,
+{
+    load_buffered(gc, BufReader::new(reader), source.as_ref(), options)
+}
+
+// Compiles a chunk as it is read, so that only the chunk's functions and not
+// its whole source need to be in memory
+pub(crate) fn load_buffered<'gc, R: BufRead>(
+    gc: &'gc GcContext,
+    mut reader: R,
+    source: &[u8],
+    options: &CompileOptions,
+) -> Result<LuaClosureProto<'gc>, Error> {
+    let mut proto = if binary_chunk::is_binary_chunk(reader.fill_buf()?) {
+        binary_chunk::load(gc, &mut reader)?
+    } else {
+        compile(gc, reader, source, options)?
+    };
+    if options.strip {
+        proto.strip_debug_info(gc);
+    }
+    if options.optimize {
+        runtime::optimize(gc, &mut proto);
     }
+    Ok(proto)
+}
+
+#[cfg(feature = "luac")]
+fn compile<'gc, R: BufRead>(
+    gc: &'gc GcContext,
+    mut reader: R,
+    source: &[u8],
+    _: &CompileOptions,
+) -> Result<LuaClosureProto<'gc>, Error> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let bin_bytes = rlua::Lua::new()
+        .context(|ctx| ctx.load(&bytes).set_name(source)?.into_function()?.dump())?;
+    let mut reader = Cursor::new(bin_bytes);
+    let proto = binary_chunk::load(gc, &mut reader)?;
+    Ok(proto)
+}
+
+#[cfg(not(feature = "luac"))]
+fn compile<'gc, R: BufRead>(
+    gc: &'gc GcContext,
+    reader: R,
+    source: &[u8],
+    options: &CompileOptions,
+) -> Result<LuaClosureProto<'gc>, Error> {
+    let chunk = parser::parse_with_options(gc, String::from_utf8_lossy(source), reader, options)?;
+    let source = gc.allocate_string(source);
+    let proto = codegen::codegen_with_options(gc, source, chunk, options)?;
+    Ok(proto)
+}
+
+#[cfg(feature = "std")]
+pub fn load_file<P: AsRef<Path>>(gc: &GcContext, path: P) -> Result<LuaClosureProto<'_>, Error> {
+    load_file_with_options(gc, path, &CompileOptions::default())
+}
+
+#[cfg(feature = "std")]
+pub fn load_file_with_options<'gc, P: AsRef<Path>>(
+    gc: &'gc GcContext,
+    path: P,
+    options: &CompileOptions,
+) -> Result<LuaClosureProto<'gc>, Error> {
+    let reader = open_chunk_file(&path)?;
+    load_buffered(gc, reader, &file_chunk_name(path), options)
+}
+
+// Loads the chunk piped to stdin, which can start with a shebang line like a
+// chunk file
+#[cfg(feature = "std")]
+pub fn load_stdin_with_options<'gc>(
+    gc: &'gc GcContext,
+    options: &CompileOptions,
+) -> Result<LuaClosureProto<'gc>, Error> {
+    let mut stdin = std::io::stdin().lock();
+    skip_chunk_prefix(&mut stdin)?;
+    load_buffered(gc, stdin, STDIN_CHUNK_NAME, options)
+}
+
+// Reads a chunk without its BOM and shebang line
+#[cfg(feature = "std")]
+pub(crate) fn read_chunk_file<P: AsRef<Path>>(path: P) -> crate::io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    open_chunk_file(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
 }
 
-pub fn load_file<P: AsRef<Path>>(gc: &GcContext, path: P) -> Result<LuaClosureProto, Error> {
+#[cfg(feature = "std")]
+pub(crate) fn open_chunk_file<P: AsRef<Path>>(path: P) -> crate::io::Result<BufReader<File>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    skip_chunk_prefix(&mut reader)?;
+    Ok(reader)
+}
+
+// Skips the BOM and the shebang line of a chunk file, keeping the newline so
+// that line numbers stay the same
+#[cfg(feature = "std")]
+pub(crate) fn skip_chunk_prefix<R: BufRead>(reader: &mut R) -> io::Result<()> {
     const BOM: &[u8] = b"\xef\xbb\xbf";
 
-    let bytes = std::fs::read(&path)?;
-    let mut slice = bytes.as_slice();
-    if let Some(s) = slice.strip_prefix(BOM) {
-        slice = s;
+    if reader.fill_buf()?.starts_with(BOM) {
+        reader.consume(BOM.len());
     }
 
-    // shebang
-    if let Some(s) = slice.strip_prefix(b"#") {
-        slice = s.trim_start_with(|ch| ch != '\n');
+    if reader.fill_buf()?.starts_with(b"#") {
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            match buf.find_byte(b'\n') {
+                Some(end) => {
+                    reader.consume(end);
+                    break;
+                }
+                None => {
+                    let len = buf.len();
+                    reader.consume(len);
+                }
+            }
+        }
     }
-
-    let mut source = b"@".to_vec();
-    source.extend_from_slice(&Vec::from_path_lossy(path.as_ref()));
-    load(gc, slice, source)
+    Ok(())
 }
 
 macro_rules! count  {
@@ -100,7 +317,7 @@ macro_rules! count  {
 
 pub(crate) use count;
 
-fn chunk_id_from_source(source: &str) -> Cow<str> {
+fn chunk_id_from_source(source: &str) -> Cow<'_, str> {
     const LUA_IDSIZE: usize = 60;
     const RETS: &str = "...";
     const PRE: &str = "[string \"";
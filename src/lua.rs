@@ -0,0 +1,359 @@
+use crate::{
+    gc::{GcContext, MaybeSend},
+    runtime::{Action, Continuation, ErrorKind, Function, Runtime, RuntimeError, Vm},
+    sandbox::Sandbox,
+    types::{
+        ConversionError, DetachedValue, FromLua, FromLuaMulti, IntoLuaMulti, NativeClosure,
+        NativeFn, Value,
+    },
+    CompileOptions, Error,
+};
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "async")]
+use crate::{runtime::future_output, stdlib::helpers::ArgumentsExt};
+
+// Embedding API for hosts that don't need to work with the heap directly.
+// Values are passed in and out as DetachedValues, so nothing borrowed from
+// the heap outlives a call. Use `with` or `runtime` for anything else.
+pub struct Lua {
+    runtime: Runtime,
+}
+
+impl Lua {
+    // Creates a runtime with the standard library loaded
+    pub fn new() -> Self {
+        let mut runtime = Runtime::new();
+        runtime
+            .heap()
+            .with(|gc, vm| vm.borrow_mut(gc).load_stdlib(gc));
+        Self { runtime }
+    }
+
+    // Creates a runtime set up for untrusted code by `sandbox`
+    pub fn sandboxed(sandbox: &Sandbox) -> Self {
+        let mut runtime = Runtime::new();
+        runtime
+            .heap()
+            .with(|gc, vm| sandbox.apply(gc, &mut vm.borrow_mut(gc)));
+        Self { runtime }
+    }
+
+    pub fn runtime(&mut self) -> &mut Runtime {
+        &mut self.runtime
+    }
+
+    pub fn with<F, R>(&mut self, f: F) -> R
+    where
+        F: for<'gc> FnOnce(&'gc GcContext, &mut Vm<'gc>) -> R,
+    {
+        self.runtime
+            .heap()
+            .with(|gc, vm| f(gc, &mut vm.borrow_mut(gc)))
+    }
+
+    pub fn load<B: AsRef<[u8]>>(&mut self, source: B) -> Chunk<'_> {
+        Chunk {
+            lua: self,
+            source: source.as_ref().to_vec(),
+            name: None,
+            options: CompileOptions::default(),
+        }
+    }
+
+    pub fn globals(&mut self) -> Globals<'_> {
+        Globals { lua: self }
+    }
+
+    // Sets the global `name` to a function that calls `f`, converting its
+    // arguments and results, e.g. `lua.register("add", |a: i64, b: i64| Ok(a + b))`
+    pub fn register<N, F, A>(&mut self, name: N, f: F)
+    where
+        N: AsRef<[u8]>,
+        F: 'static + MaybeSend + for<'gc> NativeFn<'gc, A>,
+    {
+        self.with(|gc, vm| {
+            vm.globals().borrow_mut(gc).set_field(
+                gc.allocate_string(name.as_ref()),
+                gc.allocate(NativeClosure::from_fn(f)),
+            );
+        });
+    }
+
+    // Sets the global `name` to a function that waits on the future `f`
+    // returns, e.g. to do I/O without blocking the executor that runs the
+    // script with exec_async or eval_async. Arguments convert like in
+    // `register`, with several arguments taken as a tuple.
+    #[cfg(feature = "async")]
+    pub fn register_async<N, F, A, Fut, R>(&mut self, name: N, f: F)
+    where
+        N: AsRef<[u8]>,
+        F: 'static + MaybeSend + Fn(A) -> Fut,
+        A: for<'gc> FromLuaMulti<'gc>,
+        Fut: 'static + MaybeSend + std::future::Future<Output = Result<R, ErrorKind>>,
+        R: 'static + MaybeSend + for<'gc> IntoLuaMulti<'gc>,
+    {
+        self.with(|gc, vm| {
+            let function = NativeClosure::new(move |gc, _, args| {
+                let future = f(A::from_lua_multi(args.without_callee(), gc)?);
+                Ok(Action::Await {
+                    future: Box::pin(async move {
+                        let result = future.await;
+                        future_output(move |gc| result.map(|values| values.into_lua_multi(gc)))
+                    }),
+                    continuation: Continuation::new(|_, _, results: Result<Vec<Value>, _>| {
+                        results.map(Action::Return)
+                    }),
+                })
+            });
+            vm.globals()
+                .borrow_mut(gc)
+                .set_field(gc.allocate_string(name.as_ref()), gc.allocate(function));
+        });
+    }
+
+    // Makes `require(name)` return the value built by `loader`, which is
+    // called the first time the module is required
+    pub fn register_module<N, F>(&mut self, name: N, loader: F)
+    where
+        N: AsRef<[u8]>,
+        F: 'static
+            + MaybeSend
+            + for<'gc> Fn(&'gc GcContext, &mut Vm<'gc>) -> Result<Value<'gc>, ErrorKind>,
+    {
+        self.with(|gc, vm| vm.register_module(gc, name, loader));
+    }
+
+    // Runs module `name` again, so that require returns its new value
+    pub fn reload_module<N: AsRef<[u8]>>(&mut self, name: N) -> Result<(), Error> {
+        self.reload(name.as_ref(), false)
+    }
+
+    // Like reload_module, but updates the table the module returned before
+    // in place, with the new functions keeping the upvalues of the ones they
+    // replace, as Vm::reload_module does with `patch`
+    pub fn patch_module<N: AsRef<[u8]>>(&mut self, name: N) -> Result<(), Error> {
+        self.reload(name.as_ref(), true)
+    }
+
+    fn reload(&mut self, name: &[u8], patch: bool) -> Result<(), Error> {
+        self.with(|gc, vm| {
+            vm.reload_module(gc, name, patch)
+                .map(|_| ())
+                .map_err(|kind| {
+                    Error::Runtime(RuntimeError {
+                        kind,
+                        traceback: Vec::new(),
+                    })
+                })
+        })
+    }
+
+    // The value a script raised `err` with, like the table given to error().
+    // Errors raised with a message give the message.
+    pub fn error_value(&mut self, err: &RuntimeError) -> Option<DetachedValue> {
+        self.with(|gc, vm| DetachedValue::detach(vm.error_value(gc, &err.kind)))
+    }
+
+    // Calls the global function `name`
+    pub fn call<N, A, R>(&mut self, name: N, args: A) -> Result<R, Error>
+    where
+        N: AsRef<[u8]>,
+        A: for<'gc> IntoLuaMulti<'gc>,
+        R: for<'gc> FromLuaMulti<'gc>,
+    {
+        self.with(|gc, vm| {
+            let function = vm
+                .globals()
+                .borrow()
+                .get_field(gc.allocate_string(name.as_ref()));
+            let function = Function::from_lua(function, gc)?;
+            function.call(gc, vm, args).map_err(|kind| {
+                Error::Runtime(RuntimeError {
+                    kind,
+                    traceback: Vec::new(),
+                })
+            })
+        })
+    }
+}
+
+impl Default for Lua {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Chunk<'a> {
+    lua: &'a mut Lua,
+    source: Vec<u8>,
+    name: Option<Vec<u8>>,
+    options: CompileOptions,
+}
+
+impl<'a> Chunk<'a> {
+    // Chunk name used in error messages, which defaults to the source
+    pub fn name<N: AsRef<[u8]>>(mut self, name: N) -> Self {
+        self.name = Some(name.as_ref().to_vec());
+        self
+    }
+
+    pub fn options(mut self, options: CompileOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn exec(self) -> Result<(), Error> {
+        self.call(false).map(|_| ())
+    }
+
+    // Evaluates the chunk as an expression if it is one, or runs it as a
+    // block otherwise, and converts its first result
+    pub fn eval<T>(self) -> Result<T, Error>
+    where
+        T: TryFrom<DetachedValue, Error = ConversionError>,
+    {
+        let value = self
+            .eval_multi()?
+            .into_iter()
+            .next()
+            .unwrap_or(DetachedValue::Nil);
+        Ok(T::try_from(value)?)
+    }
+
+    pub fn eval_multi(self) -> Result<Vec<DetachedValue>, Error> {
+        self.call(true)
+    }
+
+    // Like exec, eval and eval_multi, but native functions registered with
+    // register_async don't block the thread while they wait
+    #[cfg(feature = "async")]
+    pub async fn exec_async(self) -> Result<(), Error> {
+        self.call_async(false).await.map(|_| ())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn eval_async<T>(self) -> Result<T, Error>
+    where
+        T: TryFrom<DetachedValue, Error = ConversionError>,
+    {
+        let value = self
+            .eval_multi_async()
+            .await?
+            .into_iter()
+            .next()
+            .unwrap_or(DetachedValue::Nil);
+        Ok(T::try_from(value)?)
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn eval_multi_async(self) -> Result<Vec<DetachedValue>, Error> {
+        self.call_async(true).await
+    }
+
+    #[cfg(feature = "async")]
+    async fn call_async(self, as_expression: bool) -> Result<Vec<DetachedValue>, Error> {
+        let (lua, results) = self.start(as_expression)?;
+        lua.runtime.finish_async().await?;
+        let results = std::mem::take(&mut *results.lock().unwrap());
+        Ok(results)
+    }
+
+    fn call(self, as_expression: bool) -> Result<Vec<DetachedValue>, Error> {
+        let (lua, results) = self.start(as_expression)?;
+        lua.runtime.finish()?;
+        let results = std::mem::take(&mut *results.lock().unwrap());
+        Ok(results)
+    }
+
+    // Sets up the chunk to run on the runtime, with its results going to
+    // the returned vector once it returns
+    #[allow(clippy::type_complexity)]
+    fn start(
+        self,
+        as_expression: bool,
+    ) -> Result<(&'a mut Lua, Arc<Mutex<Vec<DetachedValue>>>), Error> {
+        let name = self.name.unwrap_or_else(|| self.source.clone());
+        let source = self.source;
+        let options = self.options;
+
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let sink = results.clone();
+        self.lua.runtime.start(move |gc, vm| {
+            let vm = vm.borrow();
+            let expression = as_expression
+                .then(|| {
+                    let expression = [b"return ", source.as_slice()].concat();
+                    vm.load_with_options(gc, expression, &name, &options).ok()
+                })
+                .flatten();
+            let closure = match expression {
+                Some(closure) => closure,
+                None => vm.load_with_options(gc, &source, &name, &options)?,
+            };
+            let callee: Value = gc.allocate(closure).into();
+            let call = NativeClosure::with_upvalue(callee, move |_, _, callee, _| {
+                let sink = sink.clone();
+                Ok(Action::Call {
+                    callee: *callee,
+                    args: Vec::new(),
+                    continuation: Continuation::new(move |_, _, results: Vec<Value>| {
+                        let mut detached = Vec::with_capacity(results.len());
+                        for value in results {
+                            match DetachedValue::detach(value) {
+                                Some(value) => detached.push(value),
+                                None => {
+                                    return Err(ErrorKind::other(format!(
+                                        "cannot return {} to the host",
+                                        value.display_debug()
+                                    )))
+                                }
+                            }
+                        }
+                        *sink.lock().unwrap() = detached;
+                        Ok(Action::Return(Vec::new()))
+                    }),
+                })
+            });
+            Ok(gc.allocate(call).into())
+        })?;
+        Ok((self.lua, results))
+    }
+}
+
+pub struct Globals<'a> {
+    lua: &'a mut Lua,
+}
+
+impl Globals<'_> {
+    pub fn get<N, T>(&mut self, name: N) -> Result<T, Error>
+    where
+        N: AsRef<[u8]>,
+        T: TryFrom<DetachedValue, Error = ConversionError>,
+    {
+        let value = self.lua.with(|gc, vm| {
+            let value = vm
+                .globals()
+                .borrow()
+                .get_field(gc.allocate_string(name.as_ref()));
+            DetachedValue::detach(value).ok_or(ConversionError {
+                from: value.ty().name(),
+                to: "detached value",
+            })
+        })?;
+        Ok(T::try_from(value)?)
+    }
+
+    pub fn set<N, V>(&mut self, name: N, value: V)
+    where
+        N: AsRef<[u8]>,
+        V: Into<DetachedValue>,
+    {
+        let value = value.into();
+        self.lua.with(|gc, vm| {
+            vm.globals()
+                .borrow_mut(gc)
+                .set_field(gc.allocate_string(name.as_ref()), value.attach(gc));
+        });
+    }
+}
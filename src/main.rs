@@ -1,13 +1,30 @@
 use anyhow::{Error, Result};
 use bstr::{ByteSlice, ByteVec, B};
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+#[cfg(feature = "dap")]
+use mochi_lua::dap::{Connection, DapHandler};
 use mochi_lua::{
-    gc::GcHeap,
-    runtime::{OpCode, Runtime, RuntimeError},
-    types::{Integer, LineRange, LuaClosureProto, Table, UpvalueDescription, Value},
+    bundle::Bundle,
+    chunk_cache::ChunkCache,
+    gc::{GcContext, GcHeap},
+    runtime::{Action, Continuation, DependencyGraph, Runtime, RuntimeError},
+    types::{FloatFormat, Integer, NativeClosure, Table, Value},
+    CompileOptions,
+};
+#[cfg(feature = "debugger")]
+use mochi_lua::{
+    debugger::{DebugHandler, Resume, Stop, StopReason},
+    runtime::Vm,
 };
 use rustyline::error::ReadlineError;
-use std::{fs::File, io::BufWriter, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs::File,
+    io::{BufWriter, IsTerminal, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    time::Instant,
+};
 
 #[cfg(all(feature = "jemalloc", not(target_env = "msvc")))]
 #[global_allocator]
@@ -25,23 +42,121 @@ struct Cli {
     #[arg(short, value_name = "STAT", action = clap::ArgAction::Append)]
     execute: Vec<String>,
 
+    /// Require library <NAME> into global <NAME>, or into global <G> with
+    /// G=NAME
+    #[arg(short = 'l', value_name = "[G=]NAME", action = clap::ArgAction::Append)]
+    require: Vec<String>,
+
     /// Enter interactive mode after executing <SCRIPT>
     #[arg(short, default_value_t = false)]
     interactive: bool,
 
+    #[command(flatten)]
+    compile_flags: CompileFlags,
+
+    /// Compile <SCRIPT> to a precompiled chunk instead of running it
+    #[arg(short, long, requires = "script")]
+    compile: bool,
+
+    /// Output the chunk compiled with -c to <OUTPUT> [default: luac.out]
+    #[arg(short, long, requires = "compile")]
+    output: Option<PathBuf>,
+
+    /// List the functions of <SCRIPT> instead of running it (use --list
+    /// --list for full listing)
+    #[arg(long, action = clap::ArgAction::Count, requires = "script")]
+    list: u8,
+
+    /// Keep the compiled chunks of <SCRIPT> and the files it loads in <DIR>,
+    /// and load them from there while the files stay the same
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Profile <SCRIPT> and write the sampled stacks to <FILE> (stderr by
+    /// default) in the folded format of flamegraph tools
+    #[cfg(feature = "profiler")]
+    #[arg(
+        long,
+        value_name = "FILE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "-"
+    )]
+    profile: Option<PathBuf>,
+
+    /// Count the lines of <SCRIPT> that are run and write the report to
+    /// <FILE>, as a Lua table if it ends in .lua or in LCOV format otherwise
+    #[cfg(feature = "coverage")]
+    #[arg(long, value_name = "FILE", requires = "script")]
+    coverage: Option<PathBuf>,
+
+    /// Run <SCRIPT> under an interactive debugger, stopping at its first
+    /// line
+    #[cfg(feature = "debugger")]
+    #[arg(long, requires = "script")]
+    debug: bool,
+
+    /// Serve the Debug Adapter Protocol on stdio, or on a TCP connection
+    /// accepted at <ADDR>, and run the script the client launches (or
+    /// <SCRIPT> when it attaches)
+    #[cfg(feature = "dap")]
+    #[arg(
+        long,
+        value_name = "ADDR",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "-"
+    )]
+    dap: Option<String>,
+
     #[clap(subcommand)]
     subcommand: Option<Command>,
 }
 
 #[derive(Debug, Subcommand)]
 enum Command {
+    Bundle(BundleCommand),
     Compile(CompileCommand),
+    Deps(DepsCommand),
+}
+
+#[derive(Debug, clap::Args)]
+struct CompileFlags {
+    /// Strip debug information
+    #[arg(short, long)]
+    strip: bool,
+
+    /// Reject goto statements and labels
+    #[arg(long)]
+    forbid_goto: bool,
+
+    /// Look up global names in <NAME> instead of _ENV
+    #[arg(long, value_name = "NAME", default_value = "_ENV")]
+    env_name: String,
+
+    /// Optimize the compiled code
+    #[arg(short = 'O', long)]
+    optimize: bool,
+}
+
+impl From<&CompileFlags> for CompileOptions {
+    fn from(flags: &CompileFlags) -> Self {
+        Self {
+            strip: flags.strip,
+            forbid_goto: flags.forbid_goto,
+            env_name: flags.env_name.clone().into_bytes(),
+            optimize: flags.optimize,
+        }
+    }
 }
 
 #[derive(Debug, Parser)]
 struct CompileCommand {
     filename: PathBuf,
 
+    #[command(flatten)]
+    compile_flags: CompileFlags,
+
     /// List (use -l -l for full listing)
     #[arg(short,  action = clap::ArgAction::Count)]
     list: u8,
@@ -55,70 +170,428 @@ struct CompileCommand {
     parse_only: bool,
 }
 
+/// Run a script and print the modules it required
+#[derive(Debug, Parser)]
+struct DepsCommand {
+    script: PathBuf,
+
+    #[arg(allow_hyphen_values = true, trailing_var_arg = true)]
+    args: Vec<String>,
+}
+
+/// Bundle a script and the modules it requires into a single file
+#[derive(Debug, Parser)]
+struct BundleCommand {
+    script: PathBuf,
+
+    /// Output to file <OUTPUT>
+    #[arg(short)]
+    output: PathBuf,
+
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = BundleFormat::Lua)]
+    format: BundleFormat,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum BundleFormat {
+    /// Lua source
+    Lua,
+    /// Precompiled chunk
+    Luac,
+    /// Copy of this executable running the bundle
+    Exe,
+}
+
+// Trailer of the executables written by `mochi bundle -f exe`, after the
+// chunk they run: the chunk size as a little-endian u64 and this magic
+const EMBEDDED_CHUNK_MAGIC: &[u8; 8] = b"MOCHIBND";
+
 fn main() -> Result<()> {
-    let cli = Cli::parse();
+    if let Some(chunk) = embedded_chunk() {
+        return run_embedded_chunk(chunk);
+    }
+
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches)?;
     if let Some(command) = cli.subcommand {
         match command {
+            Command::Bundle(command) => command.run()?,
             Command::Compile(command) => command.run()?,
+            Command::Deps(command) => command.run()?,
         }
         return Ok(());
     }
 
+    if cli.compile || cli.list > 0 {
+        let command = CompileCommand {
+            filename: cli.script.unwrap(),
+            compile_flags: cli.compile_flags,
+            list: cli.list,
+            output: cli.output.unwrap_or_else(|| PathBuf::from("luac.out")),
+            parse_only: !cli.compile,
+        };
+        return command.run();
+    }
+
+    let options = CompileOptions::from(&cli.compile_flags);
     let mut runtime = Runtime::new();
     runtime.heap().with(|gc, vm| -> Result<()> {
         let mut vm = vm.borrow_mut(gc);
         vm.load_stdlib(gc);
+        vm.set_compile_options(options.clone());
+        if let Some(dir) = &cli.cache_dir {
+            vm.set_chunk_cache(Some(ChunkCache::new(dir)));
+        }
 
         let args = std::env::args_os();
-        let base = if cli.script.is_some() {
-            (args.len() - cli.args.len() - 1) as Integer
+        let script = if cli.script.is_some() {
+            args.len() - cli.args.len() - 1
         } else {
             0
         };
+        set_script_globals(gc, &mut vm.globals().borrow_mut(gc), args, script)
+    })?;
 
-        let mut arg = Table::new();
-        for (i, x) in args.enumerate() {
-            arg.set(
-                i as Integer - base,
-                gc.allocate_string(Vec::from_os_string(x).unwrap()),
-            )?;
-        }
-        vm.globals()
-            .borrow_mut(gc)
-            .set_field(gc.allocate_string(B("arg")), gc.allocate_cell(arg));
+    run_lua_init(&mut runtime, &options)?;
 
-        Ok(())
-    })?;
+    // -e and -l run in the order they're given
+    let mut startup: Vec<_> = matches
+        .indices_of("execute")
+        .into_iter()
+        .flatten()
+        .zip(cli.execute.iter().map(Startup::Execute))
+        .chain(
+            matches
+                .indices_of("require")
+                .into_iter()
+                .flatten()
+                .zip(cli.require.iter().map(Startup::Require)),
+        )
+        .collect();
+    startup.sort_by_key(|(index, _)| *index);
+    for (_, action) in startup {
+        match action {
+            Startup::Execute(stat) => runtime
+                .execute(|gc, vm| {
+                    let closure =
+                        vm.borrow()
+                            .load_with_options(gc, stat, "=(command line)", &options)?;
+                    Ok(gc.allocate(closure).into())
+                })
+                .map_err(Error::msg)?,
+            Startup::Require(spec) => require_library(&mut runtime, spec)?,
+        }
+    }
 
-    for stat in &cli.execute {
-        runtime
-            .execute(|gc, vm| {
-                let closure = vm.borrow().load(gc, stat, "=(command line)")?;
-                Ok(gc.allocate(closure).into())
-            })
-            .map_err(Error::msg)?;
+    #[cfg(feature = "dap")]
+    if let Some(addr) = &cli.dap {
+        return run_dap(&mut runtime, addr, cli.script.as_deref(), &options);
     }
 
     if let Some(script) = &cli.script {
+        #[cfg(feature = "profiler")]
+        if cli.profile.is_some() {
+            runtime.heap().with(|gc, vm| {
+                vm.borrow_mut(gc)
+                    .start_profiler(std::time::Duration::from_millis(1))
+            });
+        }
+        #[cfg(feature = "coverage")]
+        if cli.coverage.is_some() {
+            runtime
+                .heap()
+                .with(|gc, vm| vm.borrow_mut(gc).start_coverage());
+        }
+        #[cfg(feature = "debugger")]
+        if cli.debug {
+            runtime.heap().with(|gc, vm| {
+                vm.borrow_mut(gc)
+                    .attach_debugger(DebugConsole::default())
+                    .set_resume(Resume::Step)
+            });
+        }
+
+        let result = runtime.execute(|gc, vm| {
+            let proto = if script.as_os_str() == "-" {
+                mochi_lua::load_stdin_with_options(gc, &options)?
+            } else if let Some(cache) = vm.borrow().chunk_cache() {
+                cache.load_file(gc, script, &options)?
+            } else {
+                mochi_lua::load_file_with_options(gc, script, &options)?
+            };
+            let mut vm = vm.borrow_mut(gc);
+            vm.prefetch_requires(gc, &proto);
+            let chunk = gc.allocate(vm.load_proto(gc, proto)).into();
+            Ok(call_with_args(gc, chunk, &cli.args))
+        });
+
+        #[cfg(feature = "profiler")]
+        if let Some(output) = &cli.profile {
+            let profile = runtime
+                .heap()
+                .with(|gc, vm| vm.borrow_mut(gc).stop_profiler());
+            if let Some(profile) = profile {
+                if output.as_os_str() == "-" {
+                    profile.write_folded(&mut std::io::stderr().lock())?;
+                } else {
+                    profile.write_folded(&mut BufWriter::new(File::create(output)?))?;
+                }
+            }
+        }
+
+        // Coverage is reported for scripts that fail too
+        let result = result.and_then(|()| runtime.run_remaining_timers());
+        #[cfg(feature = "coverage")]
+        if let Some(output) = &cli.coverage {
+            let coverage = runtime
+                .heap()
+                .with(|gc, vm| vm.borrow_mut(gc).stop_coverage());
+            if let Some(coverage) = coverage {
+                let mut w = BufWriter::new(File::create(output)?);
+                if output.extension().is_some_and(|ext| ext == "lua") {
+                    coverage.write_lua(&mut w)?;
+                } else {
+                    coverage.write_lcov(&mut w)?;
+                }
+            }
+        }
+        result.map_err(Error::msg)?;
+    }
+
+    if cli.interactive {
+        return do_repl(&mut runtime);
+    }
+    // Like lua, without a script or statements to run, the chunk piped to
+    // stdin is run instead of the REPL
+    if cli.execute.is_empty() && cli.script.is_none() {
+        if std::io::stdin().is_terminal() {
+            return do_repl(&mut runtime);
+        }
         runtime
             .execute(|gc, vm| {
-                let closure = vm.borrow().load_file(gc, script)?;
-                Ok(gc.allocate(closure).into())
+                let proto = mochi_lua::load_stdin_with_options(gc, &options)?;
+                Ok(gc.allocate(vm.borrow().load_proto(gc, proto)).into())
             })
             .map_err(Error::msg)?;
+        runtime.run_remaining_timers().map_err(Error::msg)?;
     }
+    Ok(())
+}
 
-    if cli.interactive || (cli.execute.is_empty() && cli.script.is_none()) {
-        do_repl(&mut runtime)
+// Runs the statements in LUA_INIT_5_4 or LUA_INIT, or the file they name
+// after an '@'
+#[cfg(feature = "dap")]
+fn run_dap(
+    runtime: &mut Runtime,
+    addr: &str,
+    script: Option<&std::path::Path>,
+    options: &CompileOptions,
+) -> Result<()> {
+    let connection = if addr == "-" {
+        Connection::stdio()
     } else {
+        eprintln!("waiting for a debugger client on {addr}");
+        Connection::listen(addr)?
+    };
+    let launch = runtime.heap().with(|gc, vm| {
+        let mut vm = vm.borrow_mut(gc);
+        vm.attach_debugger(DapHandler::new(connection.clone()));
+        connection.configure(&mut vm)
+    })?;
+    let script = match (&launch.program, script) {
+        (Some(program), _) => PathBuf::from(program),
+        (None, Some(script)) => script.to_path_buf(),
+        (None, None) => return Err(Error::msg("no script to debug")),
+    };
+
+    runtime.heap().with(|gc, vm| -> Result<()> {
+        let globals = vm.borrow().globals();
+        let mut globals = globals.borrow_mut(gc);
+        if let Some(program) = &launch.program {
+            let args = std::iter::once(program)
+                .chain(&launch.args)
+                .map(OsString::from);
+            set_script_globals(gc, &mut globals, args, 0)?;
+        }
+        // Output on stdout would be taken for messages
+        if addr == "-" {
+            let connection = connection.clone();
+            let print = NativeClosure::new(move |_, vm, args: Vec<Value>| {
+                let mut out = Vec::new();
+                for (i, value) in args.iter().skip(1).enumerate() {
+                    if i > 0 {
+                        out.push(b'\t');
+                    }
+                    value.fmt_bytes_with(&mut out, vm.float_format())?;
+                }
+                out.push(b'\n');
+                connection.output("stdout", &String::from_utf8_lossy(&out));
+                Ok(Action::Return(Vec::new()))
+            });
+            globals.set_field(gc.allocate_string(B("print")), gc.allocate(print));
+        }
         Ok(())
+    })?;
+
+    let result = runtime
+        .execute(|gc, vm| {
+            let proto = mochi_lua::load_file_with_options(gc, &script, options)?;
+            Ok(gc.allocate(vm.borrow().load_proto(gc, proto)).into())
+        })
+        .and_then(|()| runtime.run_remaining_timers());
+    if let Err(err) = &result {
+        connection.output("stderr", &format!("{err}\n"));
+    }
+    connection.finish(result.is_err().into());
+    Ok(())
+}
+
+fn run_lua_init(runtime: &mut Runtime, options: &CompileOptions) -> Result<()> {
+    let Some((var, init)) = ["LUA_INIT_5_4", "LUA_INIT"]
+        .into_iter()
+        .find_map(|var| Some((var, std::env::var_os(var)?)))
+    else {
+        return Ok(());
+    };
+    let init = Vec::from_os_string(init).map_err(|_| Error::msg(format!("invalid {var}")))?;
+    runtime
+        .execute(|gc, vm| {
+            let proto = match init.strip_prefix(b"@") {
+                Some(path) => mochi_lua::load_file_with_options(gc, path.to_path()?, options)?,
+                None => mochi_lua::load_with_options(gc, &init, format!("={var}"), options)?,
+            };
+            Ok(gc.allocate(vm.borrow().load_proto(gc, proto)).into())
+        })
+        .map_err(Error::msg)
+}
+
+// Sets `arg` like the reference interpreter does, with the script at index
+// 0, the interpreter and its options at negative indices and the arguments of
+// the script after it, and `env` to a table of the environment variables
+fn set_script_globals<'gc>(
+    gc: &'gc GcContext,
+    globals: &mut Table<'gc>,
+    args: impl IntoIterator<Item = OsString>,
+    script: usize,
+) -> Result<()> {
+    let mut arg = Table::new();
+    for (i, x) in args.into_iter().enumerate() {
+        arg.set(
+            i as Integer - script as Integer,
+            gc.allocate_string(Vec::from_os_str_lossy(&x).into_owned()),
+        )?;
     }
+    globals.set_field(gc.allocate_string(B("arg")), gc.allocate_cell(arg));
+
+    let mut env = Table::new();
+    for (name, value) in std::env::vars_os() {
+        env.set_field(
+            gc.allocate_string(Vec::from_os_str_lossy(&name).into_owned()),
+            gc.allocate_string(Vec::from_os_str_lossy(&value).into_owned()),
+        );
+    }
+    globals.set_field(gc.allocate_string(B("env")), gc.allocate_cell(env));
+    Ok(())
+}
+
+// A function that calls `chunk` with `args`, which it gets as `...`
+fn call_with_args<'gc>(gc: &'gc GcContext, chunk: Value<'gc>, args: &[String]) -> Value<'gc> {
+    let args = args.to_vec();
+    let closure = NativeClosure::with_upvalue(chunk, move |gc, _, &chunk, _| {
+        Ok(Action::Call {
+            callee: chunk,
+            args: args
+                .iter()
+                .map(|arg| gc.allocate_string(arg.as_bytes()).into())
+                .collect(),
+            continuation: Continuation::new(|_, _, results: Vec<Value>| {
+                Ok(Action::Return(results))
+            }),
+        })
+    });
+    gc.allocate(closure).into()
+}
+
+enum Startup<'a> {
+    Execute(&'a String),
+    Require(&'a String),
+}
+
+// Calls require for an -l option and sets the global to the module
+fn require_library(runtime: &mut Runtime, spec: &str) -> Result<()> {
+    // Like in lua, a version suffix after '-' isn't part of the global name
+    let (global, module) = spec
+        .split_once('=')
+        .unwrap_or_else(|| (spec.split('-').next().unwrap_or(spec), spec));
+    runtime.heap().with(|gc, vm| -> Result<()> {
+        let mut vm = vm.borrow_mut(gc);
+        let globals = vm.globals();
+        let require = globals.borrow().get_field(gc.allocate_string(B("require")));
+        let results = vm
+            .call_value(
+                gc,
+                require,
+                vec![gc.allocate_string(module.as_bytes()).into()],
+            )
+            .map_err(Error::msg)?;
+        globals.borrow_mut(gc).set_field(
+            gc.allocate_string(global.as_bytes()),
+            results.first().copied().unwrap_or_default(),
+        );
+        Ok(())
+    })
+}
+
+fn embedded_chunk() -> Option<Vec<u8>> {
+    let mut file = File::open(std::env::current_exe().ok()?).ok()?;
+    let trailer_start = file.seek(SeekFrom::End(-16)).ok()?;
+    let mut trailer = [0; 16];
+    file.read_exact(&mut trailer).ok()?;
+    if &trailer[8..] != EMBEDDED_CHUNK_MAGIC {
+        return None;
+    }
+    let size = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+    file.seek(SeekFrom::Start(trailer_start.checked_sub(size)?))
+        .ok()?;
+    let mut chunk = vec![0; size.try_into().ok()?];
+    file.read_exact(&mut chunk).ok()?;
+    Some(chunk)
+}
+
+fn run_embedded_chunk(chunk: Vec<u8>) -> Result<()> {
+    let mut runtime = Runtime::new();
+    runtime.heap().with(|gc, vm| -> Result<()> {
+        let mut vm = vm.borrow_mut(gc);
+        vm.load_stdlib(gc);
+        set_script_globals(gc, &mut vm.globals().borrow_mut(gc), std::env::args_os(), 0)
+    })?;
+
+    let args: Vec<_> = std::env::args_os()
+        .skip(1)
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    runtime
+        .execute(|gc, vm| {
+            let closure = vm.borrow().load(gc, &chunk, "=?")?;
+            Ok(call_with_args(gc, gc.allocate(closure).into(), &args))
+        })
+        .map_err(Error::msg)
 }
 
 fn do_repl(runtime: &mut Runtime) -> Result<()> {
-    let mut rl = rustyline::DefaultEditor::new()?;
+    let config = rustyline::Config::builder()
+        .completion_type(rustyline::CompletionType::List)
+        .build();
+    let mut rl =
+        rustyline::Editor::<Completions, rustyline::history::DefaultHistory>::with_config(config)?;
+    let history = History::open();
+    history.load(&mut rl)?;
     let mut buf = String::new();
     loop {
+        let completions = Completions::of_globals(runtime);
+        rl.set_helper(Some(completions));
+
         let is_first_line = buf.is_empty();
         let prompt =
             runtime.heap().with(|gc, vm| {
@@ -140,16 +613,24 @@ fn do_repl(runtime: &mut Runtime) -> Result<()> {
 
         match rl.readline(&prompt) {
             Ok(line) => {
-                const SOURCE: &str = "=stdin";
-
                 if is_first_line {
+                    if let Some(command) = line.trim_start().strip_prefix(':') {
+                        if let Err(err) = run_command(runtime, command) {
+                            eprintln!("{err}");
+                        }
+                        history.add(&mut rl, &line)?;
+                        continue;
+                    }
+
                     let result = runtime.execute(|gc, vm| {
-                        let closure = vm.borrow().load(gc, format!("print({line})"), SOURCE)?;
-                        Ok(gc.allocate(closure).into())
+                        let closure =
+                            vm.borrow()
+                                .load(gc, format!("return {line}"), REPL_SOURCE)?;
+                        Ok(print_results(gc, gc.allocate(closure).into()))
                     });
                     match result {
                         Ok(()) => {
-                            rl.add_history_entry(line)?;
+                            history.add(&mut rl, &line)?;
                             continue;
                         }
                         Err(RuntimeError {
@@ -158,7 +639,7 @@ fn do_repl(runtime: &mut Runtime) -> Result<()> {
                         }) => (),
                         Err(err) => {
                             eprintln!("{err}");
-                            rl.add_history_entry(line)?;
+                            history.add(&mut rl, &line)?;
                             continue;
                         }
                     }
@@ -167,24 +648,413 @@ fn do_repl(runtime: &mut Runtime) -> Result<()> {
                 }
                 buf.push_str(&line);
 
-                let result = runtime.execute(|gc, vm| match vm.borrow().load(gc, &buf, SOURCE) {
-                    Ok(closure) => Ok(gc.allocate(closure).into()),
-                    Err(err) => Err(err.into()),
-                });
+                let result =
+                    runtime.execute(|gc, vm| match vm.borrow().load(gc, &buf, REPL_SOURCE) {
+                        Ok(closure) => Ok(print_results(gc, gc.allocate(closure).into())),
+                        Err(err) => Err(err.into()),
+                    });
                 match result {
                     Ok(()) => (),
                     Err(err) if is_incomplete_input_error(&err) => continue,
                     Err(err) => eprintln!("{err}"),
                 }
-                rl.add_history_entry(&buf)?;
+                history.add(&mut rl, &buf)?;
                 buf.clear();
             }
+            // Ctrl-C abandons an incomplete chunk, and exits otherwise
+            Err(ReadlineError::Interrupted) if !buf.is_empty() => buf.clear(),
             Err(ReadlineError::Interrupted | ReadlineError::Eof) => return Ok(()),
             Err(err) => return Err(err.into()),
         }
     }
 }
 
+const REPL_SOURCE: &str = "=stdin";
+
+const REPL_HELP: &str = "\
+:help         show this help
+:load FILE    run FILE
+:time CODE    run an expression or statements and show how long it took
+:dis EXPR     list the instructions of the Lua function EXPR evaluates to
+:gc           run a full garbage collection and show the memory in use";
+
+fn run_command(runtime: &mut Runtime, command: &str) -> Result<()> {
+    let (name, arg) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+    let arg = arg.trim();
+    match name {
+        "help" => println!("{REPL_HELP}"),
+        "load" => runtime
+            .execute(|gc, vm| {
+                let closure = vm.borrow().load_file(gc, arg)?;
+                Ok(print_results(gc, gc.allocate(closure).into()))
+            })
+            .map_err(Error::msg)?,
+        "time" => {
+            let start = Instant::now();
+            let result = runtime.execute(|gc, vm| {
+                let vm = vm.borrow();
+                let closure = match vm.load(gc, format!("return {arg}"), REPL_SOURCE) {
+                    Ok(closure) => closure,
+                    Err(_) => vm.load(gc, arg, REPL_SOURCE)?,
+                };
+                Ok(print_results(gc, gc.allocate(closure).into()))
+            });
+            println!("time: {:.3?}", start.elapsed());
+            result.map_err(Error::msg)?;
+        }
+        "dis" => runtime.heap().with(|gc, vm| -> Result<()> {
+            let mut vm = vm.borrow_mut(gc);
+            let closure = vm
+                .load(gc, format!("return {arg}"), REPL_SOURCE)
+                .map_err(Error::msg)?;
+            let results = vm
+                .call_value(gc, gc.allocate(closure).into(), Vec::new())
+                .map_err(Error::msg)?;
+            match results.first() {
+                Some(Value::LuaClosure(closure)) => {
+                    let mut stdout = std::io::stdout().lock();
+                    mochi_lua::disasm::disassemble(&mut stdout, &closure.proto(), true)?;
+                    Ok(())
+                }
+                _ => Err(Error::msg(format!("'{arg}' is not a Lua function"))),
+            }
+        })?,
+        "gc" => {
+            let before = runtime.heap().with(|gc, _| gc.total_bytes());
+            runtime.heap().full_gc();
+            let after = runtime.heap().with(|gc, _| gc.total_bytes());
+            println!(
+                "{:.1} KB in use, {:.1} KB freed",
+                after as f64 / 1024.0,
+                before.saturating_sub(after) as f64 / 1024.0
+            );
+        }
+        _ => return Err(Error::msg(format!("unknown command ':{name}', try :help"))),
+    }
+    Ok(())
+}
+
+// REPL history kept across sessions in ~/.mochi_history, one entry per line
+// with the newlines of multiline entries escaped
+struct History {
+    path: Option<PathBuf>,
+}
+
+impl History {
+    const MAX_LEN: usize = 1000;
+
+    fn open() -> Self {
+        let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+        Self {
+            path: home.map(|home| PathBuf::from(home).join(".mochi_history")),
+        }
+    }
+
+    // Failing to read or write the history file only loses the history
+    fn load<H: rustyline::Helper>(
+        &self,
+        rl: &mut rustyline::Editor<H, rustyline::history::DefaultHistory>,
+    ) -> Result<()> {
+        let Some(contents) = self.path.as_ref().and_then(|path| std::fs::read(path).ok()) else {
+            return Ok(());
+        };
+        let lines: Vec<_> = contents.lines().collect();
+        let lines = &lines[lines.len().saturating_sub(Self::MAX_LEN)..];
+        for line in lines {
+            let mut entry = Vec::with_capacity(line.len());
+            let mut bytes = line.iter();
+            while let Some(&b) = bytes.next() {
+                match (b, bytes.clone().next()) {
+                    (b'\\', Some(b'n')) => entry.push(b'\n'),
+                    (b'\\', Some(b'\\')) => entry.push(b'\\'),
+                    _ => {
+                        entry.push(b);
+                        continue;
+                    }
+                }
+                bytes.next();
+            }
+            rl.add_history_entry(entry.to_str_lossy())?;
+        }
+        // Keeps the file from growing without bound
+        if lines.len() < contents.lines().count() {
+            if let Some(path) = &self.path {
+                let mut contents = bstr::join("\n", lines);
+                contents.push(b'\n');
+                let _ = std::fs::write(path, contents);
+            }
+        }
+        Ok(())
+    }
+
+    fn add<H: rustyline::Helper>(
+        &self,
+        rl: &mut rustyline::Editor<H, rustyline::history::DefaultHistory>,
+        entry: &str,
+    ) -> Result<()> {
+        if !rl.add_history_entry(entry)? {
+            return Ok(());
+        }
+        if let Some(path) = &self.path {
+            let line = entry.replace('\\', "\\\\").replace('\n', "\\n") + "\n";
+            let _ = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(line.as_bytes()));
+        }
+        Ok(())
+    }
+}
+
+// Names for tab completion, taken from the globals before each line is read:
+// the globals and keywords, the fields of tables in globals, and the string
+// methods after ':'
+#[derive(Default)]
+struct Completions {
+    globals: Vec<String>,
+    fields: HashMap<String, Vec<String>>,
+    methods: Vec<String>,
+}
+
+impl Completions {
+    fn of_globals(runtime: &mut Runtime) -> Self {
+        fn string_keys(table: &Table) -> Vec<String> {
+            let mut keys: Vec<_> = table
+                .iter()
+                .filter_map(|(key, _)| match key {
+                    Value::String(key) => key.to_str().ok().map(str::to_owned),
+                    _ => None,
+                })
+                .collect();
+            keys.sort();
+            keys
+        }
+
+        runtime.heap().with(|gc, vm| {
+            let vm = vm.borrow();
+            let globals = vm.globals();
+            let globals = globals.borrow();
+            let mut completions = Self {
+                globals: string_keys(&globals),
+                ..Default::default()
+            };
+            completions.globals.extend(
+                KEYWORDS
+                    .iter()
+                    .map(|keyword| keyword.to_str_lossy().into_owned()),
+            );
+            for (key, value) in globals.iter() {
+                if let (Value::String(key), Some(table)) = (key, value.borrow_as_table()) {
+                    if let Ok(key) = key.to_str() {
+                        completions
+                            .fields
+                            .insert(key.to_owned(), string_keys(&table));
+                    }
+                }
+            }
+            let string = globals.get_field(gc.allocate_string(B("string")));
+            if let Some(string) = string.borrow_as_table() {
+                completions.methods = string_keys(&string);
+            }
+            completions
+        })
+    }
+}
+
+impl rustyline::completion::Completer for Completions {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+        let start = line[..pos].rfind(|c| !is_name_char(c)).map_or(0, |i| i + 1);
+        let partial = &line[start..pos];
+        let before = &line[..start];
+        let names = if before.ends_with(':') {
+            &self.methods
+        } else if let Some(before) = before.strip_suffix('.') {
+            let table_start = before.rfind(|c| !is_name_char(c)).map_or(0, |i| i + 1);
+            // Only fields of globals are known
+            if before[..table_start].ends_with(['.', ':']) {
+                return Ok((start, Vec::new()));
+            }
+            match self.fields.get(&before[table_start..]) {
+                Some(fields) => fields,
+                None => return Ok((start, Vec::new())),
+            }
+        } else {
+            &self.globals
+        };
+        let candidates = names
+            .iter()
+            .filter(|name| name.starts_with(partial))
+            .cloned()
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl rustyline::hint::Hinter for Completions {
+    type Hint = String;
+}
+
+impl rustyline::highlight::Highlighter for Completions {}
+
+impl rustyline::validate::Validator for Completions {}
+
+impl rustyline::Helper for Completions {}
+
+// A function that calls `chunk` and prints what it returns, separated by
+// tabs
+fn print_results<'gc>(gc: &'gc GcContext, chunk: Value<'gc>) -> Value<'gc> {
+    let closure = NativeClosure::with_upvalue(chunk, |_, _, &chunk, _| {
+        Ok(Action::Call {
+            callee: chunk,
+            args: Vec::new(),
+            continuation: Continuation::new(|_, vm, results: Vec<Value>| {
+                if !results.is_empty() {
+                    let mut out = Vec::new();
+                    for (i, value) in results.iter().enumerate() {
+                        if i > 0 {
+                            out.push(b'\t');
+                        }
+                        render_value(&mut out, *value, vm.float_format(), &mut Vec::new());
+                    }
+                    out.push(b'\n');
+                    std::io::stdout().write_all(&out)?;
+                }
+                Ok(Action::Return(Vec::new()))
+            }),
+        })
+    });
+    gc.allocate(closure).into()
+}
+
+// Shows strings quoted and tables with their contents, down to a few levels
+// and without following cycles. `path` holds the tables being shown.
+fn render_value(
+    out: &mut Vec<u8>,
+    value: Value,
+    float_format: FloatFormat,
+    path: &mut Vec<*const ()>,
+) {
+    const MAX_DEPTH: usize = 3;
+    const MAX_ENTRIES: usize = 32;
+
+    let table = match value {
+        Value::String(s) => {
+            out.push(b'"');
+            for &b in s.as_bytes() {
+                match b {
+                    b'"' => out.extend_from_slice(b"\\\""),
+                    b'\\' => out.extend_from_slice(b"\\\\"),
+                    b'\n' => out.extend_from_slice(b"\\n"),
+                    b'\r' => out.extend_from_slice(b"\\r"),
+                    b'\t' => out.extend_from_slice(b"\\t"),
+                    b if b.is_ascii_control() => out.extend_from_slice(format!("\\{b}").as_bytes()),
+                    b => out.push(b),
+                }
+            }
+            out.push(b'"');
+            return;
+        }
+        Value::Table(table) => table,
+        _ => {
+            let _ = value.fmt_bytes_with(out, float_format);
+            return;
+        }
+    };
+    let ptr = table.as_ptr() as *const ();
+    if path.contains(&ptr) {
+        out.extend_from_slice(b"<cycle>");
+        return;
+    }
+    let table = table.borrow();
+    if table.is_empty() {
+        out.extend_from_slice(b"{}");
+        return;
+    }
+    if path.len() >= MAX_DEPTH {
+        out.extend_from_slice(b"{...}");
+        return;
+    }
+
+    path.push(ptr);
+    out.push(b'{');
+    let sequence_len = table.sequence_values().count();
+    let fields = table.iter().filter(|(key, _)| match key {
+        Value::Integer(i) => !(1..=sequence_len as Integer).contains(i),
+        _ => true,
+    });
+    let entries = table
+        .sequence_values()
+        .map(|value| (None, value))
+        .chain(fields.map(|(key, value)| (Some(key), value)));
+    for (i, (key, value)) in entries.enumerate() {
+        if i > 0 {
+            out.extend_from_slice(b", ");
+        }
+        if i == MAX_ENTRIES {
+            out.extend_from_slice(b"...");
+            break;
+        }
+        match key {
+            Some(Value::String(name)) if is_identifier(&name) => {
+                out.extend_from_slice(&name);
+                out.extend_from_slice(b" = ");
+            }
+            Some(key) => {
+                out.push(b'[');
+                render_value(out, key, float_format, path);
+                out.extend_from_slice(b"] = ");
+            }
+            None => (),
+        }
+        render_value(out, value, float_format, path);
+    }
+    out.push(b'}');
+    path.pop();
+}
+
+const KEYWORDS: &[&[u8]] = &[
+    b"and",
+    b"break",
+    b"do",
+    b"else",
+    b"elseif",
+    b"end",
+    b"false",
+    b"for",
+    b"function",
+    b"goto",
+    b"if",
+    b"in",
+    b"local",
+    b"nil",
+    b"not",
+    b"or",
+    b"repeat",
+    b"return",
+    b"then",
+    b"true",
+    b"until",
+    b"while",
+];
+
+fn is_identifier(name: &[u8]) -> bool {
+    name.first()
+        .is_some_and(|c| c.is_ascii_alphabetic() || *c == b'_')
+        && name.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_')
+        && !KEYWORDS.contains(&name)
+}
+
 fn is_incomplete_input_error(err: &RuntimeError) -> bool {
     match err {
         RuntimeError {
@@ -207,15 +1077,74 @@ fn is_incomplete_input_error(err: &RuntimeError) -> bool {
     }
 }
 
+impl BundleCommand {
+    fn run(self) -> Result<()> {
+        let mut runtime = Runtime::new();
+        runtime.heap().with(|gc, vm| -> Result<()> {
+            let mut vm = vm.borrow_mut(gc);
+            vm.load_stdlib(gc);
+
+            let package = vm
+                .globals()
+                .borrow()
+                .get_field(gc.allocate_string(B("package")));
+            let package = package.borrow_as_table().unwrap();
+            let path = package.get_field(gc.allocate_string(B("path")));
+            let loaded = package.get_field(gc.allocate_string(B("loaded")));
+            let loaded = loaded.borrow_as_table().unwrap();
+
+            let bundle = Bundle::new(gc, &self.script, path.coerce_to_string().unwrap())?;
+            for name in bundle.unresolved() {
+                if loaded
+                    .get_field(gc.allocate_string(name.as_slice()))
+                    .is_nil()
+                {
+                    eprintln!(
+                        "warning: module '{}' not found, it will be required at run time",
+                        name.as_bstr()
+                    );
+                }
+            }
+
+            let source = bundle.to_source();
+            if let BundleFormat::Lua = self.format {
+                std::fs::write(&self.output, source)?;
+                return Ok(());
+            }
+
+            let mut chunk_name = b"@".to_vec();
+            chunk_name.extend_from_slice(&Vec::from_path_lossy(&self.script));
+            let proto = mochi_lua::load(gc, source, chunk_name)?;
+            let mut chunk = Vec::new();
+            mochi_lua::binary_chunk::dump(&mut chunk, &proto)?;
+
+            let mut writer = BufWriter::new(File::create(&self.output)?);
+            if let BundleFormat::Exe = self.format {
+                let exe = std::env::current_exe()?;
+                std::io::copy(&mut File::open(&exe)?, &mut writer)?;
+                writer.write_all(&chunk)?;
+                writer.write_all(&(chunk.len() as u64).to_le_bytes())?;
+                writer.write_all(EMBEDDED_CHUNK_MAGIC)?;
+                writer.into_inner()?;
+                std::fs::set_permissions(&self.output, std::fs::metadata(exe)?.permissions())?;
+            } else {
+                writer.write_all(&chunk)?;
+            }
+            Ok(())
+        })
+    }
+}
+
 impl CompileCommand {
     fn run(self) -> Result<()> {
         let mut heap = GcHeap::new();
         heap.with(|gc, _| -> Result<()> {
-            let proto = mochi_lua::load_file(gc, &self.filename)?;
+            let options = CompileOptions::from(&self.compile_flags);
+            let proto = mochi_lua::load_file_with_options(gc, &self.filename, &options)?;
 
             if self.list > 0 {
                 let mut stdout = std::io::stdout().lock();
-                self.dump_proto(&mut stdout, &proto)?;
+                mochi_lua::disasm::disassemble(&mut stdout, &proto, self.list > 1)?;
             }
             if self.parse_only {
                 return Ok(());
@@ -226,182 +1155,208 @@ impl CompileCommand {
             Ok(())
         })
     }
+}
+
+impl DepsCommand {
+    fn run(self) -> Result<()> {
+        let mut runtime = Runtime::new();
+        runtime.heap().with(|gc, vm| -> Result<()> {
+            let mut vm = vm.borrow_mut(gc);
+            vm.load_stdlib(gc);
+            let args = std::env::args_os();
+            let script = args.len() - self.args.len() - 1;
+            set_script_globals(gc, &mut vm.globals().borrow_mut(gc), args, script)
+        })?;
 
-    fn dump_proto(&self, w: &mut impl std::io::Write, proto: &LuaClosureProto) -> Result<()> {
-        fn format_counter(word: &str, n: usize) -> String {
-            format!("{n} {word}{}", if n == 1 { "" } else { "s" })
+        let mut chunk = Vec::new();
+        let result = runtime.execute(|gc, vm| {
+            let proto = mochi_lua::load_file(gc, &self.script)?;
+            chunk = proto.source.as_bytes().to_vec();
+            Ok(gc.allocate(vm.borrow().load_proto(gc, proto)).into())
+        });
+
+        let graph = runtime
+            .heap()
+            .with(|_, vm| vm.borrow().dependency_graph().clone());
+        if !chunk.is_empty() {
+            let mut stdout = std::io::stdout().lock();
+            print_dependencies(&mut stdout, &graph, &chunk, 0, &mut HashSet::new())?;
         }
+        result.map_err(Error::msg)
+    }
+}
 
-        let source = proto.source.as_bstr();
-        let source = if let Some(b'@' | b'=') = source.first() {
-            &source[1..]
-        } else {
-            source
-        };
-        match &proto.lines_defined {
-            LineRange::File => write!(w, "main <{source}:0,0> ")?,
-            LineRange::Lines(range) => write!(
-                w,
-                "function <{}:{},{}> ",
-                source,
-                range.start(),
-                range.end()
-            )?,
-        };
-        writeln!(
-            w,
-            "({})\n{}, {}, {}, {}",
-            format_counter("instruction", proto.code.len()),
-            format_counter("slot", proto.max_stack_size as usize),
-            format_counter("upvalue", proto.upvalues.len()),
-            format_counter("constant", proto.constants.len()),
-            format_counter("function", proto.protos.len())
-        )?;
+fn print_dependencies<'a>(
+    w: &mut impl std::io::Write,
+    graph: &'a DependencyGraph,
+    chunk: &'a [u8],
+    depth: usize,
+    visited: &mut HashSet<&'a [u8]>,
+) -> Result<()> {
+    if depth == 0 {
+        w.write_all(chunk.strip_prefix(b"@").unwrap_or(chunk))?;
+        writeln!(w)?;
+    }
+    if !visited.insert(chunk) {
+        return Ok(());
+    }
+    for name in graph.requires_of(chunk) {
+        write!(w, "{:indent$}", "", indent = (depth + 1) * 2)?;
+        w.write_all(name)?;
+        let module = graph.module(name);
+        if let Some(path) = module.and_then(|module| module.path.as_ref()) {
+            write!(w, " ({})", path.display())?;
+        }
+        writeln!(w)?;
+        if let Some(chunk) = module.and_then(|module| module.chunk.as_deref()) {
+            print_dependencies(w, graph, chunk, depth + 1, visited)?;
+        }
+    }
+    Ok(())
+}
+
+// The console of --debug, which reads commands from stdin
+#[cfg(feature = "debugger")]
+#[derive(Default)]
+struct DebugConsole {
+    // Frame that locals, upvalues and print are about
+    level: usize,
+    sources: HashMap<String, Vec<String>>,
+}
 
-        for (i, insn) in proto.code.iter().enumerate() {
-            let opcode = insn.opcode();
-            write!(w, "\t{}\t{opcode:9}\t", i + 1)?;
-            match opcode {
-                OpCode::Return0 => w.write_all(b"\n")?,
-                OpCode::LoadKX
-                | OpCode::LoadFalse
-                | OpCode::LFalseSkip
-                | OpCode::LoadTrue
-                | OpCode::Close
-                | OpCode::Tbc
-                | OpCode::Return1
-                | OpCode::VarArgPrep => writeln!(w, "{}", insn.a())?,
-                OpCode::Jmp => writeln!(w, "{}", insn.sj())?,
-                OpCode::ExtraArg => writeln!(w, "{}", insn.ax())?,
-                OpCode::Test => writeln!(w, "{} {}", insn.a(), insn.k() as u8)?,
-                OpCode::Move
-                | OpCode::LoadNil
-                | OpCode::GetUpval
-                | OpCode::SetUpval
-                | OpCode::Unm
-                | OpCode::BNot
-                | OpCode::Not
-                | OpCode::Len
-                | OpCode::Concat => writeln!(w, "{} {}", insn.a(), insn.b())?,
-                OpCode::LoadK
-                | OpCode::Closure
-                | OpCode::ForLoop
-                | OpCode::ForPrep
-                | OpCode::TForPrep
-                | OpCode::TForLoop => writeln!(w, "{} {}", insn.a(), insn.bx())?,
-                OpCode::LoadI | OpCode::LoadF => writeln!(w, "{} {}", insn.a(), insn.sbx())?,
-                OpCode::TForCall | OpCode::VarArg => writeln!(w, "{} {}", insn.a(), insn.c())?,
-                OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::EqK | OpCode::TestSet => {
-                    writeln!(w, "{} {} {}", insn.a(), insn.b(), insn.k() as u8)?
+#[cfg(feature = "debugger")]
+const DEBUG_HELP: &str = "\
+break [FILE:]LINE  set a breakpoint
+delete ID          delete a breakpoint
+continue           run until a breakpoint
+step               run to the next line, entering calls
+next               run to the next line of this function
+finish             run until this function returns
+backtrace          show the running functions
+frame N            select the function N levels up
+locals             show the locals of the selected function
+upvalues           show the upvalues of the selected function
+print EXPR         evaluate EXPR in the selected function
+quit               exit";
+
+#[cfg(feature = "debugger")]
+impl DebugHandler for DebugConsole {
+    fn stopped<'gc>(&mut self, gc: &'gc GcContext, vm: &mut Vm<'gc>, stop: &Stop) -> Resume {
+        self.level = 0;
+        if let StopReason::Breakpoint(id) = stop.reason {
+            eprintln!("breakpoint {id}");
+        }
+        self.show_line(&stop.source, Some(stop.line));
+
+        let mut input = String::new();
+        loop {
+            eprint!("(debug) ");
+            input.clear();
+            if !matches!(std::io::stdin().read_line(&mut input), Ok(n) if n > 0) {
+                // Without commands, the script runs to the end
+                vm.detach_debugger();
+                return Resume::Continue;
+            }
+            let input = input.trim();
+            let (command, arg) = input.split_once(' ').unwrap_or((input, ""));
+            let arg = arg.trim();
+            match command {
+                "" => (),
+                "c" | "continue" => return Resume::Continue,
+                "s" | "step" => return Resume::Step,
+                "n" | "next" => return Resume::Next,
+                "f" | "finish" => return Resume::Finish,
+                "b" | "break" => {
+                    let (file, line) = match arg.rsplit_once(':') {
+                        Some((file, line)) => (file, line),
+                        None => (stop.source.as_str(), arg),
+                    };
+                    match line.parse() {
+                        Ok(line) => {
+                            let id = vm.debugger().unwrap().add_breakpoint(file, line);
+                            eprintln!("breakpoint {id} at {file}:{line}");
+                        }
+                        Err(_) => eprintln!("usage: break [FILE:]LINE"),
+                    }
                 }
-                OpCode::EqI | OpCode::LtI | OpCode::LeI | OpCode::GtI | OpCode::GeI => {
-                    writeln!(w, "{} {} {}", insn.a(), insn.sb(), insn.k() as u8)?
+                "d" | "delete" => match arg.parse() {
+                    Ok(id) if vm.debugger().unwrap().remove_breakpoint(id) => (),
+                    _ => eprintln!("no breakpoint {arg}"),
+                },
+                "bt" | "backtrace" => {
+                    for (level, frame) in vm.debug_frames().iter().enumerate() {
+                        let marker = if level == self.level { '>' } else { ' ' };
+                        let line = frame
+                            .line
+                            .map(|line| format!(":{line}"))
+                            .unwrap_or_default();
+                        let name = frame.name.as_deref().unwrap_or("?");
+                        eprintln!("{marker}#{level} {}{line} in {name}", frame.source);
+                    }
                 }
-                OpCode::GetTabUp
-                | OpCode::GetTable
-                | OpCode::GetI
-                | OpCode::GetField
-                | OpCode::NewTable
-                | OpCode::AddK
-                | OpCode::SubK
-                | OpCode::MulK
-                | OpCode::ModK
-                | OpCode::PowK
-                | OpCode::DivK
-                | OpCode::IDivK
-                | OpCode::BAndK
-                | OpCode::BOrK
-                | OpCode::BXorK
-                | OpCode::Add
-                | OpCode::Sub
-                | OpCode::Mul
-                | OpCode::Mod
-                | OpCode::Pow
-                | OpCode::Div
-                | OpCode::IDiv
-                | OpCode::BAnd
-                | OpCode::BOr
-                | OpCode::BXor
-                | OpCode::Shl
-                | OpCode::Shr
-                | OpCode::MmBin
-                | OpCode::Call
-                | OpCode::SetList => writeln!(w, "{} {} {}", insn.a(), insn.b(), insn.c())?,
-                OpCode::MmBinK => writeln!(
-                    w,
-                    "{} {} {} {}",
-                    insn.a(),
-                    insn.b(),
-                    insn.c(),
-                    insn.k() as u8
-                )?,
-                OpCode::MmBinI => writeln!(
-                    w,
-                    "{} {} {} {}",
-                    insn.a(),
-                    insn.sb(),
-                    insn.c(),
-                    insn.k() as u8
-                )?,
-                OpCode::AddI | OpCode::ShrI | OpCode::ShlI => {
-                    writeln!(w, "{} {} {}", insn.a(), insn.b(), insn.sc())?
+                "frame" => {
+                    let frames = vm.debug_frames();
+                    match arg
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|level| Some((level, frames.get(level)?)))
+                    {
+                        Some((level, frame)) => {
+                            self.level = level;
+                            self.show_line(&frame.source, frame.line);
+                        }
+                        None => eprintln!("no frame {arg}"),
+                    }
                 }
-                OpCode::SetTabUp
-                | OpCode::SetTable
-                | OpCode::SetI
-                | OpCode::SetField
-                | OpCode::Self_
-                | OpCode::TailCall
-                | OpCode::Return => writeln!(
-                    w,
-                    "{} {} {}{}",
-                    insn.a(),
-                    insn.b(),
-                    insn.c(),
-                    if insn.k() { "k" } else { "" }
-                )?,
-            };
-        }
-
-        if self.list > 1 {
-            writeln!(w, "constants ({}):", proto.constants.len())?;
-            for (i, constant) in proto.constants.iter().enumerate() {
-                write!(w, "\t{i}\t")?;
-                w.write_all(match constant {
-                    Value::Nil => b"N",
-                    Value::Boolean(_) => b"B",
-                    Value::Integer(_) => b"I",
-                    Value::Number(_) => b"F",
-                    Value::String(_) => b"S",
-                    _ => b"?",
-                })?;
-                match constant {
-                    Value::Nil | Value::Boolean(_) | Value::Integer(_) | Value::Number(_) => {
-                        w.write_all(b"\t")?;
-                        constant.fmt_bytes(w)?;
-                        w.write_all(b"\n")?;
+                "l" | "locals" | "u" | "upvalues" => {
+                    let vars = if command.starts_with('l') {
+                        vm.debug_locals(self.level)
+                    } else {
+                        vm.debug_upvalues(self.level)
+                    };
+                    for (name, value) in vars {
+                        let mut out = Vec::new();
+                        render_value(&mut out, value, vm.float_format(), &mut Vec::new());
+                        eprintln!("{name} = {}", out.as_bstr());
                     }
-                    Value::String(s) => writeln!(w, "\t{s:?}")?,
-                    _ => w.write_all(b"\t?\n")?,
-                };
-            }
-
-            writeln!(w, "upvalues ({}):", proto.upvalues.len())?;
-            for (i, desc) in proto.upvalues.iter().enumerate() {
-                write!(w, "\t{i}\t")?;
-                match desc {
-                    UpvalueDescription::Register(i) => writeln!(w, "1\t{}", i.0)?,
-                    UpvalueDescription::Upvalue(i) => writeln!(w, "0\t{}", i.0)?,
                 }
+                "p" | "print" => match vm.debug_evaluate(gc, self.level, arg) {
+                    Ok(values) if values.is_empty() => (),
+                    Ok(values) => {
+                        let mut out = Vec::new();
+                        for (i, value) in values.iter().enumerate() {
+                            if i > 0 {
+                                out.push(b'\t');
+                            }
+                            render_value(&mut out, *value, vm.float_format(), &mut Vec::new());
+                        }
+                        eprintln!("{}", out.as_bstr());
+                    }
+                    Err(err) => eprintln!("{err}"),
+                },
+                "q" | "quit" => std::process::exit(0),
+                "h" | "help" => eprintln!("{DEBUG_HELP}"),
+                _ => eprintln!("unknown command '{command}' (try help)"),
             }
         }
+    }
+}
 
-        writeln!(w)?;
-
-        for proto in proto.protos.iter() {
-            self.dump_proto(w, proto)?;
+#[cfg(feature = "debugger")]
+impl DebugConsole {
+    fn show_line(&mut self, source: &str, line: Option<u32>) {
+        let Some(line) = line else {
+            eprintln!("{source}");
+            return;
+        };
+        eprintln!("{source}:{line}");
+        let lines = self.sources.entry(source.to_owned()).or_insert_with(|| {
+            std::fs::read_to_string(source)
+                .map(|text| text.lines().map(str::to_owned).collect())
+                .unwrap_or_default()
+        });
+        if let Some(text) = (line as usize).checked_sub(1).and_then(|i| lines.get(i)) {
+            eprintln!("{line}\t{text}");
         }
-
-        Ok(())
     }
 }
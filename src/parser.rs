@@ -1,12 +1,16 @@
 pub mod ast;
 
 pub use crate::lexer::LexerError;
+use crate::prelude::*;
 
 use crate::{
     gc::GcContext,
+    io::Read,
     lexer::{Lexer, Token},
     types::LuaString,
+    CompileOptions,
 };
+use alloc::borrow::Cow;
 use ast::{
     AssignmentStatement, BinaryOp, BinaryOpExpression, Block, Chunk, Expression, ForStatement,
     FunctionArguments, FunctionCallStatement, FunctionExpression, FunctionStatement, IfStatement,
@@ -14,7 +18,6 @@ use ast::{
     Suffix, SuffixedExpression, TableConstructorExpression, TableField, TableRecordKey, UnaryOp,
     UnaryOpExpression, Variable, WhileStatement,
 };
-use std::{borrow::Cow, io::Read};
 
 #[derive(Debug, thiserror::Error)]
 pub struct ParseError {
@@ -28,8 +31,8 @@ pub struct ParseError {
     pub incomplete_input: bool,
 }
 
-impl std::fmt::Display for ParseError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}:{}: {}", self.source, self.lineno, self.kind)?;
         if let Some(token) = &self.next_token {
             write!(f, " near {token}")?;
@@ -49,6 +52,9 @@ pub enum ErrorKind {
     #[error("unexpected symbol")]
     UnexpectedSymbol,
 
+    #[error("'{0}' not allowed")]
+    NotAllowed(&'static str),
+
     #[error(transparent)]
     Lexer(#[from] LexerError),
 }
@@ -73,8 +79,18 @@ pub fn parse<R: Read, S: AsRef<str>>(
     gc: &GcContext,
     source: S,
     reader: R,
-) -> Result<Chunk, ParseError> {
+) -> Result<Chunk<'_>, ParseError> {
+    parse_with_options(gc, source, reader, &CompileOptions::default())
+}
+
+pub fn parse_with_options<'gc, R: Read, S: AsRef<str>>(
+    gc: &'gc GcContext,
+    source: S,
+    reader: R,
+    options: &CompileOptions,
+) -> Result<Chunk<'gc>, ParseError> {
     let mut parser = Parser::new(gc, reader);
+    parser.forbid_goto = options.forbid_goto;
     match parser.parse_chunk() {
         Ok(chunk) => Ok(chunk),
         Err(kind) => {
@@ -98,12 +114,14 @@ pub fn parse<R: Read, S: AsRef<str>>(
 
 struct Parser<'gc, R: Read> {
     lexer: Lexer<'gc, R>,
+    forbid_goto: bool,
 }
 
 impl<'gc, R: Read> Parser<'gc, R> {
     fn new(gc: &'gc GcContext, reader: R) -> Self {
         Self {
             lexer: Lexer::new(gc, reader),
+            forbid_goto: false,
         }
     }
 
@@ -127,12 +145,16 @@ impl<'gc, R: Read> Parser<'gc, R> {
                     })
                 }
                 Some(Token::Return) => {
+                    let line = self.lexer.lineno();
                     return Ok(Block {
                         statements,
-                        return_statement: Some(self.parse_return_statement()?),
-                    })
+                        return_statement: Some((line, self.parse_return_statement()?)),
+                    });
+                }
+                _ => {
+                    let line = self.lexer.lineno();
+                    statements.push((line, self.parse_statement()?));
                 }
-                _ => statements.push(self.parse_statement()?),
             }
         }
     }
@@ -274,6 +296,7 @@ impl<'gc, R: Read> Parser<'gc, R> {
 
     fn parse_func_statement(&mut self) -> Result<FunctionStatement<'gc>, ErrorKind> {
         self.expect(Token::Function)?;
+        let line_defined = self.lexer.lineno();
         let name = self.expect_name()?;
         let mut fields = Vec::new();
         while self.lexer.consume_if_eq(Token::Dot)? {
@@ -284,39 +307,11 @@ impl<'gc, R: Read> Parser<'gc, R> {
         } else {
             None
         };
-        self.expect(Token::LeftParen)?;
-
-        let mut params = Vec::new();
-        let mut is_vararg = false;
-        if !self.lexer.consume_if_eq(Token::RightParen)? {
-            loop {
-                match self.lexer.consume()? {
-                    Some(Token::Name(name)) => params.push(name),
-                    Some(Token::Dots) => {
-                        is_vararg = true;
-                        break;
-                    }
-                    _ => return Err(ErrorKind::unexpected_token("<name> or '...'")),
-                }
-                if !self.lexer.consume_if_eq(Token::Comma)? {
-                    break;
-                }
-            }
-            self.expect(Token::RightParen)?;
-        }
-
-        let body = self.parse_block()?;
-        self.expect(Token::End)?;
-
         Ok(FunctionStatement {
             name,
             fields,
             method,
-            expression: FunctionExpression {
-                params,
-                is_vararg,
-                body,
-            },
+            expression: self.parse_func_body(line_defined)?,
         })
     }
 
@@ -345,6 +340,9 @@ impl<'gc, R: Read> Parser<'gc, R> {
     }
 
     fn parse_label(&mut self) -> Result<LuaString<'gc>, ErrorKind> {
+        if self.forbid_goto {
+            return Err(ErrorKind::NotAllowed("::"));
+        }
         self.expect(Token::DoubleColon)?;
         let label = self.expect_name()?;
         self.expect(Token::DoubleColon)?;
@@ -352,6 +350,9 @@ impl<'gc, R: Read> Parser<'gc, R> {
     }
 
     fn parse_goto_statement(&mut self) -> Result<LuaString<'gc>, ErrorKind> {
+        if self.forbid_goto {
+            return Err(ErrorKind::NotAllowed("goto"));
+        }
         self.expect(Token::Goto)?;
         self.expect_name()
     }
@@ -532,6 +533,14 @@ impl<'gc, R: Read> Parser<'gc, R> {
 
     fn parse_func_expr(&mut self) -> Result<FunctionExpression<'gc>, ErrorKind> {
         self.expect(Token::Function)?;
+        let line_defined = self.lexer.lineno();
+        self.parse_func_body(line_defined)
+    }
+
+    fn parse_func_body(
+        &mut self,
+        line_defined: usize,
+    ) -> Result<FunctionExpression<'gc>, ErrorKind> {
         self.expect(Token::LeftParen)?;
 
         let mut params = Vec::new();
@@ -560,6 +569,8 @@ impl<'gc, R: Read> Parser<'gc, R> {
             params,
             is_vararg,
             body,
+            line_defined,
+            last_line_defined: self.lexer.lineno(),
         })
     }
 
@@ -1,12 +1,16 @@
-use crate::types::{Integer, LuaString, Number};
+use crate::{
+    prelude::*,
+    types::{Integer, LuaString, Number},
+};
 
 #[derive(Debug, Clone)]
 pub struct Chunk<'gc>(pub Block<'gc>);
 
 #[derive(Debug, Clone)]
 pub struct Block<'gc> {
-    pub statements: Vec<Statement<'gc>>,
-    pub return_statement: Option<ReturnStatement<'gc>>,
+    // Statements with the lines they start on
+    pub statements: Vec<(usize, Statement<'gc>)>,
+    pub return_statement: Option<(usize, ReturnStatement<'gc>)>,
 }
 
 #[derive(Debug, Clone)]
@@ -145,6 +149,8 @@ pub struct FunctionExpression<'gc> {
     pub params: Vec<LuaString<'gc>>,
     pub is_vararg: bool,
     pub body: Block<'gc>,
+    pub line_defined: usize,
+    pub last_line_defined: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -0,0 +1,14 @@
+// What the std prelude has over core's, so that modules read the same with
+// and without the "std" feature
+pub(crate) use alloc::{
+    borrow::ToOwned,
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+#[cfg(not(feature = "std"))]
+#[allow(unused_imports)]
+pub(crate) use crate::math::Float;
@@ -0,0 +1,193 @@
+use crate::{
+    gc::Gc,
+    runtime::{Frame, Vm},
+    types::{LineRange, LuaClosureProto, LuaThread},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+#[derive(Debug, Clone)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub calls: u64,
+    pub instructions: u64,
+    // Samples taken while the function itself was running
+    pub samples: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub interval: Duration,
+    // Functions sorted by instructions executed
+    pub functions: Vec<FunctionProfile>,
+    // Number of samples of each stack, with frames from the outermost
+    // separated by ';'
+    pub stacks: BTreeMap<String, u64>,
+}
+
+impl Profile {
+    // Writes the stacks in the folded format taken by flamegraph tools
+    pub fn write_folded(&self, w: &mut impl Write) -> std::io::Result<()> {
+        for (stack, count) in &self.stacks {
+            writeln!(w, "{stack} {count}")?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct Sampler<'gc> {
+    interval: Duration,
+    should_sample: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+    timer: Option<JoinHandle<()>>,
+    stacks: HashMap<String, u64>,
+    leaf_samples: HashMap<*const LuaClosureProto<'gc>, u64>,
+    names: HashMap<*const LuaClosureProto<'gc>, String>,
+}
+
+impl Sampler<'_> {
+    fn new(interval: Duration) -> Self {
+        let should_sample = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let timer = {
+            let should_sample = should_sample.clone();
+            let stopped = stopped.clone();
+            std::thread::spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    std::thread::sleep(interval);
+                    should_sample.store(true, Ordering::Relaxed);
+                }
+            })
+        };
+        Self {
+            interval,
+            should_sample,
+            stopped,
+            timer: Some(timer),
+            stacks: Default::default(),
+            leaf_samples: Default::default(),
+            names: Default::default(),
+        }
+    }
+
+    pub(crate) fn should_sample(&self) -> bool {
+        self.should_sample.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Sampler<'_> {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(timer) = self.timer.take() {
+            timer.join().ok();
+        }
+    }
+}
+
+impl<'gc> Vm<'gc> {
+    // Starts sampling the running stack every `interval`, and counting calls
+    // and instructions per function
+    pub fn start_profiler(&mut self, interval: Duration) {
+        self.set_function_stats_enabled(false);
+        self.set_function_stats_enabled(true);
+        self.profiler = Some(Sampler::new(interval));
+    }
+
+    pub fn stop_profiler(&mut self) -> Option<Profile> {
+        let sampler = self.profiler.take()?;
+        let functions = self
+            .function_stats()
+            .into_iter()
+            .map(|stats| FunctionProfile {
+                name: sampler
+                    .names
+                    .get(&stats.proto.as_ptr())
+                    .cloned()
+                    .unwrap_or_else(|| function_name(&stats.proto, None)),
+                calls: stats.calls,
+                instructions: stats.instructions,
+                samples: sampler
+                    .leaf_samples
+                    .get(&stats.proto.as_ptr())
+                    .copied()
+                    .unwrap_or(0),
+            })
+            .collect();
+        self.set_function_stats_enabled(false);
+        Some(Profile {
+            interval: sampler.interval,
+            functions,
+            stacks: sampler
+                .stacks
+                .iter()
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
+        })
+    }
+
+    // `current` is the borrowed current thread, which isn't borrowed again
+    pub(crate) fn record_sample(&mut self, current: &LuaThread<'gc>) {
+        let Some(sampler) = &mut self.profiler else {
+            return;
+        };
+        sampler.should_sample.store(false, Ordering::Relaxed);
+
+        let mut stack = String::new();
+        let mut leaf = None;
+        let mut push_frames = |thread: &LuaThread<'gc>, is_coroutine: bool| {
+            let mut caller: Option<(Gc<LuaClosureProto>, usize)> = None;
+            for frame in thread.frames.iter().filter_map(Frame::as_lua) {
+                let proto = thread.stack[frame.bottom].as_lua_closure().unwrap().proto;
+                // functions are named after the call site, as protos don't
+                // always know where they were defined
+                let name = match caller {
+                    Some((caller, pc)) => {
+                        let call_name = caller.funcname_from_code(pc).map(|info| info.name);
+                        function_name(&proto, call_name)
+                    }
+                    None if is_coroutine => function_name(&proto, Some("coroutine")),
+                    None => {
+                        let source = String::from_utf8_lossy(&proto.source);
+                        format!("main chunk <{}>", crate::chunk_id_from_source(&source))
+                    }
+                };
+                if !stack.is_empty() {
+                    stack.push(';');
+                }
+                stack.push_str(&name.replace(';', ":"));
+                sampler.names.insert(proto.as_ptr(), name);
+                leaf = Some(proto.as_ptr());
+                caller = Some((proto, frame.last_pc()));
+            }
+        };
+        if let Some((_, resumers)) = self.thread_stack.split_last() {
+            for (i, thread) in resumers.iter().enumerate() {
+                push_frames(&thread.borrow(), i > 0);
+            }
+        }
+        push_frames(current, self.thread_stack.len() > 1);
+
+        if let Some(leaf) = leaf {
+            *sampler.stacks.entry(stack).or_default() += 1;
+            *sampler.leaf_samples.entry(leaf).or_default() += 1;
+        }
+    }
+}
+
+fn function_name(proto: &LuaClosureProto, name: Option<&str>) -> String {
+    let source = String::from_utf8_lossy(&proto.source);
+    let source = crate::chunk_id_from_source(&source);
+    let name = name.unwrap_or("function");
+    match &proto.lines_defined {
+        LineRange::Lines(range) => format!("{name} <{source}:{}>", range.start()),
+        LineRange::File => format!("{name} <{source}>"),
+    }
+}
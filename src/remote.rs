@@ -0,0 +1,255 @@
+pub(crate) mod sha256;
+
+use crate::gc::MaybeSend;
+use std::{collections::HashMap, path::PathBuf};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RemoteError {
+    #[error("'{0}' is not an https URL")]
+    InsecureUrl(String),
+
+    #[error("invalid integrity hash '{0}'")]
+    InvalidIntegrity(String),
+
+    #[error("invalid lockfile entry at line {0}")]
+    InvalidLockfile(usize),
+}
+
+// Returns the contents at a URL, or None if there is nothing there so that
+// the next location is tried
+pub type Fetcher = dyn Fn(&str) -> Result<Option<Vec<u8>>, String>;
+
+// Modules that require fetches over HTTPS, for hosts that distribute Lua
+// libraries from an artifact server instead of vendoring the files. Mochi
+// doesn't make requests itself, the host provides the fetcher.
+//
+// The module "a.b" is looked up as "a/b.lua" and "a/b/init.lua" under each
+// base URL in turn. Only URLs with a pinned integrity hash are fetched unless
+// unpinned ones are allowed, and a module that doesn't match its hash fails
+// to load. Fetched modules are kept in the cache directory, if there is one,
+// and later lookups use the cached copy when it still matches.
+pub struct RemoteModules {
+    base_urls: Vec<String>,
+    fetcher: Box<Fetcher>,
+    pins: HashMap<String, [u8; 32]>,
+    allow_unpinned: bool,
+    cache_dir: Option<PathBuf>,
+}
+
+impl std::fmt::Debug for RemoteModules {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RemoteModules")
+            .field("base_urls", &self.base_urls)
+            .field("pins", &self.pins.len())
+            .field("allow_unpinned", &self.allow_unpinned)
+            .field("cache_dir", &self.cache_dir)
+            .finish()
+    }
+}
+
+pub(crate) enum Lookup {
+    Found { url: String, contents: Vec<u8> },
+    NotFound(Vec<u8>),
+    Failed { url: String, message: String },
+}
+
+impl RemoteModules {
+    pub fn new<F>(fetcher: F) -> Self
+    where
+        F: 'static + MaybeSend + Fn(&str) -> Result<Option<Vec<u8>>, String>,
+    {
+        Self {
+            base_urls: Vec::new(),
+            fetcher: Box::new(fetcher),
+            pins: HashMap::new(),
+            allow_unpinned: false,
+            cache_dir: None,
+        }
+    }
+
+    pub fn add_base_url<S: Into<String>>(&mut self, url: S) -> Result<&mut Self, RemoteError> {
+        let mut url = url.into();
+        if !url.starts_with("https://") {
+            return Err(RemoteError::InsecureUrl(url));
+        }
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        self.base_urls.push(url);
+        Ok(self)
+    }
+
+    // Pins the module at `url` to an integrity hash of the form
+    // "sha256-<base64 digest>"
+    pub fn pin<S: Into<String>>(
+        &mut self,
+        url: S,
+        integrity: &str,
+    ) -> Result<&mut Self, RemoteError> {
+        let digest = parse_integrity(integrity)
+            .ok_or_else(|| RemoteError::InvalidIntegrity(integrity.to_owned()))?;
+        self.pins.insert(url.into(), digest);
+        Ok(self)
+    }
+
+    // Pins every module listed in a lockfile, which has a URL and its
+    // integrity hash on each line. Blank lines and lines starting with '#'
+    // are skipped.
+    pub fn load_lockfile(&mut self, lockfile: &str) -> Result<&mut Self, RemoteError> {
+        for (i, line) in lockfile.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (Some(url), Some(integrity), None) = (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(RemoteError::InvalidLockfile(i + 1));
+            };
+            let digest = parse_integrity(integrity).ok_or(RemoteError::InvalidLockfile(i + 1))?;
+            self.pins.insert(url.to_owned(), digest);
+        }
+        Ok(self)
+    }
+
+    pub fn allow_unpinned(&mut self, allow: bool) -> &mut Self {
+        self.allow_unpinned = allow;
+        self
+    }
+
+    pub fn cache_dir<P: Into<PathBuf>>(&mut self, dir: P) -> &mut Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    // The integrity hash of `contents` to pin it with
+    pub fn integrity(contents: &[u8]) -> String {
+        format!("sha256-{}", base64_encode(&sha256::digest(contents)))
+    }
+
+    pub(crate) fn find_module(&self, name: &[u8]) -> Lookup {
+        let mut msg = Vec::new();
+        for base_url in &self.base_urls {
+            for path in crate::archive::Archive::module_paths(name) {
+                let url = format!("{base_url}{}", String::from_utf8_lossy(&path));
+                if !self.allow_unpinned && !self.pins.contains_key(&url) {
+                    msg.extend_from_slice(format!("\n\tno pinned module '{url}'").as_bytes());
+                    continue;
+                }
+
+                if let Some(contents) = self.read_cache(&url) {
+                    if self.check(&url, &contents).is_ok() {
+                        return Lookup::Found { url, contents };
+                    }
+                }
+
+                let contents = match (self.fetcher)(&url) {
+                    Ok(Some(contents)) => contents,
+                    Ok(None) => {
+                        msg.extend_from_slice(format!("\n\tno module at '{url}'").as_bytes());
+                        continue;
+                    }
+                    Err(message) => return Lookup::Failed { url, message },
+                };
+                if let Err(message) = self.check(&url, &contents) {
+                    return Lookup::Failed { url, message };
+                }
+                self.write_cache(&url, &contents);
+                return Lookup::Found { url, contents };
+            }
+        }
+        // Messages are joined by require, which adds the first separator
+        Lookup::NotFound(msg.strip_prefix(b"\n\t").unwrap_or(&msg).to_vec())
+    }
+
+    fn check(&self, url: &str, contents: &[u8]) -> Result<(), String> {
+        match self.pins.get(url) {
+            Some(expected) => {
+                let digest = sha256::digest(contents);
+                if digest == *expected {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "integrity check failed (expected sha256-{}, got sha256-{})",
+                        base64_encode(expected),
+                        base64_encode(&digest)
+                    ))
+                }
+            }
+            None if self.allow_unpinned => Ok(()),
+            None => Err("no integrity hash pinned".to_owned()),
+        }
+    }
+
+    // Cached modules are named after the hash of their URL
+    fn cache_path(&self, url: &str) -> Option<PathBuf> {
+        let dir = self.cache_dir.as_ref()?;
+        let name: String = sha256::digest(url.as_bytes())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        Some(dir.join(name + ".lua"))
+    }
+
+    fn read_cache(&self, url: &str) -> Option<Vec<u8>> {
+        std::fs::read(self.cache_path(url)?).ok()
+    }
+
+    // Failing to cache a module only means fetching it again next time
+    fn write_cache(&self, url: &str, contents: &[u8]) {
+        if let Some(path) = self.cache_path(url) {
+            let _ = path
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| std::fs::write(path, contents));
+        }
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn parse_integrity(integrity: &str) -> Option<[u8; 32]> {
+    let digest = base64_decode(integrity.strip_prefix("sha256-")?)?;
+    digest.try_into().ok()
+}
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if !s.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    for chunk in s.chunks(4) {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 {
+            return None;
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let digit = BASE64_ALPHABET.iter().position(|&d| d == c)?;
+            n = n << 6 | digit as u32;
+        }
+        n <<= 6 * padding as u32;
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(out)
+}
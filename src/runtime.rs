@@ -2,32 +2,85 @@ pub(crate) mod instruction;
 
 mod action;
 mod bytecode_vm;
+mod call;
 mod debug;
+#[cfg(feature = "std")]
+mod deps;
+mod determinism;
 mod error;
 mod frame;
+mod fuel;
+#[cfg(feature = "async")]
+mod future;
+#[cfg(feature = "jit")]
+mod jit;
 mod metamethod;
 mod opcode;
 mod ops;
-
+mod optimize;
+#[cfg(feature = "catch-panic")]
+mod panic;
+#[cfg(feature = "std")]
+mod prefetch;
+mod reload;
+mod rewrite;
+mod stats;
+#[cfg(feature = "std")]
+mod timer;
+#[cfg(feature = "trace")]
+mod trace;
+mod verify;
+
+use crate::prelude::*;
 pub use action::{Action, Continuation};
-pub use error::{ErrorKind, Operation, RuntimeError};
+pub use call::Function;
+#[cfg(feature = "std")]
+pub use deps::{DependencyGraph, ModuleInfo};
+pub use determinism::{Clock, Deterministic};
+pub use error::{ErrorKind, ErrorObject, InternalError, Operation, RuntimeError};
 pub(crate) use frame::{ContinuationFrame, Frame, LuaFrame};
+#[cfg(feature = "async")]
+pub use future::{future_output, FutureOutput, LuaFuture};
 pub use instruction::Instruction;
+#[cfg(feature = "jit")]
+pub(crate) use jit::JitState;
 pub use metamethod::Metamethod;
 pub use opcode::OpCode;
+pub use optimize::optimize;
+#[cfg(feature = "std")]
+pub(crate) use prefetch::collect_requires;
+pub use rewrite::{rewrite_protos, CodeRewriter, RewriteError};
+pub use stats::FunctionStats;
+pub use verify::{verify, VerifyError};
 
 use crate::{
-    gc::{GarbageCollect, GcCell, GcContext, GcHeap, Tracer},
-    types::{LuaString, LuaThread, Table, ThreadStatus, Type, Upvalue, Value},
-    Error, LuaClosure,
+    archive::Archive,
+    collections::HashMap,
+    gc::{GarbageCollect, GcCell, GcContext, GcHeap, MaybeSend, Tracer},
+    sync::Mutex,
+    types::{
+        FloatFormat, Integer, LuaClosureProto, LuaString, LuaThread, NativeClosure, Table,
+        ThreadStatus, Type, Upvalue, UserData, Value,
+    },
+    CompileOptions, Error, LuaClosure,
+};
+#[cfg(feature = "std")]
+use crate::{chunk_cache::ChunkCache, remote::RemoteModules};
+use alloc::sync::Arc;
+use core::{
+    any::{Any, TypeId},
+    ops::ControlFlow,
 };
-use std::{ops::ControlFlow, path::Path};
+#[cfg(feature = "std")]
+use std::{path::Path, time::Instant};
 
 use self::debug::DebugNameInfo;
 
 #[derive(Default)]
 pub struct Runtime {
     heap: GcHeap,
+    #[cfg(feature = "async")]
+    awaiting: Option<LuaFuture>,
 }
 
 impl Runtime {
@@ -35,6 +88,14 @@ impl Runtime {
         Default::default()
     }
 
+    pub fn with_heap(heap: GcHeap) -> Self {
+        Self {
+            heap,
+            #[cfg(feature = "async")]
+            awaiting: None,
+        }
+    }
+
     pub fn heap(&mut self) -> &mut GcHeap {
         &mut self.heap
     }
@@ -50,9 +111,28 @@ impl Runtime {
             GcCell<'gc, Vm<'gc>>,
         ) -> Result<
             Value<'gc>,
-            Box<dyn std::error::Error + Send + Sync + 'static>,
+            Box<dyn core::error::Error + Send + Sync + 'static>,
+        >,
+    {
+        self.start(f)?;
+        self.finish()
+    }
+
+    // Sets up `f` to be called on the main thread without running it.
+    // Use run_for to run it.
+    pub fn start<F>(&mut self, f: F) -> Result<(), RuntimeError>
+    where
+        F: for<'gc> FnOnce(
+            &'gc GcContext,
+            GcCell<'gc, Vm<'gc>>,
+        ) -> Result<
+            Value<'gc>,
+            Box<dyn core::error::Error + Send + Sync + 'static>,
         >,
     {
+        #[cfg(feature = "catch-panic")]
+        panic::take_last_instruction();
+
         let result = self.heap.with(|gc, vm| {
             let value = match f(gc, vm) {
                 Ok(value) => value,
@@ -70,28 +150,140 @@ impl Runtime {
             assert!(thread_ref.frames.is_empty());
             assert!(thread_ref.open_upvalues.is_empty());
             thread_ref.stack.push(value);
-            vm.push_frame(&mut thread_ref, 0)?;
+            let _ = vm.push_frame(&mut thread_ref, 0)?;
 
             Ok(())
         });
-        match result {
-            Ok(()) => (),
-            Err(kind) => {
-                return Err(RuntimeError {
-                    kind,
-                    traceback: Vec::new(),
-                })
-            }
+        result.map_err(|kind| RuntimeError {
+            kind,
+            traceback: Vec::new(),
+        })
+    }
+
+    // Runs the started function to completion, blocking while it sleeps or
+    // waits on a future
+    pub fn finish(&mut self) -> Result<(), RuntimeError> {
+        while !self.run()? {
+            #[cfg(feature = "std")]
+            self.wait_until_awake()?;
+            #[cfg(feature = "async")]
+            self.block_on_awaited();
+        }
+        Ok(())
+    }
+
+    // Runs the started function for about `budget` instructions.
+    // Execution can only pause between some instructions, so a slice may
+    // run a few instructions over budget.
+    // Timers that are due run first, and nothing else runs while the runtime
+    // is asleep or the future it waits on isn't ready, which is polled
+    // without a waker.
+    pub fn run_for(&mut self, budget: u64) -> Result<Slice, RuntimeError> {
+        #[cfg(feature = "std")]
+        self.run_timers()?;
+        #[cfg(feature = "async")]
+        let awaiting = self
+            .poll_awaited(&mut core::task::Context::from_waker(
+                core::task::Waker::noop(),
+            ))
+            .is_pending();
+        #[cfg(not(feature = "async"))]
+        let awaiting = false;
+        #[cfg(feature = "std")]
+        let awaiting = awaiting || self.is_asleep();
+        if awaiting {
+            return Ok(Slice {
+                instructions: 0,
+                finished: false,
+            });
         }
 
+        let budget = i64::try_from(budget).unwrap_or(i64::MAX);
+        self.heap
+            .with(|gc, vm| vm.borrow_mut(gc).replace_budget(budget));
+        let result = self.run();
+        let remaining = self
+            .heap
+            .with(|gc, vm| vm.borrow_mut(gc).replace_budget(i64::MAX));
+        let instructions = budget.saturating_sub(remaining) as u64;
+        result.map(|finished| Slice {
+            instructions,
+            finished,
+        })
+    }
+
+    pub fn is_running(&mut self) -> bool {
+        self.heap.with(|_, vm| !vm.borrow().thread_stack.is_empty())
+    }
+
+    fn run(&mut self) -> Result<bool, RuntimeError> {
         loop {
-            let action = self
-                .heap
-                .with(|gc, vm| vm.borrow_mut(gc).execute_single_step(gc))?;
-            match action {
-                RuntimeAction::StepGc => self.heap.step(),
+            match self.execute_single_step()? {
+                RuntimeAction::StepGc => {
+                    self.heap.step();
+                    self.enforce_memory_limit()?;
+                }
                 RuntimeAction::MutateGc(mutator) => mutator(&mut self.heap),
-                RuntimeAction::Exit => return Ok(()),
+                #[cfg(feature = "async")]
+                RuntimeAction::Await(future) => {
+                    self.awaiting = Some(future);
+                    return Ok(false);
+                }
+                RuntimeAction::Pause => return Ok(false),
+                RuntimeAction::Exit => return Ok(true),
+            }
+        }
+    }
+
+    fn enforce_memory_limit(&mut self) -> Result<(), RuntimeError> {
+        fn is_over_limit(heap: &mut GcHeap) -> bool {
+            heap.with(|gc, vm| {
+                let vm = vm.borrow();
+                !vm.thread_stack.is_empty()
+                    && vm
+                        .memory_limit
+                        .is_some_and(|limit| gc.total_bytes() > limit)
+            })
+        }
+
+        // The allocator refusing an allocation counts as running out of
+        // memory too
+        let allocation_failed = self
+            .heap
+            .with(|gc, vm| gc.take_allocation_failure() && !vm.borrow().thread_stack.is_empty());
+        let out_of_memory = allocation_failed
+            || is_over_limit(&mut self.heap) && {
+                self.heap.full_gc();
+                is_over_limit(&mut self.heap)
+            };
+        if out_of_memory {
+            self.heap.with(|gc, vm| {
+                vm.borrow_mut(gc)
+                    .unwind(gc, ErrorKind::other("not enough memory"))
+            })?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "catch-panic"))]
+    fn execute_single_step(&mut self) -> Result<RuntimeAction, RuntimeError> {
+        self.heap
+            .with(|gc, vm| vm.borrow_mut(gc).execute_single_step(gc))
+    }
+
+    #[cfg(feature = "catch-panic")]
+    fn execute_single_step(&mut self) -> Result<RuntimeAction, RuntimeError> {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            self.heap
+                .with(|gc, vm| vm.borrow_mut(gc).execute_single_step(gc))
+        }));
+        match result {
+            Ok(result) => result,
+            Err(payload) => {
+                let message = panic::message_from_payload(payload.as_ref());
+                Err(self
+                    .heap
+                    .with(|gc, vm| vm.borrow_mut(gc).recover_from_panic(gc, message)))
             }
         }
     }
@@ -100,16 +292,75 @@ impl Runtime {
 enum RuntimeAction {
     StepGc,
     MutateGc(Box<dyn Fn(&mut GcHeap)>),
+    #[cfg(feature = "async")]
+    Await(LuaFuture),
+    Pause,
     Exit,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct Slice {
+    pub instructions: u64,
+    pub finished: bool,
+}
+
 pub struct Vm<'gc> {
     registry: GcCell<'gc, Table<'gc>>,
     main_thread: GcCell<'gc, LuaThread<'gc>>,
     globals: GcCell<'gc, Table<'gc>>,
-    thread_stack: Vec<GcCell<'gc, LuaThread<'gc>>>,
+    pub(crate) thread_stack: Vec<GcCell<'gc, LuaThread<'gc>>>,
+    // Threads running a call_value, which can't yield until it returns
+    host_calls: Vec<GcCell<'gc, LuaThread<'gc>>>,
     metamethod_names: [LuaString<'gc>; Metamethod::COUNT],
     metatables: [Option<GcCell<'gc, Table<'gc>>>; Type::COUNT],
+    userdata_metatables: HashMap<TypeId, GcCell<'gc, Table<'gc>>>,
+    // Error objects that ErrorKind::Object refers to by key, which is also
+    // registry._ERROROBJECTS
+    error_objects: GcCell<'gc, Table<'gc>>,
+    next_error_object: Integer,
+    released_error_objects: Arc<Mutex<Vec<Integer>>>,
+    // Instructions left before execution pauses
+    budget: i64,
+    // Instructions left before running out of fuel, as of when the budget was
+    // `fuel_mark`. The budget is capped by the fuel, and `budget_held` is the
+    // part of it over the cap.
+    fuel: Option<i64>,
+    fuel_mark: i64,
+    budget_held: i64,
+    // Bytes print, tostring and string.format keep from a single value
+    output_limit: Option<usize>,
+    float_format: FloatFormat,
+    memory_limit: Option<usize>,
+    // Calls made with call_value and coroutines resumed, which nest on the
+    // Rust stack or grow the heap with a new thread stack each
+    c_call_limit: usize,
+    // Set by mochi.sleep until the runtime can run again
+    #[cfg(feature = "std")]
+    wake_time: Option<Instant>,
+    #[cfg(feature = "std")]
+    timers: Vec<timer::Timer<'gc>>,
+    #[cfg(feature = "std")]
+    prefetched_chunks: HashMap<Vec<u8>, Vec<u8>>,
+    archives: Vec<Archive>,
+    #[cfg(feature = "std")]
+    remote_modules: Option<RemoteModules>,
+    #[cfg(feature = "std")]
+    chunk_cache: Option<ChunkCache>,
+    #[cfg(feature = "std")]
+    compile_options: CompileOptions,
+    stats: Option<stats::StatsRecorder<'gc>>,
+    deterministic: Option<Deterministic>,
+    ordered_keys: determinism::OrderedKeys<'gc>,
+    #[cfg(feature = "std")]
+    dependencies: DependencyGraph,
+    #[cfg(feature = "profiler")]
+    pub(crate) profiler: Option<crate::profiler::Sampler<'gc>>,
+    #[cfg(feature = "debugger")]
+    pub(crate) debugger: Option<crate::debugger::Debugger>,
+    #[cfg(feature = "coverage")]
+    pub(crate) coverage: Option<crate::coverage::Collector<'gc>>,
+    #[cfg(feature = "jit")]
+    jit: jit::Jit,
 }
 
 unsafe impl GarbageCollect for Vm<'_> {
@@ -118,8 +369,19 @@ unsafe impl GarbageCollect for Vm<'_> {
         self.main_thread.trace(tracer);
         self.globals.trace(tracer);
         self.thread_stack.trace(tracer);
+        self.host_calls.trace(tracer);
         self.metamethod_names.trace(tracer);
         self.metatables.trace(tracer);
+        for metatable in self.userdata_metatables.values() {
+            metatable.trace(tracer);
+        }
+        self.error_objects.trace(tracer);
+        #[cfg(feature = "std")]
+        self.timers.trace(tracer);
+        self.stats.trace(tracer);
+        self.ordered_keys.trace(tracer);
+        #[cfg(feature = "coverage")]
+        self.coverage.trace(tracer);
     }
 }
 
@@ -127,14 +389,58 @@ impl<'gc> Vm<'gc> {
     pub(crate) fn new(gc: &'gc GcContext) -> Self {
         let main_thread = gc.allocate_cell(LuaThread::new());
         let globals = gc.allocate_cell(Table::new());
-        let registry = Table::from(vec![main_thread.into(), globals.into()]);
+        let mut registry = Table::from(vec![main_thread.into(), globals.into()]);
+        let error_objects = gc.allocate_cell(Table::new());
+        registry.set_field(
+            gc.allocate_string(b"_ERROROBJECTS".as_slice()),
+            error_objects,
+        );
         Self {
             registry: gc.allocate_cell(registry),
             main_thread,
             globals,
             thread_stack: Default::default(),
+            host_calls: Vec::new(),
             metamethod_names: Metamethod::allocate_names(gc),
             metatables: Default::default(),
+            userdata_metatables: Default::default(),
+            error_objects,
+            next_error_object: 1,
+            released_error_objects: Default::default(),
+            budget: i64::MAX,
+            fuel: None,
+            fuel_mark: i64::MAX,
+            budget_held: 0,
+            output_limit: None,
+            float_format: FloatFormat::default(),
+            memory_limit: None,
+            c_call_limit: DEFAULT_C_CALL_LIMIT,
+            #[cfg(feature = "std")]
+            wake_time: None,
+            #[cfg(feature = "std")]
+            timers: Vec::new(),
+            #[cfg(feature = "std")]
+            prefetched_chunks: Default::default(),
+            archives: Vec::new(),
+            #[cfg(feature = "std")]
+            remote_modules: None,
+            #[cfg(feature = "std")]
+            chunk_cache: None,
+            #[cfg(feature = "std")]
+            compile_options: CompileOptions::default(),
+            stats: None,
+            deterministic: None,
+            ordered_keys: Default::default(),
+            #[cfg(feature = "std")]
+            dependencies: Default::default(),
+            #[cfg(feature = "profiler")]
+            profiler: None,
+            #[cfg(feature = "debugger")]
+            debugger: None,
+            #[cfg(feature = "coverage")]
+            coverage: None,
+            #[cfg(feature = "jit")]
+            jit: Default::default(),
         }
     }
 
@@ -142,6 +448,36 @@ impl<'gc> Vm<'gc> {
         self.registry
     }
 
+    // Makes an error raised with a value. Strings become the message, and
+    // other values are kept until they're taken back with error_value.
+    pub fn error_from_object(&mut self, gc: &'gc GcContext, value: Value<'gc>) -> ErrorKind {
+        if let Value::String(_) = value {
+            return ErrorKind::from_error_object(value);
+        }
+        let released = core::mem::take(&mut *self.released_error_objects.lock().unwrap());
+        let mut error_objects = self.error_objects.borrow_mut(gc);
+        for id in released {
+            error_objects.set_integer_key(id, Value::Nil);
+        }
+        let id = self.next_error_object;
+        self.next_error_object += 1;
+        error_objects.set_integer_key(id, value);
+        ErrorKind::Object(ErrorObject::new(
+            id,
+            value,
+            self.released_error_objects.clone(),
+        ))
+    }
+
+    // The value an error was raised with: the error object for errors raised
+    // with one, and the message otherwise
+    pub fn error_value(&self, gc: &'gc GcContext, kind: &ErrorKind) -> Value<'gc> {
+        match kind {
+            ErrorKind::Object(obj) => self.error_objects.borrow().get_integer_key(obj.id()),
+            _ => gc.allocate_string(kind.to_string().into_bytes()).into(),
+        }
+    }
+
     pub fn main_thread(&self) -> GcCell<'gc, LuaThread<'gc>> {
         self.main_thread
     }
@@ -161,6 +497,26 @@ impl<'gc> Vm<'gc> {
         crate::stdlib::load(gc, self);
     }
 
+    // Adds `name` to package.preload, so that the first require of it calls
+    // `loader` and later ones return the same value
+    pub fn register_module<N, F>(&mut self, gc: &'gc GcContext, name: N, loader: F)
+    where
+        N: AsRef<[u8]>,
+        F: 'static + MaybeSend + Fn(&'gc GcContext, &mut Vm<'gc>) -> Result<Value<'gc>, ErrorKind>,
+    {
+        let preload_key = gc.allocate_string(crate::stdlib::LUA_PRELOAD_TABLE);
+        let preload = self.registry.borrow().get_field(preload_key);
+        let preload = preload.as_table().unwrap_or_else(|| {
+            let preload = gc.allocate_cell(Table::new());
+            self.registry.borrow_mut(gc).set_field(preload_key, preload);
+            preload
+        });
+        let loader = NativeClosure::new(move |gc, vm, _| Ok(Action::Return(vec![loader(gc, vm)?])));
+        preload
+            .borrow_mut(gc)
+            .set_field(gc.allocate_string(name.as_ref()), gc.allocate(loader));
+    }
+
     pub fn load<B, S>(
         &self,
         gc: &'gc GcContext,
@@ -171,25 +527,167 @@ impl<'gc> Vm<'gc> {
         B: AsRef<[u8]>,
         S: AsRef<[u8]>,
     {
-        let proto = crate::load(gc, bytes, source)?;
-        let mut closure = LuaClosure::from(gc.allocate(proto));
-        closure
-            .upvalues
-            .push(gc.allocate_cell(Value::Table(self.globals).into()));
-        Ok(closure)
+        self.load_with_options(gc, bytes, source, &CompileOptions::default())
     }
 
+    pub fn load_with_options<B, S>(
+        &self,
+        gc: &'gc GcContext,
+        bytes: B,
+        source: S,
+        options: &CompileOptions,
+    ) -> Result<LuaClosure<'gc>, Error>
+    where
+        B: AsRef<[u8]>,
+        S: AsRef<[u8]>,
+    {
+        let proto = crate::load_with_options(gc, bytes, source, options)?;
+        Ok(self.load_proto(gc, proto))
+    }
+
+    #[cfg(feature = "std")]
     pub fn load_file<P: AsRef<Path>>(
         &self,
         gc: &'gc GcContext,
         path: P,
     ) -> Result<LuaClosure<'gc>, Error> {
-        let proto = crate::load_file(gc, path)?;
-        let mut closure = LuaClosure::from(gc.allocate(proto));
-        closure
-            .upvalues
-            .push(gc.allocate_cell(Value::Table(self.globals).into()));
-        Ok(closure)
+        self.load_file_with_options(gc, path, &self.compile_options)
+    }
+
+    #[cfg(feature = "std")]
+    pub fn load_file_with_options<P: AsRef<Path>>(
+        &self,
+        gc: &'gc GcContext,
+        path: P,
+        options: &CompileOptions,
+    ) -> Result<LuaClosure<'gc>, Error> {
+        let proto = match &self.chunk_cache {
+            Some(cache) => cache.load_file(gc, path, options)?,
+            None => crate::load_file_with_options(gc, path, options)?,
+        };
+        Ok(self.load_proto(gc, proto))
+    }
+
+    // Makes load_file, and so require, dofile and loadfile, keep compiled
+    // chunks in `cache`. `None` compiles files every time.
+    #[cfg(feature = "std")]
+    pub fn set_chunk_cache(&mut self, cache: Option<ChunkCache>) {
+        self.chunk_cache = cache;
+    }
+
+    #[cfg(feature = "std")]
+    pub fn chunk_cache(&self) -> Option<&ChunkCache> {
+        self.chunk_cache.as_ref()
+    }
+
+    // Options load_file, and so require, dofile and loadfile, compile files
+    // with, modules prefetched for require included
+    #[cfg(feature = "std")]
+    pub fn set_compile_options(&mut self, options: CompileOptions) {
+        self.compile_options = options;
+    }
+
+    #[cfg(feature = "std")]
+    pub fn compile_options(&self) -> &CompileOptions {
+        &self.compile_options
+    }
+
+    pub fn load_proto(&self, gc: &'gc GcContext, proto: LuaClosureProto<'gc>) -> LuaClosure<'gc> {
+        LuaClosure::with_environment(gc, gc.allocate(proto), self.globals.into())
+    }
+
+    // Lets require load modules from `archive`, which is searched after
+    // package.preload and before package.path
+    pub fn add_archive(&mut self, archive: Archive) {
+        self.archives.push(archive);
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn archives(&self) -> &[Archive] {
+        &self.archives
+    }
+
+    // Lets require fetch modules from `remote`, which is searched after all
+    // the other package.searchers. `None` stops fetching.
+    #[cfg(feature = "std")]
+    pub fn set_remote_modules(&mut self, remote: Option<RemoteModules>) {
+        self.remote_modules = remote;
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn remote_modules(&self) -> Option<&RemoteModules> {
+        self.remote_modules.as_ref()
+    }
+
+    // Caps how many bytes of a single value print, tostring and
+    // string.format's %s produce, so a script can't flood the host's output
+    // with a huge string. Longer values are cut and marked as truncated.
+    // There's no limit by default, and `None` removes it.
+    pub fn set_output_limit(&mut self, limit: Option<usize>) {
+        self.output_limit = limit;
+    }
+
+    pub fn output_limit(&self) -> Option<usize> {
+        self.output_limit
+    }
+
+    // Sets how tostring, print, string.format's %s and concatenation write
+    // floats, "%.14g" by default. FloatFormat::Significant(17) and
+    // FloatFormat::Hex give strings that tonumber turns back into the same
+    // float.
+    pub fn set_float_format(&mut self, format: FloatFormat) {
+        self.float_format = format;
+    }
+
+    pub fn float_format(&self) -> FloatFormat {
+        self.float_format
+    }
+
+    // Raises a "not enough memory" error when the heap is over `limit`
+    // bytes after a full collection. The heap is checked as the collector
+    // runs, so it can go over the limit for a while, e.g. by a single large
+    // allocation. Calls made with call_value aren't checked, as they don't
+    // run the collector.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+    }
+
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    // Raises a "C stack overflow" error when calls made with call_value and
+    // coroutines resumed are nested more than `limit` deep, like
+    // LUAI_MAXCCALLS in the reference implementation. Calls between Lua and
+    // native functions don't count, as they don't nest on the Rust stack;
+    // they're limited by the size of the Lua stack instead.
+    pub fn set_c_call_limit(&mut self, limit: usize) {
+        self.c_call_limit = limit;
+    }
+
+    pub fn c_call_limit(&self) -> usize {
+        self.c_call_limit
+    }
+
+    // Turns the compilation of hot functions to native code on or off. It's
+    // on by default. Functions compiled already go back to the interpreter
+    // as well while it's off.
+    #[cfg(feature = "jit")]
+    pub fn set_jit_enabled(&mut self, enabled: bool) {
+        self.jit.disabled = !enabled;
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn jit_enabled(&self) -> bool {
+        !self.jit.disabled
+    }
+
+    pub(crate) fn check_c_calls(&self) -> Result<(), ErrorKind> {
+        if self.thread_stack.len() + self.host_calls.len() >= self.c_call_limit {
+            Err(ErrorKind::CStackOverflow)
+        } else {
+            Ok(())
+        }
     }
 
     pub fn metamethod_name(&self, metamethod: Metamethod) -> LuaString<'gc> {
@@ -224,58 +722,40 @@ impl<'gc> Vm<'gc> {
         self.metatables[ty as usize] = metatable.into();
     }
 
+    // Metatable of the userdata holding a T created with create_userdata
+    pub fn userdata_metatable<T: Any>(&self) -> Option<GcCell<'gc, Table<'gc>>> {
+        self.userdata_metatables.get(&TypeId::of::<T>()).copied()
+    }
+
+    // The metatable is taken as impl Into so that T can be given explicitly
+    pub fn set_userdata_metatable<T: Any>(
+        &mut self,
+        metatable: impl Into<Option<GcCell<'gc, Table<'gc>>>>,
+    ) {
+        match metatable.into() {
+            Some(metatable) => self
+                .userdata_metatables
+                .insert(TypeId::of::<T>(), metatable),
+            None => self.userdata_metatables.remove(&TypeId::of::<T>()),
+        };
+    }
+
+    pub fn create_userdata<T: Any + MaybeSend>(
+        &self,
+        gc: &'gc GcContext,
+        data: T,
+    ) -> GcCell<'gc, UserData<'gc>> {
+        let mut userdata = UserData::new(data);
+        userdata.set_metatable(self.userdata_metatable::<T>());
+        gc.allocate_cell(userdata)
+    }
+
     fn execute_single_step(&mut self, gc: &'gc GcContext) -> Result<RuntimeAction, RuntimeError> {
         while !self.thread_stack.is_empty() {
             match self.execute_next_frame(gc) {
                 Ok(Some(action)) => return Ok(action),
                 Ok(None) => (),
-                Err(kind) => {
-                    let thread = self.current_thread();
-                    let mut thread_ref = thread.borrow_mut(gc);
-
-                    let protection_boundary = thread_ref
-                        .frames
-                        .iter_mut()
-                        .enumerate()
-                        .rev()
-                        .find_map(|(i, frame)| match frame {
-                            Frame::ProtectedCallContinuation {
-                                inner,
-                                callee_bottom,
-                            } => {
-                                inner
-                                    .continuation
-                                    .as_mut()
-                                    .unwrap()
-                                    .set_args(Err(kind.clone()));
-                                Some((i, *callee_bottom))
-                            }
-                            _ => None,
-                        });
-
-                    if let Some((frame_index, boundary)) = protection_boundary {
-                        thread_ref.close_upvalues(gc, boundary);
-                        thread_ref.frames.truncate(frame_index + 1);
-                    } else {
-                        self.thread_stack.pop().unwrap();
-                        thread_ref.status = ThreadStatus::Error(kind.clone());
-
-                        if self.thread_stack.is_empty() {
-                            let traceback = thread_ref.traceback();
-                            *thread_ref = LuaThread::new();
-                            return Err(RuntimeError { kind, traceback });
-                        }
-                        drop(thread_ref);
-
-                        let mut resumer_ref = self.thread_stack.last().unwrap().borrow_mut(gc);
-                        match resumer_ref.frames.as_mut_slice() {
-                            [.., Frame::ResumeContinuation(frame)] => {
-                                frame.continuation.as_mut().unwrap().set_args(Err(kind))
-                            }
-                            _ => unreachable!(),
-                        }
-                    }
-                }
+                Err(kind) => self.unwind(gc, self.locate_error(kind))?,
             }
             if gc.should_perform_gc() {
                 return Ok(RuntimeAction::StepGc);
@@ -289,41 +769,164 @@ impl<'gc> Vm<'gc> {
         })
     }
 
+    // Prefixes the position of the innermost Lua function to the message if
+    // it raised the error or called the native function that did (refer to
+    // "luaG_runerror" in ldebug.c and "luaL_error" in lauxlib.c)
+    fn locate_error(&self, kind: ErrorKind) -> ErrorKind {
+        if !kind.needs_location() {
+            return kind;
+        }
+        let level = match self.current_thread().borrow().frames.last() {
+            Some(Frame::Native { .. }) => 2,
+            _ => 1,
+        };
+        match self.location(level) {
+            Some(location) => ErrorKind::Located {
+                location,
+                kind: kind.into(),
+            },
+            None => kind,
+        }
+    }
+
+    // Passes an error to the innermost protected call, or to the resumer if
+    // the current coroutine has none. Errors the main thread doesn't catch are
+    // returned.
+    fn unwind(&mut self, gc: &'gc GcContext, kind: ErrorKind) -> Result<(), RuntimeError> {
+        let thread = self.current_thread();
+        let mut thread_ref = thread.borrow_mut(gc);
+
+        let protection_boundary =
+            thread_ref
+                .frames
+                .iter_mut()
+                .enumerate()
+                .rev()
+                .find_map(|(i, frame)| match frame {
+                    Frame::ProtectedCallContinuation {
+                        inner,
+                        callee_bottom,
+                    } => {
+                        inner
+                            .continuation
+                            .as_mut()
+                            .unwrap()
+                            .set_args(Err(kind.clone()));
+                        Some((i, *callee_bottom))
+                    }
+                    _ => None,
+                });
+
+        if let Some((frame_index, boundary)) = protection_boundary {
+            thread_ref.close_upvalues(gc, boundary);
+            thread_ref.frames.truncate(frame_index + 1);
+        } else {
+            self.thread_stack.pop().unwrap();
+            thread_ref.status = ThreadStatus::Error(kind.clone());
+
+            if self.thread_stack.is_empty() {
+                let traceback = thread_ref.traceback();
+                *thread_ref = LuaThread::new();
+                return Err(RuntimeError { kind, traceback });
+            }
+            drop(thread_ref);
+
+            let mut resumer_ref = self.thread_stack.last().unwrap().borrow_mut(gc);
+            match resumer_ref.frames.as_mut_slice() {
+                [.., Frame::ResumeContinuation(frame)] => {
+                    frame.continuation.as_mut().unwrap().set_args(Err(kind))
+                }
+                _ => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "catch-panic")]
+    fn recover_from_panic(&mut self, gc: &'gc GcContext, message: String) -> RuntimeError {
+        let (pc, opcode) = match panic::take_last_instruction() {
+            Some((pc, opcode)) => (Some(pc), opcode),
+            None => (None, None),
+        };
+
+        let thread = self.current_thread();
+        let thread_ref = thread.borrow();
+        let source = thread_ref
+            .frames
+            .iter()
+            .rev()
+            .find_map(Frame::as_lua)
+            .and_then(|frame| thread_ref.stack_closure(frame.bottom))
+            .map(|closure| {
+                let source = String::from_utf8_lossy(&closure.proto.source);
+                crate::chunk_id_from_source(&source).into_owned()
+            });
+        let traceback = thread_ref.traceback();
+        drop(thread_ref);
+
+        let kind = ErrorKind::Internal(InternalError {
+            message,
+            opcode,
+            pc,
+            source,
+        });
+
+        // The interrupted threads may be in an inconsistent state,
+        // so none of them can be resumed again.
+        for thread in self.thread_stack.drain(..) {
+            thread.borrow_mut(gc).status = ThreadStatus::Error(kind.clone());
+        }
+        *self.main_thread.borrow_mut(gc) = LuaThread::new();
+
+        RuntimeError { kind, traceback }
+    }
+
     pub(crate) fn push_frame(
         &self,
         thread: &mut LuaThread<'gc>,
         bottom: usize,
     ) -> Result<ControlFlow<()>, ErrorKind> {
-        match thread.stack[bottom] {
-            Value::LuaClosure(_) => {
-                thread.frames.push(Frame::Lua(LuaFrame::new(bottom)));
-                Ok(ControlFlow::Continue(()))
-            }
-            Value::NativeFunction(_) | Value::NativeClosure(_) => {
-                thread.frames.push(Frame::Native { bottom });
-                Ok(ControlFlow::Break(()))
-            }
-            value => match self.metamethod_of_object(Metamethod::Call, value) {
-                Some(metatable) => {
-                    thread.stack.insert(bottom, metatable);
-                    self.push_frame(thread, bottom)
+        check_stack(bottom)?;
+        for _ in 0..2000 {
+            match thread.stack[bottom] {
+                Value::LuaClosure(_) => {
+                    thread.frames.push(Frame::Lua(LuaFrame::new(bottom)));
+                    return Ok(ControlFlow::Continue(()));
                 }
-                None => {
-                    if let Some(DebugNameInfo { kind, name }) =
-                        self.funcname_from_call(thread, bottom)
-                    {
-                        Err(ErrorKind::other(format!(
-                            "attempt to call a nil value ({kind} {name:?})"
-                        )))
-                    } else {
-                        Err(ErrorKind::TypeError {
+                Value::NativeFunction(_) | Value::NativeClosure(_) => {
+                    thread.frames.push(Frame::Native { bottom });
+                    return Ok(ControlFlow::Break(()));
+                }
+                value => match self.metamethod_of_object(Metamethod::Call, value) {
+                    Some(metatable) => thread.stack.insert(bottom, metatable),
+                    None => {
+                        return Err(ErrorKind::TypeError {
                             operation: Operation::Call,
                             ty: value.ty(),
+                            description: self
+                                .funcname_from_call(thread, bottom)
+                                .map(|DebugNameInfo { kind, name }| format!("{kind} '{name}'")),
                         })
                     }
-                }
-            },
+                },
+            }
         }
+        Err(ErrorKind::other("'__call' chain too long; possible loop"))
+    }
+}
+
+// Lua stack slots a thread can use, like LUAI_MAXSTACK in the reference
+// implementation
+const MAX_STACK_SIZE: usize = 1_000_000;
+
+const DEFAULT_C_CALL_LIMIT: usize = 200;
+
+// Checks if a function can be called at `bottom` of the stack
+fn check_stack(bottom: usize) -> Result<(), ErrorKind> {
+    if bottom >= MAX_STACK_SIZE {
+        Err(ErrorKind::StackOverflow)
+    } else {
+        Ok(())
     }
 }
 
@@ -342,7 +945,7 @@ impl<'gc> LuaThread<'gc> {
             .find_map(|f| f.as_lua().filter(|l| l.bottom <= bottom))
     }
 
-    fn stack_closure(&self, n: usize) -> Option<&'_ LuaClosure<'gc>> {
+    pub(crate) fn stack_closure(&self, n: usize) -> Option<&'_ LuaClosure<'gc>> {
         self.stack.get(n).and_then(|v| v.as_lua_closure())
     }
 }
@@ -356,16 +959,20 @@ impl<'gc> Upvalue<'gc> {
         stack: &[Value<'gc>],
     ) -> Value<'gc> {
         match self {
+            // Precompiled chunks can capture registers above a call, which
+            // are gone while the call runs. They read as nil, and writes to
+            // them are dropped.
             Upvalue::Open { thread, index } => {
-                if GcCell::ptr_eq(thread, &current_thread) {
+                let value = if GcCell::ptr_eq(thread, &current_thread) {
                     if *index < base {
-                        lower_stack[*index]
+                        lower_stack.get(*index).copied()
                     } else {
-                        stack[*index - base]
+                        stack.get(*index - base).copied()
                     }
                 } else {
-                    thread.borrow().stack[*index]
-                }
+                    thread.borrow().stack.get(*index).copied()
+                };
+                value.unwrap_or_default()
             }
             Upvalue::Closed(value) => *value,
         }
@@ -382,14 +989,19 @@ impl<'gc> Upvalue<'gc> {
     ) {
         match self {
             Upvalue::Open { thread, index } => {
-                if GcCell::ptr_eq(thread, &current_thread) {
+                let mut thread_ref;
+                let slot = if GcCell::ptr_eq(thread, &current_thread) {
                     if *index < base {
-                        lower_stack[*index] = value;
+                        lower_stack.get_mut(*index)
                     } else {
-                        stack[*index - base] = value;
+                        stack.get_mut(*index - base)
                     }
                 } else {
-                    thread.borrow_mut(gc).stack[*index] = value;
+                    thread_ref = thread.borrow_mut(gc);
+                    thread_ref.stack.get_mut(*index)
+                };
+                if let Some(slot) = slot {
+                    *slot = value;
                 }
             }
             Upvalue::Closed(v) => *v = value,
@@ -1,6 +1,7 @@
 use super::{frame::ContinuationFrame, ErrorKind, Frame, RuntimeAction, Vm};
 use crate::{
-    gc::{GarbageCollect, GcCell, GcContext, GcHeap, Tracer},
+    gc::{GarbageCollect, GcCell, GcContext, GcHeap, MaybeSend, Tracer},
+    prelude::*,
     types::{LuaThread, ThreadStatus, Value},
 };
 
@@ -31,6 +32,12 @@ pub enum Action<'gc> {
         mutator: Box<dyn Fn(&mut GcHeap)>,
         continuation: Continuation<'gc, ()>,
     },
+    // Suspends the runtime until `future` is ready
+    #[cfg(feature = "async")]
+    Await {
+        future: super::LuaFuture,
+        continuation: Continuation<'gc, Result<Vec<Value<'gc>>, ErrorKind>>,
+    },
 }
 
 trait ContinuationFn<'gc, T>: GarbageCollect {
@@ -50,7 +57,9 @@ unsafe impl<R> GarbageCollect for Continuation<'_, R> {
 impl<'gc, T: 'gc> Continuation<'gc, T> {
     pub fn new<F>(f: F) -> Self
     where
-        F: 'static + Fn(&'gc GcContext, &mut Vm<'gc>, T) -> Result<Action<'gc>, ErrorKind>,
+        F: 'static
+            + MaybeSend
+            + Fn(&'gc GcContext, &mut Vm<'gc>, T) -> Result<Action<'gc>, ErrorKind>,
     {
         struct SimpleContinuation<R, F> {
             args: Option<R>,
@@ -93,7 +102,9 @@ impl<'gc, T: 'gc> Continuation<'gc, T> {
     pub fn with_context<C, F>(context: C, f: F) -> Self
     where
         C: 'gc + GarbageCollect,
-        F: 'static + Fn(&'gc GcContext, &mut Vm<'gc>, C, T) -> Result<Action<'gc>, ErrorKind>,
+        F: 'static
+            + MaybeSend
+            + Fn(&'gc GcContext, &mut Vm<'gc>, C, T) -> Result<Action<'gc>, ErrorKind>,
     {
         struct ContextContinuation<C, R, F> {
             context: Option<C>,
@@ -191,7 +202,7 @@ impl<'gc> Vm<'gc> {
                 };
                 thread_ref.stack.push(callee);
                 thread_ref.stack.append(&mut args);
-                self.push_frame(&mut thread_ref, bottom)?;
+                let _ = self.push_frame(&mut thread_ref, bottom)?;
             }
             Action::ProtectedCall {
                 callee,
@@ -208,14 +219,14 @@ impl<'gc> Vm<'gc> {
                 };
                 thread_ref.stack.push(callee);
                 thread_ref.stack.append(&mut args);
-                self.push_frame(&mut thread_ref, bottom)?;
+                let _ = self.push_frame(&mut thread_ref, bottom)?;
             }
             Action::TailCall { callee, mut args } => {
                 thread_ref.frames.pop().unwrap();
                 thread_ref.stack.truncate(bottom);
                 thread_ref.stack.push(callee);
                 thread_ref.stack.append(&mut args);
-                self.push_frame(&mut thread_ref, bottom)?;
+                let _ = self.push_frame(&mut thread_ref, bottom)?;
             }
             Action::Return(mut results) => {
                 thread_ref.frames.pop().unwrap();
@@ -247,7 +258,7 @@ impl<'gc> Vm<'gc> {
                     ThreadStatus::Unresumable => {
                         Err(ErrorKind::other("cannot resume non-suspended coroutine"))
                     }
-                    ThreadStatus::Resumable => Ok(()),
+                    ThreadStatus::Resumable => self.check_c_calls(),
                 };
                 match resume_result {
                     Ok(()) => (),
@@ -277,6 +288,11 @@ impl<'gc> Vm<'gc> {
                     }
                     _ => (),
                 }
+                if !self.can_yield() {
+                    return Err(ErrorKind::other(
+                        "attempt to yield across a C-call boundary",
+                    ));
+                }
 
                 let resumer = self.thread_stack.pop().unwrap();
                 debug_assert!(GcCell::ptr_eq(&resumer, &thread));
@@ -306,6 +322,26 @@ impl<'gc> Vm<'gc> {
                     });
                 return Ok(Some(RuntimeAction::MutateGc(mutator)));
             }
+            #[cfg(feature = "async")]
+            Action::Await {
+                future,
+                continuation,
+            } => {
+                // call_value runs the callee to completion, so the runtime
+                // can't be suspended under it
+                if !self.can_yield() {
+                    return Err(ErrorKind::other(
+                        "attempt to await across a C-call boundary",
+                    ));
+                }
+                thread_ref.stack.truncate(bottom);
+                *thread_ref.frames.last_mut().unwrap() =
+                    Frame::AwaitContinuation(ContinuationFrame {
+                        bottom,
+                        continuation: Some(continuation),
+                    });
+                return Ok(Some(RuntimeAction::Await(future)));
+            }
         }
 
         Ok(None)
@@ -1,16 +1,24 @@
-use super::{opcode, ops, ErrorKind, Frame, LuaFrame, Metamethod, Operation, Vm};
+use super::{
+    opcode, ops, Action, ErrorKind, Frame, Instruction, LuaFrame, Metamethod, OpCode, Operation,
+    RuntimeAction, Vm,
+};
 use crate::{
     gc::GcContext,
-    types::{Integer, Number, Table, Upvalue, UpvalueDescription, Value},
+    prelude::*,
+    types::{Integer, LuaString, Number, Table, UpvalueDescription, Value},
     LuaClosure,
 };
-use std::{
+use core::{
+    cell::Cell,
     cmp::PartialOrd,
     ops::{Add, BitAnd, BitOr, BitXor, ControlFlow, Div, Mul, Sub},
 };
 
 impl<'gc> Vm<'gc> {
-    pub(super) fn execute_lua_frame(&self, gc: &'gc GcContext) -> Result<(), ErrorKind> {
+    pub(super) fn execute_lua_frame(
+        &mut self,
+        gc: &'gc GcContext,
+    ) -> Result<Option<RuntimeAction>, ErrorKind> {
         let thread = self.current_thread();
         let mut thread_ref = thread.borrow_mut(gc);
 
@@ -31,27 +39,88 @@ impl<'gc> Vm<'gc> {
             let upvalues = closure.upvalues.as_slice();
             let proto = closure.proto.as_ref();
             let code = proto.code.as_ref();
-            let constants = proto.constants.as_ref();
+            let constants = &proto.constants;
+
+            if self.stats.is_some() {
+                self.record_frame_entry(closure.proto, pc == 0);
+            }
 
-            let saved_stack_top = thread_ref.stack.len();
+            let mut saved_stack_top = thread_ref.stack.len();
             let new_stack_len = base + proto.max_stack_size as usize;
             if saved_stack_top < new_stack_len {
                 thread_ref.stack.resize(new_stack_len, Value::Nil);
             }
 
-            let (lower_stack, stack) = thread_ref.stack.split_at_mut(base);
+            #[cfg(feature = "debugger")]
+            let depth = (self.thread_stack.len(), thread_ref.frames.len());
+
+            let (mut lower_stack, mut stack) = thread_ref.stack.split_at_mut(base);
+
+            #[cfg(feature = "jit")]
+            {
+                pc = self.enter_jit(proto, stack, pc);
+            }
 
             while let Some(&insn) = code.get(pc) {
+                // samples are taken where execution could pause, as the frame
+                // is restarted afterwards
+                #[cfg(feature = "profiler")]
+                if pc > 0
+                    && can_pause_before(insn)
+                    && self.profiler.as_ref().is_some_and(|p| p.should_sample())
+                {
+                    thread_ref.save_pc(pc);
+                    self.record_sample(&thread_ref);
+                    continue 'start;
+                }
+
+                #[cfg(feature = "debugger")]
+                if self.debugger.is_some()
+                    && can_pause_before(insn)
+                    && self.check_debug_stop(thread, depth, bottom, proto, pc)
+                {
+                    if pc == 0 && self.stats.is_some() {
+                        self.record_pause_before_call();
+                    }
+                    thread_ref.save_pc(pc);
+                    return Ok(None);
+                }
+
+                self.budget -= 1;
+                if self.budget < 0 && can_pause_before(insn) {
+                    self.budget += 1;
+                    if self.is_out_of_fuel() {
+                        thread_ref.save_pc(pc + 1);
+                        return Err(ErrorKind::FuelExhausted);
+                    }
+                    if pc == 0 && self.stats.is_some() {
+                        self.record_pause_before_call();
+                    }
+                    thread_ref.save_pc(pc);
+                    return Ok(Some(RuntimeAction::Pause));
+                }
+
+                #[cfg(feature = "coverage")]
+                if self.coverage.is_some() {
+                    self.record_coverage(thread, bottom, closure.proto, pc);
+                }
+
+                #[cfg(feature = "trace")]
+                super::trace::trace_instruction(pc, insn, stack);
+
+                #[cfg(feature = "catch-panic")]
+                super::panic::record_instruction(pc, insn);
+
                 pc += 1;
 
                 match insn.raw_opcode() {
                     opcode::MOVE => stack[insn.a()] = stack[insn.b()],
                     opcode::LOADI => stack[insn.a()] = Value::Integer(insn.sbx() as Integer),
                     opcode::LOADF => stack[insn.a()] = Value::Number(insn.sbx() as Number),
-                    opcode::LOADK => stack[insn.a()] = constants[insn.bx()],
+                    opcode::LOADK => stack[insn.a()] = constants.value(insn.bx()),
                     opcode::LOADKX => {
                         let next_insn = code[pc];
-                        let rb = constants[next_insn.ax()];
+                        let rb = constants.value(next_insn.ax());
                         stack[insn.a()] = rb;
                         pc += 1;
                     }
@@ -85,13 +154,13 @@ impl<'gc> Vm<'gc> {
                             upvalues[insn.b()]
                                 .borrow()
                                 .get(thread, base, lower_stack, stack);
-                        let rc = match constants[insn.c() as usize] {
+                        let rc = match constants.value(insn.c() as usize) {
                             Value::String(s) => s,
                             _ => unreachable!(),
                         };
-                        let value = table.borrow_as_table().map(|table| table.get_field(rc));
-                        match value {
-                            Some(Value::Nil) | None => {
+                        let hint = proto.inline_caches.hint(pc - 1);
+                        match self.get_field_cached(table, rc, hint) {
+                            None => {
                                 thread_ref.save_pc(pc);
                                 match self.index_slow_path(
                                     &mut thread_ref,
@@ -100,7 +169,7 @@ impl<'gc> Vm<'gc> {
                                     base + insn.a(),
                                 )? {
                                     ControlFlow::Continue(()) => continue 'start,
-                                    ControlFlow::Break(()) => return Ok(()),
+                                    ControlFlow::Break(()) => return Ok(None),
                                 }
                             }
                             Some(v) => stack[insn.a()] = v,
@@ -120,7 +189,7 @@ impl<'gc> Vm<'gc> {
                                     base + insn.a(),
                                 )? {
                                     ControlFlow::Continue(()) => continue 'start,
-                                    ControlFlow::Break(()) => return Ok(()),
+                                    ControlFlow::Break(()) => return Ok(None),
                                 }
                             }
                             Some(v) => stack[insn.a()] = v,
@@ -140,7 +209,7 @@ impl<'gc> Vm<'gc> {
                                     base + insn.a(),
                                 )? {
                                     ControlFlow::Continue(()) => continue 'start,
-                                    ControlFlow::Break(()) => return Ok(()),
+                                    ControlFlow::Break(()) => return Ok(None),
                                 }
                             }
                             Some(v) => stack[insn.a()] = v,
@@ -148,13 +217,13 @@ impl<'gc> Vm<'gc> {
                     }
                     opcode::GETFIELD => {
                         let rb = stack[insn.b()];
-                        let rc = match constants[insn.c() as usize] {
+                        let rc = match constants.value(insn.c() as usize) {
                             Value::String(s) => s,
                             _ => unreachable!(),
                         };
-                        let value = rb.borrow_as_table().map(|table| table.get_field(rc));
-                        match value {
-                            Some(Value::Nil) | None => {
+                        let hint = proto.inline_caches.hint(pc - 1);
+                        match self.get_field_cached(rb, rc, hint) {
+                            None => {
                                 thread_ref.save_pc(pc);
                                 match self.index_slow_path(
                                     &mut thread_ref,
@@ -163,14 +232,14 @@ impl<'gc> Vm<'gc> {
                                     base + insn.a(),
                                 )? {
                                     ControlFlow::Continue(()) => continue 'start,
-                                    ControlFlow::Break(()) => return Ok(()),
+                                    ControlFlow::Break(()) => return Ok(None),
                                 }
                             }
                             Some(v) => stack[insn.a()] = v,
                         }
                     }
                     opcode::SETTABUP => {
-                        let kb = match constants[insn.b()] {
+                        let kb = match constants.value(insn.b()) {
                             Value::String(s) => s,
                             _ => unreachable!(),
                         };
@@ -179,16 +248,21 @@ impl<'gc> Vm<'gc> {
                                 .borrow()
                                 .get(thread, base, lower_stack, stack);
                         let c = insn.c() as usize;
-                        let rkc = if insn.k() { constants[c] } else { stack[c] };
+                        let rkc = if insn.k() {
+                            constants.value(c)
+                        } else {
+                            stack[c]
+                        };
+                        let hint = proto.inline_caches.hint(pc - 1);
                         let replaced = table
                             .borrow_as_table_mut(gc)
-                            .map(|mut table| table.replace_field(kb, rkc))
+                            .map(|mut table| table.replace_field_hinted(kb, rkc, hint))
                             .unwrap_or_default();
                         if !replaced {
                             thread_ref.save_pc(pc);
                             match self.new_index_slow_path(gc, &mut thread_ref, table, kb, rkc)? {
                                 ControlFlow::Continue(()) => continue 'start,
-                                ControlFlow::Break(()) => return Ok(()),
+                                ControlFlow::Break(()) => return Ok(None),
                             }
                         }
                     }
@@ -196,7 +270,11 @@ impl<'gc> Vm<'gc> {
                         let ra = stack[insn.a()];
                         let rb = stack[insn.b()];
                         let c = insn.c() as usize;
-                        let rkc = if insn.k() { constants[c] } else { stack[c] };
+                        let rkc = if insn.k() {
+                            constants.value(c)
+                        } else {
+                            stack[c]
+                        };
                         let replaced = ra
                             .borrow_as_table_mut(gc)
                             .map(|mut table| table.replace(rb, rkc))
@@ -206,7 +284,7 @@ impl<'gc> Vm<'gc> {
                             thread_ref.save_pc(pc);
                             match self.new_index_slow_path(gc, &mut thread_ref, ra, rb, rkc)? {
                                 ControlFlow::Continue(()) => continue 'start,
-                                ControlFlow::Break(()) => return Ok(()),
+                                ControlFlow::Break(()) => return Ok(None),
                             }
                         }
                     }
@@ -214,7 +292,11 @@ impl<'gc> Vm<'gc> {
                         let ra = stack[insn.a()];
                         let b = insn.b() as Integer;
                         let c = insn.c() as usize;
-                        let rkc = if insn.k() { constants[c] } else { stack[c] };
+                        let rkc = if insn.k() {
+                            constants.value(c)
+                        } else {
+                            stack[c]
+                        };
                         let replaced = ra
                             .borrow_as_table_mut(gc)
                             .map(|mut table| table.replace_integer_key(b, rkc))
@@ -223,46 +305,58 @@ impl<'gc> Vm<'gc> {
                             thread_ref.save_pc(pc);
                             match self.new_index_slow_path(gc, &mut thread_ref, ra, b, rkc)? {
                                 ControlFlow::Continue(()) => continue 'start,
-                                ControlFlow::Break(()) => return Ok(()),
+                                ControlFlow::Break(()) => return Ok(None),
                             }
                         }
                     }
                     opcode::SETFIELD => {
                         let ra = stack[insn.a()];
-                        let kb = match constants[insn.b()] {
+                        let kb = match constants.value(insn.b()) {
                             Value::String(s) => s,
                             _ => unreachable!(),
                         };
                         let c = insn.c() as usize;
-                        let rkc = if insn.k() { constants[c] } else { stack[c] };
+                        let rkc = if insn.k() {
+                            constants.value(c)
+                        } else {
+                            stack[c]
+                        };
+                        let hint = proto.inline_caches.hint(pc - 1);
                         let replaced = ra
                             .borrow_as_table_mut(gc)
-                            .map(|mut table| table.replace_field(kb, rkc))
+                            .map(|mut table| table.replace_field_hinted(kb, rkc, hint))
                             .unwrap_or_default();
                         if !replaced {
                             thread_ref.save_pc(pc);
                             match self.new_index_slow_path(gc, &mut thread_ref, ra, kb, rkc)? {
                                 ControlFlow::Continue(()) => continue 'start,
-                                ControlFlow::Break(()) => return Ok(()),
+                                ControlFlow::Break(()) => return Ok(None),
                             }
                         }
                     }
                     opcode::NEWTABLE => {
-                        let mut b = insn.b();
-                        if b > 0 {
-                            b = 1 << (b - 1);
-                        }
-                        let mut c = insn.c() as usize;
+                        // B encodes log2(hash size) + 1, and C (extended by
+                        // EXTRAARG when k is set) the array size. They're
+                        // only hints, which precompiled chunks can make huge.
+                        const MAX_PREALLOCATION: usize = 1 << 16;
+                        let nrec = match insn.b() {
+                            0 => 0,
+                            b => 1usize.checked_shl(b as u32 - 1).unwrap_or(usize::MAX),
+                        };
+                        let mut narr = insn.c() as usize;
                         if insn.k() {
                             let next_insn = code[pc];
-                            c += next_insn.ax() * (u8::MAX as usize + 1);
+                            narr += next_insn.ax() * (u8::MAX as usize + 1);
                         }
-                        let table = Table::with_size(c, b);
+                        let table = Table::with_capacity(
+                            narr.min(MAX_PREALLOCATION),
+                            nrec.min(MAX_PREALLOCATION),
+                        );
                         stack[insn.a()] = gc.allocate_cell(table).into();
                         pc += 1;
                         if gc.should_perform_gc() {
                             thread_ref.save_pc(pc);
-                            return Ok(());
+                            return Ok(None);
                         }
                     }
                     opcode::SELF => {
@@ -270,18 +364,28 @@ impl<'gc> Vm<'gc> {
                         let rb = stack[insn.b()];
                         stack[a + 1] = rb;
                         let c = insn.c() as usize;
-                        let rkc = if insn.k() { constants[c] } else { stack[c] };
-                        let rkc = match rkc {
-                            Value::String(s) => s,
-                            _ => unreachable!(),
+                        let rkc = if insn.k() {
+                            constants.value(c)
+                        } else {
+                            stack[c]
+                        };
+                        let value = match rkc {
+                            Value::String(key) => {
+                                let hint = proto.inline_caches.hint(pc - 1);
+                                self.get_field_cached(rb, key, hint)
+                            }
+                            // Only precompiled chunks can have other keys
+                            key => rb
+                                .borrow_as_table()
+                                .map(|table| table.get(key))
+                                .filter(|value| !value.is_nil()),
                         };
-                        let value = rb.borrow_as_table().map(|table| table.get_field(rkc));
                         match value {
-                            Some(Value::Nil) | None => {
+                            None => {
                                 thread_ref.save_pc(pc);
                                 match self.index_slow_path(&mut thread_ref, rb, rkc, base + a)? {
                                     ControlFlow::Continue(()) => continue 'start,
-                                    ControlFlow::Break(()) => return Ok(()),
+                                    ControlFlow::Break(()) => return Ok(None),
                                 }
                             }
                             Some(v) => stack[a] = v,
@@ -422,7 +526,7 @@ impl<'gc> Vm<'gc> {
                             dest,
                         )? {
                             ControlFlow::Continue(()) => continue 'start,
-                            ControlFlow::Break(()) => return Ok(()),
+                            ControlFlow::Break(()) => return Ok(None),
                         }
                     }
                     opcode::MMBINI => {
@@ -436,12 +540,12 @@ impl<'gc> Vm<'gc> {
                         thread_ref.save_pc(pc);
                         match self.arithmetic_slow_path(&mut thread_ref, metamethod, a, b, dest)? {
                             ControlFlow::Continue(()) => continue 'start,
-                            ControlFlow::Break(()) => return Ok(()),
+                            ControlFlow::Break(()) => return Ok(None),
                         }
                     }
                     opcode::MMBINK => {
                         let ra = stack[insn.a()];
-                        let imm = constants[insn.b()];
+                        let imm = constants.value(insn.b());
                         let metamethod = Metamethod::from(insn.c());
                         let prev_insn = code[pc - 2];
                         let dest = base + prev_insn.a();
@@ -450,7 +554,7 @@ impl<'gc> Vm<'gc> {
                         thread_ref.save_pc(pc);
                         match self.arithmetic_slow_path(&mut thread_ref, metamethod, a, b, dest)? {
                             ControlFlow::Continue(()) => continue 'start,
-                            ControlFlow::Break(()) => return Ok(()),
+                            ControlFlow::Break(()) => return Ok(None),
                         }
                     }
                     opcode::UNM => {
@@ -470,7 +574,7 @@ impl<'gc> Vm<'gc> {
                                 base + a,
                             )? {
                                 ControlFlow::Continue(()) => continue 'start,
-                                ControlFlow::Break(()) => return Ok(()),
+                                ControlFlow::Break(()) => return Ok(None),
                             }
                         };
                         stack[a] = result;
@@ -490,14 +594,14 @@ impl<'gc> Vm<'gc> {
                                 base + a,
                             )? {
                                 ControlFlow::Continue(()) => continue 'start,
-                                ControlFlow::Break(()) => return Ok(()),
+                                ControlFlow::Break(()) => return Ok(None),
                             }
                         };
                         stack[a] = result;
                     }
                     opcode::NOT => {
                         let rb = stack[insn.b()];
-                        stack[insn.a()] = Value::Boolean(!rb.to_boolean())
+                        stack[insn.a()] = Value::Boolean(!rb.is_truthy())
                     }
                     opcode::LEN => {
                         let a = insn.a();
@@ -509,7 +613,7 @@ impl<'gc> Vm<'gc> {
                                     thread_ref.save_pc(pc);
                                     match self.len_slow_path(&mut thread_ref, rb, base + a)? {
                                         ControlFlow::Continue(()) => continue 'start,
-                                        ControlFlow::Break(()) => return Ok(()),
+                                        ControlFlow::Break(()) => return Ok(None),
                                     }
                                 } else {
                                     table.borrow().lua_len()
@@ -519,7 +623,7 @@ impl<'gc> Vm<'gc> {
                                 thread_ref.save_pc(pc);
                                 match self.len_slow_path(&mut thread_ref, rb, base + a)? {
                                     ControlFlow::Continue(()) => continue 'start,
-                                    ControlFlow::Break(()) => return Ok(()),
+                                    ControlFlow::Break(()) => return Ok(None),
                                 }
                             }
                         };
@@ -530,8 +634,9 @@ impl<'gc> Vm<'gc> {
                         if b >= 1 {
                             let a = insn.a();
                             let mut strings = Vec::with_capacity(b);
+                            let float_format = self.float_format;
                             for (i, value) in stack[a..].iter().take(b).enumerate().rev() {
-                                if let Some(string) = value.to_string() {
+                                if let Some(string) = value.coerce_to_string_with(float_format) {
                                     strings.push(string);
                                     continue;
                                 }
@@ -551,14 +656,14 @@ impl<'gc> Vm<'gc> {
                                     base + a,
                                 )? {
                                     ControlFlow::Continue(()) => continue 'start,
-                                    ControlFlow::Break(()) => return Ok(()),
+                                    ControlFlow::Break(()) => return Ok(None),
                                 }
                             }
                             strings.reverse();
                             stack[a] = gc.allocate_string(strings.concat()).into();
                             if gc.should_perform_gc() {
                                 thread_ref.save_pc(pc);
-                                return Ok(());
+                                return Ok(None);
                             }
                         }
                     }
@@ -568,7 +673,13 @@ impl<'gc> Vm<'gc> {
                         continue 'start;
                     }
                     opcode::TBC => todo!("TBC"),
-                    opcode::JMP => pc = (pc as isize + insn.sj() as isize) as usize,
+                    opcode::JMP => {
+                        pc = (pc as isize + insn.sj() as isize) as usize;
+                        #[cfg(feature = "jit")]
+                        if insn.sj() < 0 {
+                            pc = self.enter_jit(proto, stack, pc);
+                        }
+                    }
                     opcode::EQ => {
                         let ra = stack[insn.a()];
                         let rb = stack[insn.b()];
@@ -587,7 +698,7 @@ impl<'gc> Vm<'gc> {
                                 code,
                             )? {
                                 ControlFlow::Continue(()) => continue 'start,
-                                ControlFlow::Break(()) => return Ok(()),
+                                ControlFlow::Break(()) => return Ok(None),
                             }
                         } else {
                             ops::do_conditional_jump(&mut pc, code, insn, false);
@@ -609,7 +720,7 @@ impl<'gc> Vm<'gc> {
                                     code,
                                 )? {
                                     ControlFlow::Continue(()) => continue 'start,
-                                    ControlFlow::Break(()) => return Ok(()),
+                                    ControlFlow::Break(()) => return Ok(None),
                                 }
                             }
                         }
@@ -630,14 +741,14 @@ impl<'gc> Vm<'gc> {
                                     code,
                                 )? {
                                     ControlFlow::Continue(()) => continue 'start,
-                                    ControlFlow::Break(()) => return Ok(()),
+                                    ControlFlow::Break(()) => return Ok(None),
                                 }
                             }
                         }
                     }
                     opcode::EQK => {
                         let ra = stack[insn.a()];
-                        let rb = constants[insn.b()];
+                        let rb = constants.value(insn.b());
                         let cond = ra == rb;
                         ops::do_conditional_jump(&mut pc, code, insn, cond)
                     }
@@ -672,7 +783,7 @@ impl<'gc> Vm<'gc> {
                                     code,
                                 )? {
                                     ControlFlow::Continue(()) => continue 'start,
-                                    ControlFlow::Break(()) => return Ok(()),
+                                    ControlFlow::Break(()) => return Ok(None),
                                 }
                             }
                         }
@@ -698,7 +809,7 @@ impl<'gc> Vm<'gc> {
                                     code,
                                 )? {
                                     ControlFlow::Continue(()) => continue 'start,
-                                    ControlFlow::Break(()) => return Ok(()),
+                                    ControlFlow::Break(()) => return Ok(None),
                                 }
                             }
                         }
@@ -724,7 +835,7 @@ impl<'gc> Vm<'gc> {
                                     code,
                                 )? {
                                     ControlFlow::Continue(()) => continue 'start,
-                                    ControlFlow::Break(()) => return Ok(()),
+                                    ControlFlow::Break(()) => return Ok(None),
                                 }
                             }
                         }
@@ -750,18 +861,18 @@ impl<'gc> Vm<'gc> {
                                     code,
                                 )? {
                                     ControlFlow::Continue(()) => continue 'start,
-                                    ControlFlow::Break(()) => return Ok(()),
+                                    ControlFlow::Break(()) => return Ok(None),
                                 }
                             }
                         }
                     }
                     opcode::TEST => {
-                        let cond = stack[insn.a()].to_boolean();
+                        let cond = stack[insn.a()].is_truthy();
                         ops::do_conditional_jump(&mut pc, code, insn, cond);
                     }
                     opcode::TESTSET => {
                         let rb = stack[insn.b()];
-                        let cond = rb.to_boolean();
+                        let cond = rb.is_truthy();
                         if cond == insn.k() {
                             stack[insn.a()] = rb;
                             let next_insn = code[pc];
@@ -777,12 +888,52 @@ impl<'gc> Vm<'gc> {
                         thread_ref.save_pc(pc);
                         thread_ref.stack.truncate(if b > 0 {
                             base + a + b
-                        } else {
+                        } else if saved_stack_top > base + a {
                             saved_stack_top
+                        } else {
+                            return Err(open_top_error());
                         });
-                        match self.push_frame(&mut thread_ref, base + a)? {
-                            ControlFlow::Continue(()) => continue 'start,
-                            ControlFlow::Break(()) => return Ok(()),
+
+                        // Native functions are called in place, so that a plain
+                        // return resumes this frame without going back through
+                        // execute_next_frame
+                        let callee_bottom = base + a;
+                        let callee = thread_ref.stack[callee_bottom];
+                        if !matches!(callee, Value::NativeFunction(_) | Value::NativeClosure(_)) {
+                            match self.push_frame(&mut thread_ref, callee_bottom)? {
+                                ControlFlow::Continue(()) => continue 'start,
+                                ControlFlow::Break(()) => return Ok(None),
+                            }
+                        }
+
+                        let args = thread_ref.stack.split_off(callee_bottom);
+                        drop(thread_ref);
+                        let result = match &callee {
+                            Value::NativeFunction(func) => (func.0)(gc, self, args),
+                            Value::NativeClosure(closure) => closure.call(gc, self, args),
+                            _ => unreachable!(),
+                        };
+                        thread_ref = thread.borrow_mut(gc);
+                        match result {
+                            Ok(Action::Return(mut results)) => {
+                                thread_ref.stack.truncate(callee_bottom);
+                                thread_ref.stack.append(&mut results);
+                                if gc.should_perform_gc() {
+                                    return Ok(None);
+                                }
+                                saved_stack_top = thread_ref.stack.len();
+                                if saved_stack_top < new_stack_len {
+                                    thread_ref.stack.resize(new_stack_len, Value::Nil);
+                                }
+                                (lower_stack, stack) = thread_ref.stack.split_at_mut(base);
+                            }
+                            result => {
+                                thread_ref.frames.push(Frame::Native {
+                                    bottom: callee_bottom,
+                                });
+                                drop(thread_ref);
+                                return self.handle_action(gc, result?, callee_bottom);
+                            }
                         }
                     }
                     opcode::TAILCALL => {
@@ -794,7 +945,9 @@ impl<'gc> Vm<'gc> {
                         let num_results = if b > 0 {
                             b - 1
                         } else {
-                            saved_stack_top - a - base - 1
+                            saved_stack_top
+                                .checked_sub(a + base + 1)
+                                .ok_or_else(open_top_error)?
                         };
                         thread_ref
                             .stack
@@ -806,7 +959,7 @@ impl<'gc> Vm<'gc> {
                         thread_ref.frames.pop().unwrap();
                         match self.push_frame(&mut thread_ref, bottom)? {
                             ControlFlow::Continue(()) => continue 'start,
-                            ControlFlow::Break(()) => return Ok(()),
+                            ControlFlow::Break(()) => return Ok(None),
                         }
                     }
                     opcode::RETURN => {
@@ -818,7 +971,9 @@ impl<'gc> Vm<'gc> {
                         let num_results = if b > 0 {
                             b - 1
                         } else {
-                            saved_stack_top - a - base
+                            saved_stack_top
+                                .checked_sub(a + base)
+                                .ok_or_else(open_top_error)?
                         };
                         thread_ref
                             .stack
@@ -827,7 +982,7 @@ impl<'gc> Vm<'gc> {
                         thread_ref.frames.pop().unwrap();
                         match thread_ref.frames.as_slice() {
                             [.., Frame::Lua(_)] => continue 'start,
-                            _ => return Ok(()),
+                            _ => return Ok(None),
                         }
                     }
                     opcode::RETURN0 => {
@@ -835,7 +990,7 @@ impl<'gc> Vm<'gc> {
                         thread_ref.frames.pop().unwrap();
                         match thread_ref.frames.as_slice() {
                             [.., Frame::Lua(_)] => continue 'start,
-                            _ => return Ok(()),
+                            _ => return Ok(None),
                         }
                     }
                     opcode::RETURN1 => {
@@ -844,33 +999,25 @@ impl<'gc> Vm<'gc> {
                         thread_ref.frames.pop().unwrap();
                         match thread_ref.frames.as_slice() {
                             [.., Frame::Lua(_)] => continue 'start,
-                            _ => return Ok(()),
+                            _ => return Ok(None),
                         }
                     }
                     opcode::FORLOOP => {
                         let a = insn.a();
-                        let next_index = match stack[a + 2] {
-                            Value::Integer(step) => {
-                                let count = match stack[a + 1] {
-                                    Value::Integer(i) => i,
-                                    _ => unreachable!(),
-                                };
+                        let next_index = match (stack[a], stack[a + 1], stack[a + 2]) {
+                            (
+                                Value::Integer(index),
+                                Value::Integer(count),
+                                Value::Integer(step),
+                            ) => {
                                 if count > 0 {
-                                    let index = match stack[a] {
-                                        Value::Integer(i) => i,
-                                        _ => unreachable!(),
-                                    };
                                     stack[a + 1] = count.wrapping_sub(1).into();
                                     Some(index.wrapping_add(step).into())
                                 } else {
                                     None
                                 }
                             }
-                            Value::Number(step) => {
-                                let (index, limit) = match (stack[a], stack[a + 1]) {
-                                    (Value::Number(index), Value::Number(limit)) => (index, limit),
-                                    _ => unreachable!(),
-                                };
+                            (Value::Number(index), Value::Number(limit), Value::Number(step)) => {
                                 let next_index = index + step;
                                 if step >= 0.0 {
                                     limit >= next_index
@@ -879,12 +1026,18 @@ impl<'gc> Vm<'gc> {
                                 }
                                 .then_some(next_index.into())
                             }
-                            _ => unreachable!(),
+                            // Precompiled chunks can jump into a loop without
+                            // going through its FORPREP
+                            _ => return Err(ErrorKind::other("'for' loop is not initialized")),
                         };
                         if let Some(next_index) = next_index {
                             stack[a] = next_index;
                             stack[a + 3] = next_index;
                             pc -= insn.bx();
+                            #[cfg(feature = "jit")]
+                            {
+                                pc = self.enter_jit(proto, stack, pc);
+                            }
                         }
                     }
                     opcode::FORPREP => {
@@ -905,7 +1058,7 @@ impl<'gc> Vm<'gc> {
                             .copy_within(arg_base..arg_base + 3, new_bottom);
                         match self.push_frame(&mut thread_ref, new_bottom)? {
                             ControlFlow::Continue(()) => continue 'start,
-                            ControlFlow::Break(()) => return Ok(()),
+                            ControlFlow::Break(()) => return Ok(None),
                         }
                     }
                     opcode::TFORLOOP => {
@@ -937,6 +1090,7 @@ impl<'gc> Vm<'gc> {
                                     .ok_or_else(|| ErrorKind::TypeError {
                                         operation: Operation::Index,
                                         ty: ra.ty(),
+                                        description: None,
                                     })?;
                             let new_array_len = offset + n;
                             if new_array_len > table.array().len() {
@@ -955,9 +1109,7 @@ impl<'gc> Vm<'gc> {
                             .map(|desc| match desc {
                                 UpvalueDescription::Register(index) => {
                                     let index = base + index.0 as usize;
-                                    *thread_ref.open_upvalues.entry(index).or_insert_with(|| {
-                                        gc.allocate_cell(Upvalue::Open { thread, index })
-                                    })
+                                    thread_ref.find_upvalue(gc, thread, index)
                                 }
                                 UpvalueDescription::Upvalue(index) => upvalues[index.0 as usize],
                             })
@@ -966,7 +1118,7 @@ impl<'gc> Vm<'gc> {
                             gc.allocate(LuaClosure { proto, upvalues }).into();
                         thread_ref.save_pc(pc);
                         if gc.should_perform_gc() {
-                            return Ok(());
+                            return Ok(None);
                         } else {
                             continue 'start;
                         }
@@ -1035,4 +1187,39 @@ impl<'gc> Vm<'gc> {
             unreachable!()
         }
     }
+
+    // Field lookup for GETTABUP, GETFIELD and SELF. Also follows an __index
+    // table one level deep, which is where methods usually live. Returns None
+    // when the lookup has to go through index_slow_path.
+    fn get_field_cached(
+        &self,
+        table: Value<'gc>,
+        key: LuaString<'gc>,
+        hint: &Cell<u32>,
+    ) -> Option<Value<'gc>> {
+        let table = table.borrow_as_table()?;
+        let value = table.get_field_hinted(key, hint);
+        if !value.is_nil() {
+            return Some(value);
+        }
+        let index = table
+            .metatable()?
+            .borrow()
+            .get_field(self.metamethod_name(Metamethod::Index));
+        let value = index.borrow_as_table()?.get_field_hinted(key, hint);
+        (!value.is_nil()).then_some(value)
+    }
+}
+
+// Execution resumes by reentering the frame, which loses the stack top set
+// by the previous instruction and the argument count VARARGPREP relies on.
+fn can_pause_before(insn: Instruction) -> bool {
+    let opcode = insn.opcode();
+    opcode != OpCode::VarArgPrep && !(opcode.modes().it && insn.b() == 0)
+}
+
+// Instructions taking B = 0 use the stack top set by the call or vararg
+// before them, which precompiled chunks don't always have
+fn open_top_error() -> ErrorKind {
+    ErrorKind::other("stack top is not set")
 }
@@ -0,0 +1,142 @@
+use super::{Continuation, ContinuationFrame, ErrorKind, Frame, Vm};
+use crate::{
+    gc::{GarbageCollect, GcContext, Tracer},
+    prelude::*,
+    types::{ConversionError, FromLua, FromLuaMulti, IntoLua, IntoLuaMulti, Value},
+};
+
+// A Lua or native function that can be called from Rust
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Function<'gc>(Value<'gc>);
+
+unsafe impl GarbageCollect for Function<'_> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.0.trace(tracer);
+    }
+}
+
+impl<'gc> Function<'gc> {
+    pub fn as_value(&self) -> Value<'gc> {
+        self.0
+    }
+
+    // Runs the function to completion before returning, unlike returning an
+    // Action::Call from a native function. Calls made this way can't yield,
+    // and collectgarbage doesn't collect while they're running.
+    pub fn call<A, R>(&self, gc: &'gc GcContext, vm: &mut Vm<'gc>, args: A) -> Result<R, ErrorKind>
+    where
+        A: IntoLuaMulti<'gc>,
+        R: FromLuaMulti<'gc>,
+    {
+        let results = vm.call_value(gc, self.0, args.into_lua_multi(gc))?;
+        R::from_lua_multi(&results, gc)
+    }
+}
+
+impl<'gc> FromLua<'gc> for Function<'gc> {
+    fn from_lua(value: Value<'gc>, _: &'gc GcContext) -> Result<Self, ConversionError> {
+        match value {
+            Value::LuaClosure(_) | Value::NativeFunction(_) | Value::NativeClosure(_) => {
+                Ok(Self(value))
+            }
+            _ => Err(ConversionError {
+                from: value.ty().name(),
+                to: "function",
+            }),
+        }
+    }
+}
+
+impl<'gc> IntoLua<'gc> for Function<'gc> {
+    fn into_lua(self, _: &'gc GcContext) -> Value<'gc> {
+        self.0
+    }
+}
+
+impl<'gc> Vm<'gc> {
+    // Calls `callee` on the current thread, running it until it returns.
+    // A protected call frame under the callee catches its errors and keeps
+    // its returns from resuming the frames below.
+    pub fn call_value(
+        &mut self,
+        gc: &'gc GcContext,
+        callee: Value<'gc>,
+        mut args: Vec<Value<'gc>>,
+    ) -> Result<Vec<Value<'gc>>, ErrorKind> {
+        self.check_c_calls()?;
+        let idle = self.thread_stack.is_empty();
+        if idle {
+            self.thread_stack.push(self.main_thread);
+        }
+        let thread = self.current_thread();
+        let depth = self.thread_stack.len();
+        let caller_stats = self.suspend_function_stats();
+        let budget = self.replace_budget(i64::MAX);
+        self.host_calls.push(thread);
+
+        let (barrier, bottom) = {
+            let mut thread_ref = thread.borrow_mut(gc);
+            let barrier = thread_ref.frames.len();
+            let bottom = thread_ref.stack.len();
+            thread_ref.frames.push(Frame::ProtectedCallContinuation {
+                inner: ContinuationFrame {
+                    bottom,
+                    continuation: Some(Continuation::new(|_, _, _| unreachable!())),
+                },
+                callee_bottom: bottom,
+            });
+            thread_ref.stack.push(callee);
+            thread_ref.stack.append(&mut args);
+            (barrier, bottom)
+        };
+
+        let pushed = self.push_frame(&mut thread.borrow_mut(gc), bottom);
+        let result = pushed.and_then(|_| loop {
+            let returned =
+                self.thread_stack.len() == depth && thread.borrow().frames.len() == barrier + 1;
+            if returned {
+                break Ok(());
+            }
+            // GC steps and mutations are skipped, as the heap can't be
+            // borrowed mutably until the call returns
+            if let Err(kind) = self.execute_next_frame(gc) {
+                if let Err(err) = self.unwind(gc, self.locate_error(kind)) {
+                    break Err(err.kind);
+                }
+            }
+        });
+
+        let mut thread_ref = thread.borrow_mut(gc);
+        let caught = match thread_ref.frames.pop() {
+            Some(Frame::ProtectedCallContinuation { inner, .. }) => inner
+                .continuation
+                .as_ref()
+                .and_then(|continuation| continuation.args().cloned()),
+            _ => unreachable!(),
+        };
+        let results = thread_ref.stack.split_off(bottom);
+        drop(thread_ref);
+
+        self.host_calls.pop();
+        self.record_frame_exit();
+        let remaining = self.replace_budget(i64::MAX);
+        self.replace_budget(budget.saturating_sub(i64::MAX - remaining));
+        self.resume_function_stats(caller_stats);
+        if idle {
+            self.thread_stack.pop();
+        }
+
+        result?;
+        match caught {
+            Some(Err(kind)) => Err(kind),
+            _ => Ok(results),
+        }
+    }
+
+    pub(super) fn can_yield(&self) -> bool {
+        match self.thread_stack.as_slice() {
+            [.., current] => !self.host_calls.iter().any(|thread| thread.ptr_eq(current)),
+            [] => false,
+        }
+    }
+}
@@ -1,10 +1,15 @@
-use crate::types::{AbsLineInfo, LuaClosureProto, LuaThread, Value};
+use crate::{
+    prelude::*,
+    types::{AbsLineInfo, LineRange, LuaClosureProto, LuaThread, Type},
+};
 
 use super::{
     opcode::{self, OpCode},
     Instruction, LuaFrame, Metamethod, Vm,
 };
 
+const ABSLINEINFO: u8 = 0x80;
+
 pub(crate) struct DebugNameInfo<'a> {
     pub kind: &'static str,
     pub name: &'a str,
@@ -26,13 +31,75 @@ impl<'gc> Vm<'gc> {
         let closure = thread.stack_closure(frame.bottom)?;
         closure.proto.funcname_from_code(frame.last_pc())
     }
+
+    // "source:line:" of the function `level` levels up the stack from a
+    // native function, where level 1 is its caller (refer to "luaL_where" in
+    // lauxlib.c). None for native functions and functions without line info.
+    pub fn location(&self, level: usize) -> Option<String> {
+        let thread = self.current_thread();
+        let thread = thread.borrow();
+        let frame = thread
+            .frames
+            .iter()
+            .rev()
+            .nth(level.checked_sub(1)?)?
+            .as_lua()?;
+        let proto = &thread.stack_closure(frame.bottom)?.proto;
+        let line = proto.get_currentline(frame)?;
+        let source = String::from_utf8_lossy(&proto.source);
+        Some(format!("{}:{line}:", crate::chunk_id_from_source(&source)))
+    }
+
+    // Describes the operand of the faulting instruction of the current Lua
+    // frame that has the type `ty`, like "local 'x'" (refer to "varinfo" in
+    // ldebug.c)
+    pub(crate) fn describe_operand(&self, ty: Type) -> Option<String> {
+        let thread = self.current_thread();
+        let thread = thread.borrow();
+        let frame = thread.frames.last()?.as_lua()?;
+        let proto = &thread.stack_closure(frame.bottom)?.proto;
+        let pc = frame.last_pc();
+        let insn = *proto.code.get(pc)?;
+        let registers = match insn.raw_opcode() {
+            opcode::GETTABUP => {
+                let name = proto.upvalname(insn.b())?;
+                return Some(format!("upvalue '{name}'"));
+            }
+            opcode::SETTABUP => {
+                let name = proto.upvalname(insn.a())?;
+                return Some(format!("upvalue '{name}'"));
+            }
+            opcode::GETTABLE | opcode::GETI | opcode::GETFIELD | opcode::SELF => {
+                insn.b()..insn.b() + 1
+            }
+            opcode::SETTABLE | opcode::SETI | opcode::SETFIELD => insn.a()..insn.a() + 1,
+            opcode::UNM | opcode::BNOT | opcode::LEN => insn.b()..insn.b() + 1,
+            opcode::MMBINI | opcode::MMBINK => insn.a()..insn.a() + 1,
+            opcode::MMBIN => {
+                // Either operand can be the culprit
+                let ra = thread.stack.get(frame.base + insn.a())?;
+                if ra.ty() == ty {
+                    insn.a()..insn.a() + 1
+                } else {
+                    insn.b()..insn.b() + 1
+                }
+            }
+            // The rightmost value that isn't a string or a number
+            opcode::CONCAT => insn.a()..insn.a() + insn.b(),
+            _ => return None,
+        };
+        let reg = registers
+            .rev()
+            .find(|reg| thread.stack.get(frame.base + reg).map(|v| v.ty()) == Some(ty))?;
+        let DebugNameInfo { kind, name } = proto.get_objname(pc, reg)?;
+        Some(format!("{kind} '{name}'"))
+    }
 }
 
 impl<'gc> LuaClosureProto<'gc> {
     pub(crate) fn funcname_from_code(&self, pc: usize) -> Option<DebugNameInfo<'_>> {
         let insn = self.code.get(pc)?;
-        let mut tm = Metamethod::Index;
-        match insn.raw_opcode() {
+        let tm = match insn.raw_opcode() {
             opcode::CALL | opcode::TAILCALL => {
                 return self.get_objname(pc, insn.a()); // Get function name
             }
@@ -45,26 +112,22 @@ impl<'gc> LuaClosureProto<'gc> {
             | opcode::GETTABUP
             | opcode::GETTABLE
             | opcode::GETI
-            | opcode::GETFIELD => {
-                tm = Metamethod::Index;
-            }
+            | opcode::GETFIELD => Metamethod::Index,
             opcode::SETTABUP | opcode::SETTABLE | opcode::SETI | opcode::SETFIELD => {
-                tm = Metamethod::NewIndex;
+                Metamethod::NewIndex
             }
-            opcode::MMBIN | opcode::MMBINI | opcode::MMBINK => {
-                tm = Metamethod::from(insn.c());
-            }
-            opcode::UNM => tm = Metamethod::Unm,
-            opcode::BNOT => tm = Metamethod::BNot,
-            opcode::LEN => tm = Metamethod::Len,
-            opcode::CONCAT => tm = Metamethod::Concat,
-            opcode::EQ => tm = Metamethod::Eq,
+            opcode::MMBIN | opcode::MMBINI | opcode::MMBINK => Metamethod::from(insn.c()),
+            opcode::UNM => Metamethod::Unm,
+            opcode::BNOT => Metamethod::BNot,
+            opcode::LEN => Metamethod::Len,
+            opcode::CONCAT => Metamethod::Concat,
+            opcode::EQ => Metamethod::Eq,
             // No cases for OP_EQI and OP_EQK, as they don't call metamethods
-            opcode::LT | opcode::LTI | opcode::GTI => tm = Metamethod::Lt,
-            opcode::LE | opcode::LEI | opcode::GEI => tm = Metamethod::Le,
-            opcode::CLOSE | opcode::RETURN => tm = Metamethod::Close,
+            opcode::LT | opcode::LTI | opcode::GTI => Metamethod::Lt,
+            opcode::LE | opcode::LEI | opcode::GEI => Metamethod::Le,
+            opcode::CLOSE | opcode::RETURN => Metamethod::Close,
             _ => return None,
-        }
+        };
         Some(("metamethod", tm.static_name()).into())
     }
 
@@ -108,7 +171,7 @@ impl<'gc> LuaClosureProto<'gc> {
                 } else {
                     insn.ax()
                 };
-                if let Some(s) = self.constants[b].as_lua_string() {
+                if let Some(s) = self.constants.get_string(b) {
                     return Some(("constant", s.as_str().ok()?).into());
                 }
             }
@@ -138,20 +201,20 @@ impl<'gc> LuaClosureProto<'gc> {
             let op: OpCode = i.opcode();
             let a = i.a();
 
-            let mut change: bool = false; // True if current instruction changed 'reg'.
-            match op {
+            // True if current instruction changed 'reg'.
+            let change = match op {
                 OpCode::LoadNil => {
                     // Set registers from 'a' to 'a+b'.
                     let b = i.b();
-                    change = a <= reg && reg <= a + b;
+                    a <= reg && reg <= a + b
                 }
                 OpCode::TForCall => {
                     // Affect all regs above its base.
-                    change = reg >= a + 2;
+                    reg >= a + 2
                 }
                 OpCode::Call | OpCode::TailCall => {
                     // Affect all registers above base.
-                    change = reg >= a;
+                    reg >= a
                 }
                 OpCode::Jmp => {
                     // Doesn't change registers, but changes 'jmptarget'.
@@ -162,13 +225,13 @@ impl<'gc> LuaClosureProto<'gc> {
                     if dest <= lastpc && dest > jmptarget {
                         jmptarget = dest; // Update 'jmptarget'.
                     }
-                    change = false;
+                    false
                 }
                 _ => {
                     // Any instruction that sets A.
-                    change = op.modes().set_a && reg == a;
+                    op.modes().set_a && reg == a
                 }
-            }
+            };
 
             if change {
                 setreg = filterpc(pc, jmptarget);
@@ -201,8 +264,7 @@ impl<'gc> LuaClosureProto<'gc> {
 
     fn kname(&self, k: u8) -> &'_ str {
         self.constants
-            .get(k as usize)
-            .and_then(Value::as_lua_string)
+            .get_string(k as usize)
             .and_then(|s| s.as_str().ok())
             .unwrap_or("?")
     }
@@ -213,9 +275,9 @@ impl<'gc> LuaClosureProto<'gc> {
             .unwrap_or("??")
     }
 
-    fn upvalname(&self, _uv: usize) -> Option<&str> {
-        // TODO: fetch name from upvalue
-        None
+    pub(crate) fn upvalname(&self, uv: usize) -> Option<&str> {
+        let name = self.upvalue_names.as_ref()?.get(uv)?;
+        Some(name.as_str().unwrap_or("?"))
     }
 
     /*
@@ -247,30 +309,82 @@ impl<'gc> LuaClosureProto<'gc> {
      ** the desired instruction.
      */
     pub(crate) fn get_funcline(&self, pc: u32) -> Option<u32> {
-        let mut abs = self.get_baseline(pc);
         let lineinfo = self.line_info.as_ref()?;
-        let mut baseline = abs.line;
-        while abs.pc < pc {
-            baseline += lineinfo[abs.pc as usize] as u32;
-            abs.pc += 1;
+        let (start, mut baseline) = match self.get_baseline(pc) {
+            Some(abs) => (abs.pc as usize + 1, abs.line as i64),
+            None => (0, self.line_defined() as i64),
+        };
+        for delta in lineinfo.get(start..=pc as usize)? {
+            baseline += *delta as i8 as i64;
         }
-        Some(baseline)
+        Some(baseline as u32)
     }
 
-    pub(crate) fn get_baseline(&self, pc: u32) -> AbsLineInfo {
-        self.abs_line_info
-            .as_ref()
-            .and_then(|abs| {
-                let i = match abs.binary_search_by_key(&pc, |i| i.pc) {
-                    Ok(i) => i,
-                    Err(i) => i.checked_sub(1).unwrap_or(0),
-                };
-                abs.get(i).filter(|abs| abs.pc <= pc).copied()
-            })
-            .unwrap_or_else(|| AbsLineInfo {
-                pc: 0,
-                line: self.lines_defined.baseline(),
-            })
+    pub(crate) fn get_baseline(&self, pc: u32) -> Option<AbsLineInfo> {
+        let abs = self.abs_line_info.as_ref()?;
+        let i = match abs.binary_search_by_key(&pc, |i| i.pc) {
+            Ok(i) => i,
+            Err(i) => i.checked_sub(1)?,
+        };
+        abs.get(i).copied()
+    }
+
+    // Decodes the line of every instruction
+    pub(crate) fn lines(&self) -> Option<Vec<u32>> {
+        let lineinfo = self.line_info.as_ref()?;
+        let abs = self.abs_line_info.as_deref().unwrap_or_default();
+        let mut line = self.line_defined() as i64;
+        let mut lines = Vec::with_capacity(lineinfo.len());
+        for (pc, delta) in lineinfo.iter().enumerate() {
+            if *delta == ABSLINEINFO {
+                let i = abs.binary_search_by_key(&(pc as u32), |i| i.pc).ok()?;
+                line = abs[i].line as i64;
+            } else {
+                line += *delta as i8 as i64;
+            }
+            lines.push(line as u32);
+        }
+        Some(lines)
+    }
+
+    // refer to "savelineinfo" in lcode.c
+    pub(crate) fn set_lines(&mut self, lines: &[u32]) {
+        const LIMLINEDIFF: i64 = 0x80;
+        const MAXIWTHABS: usize = 128;
+
+        let mut lineinfo = Vec::with_capacity(lines.len());
+        let mut abs_line_info = Vec::new();
+        let mut previous_line = self.line_defined() as i64;
+        let mut iwthabs = 0;
+        for (pc, line) in lines.iter().enumerate() {
+            let linedif = *line as i64 - previous_line;
+            if linedif.abs() >= LIMLINEDIFF || iwthabs >= MAXIWTHABS {
+                abs_line_info.push(AbsLineInfo {
+                    pc: pc as u32,
+                    line: *line,
+                });
+                lineinfo.push(ABSLINEINFO);
+                iwthabs = 1;
+            } else {
+                lineinfo.push(linedif as i8 as u8);
+                iwthabs += 1;
+            }
+            previous_line = *line as i64;
+        }
+
+        self.line_info = Some(lineinfo.into_boxed_slice());
+        self.abs_line_info = if abs_line_info.is_empty() {
+            None
+        } else {
+            Some(abs_line_info.into_boxed_slice())
+        };
+    }
+
+    fn line_defined(&self) -> u32 {
+        match &self.lines_defined {
+            LineRange::File => 0,
+            LineRange::Lines(range) => *range.start(),
+        }
     }
 
     pub(crate) fn get_localname(&self, mut ln: u32, pc: u32) -> Option<&'_ str> {
@@ -281,7 +395,7 @@ impl<'gc> LuaClosureProto<'gc> {
             .take_while(|l| l.pc.start <= pc)
             .filter(|l| pc < l.pc.end)
             .find(|_| {
-                ln = ln.checked_sub(1).unwrap_or(0);
+                ln = ln.saturating_sub(1);
                 ln == 0
             })?;
         item.name.as_str().ok()
@@ -290,6 +404,6 @@ impl<'gc> LuaClosureProto<'gc> {
 
 impl LuaFrame {
     pub(crate) fn last_pc(&self) -> usize {
-        self.pc.checked_sub(1).unwrap_or(0)
+        self.pc.saturating_sub(1)
     }
 }
@@ -0,0 +1,120 @@
+use super::Vm;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleInfo {
+    pub name: Vec<u8>,
+    // Chunk name of the module, if it was loaded from Lua code
+    pub chunk: Option<Vec<u8>>,
+    pub path: Option<PathBuf>,
+    // Modification time of `path` when the module was loaded
+    pub modified: Option<SystemTime>,
+    pub loaded_at: SystemTime,
+}
+
+// Which chunks required which modules, recorded by require.
+// Chunks are identified by their chunk name (e.g. "@script.lua") and modules
+// by the name they were required with.
+#[derive(Debug, Default, Clone)]
+pub struct DependencyGraph {
+    modules: BTreeMap<Vec<u8>, ModuleInfo>,
+    requires: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>,
+}
+
+impl DependencyGraph {
+    pub fn module(&self, name: &[u8]) -> Option<&ModuleInfo> {
+        self.modules.get(name)
+    }
+
+    // Modules loaded by require, excluding the ones that were already loaded
+    // (e.g. the standard library)
+    pub fn modules(&self) -> impl Iterator<Item = &ModuleInfo> {
+        self.modules.values()
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = &[u8]> {
+        self.requires.keys().map(Vec::as_slice)
+    }
+
+    // Names of the modules `chunk` required
+    pub fn requires_of(&self, chunk: &[u8]) -> impl Iterator<Item = &[u8]> {
+        self.requires
+            .get(chunk)
+            .into_iter()
+            .flatten()
+            .map(Vec::as_slice)
+    }
+
+    // Modules that required `name` directly or through other modules, which
+    // are the ones to reload when `name` changes
+    pub fn dependents(&self, name: &[u8]) -> Vec<&[u8]> {
+        let mut dependents = Vec::new();
+        let mut queue = vec![name];
+        while let Some(name) = queue.pop() {
+            for (chunk, required) in &self.requires {
+                if !required.contains(name) {
+                    continue;
+                }
+                for module in self.modules.values() {
+                    if module.chunk.as_ref() == Some(chunk)
+                        && module.name != name
+                        && !dependents.contains(&module.name.as_slice())
+                    {
+                        dependents.push(module.name.as_slice());
+                        queue.push(module.name.as_slice());
+                    }
+                }
+            }
+        }
+        dependents
+    }
+
+    pub fn clear(&mut self) {
+        self.modules.clear();
+        self.requires.clear();
+    }
+
+    pub(crate) fn record_require(&mut self, chunk: &[u8], name: &[u8]) {
+        self.requires
+            .entry(chunk.to_vec())
+            .or_default()
+            .insert(name.to_vec());
+    }
+
+    pub(crate) fn record_module(
+        &mut self,
+        name: &[u8],
+        chunk: Option<&[u8]>,
+        path: Option<PathBuf>,
+    ) {
+        let modified = path
+            .as_ref()
+            .and_then(|path| std::fs::metadata(path).ok())
+            .and_then(|metadata| metadata.modified().ok());
+        self.modules.insert(
+            name.to_vec(),
+            ModuleInfo {
+                name: name.to_vec(),
+                chunk: chunk.map(<[u8]>::to_vec),
+                path,
+                modified,
+                // Through chrono, as SystemTime::now panics on wasm32
+                loaded_at: chrono::Utc::now().into(),
+            },
+        );
+    }
+}
+
+impl Vm<'_> {
+    pub fn dependency_graph(&self) -> &DependencyGraph {
+        &self.dependencies
+    }
+
+    pub fn dependency_graph_mut(&mut self) -> &mut DependencyGraph {
+        &mut self.dependencies
+    }
+}
@@ -0,0 +1,163 @@
+use super::Vm;
+use crate::{
+    gc::{GarbageCollect, GcCell, MaybeSend, Tracer},
+    prelude::*,
+    types::{Table, TableError, Value},
+};
+use core::cmp::Ordering;
+
+// Stands in for the system clock when execution is deterministic
+pub trait Clock: MaybeSend {
+    // Seconds since the Unix epoch, for os.time and os.date
+    fn time(&mut self) -> i64;
+
+    // Seconds of processor time used, for os.clock
+    fn clock(&mut self) -> f64;
+
+    // Offset of local time from UTC in seconds, for os.date and os.time
+    fn utc_offset(&mut self) -> i32 {
+        0
+    }
+}
+
+pub struct Deterministic {
+    // Seed of math.random, also used by math.randomseed without arguments
+    pub seed: i64,
+    pub clock: Box<dyn Clock>,
+}
+
+// Keys of tables being traversed with next while execution is
+// deterministic, from the most recently started
+#[derive(Default)]
+pub(super) struct OrderedKeys<'gc>(Vec<(GcCell<'gc, Table<'gc>>, Vec<Value<'gc>>)>);
+
+unsafe impl GarbageCollect for OrderedKeys<'_> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for (table, keys) in &self.0 {
+            table.trace(tracer);
+            keys.trace(tracer);
+        }
+    }
+}
+
+// Traversals kept at once, enough for loops nested a few deep
+const MAX_TRAVERSALS: usize = 8;
+
+impl<'gc> Vm<'gc> {
+    // Makes runs of the same scripts with the same inputs give the same
+    // results: math.random is seeded with the given seed, os.time, os.clock
+    // and os.date read its clock, and next and pairs visit keys in an order
+    // that doesn't depend on where objects were allocated. Keys that are
+    // tables, functions, userdata or threads are still visited in address
+    // order. Set it before math.random is first used, which is when it's
+    // seeded. `None` goes back to the system clock and entropy.
+    pub fn set_deterministic(&mut self, deterministic: Option<Deterministic>) {
+        self.deterministic = deterministic;
+        self.ordered_keys = Default::default();
+    }
+
+    pub fn is_deterministic(&self) -> bool {
+        self.deterministic.is_some()
+    }
+
+    pub(crate) fn deterministic(&mut self) -> Option<&mut Deterministic> {
+        self.deterministic.as_mut()
+    }
+
+    // Like Table::next, visiting keys in the order of ordered_key_cmp. The
+    // keys are sorted when a traversal starts and looked up again after.
+    pub(crate) fn ordered_next(
+        &mut self,
+        table: GcCell<'gc, Table<'gc>>,
+        key: Value<'gc>,
+    ) -> Result<Option<(Value<'gc>, Value<'gc>)>, TableError> {
+        let traversals = &mut self.ordered_keys.0;
+        let position = traversals
+            .iter()
+            .position(|(traversed, _)| GcCell::ptr_eq(traversed, &table));
+        let cached = match key {
+            Value::Nil => None,
+            key => position.and_then(|i| {
+                let keys = &traversals[i].1;
+                let found = keys.binary_search_by(|k| ordered_key_cmp(k, &key)).ok()?;
+                Some((i, found + 1))
+            }),
+        };
+        let (index, start) = match cached {
+            Some(cached) => cached,
+            None => {
+                if let Some(i) = position {
+                    traversals.remove(i);
+                }
+                let mut keys = Vec::new();
+                let mut next = table.borrow().next(Value::Nil)?;
+                while let Some((key, _)) = next {
+                    keys.push(key);
+                    next = table.borrow().next(key)?;
+                }
+                keys.sort_by(ordered_key_cmp);
+                let start = match key {
+                    Value::Nil => 0,
+                    key => match keys.binary_search_by(|k| ordered_key_cmp(k, &key)) {
+                        Ok(i) => i + 1,
+                        Err(_) => return Err(TableError::InvalidKeyToNext),
+                    },
+                };
+                traversals.truncate(MAX_TRAVERSALS - 1);
+                traversals.insert(0, (table, keys));
+                (0, start)
+            }
+        };
+
+        let table = table.borrow();
+        let keys = &traversals[index].1;
+        // Entries removed since the keys were sorted are skipped
+        Ok(keys[start..].iter().find_map(|key| {
+            let value = table.get(*key);
+            (!value.is_nil()).then_some((*key, value))
+        }))
+    }
+}
+
+// Booleans, then numbers, then strings, each in their natural order, then
+// other objects by type and address
+fn ordered_key_cmp(a: &Value, b: &Value) -> Ordering {
+    fn rank(value: &Value) -> usize {
+        match value {
+            Value::Nil => 0,
+            Value::Boolean(_) => 1,
+            Value::Integer(_) | Value::Number(_) => 2,
+            Value::String(_) => 3,
+            Value::Table(_) => 4,
+            Value::LuaClosure(_) => 5,
+            Value::NativeFunction(_) => 6,
+            Value::NativeClosure(_) => 7,
+            Value::UserData(_) => 8,
+            Value::Thread(_) => 9,
+        }
+    }
+    fn address(value: &Value) -> usize {
+        match value {
+            Value::Table(x) => x.as_ptr() as usize,
+            Value::LuaClosure(x) => x.as_ptr() as usize,
+            Value::NativeFunction(x) => x.as_ptr() as usize,
+            Value::NativeClosure(x) => x.as_ptr() as usize,
+            Value::UserData(x) => x.as_ptr() as usize,
+            Value::Thread(x) => x.as_ptr() as usize,
+            _ => 0,
+        }
+    }
+    match (a, b) {
+        (Value::Boolean(a), Value::Boolean(b)) => a.cmp(b),
+        (Value::Integer(a), Value::Integer(b)) => a.cmp(b),
+        // Float keys with integral values are too large to be integers, so
+        // only 2^63 can compare equal to one, by rounding
+        (Value::Integer(a), Value::Number(b)) => (*a as f64).total_cmp(b).then(Ordering::Less),
+        (Value::Number(a), Value::Integer(b)) => a.total_cmp(&(*b as f64)).then(Ordering::Greater),
+        (Value::Number(a), Value::Number(b)) => a.total_cmp(b),
+        (Value::String(a), Value::String(b)) => a.as_bytes().cmp(b.as_bytes()),
+        _ => rank(a)
+            .cmp(&rank(b))
+            .then_with(|| address(a).cmp(&address(b))),
+    }
+}
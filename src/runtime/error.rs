@@ -1,125 +1,266 @@
-use crate::types::{TableError, TracebackFrame, Type, Value};
-use std::{borrow::Cow, fmt::Display, sync::Arc};
-
-#[derive(Debug, thiserror::Error)]
-pub struct RuntimeError {
-    #[source]
-    pub kind: ErrorKind,
-
-    pub traceback: Vec<TracebackFrame>,
-}
-
-impl Display for RuntimeError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        writeln!(f, "{}\nstack traceback:", self.kind,)?;
-        if let Some((last, frames)) = self.traceback.split_last() {
-            for frame in frames {
-                writeln!(f, "\t{frame}")?;
-            }
-            write!(f, "\t{last}")?;
-        }
-        Ok(())
-    }
-}
-
-#[derive(Debug, thiserror::Error)]
-pub enum ErrorKind {
-    #[error("attempt to {operation} a {ty} value")]
-    TypeError { operation: Operation, ty: Type },
-
-    #[error("bad argument #{nth} ({message})")]
-    ArgumentError { nth: usize, message: &'static str },
-
-    #[error("bad argument #{nth} ({expected_type} expected, got {got})",
-        got = got_type.unwrap_or("no value")
-    )]
-    ArgumentTypeError {
-        nth: usize,
-        expected_type: &'static str,
-        got_type: Option<&'static str>,
-    },
-
-    #[error("bad 'for' {what} (number expected, got {got_type})")]
-    ForError {
-        what: &'static str,
-        got_type: &'static str,
-    },
-
-    #[error(transparent)]
-    Table(#[from] TableError),
-
-    #[error(transparent)]
-    Io(#[from] std::io::Error),
-
-    #[error("{0}")]
-    Other(String),
-
-    #[error(transparent)]
-    External(Arc<dyn std::error::Error + Send + Sync>),
-}
-
-impl Clone for ErrorKind {
-    fn clone(&self) -> Self {
-        match self {
-            Self::TypeError { operation, ty } => Self::TypeError {
-                operation: *operation,
-                ty: *ty,
-            },
-            Self::ArgumentError { nth, message } => Self::ArgumentError { nth: *nth, message },
-            Self::ArgumentTypeError {
-                nth,
-                expected_type,
-                got_type,
-            } => Self::ArgumentTypeError {
-                nth: *nth,
-                expected_type,
-                got_type: *got_type,
-            },
-            Self::ForError { what, got_type } => Self::ForError { what, got_type },
-            Self::Table(e) => Self::Table(e.clone()),
-            Self::Io(e) => Self::Io(std::io::Error::new(e.kind(), e.to_string())),
-            Self::Other(s) => Self::Other(s.clone()),
-            Self::External(err) => Self::External(err.clone()),
-        }
-    }
-}
-
-impl ErrorKind {
-    pub fn other<'a, S: Into<Cow<'a, str>>>(s: S) -> Self {
-        Self::Other(s.into().into_owned())
-    }
-
-    pub fn from_error_object(error_object: Value) -> Self {
-        let msg = if let Some(s) = error_object.to_string() {
-            String::from_utf8_lossy(&s).to_string()
-        } else {
-            format!("(error object is a {} value)", error_object.ty().name())
-        };
-        Self::Other(msg)
-    }
-}
-
-#[derive(Debug, Clone, Copy)]
-pub enum Operation {
-    Index,
-    Call,
-    Concatenate,
-    Arithmetic,
-    BitwiseOp,
-    Compare,
-    Length,
-}
-
-impl Display for Operation {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Self::Index => f.write_str("index"),
-            Self::Call => f.write_str("call"),
-            Self::Concatenate => f.write_str("concatenate"),
-            Self::Arithmetic => f.write_str("perform arithmetic on"),
-            Self::BitwiseOp => f.write_str("perform bitwise operation on"),
-            Self::Compare => f.write_str("compare"),
-            Self::Length => f.write_str("get length of"),
-        }
-    }
-}
+use super::OpCode;
+use crate::{
+    prelude::*,
+    sync::Mutex,
+    types::{Integer, TableError, TracebackFrame, Type, Value},
+};
+use alloc::{borrow::Cow, sync::Arc};
+use core::fmt::Display;
+
+#[derive(Debug, thiserror::Error)]
+pub struct RuntimeError {
+    #[source]
+    pub kind: ErrorKind,
+
+    pub traceback: Vec<TracebackFrame>,
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        writeln!(f, "{}\nstack traceback:", self.kind,)?;
+        if let Some((last, frames)) = self.traceback.split_last() {
+            for frame in frames {
+                writeln!(f, "\t{frame}")?;
+            }
+            write!(f, "\t{last}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ErrorKind {
+    #[error("attempt to {operation} a {ty} value{description}",
+        description = description.as_ref().map(|d| format!(" ({d})")).unwrap_or_default()
+    )]
+    TypeError {
+        operation: Operation,
+        ty: Type,
+        // What the value is, like "local 'x'" or "field 'y'"
+        description: Option<String>,
+    },
+
+    #[error("bad argument #{nth} ({message})")]
+    ArgumentError { nth: usize, message: &'static str },
+
+    #[error("bad argument #{nth} ({expected_type} expected, got {got})",
+        got = got_type.unwrap_or("no value")
+    )]
+    ArgumentTypeError {
+        nth: usize,
+        expected_type: &'static str,
+        got_type: Option<&'static str>,
+    },
+
+    #[error("bad 'for' {what} (number expected, got {got_type})")]
+    ForError {
+        what: &'static str,
+        got_type: &'static str,
+    },
+
+    #[error("execution budget exceeded")]
+    FuelExhausted,
+
+    #[error("stack overflow")]
+    StackOverflow,
+
+    #[error("C stack overflow")]
+    CStackOverflow,
+
+    #[error(transparent)]
+    Table(#[from] TableError),
+
+    #[error(transparent)]
+    Io(#[from] crate::io::Error),
+
+    #[error("{0}")]
+    Other(String),
+
+    // Raised with error() or assert() and a string, which is the message as is
+    #[error("{0}")]
+    ExplicitError(String),
+
+    #[error("{0}")]
+    Object(ErrorObject),
+
+    #[error("{location} {kind}")]
+    Located {
+        location: String,
+        kind: Box<ErrorKind>,
+    },
+
+    #[error(transparent)]
+    External(Arc<dyn core::error::Error + Send + Sync>),
+
+    #[error(transparent)]
+    Internal(InternalError),
+}
+
+impl Clone for ErrorKind {
+    fn clone(&self) -> Self {
+        match self {
+            Self::TypeError {
+                operation,
+                ty,
+                description,
+            } => Self::TypeError {
+                operation: *operation,
+                ty: *ty,
+                description: description.clone(),
+            },
+            Self::ArgumentError { nth, message } => Self::ArgumentError { nth: *nth, message },
+            Self::ArgumentTypeError {
+                nth,
+                expected_type,
+                got_type,
+            } => Self::ArgumentTypeError {
+                nth: *nth,
+                expected_type,
+                got_type: *got_type,
+            },
+            Self::ForError { what, got_type } => Self::ForError { what, got_type },
+            Self::FuelExhausted => Self::FuelExhausted,
+            Self::StackOverflow => Self::StackOverflow,
+            Self::CStackOverflow => Self::CStackOverflow,
+            Self::Table(e) => Self::Table(e.clone()),
+            Self::Io(e) => Self::Io(crate::io::Error::new(e.kind(), e.to_string())),
+            Self::Other(s) => Self::Other(s.clone()),
+            Self::ExplicitError(s) => Self::ExplicitError(s.clone()),
+            Self::Object(obj) => Self::Object(obj.clone()),
+            Self::Located { location, kind } => Self::Located {
+                location: location.clone(),
+                kind: kind.clone(),
+            },
+            Self::External(err) => Self::External(err.clone()),
+            Self::Internal(err) => Self::Internal(err.clone()),
+        }
+    }
+}
+
+impl ErrorKind {
+    pub fn other<'a, S: Into<Cow<'a, str>>>(s: S) -> Self {
+        Self::Other(s.into().into_owned())
+    }
+
+    pub fn from_error_object(error_object: Value) -> Self {
+        Self::ExplicitError(error_object_message(error_object))
+    }
+
+    // Whether the position where the error was raised goes in front of the
+    // message. Errors raised with error() have their own position, if any.
+    pub(crate) fn needs_location(&self) -> bool {
+        !matches!(
+            self,
+            Self::FuelExhausted
+                | Self::External(_)
+                | Self::Internal(_)
+                | Self::ExplicitError(_)
+                | Self::Object(_)
+        )
+    }
+}
+
+fn error_object_message(error_object: Value) -> String {
+    if let Some(s) = error_object.coerce_to_string() {
+        String::from_utf8_lossy(&s).to_string()
+    } else {
+        format!("(error object is a {} value)", error_object.ty().name())
+    }
+}
+
+// A value other than a string raised as an error. The value stays in the
+// registry for as long as an ErrorKind refers to it, and Vm::error_value gets
+// it back.
+#[derive(Debug, Clone)]
+pub struct ErrorObject(Arc<ErrorObjectRef>);
+
+#[derive(Debug)]
+struct ErrorObjectRef {
+    id: Integer,
+    message: String,
+    released: Arc<Mutex<Vec<Integer>>>,
+}
+
+impl Drop for ErrorObjectRef {
+    #[cfg_attr(not(feature = "std"), allow(irrefutable_let_patterns))]
+    fn drop(&mut self) {
+        if let Ok(mut released) = self.released.lock() {
+            released.push(self.id);
+        }
+    }
+}
+
+impl ErrorObject {
+    pub(crate) fn new(id: Integer, value: Value, released: Arc<Mutex<Vec<Integer>>>) -> Self {
+        Self(Arc::new(ErrorObjectRef {
+            id,
+            message: error_object_message(value),
+            released,
+        }))
+    }
+
+    pub(crate) fn id(&self) -> Integer {
+        self.0.id
+    }
+}
+
+impl Display for ErrorObject {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(&self.0.message)
+    }
+}
+
+/// A panic raised inside the interpreter, caught when the `catch-panic`
+/// feature is enabled.
+///
+/// `opcode` and `pc` describe the last instruction dispatched before the
+/// panic, and `source` is the chunk of the innermost Lua frame.
+#[derive(Debug, Clone)]
+pub struct InternalError {
+    pub message: String,
+    pub opcode: Option<OpCode>,
+    pub pc: Option<usize>,
+    pub source: Option<String>,
+}
+
+impl Display for InternalError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "internal error: {}", self.message)?;
+        match (self.opcode, self.pc) {
+            (Some(opcode), Some(pc)) => write!(f, " (at {opcode}, pc {pc}")?,
+            (None, Some(pc)) => write!(f, " (at pc {pc}")?,
+            _ => return Ok(()),
+        }
+        if let Some(source) = &self.source {
+            write!(f, " in {source}")?;
+        }
+        f.write_str(")")
+    }
+}
+
+impl core::error::Error for InternalError {}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Operation {
+    Index,
+    Call,
+    Concatenate,
+    Arithmetic,
+    BitwiseOp,
+    Compare,
+    Length,
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Index => f.write_str("index"),
+            Self::Call => f.write_str("call"),
+            Self::Concatenate => f.write_str("concatenate"),
+            Self::Arithmetic => f.write_str("perform arithmetic on"),
+            Self::BitwiseOp => f.write_str("perform bitwise operation on"),
+            Self::Compare => f.write_str("compare"),
+            Self::Length => f.write_str("get length of"),
+        }
+    }
+}
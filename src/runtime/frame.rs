@@ -1,6 +1,7 @@
 use super::{Continuation, ErrorKind, Operation, RuntimeAction, Vm};
 use crate::{
     gc::{GarbageCollect, GcCell, GcContext, Tracer},
+    prelude::*,
     types::Value,
 };
 
@@ -20,6 +21,8 @@ pub(crate) enum Frame<'gc> {
     },
     ResumeContinuation(ContinuationFrame<'gc, Result<Vec<Value<'gc>>, ErrorKind>>),
     MutateGcContinuation(ContinuationFrame<'gc, ()>),
+    #[cfg(feature = "async")]
+    AwaitContinuation(ContinuationFrame<'gc, Result<Vec<Value<'gc>>, ErrorKind>>),
 }
 
 impl<'gc> Frame<'gc> {
@@ -40,6 +43,8 @@ unsafe impl GarbageCollect for Frame<'_> {
                 inner.trace(tracer)
             }
             Self::MutateGcContinuation(inner) => inner.trace(tracer),
+            #[cfg(feature = "async")]
+            Self::AwaitContinuation(inner) => inner.trace(tracer),
         }
     }
 }
@@ -68,8 +73,8 @@ pub(crate) struct ContinuationFrame<'gc, R> {
     pub continuation: Option<Continuation<'gc, R>>,
 }
 
-impl<R> std::fmt::Debug for ContinuationFrame<'_, R> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl<R> core::fmt::Debug for ContinuationFrame<'_, R> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("ContinuationFrame")
             .field("bottom", &self.bottom)
             .finish()
@@ -92,8 +97,24 @@ impl<'gc> Vm<'gc> {
 
         if let [.., Frame::Lua(_)] = thread_ref.frames.as_slice() {
             drop(thread_ref);
-            self.execute_lua_frame(gc)?;
-            return Ok(None);
+            let result = match self.execute_lua_frame(gc) {
+                Err(ErrorKind::TypeError {
+                    operation,
+                    ty,
+                    description: None,
+                }) => Err(ErrorKind::TypeError {
+                    operation,
+                    ty,
+                    description: self.describe_operand(ty),
+                }),
+                result => result,
+            };
+            self.record_frame_exit();
+            #[cfg(feature = "debugger")]
+            if let Ok(None) = result {
+                self.run_debug_handler(gc);
+            }
+            return result;
         }
 
         let mut current_frame = thread_ref.frames.pop();
@@ -112,6 +133,7 @@ impl<'gc> Vm<'gc> {
                         return Err(ErrorKind::TypeError {
                             operation: Operation::Call,
                             ty: value.ty(),
+                            description: None,
                         })
                     }
                 };
@@ -161,11 +183,19 @@ impl<'gc> Vm<'gc> {
                 drop(thread_ref);
                 (*bottom, continuation.take().unwrap().call(gc, self))
             }
+            #[cfg(feature = "async")]
+            Some(Frame::AwaitContinuation(ContinuationFrame {
+                bottom,
+                continuation,
+            })) => {
+                drop(thread_ref);
+                (*bottom, continuation.take().unwrap().call(gc, self))
+            }
             None => {
                 let coroutine = self.thread_stack.pop().unwrap();
                 debug_assert!(GcCell::ptr_eq(&coroutine, &thread));
 
-                let values = std::mem::take(&mut thread_ref.stack);
+                let values = core::mem::take(&mut thread_ref.stack);
                 if let Some(coroutine) = self.thread_stack.last() {
                     match coroutine.borrow_mut(gc).frames.as_mut_slice() {
                         [.., Frame::ResumeContinuation(frame)] => {
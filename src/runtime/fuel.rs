@@ -0,0 +1,48 @@
+use super::Vm;
+
+impl<'gc> Vm<'gc> {
+    // Limits Lua code to running `fuel` more instructions. Once it runs out,
+    // an "execution budget exceeded" error is raised before any further
+    // instruction. pcall can catch it, but as the fuel stays empty the code
+    // around the pcall fails right after.
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.refuel(Some(i64::try_from(fuel).unwrap_or(i64::MAX)));
+    }
+
+    pub fn clear_fuel(&mut self) {
+        self.refuel(None);
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+            .map(|fuel| (fuel - (self.fuel_mark - self.budget)).max(0) as u64)
+    }
+
+    pub(super) fn is_out_of_fuel(&self) -> bool {
+        self.fuel
+            .is_some_and(|fuel| fuel <= self.fuel_mark - self.budget)
+    }
+
+    // Sets the budget and returns what was left of the previous one.
+    // The fuel is charged for the instructions run since the budget was last
+    // set, and caps the new budget so that execution stops when it runs out.
+    pub(super) fn replace_budget(&mut self, budget: i64) -> i64 {
+        let previous = self.budget.saturating_add(self.budget_held);
+        if let Some(fuel) = &mut self.fuel {
+            *fuel -= self.fuel_mark - self.budget;
+        }
+        self.budget = match self.fuel {
+            Some(fuel) => budget.min(fuel.max(0)),
+            None => budget,
+        };
+        self.budget_held = budget - self.budget;
+        self.fuel_mark = self.budget;
+        previous
+    }
+
+    fn refuel(&mut self, fuel: Option<i64>) {
+        let budget = self.replace_budget(0);
+        self.fuel = fuel;
+        self.replace_budget(budget);
+    }
+}
@@ -0,0 +1,97 @@
+use super::{frame::Frame, ErrorKind, Runtime, RuntimeError, Vm};
+use crate::{
+    gc::{GcContext, MaybeSend},
+    types::Value,
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::Thread,
+};
+
+// Future a native function waits on with Action::Await. The runtime doesn't
+// run until it's ready, and its output gives the values the continuation is
+// called with. Both are Send with the "send" feature, like MaybeSend.
+#[cfg(feature = "send")]
+pub type LuaFuture = Pin<Box<dyn Future<Output = FutureOutput> + Send>>;
+#[cfg(not(feature = "send"))]
+pub type LuaFuture = Pin<Box<dyn Future<Output = FutureOutput>>>;
+
+#[cfg(feature = "send")]
+pub type FutureOutput =
+    Box<dyn Send + for<'gc> FnOnce(&'gc GcContext) -> Result<Vec<Value<'gc>>, ErrorKind>>;
+#[cfg(not(feature = "send"))]
+pub type FutureOutput =
+    Box<dyn for<'gc> FnOnce(&'gc GcContext) -> Result<Vec<Value<'gc>>, ErrorKind>>;
+
+pub fn future_output<F>(f: F) -> FutureOutput
+where
+    F: 'static + MaybeSend + for<'gc> FnOnce(&'gc GcContext) -> Result<Vec<Value<'gc>>, ErrorKind>,
+{
+    Box::new(f)
+}
+
+impl<'gc> Vm<'gc> {
+    fn finish_await(&mut self, gc: &'gc GcContext, results: Result<Vec<Value<'gc>>, ErrorKind>) {
+        match self.current_thread().borrow_mut(gc).frames.as_mut_slice() {
+            [.., Frame::AwaitContinuation(frame)] => {
+                frame.continuation.as_mut().unwrap().set_args(results)
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Runtime {
+    // Whether the runtime waits on a future a native function returned
+    pub fn is_awaiting(&self) -> bool {
+        self.awaiting.is_some()
+    }
+
+    // Polls the future the runtime waits on, and lets the runtime run again
+    // once it's ready
+    pub fn poll_awaited(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let Some(future) = &mut self.awaiting else {
+            return Poll::Ready(());
+        };
+        let Poll::Ready(output) = future.as_mut().poll(cx) else {
+            return Poll::Pending;
+        };
+        self.awaiting = None;
+        self.heap.with(|gc, vm| {
+            let results = output(gc);
+            vm.borrow_mut(gc).finish_await(gc, results);
+        });
+        Poll::Ready(())
+    }
+
+    // Runs the started function to completion like `execute` does, but
+    // returns to the executor instead of blocking while futures are pending
+    pub async fn finish_async(&mut self) -> Result<(), RuntimeError> {
+        while !self.run()? {
+            self.wait_until_awake()?;
+            std::future::poll_fn(|cx| self.poll_awaited(cx)).await;
+        }
+        Ok(())
+    }
+
+    pub(super) fn block_on_awaited(&mut self) {
+        struct ThreadWaker(Thread);
+
+        impl Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+        while self
+            .poll_awaited(&mut Context::from_waker(&waker))
+            .is_pending()
+        {
+            std::thread::park();
+        }
+    }
+}
@@ -11,8 +11,8 @@ pub const OFFSET_SJ: i32 = UINT25_MAX as i32 >> 1;
 #[derive(Clone, Copy)]
 pub struct Instruction(pub u32);
 
-impl std::fmt::Debug for Instruction {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Debug for Instruction {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         f.debug_tuple("Instruction").field(&self.opcode()).finish()
     }
 }
@@ -0,0 +1,1058 @@
+use super::{opcode, Instruction, Vm};
+use crate::types::{Integer, LuaClosureProto, Number, Value};
+use cranelift_codegen::{
+    ir::{
+        condcodes::{FloatCC, IntCC},
+        types, AbiParam, Block, InstBuilder, MemFlags,
+    },
+    settings::{self, Configurable},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Switch};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::Module;
+use std::cell::Cell;
+
+// Function entries plus backward jumps taken before a proto gets compiled
+const HOT_THRESHOLD: u32 = 1000;
+
+// Guard failures after which the compiled code of a proto is dropped and the
+// proto goes back to being interpreted
+const MAX_DEOPTS: u32 = 100;
+
+// Compiled code works on the stack in place, relying on Value being
+// repr(C, u8) with this feature
+const VALUE_SIZE: i32 = std::mem::size_of::<Value>() as i32;
+const PAYLOAD_OFFSET: i32 = 8;
+const TAG_NIL: i64 = 0;
+const TAG_BOOLEAN: i64 = 1;
+const TAG_INTEGER: i64 = 2;
+const TAG_NUMBER: i64 = 3;
+
+// Compiled code returns the pc to resume interpreting at, with this bit set
+// if it had to bail out because of a failed type guard
+const DEOPT: u64 = 1 << 32;
+
+type CompiledFn = unsafe extern "C" fn(stack: *mut Value, pc: u64, budget: *mut i64) -> u64;
+
+#[derive(Default)]
+pub(crate) struct JitState {
+    counter: Cell<u32>,
+    code: Cell<Option<CompiledFn>>,
+    deopts: Cell<u32>,
+    disabled: Cell<bool>,
+}
+
+// Compiled code belongs to the Jit it was compiled by, so it isn't cloned
+impl Clone for JitState {
+    fn clone(&self) -> Self {
+        Default::default()
+    }
+}
+
+impl std::fmt::Debug for JitState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JitState")
+            .field("compiled", &self.code.get().is_some())
+            .field("disabled", &self.disabled.get())
+            .finish()
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Jit {
+    module: Option<JITModule>,
+    unavailable: bool,
+    pub(super) disabled: bool,
+    builder_context: FunctionBuilderContext,
+}
+
+impl Jit {
+    fn module(&mut self) -> Option<&mut JITModule> {
+        if self.module.is_none() && !self.unavailable {
+            self.module = new_module();
+            self.unavailable = self.module.is_none();
+        }
+        self.module.as_mut()
+    }
+
+    fn compile(&mut self, proto: &LuaClosureProto) -> Option<CompiledFn> {
+        let mut builder_context = std::mem::take(&mut self.builder_context);
+        let module = self.module()?;
+
+        let pointer_type = module.target_config().pointer_type();
+        let mut context = module.make_context();
+        let signature = &mut context.func.signature;
+        signature.params.push(AbiParam::new(pointer_type));
+        signature.params.push(AbiParam::new(types::I64));
+        signature.params.push(AbiParam::new(pointer_type));
+        signature.returns.push(AbiParam::new(types::I64));
+
+        let builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+        Translator::new(builder, &proto.code).translate(proto);
+
+        let id = module
+            .declare_anonymous_function(&context.func.signature)
+            .ok()?;
+        module.define_function(id, &mut context).ok()?;
+        module.clear_context(&mut context);
+        module.finalize_definitions().ok()?;
+        let code = module.get_finalized_function(id);
+        self.builder_context = builder_context;
+        Some(unsafe { std::mem::transmute::<*const u8, CompiledFn>(code) })
+    }
+}
+
+fn new_module() -> Option<JITModule> {
+    if !has_expected_value_layout() {
+        return None;
+    }
+
+    let mut flags = settings::builder();
+    flags.set("use_colocated_libcalls", "false").ok()?;
+    flags.set("is_pic", "false").ok()?;
+    flags.set("opt_level", "speed").ok()?;
+    let isa = cranelift_native::builder()
+        .ok()?
+        .finish(settings::Flags::new(flags))
+        .ok()?;
+    let builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+    Some(JITModule::new(builder))
+}
+
+fn has_expected_value_layout() -> bool {
+    // Reads only the tag and the payload, as the other bytes are padding
+    fn read<T: Copy>(value: &Value, offset: i32) -> T {
+        let ptr = (value as *const Value).cast::<u8>();
+        unsafe { ptr.add(offset as usize).cast::<T>().read() }
+    }
+    let integer = Value::Integer(-2);
+    let number = Value::Number(0.5);
+    let boolean = Value::Boolean(true);
+    std::mem::size_of::<Value>() == VALUE_SIZE as usize
+        && std::mem::align_of::<Value>() == PAYLOAD_OFFSET as usize
+        && read::<u8>(&Value::Nil, 0) as i64 == TAG_NIL
+        && read::<u8>(&boolean, 0) as i64 == TAG_BOOLEAN
+        && read::<u8>(&boolean, PAYLOAD_OFFSET) == 1
+        && read::<u8>(&integer, 0) as i64 == TAG_INTEGER
+        && read::<i64>(&integer, PAYLOAD_OFFSET) == -2
+        && read::<u8>(&number, 0) as i64 == TAG_NUMBER
+        && read::<u64>(&number, PAYLOAD_OFFSET) == 0.5f64.to_bits()
+}
+
+impl<'gc> Vm<'gc> {
+    // Runs the compiled code of `proto` from `pc`, compiling it first if it
+    // just became hot. Returns the pc to continue interpreting at, which is
+    // `pc` itself when there is no compiled code.
+    pub(super) fn enter_jit(
+        &mut self,
+        proto: &LuaClosureProto<'gc>,
+        stack: &mut [Value<'gc>],
+        pc: usize,
+    ) -> usize {
+        // Breakpoints and steps can't stop compiled code
+        #[cfg(feature = "debugger")]
+        if self.debugger.is_some() {
+            return pc;
+        }
+        // nor does it count lines run
+        #[cfg(feature = "coverage")]
+        if self.coverage.is_some() {
+            return pc;
+        }
+        // nor take samples
+        #[cfg(feature = "profiler")]
+        if self.profiler.is_some() {
+            return pc;
+        }
+        // nor trace instructions
+        #[cfg(feature = "trace")]
+        if tracing::enabled!(target: "mochi::vm", tracing::Level::TRACE) {
+            return pc;
+        }
+        if self.jit.disabled {
+            return pc;
+        }
+        let state = &proto.jit;
+        let code = match state.code.get() {
+            Some(code) => code,
+            None => {
+                if state.disabled.get() {
+                    return pc;
+                }
+                let count = state.counter.get() + 1;
+                state.counter.set(count);
+                if count < HOT_THRESHOLD {
+                    return pc;
+                }
+                match self.jit.compile(proto) {
+                    Some(code) => {
+                        state.code.set(Some(code));
+                        code
+                    }
+                    None => {
+                        state.disabled.set(true);
+                        return pc;
+                    }
+                }
+            }
+        };
+
+        let exit = unsafe { code(stack.as_mut_ptr(), pc as u64, &mut self.budget) };
+        if exit & DEOPT != 0 {
+            let deopts = state.deopts.get() + 1;
+            state.deopts.set(deopts);
+            if deopts >= MAX_DEOPTS {
+                state.code.set(None);
+                state.disabled.set(true);
+            }
+        }
+        exit as u32 as usize
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(usize),
+    Integer(Integer),
+    Number(Number),
+}
+
+#[derive(Clone, Copy)]
+enum ArithmeticOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+#[derive(Clone, Copy)]
+enum BitwiseOp {
+    And,
+    Or,
+    Xor,
+}
+
+#[derive(Clone, Copy)]
+enum Comparison {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    fn int_cc(self) -> IntCC {
+        match self {
+            Self::Eq => IntCC::Equal,
+            Self::Lt => IntCC::SignedLessThan,
+            Self::Le => IntCC::SignedLessThanOrEqual,
+            Self::Gt => IntCC::SignedGreaterThan,
+            Self::Ge => IntCC::SignedGreaterThanOrEqual,
+        }
+    }
+
+    fn float_cc(self) -> FloatCC {
+        match self {
+            Self::Eq => FloatCC::Equal,
+            Self::Lt => FloatCC::LessThan,
+            Self::Le => FloatCC::LessThanOrEqual,
+            Self::Gt => FloatCC::GreaterThan,
+            Self::Ge => FloatCC::GreaterThanOrEqual,
+        }
+    }
+}
+
+// Translates every instruction to its own block. Instructions without a
+// translation, and guards that fail, return to the interpreter at the
+// instruction, which then handles it as usual.
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    code: &'a [Instruction],
+    blocks: Vec<Block>,
+    exit_block: Block,
+    // Exits before the instruction being translated finishes, which gives
+    // back what it took from the budget
+    refund_block: Block,
+    stack: cranelift_codegen::ir::Value,
+    budget: cranelift_codegen::ir::Value,
+    pc: usize,
+}
+
+impl<'a> Translator<'a> {
+    fn new(mut builder: FunctionBuilder<'a>, code: &'a [Instruction]) -> Self {
+        let entry_block = builder.create_block();
+        builder.append_block_params_for_function_params(entry_block);
+        builder.switch_to_block(entry_block);
+        builder.seal_block(entry_block);
+        let params = builder.block_params(entry_block);
+        let (stack, pc, budget) = (params[0], params[1], params[2]);
+
+        let blocks: Vec<_> = code.iter().map(|_| builder.create_block()).collect();
+        let exit_block = builder.create_block();
+        builder.append_block_param(exit_block, types::I64);
+        let refund_block = builder.create_block();
+        builder.append_block_param(refund_block, types::I64);
+
+        let mut switch = Switch::new();
+        for (i, block) in blocks.iter().enumerate() {
+            switch.set_entry(i as u128, *block);
+        }
+        let out_of_range = builder.create_block();
+        switch.emit(&mut builder, pc, out_of_range);
+        builder.switch_to_block(out_of_range);
+        builder.seal_block(out_of_range);
+        builder.ins().jump(exit_block, &[pc]);
+
+        Self {
+            builder,
+            code,
+            blocks,
+            exit_block,
+            refund_block,
+            stack,
+            budget,
+            pc: 0,
+        }
+    }
+
+    fn translate(mut self, proto: &LuaClosureProto) {
+        for (pc, &insn) in self.code.iter().enumerate() {
+            self.builder.switch_to_block(self.blocks[pc]);
+            self.pc = pc;
+            self.charge_budget(pc);
+            self.translate_instruction(proto, pc, insn);
+        }
+
+        self.builder.switch_to_block(self.refund_block);
+        let flags = MemFlags::trusted();
+        let budget = self.builder.ins().load(types::I64, flags, self.budget, 0);
+        let budget = self.builder.ins().iadd_imm(budget, 1);
+        self.builder.ins().store(flags, budget, self.budget, 0);
+        let result = self.builder.block_params(self.refund_block)[0];
+        self.builder.ins().jump(self.exit_block, &[result]);
+
+        self.builder.switch_to_block(self.exit_block);
+        let result = self.builder.block_params(self.exit_block)[0];
+        self.builder.ins().return_(&[result]);
+
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+    }
+
+    fn translate_instruction(&mut self, proto: &LuaClosureProto, pc: usize, insn: Instruction) {
+        let next = pc + 1;
+        match insn.raw_opcode() {
+            opcode::MOVE => {
+                let flags = MemFlags::trusted();
+                for offset in [0, 8] {
+                    let x = self.builder.ins().load(
+                        types::I64,
+                        flags,
+                        self.stack,
+                        register_offset(insn.b()) + offset,
+                    );
+                    self.builder.ins().store(
+                        flags,
+                        x,
+                        self.stack,
+                        register_offset(insn.a()) + offset,
+                    );
+                }
+                self.jump(next);
+            }
+            opcode::LOADI => {
+                let x = self.builder.ins().iconst(types::I64, insn.sbx() as i64);
+                self.store_integer(insn.a(), x);
+                self.jump(next);
+            }
+            opcode::LOADF => {
+                let x = self.builder.ins().f64const(insn.sbx() as Number);
+                self.store_number(insn.a(), x);
+                self.jump(next);
+            }
+            opcode::LOADK | opcode::LOADKX => {
+                let (index, next) = if insn.raw_opcode() == opcode::LOADK {
+                    (insn.bx(), next)
+                } else {
+                    (self.code[next].ax(), next + 1)
+                };
+                match proto.constants.value(index) {
+                    Value::Nil => self.store_tag(insn.a(), TAG_NIL),
+                    Value::Boolean(b) => self.store_boolean_const(insn.a(), b),
+                    Value::Integer(i) => {
+                        let x = self.builder.ins().iconst(types::I64, i);
+                        self.store_integer(insn.a(), x);
+                    }
+                    Value::Number(n) => {
+                        let x = self.builder.ins().f64const(n);
+                        self.store_number(insn.a(), x);
+                    }
+                    _ => return self.exit(pc, false),
+                }
+                self.jump(next);
+            }
+            opcode::LOADFALSE | opcode::LFALSESKIP => {
+                self.store_boolean_const(insn.a(), false);
+                let skip = insn.raw_opcode() == opcode::LFALSESKIP;
+                self.jump(if skip { next + 1 } else { next });
+            }
+            opcode::LOADTRUE => {
+                self.store_boolean_const(insn.a(), true);
+                self.jump(next);
+            }
+            opcode::LOADNIL => {
+                for r in insn.a()..=insn.a() + insn.b() {
+                    self.store_tag(r, TAG_NIL);
+                }
+                self.jump(next);
+            }
+            opcode::ADDI => self.arithmetic(
+                pc,
+                insn.a(),
+                Operand::Register(insn.b()),
+                Operand::Integer(insn.sc() as Integer),
+                ArithmeticOp::Add,
+            ),
+            opcode::ADDK | opcode::SUBK | opcode::MULK | opcode::MODK | opcode::DIVK => {
+                let op = match insn.raw_opcode() {
+                    opcode::ADDK => ArithmeticOp::Add,
+                    opcode::SUBK => ArithmeticOp::Sub,
+                    opcode::MULK => ArithmeticOp::Mul,
+                    opcode::MODK => ArithmeticOp::Mod,
+                    _ => ArithmeticOp::Div,
+                };
+                match constant_operand(proto, insn.c() as usize) {
+                    Some(rhs) => {
+                        self.arithmetic(pc, insn.a(), Operand::Register(insn.b()), rhs, op)
+                    }
+                    None => self.exit(pc, false),
+                }
+            }
+            opcode::ADD | opcode::SUB | opcode::MUL | opcode::MOD | opcode::DIV => {
+                let op = match insn.raw_opcode() {
+                    opcode::ADD => ArithmeticOp::Add,
+                    opcode::SUB => ArithmeticOp::Sub,
+                    opcode::MUL => ArithmeticOp::Mul,
+                    opcode::MOD => ArithmeticOp::Mod,
+                    _ => ArithmeticOp::Div,
+                };
+                self.arithmetic(
+                    pc,
+                    insn.a(),
+                    Operand::Register(insn.b()),
+                    Operand::Register(insn.c() as usize),
+                    op,
+                )
+            }
+            opcode::BANDK | opcode::BORK | opcode::BXORK => {
+                let op = match insn.raw_opcode() {
+                    opcode::BANDK => BitwiseOp::And,
+                    opcode::BORK => BitwiseOp::Or,
+                    _ => BitwiseOp::Xor,
+                };
+                match proto.constants.value(insn.c() as usize) {
+                    Value::Integer(i) => {
+                        self.bitwise(pc, insn.a(), insn.b(), Operand::Integer(i), op)
+                    }
+                    _ => self.exit(pc, false),
+                }
+            }
+            opcode::BAND | opcode::BOR | opcode::BXOR => {
+                let op = match insn.raw_opcode() {
+                    opcode::BAND => BitwiseOp::And,
+                    opcode::BOR => BitwiseOp::Or,
+                    _ => BitwiseOp::Xor,
+                };
+                let rhs = Operand::Register(insn.c() as usize);
+                self.bitwise(pc, insn.a(), insn.b(), rhs, op)
+            }
+            opcode::UNM => {
+                let tag = self.load_tag(insn.b());
+                let is_integer = self.builder.ins().icmp_imm(IntCC::Equal, tag, TAG_INTEGER);
+                let integer_block = self.builder.create_block();
+                let other_block = self.builder.create_block();
+                let number_block = self.builder.create_block();
+                self.builder
+                    .ins()
+                    .brif(is_integer, integer_block, &[], other_block, &[]);
+
+                self.builder.switch_to_block(integer_block);
+                let x = self.load_integer(insn.b());
+                let x = self.builder.ins().ineg(x);
+                self.store_integer(insn.a(), x);
+                self.jump(next);
+
+                self.builder.switch_to_block(other_block);
+                let is_number = self.builder.ins().icmp_imm(IntCC::Equal, tag, TAG_NUMBER);
+                self.guard(is_number, number_block, pc);
+
+                self.builder.switch_to_block(number_block);
+                let x = self.load_number(insn.b());
+                let x = self.builder.ins().fneg(x);
+                self.store_number(insn.a(), x);
+                self.jump(next);
+            }
+            opcode::NOT => {
+                let is_truthy = self.is_truthy(insn.b());
+                let is_falsy = self.builder.ins().bxor_imm(is_truthy, 1);
+                self.store_boolean(insn.a(), is_falsy);
+                self.jump(next);
+            }
+            opcode::JMP => {
+                let target = (next as isize + insn.sj() as isize) as usize;
+                self.jump(target);
+            }
+            opcode::EQ | opcode::LT | opcode::LE => {
+                let comparison = match insn.raw_opcode() {
+                    opcode::EQ => Comparison::Eq,
+                    opcode::LT => Comparison::Lt,
+                    _ => Comparison::Le,
+                };
+                self.compare(pc, insn, insn.a(), Operand::Register(insn.b()), comparison);
+            }
+            opcode::EQK => match constant_operand(proto, insn.b()) {
+                Some(rhs) => self.compare(pc, insn, insn.a(), rhs, Comparison::Eq),
+                None => self.exit(pc, false),
+            },
+            opcode::EQI | opcode::LTI | opcode::LEI | opcode::GTI | opcode::GEI => {
+                let comparison = match insn.raw_opcode() {
+                    opcode::EQI => Comparison::Eq,
+                    opcode::LTI => Comparison::Lt,
+                    opcode::LEI => Comparison::Le,
+                    opcode::GTI => Comparison::Gt,
+                    _ => Comparison::Ge,
+                };
+                let rhs = Operand::Integer(insn.sb() as Integer);
+                self.compare(pc, insn, insn.a(), rhs, comparison);
+            }
+            opcode::TEST => {
+                let cond = self.is_truthy(insn.a());
+                self.conditional_jump(pc, insn, cond);
+            }
+            opcode::TESTSET => {
+                let cond = self.is_truthy(insn.b());
+                let set_block = self.builder.create_block();
+                let skip_block = self.target_block(pc + 2);
+                if insn.k() {
+                    self.builder
+                        .ins()
+                        .brif(cond, set_block, &[], skip_block, &[]);
+                } else {
+                    self.builder
+                        .ins()
+                        .brif(cond, skip_block, &[], set_block, &[]);
+                }
+                self.builder.switch_to_block(set_block);
+                let flags = MemFlags::trusted();
+                for offset in [0, 8] {
+                    let x = self.builder.ins().load(
+                        types::I64,
+                        flags,
+                        self.stack,
+                        register_offset(insn.b()) + offset,
+                    );
+                    self.builder.ins().store(
+                        flags,
+                        x,
+                        self.stack,
+                        register_offset(insn.a()) + offset,
+                    );
+                }
+                let target = (next as isize + self.code[next].sj() as isize + 1) as usize;
+                self.jump(target);
+            }
+            opcode::FORLOOP => self.for_loop(pc, insn),
+            _ => self.exit(pc, false),
+        }
+    }
+
+    fn arithmetic(&mut self, pc: usize, a: usize, lhs: Operand, rhs: Operand, op: ArithmeticOp) {
+        // skips the MMBIN that follows
+        let next = pc + 2;
+
+        let float_block = self.builder.create_block();
+        let integers_possible = !matches!(op, ArithmeticOp::Div)
+            && !matches!(lhs, Operand::Number(_))
+            && !matches!(rhs, Operand::Number(_));
+        if integers_possible {
+            let integer_block = self.builder.create_block();
+            let mut cond = None;
+            for operand in [lhs, rhs] {
+                if let Some(is_integer) = self.has_tag(operand, TAG_INTEGER) {
+                    cond = Some(match cond {
+                        Some(c) => self.builder.ins().band(c, is_integer),
+                        None => is_integer,
+                    });
+                }
+            }
+            match cond {
+                Some(cond) => {
+                    self.builder
+                        .ins()
+                        .brif(cond, integer_block, &[], float_block, &[]);
+                }
+                None => {
+                    self.builder.ins().jump(integer_block, &[]);
+                }
+            }
+
+            self.builder.switch_to_block(integer_block);
+            let l = self.integer_operand(lhs);
+            let r = self.integer_operand(rhs);
+            let x = match op {
+                ArithmeticOp::Add => self.builder.ins().iadd(l, r),
+                ArithmeticOp::Sub => self.builder.ins().isub(l, r),
+                ArithmeticOp::Mul => self.builder.ins().imul(l, r),
+                ArithmeticOp::Mod => match self.integer_mod(pc, l, r, rhs) {
+                    Some(x) => x,
+                    None => return,
+                },
+                ArithmeticOp::Div => unreachable!(),
+            };
+            self.store_integer(a, x);
+            self.jump(next);
+        } else {
+            self.builder.ins().jump(float_block, &[]);
+        }
+
+        self.builder.switch_to_block(float_block);
+        if matches!(op, ArithmeticOp::Mod) {
+            return self.exit(pc, true);
+        }
+        let compute_block = self.builder.create_block();
+        let mut cond = None;
+        for operand in [lhs, rhs] {
+            if let Operand::Register(r) = operand {
+                let tag = self.load_tag(r);
+                let is_integer = self.builder.ins().icmp_imm(IntCC::Equal, tag, TAG_INTEGER);
+                let is_number = self.builder.ins().icmp_imm(IntCC::Equal, tag, TAG_NUMBER);
+                let is_numeric = self.builder.ins().bor(is_integer, is_number);
+                cond = Some(match cond {
+                    Some(c) => self.builder.ins().band(c, is_numeric),
+                    None => is_numeric,
+                });
+            }
+        }
+        match cond {
+            Some(cond) => self.guard(cond, compute_block, pc),
+            None => {
+                self.builder.ins().jump(compute_block, &[]);
+            }
+        }
+
+        self.builder.switch_to_block(compute_block);
+        let l = self.float_operand(lhs);
+        let r = self.float_operand(rhs);
+        let x = match op {
+            ArithmeticOp::Add => self.builder.ins().fadd(l, r),
+            ArithmeticOp::Sub => self.builder.ins().fsub(l, r),
+            ArithmeticOp::Mul => self.builder.ins().fmul(l, r),
+            ArithmeticOp::Div => self.builder.ins().fdiv(l, r),
+            ArithmeticOp::Mod => unreachable!(),
+        };
+        self.store_number(a, x);
+        self.jump(next);
+    }
+
+    // Same as ops::modi. Divisors of 0 and -1 are left to the interpreter.
+    // Returns None if the current block was terminated.
+    fn integer_mod(
+        &mut self,
+        pc: usize,
+        m: cranelift_codegen::ir::Value,
+        n: cranelift_codegen::ir::Value,
+        divisor: Operand,
+    ) -> Option<cranelift_codegen::ir::Value> {
+        match divisor {
+            Operand::Integer(0 | -1) => {
+                self.exit(pc, false);
+                return None;
+            }
+            Operand::Register(_) => {
+                let is_zero = self.builder.ins().icmp_imm(IntCC::Equal, n, 0);
+                let is_minus_one = self.builder.ins().icmp_imm(IntCC::Equal, n, -1);
+                let is_special = self.builder.ins().bor(is_zero, is_minus_one);
+                let is_regular = self.builder.ins().bxor_imm(is_special, 1);
+                let compute_block = self.builder.create_block();
+                self.guard(is_regular, compute_block, pc);
+                self.builder.switch_to_block(compute_block);
+            }
+            _ => (),
+        }
+        let r = self.builder.ins().srem(m, n);
+        let is_nonzero = self.builder.ins().icmp_imm(IntCC::NotEqual, r, 0);
+        let signs = self.builder.ins().bxor(r, n);
+        let differ = self.builder.ins().icmp_imm(IntCC::SignedLessThan, signs, 0);
+        let adjust = self.builder.ins().band(is_nonzero, differ);
+        let adjusted = self.builder.ins().iadd(r, n);
+        Some(self.builder.ins().select(adjust, adjusted, r))
+    }
+
+    // Integers only, floats with an integral value are left to the interpreter
+    fn bitwise(&mut self, pc: usize, a: usize, b: usize, rhs: Operand, op: BitwiseOp) {
+        let compute_block = self.builder.create_block();
+        let lhs_tag = self.load_tag(b);
+        let mut cond = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::Equal, lhs_tag, TAG_INTEGER);
+        if let Some(is_integer) = self.has_tag(rhs, TAG_INTEGER) {
+            cond = self.builder.ins().band(cond, is_integer);
+        }
+        self.guard(cond, compute_block, pc);
+
+        self.builder.switch_to_block(compute_block);
+        let l = self.load_integer(b);
+        let r = self.integer_operand(rhs);
+        let x = match op {
+            BitwiseOp::And => self.builder.ins().band(l, r),
+            BitwiseOp::Or => self.builder.ins().bor(l, r),
+            BitwiseOp::Xor => self.builder.ins().bxor(l, r),
+        };
+        self.store_integer(a, x);
+        self.jump(pc + 2);
+    }
+
+    // Compares integers with integers and floats with floats or immediates.
+    // Anything else may need a metamethod or a mixed comparison, which the
+    // interpreter takes care of.
+    fn compare(
+        &mut self,
+        pc: usize,
+        insn: Instruction,
+        a: usize,
+        rhs: Operand,
+        comparison: Comparison,
+    ) {
+        let lhs_tag = self.load_tag(a);
+        let integer_block = self.builder.create_block();
+        let other_block = self.builder.create_block();
+        let float_block = self.builder.create_block();
+
+        let lhs_is_integer = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::Equal, lhs_tag, TAG_INTEGER);
+        let lhs_is_number = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::Equal, lhs_tag, TAG_NUMBER);
+        match rhs {
+            Operand::Register(r) => {
+                let rhs_tag = self.load_tag(r);
+                let same_tag = self.builder.ins().icmp(IntCC::Equal, lhs_tag, rhs_tag);
+                let both_integers = self.builder.ins().band(same_tag, lhs_is_integer);
+                self.builder
+                    .ins()
+                    .brif(both_integers, integer_block, &[], other_block, &[]);
+                self.builder.switch_to_block(other_block);
+                let both_numbers = self.builder.ins().band(same_tag, lhs_is_number);
+                self.guard(both_numbers, float_block, pc);
+            }
+            Operand::Integer(_) => {
+                self.builder
+                    .ins()
+                    .brif(lhs_is_integer, integer_block, &[], other_block, &[]);
+                self.builder.switch_to_block(other_block);
+                self.guard(lhs_is_number, float_block, pc);
+            }
+            Operand::Number(_) => {
+                self.builder.ins().jump(other_block, &[]);
+                self.builder.switch_to_block(other_block);
+                self.guard(lhs_is_number, float_block, pc);
+            }
+        }
+
+        self.builder.switch_to_block(integer_block);
+        if matches!(rhs, Operand::Number(_)) {
+            self.exit(pc, true);
+        } else {
+            let l = self.load_integer(a);
+            let r = self.integer_operand(rhs);
+            let cond = self.builder.ins().icmp(comparison.int_cc(), l, r);
+            self.conditional_jump(pc, insn, cond);
+        }
+
+        self.builder.switch_to_block(float_block);
+        if let Operand::Integer(i) = rhs {
+            // comparing with the converted integer would be inexact
+            if i.unsigned_abs() > 1 << Number::MANTISSA_DIGITS {
+                return self.exit(pc, true);
+            }
+        }
+        let l = self.load_number(a);
+        let r = self.float_operand(rhs);
+        let cond = self.builder.ins().fcmp(comparison.float_cc(), l, r);
+        self.conditional_jump(pc, insn, cond);
+    }
+
+    fn for_loop(&mut self, pc: usize, insn: Instruction) {
+        let a = insn.a();
+        let next = pc + 1;
+        let check_block = self.builder.create_block();
+        let continue_block = self.builder.create_block();
+
+        let step_tag = self.load_tag(a + 2);
+        let is_integer = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::Equal, step_tag, TAG_INTEGER);
+        self.guard(is_integer, check_block, pc);
+
+        self.builder.switch_to_block(check_block);
+        let count = self.load_integer(a + 1);
+        let has_next = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::SignedGreaterThan, count, 0);
+        let done_block = self.target_block(next);
+        self.builder
+            .ins()
+            .brif(has_next, continue_block, &[], done_block, &[]);
+
+        self.builder.switch_to_block(continue_block);
+        let count = self.builder.ins().iadd_imm(count, -1);
+        self.store_integer(a + 1, count);
+        let index = self.load_integer(a);
+        let step = self.load_integer(a + 2);
+        let index = self.builder.ins().iadd(index, step);
+        self.store_integer(a, index);
+        self.store_integer(a + 3, index);
+        self.jump(next - insn.bx());
+    }
+
+    // Same as ops::do_conditional_jump
+    fn conditional_jump(
+        &mut self,
+        pc: usize,
+        insn: Instruction,
+        cond: cranelift_codegen::ir::Value,
+    ) {
+        let jmp = pc + 1;
+        let target = (jmp as isize + self.code[jmp].sj() as isize + 1) as usize;
+        let taken_block = self.builder.create_block();
+        let skip_block = self.target_block(pc + 2);
+        if insn.k() {
+            self.builder
+                .ins()
+                .brif(cond, taken_block, &[], skip_block, &[]);
+        } else {
+            self.builder
+                .ins()
+                .brif(cond, skip_block, &[], taken_block, &[]);
+        }
+        self.builder.switch_to_block(taken_block);
+        self.jump(target);
+    }
+
+    fn guard(&mut self, cond: cranelift_codegen::ir::Value, ok_block: Block, pc: usize) {
+        let exit = self
+            .builder
+            .ins()
+            .iconst(types::I64, (pc as u64 | DEOPT) as i64);
+        let exit_block = self.exit_block_for(pc);
+        self.builder
+            .ins()
+            .brif(cond, ok_block, &[], exit_block, &[exit]);
+    }
+
+    // Block of the instruction at `target`, or a block going back to the
+    // interpreter if there's no such instruction
+    fn target_block(&mut self, target: usize) -> Block {
+        if let Some(block) = self.blocks.get(target) {
+            return *block;
+        }
+        let current = self.builder.current_block().unwrap();
+        let block = self.builder.create_block();
+        self.builder.switch_to_block(block);
+        self.exit(target, false);
+        self.builder.switch_to_block(current);
+        block
+    }
+
+    fn jump(&mut self, target: usize) {
+        match self.blocks.get(target) {
+            Some(block) => {
+                self.builder.ins().jump(*block, &[]);
+            }
+            None => self.exit(target, false),
+        }
+    }
+
+    // Each instruction takes one from the budget, as in the interpreter, so
+    // that fuel and pauses are the same with and without compiled code.
+    // When it runs out, the interpreter takes over at the instruction, and
+    // takes it from the budget again.
+    fn charge_budget(&mut self, pc: usize) {
+        let flags = MemFlags::trusted();
+        let budget = self.builder.ins().load(types::I64, flags, self.budget, 0);
+        let budget = self.builder.ins().iadd_imm(budget, -1);
+        let exhausted = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::SignedLessThan, budget, 0);
+        let charge_block = self.builder.create_block();
+        let exhausted_block = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(exhausted, exhausted_block, &[], charge_block, &[]);
+
+        self.builder.switch_to_block(exhausted_block);
+        let exit = self.builder.ins().iconst(types::I64, pc as i64);
+        self.builder.ins().jump(self.exit_block, &[exit]);
+
+        self.builder.switch_to_block(charge_block);
+        self.builder.ins().store(flags, budget, self.budget, 0);
+    }
+
+    fn exit(&mut self, pc: usize, deopt: bool) {
+        let code = if deopt { pc as u64 | DEOPT } else { pc as u64 };
+        let exit = self.builder.ins().iconst(types::I64, code as i64);
+        let exit_block = self.exit_block_for(pc);
+        self.builder.ins().jump(exit_block, &[exit]);
+    }
+
+    // The interpreter takes the instruction at `pc` from the budget when it
+    // runs it, so it's given back if it's the one being translated
+    fn exit_block_for(&self, pc: usize) -> Block {
+        if pc == self.pc {
+            self.refund_block
+        } else {
+            self.exit_block
+        }
+    }
+
+    fn has_tag(&mut self, operand: Operand, tag: i64) -> Option<cranelift_codegen::ir::Value> {
+        match operand {
+            Operand::Register(r) => {
+                let t = self.load_tag(r);
+                Some(self.builder.ins().icmp_imm(IntCC::Equal, t, tag))
+            }
+            _ => None,
+        }
+    }
+
+    fn is_truthy(&mut self, r: usize) -> cranelift_codegen::ir::Value {
+        let tag = self.load_tag(r);
+        let payload = self.builder.ins().load(
+            types::I8,
+            MemFlags::trusted(),
+            self.stack,
+            register_offset(r) + PAYLOAD_OFFSET,
+        );
+        let is_nil = self.builder.ins().icmp_imm(IntCC::Equal, tag, TAG_NIL);
+        let is_boolean = self.builder.ins().icmp_imm(IntCC::Equal, tag, TAG_BOOLEAN);
+        let is_zero = self.builder.ins().icmp_imm(IntCC::Equal, payload, 0);
+        let is_false = self.builder.ins().band(is_boolean, is_zero);
+        let is_falsy = self.builder.ins().bor(is_nil, is_false);
+        self.builder.ins().bxor_imm(is_falsy, 1)
+    }
+
+    fn integer_operand(&mut self, operand: Operand) -> cranelift_codegen::ir::Value {
+        match operand {
+            Operand::Register(r) => self.load_integer(r),
+            Operand::Integer(i) => self.builder.ins().iconst(types::I64, i),
+            Operand::Number(_) => unreachable!(),
+        }
+    }
+
+    // Registers must hold an integer or a float
+    fn float_operand(&mut self, operand: Operand) -> cranelift_codegen::ir::Value {
+        match operand {
+            Operand::Register(r) => {
+                let tag = self.load_tag(r);
+                let is_integer = self.builder.ins().icmp_imm(IntCC::Equal, tag, TAG_INTEGER);
+                let i = self.load_integer(r);
+                let i = self.builder.ins().fcvt_from_sint(types::F64, i);
+                let x = self.load_number(r);
+                self.builder.ins().select(is_integer, i, x)
+            }
+            Operand::Integer(i) => self.builder.ins().f64const(i as Number),
+            Operand::Number(x) => self.builder.ins().f64const(x),
+        }
+    }
+
+    fn load_tag(&mut self, r: usize) -> cranelift_codegen::ir::Value {
+        self.builder.ins().load(
+            types::I8,
+            MemFlags::trusted(),
+            self.stack,
+            register_offset(r),
+        )
+    }
+
+    fn load_integer(&mut self, r: usize) -> cranelift_codegen::ir::Value {
+        self.builder.ins().load(
+            types::I64,
+            MemFlags::trusted(),
+            self.stack,
+            register_offset(r) + PAYLOAD_OFFSET,
+        )
+    }
+
+    fn load_number(&mut self, r: usize) -> cranelift_codegen::ir::Value {
+        self.builder.ins().load(
+            types::F64,
+            MemFlags::trusted(),
+            self.stack,
+            register_offset(r) + PAYLOAD_OFFSET,
+        )
+    }
+
+    fn store_tag(&mut self, r: usize, tag: i64) {
+        let tag = self.builder.ins().iconst(types::I8, tag);
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), tag, self.stack, register_offset(r));
+    }
+
+    fn store_integer(&mut self, r: usize, x: cranelift_codegen::ir::Value) {
+        self.store_tag(r, TAG_INTEGER);
+        self.builder.ins().store(
+            MemFlags::trusted(),
+            x,
+            self.stack,
+            register_offset(r) + PAYLOAD_OFFSET,
+        );
+    }
+
+    fn store_number(&mut self, r: usize, x: cranelift_codegen::ir::Value) {
+        self.store_tag(r, TAG_NUMBER);
+        self.builder.ins().store(
+            MemFlags::trusted(),
+            x,
+            self.stack,
+            register_offset(r) + PAYLOAD_OFFSET,
+        );
+    }
+
+    // `b` is an i8 that is either 0 or 1
+    fn store_boolean(&mut self, r: usize, b: cranelift_codegen::ir::Value) {
+        self.store_tag(r, TAG_BOOLEAN);
+        self.builder.ins().store(
+            MemFlags::trusted(),
+            b,
+            self.stack,
+            register_offset(r) + PAYLOAD_OFFSET,
+        );
+    }
+
+    fn store_boolean_const(&mut self, r: usize, b: bool) {
+        let b = self.builder.ins().iconst(types::I8, b as i64);
+        self.store_boolean(r, b);
+    }
+}
+
+fn register_offset(r: usize) -> i32 {
+    r as i32 * VALUE_SIZE
+}
+
+fn constant_operand(proto: &LuaClosureProto, index: usize) -> Option<Operand> {
+    match proto.constants.value(index) {
+        Value::Integer(i) => Some(Operand::Integer(i)),
+        Value::Number(x) => Some(Operand::Number(x)),
+        _ => None,
+    }
+}
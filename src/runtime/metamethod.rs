@@ -1,12 +1,13 @@
 use super::{
-    Action, Continuation, ContinuationFrame, ErrorKind, Frame, Instruction, Operation, Vm,
+    ops, Action, Continuation, ContinuationFrame, ErrorKind, Frame, Instruction, Operation, Vm,
 };
 use crate::{
-    gc::GcContext,
+    gc::{GcContext, MaybeSend},
+    prelude::*,
     types::{LuaString, LuaThread, Value},
 };
 use bstr::B;
-use std::ops::ControlFlow;
+use core::ops::ControlFlow;
 
 macro_rules! metamethods {
     ($($variant:ident => $name:tt,)*) => {
@@ -18,7 +19,7 @@ macro_rules! metamethods {
         impl Metamethod {
             pub const COUNT: usize = crate::count!($($variant)*);
 
-            pub fn allocate_names(gc: &GcContext) -> [LuaString; Self::COUNT] {
+            pub fn allocate_names(gc: &GcContext) -> [LuaString<'_>; Self::COUNT] {
                 [
                     $(gc.allocate_string(B($name)),)*
                 ]
@@ -102,13 +103,14 @@ impl<'gc> Vm<'gc> {
                     return Err(ErrorKind::TypeError {
                         operation: Operation::Index,
                         ty: table_like.ty(),
+                        description: None,
                     });
                 }
                 metamethod
             };
             match metamethod {
                 Value::NativeFunction(_) | Value::LuaClosure(_) | Value::NativeClosure(_) => {
-                    return Ok(self.push_metamethod_frame_with_continuation(
+                    return self.push_metamethod_frame_with_continuation(
                         thread,
                         metamethod,
                         &[table_like, key],
@@ -117,7 +119,7 @@ impl<'gc> Vm<'gc> {
                                 results.first().copied().unwrap_or_default();
                             Ok(Action::ReturnArguments)
                         },
-                    ));
+                    );
                 }
                 Value::Table(table) => {
                     let value = table.borrow().get(key);
@@ -150,11 +152,15 @@ impl<'gc> Vm<'gc> {
         let new_index_key = self.metamethod_name(Metamethod::NewIndex);
         for _ in 0..2000 {
             let metamethod = if let Value::Table(table) = table_like {
-                let metamethod = table
-                    .borrow()
-                    .metatable()
-                    .map(|metatable| metatable.borrow().get_field(new_index_key))
-                    .unwrap_or_default();
+                let metamethod = {
+                    let table = table.borrow();
+                    // __newindex isn't called for frozen tables either
+                    table.check_not_frozen()?;
+                    table
+                        .metatable()
+                        .map(|metatable| metatable.borrow().get_field(new_index_key))
+                        .unwrap_or_default()
+                };
                 if metamethod.is_nil() {
                     table.borrow_mut(gc).set(key, value)?;
                     return Ok(ControlFlow::Continue(()));
@@ -169,17 +175,18 @@ impl<'gc> Vm<'gc> {
                     return Err(ErrorKind::TypeError {
                         operation: Operation::Index,
                         ty: table_like.ty(),
+                        description: None,
                     });
                 }
                 metamethod
             };
             match metamethod {
                 Value::NativeFunction(_) | Value::LuaClosure(_) | Value::NativeClosure(_) => {
-                    return Ok(self.push_metamethod_frame(
+                    return self.push_metamethod_frame(
                         thread,
                         metamethod,
                         &[table_like, key, value.into()],
-                    ));
+                    );
                 }
                 Value::Table(table) => {
                     let value = table.borrow().get(key);
@@ -206,29 +213,71 @@ impl<'gc> Vm<'gc> {
         b: Value<'gc>,
         dest: usize,
     ) -> Result<ControlFlow<()>, ErrorKind> {
+        let is_bitwise = matches!(
+            metamethod,
+            Metamethod::BAnd
+                | Metamethod::BOr
+                | Metamethod::BXor
+                | Metamethod::Shl
+                | Metamethod::Shr
+                | Metamethod::BNot
+        );
+
+        // Strings are converted to numbers before looking for metamethods,
+        // except by bitwise operations, as the string metatable of the
+        // reference implementation only has arithmetic metamethods
+        let coerced = if is_bitwise {
+            (
+                a.to_integer_without_string_coercion().map(Value::Integer),
+                b.to_integer_without_string_coercion().map(Value::Integer),
+            )
+        } else {
+            (a.coerce_to_number(), b.coerce_to_number())
+        };
+        if let (Some(x), Some(y)) = coerced {
+            if let (Value::Integer(_), Value::Integer(0)) = (x, y) {
+                match metamethod {
+                    Metamethod::IDiv => return Err(ErrorKind::other("attempt to perform 'n//0'")),
+                    Metamethod::Mod => return Err(ErrorKind::other("attempt to perform 'n%0'")),
+                    _ => (),
+                }
+            }
+            if let Some(result) = ops::arithmetic_on_numbers(metamethod, x, y) {
+                thread.stack[dest] = result;
+                return Ok(ControlFlow::Continue(()));
+            }
+        }
+
         let metamethod_value = self
             .metamethod_of_object(metamethod, a)
             .or_else(|| self.metamethod_of_object(metamethod, b));
         let metamethod_value = match metamethod_value {
             Some(value) => value,
             None => {
-                let operation = match metamethod {
-                    Metamethod::BAnd
-                    | Metamethod::BOr
-                    | Metamethod::BXor
-                    | Metamethod::Shl
-                    | Metamethod::Shr
-                    | Metamethod::BNot => Operation::BitwiseOp,
-                    _ => Operation::Arithmetic,
+                let operation = if is_bitwise {
+                    if a.to_number_without_string_coercion().is_some()
+                        && b.to_number_without_string_coercion().is_some()
+                    {
+                        return Err(ErrorKind::other("number has no integer representation"));
+                    }
+                    Operation::BitwiseOp
+                } else {
+                    Operation::Arithmetic
+                };
+                // Blame the first operand that can't be converted
+                let convertible = match operation {
+                    Operation::BitwiseOp => a.to_number_without_string_coercion().is_some(),
+                    _ => a.coerce_to_float().is_some(),
                 };
                 return Err(ErrorKind::TypeError {
                     operation,
-                    ty: b.ty(),
+                    ty: if convertible { b.ty() } else { a.ty() },
+                    description: None,
                 });
             }
         };
 
-        Ok(self.push_metamethod_frame_with_continuation(
+        self.push_metamethod_frame_with_continuation(
             thread,
             metamethod_value,
             &[a, b],
@@ -237,7 +286,7 @@ impl<'gc> Vm<'gc> {
                     results.first().copied().unwrap_or_default();
                 Ok(Action::ReturnArguments)
             },
-        ))
+        )
     }
 
     pub(super) fn compare_slow_path(
@@ -255,17 +304,18 @@ impl<'gc> Vm<'gc> {
             .ok_or_else(|| ErrorKind::TypeError {
                 operation: Operation::Compare,
                 ty: b.ty(),
+                description: None,
             })?;
 
         let insn = code[pc - 1];
         let next_insn = code[pc];
 
-        Ok(self.push_metamethod_frame_with_continuation(
+        self.push_metamethod_frame_with_continuation(
             thread,
             metamethod,
             &[a, b],
             move |gc, vm, results| {
-                let cond = results.first().map(Value::to_boolean).unwrap_or_default();
+                let cond = results.first().map(Value::is_truthy).unwrap_or_default();
                 let new_pc = if cond == insn.k() {
                     (pc as isize + next_insn.sj() as isize + 1) as usize
                 } else {
@@ -274,7 +324,7 @@ impl<'gc> Vm<'gc> {
                 vm.current_thread().borrow_mut(gc).save_pc(new_pc);
                 Ok(Action::ReturnArguments)
             },
-        ))
+        )
     }
 
     pub(super) fn len_slow_path(
@@ -288,9 +338,10 @@ impl<'gc> Vm<'gc> {
             .ok_or_else(|| ErrorKind::TypeError {
                 operation: Operation::Length,
                 ty: value.ty(),
+                description: None,
             })?;
 
-        Ok(self.push_metamethod_frame_with_continuation(
+        self.push_metamethod_frame_with_continuation(
             thread,
             metamethod,
             &[value, value],
@@ -299,7 +350,7 @@ impl<'gc> Vm<'gc> {
                     results.first().copied().unwrap_or_default();
                 Ok(Action::ReturnArguments)
             },
-        ))
+        )
     }
 
     pub(super) fn concat_slow_path<R>(
@@ -319,10 +370,14 @@ impl<'gc> Vm<'gc> {
             .or_else(|| self.metamethod_of_object(Metamethod::Concat, rhs))
             .ok_or_else(|| ErrorKind::TypeError {
                 operation: Operation::Concatenate,
-                ty: rhs.ty(),
+                ty: match lhs {
+                    Value::String(_) | Value::Number(_) | Value::Integer(_) => rhs.ty(),
+                    _ => lhs.ty(),
+                },
+                description: None,
             })?;
 
-        Ok(self.push_metamethod_frame_with_continuation(
+        self.push_metamethod_frame_with_continuation(
             thread,
             metamethod,
             &[lhs, rhs],
@@ -337,17 +392,19 @@ impl<'gc> Vm<'gc> {
                     return Ok(Action::ReturnArguments);
                 }
 
-                let s = match concatenated.to_string() {
+                let float_format = vm.float_format;
+                let s = match concatenated.coerce_to_string_with(float_format) {
                     Some(s) => s,
                     None => {
-                        vm.concat_slow_path(&mut thread, lhs_index - 1, concatenated, dest)?;
+                        let _ =
+                            vm.concat_slow_path(&mut thread, lhs_index - 1, concatenated, dest)?;
                         return Ok(Action::ReturnArguments);
                     }
                 };
 
                 let mut strings = vec![s];
                 for (i, value) in stack[dest..].iter().take(lhs_index).enumerate().rev() {
-                    if let Some(string) = value.to_string() {
+                    if let Some(string) = value.coerce_to_string_with(float_format) {
                         strings.push(string);
                         continue;
                     }
@@ -359,39 +416,38 @@ impl<'gc> Vm<'gc> {
                             (i, gc.allocate_string(strings.concat()).into())
                         }
                     };
-                    vm.concat_slow_path(&mut thread, lhs_index, rhs, dest)?;
+                    let _ = vm.concat_slow_path(&mut thread, lhs_index, rhs, dest)?;
                     return Ok(Action::ReturnArguments);
                 }
                 strings.reverse();
                 stack[dest] = gc.allocate_string(strings.concat()).into();
                 Ok(Action::ReturnArguments)
             },
-        ))
+        )
     }
 
-    #[must_use]
     pub(super) fn push_metamethod_frame(
         &self,
         thread: &mut LuaThread<'gc>,
         metamethod: Value<'gc>,
         args: &[Value<'gc>],
-    ) -> ControlFlow<()> {
+    ) -> Result<ControlFlow<()>, ErrorKind> {
         let metamethod_bottom = thread.stack.len();
         thread.stack.push(metamethod);
         thread.stack.extend_from_slice(args);
-        self.push_frame(thread, metamethod_bottom).unwrap()
+        self.push_frame(thread, metamethod_bottom)
     }
 
-    #[must_use]
     pub(super) fn push_metamethod_frame_with_continuation<F>(
         &self,
         thread: &mut LuaThread<'gc>,
         metamethod: Value<'gc>,
         args: &[Value<'gc>],
         continuation: F,
-    ) -> ControlFlow<()>
+    ) -> Result<ControlFlow<()>, ErrorKind>
     where
         F: 'static
+            + MaybeSend
             + Fn(&'gc GcContext, &mut Vm<'gc>, Vec<Value<'gc>>) -> Result<Action<'gc>, ErrorKind>,
     {
         let current_bottom = match thread.frames.as_slice() {
@@ -399,6 +455,9 @@ impl<'gc> Vm<'gc> {
             _ => unreachable!(),
         };
         let metamethod_bottom = thread.stack.len();
+        // Checked before the continuation is pushed, so that the error is
+        // raised in the calling frame
+        super::check_stack(metamethod_bottom)?;
         thread.stack.push(metamethod);
         thread.stack.extend_from_slice(args);
         thread.frames.push(Frame::CallContinuation {
@@ -408,6 +467,6 @@ impl<'gc> Vm<'gc> {
             },
             callee_bottom: metamethod_bottom,
         });
-        self.push_frame(thread, metamethod_bottom).unwrap()
+        self.push_frame(thread, metamethod_bottom)
     }
 }
@@ -12,8 +12,8 @@ macro_rules! opcodes {
             }
         }
 
-        impl std::fmt::Display for OpCode {
-            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        impl core::fmt::Display for OpCode {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
                 let s = match self {
                     $(Self::$variant => stringify!($name),)*
                 };
@@ -21,6 +21,31 @@ macro_rules! opcodes {
             }
         }
 
+        // Instructions are logged under a target per opcode, like
+        // mochi::vm::GETTABUP, to pick out the ones of interest
+        #[cfg(feature = "trace")]
+        impl OpCode {
+            pub(super) fn trace(
+                self,
+                pc: usize,
+                operands: &dyn core::fmt::Display,
+                ra: &dyn core::fmt::Display,
+                registers: usize,
+            ) {
+                match self {
+                    $(Self::$variant => tracing::trace!(
+                        target: concat!("mochi::vm::", stringify!($name)),
+                        pc,
+                        operands = %operands,
+                        ra = %ra,
+                        registers,
+                        "{}",
+                        stringify!($name),
+                    ),)*
+                }
+            }
+        }
+
         $(
             #[allow(dead_code)]
             pub const $name: u32 = OpCode::$variant as u32;
@@ -28,8 +53,8 @@ macro_rules! opcodes {
     }
 }
 
-impl std::fmt::Debug for OpCode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for OpCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{self}")
     }
 }
@@ -128,6 +153,7 @@ impl OpCode {
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum OpMode {
     ABC,
     ABx,
@@ -1,16 +1,28 @@
-use super::{ErrorKind, Instruction};
+use super::{ErrorKind, Instruction, Metamethod};
+#[cfg(not(feature = "std"))]
+#[allow(unused_imports)]
+use crate::math::Float;
 use crate::{
     number_is_valid_integer,
-    types::{Integer, Number, Value},
+    types::{Constants, Integer, Number, Value},
 };
+use core::ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Sub};
 
-fn arithmetic<'gc, I, F>(a: Value<'gc>, b: Value<'gc>, int_op: I, float_op: F) -> Option<Value<'gc>>
+// Integer operations that can fail, like division by zero, return None to
+// leave the operation to the slow path, which raises the error
+fn arithmetic<'gc, I, R, F>(
+    a: Value<'gc>,
+    b: Value<'gc>,
+    int_op: I,
+    float_op: F,
+) -> Option<Value<'gc>>
 where
-    I: Fn(Integer, Integer) -> Integer,
+    I: Fn(Integer, Integer) -> R,
+    R: Into<Option<Integer>>,
     F: Fn(Number, Number) -> Number,
 {
     if let (Value::Integer(a), Value::Integer(b)) = (a, b) {
-        return Some(Value::Integer(int_op(a, b)));
+        return int_op(a, b).into().map(Value::Integer);
     }
     if let (Some(a), Some(b)) = (
         a.to_number_without_string_coercion(),
@@ -21,6 +33,44 @@ where
     None
 }
 
+// Arithmetic on operands that are already numbers, for the slow path after
+// string coercion. Bitwise operands must be integers
+pub(super) fn arithmetic_on_numbers<'gc>(
+    metamethod: Metamethod,
+    a: Value<'gc>,
+    b: Value<'gc>,
+) -> Option<Value<'gc>> {
+    let float_op = |op: fn(Number, Number) -> Number| {
+        Some(Value::Number(op(
+            a.to_number_without_string_coercion()?,
+            b.to_number_without_string_coercion()?,
+        )))
+    };
+    let bitwise_op = |op: fn(Integer, Integer) -> Integer| {
+        Some(Value::Integer(op(
+            a.to_integer_without_string_coercion()?,
+            b.to_integer_without_string_coercion()?,
+        )))
+    };
+    match metamethod {
+        Metamethod::Add => arithmetic(a, b, Integer::wrapping_add, Number::add),
+        Metamethod::Sub => arithmetic(a, b, Integer::wrapping_sub, Number::sub),
+        Metamethod::Mul => arithmetic(a, b, Integer::wrapping_mul, Number::mul),
+        Metamethod::Mod => arithmetic(a, b, modi, modf),
+        Metamethod::IDiv => arithmetic(a, b, idivi, idivf),
+        Metamethod::Unm => arithmetic(a, b, |x, _| x.wrapping_neg(), |x, _| -x),
+        Metamethod::Pow => float_op(Number::powf),
+        Metamethod::Div => float_op(Number::div),
+        Metamethod::BAnd => bitwise_op(Integer::bitand),
+        Metamethod::BOr => bitwise_op(Integer::bitor),
+        Metamethod::BXor => bitwise_op(Integer::bitxor),
+        Metamethod::Shl => bitwise_op(shl),
+        Metamethod::Shr => bitwise_op(shr),
+        Metamethod::BNot => bitwise_op(|x, _| !x),
+        _ => None,
+    }
+}
+
 pub(super) fn compare_with_immediate<I, F>(
     a: Value,
     imm: i16,
@@ -38,14 +88,15 @@ where
     }
 }
 
-pub(super) fn do_arithmetic<I, F>(
+pub(super) fn do_arithmetic<I, R, F>(
     stack: &mut [Value],
     pc: &mut usize,
     insn: Instruction,
     int_op: I,
     float_op: F,
 ) where
-    I: Fn(Integer, Integer) -> Integer,
+    I: Fn(Integer, Integer) -> R,
+    R: Into<Option<Integer>>,
     F: Fn(Number, Number) -> Number,
 {
     let rb = stack[insn.b()];
@@ -56,19 +107,20 @@ pub(super) fn do_arithmetic<I, F>(
     }
 }
 
-pub(super) fn do_arithmetic_with_constant<'gc, I, F>(
+pub(super) fn do_arithmetic_with_constant<'gc, I, R, F>(
     stack: &mut [Value<'gc>],
     pc: &mut usize,
-    constants: &[Value<'gc>],
+    constants: &Constants<'gc>,
     insn: Instruction,
     int_op: I,
     float_op: F,
 ) where
-    I: Fn(Integer, Integer) -> Integer,
+    I: Fn(Integer, Integer) -> R,
+    R: Into<Option<Integer>>,
     F: Fn(Number, Number) -> Number,
 {
     let rb = stack[insn.b()];
-    let kc = constants[insn.c() as usize];
+    let kc = constants.value(insn.c() as usize);
     debug_assert!(matches!(kc, Value::Integer(_) | Value::Number(_)));
     if let Some(result) = arithmetic(rb, kc, int_op, float_op) {
         stack[insn.a()] = result;
@@ -124,14 +176,14 @@ pub(super) fn do_float_arithmetic<F>(
 pub(super) fn do_float_arithmetic_with_constant<'gc, F>(
     stack: &mut [Value<'gc>],
     pc: &mut usize,
-    constants: &[Value<'gc>],
+    constants: &Constants<'gc>,
     insn: Instruction,
     float_op: F,
 ) where
     F: Fn(Number, Number) -> Number,
 {
     let rb = stack[insn.b()];
-    let kc = constants[insn.c() as usize];
+    let kc = constants.value(insn.c() as usize);
     debug_assert!(matches!(kc, Value::Integer(_) | Value::Number(_)));
     if let (Some(b), Some(c)) = (
         rb.to_number_without_string_coercion(),
@@ -160,7 +212,7 @@ where
 pub(super) fn do_bitwise_op_with_constant<'gc, I>(
     stack: &mut [Value<'gc>],
     pc: &mut usize,
-    constants: &[Value<'gc>],
+    constants: &Constants<'gc>,
     insn: Instruction,
     int_op: I,
 ) where
@@ -168,7 +220,7 @@ pub(super) fn do_bitwise_op_with_constant<'gc, I>(
 {
     let rb = stack[insn.b()];
     if let Some(a) = rb.to_integer_without_string_coercion() {
-        stack[insn.a()] = match constants[insn.c() as usize] {
+        stack[insn.a()] = match constants.value(insn.c() as usize) {
             Value::Integer(b) => Value::Integer(int_op(a, b)),
             _ => unreachable!(),
         };
@@ -190,6 +242,7 @@ pub(super) fn do_conditional_jump(
     }
 }
 
+// Unlike arithmetic, 'for' doesn't convert strings to numbers
 pub(super) fn do_forprep(for_stack: &mut [Value]) -> Result<bool, ErrorKind> {
     let [init_value, limit_value, step_value, control_variable]: &mut [_; 4] =
         (&mut for_stack[..4]).try_into().unwrap();
@@ -201,10 +254,10 @@ pub(super) fn do_forprep(for_stack: &mut [Value]) -> Result<bool, ErrorKind> {
 
         *control_variable = *init_value;
 
-        let limit = match limit_value.to_integer() {
+        let limit = match limit_value.to_integer_without_string_coercion() {
             Some(l) => l,
             None => {
-                let float_limit = match limit_value.to_number() {
+                let float_limit = match limit_value.to_number_without_string_coercion() {
                     Some(l) => l,
                     None => {
                         return Err(ErrorKind::ForError {
@@ -251,18 +304,24 @@ pub(super) fn do_forprep(for_stack: &mut [Value]) -> Result<bool, ErrorKind> {
         return Ok(true);
     }
 
-    let limit = limit_value.to_number().ok_or(ErrorKind::ForError {
-        what: "limit",
-        got_type: limit_value.ty().name(),
-    })?;
-    let step = step_value.to_number().ok_or(ErrorKind::ForError {
-        what: "step",
-        got_type: step_value.ty().name(),
-    })?;
-    let init = init_value.to_number().ok_or(ErrorKind::ForError {
-        what: "initial value",
-        got_type: init_value.ty().name(),
-    })?;
+    let limit = limit_value
+        .to_number_without_string_coercion()
+        .ok_or(ErrorKind::ForError {
+            what: "limit",
+            got_type: limit_value.ty().name(),
+        })?;
+    let step = step_value
+        .to_number_without_string_coercion()
+        .ok_or(ErrorKind::ForError {
+            what: "step",
+            got_type: step_value.ty().name(),
+        })?;
+    let init = init_value
+        .to_number_without_string_coercion()
+        .ok_or(ErrorKind::ForError {
+            what: "initial value",
+            got_type: init_value.ty().name(),
+        })?;
     if step == 0.0 {
         return Err(ErrorKind::other("'for' step is zero"));
     }
@@ -284,16 +343,17 @@ pub(super) fn do_forprep(for_stack: &mut [Value]) -> Result<bool, ErrorKind> {
     Ok(true)
 }
 
-pub(super) fn idivi(m: Integer, n: Integer) -> Integer {
+// None when dividing by zero
+pub(super) fn idivi(m: Integer, n: Integer) -> Option<Integer> {
     match n {
-        0 => todo!("attempt to divide by zero"),
-        -1 => m.wrapping_neg(),
+        0 => None,
+        -1 => Some(m.wrapping_neg()),
         _ => {
             let q = m / n;
             if m ^ n < 0 && m % n != 0 {
-                q - 1
+                Some(q - 1)
             } else {
-                q
+                Some(q)
             }
         }
     }
@@ -303,16 +363,17 @@ pub(super) fn idivf(m: Number, n: Number) -> Number {
     (m / n).floor()
 }
 
-pub(super) fn modi(m: Integer, n: Integer) -> Integer {
+// None when dividing by zero
+pub(super) fn modi(m: Integer, n: Integer) -> Option<Integer> {
     match n {
-        0 => todo!("attempt to perform 'n%0'"),
-        -1 => 0,
+        0 => None,
+        -1 => Some(0),
         _ => {
             let r = m % n;
             if r != 0 && r ^ n < 0 {
-                r + n
+                Some(r + n)
             } else {
-                r
+                Some(r)
             }
         }
     }
@@ -0,0 +1,314 @@
+use super::{
+    instruction::{OFFSET_SBX, OFFSET_SJ, UINT17_MAX},
+    opcode, ops, CodeRewriter, Instruction,
+};
+use crate::{
+    gc::GcContext,
+    prelude::*,
+    types::{Integer, LuaClosureProto, Number, Value},
+};
+
+// Rewrites compiled code of a proto and its nested protos to do less work:
+// - arithmetic on a number loaded right before it is done at compile time
+// - LOADK of numbers that fit in the instruction becomes LOADI or LOADF
+// - jumps to jumps go straight to the final target
+// - LOADNILs of registers that are overwritten before being read are removed
+pub fn optimize<'gc>(gc: &'gc GcContext, proto: &mut LuaClosureProto<'gc>) {
+    let mut protos = proto.protos.to_vec();
+    for child in &mut protos {
+        let mut new_child = LuaClosureProto::clone(child);
+        optimize(gc, &mut new_child);
+        *child = gc.allocate(new_child);
+    }
+    proto.protos = protos.into_boxed_slice();
+
+    // Folding comes first, as it leaves loads of constants behind
+    let mut rewriter = CodeRewriter::new();
+    fold_constants(proto, &mut rewriter);
+    let result = rewriter.apply(proto);
+    debug_assert!(result.is_ok(), "{result:?}");
+
+    let mut rewriter = CodeRewriter::new();
+    shorten_loads(proto, &mut rewriter);
+    shorten_jump_chains(proto, &mut rewriter);
+    remove_dead_loadnils(proto, &mut rewriter);
+    let result = rewriter.apply(proto);
+    debug_assert!(result.is_ok(), "{result:?}");
+}
+
+fn fold_constants(proto: &mut LuaClosureProto, rewriter: &mut CodeRewriter) {
+    let code = &proto.code;
+    let is_target = jump_targets(code);
+    let mut constants: Vec<_> = proto.constants.iter().collect();
+    let constants_len = constants.len();
+
+    let mut pc = 0;
+    while pc < code.len() {
+        let load = code[pc];
+        let Some(mut value) = loaded_number(load, &constants) else {
+            pc += 1;
+            continue;
+        };
+
+        // The longest run of operations on the register that can be folded
+        // into a single load
+        let mut end = pc + 1;
+        while let Some(insn) = code.get(end).filter(|_| !is_target[end]) {
+            if insn.a() != load.a() || insn.b() != load.a() {
+                break;
+            }
+            // Arithmetic is followed by its MMBIN, which is removed with it
+            let len = match insn.raw_opcode() {
+                opcode::UNM | opcode::BNOT => 1,
+                _ if !is_target[end + 1]
+                    && code.get(end + 1).is_some_and(|next| {
+                        matches!(
+                            next.raw_opcode(),
+                            opcode::MMBIN | opcode::MMBINI | opcode::MMBINK
+                        )
+                    }) =>
+                {
+                    2
+                }
+                _ => break,
+            };
+            let Some(result) = fold(*insn, value, &constants) else {
+                break;
+            };
+            value = result;
+            end += len;
+        }
+
+        if end > pc + 1 {
+            if let Some(insn) = load_number(load.a(), value, &mut constants) {
+                rewriter.replace(pc, insn);
+                for pc in pc + 1..end {
+                    rewriter.remove(pc);
+                }
+            }
+        }
+        pc = end;
+    }
+
+    if constants.len() > constants_len {
+        proto.constants = constants.into();
+    }
+}
+
+fn shorten_loads(proto: &LuaClosureProto, rewriter: &mut CodeRewriter) {
+    for (pc, insn) in proto.code.iter().enumerate() {
+        if insn.raw_opcode() != opcode::LOADK {
+            continue;
+        }
+        let value = proto.constants.value(insn.bx());
+        if let Some(insn) = load_immediate(insn.a(), value) {
+            rewriter.replace(pc, insn);
+        }
+    }
+}
+
+fn shorten_jump_chains(proto: &LuaClosureProto, rewriter: &mut CodeRewriter) {
+    let code = &proto.code;
+    let jump_target = |pc: usize| {
+        let insn = code
+            .get(pc)
+            .filter(|insn| insn.raw_opcode() == opcode::JMP)?;
+        usize::try_from(pc as isize + 1 + insn.sj() as isize).ok()
+    };
+    for pc in 0..code.len() {
+        let Some(first) = jump_target(pc) else {
+            continue;
+        };
+        // Bounded, since jumps may go around in circles
+        let mut target = first;
+        for _ in 0..code.len() {
+            match jump_target(target) {
+                Some(next) if next != target => target = next,
+                _ => break,
+            }
+        }
+        if target != first {
+            rewriter.replace(pc, jump(target as isize - (pc as isize + 1)));
+        }
+    }
+}
+
+fn remove_dead_loadnils(proto: &LuaClosureProto, rewriter: &mut CodeRewriter) {
+    let code = &proto.code;
+    for (pc, insn) in code.iter().enumerate() {
+        if insn.raw_opcode() != opcode::LOADNIL || insn.b() != 0 {
+            continue;
+        }
+        let reg = insn.a();
+        // Only instructions that read and write known registers are looked
+        // through
+        for next in &code[pc + 1..] {
+            let (writes, reads) = match next.raw_opcode() {
+                opcode::LOADI
+                | opcode::LOADF
+                | opcode::LOADK
+                | opcode::LOADFALSE
+                | opcode::LOADTRUE
+                | opcode::GETUPVAL => (next.a() == reg, false),
+                opcode::MOVE => (next.a() == reg, next.b() == reg),
+                opcode::LOADNIL => ((next.a()..=next.a() + next.b()).contains(&reg), false),
+                _ => break,
+            };
+            if reads {
+                break;
+            }
+            if writes {
+                rewriter.remove(pc);
+                break;
+            }
+        }
+    }
+}
+
+// Instructions that can be reached other than from the instruction before.
+// Arithmetic skipping its MMBIN counts as going to the next instruction.
+fn jump_targets(code: &[Instruction]) -> Vec<bool> {
+    let mut is_target = vec![false; code.len() + 1];
+    let mut mark = |target: isize| {
+        if let Some(is_target) = usize::try_from(target)
+            .ok()
+            .and_then(|target| is_target.get_mut(target))
+        {
+            *is_target = true;
+        }
+    };
+    for (pc, insn) in code.iter().enumerate() {
+        let next = pc as isize + 1;
+        match insn.raw_opcode() {
+            opcode::JMP => mark(next + insn.sj() as isize),
+            opcode::FORPREP => mark(next + insn.bx() as isize + 1),
+            opcode::TFORPREP => mark(next + insn.bx() as isize),
+            opcode::FORLOOP | opcode::TFORLOOP => mark(next - insn.bx() as isize),
+            opcode::LFALSESKIP => mark(next + 1),
+            _ if insn.opcode().modes().test => mark(next + 1),
+            _ => (),
+        }
+    }
+    is_target
+}
+
+fn loaded_number<'gc>(insn: Instruction, constants: &[Value<'gc>]) -> Option<Value<'gc>> {
+    match insn.raw_opcode() {
+        opcode::LOADI => Some(Value::Integer(insn.sbx() as Integer)),
+        opcode::LOADF => Some(Value::Number(insn.sbx() as Number)),
+        opcode::LOADK => {
+            let value = constants[insn.bx()];
+            matches!(value, Value::Integer(_) | Value::Number(_)).then_some(value)
+        }
+        _ => None,
+    }
+}
+
+// The result of an operation on a register holding `value`, as the
+// interpreter computes it, or None if it would raise an error or need a
+// metamethod
+fn fold<'gc>(insn: Instruction, value: Value<'gc>, constants: &[Value<'gc>]) -> Option<Value<'gc>> {
+    let arithmetic = |rhs: Value,
+                      int_op: fn(Integer, Integer) -> Option<Integer>,
+                      float_op: fn(Number, Number) -> Number| {
+        match (value, rhs) {
+            (Value::Integer(a), Value::Integer(b)) => int_op(a, b).map(Value::Integer),
+            _ => Some(Value::Number(float_op(
+                value.to_number_without_string_coercion()?,
+                rhs.to_number_without_string_coercion()?,
+            ))),
+        }
+    };
+    let float_arithmetic = |rhs: Value, float_op: fn(Number, Number) -> Number| {
+        Some(Value::Number(float_op(
+            value.to_number_without_string_coercion()?,
+            rhs.to_number_without_string_coercion()?,
+        )))
+    };
+    let bitwise = |rhs: Value, int_op: fn(Integer, Integer) -> Integer| {
+        Some(Value::Integer(int_op(
+            value.to_integer_without_string_coercion()?,
+            rhs.to_integer_without_string_coercion()?,
+        )))
+    };
+    let constant = || constants[insn.c() as usize];
+    let immediate = || Value::Integer(insn.sc() as Integer);
+
+    let result = match insn.raw_opcode() {
+        opcode::ADDI => arithmetic(immediate(), |a, b| Some(a.wrapping_add(b)), |a, b| a + b)?,
+        opcode::ADDK => arithmetic(constant(), |a, b| Some(a.wrapping_add(b)), |a, b| a + b)?,
+        opcode::SUBK => arithmetic(constant(), |a, b| Some(a.wrapping_sub(b)), |a, b| a - b)?,
+        opcode::MULK => arithmetic(constant(), |a, b| Some(a.wrapping_mul(b)), |a, b| a * b)?,
+        opcode::MODK => arithmetic(constant(), ops::modi, ops::modf)?,
+        opcode::POWK => float_arithmetic(constant(), Number::powf)?,
+        opcode::DIVK => float_arithmetic(constant(), |a, b| a / b)?,
+        opcode::IDIVK => arithmetic(constant(), ops::idivi, ops::idivf)?,
+        opcode::BANDK => bitwise(constant(), |a, b| a & b)?,
+        opcode::BORK => bitwise(constant(), |a, b| a | b)?,
+        opcode::BXORK => bitwise(constant(), |a, b| a ^ b)?,
+        opcode::SHRI => bitwise(immediate(), |a, b| ops::shl(a, b.wrapping_neg()))?,
+        opcode::SHLI => bitwise(immediate(), |a, b| ops::shl(b, a))?,
+        opcode::UNM => match value {
+            Value::Integer(x) => Value::Integer(x.wrapping_neg()),
+            _ => Value::Number(-value.to_number_without_string_coercion()?),
+        },
+        opcode::BNOT => Value::Integer(!value.to_integer_without_string_coercion()?),
+        _ => return None,
+    };
+    // Like the reference implementation, NaN and zero floats aren't folded,
+    // so that -0.0 isn't mistaken for 0.0 when constants are compared
+    match result {
+        Value::Number(x) if x.is_nan() || x == 0.0 => None,
+        _ => Some(result),
+    }
+}
+
+// An instruction that loads `value` into register `a`, adding it to the
+// constants if it doesn't fit in the instruction
+fn load_number<'gc>(
+    a: usize,
+    value: Value<'gc>,
+    constants: &mut Vec<Value<'gc>>,
+) -> Option<Instruction> {
+    if let Some(insn) = load_immediate(a, value) {
+        return Some(insn);
+    }
+    let is_same = |constant: &Value| match (constant, value) {
+        (Value::Integer(x), Value::Integer(y)) => *x == y,
+        (Value::Number(x), Value::Number(y)) => x.to_bits() == y.to_bits(),
+        _ => false,
+    };
+    let index = match constants.iter().position(is_same) {
+        Some(index) => index,
+        None => {
+            constants.push(value);
+            constants.len() - 1
+        }
+    };
+    (index <= UINT17_MAX as usize).then_some(Instruction(
+        opcode::LOADK | (a as u32) << 7 | (index as u32) << 15,
+    ))
+}
+
+fn load_immediate(a: usize, value: Value) -> Option<Instruction> {
+    let (op, sbx) = match value {
+        Value::Integer(i) => (opcode::LOADI, i),
+        Value::Number(x) => {
+            let i = x as Integer;
+            if (i as Number).to_bits() != x.to_bits() {
+                return None;
+            }
+            (opcode::LOADF, i)
+        }
+        _ => return None,
+    };
+    let bx = sbx
+        .checked_add(OFFSET_SBX as Integer)
+        .and_then(|bx| u32::try_from(bx).ok())
+        .filter(|bx| *bx <= UINT17_MAX)?;
+    Some(Instruction(op | (a as u32) << 7 | bx << 15))
+}
+
+fn jump(sj: isize) -> Instruction {
+    Instruction(opcode::JMP | ((sj + OFFSET_SJ as isize) as u32) << 7)
+}
@@ -0,0 +1,27 @@
+use super::{opcode, Instruction, OpCode};
+use std::{any::Any, cell::Cell};
+
+thread_local! {
+    static LAST_INSTRUCTION: Cell<Option<(usize, Instruction)>> = const { Cell::new(None) };
+}
+
+pub(super) fn record_instruction(pc: usize, insn: Instruction) {
+    LAST_INSTRUCTION.with(|last| last.set(Some((pc, insn))));
+}
+
+pub(super) fn take_last_instruction() -> Option<(usize, Option<OpCode>)> {
+    LAST_INSTRUCTION.with(|last| last.take()).map(|(pc, insn)| {
+        let opcode = (insn.raw_opcode() <= opcode::EXTRAARG).then(|| insn.opcode());
+        (pc, opcode)
+    })
+}
+
+pub(super) fn message_from_payload(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        (*s).to_owned()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
@@ -0,0 +1,180 @@
+use super::{opcode, Vm};
+use crate::{
+    binary_chunk,
+    chunk_cache::ChunkCache,
+    gc::{GcContext, GcHeap},
+    stdlib::package::{search_path, LUA_LSUBSEP},
+    types::LuaClosureProto,
+    CompileOptions,
+};
+use bstr::{ByteSlice, B};
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+impl<'gc> Vm<'gc> {
+    // Compiles the Lua modules `names` resolve to through package.path, and
+    // the modules they require with a literal name, on a pool of threads.
+    // Compiled chunks are kept as bytecode and instantiated on this thread
+    // when the module is actually required.
+    // Modules that fail to compile are skipped, so that require reports the
+    // error as usual. Does nothing on a single core.
+    pub fn prefetch_modules<I, S>(&mut self, gc: &'gc GcContext, names: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        if num_cpus() <= 1 {
+            return;
+        }
+        let Some(path) = self.package_path(gc) else {
+            return;
+        };
+
+        let mut seen = HashSet::new();
+        let mut queue: Vec<_> = names
+            .into_iter()
+            .filter_map(|name| search_path(name, &path, b".", LUA_LSUBSEP).ok())
+            .filter(|filename| {
+                !self.prefetched_chunks.contains_key(filename) && seen.insert(filename.clone())
+            })
+            .collect();
+
+        while !queue.is_empty() {
+            let compiled =
+                compile_in_parallel(&queue, &self.compile_options, self.chunk_cache.as_ref());
+            queue.clear();
+            for (filename, (bytes, requires)) in compiled {
+                self.prefetched_chunks.insert(filename, bytes);
+                for name in requires {
+                    if let Ok(filename) = search_path(name, &path, b".", LUA_LSUBSEP) {
+                        if !self.prefetched_chunks.contains_key(&filename)
+                            && seen.insert(filename.clone())
+                        {
+                            queue.push(filename);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Prefetches the modules `proto` requires with a literal name
+    pub fn prefetch_requires(&mut self, gc: &'gc GcContext, proto: &LuaClosureProto<'gc>) {
+        let mut names = Vec::new();
+        collect_requires(proto, &mut names);
+        if !names.is_empty() {
+            self.prefetch_modules(gc, names);
+        }
+    }
+
+    pub(crate) fn take_prefetched_chunk(&mut self, filename: &[u8]) -> Option<Vec<u8>> {
+        self.prefetched_chunks.remove(filename)
+    }
+
+    fn package_path(&self, gc: &'gc GcContext) -> Option<Vec<u8>> {
+        let loaded = self
+            .registry
+            .borrow()
+            .get_field(gc.allocate_string(crate::stdlib::LUA_LOADED_TABLE));
+        let package = loaded
+            .borrow_as_table()?
+            .get_field(gc.allocate_string(B("package")));
+        let path = package
+            .borrow_as_table()?
+            .get_field(gc.allocate_string(B("path")));
+        path.coerce_to_string().map(|path| path.to_vec())
+    }
+}
+
+fn num_cpus() -> usize {
+    std::thread::available_parallelism()
+        .map(usize::from)
+        .unwrap_or(1)
+}
+
+type CompiledModule = (Vec<u8>, Vec<Vec<u8>>);
+
+// Each worker compiles into a heap of its own and hands back the dumped
+// chunk, since protos cannot leave the heap they were allocated in.
+fn compile_in_parallel(
+    filenames: &[Vec<u8>],
+    options: &CompileOptions,
+    cache: Option<&ChunkCache>,
+) -> Vec<(Vec<u8>, CompiledModule)> {
+    let num_workers = num_cpus().min(filenames.len());
+    let next = AtomicUsize::new(0);
+    let compiled = Mutex::new(Vec::with_capacity(filenames.len()));
+
+    std::thread::scope(|scope| {
+        for _ in 0..num_workers {
+            scope.spawn(|| {
+                let mut heap = GcHeap::new();
+                while let Some(filename) = filenames.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    if let Some(module) = compile_module(&mut heap, filename, options, cache) {
+                        compiled.lock().unwrap().push((filename.clone(), module));
+                    }
+                }
+            });
+        }
+    });
+
+    compiled.into_inner().unwrap()
+}
+
+// Compiled the way require would compile the file itself
+fn compile_module(
+    heap: &mut GcHeap,
+    filename: &[u8],
+    options: &CompileOptions,
+    cache: Option<&ChunkCache>,
+) -> Option<CompiledModule> {
+    let path = filename.to_path().ok()?;
+    heap.with(|gc, _| {
+        let proto = match cache {
+            Some(cache) => cache.load_file(gc, path, options),
+            None => crate::load_file_with_options(gc, path, options),
+        }
+        .ok()?;
+        let mut requires = Vec::new();
+        collect_requires(&proto, &mut requires);
+        let mut bytes = Vec::new();
+        binary_chunk::dump(&mut bytes, &proto).ok()?;
+        Some((bytes, requires))
+    })
+}
+
+// Looks for `require "name"` calls, i.e.
+//   GETTABUP  A _ENV "require"
+//   LOADK     A+1 "name"
+//   CALL      A 2 C
+pub(crate) fn collect_requires(proto: &LuaClosureProto, names: &mut Vec<Vec<u8>>) {
+    for insns in proto.code.windows(3) {
+        let [get, arg, call] = insns else {
+            unreachable!()
+        };
+        if get.raw_opcode() != opcode::GETTABUP
+            || arg.raw_opcode() != opcode::LOADK
+            || call.raw_opcode() != opcode::CALL
+            || arg.a() != get.a() + 1
+            || call.a() != get.a()
+            || call.b() != 2
+        {
+            continue;
+        }
+        let is_require = proto
+            .constants
+            .get_string(get.c() as usize)
+            .is_some_and(|s| s.as_ref() == b"require");
+        if let (true, Some(name)) = (is_require, proto.constants.get_string(arg.bx())) {
+            names.push(name.to_vec());
+        }
+    }
+    for child in proto.protos.iter() {
+        collect_requires(child, names);
+    }
+}
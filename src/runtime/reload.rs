@@ -0,0 +1,127 @@
+use super::{ErrorKind, Vm};
+use crate::{
+    gc::{GcCell, GcContext},
+    prelude::*,
+    types::{Table, Upvalue, Value},
+    LuaClosure,
+};
+
+impl<'gc> Vm<'gc> {
+    // Runs module `name` again through require and returns its new value,
+    // which later requires return. If it fails, the previous value is kept.
+    //
+    // With `patch`, a table the module returned before is updated in place
+    // with the fields of the new one and stays in package.loaded, so code
+    // holding on to it sees the new functions. Those share the upvalues of
+    // the functions they replace that have the same names, which keeps state
+    // like counters and caches the module keeps in locals.
+    pub fn reload_module<N: AsRef<[u8]>>(
+        &mut self,
+        gc: &'gc GcContext,
+        name: N,
+        patch: bool,
+    ) -> Result<Value<'gc>, ErrorKind> {
+        let name = gc.allocate_string(name.as_ref());
+        let loaded = self
+            .registry
+            .borrow()
+            .get_field(gc.allocate_string(crate::stdlib::LUA_LOADED_TABLE));
+        let loaded = loaded
+            .as_table()
+            .ok_or_else(|| ErrorKind::other("package library is not loaded"))?;
+        let require = self
+            .globals
+            .borrow()
+            .get_field(gc.allocate_string(b"require".as_slice()));
+
+        let old = loaded.borrow().get_field(name);
+        loaded.borrow_mut(gc).set_field(name, Value::Nil);
+        let new = match self.call_value(gc, require, vec![name.into()]) {
+            Ok(results) => results.first().copied().unwrap_or_default(),
+            Err(err) => {
+                loaded.borrow_mut(gc).set_field(name, old);
+                return Err(err);
+            }
+        };
+        match (patch, old.as_table(), new.as_table()) {
+            (true, Some(old_table), Some(new_table)) if !GcCell::ptr_eq(&old_table, &new_table) => {
+                patch_table(gc, old_table, new_table);
+                loaded.borrow_mut(gc).set_field(name, old);
+                Ok(old)
+            }
+            _ => Ok(new),
+        }
+    }
+}
+
+fn patch_table<'gc>(
+    gc: &'gc GcContext,
+    old: GcCell<'gc, Table<'gc>>,
+    new: GcCell<'gc, Table<'gc>>,
+) {
+    let fields: Vec<_> = new.borrow().iter().collect();
+
+    // Upvalues of the new functions paired with the ones of the old
+    // functions under the same keys, by upvalue name
+    let mut joined: Vec<(GcCell<Upvalue>, GcCell<Upvalue>)> = Vec::new();
+    for (key, value) in &fields {
+        let old_value = old.borrow().get(*key);
+        let (Some(new_function), Some(old_function)) =
+            (value.as_lua_closure(), old_value.as_lua_closure())
+        else {
+            continue;
+        };
+        for (i, upvalue) in new_function.upvalues.iter().enumerate() {
+            let Some(name) = upvalue_name(new_function, i) else {
+                continue;
+            };
+            let old_upvalue = (0..old_function.upvalues.len())
+                .find(|j| upvalue_name(old_function, *j) == Some(name))
+                .map(|j| old_function.upvalues[j]);
+            if let Some(old_upvalue) = old_upvalue {
+                if !joined.iter().any(|(new, _)| GcCell::ptr_eq(new, upvalue)) {
+                    joined.push((*upvalue, old_upvalue));
+                }
+            }
+        }
+    }
+
+    let mut old = old.borrow_mut(gc);
+    let removed: Vec<_> = old
+        .iter()
+        .map(|(key, _)| key)
+        .filter(|key| new.borrow().get(*key).is_nil())
+        .collect();
+    for key in removed {
+        old.set(key, Value::Nil).unwrap();
+    }
+    let rejoined = |upvalue: &GcCell<'gc, Upvalue<'gc>>| {
+        joined
+            .iter()
+            .find(|(new, _)| GcCell::ptr_eq(new, upvalue))
+            .map(|(_, old)| *old)
+    };
+    for (key, value) in fields {
+        let value = match value.as_lua_closure() {
+            Some(function) if function.upvalues.iter().any(|u| rejoined(u).is_some()) => {
+                let upvalues = function
+                    .upvalues
+                    .iter()
+                    .map(|upvalue| rejoined(upvalue).unwrap_or(*upvalue))
+                    .collect();
+                let function = LuaClosure {
+                    proto: function.proto,
+                    upvalues,
+                };
+                gc.allocate(function).into()
+            }
+            _ => value,
+        };
+        old.set(key, value).unwrap();
+    }
+}
+
+fn upvalue_name<'a>(function: &'a LuaClosure, i: usize) -> Option<&'a [u8]> {
+    let names = function.proto.upvalue_names.as_ref()?;
+    Some(names.get(i)?.as_bytes())
+}
@@ -0,0 +1,250 @@
+use super::{
+    instruction::{OFFSET_SJ, UINT17_MAX, UINT25_MAX},
+    opcode, Instruction,
+};
+use crate::{
+    gc::GcContext,
+    prelude::*,
+    types::{InlineCaches, LocalVariable, LuaClosureProto},
+};
+use alloc::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RewriteError {
+    #[error("pc {0} is out of range")]
+    PcOutOfRange(usize),
+
+    #[error("cannot insert instructions before pc {0}")]
+    InvalidInsertionPoint(usize),
+
+    #[error("cannot remove the instruction at pc {0}")]
+    InvalidRemoval(usize),
+
+    #[error("jump at pc {0} is out of range after rewriting")]
+    JumpOutOfRange(usize),
+}
+
+// Inserts instructions into a proto while keeping jump targets, line info
+// and local variable ranges pointing at the original instructions.
+//
+// Instructions inserted before pc N are attributed to the instruction at N:
+// they run every time the instruction at N is about to run, including when
+// it is reached by a jump, and they share its line number.
+// Inserted instructions are not relocated, so jumps among them must stay
+// within the inserted sequence.
+//
+// Replaced instructions are relocated like the originals, and jumps to a
+// removed instruction go to the instruction after it.
+#[derive(Debug, Default, Clone)]
+pub struct CodeRewriter {
+    insertions: BTreeMap<usize, Vec<Instruction>>,
+    replacements: BTreeMap<usize, Instruction>,
+    removals: BTreeSet<usize>,
+    max_stack_size: u8,
+}
+
+impl CodeRewriter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn insert_before<I>(&mut self, pc: usize, insns: I) -> &mut Self
+    where
+        I: IntoIterator<Item = Instruction>,
+    {
+        self.insertions.entry(pc).or_default().extend(insns);
+        self
+    }
+
+    pub fn replace(&mut self, pc: usize, insn: Instruction) -> &mut Self {
+        self.replacements.insert(pc, insn);
+        self
+    }
+
+    pub fn remove(&mut self, pc: usize) -> &mut Self {
+        self.removals.insert(pc);
+        self
+    }
+
+    // Makes sure the rewritten proto has at least `size` registers,
+    // for inserted instructions that need scratch registers.
+    pub fn ensure_stack_size(&mut self, size: u8) -> &mut Self {
+        self.max_stack_size = self.max_stack_size.max(size);
+        self
+    }
+
+    // Instructions cannot be inserted between instructions that are executed
+    // as a unit, e.g. a test and its jump, or an instruction and its EXTRAARG.
+    pub fn can_insert_before(code: &[Instruction], pc: usize) -> bool {
+        let Some(insn) = code.get(pc) else {
+            return false;
+        };
+        if matches!(
+            insn.raw_opcode(),
+            opcode::VARARGPREP
+                | opcode::EXTRAARG
+                | opcode::MMBIN
+                | opcode::MMBINI
+                | opcode::MMBINK
+                | opcode::TFORLOOP
+        ) {
+            return false;
+        }
+        if insn.opcode().modes().it && insn.b() == 0 {
+            return false;
+        }
+        match pc.checked_sub(1).map(|prev| code[prev]) {
+            Some(prev) => !(prev.opcode().modes().test || prev.raw_opcode() == opcode::LFALSESKIP),
+            None => true,
+        }
+    }
+
+    // Instructions executed as a unit can only be removed together
+    fn can_remove(&self, code: &[Instruction], pc: usize) -> bool {
+        let removed = |pc: Option<usize>| pc.is_some_and(|pc| self.removals.contains(&pc));
+        let insn = code[pc];
+        let prev = pc.checked_sub(1).map(|prev| code[prev]);
+        if let Some(prev) = prev {
+            let is_unit = prev.opcode().modes().test
+                || prev.raw_opcode() == opcode::LFALSESKIP
+                || matches!(
+                    insn.raw_opcode(),
+                    opcode::EXTRAARG | opcode::MMBIN | opcode::MMBINI | opcode::MMBINK
+                );
+            if is_unit && !removed(pc.checked_sub(1)) {
+                return false;
+            }
+        }
+        let has_next = (opcode::ADDI..=opcode::SHR).contains(&insn.raw_opcode())
+            || insn.opcode().modes().test
+            || insn.raw_opcode() == opcode::LFALSESKIP
+            || code
+                .get(pc + 1)
+                .is_some_and(|next| next.raw_opcode() == opcode::EXTRAARG);
+        !has_next || removed(Some(pc + 1))
+    }
+
+    pub fn apply(&self, proto: &mut LuaClosureProto) -> Result<(), RewriteError> {
+        let code = &proto.code;
+        for pc in self.insertions.keys() {
+            if *pc >= code.len() {
+                return Err(RewriteError::PcOutOfRange(*pc));
+            }
+            if !Self::can_insert_before(code, *pc) {
+                return Err(RewriteError::InvalidInsertionPoint(*pc));
+            }
+        }
+        for pc in self.replacements.keys().chain(&self.removals) {
+            if *pc >= code.len() {
+                return Err(RewriteError::PcOutOfRange(*pc));
+            }
+        }
+        for pc in &self.removals {
+            if !self.can_remove(code, *pc) {
+                return Err(RewriteError::InvalidRemoval(*pc));
+            }
+        }
+
+        // block_starts[pc] is the new address of the first instruction
+        // inserted before pc, or of the original instruction if none
+        let mut block_starts = Vec::with_capacity(code.len() + 1);
+        let mut new_len = 0;
+        for pc in 0..code.len() {
+            block_starts.push(new_len);
+            new_len += self.insertions.get(&pc).map(Vec::len).unwrap_or_default();
+            new_len += !self.removals.contains(&pc) as usize;
+        }
+        block_starts.push(new_len);
+        let block_start = |pc: isize| {
+            usize::try_from(pc)
+                .ok()
+                .and_then(|pc| block_starts.get(pc).copied())
+                .map(|start| start as isize)
+        };
+        let new_pc = |pc: usize| block_starts[pc + 1] - 1;
+
+        let code_len = code.len();
+        let mut new_code = Vec::with_capacity(new_len);
+        for (pc, insn) in code.iter().enumerate() {
+            if let Some(insns) = self.insertions.get(&pc) {
+                new_code.extend_from_slice(insns);
+            }
+            if self.removals.contains(&pc) {
+                continue;
+            }
+            let insn = self.replacements.get(&pc).unwrap_or(insn);
+            let from = new_pc(pc) as isize + 1;
+            let pc_next = pc as isize + 1;
+            let relocated = match insn.raw_opcode() {
+                opcode::JMP => block_start(pc_next + insn.sj() as isize)
+                    .and_then(|to| with_sj(*insn, to - from)),
+                opcode::FORPREP => block_start(pc_next + insn.bx() as isize + 1)
+                    .and_then(|after_forloop| with_bx(*insn, after_forloop - 1 - from)),
+                opcode::TFORPREP => block_start(pc_next + insn.bx() as isize)
+                    .and_then(|to| with_bx(*insn, to - from)),
+                opcode::FORLOOP | opcode::TFORLOOP => block_start(pc_next - insn.bx() as isize)
+                    .and_then(|to| with_bx(*insn, from - to)),
+                _ => Some(*insn),
+            };
+            new_code.push(relocated.ok_or(RewriteError::JumpOutOfRange(pc))?);
+        }
+
+        if let Some(lines) = proto.lines() {
+            let mut new_lines = Vec::with_capacity(new_len);
+            for (pc, line) in lines.iter().enumerate() {
+                let mut n = self.insertions.get(&pc).map(Vec::len).unwrap_or_default();
+                n += !self.removals.contains(&pc) as usize;
+                new_lines.extend(core::iter::repeat_n(*line, n));
+            }
+            proto.set_lines(&new_lines);
+        }
+
+        if let Some(local_vars) = &mut proto.local_vars {
+            for LocalVariable { pc, .. } in local_vars.iter_mut() {
+                let start = block_starts[(pc.start as usize).min(code_len)];
+                let end = block_starts[(pc.end as usize).min(code_len)];
+                *pc = start as u32..end as u32;
+            }
+        }
+
+        proto.inline_caches = InlineCaches::new(new_code.len());
+        proto.code = new_code.into_boxed_slice();
+        proto.max_stack_size = proto.max_stack_size.max(self.max_stack_size);
+        Ok(())
+    }
+}
+
+// Rewrites the proto and all of its nested protos, innermost first.
+// `f` is called once for every proto to register its insertions.
+pub fn rewrite_protos<'gc, F>(
+    gc: &'gc GcContext,
+    proto: &mut LuaClosureProto<'gc>,
+    f: &mut F,
+) -> Result<(), RewriteError>
+where
+    F: FnMut(&LuaClosureProto<'gc>, &mut CodeRewriter),
+{
+    let mut protos = proto.protos.to_vec();
+    for child in &mut protos {
+        let mut new_child = LuaClosureProto::clone(child);
+        rewrite_protos(gc, &mut new_child, f)?;
+        *child = gc.allocate(new_child);
+    }
+    proto.protos = protos.into_boxed_slice();
+
+    let mut rewriter = CodeRewriter::new();
+    f(proto, &mut rewriter);
+    rewriter.apply(proto)
+}
+
+fn with_sj(insn: Instruction, sj: isize) -> Option<Instruction> {
+    let sj = u32::try_from(sj + OFFSET_SJ as isize)
+        .ok()
+        .filter(|sj| *sj <= UINT25_MAX)?;
+    Some(Instruction((insn.0 & 0x7f) | sj << 7))
+}
+
+fn with_bx(insn: Instruction, bx: isize) -> Option<Instruction> {
+    let bx = u32::try_from(bx).ok().filter(|bx| *bx <= UINT17_MAX)?;
+    Some(Instruction((insn.0 & !(UINT17_MAX << 15)) | bx << 15))
+}
@@ -0,0 +1,130 @@
+use super::Vm;
+use crate::{
+    collections::HashMap,
+    gc::{GarbageCollect, Gc, Tracer},
+    prelude::*,
+    types::LuaClosureProto,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct FunctionStats<'gc> {
+    pub proto: Gc<'gc, LuaClosureProto<'gc>>,
+    pub calls: u64,
+    // Instructions executed by the function itself, not counting the ones
+    // executed by its callees
+    pub instructions: u64,
+}
+
+unsafe impl GarbageCollect for FunctionStats<'_> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.proto.trace(tracer);
+    }
+}
+
+#[derive(Default)]
+pub(super) struct StatsRecorder<'gc> {
+    functions: HashMap<*const LuaClosureProto<'gc>, FunctionStats<'gc>>,
+    // Function being executed and the budget when it started executing
+    current: Option<(*const LuaClosureProto<'gc>, i64)>,
+}
+
+unsafe impl GarbageCollect for StatsRecorder<'_> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for stats in self.functions.values() {
+            stats.trace(tracer);
+        }
+    }
+}
+
+impl<'gc> Vm<'gc> {
+    // Starts or stops recording per-function statistics. Recorded functions
+    // are kept alive until the statistics are reset or recording stops.
+    pub fn set_function_stats_enabled(&mut self, enabled: bool) {
+        match (enabled, &self.stats) {
+            (true, None) => self.stats = Some(Default::default()),
+            (false, _) => self.stats = None,
+            _ => (),
+        }
+    }
+
+    pub fn function_stats_enabled(&self) -> bool {
+        self.stats.is_some()
+    }
+
+    // Statistics of the functions run since recording started, from the one
+    // that executed the most instructions
+    pub fn function_stats(&self) -> Vec<FunctionStats<'gc>> {
+        let Some(recorder) = &self.stats else {
+            return Vec::new();
+        };
+        let mut stats: Vec<_> = recorder.functions.values().copied().collect();
+        stats.sort_by_key(|stats| core::cmp::Reverse(stats.instructions));
+        stats
+    }
+
+    pub fn reset_function_stats(&mut self) {
+        if let Some(recorder) = &mut self.stats {
+            recorder.functions.clear();
+        }
+    }
+
+    pub(super) fn record_frame_entry(&mut self, proto: Gc<'gc, LuaClosureProto<'gc>>, call: bool) {
+        self.record_frame_exit();
+        let budget = self.budget;
+        let Some(recorder) = &mut self.stats else {
+            return;
+        };
+        let stats = recorder
+            .functions
+            .entry(proto.as_ptr())
+            .or_insert(FunctionStats {
+                proto,
+                calls: 0,
+                instructions: 0,
+            });
+        if call {
+            stats.calls += 1;
+        }
+        recorder.current = Some((proto.as_ptr(), budget));
+    }
+
+    // A frame paused before its first instruction starts at 0 again when
+    // resumed, so it would be counted as called twice
+    pub(super) fn record_pause_before_call(&mut self) {
+        let Some(recorder) = &mut self.stats else {
+            return;
+        };
+        if let Some((proto, _)) = recorder.current {
+            if let Some(stats) = recorder.functions.get_mut(&proto) {
+                stats.calls = stats.calls.saturating_sub(1);
+            }
+        }
+    }
+
+    pub(super) fn record_frame_exit(&mut self) {
+        let budget = self.budget;
+        let Some(recorder) = &mut self.stats else {
+            return;
+        };
+        if let Some((proto, start_budget)) = recorder.current.take() {
+            if let Some(stats) = recorder.functions.get_mut(&proto) {
+                stats.instructions += start_budget.saturating_sub(budget) as u64;
+            }
+        }
+    }
+
+    // Calls made with call_value run while the calling frame is executing, so
+    // its instructions are counted up to the call and again after it
+    pub(super) fn suspend_function_stats(&mut self) -> Option<*const LuaClosureProto<'gc>> {
+        let caller = self.stats.as_ref()?.current.map(|(proto, _)| proto);
+        self.record_frame_exit();
+        caller
+    }
+
+    pub(super) fn resume_function_stats(&mut self, caller: Option<*const LuaClosureProto<'gc>>) {
+        let budget = self.budget;
+        if let Some(recorder) = &mut self.stats {
+            recorder.current = caller.map(|proto| (proto, budget));
+        }
+    }
+}
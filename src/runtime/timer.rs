@@ -0,0 +1,152 @@
+use super::{ErrorKind, Runtime, RuntimeError, Vm};
+use crate::{
+    gc::{GarbageCollect, Tracer},
+    types::Value,
+};
+use std::time::{Duration, Instant};
+
+pub(crate) struct Timer<'gc> {
+    callback: Value<'gc>,
+    interval: Duration,
+    due: Instant,
+}
+
+unsafe impl GarbageCollect for Timer<'_> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.callback.trace(tracer);
+    }
+}
+
+impl<'gc> Vm<'gc> {
+    // Pauses execution before the next instruction that can pause, and keeps
+    // the runtime from running again for `duration`. The budget left is held
+    // back so that it isn't counted as run.
+    pub(crate) fn sleep(&mut self, duration: Duration) -> Result<(), ErrorKind> {
+        if !self.can_yield() {
+            return Err(ErrorKind::other(
+                "attempt to sleep across a C-call boundary",
+            ));
+        }
+        self.wake_time = Some(Instant::now() + duration);
+        let budget = self.replace_budget(0);
+        self.budget_held = budget;
+        Ok(())
+    }
+
+    pub(crate) fn add_timer(&mut self, interval: Duration, callback: Value<'gc>) {
+        self.timers.push(Timer {
+            callback,
+            interval,
+            due: Instant::now() + interval,
+        });
+    }
+
+    fn wake(&mut self) {
+        self.wake_time = None;
+        let budget = self.replace_budget(0);
+        self.replace_budget(budget);
+    }
+
+    fn take_due_timer(&mut self, now: Instant) -> Option<Timer<'gc>> {
+        let (i, _) = self
+            .timers
+            .iter()
+            .enumerate()
+            .filter(|(_, timer)| timer.due <= now)
+            .min_by_key(|(_, timer)| timer.due)?;
+        Some(self.timers.swap_remove(i))
+    }
+}
+
+impl Runtime {
+    // When the runtime, which is paused by mochi.sleep, can run again
+    pub fn wake_time(&mut self) -> Option<Instant> {
+        self.heap.with(|_, vm| vm.borrow().wake_time)
+    }
+
+    // When the next timer set by mochi.timer is due
+    pub fn next_timer(&mut self) -> Option<Instant> {
+        self.heap
+            .with(|_, vm| vm.borrow().timers.iter().map(|timer| timer.due).min())
+    }
+
+    // Calls the callbacks of the timers that are due, in the order they were
+    // due, and returns how many were called. A timer is due again an interval
+    // later, or an interval from now if it fell behind, until its callback
+    // returns false. A timer whose callback fails is removed and the error is
+    // returned.
+    // The callbacks run to completion on top of whatever the runtime is
+    // running, so they can't yield.
+    pub fn run_timers(&mut self) -> Result<usize, RuntimeError> {
+        let now = Instant::now();
+        let mut count = 0;
+        loop {
+            let result = self.heap.with(|gc, vm| {
+                let mut vm = vm.borrow_mut(gc);
+                let mut timer = vm.take_due_timer(now)?;
+                let result = vm.call_value(gc, timer.callback, Vec::new());
+                Some(result.map(|results| {
+                    if results.first() != Some(&Value::Boolean(false)) {
+                        timer.due += timer.interval;
+                        if timer.due <= now {
+                            timer.due = now + timer.interval;
+                        }
+                        vm.timers.push(timer);
+                    }
+                }))
+            });
+            match result {
+                Some(Ok(())) => count += 1,
+                Some(Err(kind)) => {
+                    return Err(RuntimeError {
+                        kind,
+                        traceback: Vec::new(),
+                    })
+                }
+                None => return Ok(count),
+            }
+        }
+    }
+
+    // Runs timers as they become due until none are left, sleeping in
+    // between
+    pub fn run_remaining_timers(&mut self) -> Result<(), RuntimeError> {
+        while let Some(due) = self.next_timer() {
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+            self.run_timers()?;
+        }
+        Ok(())
+    }
+
+    // Sleeps until the runtime wakes up, running timers meanwhile
+    pub(super) fn wait_until_awake(&mut self) -> Result<(), RuntimeError> {
+        while let Some(wake_time) = self.wake_time() {
+            self.run_timers()?;
+            let now = Instant::now();
+            if wake_time <= now {
+                break;
+            }
+            let until = self
+                .next_timer()
+                .map_or(wake_time, |due| due.min(wake_time));
+            std::thread::sleep(until.saturating_duration_since(now));
+        }
+        self.heap.with(|gc, vm| vm.borrow_mut(gc).wake());
+        Ok(())
+    }
+
+    // Whether the runtime is asleep, after waking it up if it's time to
+    pub(super) fn is_asleep(&mut self) -> bool {
+        self.heap.with(|gc, vm| {
+            let mut vm = vm.borrow_mut(gc);
+            match vm.wake_time {
+                Some(wake_time) if wake_time > Instant::now() => true,
+                Some(_) => {
+                    vm.wake();
+                    false
+                }
+                None => false,
+            }
+        })
+    }
+}
@@ -0,0 +1,14 @@
+use super::{opcode, Instruction};
+use crate::{disasm::Operands, types::Value};
+
+// Logs the instruction about to run at `pc`, with the value in its register
+// A and the number of registers of the frame
+pub(super) fn trace_instruction(pc: usize, insn: Instruction, stack: &[Value]) {
+    if insn.raw_opcode() > opcode::EXTRAARG {
+        tracing::trace!(target: "mochi::vm", pc, raw = insn.0, "invalid opcode");
+        return;
+    }
+    let ra = stack.get(insn.a()).copied().unwrap_or_default();
+    insn.opcode()
+        .trace(pc, &Operands(insn), &ra.display_debug(), stack.len());
+}
@@ -0,0 +1,408 @@
+use super::{opcode, Instruction, Metamethod};
+use crate::{
+    prelude::*,
+    types::{LuaClosureProto, UpvalueDescription, Value},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyError {
+    #[error("empty function")]
+    EmptyCode,
+
+    #[error("invalid opcode at pc {0}")]
+    InvalidOpcode(usize),
+
+    #[error("unsupported instruction at pc {0}")]
+    UnsupportedInstruction(usize),
+
+    #[error("misplaced instruction at pc {0}")]
+    MisplacedInstruction(usize),
+
+    #[error("register out of range at pc {0}")]
+    RegisterOutOfRange(usize),
+
+    #[error("invalid operand at pc {0}")]
+    InvalidOperand(usize),
+
+    #[error("constant out of range at pc {0}")]
+    ConstantOutOfRange(usize),
+
+    #[error("string constant expected at pc {0}")]
+    StringConstantExpected(usize),
+
+    #[error("number constant expected at pc {0}")]
+    NumberConstantExpected(usize),
+
+    #[error("upvalue out of range at pc {0}")]
+    UpvalueOutOfRange(usize),
+
+    #[error("function out of range at pc {0}")]
+    ProtoOutOfRange(usize),
+
+    #[error("invalid metamethod at pc {0}")]
+    InvalidMetamethod(usize),
+
+    #[error("jump at pc {0} is out of range")]
+    JumpOutOfRange(usize),
+
+    #[error("test at pc {0} is not followed by a jump")]
+    MissingJump(usize),
+
+    #[error("missing EXTRAARG after pc {0}")]
+    MissingExtraArg(usize),
+
+    #[error("function does not end with a return")]
+    MissingReturn,
+
+    #[error("upvalue {0} refers to an enclosing register or upvalue out of range")]
+    InvalidUpvalue(usize),
+}
+
+// Checks that a proto and its nested protos can be run without operands
+// pointing outside of the registers, constants, upvalues, nested protos or
+// code of their function. The interpreter relies on this for precompiled
+// chunks, and the JIT accesses registers without bounds checks.
+pub fn verify(proto: &LuaClosureProto) -> Result<(), VerifyError> {
+    verify_code(proto)?;
+    for child in proto.protos.iter() {
+        for (i, desc) in child.upvalues.iter().enumerate() {
+            let valid = match desc {
+                UpvalueDescription::Register(index) => index.0 < proto.max_stack_size,
+                UpvalueDescription::Upvalue(index) => (index.0 as usize) < proto.upvalues.len(),
+            };
+            if !valid {
+                return Err(VerifyError::InvalidUpvalue(i));
+            }
+        }
+        verify(child)?;
+    }
+    Ok(())
+}
+
+fn verify_code(proto: &LuaClosureProto) -> Result<(), VerifyError> {
+    let code = &proto.code;
+    let Some(last) = code.last() else {
+        return Err(VerifyError::EmptyCode);
+    };
+    if !matches!(
+        last.raw_opcode(),
+        opcode::RETURN | opcode::RETURN0 | opcode::RETURN1 | opcode::TAILCALL | opcode::JMP
+    ) {
+        return Err(VerifyError::MissingReturn);
+    }
+
+    // EXTRAARG slots can't be jumped to, so targets are checked once all of
+    // them are known
+    let mut is_extra_arg = vec![false; code.len()];
+    let mut jumps = Vec::new();
+
+    let mut pc = 0;
+    while pc < code.len() {
+        let insn = code[pc];
+        let check = Operands { proto, pc, insn };
+        let mut jump = |offset: isize| {
+            let target = pc as isize + 1 + offset;
+            jumps.push((pc, target));
+        };
+
+        if insn.raw_opcode() > opcode::EXTRAARG {
+            return Err(VerifyError::InvalidOpcode(pc));
+        }
+        if insn.opcode().modes().test
+            && code.get(pc + 1).map(Instruction::raw_opcode) != Some(opcode::JMP)
+        {
+            return Err(VerifyError::MissingJump(pc));
+        }
+
+        let a = insn.a();
+        let b = insn.b();
+        let c = insn.c() as usize;
+        let mut has_extra_arg = false;
+        match insn.raw_opcode() {
+            opcode::MOVE
+            | opcode::ADDI
+            | opcode::SHRI
+            | opcode::SHLI
+            | opcode::UNM
+            | opcode::BNOT
+            | opcode::NOT
+            | opcode::LEN
+            | opcode::TESTSET => {
+                check.registers(a, 1)?;
+                check.registers(b, 1)?;
+            }
+            opcode::LOADI
+            | opcode::LOADF
+            | opcode::LOADFALSE
+            | opcode::LOADTRUE
+            | opcode::CLOSE
+            | opcode::EQI
+            | opcode::LTI
+            | opcode::LEI
+            | opcode::GTI
+            | opcode::GEI
+            | opcode::TEST
+            | opcode::RETURN1 => check.registers(a, 1)?,
+            opcode::LFALSESKIP => {
+                check.registers(a, 1)?;
+                jump(1);
+            }
+            opcode::LOADK => {
+                check.registers(a, 1)?;
+                check.constant(insn.bx())?;
+            }
+            opcode::LOADKX => {
+                check.registers(a, 1)?;
+                let extra_arg = check.extra_arg()?;
+                check.constant(extra_arg.ax())?;
+                has_extra_arg = true;
+            }
+            opcode::LOADNIL => check.registers(a, b + 1)?,
+            opcode::GETUPVAL | opcode::SETUPVAL => {
+                check.registers(a, 1)?;
+                check.upvalue(b)?;
+            }
+            opcode::GETTABUP => {
+                check.registers(a, 1)?;
+                check.upvalue(b)?;
+                check.string_constant(c)?;
+            }
+            opcode::GETI => {
+                check.registers(a, 1)?;
+                check.registers(b, 1)?;
+            }
+            opcode::GETFIELD => {
+                check.registers(a, 1)?;
+                check.registers(b, 1)?;
+                check.string_constant(c)?;
+            }
+            opcode::SETTABUP => {
+                check.upvalue(a)?;
+                check.string_constant(b)?;
+                check.rk(c)?;
+            }
+            opcode::SETTABLE => {
+                check.registers(a, 1)?;
+                check.registers(b, 1)?;
+                check.rk(c)?;
+            }
+            opcode::SETI => {
+                check.registers(a, 1)?;
+                check.rk(c)?;
+            }
+            opcode::SETFIELD => {
+                check.registers(a, 1)?;
+                check.string_constant(b)?;
+                check.rk(c)?;
+            }
+            opcode::NEWTABLE => {
+                check.registers(a, 1)?;
+                check.extra_arg()?;
+                has_extra_arg = true;
+            }
+            opcode::SELF => {
+                check.registers(a, 2)?;
+                check.registers(b, 1)?;
+                if insn.k() {
+                    check.string_constant(c)?;
+                } else {
+                    check.registers(c, 1)?;
+                }
+            }
+            opcode::ADDK
+            | opcode::SUBK
+            | opcode::MULK
+            | opcode::MODK
+            | opcode::POWK
+            | opcode::DIVK
+            | opcode::IDIVK
+            | opcode::BANDK
+            | opcode::BORK
+            | opcode::BXORK => {
+                check.registers(a, 1)?;
+                check.registers(b, 1)?;
+                check.number_constant(c)?;
+            }
+            opcode::GETTABLE
+            | opcode::ADD
+            | opcode::SUB
+            | opcode::MUL
+            | opcode::MOD
+            | opcode::POW
+            | opcode::DIV
+            | opcode::IDIV
+            | opcode::BAND
+            | opcode::BOR
+            | opcode::BXOR
+            | opcode::SHL
+            | opcode::SHR => {
+                check.registers(a, 1)?;
+                check.registers(b, 1)?;
+                check.registers(c, 1)?;
+            }
+            opcode::MMBIN | opcode::MMBINI | opcode::MMBINK => {
+                // The result goes to the register of the arithmetic
+                // instruction before
+                let follows_arithmetic = pc.checked_sub(1).is_some_and(|prev| {
+                    (opcode::ADDI..=opcode::SHR).contains(&code[prev].raw_opcode())
+                });
+                if !follows_arithmetic {
+                    return Err(VerifyError::MisplacedInstruction(pc));
+                }
+                check.registers(a, 1)?;
+                match insn.raw_opcode() {
+                    opcode::MMBIN => check.registers(b, 1)?,
+                    opcode::MMBINK => check.constant(b)?,
+                    _ => (),
+                }
+                if c >= Metamethod::COUNT {
+                    return Err(VerifyError::InvalidMetamethod(pc));
+                }
+            }
+            // Concatenating fewer than two values is never compiled
+            opcode::CONCAT if b < 2 => return Err(VerifyError::InvalidOperand(pc)),
+            opcode::CONCAT => check.registers(a, b)?,
+            opcode::TBC => return Err(VerifyError::UnsupportedInstruction(pc)),
+            opcode::JMP => jump(insn.sj() as isize),
+            opcode::EQ | opcode::LT | opcode::LE => {
+                check.registers(a, 1)?;
+                check.registers(b, 1)?;
+            }
+            opcode::EQK => {
+                check.registers(a, 1)?;
+                check.constant(b)?;
+            }
+            opcode::CALL | opcode::TAILCALL | opcode::RETURN | opcode::SETLIST => {
+                // B counts the values from A, plus one for returns and minus
+                // one for lists, or is 0 for up to the top
+                check.registers(a, 1)?;
+                if b > 0 {
+                    let count = match insn.raw_opcode() {
+                        opcode::RETURN => b - 1,
+                        opcode::SETLIST => b + 1,
+                        _ => b,
+                    };
+                    check.registers(a, count)?;
+                }
+                if insn.raw_opcode() == opcode::SETLIST && insn.k() {
+                    check.extra_arg()?;
+                    has_extra_arg = true;
+                }
+            }
+            opcode::RETURN0 => (),
+            opcode::FORLOOP => {
+                check.registers(a, 4)?;
+                jump(-(insn.bx() as isize));
+            }
+            opcode::FORPREP => {
+                check.registers(a, 4)?;
+                jump(insn.bx() as isize + 1);
+            }
+            opcode::TFORPREP => {
+                check.registers(a, 1)?;
+                jump(insn.bx() as isize);
+            }
+            opcode::TFORCALL => check.registers(a, 3)?,
+            opcode::TFORLOOP => {
+                check.registers(a, 5)?;
+                jump(-(insn.bx() as isize));
+            }
+            opcode::CLOSURE => {
+                check.registers(a, 1)?;
+                if insn.bx() >= proto.protos.len() {
+                    return Err(VerifyError::ProtoOutOfRange(pc));
+                }
+            }
+            opcode::VARARG => check.registers(a, 1)?,
+            // The fixed parameters are moved above the varargs
+            opcode::VARARGPREP if pc == 0 => check.registers(0, a)?,
+            _ => return Err(VerifyError::MisplacedInstruction(pc)),
+        }
+
+        // Tests skip their jump, and arithmetic skips its MMBIN when it
+        // doesn't need a metamethod
+        if insn.opcode().modes().test || (opcode::ADDI..=opcode::SHR).contains(&insn.raw_opcode()) {
+            jump(1);
+        }
+        if has_extra_arg {
+            is_extra_arg[pc + 1] = true;
+            pc += 1;
+        }
+        pc += 1;
+    }
+
+    for (pc, target) in jumps {
+        let valid = usize::try_from(target)
+            .ok()
+            .and_then(|target| is_extra_arg.get(target))
+            .is_some_and(|is_extra_arg| !is_extra_arg);
+        if !valid {
+            return Err(VerifyError::JumpOutOfRange(pc));
+        }
+    }
+    Ok(())
+}
+
+struct Operands<'a, 'gc> {
+    proto: &'a LuaClosureProto<'gc>,
+    pc: usize,
+    insn: Instruction,
+}
+
+impl Operands<'_, '_> {
+    fn registers(&self, start: usize, count: usize) -> Result<(), VerifyError> {
+        if start + count <= self.proto.max_stack_size as usize {
+            Ok(())
+        } else {
+            Err(VerifyError::RegisterOutOfRange(self.pc))
+        }
+    }
+
+    fn constant(&self, index: usize) -> Result<(), VerifyError> {
+        if index < self.proto.constants.len() {
+            Ok(())
+        } else {
+            Err(VerifyError::ConstantOutOfRange(self.pc))
+        }
+    }
+
+    fn string_constant(&self, index: usize) -> Result<(), VerifyError> {
+        self.constant(index)?;
+        match self.proto.constants.get_string(index) {
+            Some(_) => Ok(()),
+            None => Err(VerifyError::StringConstantExpected(self.pc)),
+        }
+    }
+
+    fn number_constant(&self, index: usize) -> Result<(), VerifyError> {
+        self.constant(index)?;
+        match self.proto.constants.value(index) {
+            Value::Integer(_) | Value::Number(_) => Ok(()),
+            _ => Err(VerifyError::NumberConstantExpected(self.pc)),
+        }
+    }
+
+    // A constant if k is set, a register otherwise
+    fn rk(&self, index: usize) -> Result<(), VerifyError> {
+        if self.insn.k() {
+            self.constant(index)
+        } else {
+            self.registers(index, 1)
+        }
+    }
+
+    fn upvalue(&self, index: usize) -> Result<(), VerifyError> {
+        if index < self.proto.upvalues.len() {
+            Ok(())
+        } else {
+            Err(VerifyError::UpvalueOutOfRange(self.pc))
+        }
+    }
+
+    fn extra_arg(&self) -> Result<Instruction, VerifyError> {
+        match self.proto.code.get(self.pc + 1) {
+            Some(next) if next.raw_opcode() == opcode::EXTRAARG => Ok(*next),
+            _ => Err(VerifyError::MissingExtraArg(self.pc)),
+        }
+    }
+}
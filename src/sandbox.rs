@@ -0,0 +1,55 @@
+use crate::{gc::GcContext, runtime::Vm};
+use bstr::B;
+
+pub use crate::stdlib::StdlibBuilder;
+
+// Standard library setup and limits for running untrusted code. Besides
+// what `stdlib` leaves out, getmetatable("") returns false so that scripts
+// can't reach the string metatable.
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    // StdlibBuilder::safe() by default
+    pub stdlib: StdlibBuilder,
+    // Instructions a script can run, see Vm::set_fuel
+    pub fuel: Option<u64>,
+    // Bytes the heap can grow to, see Vm::set_memory_limit
+    pub memory_limit: Option<usize>,
+    // Bytes of a single value print and tostring produce, see
+    // Vm::set_output_limit
+    pub output_limit: Option<usize>,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self {
+            stdlib: StdlibBuilder::safe(),
+            fuel: Some(100_000_000),
+            memory_limit: Some(64 << 20),
+            output_limit: Some(64 << 10),
+        }
+    }
+}
+
+impl Sandbox {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Loads the standard library into `vm` and sets the limits
+    pub fn apply<'gc>(&self, gc: &'gc GcContext, vm: &mut Vm<'gc>) {
+        self.stdlib.load(gc, vm);
+
+        let string_metatable = vm.metatable_of_object(gc.allocate_string(B("")).into());
+        if let Some(metatable) = string_metatable {
+            metatable
+                .borrow_mut(gc)
+                .set_field(gc.allocate_string(B("__metatable")), false);
+        }
+
+        if let Some(fuel) = self.fuel {
+            vm.set_fuel(fuel);
+        }
+        vm.set_memory_limit(self.memory_limit);
+        vm.set_output_limit(self.output_limit);
+    }
+}
@@ -0,0 +1,189 @@
+use crate::runtime::{Runtime, RuntimeError};
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TaskId(usize);
+
+#[derive(Debug, Default, Clone)]
+pub struct TaskStats {
+    pub slices: u64,
+    pub instructions: u64,
+    // Consecutive ticks the task didn't get a slice, which only happens when
+    // a tick budget is set
+    pub ticks_waiting: u64,
+    pub max_ticks_waiting: u64,
+}
+
+struct Task {
+    runtime: Runtime,
+    stats: TaskStats,
+    result: Option<Result<(), RuntimeError>>,
+    last_tick: u64,
+}
+
+// Runs several runtimes on the current thread, giving each started runtime
+// a slice of `slice_budget` instructions in turn.
+pub struct Scheduler {
+    tasks: Vec<Option<Task>>,
+    cursor: usize,
+    slice_budget: u64,
+    tick_budget: Option<u64>,
+    ticks: u64,
+}
+
+impl Scheduler {
+    pub fn new(slice_budget: u64) -> Self {
+        Self {
+            tasks: Vec::new(),
+            cursor: 0,
+            slice_budget,
+            tick_budget: None,
+            ticks: 0,
+        }
+    }
+
+    pub fn slice_budget(&self) -> u64 {
+        self.slice_budget
+    }
+
+    pub fn set_slice_budget(&mut self, slice_budget: u64) {
+        self.slice_budget = slice_budget;
+    }
+
+    pub fn tick_budget(&self) -> Option<u64> {
+        self.tick_budget
+    }
+
+    // Limits the instructions run by a single tick. Tasks that don't get a
+    // slice are first in line on the next tick.
+    pub fn set_tick_budget(&mut self, tick_budget: Option<u64>) {
+        self.tick_budget = tick_budget;
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    // `runtime` should have been started with Runtime::start
+    pub fn spawn(&mut self, runtime: Runtime) -> TaskId {
+        let task = Task {
+            runtime,
+            stats: Default::default(),
+            result: None,
+            last_tick: self.ticks,
+        };
+        match self.tasks.iter().position(Option::is_none) {
+            Some(i) => {
+                self.tasks[i] = Some(task);
+                TaskId(i)
+            }
+            None => {
+                self.tasks.push(Some(task));
+                TaskId(self.tasks.len() - 1)
+            }
+        }
+    }
+
+    // Gives a slice to every running task, or as many as the tick budget
+    // allows, and returns the number of tasks still running
+    pub fn tick(&mut self) -> usize {
+        self.ticks += 1;
+
+        let num_tasks = self.tasks.len();
+        let start = self.cursor;
+        let mut spent = 0;
+        for i in 0..num_tasks {
+            if self.tick_budget.is_some_and(|budget| spent >= budget) {
+                break;
+            }
+            let index = (start + i) % num_tasks;
+            let Some(task) = self.tasks[index].as_mut().filter(|t| t.result.is_none()) else {
+                continue;
+            };
+
+            match task.runtime.run_for(self.slice_budget) {
+                Ok(slice) => {
+                    task.stats.instructions += slice.instructions;
+                    spent += slice.instructions;
+                    if slice.finished {
+                        task.result = Some(Ok(()));
+                    }
+                }
+                Err(err) => task.result = Some(Err(err)),
+            }
+            task.stats.slices += 1;
+            task.stats.ticks_waiting = 0;
+            task.last_tick = self.ticks;
+            self.cursor = index + 1;
+        }
+
+        let mut num_running = 0;
+        for task in self.tasks.iter_mut().flatten() {
+            if task.result.is_some() {
+                continue;
+            }
+            num_running += 1;
+            if task.last_tick != self.ticks {
+                task.stats.ticks_waiting += 1;
+                task.stats.max_ticks_waiting =
+                    task.stats.max_ticks_waiting.max(task.stats.ticks_waiting);
+            }
+        }
+        num_running
+    }
+
+    // Ticks until every task finished, sleeping while they all are asleep
+    pub fn run(&mut self) {
+        while self.tick() > 0 {
+            if let Some(wake_time) = self.wake_time() {
+                std::thread::sleep(wake_time.saturating_duration_since(Instant::now()));
+            }
+        }
+    }
+
+    // When the first task wakes up or has a timer due, if every running task
+    // is asleep
+    fn wake_time(&mut self) -> Option<Instant> {
+        let now = Instant::now();
+        let mut earliest: Option<Instant> = None;
+        for task in self.tasks.iter_mut().flatten() {
+            if task.result.is_some() {
+                continue;
+            }
+            let wake_time = task.runtime.wake_time().filter(|&time| time > now)?;
+            let wake_time = task
+                .runtime
+                .next_timer()
+                .map_or(wake_time, |due| due.min(wake_time));
+            earliest = Some(earliest.map_or(wake_time, |earliest| earliest.min(wake_time)));
+        }
+        earliest
+    }
+
+    pub fn stats(&self, id: TaskId) -> Option<&TaskStats> {
+        self.task(id).map(|task| &task.stats)
+    }
+
+    pub fn is_finished(&self, id: TaskId) -> bool {
+        self.task(id).is_some_and(|task| task.result.is_some())
+    }
+
+    // Removes a finished task, returning its runtime and how it finished
+    pub fn take_finished(&mut self, id: TaskId) -> Option<(Runtime, Result<(), RuntimeError>)> {
+        if !self.is_finished(id) {
+            return None;
+        }
+        let task = self.tasks[id.0].take()?;
+        Some((task.runtime, task.result?))
+    }
+
+    // Removes a task whether it finished or not. An unfinished runtime is
+    // left paused.
+    pub fn remove(&mut self, id: TaskId) -> Option<Runtime> {
+        self.tasks.get_mut(id.0)?.take().map(|task| task.runtime)
+    }
+
+    fn task(&self, id: TaskId) -> Option<&Task> {
+        self.tasks.get(id.0)?.as_ref()
+    }
+}
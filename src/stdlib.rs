@@ -1,27 +1,46 @@
-mod base;
+pub(crate) mod base;
 mod coroutine;
+mod debug;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 mod file;
-mod helpers;
+pub(crate) mod helpers;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 mod io;
 mod math;
+#[cfg(feature = "std")]
+mod mochi;
+#[cfg(feature = "std")]
 mod os;
-mod package;
+#[cfg(feature = "std")]
+pub(crate) mod package;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 mod process;
 mod string;
 mod table;
+#[cfg(feature = "unicode")]
+mod unicode;
 mod utf8;
 
 use crate::{
     gc::{GcCell, GcContext},
-    runtime::Vm,
-    types::Table,
+    prelude::*,
+    runtime::{Action, ErrorKind, Vm},
+    types::{NativeClosure, NativeFunction, Table, Value},
 };
-use bstr::B;
+use bstr::{ByteSlice, B};
+use helpers::Args;
 
-const LUA_LOADED_TABLE: &[u8] = b"_LOADED";
-const LUA_PRELOAD_TABLE: &[u8] = b"_PRELOAD";
+pub(crate) const LUA_LOADED_TABLE: &[u8] = b"_LOADED";
+pub(crate) const LUA_PRELOAD_TABLE: &[u8] = b"_PRELOAD";
 
 pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) {
+    load_libs(gc, vm, |_| true);
+}
+
+fn load_libs<'gc, F>(gc: &'gc GcContext, vm: &mut Vm<'gc>, filter: F)
+where
+    F: Fn(&[u8]) -> bool,
+{
     let loaded = gc.allocate_cell(Table::new());
     vm.registry()
         .borrow_mut(gc)
@@ -32,19 +51,170 @@ pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) {
     let libs: &[(_, LoadFn)] = &[
         (B("_G"), base::load),
         (B("coroutine"), coroutine::load),
+        #[cfg(feature = "std")]
         (B("package"), package::load),
         (B("string"), string::load),
         (B("utf8"), utf8::load),
         (B("table"), table::load),
         (B("math"), math::load),
+        #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
         (B("io"), io::load),
+        #[cfg(feature = "std")]
         (B("os"), os::load),
+        (B("debug"), debug::load),
+        #[cfg(feature = "unicode")]
+        (B("unicode"), unicode::load),
+        #[cfg(feature = "std")]
+        (B("mochi"), mochi::load),
     ];
 
     for (name, load_lib) in libs {
+        if !filter(name) {
+            continue;
+        }
         let table = load_lib(gc, vm);
         let name = gc.allocate_string(*name);
         loaded.borrow_mut(gc).set_field(name, table);
         vm.globals().borrow_mut(gc).set_field(name, table);
     }
 }
+
+// Chooses which parts of the standard library get loaded, for embedders that
+// run code they don't trust. Names given to `exclude` are libraries ("io"),
+// globals ("dofile") or fields of libraries ("string.dump").
+#[derive(Debug, Clone, Default)]
+pub struct StdlibBuilder {
+    excluded: Vec<String>,
+    text_chunks_only: bool,
+    read_only_globals: bool,
+}
+
+impl StdlibBuilder {
+    // The whole standard library
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Everything that can't reach outside of the Vm or crash it. Leaves out
+    // io, os, package and require, debug, dofile, loadfile, collectgarbage
+    // and string.dump, and load only accepts text chunks.
+    pub fn safe() -> Self {
+        let mut builder = Self::new().text_chunks_only(true);
+        for name in [
+            "collectgarbage",
+            "debug",
+            "dofile",
+            "io",
+            "loadfile",
+            "os",
+            "package",
+            "string.dump",
+        ] {
+            builder = builder.exclude(name);
+        }
+        builder
+    }
+
+    pub fn exclude(mut self, name: &str) -> Self {
+        if !self.excluded.iter().any(|excluded| excluded == name) {
+            self.excluded.push(name.to_owned());
+        }
+        self
+    }
+
+    pub fn include(mut self, name: &str) -> Self {
+        self.excluded.retain(|excluded| excluded != name);
+        self
+    }
+
+    // Makes load reject binary chunks, which aren't verified
+    pub fn text_chunks_only(mut self, text_chunks_only: bool) -> Self {
+        self.text_chunks_only = text_chunks_only;
+        self
+    }
+
+    // Makes assigning to a global an error. The globals table is left empty
+    // with a metatable pointing to the real one, so rawset can still add
+    // globals of its own unless it's excluded too.
+    pub fn read_only_globals(mut self, read_only_globals: bool) -> Self {
+        self.read_only_globals = read_only_globals;
+        self
+    }
+
+    pub fn load<'gc>(&self, gc: &'gc GcContext, vm: &mut Vm<'gc>) {
+        load_libs(gc, vm, |name| {
+            name == b"_G"
+                || !self
+                    .excluded
+                    .iter()
+                    .any(|excluded| excluded.as_bytes() == name)
+        });
+
+        let globals = vm.globals();
+        for name in &self.excluded {
+            let (table, field) = match name.split_once('.') {
+                Some((lib, field)) => {
+                    match globals
+                        .borrow()
+                        .get_field(gc.allocate_string(lib.as_bytes()))
+                    {
+                        Value::Table(table) => (table, field),
+                        _ => continue,
+                    }
+                }
+                None => (globals, name.as_str()),
+            };
+            table
+                .borrow_mut(gc)
+                .set_field(gc.allocate_string(field.as_bytes()), Value::Nil);
+        }
+
+        let load = gc.allocate_string(B("load"));
+        if self.text_chunks_only && !globals.borrow().get_field(load).is_nil() {
+            globals
+                .borrow_mut(gc)
+                .set_field(load, NativeFunction::new(base::base_load_text));
+        }
+
+        if self.read_only_globals {
+            make_read_only(gc, globals);
+        }
+    }
+}
+
+fn make_read_only<'gc>(gc: &'gc GcContext, globals: GcCell<'gc, Table<'gc>>) {
+    let contents = gc.allocate_cell(core::mem::take(&mut *globals.borrow_mut(gc)));
+
+    let pairs = NativeClosure::with_upvalue(contents, |_, _, contents, _| {
+        Ok(Action::Return(vec![
+            NativeFunction::new(base::base_next).into(),
+            (*contents).into(),
+            Value::Nil,
+        ]))
+    });
+    let mut metatable = Table::new();
+    metatable.set_field(gc.allocate_string(B("__index")), contents);
+    metatable.set_field(
+        gc.allocate_string(B("__newindex")),
+        NativeFunction::new(assign_read_only_global),
+    );
+    metatable.set_field(gc.allocate_string(B("__pairs")), gc.allocate(pairs));
+    metatable.set_field(gc.allocate_string(B("__metatable")), false);
+    globals
+        .borrow_mut(gc)
+        .set_metatable(gc.allocate_cell(metatable));
+}
+
+fn assign_read_only_global<'gc>(
+    _: &'gc GcContext,
+    _: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let args = Args::from(args);
+    let name = args.arg(2);
+    let name = name.to_string()?;
+    Err(ErrorKind::Other(format!(
+        "attempt to assign to read-only global '{}'",
+        name.as_bstr()
+    )))
+}
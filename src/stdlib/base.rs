@@ -1,17 +1,17 @@
-use super::helpers::{set_functions_to_table, ArgumentsExt};
+use super::helpers::{fmt_bytes_limited, set_functions_to_table, Args, Results};
 use crate::{
+    binary_chunk,
     gc::{GcCell, GcContext},
+    io::{BufRead, Read},
+    prelude::*,
     runtime::{Action, Continuation, ErrorKind, Vm},
     string,
-    types::{Integer, LuaClosure, NativeClosure, NativeFunction, Number, Table, Value},
-    LUA_VERSION,
+    types::{Integer, LuaClosure, LuaString, NativeClosure, NativeFunction, Number, Table, Value},
+    CompileOptions, LUA_VERSION,
 };
+use alloc::sync::Arc;
 use bstr::{ByteSlice, B};
-use std::{
-    cell::Cell,
-    io::{Read, Write},
-    rc::Rc,
-};
+use core::sync::atomic::{AtomicBool, Ordering};
 
 pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>> {
     let globals = vm.globals();
@@ -22,15 +22,18 @@ pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>
         &[
             (B("assert"), base_assert),
             (B("collectgarbage"), base_collectgarbage),
+            #[cfg(feature = "std")]
             (B("dofile"), base_dofile),
             (B("error"), base_error),
             (B("getmetatable"), base_getmetatable),
             (B("ipairs"), base_ipairs),
             (B("load"), base_load),
+            #[cfg(feature = "std")]
             (B("loadfile"), base_loadfile),
             (B("next"), base_next),
             (B("pairs"), base_pairs),
             (B("pcall"), base_pcall),
+            #[cfg(feature = "std")]
             (B("print"), base_print),
             (B("rawequal"), base_rawequal),
             (B("rawget"), base_rawget),
@@ -41,6 +44,7 @@ pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>
             (B("tonumber"), base_tonumber),
             (B("tostring"), base_tostring),
             (B("type"), base_type),
+            (B("xpcall"), base_xpcall),
         ],
     );
     globals.set_field(
@@ -48,17 +52,18 @@ pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>
         gc.allocate_string(format!("Lua {}.{}", LUA_VERSION.0, LUA_VERSION.1).into_bytes()),
     );
 
-    let warning_is_on = Rc::new(Cell::new(false));
+    let warning_is_on = Arc::new(AtomicBool::new(false));
     globals.set_field(
         gc.allocate_string(B("warn")),
         gc.allocate(NativeClosure::new(move |_, _, args| {
-            let first_message = args.nth(1);
+            let args = Args::from(args);
+            let first_message = args.arg(1);
             let first_message = first_message.to_string()?;
-            if args.without_callee().len() == 1 {
+            if args.len() == 1 {
                 if let Some(control) = first_message.strip_prefix(b"@") {
                     match control {
-                        b"on" => warning_is_on.set(true),
-                        b"off" => warning_is_on.set(false),
+                        b"on" => warning_is_on.store(true, Ordering::Relaxed),
+                        b"off" => warning_is_on.store(false, Ordering::Relaxed),
                         _ => (),
                     }
                     return Ok(Action::Return(Vec::new()));
@@ -66,11 +71,13 @@ pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>
             }
 
             let mut concatenated = first_message.to_vec();
-            for i in 2..args.len() {
-                concatenated.extend_from_slice(&args.nth(i).to_string()?);
+            for i in 2..=args.len() {
+                concatenated.extend_from_slice(&args.arg(i).to_string()?);
             }
 
-            if warning_is_on.get() {
+            // There's nowhere to write warnings to without std
+            #[cfg(feature = "std")]
+            if warning_is_on.load(Ordering::Relaxed) {
                 eprintln!("Lua warning: {}", concatenated.as_bstr());
             }
             Ok(Action::Return(Vec::new()))
@@ -81,14 +88,15 @@ pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>
 }
 
 fn base_assert<'gc>(
-    _: &'gc GcContext,
-    _: &mut Vm<'gc>,
+    gc: &'gc GcContext,
+    vm: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    if args.nth(1).as_value()?.to_boolean() {
-        Ok(Action::Return(args.without_callee().to_vec()))
-    } else if let Some(error_obj) = args.nth(2).get() {
-        Err(ErrorKind::from_error_object(error_obj))
+    let args = Args::from(args);
+    if args.arg(1).as_value()?.is_truthy() {
+        Ok(Action::Return(args.rest(1).to_vec()))
+    } else if let Some(error_obj) = args.arg(2).get() {
+        Err(vm.error_from_object(gc, error_obj))
     } else {
         Err(ErrorKind::other("assertion failed!"))
     }
@@ -99,7 +107,8 @@ fn base_collectgarbage<'gc>(
     _: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let opt = args.nth(1);
+    let args = Args::from(args);
+    let opt = args.arg(1);
     let opt = opt.to_string_or(B("collect"))?;
 
     let result = match opt.as_ref() {
@@ -121,7 +130,7 @@ fn base_collectgarbage<'gc>(
         }
         b"count" => ((gc.total_bytes() as Number) / 1024.0).into(),
         b"step" => {
-            let step = args.nth(2).to_integer_or(0)?;
+            let step = args.arg(2).to_integer_or(0)?;
             return Ok(Action::MutateGc {
                 mutator: Box::new(move |heap| {
                     let finished_cycle = heap.force_step(step as isize);
@@ -141,9 +150,9 @@ fn base_collectgarbage<'gc>(
         }
         b"isrunning" => gc.is_running().into(),
         b"incremental" => {
-            let pause = args.nth(2).to_integer_or(0)?;
-            let step_multiplier = args.nth(3).to_integer_or(0)?;
-            let step_size = args.nth(4).to_integer_or(0)?;
+            let pause = args.arg(2).to_integer_or(0)?;
+            let step_multiplier = args.arg(3).to_integer_or(0)?;
+            let step_size = args.arg(4).to_integer_or(0)?;
             if pause != 0 {
                 gc.set_pause(pause as usize);
             }
@@ -157,13 +166,13 @@ fn base_collectgarbage<'gc>(
         }
         b"generational" => Value::Nil,
         b"setpause" => {
-            let pause = args.nth(2).to_integer_or(0)?;
+            let pause = args.arg(2).to_integer_or(0)?;
             let prev_pause = gc.pause();
             gc.set_pause(pause as usize);
             (prev_pause as Integer).into()
         }
         b"setstepmul" => {
-            let step_multiplier = args.nth(2).to_integer_or(0)?;
+            let step_multiplier = args.arg(2).to_integer_or(0)?;
             let prev_step_multiplier = gc.step_multiplier();
             gc.set_step_multiplier(step_multiplier as usize);
             (prev_step_multiplier as Integer).into()
@@ -178,12 +187,14 @@ fn base_collectgarbage<'gc>(
     Ok(Action::Return(vec![result]))
 }
 
+#[cfg(feature = "std")]
 fn base_dofile<'gc>(
     gc: &'gc GcContext,
     vm: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let filename = args.nth(1);
+    let args = Args::from(args);
+    let filename = args.arg(1);
     let closure = if filename.is_present() {
         let filename = filename.to_string()?;
         let path = filename
@@ -205,12 +216,21 @@ fn base_dofile<'gc>(
 }
 
 fn base_error<'gc>(
-    _: &'gc GcContext,
-    _: &mut Vm<'gc>,
+    gc: &'gc GcContext,
+    vm: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let error_obj = args.nth(1).get().unwrap_or_default();
-    Err(ErrorKind::from_error_object(error_obj))
+    let args = Args::from(args);
+    let mut error_obj = args.arg(1).get().unwrap_or_default();
+    let level = args.arg(2).to_integer_or(1)?;
+    if let Value::String(message) = error_obj {
+        if let Some(location) = usize::try_from(level).ok().and_then(|l| vm.location(l)) {
+            error_obj = gc
+                .allocate_string([location.as_bytes(), b" ", message.as_ref()].concat())
+                .into();
+        }
+    }
+    Err(vm.error_from_object(gc, error_obj))
 }
 
 fn base_getmetatable<'gc>(
@@ -218,7 +238,8 @@ fn base_getmetatable<'gc>(
     vm: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let object = args.nth(1).as_value()?;
+    let args = Args::from(args);
+    let object = args.arg(1).as_value()?;
     let metatable = vm
         .metatable_of_object(object)
         .map(|metatable| {
@@ -245,8 +266,9 @@ fn base_ipairs<'gc>(
         _: &mut Vm<'gc>,
         args: Vec<Value<'gc>>,
     ) -> Result<Action<'gc>, ErrorKind> {
-        let i = args.nth(2).to_integer()?.wrapping_add(1);
-        let value = args.nth(1).as_table()?.borrow().get_integer_key(i);
+        let args = Args::from(args);
+        let i = args.arg(2).to_integer()?.wrapping_add(1);
+        let value = args.arg(1).as_table()?.borrow().get_integer_key(i);
 
         Ok(Action::Return(if value.is_nil() {
             vec![Value::Nil]
@@ -255,7 +277,8 @@ fn base_ipairs<'gc>(
         }))
     }
 
-    let table = args.nth(1).as_value()?;
+    let args = Args::from(args);
+    let table = args.arg(1).as_value()?;
 
     Ok(Action::Return(vec![
         NativeFunction::new(iterate).into(),
@@ -264,105 +287,210 @@ fn base_ipairs<'gc>(
     ]))
 }
 
+// nil followed by an error message, how load reports failures
+fn fail<'gc>(message: impl Into<Value<'gc>>) -> Action<'gc> {
+    let mut results = Results::new();
+    results.set_result(2, message);
+    results.into()
+}
+
 fn base_load<'gc>(
     gc: &'gc GcContext,
     vm: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let mode = args.nth(3);
-    let mode = mode.to_string_or(B("bt"))?;
-    if !mode.contains(&b'b') || !mode.contains(&b't') {
-        todo!("mode != \"bt\"")
+    let args = Args::from(args);
+    args.arg(3).to_string_or(B("bt"))?;
+
+    if let Some(Value::String(chunk)) = args.arg(1).get() {
+        let chunk_name = args.arg(2);
+        let chunk_name = chunk_name.to_string_or(&*chunk)?;
+        return load_chunk(gc, vm, &args, &[&chunk], &chunk_name);
     }
+    args.arg(1).ensure_function()?;
+    args.arg(2).to_string_or(B("=(load)"))?;
+    Ok(read_chunk_pieces(args.into_vec(), Vec::new()))
+}
 
-    let proto = if let Some(Value::String(bytes)) = args.nth(1).get() {
-        let chunk_name = args.nth(2);
-        let chunk_name = chunk_name.to_string_or(&*bytes)?;
-        match crate::load(gc, bytes, chunk_name) {
-            Ok(proto) => proto,
-            Err(err) => {
-                return Ok(Action::Return(vec![
-                    Value::Nil,
-                    gc.allocate_string(err.to_string().into_bytes()).into(),
-                ]))
-            }
-        }
+// Calls the reader function given to load until it returns nil or an empty
+// string. The pieces aren't joined, the chunk is read from them in turn.
+fn read_chunk_pieces<'gc>(args: Vec<Value<'gc>>, pieces: Vec<LuaString<'gc>>) -> Action<'gc> {
+    Action::ProtectedCall {
+        callee: args[1],
+        args: Vec::new(),
+        continuation: Continuation::with_context(
+            (args, pieces),
+            |gc, vm, (args, mut pieces), result: Result<Vec<Value>, ErrorKind>| {
+                let piece = match result {
+                    Ok(results) => results.first().copied().unwrap_or_default(),
+                    Err(err) => return Ok(fail(gc.allocate_string(err.to_string().into_bytes()))),
+                };
+                match piece {
+                    Value::String(piece) if !piece.is_empty() => {
+                        pieces.push(piece);
+                        Ok(read_chunk_pieces(args, pieces))
+                    }
+                    Value::Nil | Value::String(_) => {
+                        let args = Args::from(args);
+                        let chunk_name = args.arg(2);
+                        let chunk_name = chunk_name.to_string_or(B("=(load)"))?;
+                        let pieces: Vec<&[u8]> =
+                            pieces.iter().map(|piece| piece.as_ref()).collect();
+                        load_chunk(gc, vm, &args, &pieces, &chunk_name)
+                    }
+                    Value::Integer(_) | Value::Number(_) => {
+                        let piece = piece.coerce_to_string_with(vm.float_format()).unwrap();
+                        pieces.push(gc.allocate_string(piece.as_ref()));
+                        Ok(read_chunk_pieces(args, pieces))
+                    }
+                    _ => Ok(fail(
+                        gc.allocate_string(B("reader function must return a string")),
+                    )),
+                }
+            },
+        ),
+    }
+}
+
+// Compiles a chunk given in pieces if load's mode allows its kind, and
+// returns it with the environment given to load or the globals
+fn load_chunk<'gc>(
+    gc: &'gc GcContext,
+    vm: &Vm<'gc>,
+    args: &Args<'gc>,
+    pieces: &[&[u8]],
+    chunk_name: &[u8],
+) -> Result<Action<'gc>, ErrorKind> {
+    let mode = args.arg(3);
+    let mode = mode.to_string_or(B("bt"))?;
+    let first = pieces.first().copied().unwrap_or_default();
+    let (kind, allowed) = if binary_chunk::is_binary_chunk(first) {
+        ("binary", mode.contains(&b'b'))
     } else {
-        todo!("load from function")
+        ("text", mode.contains(&b't'))
     };
+    if !allowed {
+        let message = format!(
+            "attempt to load a {kind} chunk (mode is '{}')",
+            mode.as_bstr()
+        );
+        return Ok(fail(gc.allocate_string(message.into_bytes())));
+    }
 
-    let mut closure = LuaClosure::from(gc.allocate(proto));
-    let upvalue = if let Some(upvalue) = args.nth(4).get() {
-        upvalue.into()
-    } else {
-        Value::Table(vm.globals()).into()
+    let reader = Pieces {
+        current: &[],
+        rest: pieces.iter(),
+    };
+    let proto = match crate::load_buffered(gc, reader, chunk_name, &CompileOptions::default()) {
+        Ok(proto) => proto,
+        Err(err) => return Ok(fail(gc.allocate_string(err.to_string().into_bytes()))),
     };
-    closure.upvalues.push(gc.allocate_cell(upvalue));
+
+    let env = args.arg(4).get().unwrap_or_else(|| vm.globals().into());
+    let closure = LuaClosure::with_environment(gc, gc.allocate(proto), env);
 
     Ok(Action::Return(vec![gc.allocate(closure).into()]))
 }
 
+struct Pieces<'a> {
+    current: &'a [u8],
+    rest: core::slice::Iter<'a, &'a [u8]>,
+}
+
+impl Read for Pieces<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> crate::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl BufRead for Pieces<'_> {
+    fn fill_buf(&mut self) -> crate::io::Result<&[u8]> {
+        while self.current.is_empty() {
+            match self.rest.next() {
+                Some(piece) => self.current = piece,
+                None => break,
+            }
+        }
+        Ok(self.current)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.current = &self.current[amt..];
+    }
+}
+
+// load that only accepts text chunks, as binary chunks aren't verified
+pub(crate) fn base_load_text<'gc>(
+    gc: &'gc GcContext,
+    vm: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let mut args = Args::from(args);
+    args.set_arg(3, gc.allocate_string(B("t")));
+    base_load(gc, vm, args.into_vec())
+}
+
+#[cfg(feature = "std")]
 fn base_loadfile<'gc>(
     gc: &'gc GcContext,
     vm: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let mode = args.nth(2);
+    let args = Args::from(args);
+    let mode = args.arg(2);
     let mode = mode.to_string_or(B("bt"))?;
     if !mode.contains(&b'b') || !mode.contains(&b't') {
         todo!("mode != \"bt\"")
     }
 
-    let proto = if let Some(Value::String(filename)) = args.nth(1).get() {
+    let proto = if let Some(Value::String(filename)) = args.arg(1).get() {
         filename
             .to_path()
             .map_err(|err| err.to_string())
-            .and_then(|path| crate::load_file(gc, path).map_err(|err| err.to_string()))
+            .and_then(|path| {
+                match vm.chunk_cache() {
+                    Some(cache) => cache.load_file(gc, path, vm.compile_options()),
+                    None => crate::load_file_with_options(gc, path, vm.compile_options()),
+                }
+                .map_err(|err| err.to_string())
+            })
     } else {
-        let mut bytes = Vec::new();
-        std::io::stdin()
-            .read_to_end(&mut bytes)
-            .map_err(Into::into)
-            .and_then(|_| crate::load(gc, bytes, b"=stdin"))
-            .map_err(|err| err.to_string())
+        crate::load_stdin_with_options(gc, vm.compile_options()).map_err(|err| err.to_string())
     };
     let proto = match proto {
         Ok(proto) => proto,
-        Err(err) => {
-            return Ok(Action::Return(vec![
-                Value::Nil,
-                gc.allocate_string(err.into_bytes()).into(),
-            ]))
-        }
+        Err(err) => return Ok(fail(gc.allocate_string(err.into_bytes()))),
     };
 
-    let mut closure = LuaClosure::from(gc.allocate(proto));
-    let upvalue = if let Some(upvalue) = args.nth(3).get() {
-        upvalue.into()
-    } else {
-        Value::Table(vm.globals()).into()
-    };
-    closure.upvalues.push(gc.allocate_cell(upvalue));
+    let env = args.arg(3).get().unwrap_or_else(|| vm.globals().into());
+    let closure = LuaClosure::with_environment(gc, gc.allocate(proto), env);
 
     Ok(Action::Return(vec![gc.allocate(closure).into()]))
 }
 
-fn base_next<'gc>(
+pub(crate) fn base_next<'gc>(
     _: &'gc GcContext,
-    _: &mut Vm<'gc>,
+    vm: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let table = args.nth(1).as_table()?;
-    let table = table.borrow();
-    let index = args.nth(2).get().unwrap_or_default();
+    let args = Args::from(args);
+    let table = args.arg(1).as_table()?;
+    let index = args.arg(2).get().unwrap_or_default();
+    let next = if vm.is_deterministic() {
+        vm.ordered_next(table, index)?
+    } else {
+        table.borrow().next(index)?
+    };
 
-    Ok(Action::Return(
-        if let Some((key, value)) = table.next(index)? {
-            vec![key, value]
-        } else {
-            vec![Value::Nil]
-        },
-    ))
+    Ok(Action::Return(if let Some((key, value)) = next {
+        vec![key, value]
+    } else {
+        vec![Value::Nil]
+    }))
 }
 
 fn base_pairs<'gc>(
@@ -370,7 +498,8 @@ fn base_pairs<'gc>(
     vm: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let table = args.nth(1).as_value()?;
+    let args = Args::from(args);
+    let table = args.arg(1).as_value()?;
     let metamethod = vm.metatable_of_object(table).and_then(|metatable| {
         let value = metatable
             .borrow()
@@ -395,39 +524,39 @@ fn base_pcall<'gc>(
     _: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let f = args.nth(1).as_value()?;
+    let args = Args::from(args);
+    let f = args.arg(1).as_value()?;
     Ok(Action::ProtectedCall {
         callee: f,
-        args: args.without_callee()[1..].to_vec(),
-        continuation: Continuation::new(|gc, _, result: Result<Vec<Value>, ErrorKind>| {
+        args: args.rest(2).to_vec(),
+        continuation: Continuation::new(|gc, vm, result: Result<Vec<Value>, ErrorKind>| {
             Ok(Action::Return(match result {
                 Ok(mut results) => {
                     results.insert(0, true.into());
                     results
                 }
-                Err(err) => {
-                    vec![
-                        false.into(),
-                        gc.allocate_string(err.to_string().into_bytes()).into(),
-                    ]
-                }
+                Err(err) => vec![false.into(), vm.error_value(gc, &err)],
             }))
         }),
     })
 }
 
+#[cfg(feature = "std")]
 fn base_print<'gc>(
     _: &'gc GcContext,
-    _: &mut Vm<'gc>,
+    vm: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
+    use std::io::Write;
+
+    let args = Args::from(args);
     let mut stdout = std::io::stdout().lock();
-    if let Some((last, xs)) = args.without_callee().split_last() {
+    if let Some((last, xs)) = args.rest(1).split_last() {
         for x in xs {
-            x.fmt_bytes(&mut stdout)?;
+            fmt_bytes_limited(*x, &mut stdout, vm)?;
             stdout.write_all(b"\t")?;
         }
-        last.fmt_bytes(&mut stdout)?;
+        fmt_bytes_limited(*last, &mut stdout, vm)?;
     }
     stdout.write_all(b"\n")?;
     Ok(Action::Return(Vec::new()))
@@ -438,8 +567,9 @@ fn base_rawequal<'gc>(
     _: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let v1 = args.nth(1).as_value()?;
-    let v2 = args.nth(2).as_value()?;
+    let args = Args::from(args);
+    let v1 = args.arg(1).as_value()?;
+    let v2 = args.arg(2).as_value()?;
     Ok(Action::Return(vec![(v1 == v2).into()]))
 }
 
@@ -448,8 +578,9 @@ fn base_rawget<'gc>(
     _: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let index = args.nth(2).as_value()?;
-    let value = args.nth(1).as_table()?.borrow().get(index);
+    let args = Args::from(args);
+    let index = args.arg(2).as_value()?;
+    let value = args.arg(1).as_table()?.borrow().get(index);
     Ok(Action::Return(vec![value]))
 }
 
@@ -458,7 +589,8 @@ fn base_rawlen<'gc>(
     _: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let len = match args.nth(1).get() {
+    let args = Args::from(args);
+    let len = match args.arg(1).get() {
         Some(Value::Table(t)) => t.borrow().lua_len(),
         Some(Value::String(s)) => s.len() as Integer,
         value => {
@@ -477,9 +609,10 @@ fn base_rawset<'gc>(
     _: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let table = args.nth(1).as_table()?;
-    let index = args.nth(2).as_value()?;
-    let value = args.nth(3).as_value()?;
+    let args = Args::from(args);
+    let table = args.arg(1).as_table()?;
+    let index = args.arg(2).as_value()?;
+    let value = args.arg(3).as_value()?;
 
     table.borrow_mut(gc).set(index, value)?;
 
@@ -491,8 +624,9 @@ fn base_select<'gc>(
     _: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let num_args = args.without_callee().len() as Integer;
-    let index = args.nth(1);
+    let args = Args::from(args);
+    let num_args = args.len() as Integer;
+    let index = args.arg(1);
 
     match index.get() {
         Some(Value::String(s)) if s.as_ref() == b"#" => {
@@ -512,8 +646,8 @@ fn base_select<'gc>(
             message: "index out of range",
         });
     }
-    Ok(Action::Return(
-        args.without_callee()[index as usize..].to_vec(),
+    Ok(Results::return_values(
+        args.rest(index as usize + 1).iter().copied(),
     ))
 }
 
@@ -522,8 +656,9 @@ fn base_setmetatable<'gc>(
     _: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let table = args.nth(1).as_table()?;
-    let new_metatable = match args.nth(2).get() {
+    let args = Args::from(args);
+    let table = args.arg(1).as_table()?;
+    let new_metatable = match args.arg(2).get() {
         Some(Value::Nil) => None,
         Some(Value::Table(t)) => Some(t),
         value => {
@@ -534,6 +669,7 @@ fn base_setmetatable<'gc>(
             })
         }
     };
+    table.borrow().check_not_frozen()?;
     match table.borrow().metatable() {
         Some(metatable)
             if !metatable
@@ -554,11 +690,21 @@ fn base_tonumber<'gc>(
     _: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let result = match args.nth(1).as_value()? {
+    let args = Args::from(args);
+    let base = args.arg(2);
+    let result = match args.arg(1).as_value()? {
+        // Numbers aren't converted to strings to be read in another base
+        value if base.is_present() && !matches!(value, Value::String(_)) => {
+            base.to_integer()?;
+            return Err(ErrorKind::ArgumentTypeError {
+                nth: 1,
+                expected_type: "string",
+                got_type: Some(value.ty().name()),
+            });
+        }
         Value::Integer(x) => Value::Integer(x),
         Value::Number(x) => Value::Number(x),
-        value @ Value::String(s) => {
-            let base = args.nth(2);
+        Value::String(s) => {
             let maybe_value = if base.is_present() {
                 let base = base.to_integer()?;
                 if !(2..=36).contains(&base) {
@@ -585,10 +731,7 @@ fn base_tonumber<'gc>(
                     None => None,
                 }
             } else {
-                value
-                    .to_integer()
-                    .map(Value::from)
-                    .or_else(|| value.to_number().map(Value::from))
+                string::parse_numeral(s).map(Value::from)
             };
             maybe_value.unwrap_or(Value::Nil)
         }
@@ -599,11 +742,12 @@ fn base_tonumber<'gc>(
 
 fn base_tostring<'gc>(
     gc: &'gc GcContext,
-    _: &mut Vm<'gc>,
+    vm: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
+    let args = Args::from(args);
     let mut string = Vec::new();
-    args.nth(1).as_value()?.fmt_bytes(&mut string)?;
+    fmt_bytes_limited(args.arg(1).as_value()?, &mut string, vm)?;
     Ok(Action::Return(vec![gc.allocate_string(string).into()]))
 }
 
@@ -612,6 +756,42 @@ fn base_type<'gc>(
     _: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let string = args.nth(1).as_value()?.ty().name().as_bytes();
+    let args = Args::from(args);
+    let string = args.arg(1).as_value()?.ty().name().as_bytes();
     Ok(Action::Return(vec![gc.allocate_string(string).into()]))
 }
+
+// The message handler is called with the error object after the stack is
+// unwound, so it can't see the frames the error was raised in
+fn base_xpcall<'gc>(
+    _: &'gc GcContext,
+    _: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let args = Args::from(args);
+    let f = args.arg(1).as_value()?;
+    let handler = args.arg(2).as_value()?;
+    Ok(Action::ProtectedCall {
+        callee: f,
+        args: args.rest(3).to_vec(),
+        continuation: Continuation::with_context(
+            handler,
+            |gc, vm, handler, result: Result<Vec<Value>, ErrorKind>| match result {
+                Ok(mut results) => {
+                    results.insert(0, true.into());
+                    Ok(Action::Return(results))
+                }
+                Err(err) => Ok(Action::Call {
+                    callee: handler,
+                    args: vec![vm.error_value(gc, &err)],
+                    continuation: Continuation::new(|_, _, results: Vec<Value>| {
+                        Ok(Action::Return(vec![
+                            false.into(),
+                            results.first().copied().unwrap_or_default(),
+                        ]))
+                    }),
+                }),
+            },
+        ),
+    })
+}
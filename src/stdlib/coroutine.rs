@@ -1,6 +1,7 @@
 use super::helpers::{set_functions_to_table, ArgumentsExt};
 use crate::{
     gc::{GcCell, GcContext},
+    prelude::*,
     runtime::{Action, Continuation, ErrorKind, Vm},
     types::{LuaThread, NativeClosure, Table, ThreadStatus, Value},
 };
@@ -46,9 +47,9 @@ fn coroutine_close<'gc>(
             vec![true.into()]
         }
         ThreadStatus::Error(err) => {
-            let msg = gc.allocate_string(err.to_string().into_bytes()).into();
+            let error_value = vm.error_value(gc, err);
             co.close(gc);
-            vec![false.into(), msg]
+            vec![false.into(), error_value]
         }
     }))
 }
@@ -91,16 +92,13 @@ fn coroutine_resume<'gc>(
     Ok(Action::Resume {
         coroutine,
         args,
-        continuation: Continuation::new(|gc, _, result: Result<Vec<Value>, ErrorKind>| {
+        continuation: Continuation::new(|gc, vm, result: Result<Vec<Value>, ErrorKind>| {
             Ok(Action::Return(match result {
                 Ok(mut results) => {
                     results.insert(0, true.into());
                     results
                 }
-                Err(err) => vec![
-                    false.into(),
-                    gc.allocate_string(err.to_string().into_bytes()).into(),
-                ],
+                Err(err) => vec![false.into(), vm.error_value(gc, &err)],
             }))
         }),
     })
@@ -169,7 +167,7 @@ fn coroutine_yield<'gc>(
 fn create_coroutine<'gc>(vm: &Vm<'gc>, body: Value<'gc>) -> Result<LuaThread<'gc>, ErrorKind> {
     let mut co = LuaThread::new();
     co.stack.push(body);
-    vm.push_frame(&mut co, 0)?;
+    let _ = vm.push_frame(&mut co, 0)?;
     Ok(co)
 }
 
@@ -180,8 +178,8 @@ enum CoroutineStatus {
     Dead,
 }
 
-impl std::fmt::Display for CoroutineStatus {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for CoroutineStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.write_str(self.name())
     }
 }
@@ -0,0 +1,70 @@
+use super::helpers::{set_functions_to_table, ArgumentsExt};
+use crate::{
+    gc::{GcCell, GcContext},
+    prelude::*,
+    runtime::{Action, ErrorKind, OpCode, Vm},
+    types::{Integer, Table, Value},
+};
+use bstr::B;
+
+pub fn load<'gc>(gc: &'gc GcContext, _: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>> {
+    let mut table = Table::new();
+    set_functions_to_table(gc, &mut table, &[(B("disassemble"), debug_disassemble)]);
+    gc.allocate_cell(table)
+}
+
+// Returns a row for each instruction of a Lua function with its opcode name,
+// raw a, b, c and k operands, the wider operands of the instructions that
+// have one, and the line if the function has line information
+fn debug_disassemble<'gc>(
+    gc: &'gc GcContext,
+    _: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let function = args.nth(1).ensure_function()?;
+    let Value::LuaClosure(closure) = function else {
+        return Err(ErrorKind::ArgumentError {
+            nth: 1,
+            message: "Lua function expected",
+        });
+    };
+    let proto = &closure.proto;
+    let lines = proto.lines();
+
+    let field = |row: &mut Table<'gc>, name: &str, value: Value<'gc>| {
+        row.set_field(gc.allocate_string(name.as_bytes()), value)
+    };
+    let mut rows = Vec::with_capacity(proto.code.len());
+    for (pc, insn) in proto.code.iter().enumerate() {
+        let opcode = insn.opcode();
+        let mut row = Table::new();
+        field(
+            &mut row,
+            "opcode",
+            gc.allocate_string(opcode.to_string().into_bytes()).into(),
+        );
+        field(&mut row, "a", (insn.a() as Integer).into());
+        field(&mut row, "b", (insn.b() as Integer).into());
+        field(&mut row, "c", (insn.c() as Integer).into());
+        field(&mut row, "k", insn.k().into());
+        match opcode {
+            OpCode::LoadK
+            | OpCode::Closure
+            | OpCode::ForLoop
+            | OpCode::ForPrep
+            | OpCode::TForPrep
+            | OpCode::TForLoop => field(&mut row, "bx", (insn.bx() as Integer).into()),
+            OpCode::LoadI | OpCode::LoadF => field(&mut row, "sbx", (insn.sbx() as Integer).into()),
+            OpCode::Jmp => field(&mut row, "sj", (insn.sj() as Integer).into()),
+            OpCode::ExtraArg => field(&mut row, "ax", (insn.ax() as Integer).into()),
+            _ => (),
+        }
+        if let Some(line) = lines.as_ref().and_then(|lines| lines.get(pc)) {
+            field(&mut row, "line", (*line as Integer).into());
+        }
+        rows.push(gc.allocate_cell(row).into());
+    }
+    Ok(Action::Return(vec![gc
+        .allocate_cell(Table::from(rows))
+        .into()]))
+}
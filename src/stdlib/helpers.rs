@@ -1,17 +1,19 @@
 use crate::{
     gc::{GcCell, GcContext},
-    runtime::ErrorKind,
+    prelude::*,
+    runtime::{Action, ErrorKind, Vm},
     types::{
         Integer, LuaThread, NativeFunction, NativeFunctionPtr, Number, Table, Type, UserData, Value,
     },
 };
-use std::{
+use alloc::borrow::{Borrow, Cow};
+use core::{
     any::Any,
-    borrow::{Borrow, Cow},
-    cell::RefMut,
+    cell::{Ref, RefMut},
 };
 
 pub trait ArgumentsExt<'gc> {
+    #[allow(dead_code)]
     fn callee(&self) -> Value<'gc>;
     fn without_callee(&self) -> &[Value<'gc>];
     fn nth(&self, nth: usize) -> Argument<'gc>;
@@ -37,6 +39,82 @@ where
     }
 }
 
+// Arguments of a native function, numbered from 1 like in Lua so that the
+// callee at index 0 of the underlying vector is never taken for one of them
+pub struct Args<'gc>(Vec<Value<'gc>>);
+
+impl<'gc> From<Vec<Value<'gc>>> for Args<'gc> {
+    fn from(args: Vec<Value<'gc>>) -> Self {
+        debug_assert!(!args.is_empty());
+        Self(args)
+    }
+}
+
+impl<'gc> Args<'gc> {
+    pub fn arg(&self, n: usize) -> Argument<'gc> {
+        Argument {
+            value: if n == 0 { None } else { self.0.get(n).copied() },
+            nth: n,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len() - 1
+    }
+
+    // Arguments from the nth on, empty if there are fewer than n
+    pub fn rest(&self, n: usize) -> &[Value<'gc>] {
+        self.0.get(n.max(1)..).unwrap_or_default()
+    }
+
+    // Sets the nth argument, padding the ones before it with nil
+    pub fn set_arg(&mut self, n: usize, value: impl Into<Value<'gc>>) {
+        assert!(n > 0);
+        if self.0.len() <= n {
+            self.0.resize(n + 1, Value::Nil);
+        }
+        self.0[n] = value.into();
+    }
+
+    // The underlying vector, callee included
+    pub fn into_vec(self) -> Vec<Value<'gc>> {
+        self.0
+    }
+}
+
+// Return values of a native function, numbered from 1
+#[derive(Default)]
+pub struct Results<'gc>(Vec<Value<'gc>>);
+
+impl<'gc> Results<'gc> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Sets the ith result, padding the ones before it with nil
+    pub fn set_result(&mut self, i: usize, value: impl Into<Value<'gc>>) {
+        assert!(i > 0);
+        if self.0.len() < i {
+            self.0.resize(i, Value::Nil);
+        }
+        self.0[i - 1] = value.into();
+    }
+
+    pub fn return_values<I>(values: I) -> Action<'gc>
+    where
+        I: IntoIterator,
+        I::Item: Into<Value<'gc>>,
+    {
+        Action::Return(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<'gc> From<Results<'gc>> for Action<'gc> {
+    fn from(results: Results<'gc>) -> Self {
+        Action::Return(results.0)
+    }
+}
+
 pub struct Argument<'gc> {
     value: Option<Value<'gc>>,
     nth: usize,
@@ -59,17 +137,28 @@ impl<'gc> Argument<'gc> {
         self.to_type("value", |value| Some(*value))
     }
 
-    pub fn to_boolean(&self) -> Result<bool, ErrorKind> {
-        self.to_type("boolean", |value| Some(value.to_boolean()))
+    // Any value is accepted, and a missing argument counts as nil
+    pub fn is_truthy(&self) -> bool {
+        self.value.is_some_and(|value| value.is_truthy())
     }
 
     pub fn to_integer(&self) -> Result<Integer, ErrorKind> {
-        self.to_type("integer", Value::to_integer)
+        match &self.value {
+            Some(value)
+                if value.coerce_to_integer().is_none() && value.coerce_to_float().is_some() =>
+            {
+                Err(ErrorKind::ArgumentError {
+                    nth: self.nth,
+                    message: "number has no integer representation",
+                })
+            }
+            _ => self.to_type("integer", Value::coerce_to_integer),
+        }
     }
 
     pub fn to_integer_or(&self, default: Integer) -> Result<Integer, ErrorKind> {
         if self.is_present() {
-            self.to_type("integer", Value::to_integer)
+            self.to_integer()
         } else {
             Ok(default)
         }
@@ -80,18 +169,18 @@ impl<'gc> Argument<'gc> {
         F: FnOnce() -> Integer,
     {
         if self.is_present() {
-            self.to_type("integer", Value::to_integer)
+            self.to_integer()
         } else {
             Ok(f())
         }
     }
 
     pub fn to_number(&self) -> Result<Number, ErrorKind> {
-        self.to_type("number", Value::to_number)
+        self.to_type("number", Value::coerce_to_float)
     }
 
     pub fn to_string(&self) -> Result<Cow<'_, [u8]>, ErrorKind> {
-        self.to_type("string", Value::to_string)
+        self.to_type("string", Value::coerce_to_string)
     }
 
     pub fn to_string_or<'a, I>(&'a self, default: I) -> Result<Cow<'a, [u8]>, ErrorKind>
@@ -99,7 +188,7 @@ impl<'gc> Argument<'gc> {
         I: Into<Cow<'a, [u8]>>,
     {
         if self.is_present() {
-            self.to_type("string", Value::to_string)
+            self.to_type("string", Value::coerce_to_string)
         } else {
             Ok(default.into())
         }
@@ -113,10 +202,17 @@ impl<'gc> Argument<'gc> {
         self.to_type("thread", Value::as_thread)
     }
 
+    #[cfg_attr(any(not(feature = "std"), target_arch = "wasm32"), allow(dead_code))]
     pub fn as_userdata<T: Any>(&self) -> Result<GcCell<'gc, UserData<'gc>>, ErrorKind> {
         self.to_type("userdata", |value| value.as_userdata::<T>())
     }
 
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    pub fn borrow_as_userdata<'a, T: Any>(&'a self) -> Result<Ref<'a, T>, ErrorKind> {
+        self.to_type("userdata", |value| value.borrow_as_userdata())
+    }
+
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
     pub fn borrow_as_userdata_mut<'a, T: Any>(
         &'a self,
         gc: &'gc GcContext,
@@ -150,6 +246,28 @@ impl<'gc> Argument<'gc> {
     }
 }
 
+// Writes `value` like fmt_bytes, but with the Vm's float format and cutting
+// strings longer than its output limit
+pub fn fmt_bytes_limited<W: crate::io::Write>(
+    value: Value,
+    f: &mut W,
+    vm: &Vm,
+) -> crate::io::Result<()> {
+    match (value, vm.output_limit()) {
+        (Value::String(s), Some(limit)) if s.len() > limit => write_truncated(f, &s, limit),
+        _ => value.fmt_bytes_with(f, vm.float_format()),
+    }
+}
+
+pub fn write_truncated<W: crate::io::Write>(
+    f: &mut W,
+    s: &[u8],
+    limit: usize,
+) -> crate::io::Result<()> {
+    f.write_all(&s[..limit])?;
+    write!(f, "...({} bytes truncated)", s.len() - limit)
+}
+
 pub fn set_functions_to_table<'gc>(
     gc: &'gc GcContext,
     table: &mut Table<'gc>,
@@ -2,14 +2,18 @@ use super::helpers::ArgumentsExt;
 use crate::{
     gc::{GcCell, GcContext},
     number_is_valid_integer,
+    prelude::*,
     runtime::{Action, ErrorKind, Vm},
     stdlib::helpers::set_functions_to_table,
+    sync::Mutex,
     types::{Integer, NativeClosure, Number, Table, Value},
 };
+use alloc::sync::Arc;
 use bstr::B;
-use rand::{rngs::OsRng, Rng, RngCore, SeedableRng};
+#[cfg(feature = "std")]
+use rand::rngs::OsRng;
+use rand::{Rng, RngCore, SeedableRng};
 use rand_xoshiro::Xoshiro256StarStar;
-use std::{cell::RefCell, ops::DerefMut, rc::Rc, time::SystemTime};
 
 pub fn load<'gc>(gc: &'gc GcContext, _: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>> {
     let mut table = Table::new();
@@ -50,24 +54,41 @@ pub fn load<'gc>(gc: &'gc GcContext, _: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>>
     table.set_field(gc.allocate_string(B("huge")), Number::INFINITY);
     table.set_field(gc.allocate_string(B("maxinteger")), Integer::MAX);
     table.set_field(gc.allocate_string(B("mininteger")), Integer::MIN);
-    table.set_field(gc.allocate_string(B("pi")), std::f64::consts::PI);
+    table.set_field(gc.allocate_string(B("pi")), core::f64::consts::PI);
 
+    // SystemTime::now panics on wasm32-unknown-unknown, where chrono asks JS
+    #[cfg(feature = "std")]
     fn seed1() -> i64 {
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs() as i64
+        chrono::Utc::now().timestamp()
     }
+    #[cfg(feature = "std")]
     let seed2 = OsRng.gen();
-
-    let rng = rng_from_seeds(seed1(), seed2);
-    let rng = Rc::new(RefCell::new(rng));
+    // With neither a clock nor an entropy source, only where the table
+    // happens to live varies, like luai_makeseed without time
+    #[cfg(not(feature = "std"))]
+    fn seed1() -> i64 {
+        0
+    }
+    #[cfg(not(feature = "std"))]
+    let seed2 = &table as *const Table as i64;
+
+    // Deterministic execution replaces the seeds, so they're taken when
+    // math.random is first used rather than now
+    let seeds = move |vm: &mut Vm| match vm.deterministic() {
+        Some(deterministic) => (deterministic.seed, 0),
+        None => (seed1(), seed2),
+    };
+    let rng = Arc::new(Mutex::new(None));
     {
         let rng = rng.clone();
         table.set_field(
             gc.allocate_string(B("random")),
-            gc.allocate(NativeClosure::new(move |_, _, args| {
-                let mut rng = rng.borrow_mut();
+            gc.allocate(NativeClosure::new(move |_, vm, args| {
+                let mut rng = rng.lock().unwrap();
+                let rng = rng.get_or_insert_with(|| {
+                    let (x, y) = seeds(vm);
+                    rng_from_seeds(x, y)
+                });
                 let (lower, upper) = match args.without_callee().len() {
                     0 => return Ok(Action::Return(vec![rng.gen::<Number>().into()])),
                     1 => {
@@ -86,7 +107,7 @@ pub fn load<'gc>(gc: &'gc GcContext, _: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>>
                     _ => return Err(ErrorKind::other("wrong number of arguments")),
                 };
                 if lower <= upper {
-                    let random = random_in_range(rng.deref_mut(), lower as u64, upper as u64);
+                    let random = random_in_range(rng, lower as u64, upper as u64);
                     Ok(Action::Return(vec![(random as Integer).into()]))
                 } else {
                     Err(ErrorKind::ArgumentError {
@@ -99,15 +120,15 @@ pub fn load<'gc>(gc: &'gc GcContext, _: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>>
     }
     table.set_field(
         gc.allocate_string(B("randomseed")),
-        gc.allocate(NativeClosure::new(move |_, _, args| {
+        gc.allocate(NativeClosure::new(move |_, vm, args| {
             let (x, y) = if args.without_callee().is_empty() {
-                (seed1(), seed2)
+                seeds(vm)
             } else {
                 let x = args.nth(1).to_integer()?;
                 let y = args.nth(2).to_integer_or(0)?;
                 (x, y)
             };
-            *rng.borrow_mut() = rng_from_seeds(x, y);
+            *rng.lock().unwrap() = Some(rng_from_seeds(x, y));
 
             Ok(Action::Return(vec![x.into(), y.into()]))
         })),
@@ -229,7 +250,7 @@ fn math_fmod<'gc>(
                 message: "zero",
             })
         }
-        (Value::Integer(x), Value::Integer(y)) => (x % y).into(),
+        (Value::Integer(x), Value::Integer(y)) => x.wrapping_rem(y).into(),
         _ => (x.to_number()? % y.to_number()?).into(),
     };
     Ok(Action::Return(vec![result]))
@@ -307,7 +328,7 @@ fn math_tointeger<'gc>(
     let result = args
         .nth(1)
         .as_value()?
-        .to_integer()
+        .coerce_to_integer()
         .map(|i| i.into())
         .unwrap_or_default();
     Ok(Action::Return(vec![result]))
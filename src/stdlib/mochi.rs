@@ -0,0 +1,92 @@
+use super::helpers::{set_functions_to_table, ArgumentsExt};
+use crate::{
+    gc::{GcCell, GcContext},
+    runtime::{Action, ErrorKind, Vm},
+    types::{NativeClosure, Table, Value},
+};
+use bstr::B;
+use std::time::Duration;
+
+// Features of the build that scripts can check for with mochi.has, besides
+// the fields of this library
+const FEATURES: &[(&[u8], bool)] = &[
+    (b"dylib-modules", cfg!(feature = "dylib-modules")),
+    (b"jit", cfg!(feature = "jit")),
+];
+
+// Extensions to the standard library are collected here, so that scripts can
+// check what they can use with mochi.has instead of probing globals. The
+// unicode library, which is also a global, has to be loaded before this one
+// to be included.
+pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>> {
+    let mut table = Table::new();
+    set_functions_to_table(
+        gc,
+        &mut table,
+        &[(B("sleep"), mochi_sleep), (B("timer"), mochi_timer)],
+    );
+    table.set_field(
+        gc.allocate_string(B("version")),
+        gc.allocate_string(env!("CARGO_PKG_VERSION").as_bytes()),
+    );
+    let unicode = gc.allocate_string(B("unicode"));
+    let lib = vm.globals().borrow().get_field(unicode);
+    if let Value::Table(_) = lib {
+        table.set_field(unicode, lib);
+    }
+
+    let table = gc.allocate_cell(table);
+    let has = NativeClosure::with_upvalue(table, |gc, _, table, args| {
+        let name = args.nth(1);
+        let name = name.to_string()?;
+        let has = FEATURES
+            .iter()
+            .any(|(feature, enabled)| *enabled && *feature == name.as_ref())
+            || !table
+                .borrow()
+                .get_field(gc.allocate_string(name.as_ref()))
+                .is_nil();
+        Ok(Action::Return(vec![has.into()]))
+    });
+    table
+        .borrow_mut(gc)
+        .set_field(gc.allocate_string(B("has")), gc.allocate(has));
+    table
+}
+
+fn nth_duration(args: &[Value], nth: usize) -> Result<Duration, ErrorKind> {
+    Duration::try_from_secs_f64(args.nth(nth).to_number()?).map_err(|_| ErrorKind::ArgumentError {
+        nth,
+        message: "invalid duration",
+    })
+}
+
+// Pauses the runtime for the given number of seconds without blocking the
+// host, which can run other runtimes meanwhile
+fn mochi_sleep<'gc>(
+    _: &'gc GcContext,
+    vm: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let duration = nth_duration(&args, 1)?;
+    vm.sleep(duration)?;
+    Ok(Action::Return(Vec::new()))
+}
+
+// Calls the callback every interval seconds until it returns false
+fn mochi_timer<'gc>(
+    _: &'gc GcContext,
+    vm: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let interval = nth_duration(&args, 1)?;
+    if interval.is_zero() {
+        return Err(ErrorKind::ArgumentError {
+            nth: 1,
+            message: "interval must be positive",
+        });
+    }
+    let callback = args.nth(2).ensure_function()?;
+    vm.add_timer(interval, callback);
+    Ok(Action::Return(Vec::new()))
+}
@@ -1,15 +1,17 @@
-use super::{
-    file,
-    helpers::{set_functions_to_table, ArgumentsExt},
-    process,
-};
+use super::helpers::{set_functions_to_table, ArgumentsExt};
+#[cfg(not(target_arch = "wasm32"))]
+use super::{file, process};
 use crate::{
     gc::{GcCell, GcContext},
     runtime::{Action, ErrorKind, Vm},
     types::{Integer, Table, Value},
 };
-use bstr::{ByteSlice, ByteVec, B};
-use chrono::{DateTime, Datelike, Local, NaiveDateTime, TimeZone, Timelike, Utc};
+#[cfg(not(target_arch = "wasm32"))]
+use bstr::ByteVec;
+use bstr::{ByteSlice, B};
+use chrono::{
+    DateTime, Datelike, FixedOffset, Local, NaiveDateTime, Offset, TimeZone, Timelike, Utc,
+};
 
 pub fn load<'gc>(gc: &'gc GcContext, _: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>> {
     let mut table = Table::new();
@@ -20,10 +22,15 @@ pub fn load<'gc>(gc: &'gc GcContext, _: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>>
             (B("clock"), os_clock),
             (B("date"), os_date),
             (B("difftime"), os_difftime),
+            #[cfg(not(target_arch = "wasm32"))]
             (B("execute"), os_execute),
+            #[cfg(not(target_arch = "wasm32"))]
             (B("exit"), os_exit),
+            #[cfg(not(target_arch = "wasm32"))]
             (B("getenv"), os_getenv),
+            #[cfg(not(target_arch = "wasm32"))]
             (B("remove"), os_remove),
+            #[cfg(not(target_arch = "wasm32"))]
             (B("rename"), os_rename),
             (B("setlocale"), os_setlocale),
             (B("time"), os_time),
@@ -34,25 +41,29 @@ pub fn load<'gc>(gc: &'gc GcContext, _: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>>
 
 fn os_clock<'gc>(
     _: &'gc GcContext,
-    _: &mut Vm<'gc>,
+    vm: &mut Vm<'gc>,
     _: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
-    let clock = cpu_time::ProcessTime::now()
-        .as_duration()
-        .as_secs_f64()
-        .into();
-    Ok(Action::Return(vec![clock]))
+    if let Some(deterministic) = vm.deterministic() {
+        return Ok(Action::Return(vec![deterministic.clock.clock().into()]));
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    let clock = cpu_time::ProcessTime::now().as_duration().as_secs_f64();
+    // There's no CPU time to read, so the clock is wall-clock time
+    #[cfg(target_arch = "wasm32")]
+    let clock = Utc::now().timestamp_micros() as f64 / 1e6;
+    Ok(Action::Return(vec![clock.into()]))
 }
 
 fn os_date<'gc>(
     gc: &'gc GcContext,
-    _: &mut Vm<'gc>,
+    vm: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
     let format = args.nth(1);
     let format = format.to_string_or(B("%c"))?;
 
-    let time = args.nth(2).to_integer_or_else(|| Utc::now().timestamp())?;
+    let time = args.nth(2).to_integer_or_else(|| now(vm))?;
     if NaiveDateTime::from_timestamp_opt(time, 0).is_none() {
         return Err(ErrorKind::ArgumentError {
             nth: 2,
@@ -72,7 +83,7 @@ fn os_date<'gc>(
             set_datetime_to_table(gc, &mut table, &datetime);
             table.set_field(gc.allocate_string(B("isdst")), false);
         } else {
-            let datetime = datetime_from_timestamp(Local, time)?;
+            let datetime = datetime_from_timestamp(local_offset(vm, time)?, time)?;
             set_datetime_to_table(gc, &mut table, &datetime);
         }
         return Ok(Action::Return(vec![gc.allocate_cell(table).into()]));
@@ -100,7 +111,7 @@ fn os_date<'gc>(
     let formatted = if is_utc {
         datetime_from_timestamp(Utc, time)?.format(&format)
     } else {
-        datetime_from_timestamp(Local, time)?.format(&format)
+        datetime_from_timestamp(local_offset(vm, time)?, time)?.format(&format)
     };
     Ok(Action::Return(vec![gc
         .allocate_string(formatted.to_string().into_bytes())
@@ -117,6 +128,7 @@ fn os_difftime<'gc>(
     Ok(Action::Return(vec![(t2 - t1).into()]))
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 fn os_execute<'gc>(
     gc: &'gc GcContext,
     _: &mut Vm<'gc>,
@@ -141,6 +153,7 @@ fn os_execute<'gc>(
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 fn os_exit<'gc>(
     _: &'gc GcContext,
     _: &mut Vm<'gc>,
@@ -166,6 +179,7 @@ fn os_exit<'gc>(
     std::process::exit(code)
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 fn os_getenv<'gc>(
     gc: &'gc GcContext,
     _: &mut Vm<'gc>,
@@ -183,6 +197,7 @@ fn os_getenv<'gc>(
     Ok(Action::Return(vec![env]))
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 fn os_remove<'gc>(
     gc: &'gc GcContext,
     _: &mut Vm<'gc>,
@@ -206,6 +221,7 @@ fn os_remove<'gc>(
     })
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 fn os_rename<'gc>(
     gc: &'gc GcContext,
     _: &mut Vm<'gc>,
@@ -254,7 +270,7 @@ fn os_setlocale<'gc>(
 
 fn os_time<'gc>(
     gc: &'gc GcContext,
-    _: &mut Vm<'gc>,
+    vm: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
     fn get_field<'gc, T, D>(
@@ -273,7 +289,7 @@ fn os_time<'gc>(
             default
                 .into()
                 .ok_or_else(|| ErrorKind::Other(format!("field '{field}' missing in date table")))
-        } else if let Some(i) = value.to_integer() {
+        } else if let Some(i) = value.coerce_to_integer() {
             i.try_into()
                 .map_err(|_| ErrorKind::Other(format!("field '{field}' is out-of-bound")))
         } else {
@@ -286,24 +302,31 @@ fn os_time<'gc>(
 
     let table = args.nth(1);
     if !table.is_present() {
-        return Ok(Action::Return(vec![Utc::now().timestamp().into()]));
+        return Ok(Action::Return(vec![now(vm).into()]));
     }
 
     let table = table.as_table()?;
     let mut table = table.borrow_mut(gc);
-    let datetime = Local
-        .with_ymd_and_hms(
-            get_field(gc, &table, b"year", None)?,
-            get_field(gc, &table, b"month", None)?,
-            get_field(gc, &table, b"day", None)?,
-            get_field(gc, &table, b"hour", 12)?,
-            get_field(gc, &table, b"min", 0)?,
-            get_field(gc, &table, b"sec", 0)?,
-        )
-        .earliest()
-        .ok_or_else(|| {
-            ErrorKind::other("time result cannot be represented in this installation")
-        })?;
+    let (year, month, day, hour, min, sec) = (
+        get_field(gc, &table, b"year", None)?,
+        get_field(gc, &table, b"month", None)?,
+        get_field(gc, &table, b"day", None)?,
+        get_field(gc, &table, b"hour", 12)?,
+        get_field(gc, &table, b"min", 0)?,
+        get_field(gc, &table, b"sec", 0)?,
+    );
+    let datetime = match vm.deterministic() {
+        Some(deterministic) => clock_offset(deterministic.clock.utc_offset())?
+            .with_ymd_and_hms(year, month, day, hour, min, sec)
+            .earliest(),
+        None => Local
+            .with_ymd_and_hms(year, month, day, hour, min, sec)
+            .earliest()
+            .map(|datetime| datetime.fixed_offset()),
+    };
+    let datetime = datetime.ok_or_else(|| {
+        ErrorKind::other("time result cannot be represented in this installation")
+    })?;
     set_datetime_to_table(gc, &mut table, &datetime);
 
     Ok(Action::Return(vec![datetime.timestamp().into()]))
@@ -327,6 +350,27 @@ fn set_datetime_to_table<'gc, Tz: TimeZone>(
     );
 }
 
+// Deterministic execution reads the time from its clock
+fn now(vm: &mut Vm) -> Integer {
+    match vm.deterministic() {
+        Some(deterministic) => deterministic.clock.time(),
+        None => Utc::now().timestamp(),
+    }
+}
+
+// Offset of local time at `time`, which is fixed by the clock of
+// deterministic execution
+fn local_offset(vm: &mut Vm, time: Integer) -> Result<FixedOffset, ErrorKind> {
+    match vm.deterministic() {
+        Some(deterministic) => clock_offset(deterministic.clock.utc_offset()),
+        None => Ok(datetime_from_timestamp(Local, time)?.offset().fix()),
+    }
+}
+
+fn clock_offset(seconds: i32) -> Result<FixedOffset, ErrorKind> {
+    FixedOffset::east_opt(seconds).ok_or_else(|| ErrorKind::other("invalid UTC offset"))
+}
+
 fn datetime_from_timestamp<Tz: TimeZone>(
     timezone: Tz,
     time: Integer,
@@ -1,14 +1,18 @@
+mod dylib;
+
 use super::helpers::ArgumentsExt;
 use crate::{
+    archive::Archive,
     gc::{GcCell, GcContext},
-    runtime::{Action, Continuation, ErrorKind, Vm},
-    types::{NativeClosure, NativeFunction, Table, Value},
+    remote::Lookup,
+    runtime::{Action, Continuation, ErrorKind, Frame, Vm},
+    types::{LuaString, NativeClosure, NativeFunction, NativeFunctionPtr, Table, Value},
     LUA_VERSION,
 };
 use bstr::{ByteSlice, ByteVec, B};
 use std::{
-    cell::{Cell, RefCell},
-    rc::Rc,
+    cell::Cell,
+    sync::{Arc, Mutex},
 };
 
 const LUA_PATH_SEP: &[u8] = b";";
@@ -25,7 +29,7 @@ const LUA_DIRSEP: &[u8] = {
         b"/"
     }
 };
-const LUA_LSUBSEP: &[u8] = LUA_DIRSEP;
+pub(crate) const LUA_LSUBSEP: &[u8] = LUA_DIRSEP;
 
 pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>> {
     const LUA_EXEC_DIR: &[u8] = b"!";
@@ -34,13 +38,16 @@ pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>
     let lua_vdir = format!("{}.{}", LUA_VERSION.0, LUA_VERSION.1);
     let lua_vdir = lua_vdir.as_bytes();
 
-    let package_path = {
+    // Native modules are built against this crate rather than the
+    // reference implementation, so they aren't looked for in its
+    // directories
+    let (path_default, cpath_default) = {
         #[cfg(windows)]
         {
             const LUA_LDIR: &[u8] = b"!\\lua\\";
             const LUA_CDIR: &[u8] = b"!\\";
             let lua_shrdir = &bstr::concat([b"!\\..\\share\\lua\\", lua_vdir, b"\\"])[..];
-            let lua_path_default = bstr::concat([
+            let path_default = bstr::concat([
                 LUA_LDIR,
                 b"?.lua;",
                 LUA_LDIR,
@@ -54,14 +61,17 @@ pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>
                 lua_shrdir,
                 b"?\\init.lua;.\\?.lua;.\\?\\init.lua",
             ]);
+            let cpath_default = b".\\?.dll".to_vec();
 
             // TODO: handle error
-            std::env::current_exe()
+            let exec_dir = std::env::current_exe()
                 .ok()
                 .and_then(|path| path.parent().map(|path| path.to_path_buf()))
-                .and_then(|path| Vec::from_path_buf(path).ok())
-                .map(|path| lua_path_default.replace(LUA_EXEC_DIR, path))
-                .unwrap_or(lua_path_default)
+                .and_then(|path| Vec::from_path_buf(path).ok());
+            match exec_dir {
+                Some(dir) => (path_default.replace(LUA_EXEC_DIR, &dir), cpath_default),
+                None => (path_default, cpath_default),
+            }
         }
 
         #[cfg(not(windows))]
@@ -69,7 +79,7 @@ pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>
             const LUA_ROOT: &[u8] = b"/usr/local/";
             let lua_ldir = &bstr::concat([LUA_ROOT, b"share/lua/", lua_vdir, b"/"])[..];
             let lua_cdir = &bstr::concat([LUA_ROOT, b"lib/lua/", lua_vdir, b"/"])[..];
-            bstr::concat([
+            let path_default = bstr::concat([
                 lua_ldir,
                 b"?.lua;",
                 lua_ldir,
@@ -78,9 +88,13 @@ pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>
                 b"?.lua;",
                 lua_cdir,
                 b"?/init.lua;./?.lua;./?/init.lua",
-            ])
+            ]);
+            let cpath_default = b"./?.so".to_vec();
+            (path_default, cpath_default)
         }
     };
+    let package_path = path_from_env("LUA_PATH", &path_default);
+    let package_cpath = path_from_env("MOCHI_CPATH", &cpath_default);
 
     let package = gc.allocate_cell(Table::new());
 
@@ -118,7 +132,15 @@ pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>
             ],
         )),
     );
+    table.set_field(
+        gc.allocate_string(B("cpath")),
+        gc.allocate_string(package_cpath),
+    );
     table.set_field(gc.allocate_string(B("loaded")), package_loaded);
+    table.set_field(
+        gc.allocate_string(B("loadlib")),
+        NativeFunction::new(package_loadlib),
+    );
     table.set_field(
         gc.allocate_string(B("path")),
         gc.allocate_string(package_path),
@@ -126,8 +148,14 @@ pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>
     table.set_field(gc.allocate_string(B("preload")), package_preload);
     let package_searchers = vec![
         NativeFunction::new(searcher_preload).into(),
+        NativeFunction::new(searcher_archive).into(),
         gc.allocate(NativeClosure::with_upvalue(package, searcher_lua))
             .into(),
+        gc.allocate(NativeClosure::with_upvalue(package, searcher_c))
+            .into(),
+        gc.allocate(NativeClosure::with_upvalue(package, searcher_croot))
+            .into(),
+        NativeFunction::new(searcher_remote).into(),
     ];
     table.set_field(
         gc.allocate_string(B("searchers")),
@@ -141,6 +169,33 @@ pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>
     package
 }
 
+// The path in the environment variable `var`, preferring the one suffixed
+// with the Lua version. A ";;" in it stands for the default path.
+fn path_from_env(var: &str, default: &[u8]) -> Vec<u8> {
+    let versioned_var = format!("{var}_{}_{}", LUA_VERSION.0, LUA_VERSION.1);
+    let Some(path) = std::env::var_os(versioned_var)
+        .or_else(|| std::env::var_os(var))
+        .and_then(|path| Vec::from_os_string(path).ok())
+    else {
+        return default.to_vec();
+    };
+    let Some(default_mark) = path.find(b";;") else {
+        return path;
+    };
+
+    let mut result = Vec::new();
+    if default_mark > 0 {
+        result.push_str(&path[..default_mark]);
+        result.push_str(LUA_PATH_SEP);
+    }
+    result.push_str(default);
+    if default_mark + 2 < path.len() {
+        result.push_str(LUA_PATH_SEP);
+        result.push_str(&path[default_mark + 2..]);
+    }
+    result
+}
+
 fn package_require<'gc>(
     gc: &'gc GcContext,
     vm: &mut Vm<'gc>,
@@ -149,6 +204,11 @@ fn package_require<'gc>(
 ) -> Result<Action<'gc>, ErrorKind> {
     let name = gc.allocate_string(args.nth(1).to_string()?);
 
+    if let Some(chunk) = caller_chunk(vm) {
+        vm.dependency_graph_mut()
+            .record_require(chunk.as_bytes(), name.as_bytes());
+    }
+
     let loaded = vm
         .registry()
         .borrow()
@@ -157,7 +217,7 @@ fn package_require<'gc>(
         .unwrap();
 
     let value = loaded.borrow().get_field(name);
-    if value.to_boolean() {
+    if value.is_truthy() {
         return Ok(Action::Return(vec![value]));
     }
 
@@ -169,7 +229,7 @@ fn package_require<'gc>(
         .ok_or_else(|| ErrorKind::other("'package.searchers' must be a table"))?;
 
     let i = Cell::new(0);
-    let msg = Rc::new(RefCell::new(Vec::new()));
+    let msg = Arc::new(Mutex::new(Vec::new()));
     let continuation = NativeClosure::with_upvalue(
         (name, searchers, loaded),
         move |_, _, &(name, searchers, loaded), args| {
@@ -181,7 +241,7 @@ fn package_require<'gc>(
                 return Err(ErrorKind::Other(format!(
                     "module '{}' not found:{}",
                     name.as_bstr(),
-                    msg.borrow().as_bstr()
+                    msg.lock().unwrap().as_bstr()
                 )));
             }
 
@@ -199,8 +259,8 @@ fn package_require<'gc>(
                                 | value @ Value::NativeClosure(_),
                             ) => *value,
                             Some(value) => {
-                                if let Some(s) = value.to_string() {
-                                    let mut msg = msg.borrow_mut();
+                                if let Some(s) = value.coerce_to_string() {
+                                    let mut msg = msg.lock().unwrap();
                                     msg.push_str(b"\n\t");
                                     msg.extend_from_slice(&s);
                                 }
@@ -217,13 +277,16 @@ fn package_require<'gc>(
                             }
                         };
                         let loader_data = results.get(1).copied().unwrap_or_default();
+                        let chunk = loader
+                            .as_lua_closure()
+                            .map(|closure| closure.proto.source.as_bytes().to_vec());
 
                         Ok(Action::Call {
                             callee: loader,
                             args: vec![name.into(), loader_data],
                             continuation: Continuation::with_context(
                                 (name, loaded, loader_data),
-                                |gc, _, (name, loaded, loader_data), results: Vec<Value>| {
+                                move |gc, vm, (name, loaded, loader_data), results: Vec<Value>| {
                                     let mut loaded = loaded.borrow_mut(gc);
                                     let value = match results.first() {
                                         Some(Value::Nil) | None => {
@@ -237,6 +300,17 @@ fn package_require<'gc>(
                                         Some(value) => *value,
                                     };
                                     loaded.set_field(name, value);
+
+                                    let path = loader_data
+                                        .coerce_to_string()
+                                        .filter(|data| !data.starts_with(b":"))
+                                        .and_then(|data| data.to_path().ok().map(Into::into));
+                                    vm.dependency_graph_mut().record_module(
+                                        name.as_bytes(),
+                                        chunk.as_deref(),
+                                        path,
+                                    );
+
                                     Ok(Action::Return(vec![value, loader_data]))
                                 },
                             ),
@@ -253,6 +327,16 @@ fn package_require<'gc>(
     })
 }
 
+// Chunk name of the Lua function calling require
+fn caller_chunk<'gc>(vm: &Vm<'gc>) -> Option<LuaString<'gc>> {
+    let thread = vm.current_thread();
+    let thread = thread.borrow();
+    match thread.frames.last()? {
+        Frame::Lua(frame) => Some(thread.stack[frame.bottom].as_lua_closure()?.proto.source),
+        _ => None,
+    }
+}
+
 fn package_searchpath<'gc>(
     gc: &'gc GcContext,
     _: &mut Vm<'gc>,
@@ -276,7 +360,12 @@ fn package_searchpath<'gc>(
     }))
 }
 
-fn search_path<N, P, S, D>(name: N, path: P, sep: S, dirsep: D) -> Result<Vec<u8>, Vec<u8>>
+pub(crate) fn search_path<N, P, S, D>(
+    name: N,
+    path: P,
+    sep: S,
+    dirsep: D,
+) -> Result<Vec<u8>, Vec<u8>>
 where
     N: AsRef<[u8]>,
     P: AsRef<[u8]>,
@@ -323,6 +412,74 @@ fn searcher_preload<'gc>(
     }))
 }
 
+fn searcher_archive<'gc>(
+    gc: &'gc GcContext,
+    vm: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let name = args.nth(1);
+    let name = name.to_string()?;
+
+    if vm.archives().is_empty() {
+        return Ok(Action::Return(Vec::new()));
+    }
+
+    for archive in vm.archives() {
+        let Some((path, contents)) = archive.find_module(&name) else {
+            continue;
+        };
+        let closure = vm
+            .load(gc, contents, bstr::concat([b"@", path]))
+            .map_err(|err| {
+                ErrorKind::Other(format!(
+                    "error loading module '{}' from archive file '{}':\n\t{}",
+                    name.as_bstr(),
+                    path.as_bstr(),
+                    err
+                ))
+            })?;
+        return Ok(Action::Return(vec![
+            gc.allocate(closure).into(),
+            gc.allocate_string(path).into(),
+        ]));
+    }
+
+    let msg = bstr::join(
+        "\n\t",
+        Archive::module_paths(&name)
+            .iter()
+            .map(|path| bstr::concat([b"no file '", &path[..], b"' in archive"])),
+    );
+    Ok(Action::Return(vec![gc.allocate_string(msg).into()]))
+}
+
+fn searcher_remote<'gc>(
+    gc: &'gc GcContext,
+    vm: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let name = args.nth(1);
+    let name = name.to_string()?;
+
+    let Some(remote) = vm.remote_modules() else {
+        return Ok(Action::Return(Vec::new()));
+    };
+    let (url, contents) = match remote.find_module(&name) {
+        Lookup::Found { url, contents } => (url, contents),
+        Lookup::NotFound(msg) if msg.is_empty() => return Ok(Action::Return(Vec::new())),
+        Lookup::NotFound(msg) => return Ok(Action::Return(vec![gc.allocate_string(msg).into()])),
+        Lookup::Failed { url, message } => return Err(remote_loading_error(&name, &url, message)),
+    };
+
+    let closure = vm
+        .load(gc, contents, bstr::concat([b"@", url.as_bytes()]))
+        .map_err(|err| remote_loading_error(&name, &url, err))?;
+    Ok(Action::Return(vec![
+        gc.allocate(closure).into(),
+        gc.allocate_string(url.into_bytes()).into(),
+    ]))
+}
+
 fn searcher_lua<'gc>(
     gc: &'gc GcContext,
     vm: &mut Vm<'gc>,
@@ -334,7 +491,7 @@ fn searcher_lua<'gc>(
 
     let path = package.borrow().get_field(gc.allocate_string(B("path")));
     let path = path
-        .to_string()
+        .coerce_to_string()
         .ok_or_else(|| ErrorKind::other("'package.path' must be a string"))?;
 
     let filename = match search_path(&name, path, b".", LUA_LSUBSEP) {
@@ -342,24 +499,135 @@ fn searcher_lua<'gc>(
         Err(msg) => return Ok(Action::Return(vec![gc.allocate_string(msg).into()])),
     };
 
-    let closure = filename
-        .to_path()
-        .map_err(|e| e.to_string())
-        .and_then(|path| vm.load_file(gc, path).map_err(|e| e.to_string()));
-    let closure = match closure {
-        Ok(closure) => closure,
-        Err(err) => {
-            return Err(ErrorKind::Other(format!(
-                "error loading module '{}' from file '{}':\n\t{}",
-                name.as_bstr(),
-                filename.as_bstr(),
-                err
-            )))
-        }
+    let closure = match vm.take_prefetched_chunk(&filename) {
+        Some(chunk) => vm
+            .load(gc, chunk, bstr::concat([b"@", filename.as_slice()]))
+            .map_err(|e| e.to_string()),
+        None => filename
+            .to_path()
+            .map_err(|e| e.to_string())
+            .and_then(|path| vm.load_file(gc, path).map_err(|e| e.to_string())),
     };
+    let closure = closure.map_err(|err| loading_error(&name, &filename, err))?;
 
     Ok(Action::Return(vec![
         gc.allocate(closure).into(),
         gc.allocate_string(filename).into(),
     ]))
 }
+
+fn loading_error<E: std::fmt::Display>(name: &[u8], filename: &[u8], err: E) -> ErrorKind {
+    ErrorKind::Other(format!(
+        "error loading module '{}' from file '{}':\n\t{}",
+        name.as_bstr(),
+        filename.as_bstr(),
+        err
+    ))
+}
+
+fn remote_loading_error<E: std::fmt::Display>(name: &[u8], url: &str, err: E) -> ErrorKind {
+    ErrorKind::Other(format!(
+        "error loading module '{}' from '{url}':\n\t{err}",
+        name.as_bstr()
+    ))
+}
+
+fn cpath<'gc>(gc: &'gc GcContext, package: &GcCell<'gc, Table<'gc>>) -> Result<Vec<u8>, ErrorKind> {
+    let cpath = package.borrow().get_field(gc.allocate_string(B("cpath")));
+    cpath
+        .coerce_to_string()
+        .map(|cpath| cpath.to_vec())
+        .ok_or_else(|| ErrorKind::other("'package.cpath' must be a string"))
+}
+
+// Finds the open function of module `name` in the library at `filename`
+fn open_native_module(name: &[u8], filename: &[u8]) -> Result<NativeFunctionPtr, dylib::LoadError> {
+    let mut result = Err(dylib::LoadError::Absent);
+    for function_name in dylib::open_function_names(name) {
+        result = dylib::load_function(filename, &function_name)
+            .map(|function| function.expect("open function name is never \"*\""));
+        if !matches!(result, Err(dylib::LoadError::Init(_))) {
+            break;
+        }
+    }
+    result
+}
+
+fn searcher_c<'gc>(
+    gc: &'gc GcContext,
+    _: &mut Vm<'gc>,
+    package: &GcCell<'gc, Table<'gc>>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let name = args.nth(1);
+    let name = name.to_string()?;
+
+    let filename = match search_path(&name, cpath(gc, package)?, b".", LUA_DIRSEP) {
+        Ok(filename) => filename,
+        Err(msg) => return Ok(Action::Return(vec![gc.allocate_string(msg).into()])),
+    };
+    let function = open_native_module(&name, &filename)
+        .map_err(|err| loading_error(&name, &filename, err.message()))?;
+    Ok(Action::Return(vec![
+        NativeFunction::new(function).into(),
+        gc.allocate_string(filename).into(),
+    ]))
+}
+
+// Looks for the library of the root of a submodule name, which can bundle
+// several modules
+fn searcher_croot<'gc>(
+    gc: &'gc GcContext,
+    _: &mut Vm<'gc>,
+    package: &GcCell<'gc, Table<'gc>>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let name = args.nth(1);
+    let name = name.to_string()?;
+
+    let Some(dot) = name.find_byte(b'.') else {
+        return Ok(Action::Return(Vec::new()));
+    };
+    let filename = match search_path(&name[..dot], cpath(gc, package)?, b".", LUA_DIRSEP) {
+        Ok(filename) => filename,
+        Err(msg) => return Ok(Action::Return(vec![gc.allocate_string(msg).into()])),
+    };
+    match open_native_module(&name, &filename) {
+        Ok(function) => Ok(Action::Return(vec![
+            NativeFunction::new(function).into(),
+            gc.allocate_string(filename).into(),
+        ])),
+        Err(dylib::LoadError::Init(_)) => {
+            let msg = format!(
+                "no module '{}' in file '{}'",
+                name.as_bstr(),
+                filename.as_bstr()
+            );
+            Ok(Action::Return(vec![gc
+                .allocate_string(msg.into_bytes())
+                .into()]))
+        }
+        Err(err) => Err(loading_error(&name, &filename, err.message())),
+    }
+}
+
+fn package_loadlib<'gc>(
+    gc: &'gc GcContext,
+    _: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let path = args.nth(1);
+    let path = path.to_string()?;
+    let name = args.nth(2);
+    let name = name.to_string()?;
+
+    Ok(Action::Return(match dylib::load_function(&path, &name) {
+        Ok(Some(function)) => vec![NativeFunction::new(function).into()],
+        Ok(None) => vec![true.into()],
+        Err(err) => vec![
+            Value::Nil,
+            gc.allocate_string(err.message().as_bytes()).into(),
+            gc.allocate_string(err.kind().as_bytes()).into(),
+        ],
+    }))
+}
@@ -0,0 +1,166 @@
+use crate::types::NativeFunctionPtr;
+use bstr::{ByteSlice, B};
+
+#[cfg_attr(
+    not(all(feature = "dylib-modules", any(unix, windows))),
+    allow(dead_code)
+)]
+pub(super) enum LoadError {
+    // The library couldn't be opened
+    Open(String),
+    // The library doesn't define the function
+    Init(String),
+    // The function was built against an incompatible version of this crate
+    Incompatible(String),
+    // Built without dynamic library support
+    Absent,
+}
+
+impl LoadError {
+    // The third result of package.loadlib
+    pub(super) fn kind(&self) -> &'static str {
+        match self {
+            Self::Open(_) => "open",
+            Self::Init(_) | Self::Incompatible(_) => "init",
+            Self::Absent => "absent",
+        }
+    }
+
+    pub(super) fn message(&self) -> &str {
+        match self {
+            Self::Open(msg) | Self::Init(msg) | Self::Incompatible(msg) => msg,
+            Self::Absent => "dynamic libraries not enabled; check your Lua installation",
+        }
+    }
+}
+
+// mochi_open_ followed by the module name with dots turned into
+// underscores. For names like "v2-foo", the part before the hyphen is tried
+// first and the part after it next. Modules of the reference implementation
+// export luaopen_ functions for its C API instead, which are never called.
+pub(super) fn open_function_names(name: &[u8]) -> Vec<Vec<u8>> {
+    let name = name.replace(B("."), B("_"));
+    match name.find_byte(b'-') {
+        Some(mark) => vec![
+            [B("mochi_open_"), &name[..mark]].concat(),
+            [B("mochi_open_"), &name[mark + 1..]].concat(),
+        ],
+        None => vec![[B("mochi_open_"), &name[..]].concat()],
+    }
+}
+
+// Opens the library at `path` and looks up the open function `name` in it.
+// With `name` "*", the library is only opened, making its symbols available
+// to libraries opened later.
+//
+// Open functions are exported with the native_module! macro, as C functions
+// returning a NativeModule. The native function it holds is only used if
+// the library was built against the same version of this crate with the
+// same features.
+//
+// Libraries are never unloaded, as their functions can be referenced from
+// anywhere in the heap.
+#[cfg(all(feature = "dylib-modules", any(unix, windows)))]
+pub(super) fn load_function(
+    path: &[u8],
+    name: &[u8],
+) -> Result<Option<NativeFunctionPtr>, LoadError> {
+    let global = name == b"*";
+    let library = sys::open(path, global).map_err(LoadError::Open)?;
+    if global {
+        return Ok(None);
+    }
+    let symbol = sys::symbol(library, name).map_err(LoadError::Init)?;
+    let open: extern "C" fn() -> crate::types::NativeModule =
+        unsafe { std::mem::transmute(symbol) };
+    match open().open_function() {
+        Some(function) => Ok(Some(function)),
+        None => Err(LoadError::Incompatible(format!(
+            "'{}' was built against an incompatible version of mochi-lua",
+            name.as_bstr()
+        ))),
+    }
+}
+
+#[cfg(not(all(feature = "dylib-modules", any(unix, windows))))]
+pub(super) fn load_function(_: &[u8], _: &[u8]) -> Result<Option<NativeFunctionPtr>, LoadError> {
+    Err(LoadError::Absent)
+}
+
+#[cfg(all(feature = "dylib-modules", unix))]
+mod sys {
+    use std::ffi::{c_void, CStr, CString};
+
+    pub fn open(path: &[u8], global: bool) -> Result<*mut c_void, String> {
+        let path = CString::new(path).map_err(|err| err.to_string())?;
+        let flags = libc::RTLD_NOW
+            | if global {
+                libc::RTLD_GLOBAL
+            } else {
+                libc::RTLD_LOCAL
+            };
+        let handle = unsafe { libc::dlopen(path.as_ptr(), flags) };
+        if handle.is_null() {
+            Err(last_error())
+        } else {
+            Ok(handle)
+        }
+    }
+
+    pub fn symbol(library: *mut c_void, name: &[u8]) -> Result<*mut c_void, String> {
+        let name = CString::new(name).map_err(|err| err.to_string())?;
+        let symbol = unsafe { libc::dlsym(library, name.as_ptr()) };
+        if symbol.is_null() {
+            Err(last_error())
+        } else {
+            Ok(symbol)
+        }
+    }
+
+    fn last_error() -> String {
+        let msg = unsafe { libc::dlerror() };
+        if msg.is_null() {
+            "unknown error".to_owned()
+        } else {
+            unsafe { CStr::from_ptr(msg) }
+                .to_string_lossy()
+                .into_owned()
+        }
+    }
+}
+
+#[cfg(all(feature = "dylib-modules", windows))]
+mod sys {
+    use bstr::ByteSlice;
+    use std::{
+        ffi::{c_char, c_void, CString},
+        os::windows::ffi::OsStrExt,
+    };
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn LoadLibraryExW(name: *const u16, file: *mut c_void, flags: u32) -> *mut c_void;
+        fn GetProcAddress(module: *mut c_void, name: *const c_char) -> *mut c_void;
+    }
+
+    pub fn open(path: &[u8], _: bool) -> Result<*mut c_void, String> {
+        let path = path.to_os_str().map_err(|err| err.to_string())?;
+        let path: Vec<u16> = path.encode_wide().chain([0]).collect();
+        let handle = unsafe { LoadLibraryExW(path.as_ptr(), std::ptr::null_mut(), 0) };
+        if handle.is_null() {
+            Err(std::io::Error::last_os_error().to_string())
+        } else {
+            Ok(handle)
+        }
+    }
+
+    pub fn symbol(library: *mut c_void, name: &[u8]) -> Result<*mut c_void, String> {
+        let name = CString::new(name).map_err(|err| err.to_string())?;
+        let symbol = unsafe { GetProcAddress(library, name.as_ptr()) };
+        if symbol.is_null() {
+            Err(std::io::Error::last_os_error().to_string())
+        } else {
+            Ok(symbol)
+        }
+    }
+}
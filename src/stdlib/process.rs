@@ -80,7 +80,7 @@ impl Process {
     }
 }
 
-pub fn translate_and_return_error<F>(gc: &GcContext, f: F) -> Result<Action, ErrorKind>
+pub fn translate_and_return_error<F>(gc: &GcContext, f: F) -> Result<Action<'_>, ErrorKind>
 where
     F: FnOnce() -> Result<Option<ExitStatus>, FileError>,
 {
@@ -4,11 +4,12 @@ use super::helpers::{set_functions_to_table, ArgumentsExt};
 use crate::{
     binary_chunk,
     gc::{GcCell, GcContext},
+    prelude::*,
     runtime::{Action, ErrorKind, Metamethod, Vm},
     types::{Integer, Table, Type, Value},
 };
 use bstr::{ByteSlice, B};
-use std::ops::Range;
+use core::{cmp::Ordering, ops::Range};
 
 pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>> {
     let mut table = Table::new();
@@ -23,6 +24,7 @@ pub fn load<'gc>(gc: &'gc GcContext, vm: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>
             (B("format"), format::string_format),
             (B("len"), string_len),
             (B("lower"), string_lower),
+            (B("naturalcmp"), string_naturalcmp),
             (B("sub"), string_sub),
             (B("rep"), string_rep),
             (B("reverse"), string_reverse),
@@ -87,8 +89,12 @@ fn string_dump<'gc>(
 ) -> Result<Action<'gc>, ErrorKind> {
     match args.nth(1).get() {
         Some(Value::LuaClosure(closure)) => {
+            let options = binary_chunk::DumpOptions {
+                strip: args.nth(2).is_truthy(),
+                ..Default::default()
+            };
             let mut binary = Vec::new();
-            binary_chunk::dump(&mut binary, &closure.proto)?;
+            binary_chunk::dump_with_options(&mut binary, &closure.proto, &options)?;
             Ok(Action::Return(vec![gc.allocate_string(binary).into()]))
         }
         Some(value) if value.ty() == Type::Function => {
@@ -112,7 +118,7 @@ fn string_find<'gc>(
     let pattern = args.nth(2);
     let pattern = pattern.to_string()?;
     let init = args.nth(3).to_integer_or(1)?;
-    let plain = args.nth(4).to_boolean().unwrap_or_default();
+    let plain = args.nth(4).is_truthy();
 
     let len = s.len();
     let start = match init {
@@ -161,6 +167,55 @@ fn string_lower<'gc>(
     Ok(Action::Return(vec![gc.allocate_string(lower).into()]))
 }
 
+// Usable as a table.sort comparator that orders runs of digits by their
+// numeric value, e.g. "file2" before "file10"
+fn string_naturalcmp<'gc>(
+    _: &'gc GcContext,
+    _: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let a = args.nth(1);
+    let a = a.to_string()?;
+    let b = args.nth(2);
+    let b = b.to_string()?;
+    Ok(Action::Return(vec![(natural_cmp(&a, &b)
+        == Ordering::Less)
+        .into()]))
+}
+
+fn natural_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    fn digits(s: &[u8]) -> &[u8] {
+        let len = s.iter().take_while(|ch| ch.is_ascii_digit()).count();
+        &s[..len]
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let (x, y) = (digits(&a[i..]), digits(&b[j..]));
+        if x.is_empty() || y.is_empty() {
+            match a[i].cmp(&b[j]) {
+                Ordering::Equal => (i, j) = (i + 1, j + 1),
+                ordering => return ordering,
+            }
+            continue;
+        }
+        let trimmed_x = x.trim_start_with(|ch| ch == '0');
+        let trimmed_y = y.trim_start_with(|ch| ch == '0');
+        let ordering = trimmed_x
+            .len()
+            .cmp(&trimmed_y.len())
+            .then_with(|| trimmed_x.cmp(trimmed_y));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+        i += x.len();
+        j += y.len();
+    }
+    // Strings that only differ in leading zeros are still ordered, so that
+    // the comparator is consistent
+    (a.len() - i).cmp(&(b.len() - j)).then_with(|| a.cmp(b))
+}
+
 fn string_sub<'gc>(
     gc: &'gc GcContext,
     _: &mut Vm<'gc>,
@@ -1,18 +1,21 @@
 use crate::{
     gc::GcContext,
-    math,
+    io::WriteBytesExt,
+    prelude::*,
     runtime::{Action, ErrorKind, Vm},
-    stdlib::helpers::ArgumentsExt,
+    stdlib::helpers::{write_truncated, ArgumentsExt},
+    string::{fmt_float_e, fmt_float_f, fmt_float_g, fmt_float_hex},
     types::{Integer, Number, Value},
 };
+use alloc::borrow::Cow;
 use bstr::{ByteSlice, ByteVec};
-use byteorder::WriteBytesExt;
 
 pub fn string_format<'gc>(
     gc: &'gc GcContext,
-    _: &mut Vm<'gc>,
+    vm: &mut Vm<'gc>,
     args: Vec<Value<'gc>>,
 ) -> Result<Action<'gc>, ErrorKind> {
+    let output_limit = vm.output_limit();
     let format_string = args.nth(1);
     let format_string = format_string.to_string()?;
 
@@ -20,47 +23,17 @@ pub fn string_format<'gc>(
     let mut arg_nth = 1;
 
     let mut output = Vec::new();
-    'for_spec: while let Some(&ch) = format_iter.next() {
+    while let Some(&ch) = format_iter.next() {
         if ch != b'%' {
             output.push(ch);
             continue;
         }
 
-        let mut spec = Specification::default();
-        let mut raw_spec = vec![b'%'];
-        let mut specifier = None;
-        for &ch in format_iter.by_ref() {
-            raw_spec.push(ch);
-            match ch {
-                b'%' => {
-                    output.push(b'%');
-                    continue 'for_spec;
-                }
-                b'.' => {
-                    spec.has_precision = true;
-                    spec.precision = 0;
-                }
-                b'-' => spec.left_justify = true,
-                b'+' => spec.always_sign = true,
-                b'0' if !spec.zero_pad && spec.width == 0 && !spec.has_precision => {
-                    spec.zero_pad = true
-                }
-                b'0'..=b'9' if spec.has_precision => {
-                    spec.precision *= 10;
-                    spec.precision += (ch - b'0') as usize;
-                }
-                b'0'..=b'9' => {
-                    spec.width *= 10;
-                    spec.width += (ch - b'0') as usize;
-                }
-                b'#' => spec.alternative_form = true,
-                b' ' => todo!("space modifier"),
-                _ => {
-                    specifier = Some(ch);
-                    break;
-                }
-            }
-            spec.has_modifier = true;
+        let rest = format_iter.as_slice();
+        if rest.first() == Some(&b'%') {
+            output.push(b'%');
+            format_iter.next();
+            continue;
         }
 
         arg_nth += 1;
@@ -72,134 +45,78 @@ pub fn string_format<'gc>(
             });
         }
 
+        let spec_len = rest
+            .iter()
+            .take_while(|ch| b"-+ #0.123456789".contains(ch))
+            .count();
+        if spec_len >= MAX_SPEC_LEN {
+            return Err(ErrorKind::other("invalid format string to 'format'"));
+        }
+        let raw_spec = &rest[..rest.len().min(spec_len + 1)];
+        format_iter = rest[raw_spec.len()..].iter();
+        let specifier = raw_spec.get(spec_len).copied();
+
+        // Each conversion takes only the flags C defines for it
+        let (flags, takes_precision): (&[u8], _) = match specifier {
+            Some(b'c' | b'p') => (b"-", false),
+            Some(b's') => (b"-", true),
+            Some(b'd' | b'i') => (b"-+ 0", true),
+            Some(b'u') => (b"-0", true),
+            Some(b'o' | b'x' | b'X') => (b"-#0", true),
+            Some(b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G') => (b"-+ #0", true),
+            Some(b'q') if spec_len > 0 => {
+                return Err(ErrorKind::other("specifier '%q' cannot have modifiers"))
+            }
+            Some(b'q') => (b"", false),
+            _ => {
+                return Err(ErrorKind::Other(format!(
+                    "invalid conversion '%{}' to 'format'",
+                    raw_spec.as_bstr()
+                )))
+            }
+        };
+        let spec = Specification::parse(&raw_spec[..spec_len], flags, takes_precision).ok_or_else(
+            || {
+                ErrorKind::Other(format!(
+                    "invalid conversion specification: '%{}'",
+                    raw_spec.as_bstr()
+                ))
+            },
+        )?;
+
         match specifier {
             Some(b'c') => {
                 let byte = &[arg.to_integer()? as u8];
                 spec.fmt_bytes(&mut output, byte)?
             }
-            Some(b'd' | b'i') => {
-                let value = arg.to_integer()?;
-                if spec.width == 0 && spec.precision == 0 {
-                    continue;
-                }
-                if spec.has_precision {
-                    let mut width = spec.precision;
-                    if value < 0 || spec.always_sign {
-                        width += 1;
-                    }
-                    let mut s = Vec::new();
-                    Specification {
-                        zero_pad: true,
-                        width,
-                        ..spec
-                    }
-                    .fmt_display(&mut s, value)?;
-                    spec.fmt_bytes(&mut output, s)?
-                } else {
-                    spec.fmt_display(&mut output, value)?
-                }
-            }
-            Some(b'u') => {
-                let value = arg.to_integer()? as u64;
-                if spec.width == 0 && spec.precision == 0 {
-                    continue;
-                }
-                if spec.has_precision {
-                    let mut s = Vec::new();
-                    Specification {
-                        zero_pad: true,
-                        width: spec.precision,
-                        ..spec
-                    }
-                    .fmt_display(&mut s, value)?;
-                    spec.fmt_bytes(&mut output, s)?
-                } else {
-                    spec.fmt_display(&mut output, value)?
-                }
-            }
-            Some(b'o') => spec.fmt_octal(&mut output, arg.to_integer()?)?,
-            Some(b'x') => spec.fmt_lower_hex(&mut output, arg.to_integer()?)?,
-            Some(b'X') => {
-                let mut f = Vec::new();
-                spec.fmt_upper_hex(&mut f, arg.to_integer()?)?;
-                f.make_ascii_uppercase();
-                output.append(&mut f);
+            Some(specifier @ (b'd' | b'i' | b'u' | b'o' | b'x' | b'X')) => {
+                spec.fmt_integer(&mut output, arg.to_integer()?, specifier)?
             }
-            Some(b'a') => {
-                if spec.has_modifier {
-                    return Err(ErrorKind::other(
-                        "modifiers for format '%a'/'%A' not implemented",
-                    ));
-                }
+            Some(specifier @ (b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G')) => {
+                let x = arg.to_number()?;
                 let mut f = Vec::new();
-                sprintf_a(&mut f, arg.to_number()?)?;
-                f.make_ascii_lowercase();
-                output.append(&mut f);
-            }
-            Some(b'A') => {
-                if spec.has_modifier {
-                    return Err(ErrorKind::other(
-                        "modifiers for format '%a'/'%A' not implemented",
-                    ));
-                }
-                let mut f = Vec::new();
-                sprintf_a(&mut f, arg.to_number()?)?;
-                f.make_ascii_uppercase();
-                output.append(&mut f);
-            }
-            Some(b'f') => {
-                let number = arg.to_number()?;
-                if !number.is_finite() {
-                    spec.zero_pad = false;
-                }
-                let mut f = Vec::new();
-                spec.fmt_display(&mut f, number)?;
-                f.make_ascii_lowercase();
-                output.append(&mut f);
-            }
-            Some(b'e') => {
-                let number = arg.to_number()?;
-                if !number.is_finite() {
-                    spec.zero_pad = false;
+                match specifier.to_ascii_lowercase() {
+                    b'a' => {
+                        let precision = spec.has_precision.then_some(spec.precision);
+                        fmt_float_hex(&mut f, x, precision, spec.alternative_form)?
+                    }
+                    b'e' => fmt_float_e(&mut f, x, spec.precision, spec.alternative_form)?,
+                    b'f' => fmt_float_f(&mut f, x, spec.precision, spec.alternative_form)?,
+                    _ => fmt_float_g(&mut f, x, spec.precision, spec.alternative_form)?,
                 }
-                let mut f = Vec::new();
-                spec.fmt_lower_exp(&mut f, number)?;
-                f.make_ascii_lowercase();
-                output.append(&mut f);
-            }
-            Some(b'E') => {
-                let number = arg.to_number()?;
-                if !number.is_finite() {
-                    spec.zero_pad = false;
+                if specifier.is_ascii_uppercase() {
+                    f.make_ascii_uppercase();
                 }
-                let mut f = Vec::new();
-                spec.fmt_upper_exp(&mut f, number)?;
-                f.make_ascii_uppercase();
-                output.append(&mut f);
-            }
-            Some(b'g') => {
-                let mut f = Vec::new();
-                sprintf_g(&mut f, arg.to_number()?, spec.precision)?;
-                f.make_ascii_lowercase();
-                output.append(&mut f);
-            }
-            Some(b'G') => {
-                let mut f = Vec::new();
-                sprintf_g(&mut f, arg.to_number()?, spec.precision)?;
-                f.make_ascii_uppercase();
-                spec.fmt_bytes(&mut output, &f)?;
+                spec.fmt_number_bytes(&mut output, &f)?;
             }
             Some(b'p') => {
                 if let Some(ptr) = arg.as_value()?.as_ptr() {
-                    spec.fmt_ptr(&mut output, ptr)?;
+                    spec.fmt_bytes(&mut output, format!("{ptr:p}"))?;
                 } else {
                     spec.fmt_bytes(&mut output, b"(null)")?;
                 }
             }
             Some(b'q') => {
-                if spec.has_modifier {
-                    return Err(ErrorKind::other("specifier '%q' cannot have modifiers"));
-                }
                 if !fmt_literal(&mut output, arg.as_value()?)? {
                     return Err(ErrorKind::ArgumentError {
                         nth: arg_nth,
@@ -208,9 +125,27 @@ pub fn string_format<'gc>(
                 }
             }
             Some(b's') => {
-                let s = arg.to_string()?;
+                let s = match arg.get() {
+                    Some(Value::Number(x)) => {
+                        let mut s = Vec::new();
+                        vm.float_format().fmt(&mut s, x)?;
+                        Cow::Owned(s)
+                    }
+                    _ => arg.to_string()?,
+                };
                 let mut s = s.as_ref();
-                if !spec.has_modifier {
+                if let Some(limit) = output_limit {
+                    let len = if spec.has_precision {
+                        s.len().min(spec.precision)
+                    } else {
+                        s.len()
+                    };
+                    if len > limit {
+                        write_truncated(&mut output, &s[..len], limit)?;
+                        continue;
+                    }
+                }
+                if spec_len == 0 {
                     output.push_str(s);
                     continue;
                 }
@@ -229,20 +164,19 @@ pub fn string_format<'gc>(
                 }
                 spec.fmt_bytes(&mut output, s)?;
             }
-            _ => {
-                return Err(ErrorKind::Other(format!(
-                    "invalid conversion '{}' to 'format'",
-                    raw_spec.as_bstr()
-                )))
-            }
+            _ => unreachable!(),
         }
     }
 
     Ok(Action::Return(vec![gc.allocate_string(output).into()]))
 }
 
+// Longest run of flags, width and precision accepted, as in the reference
+// implementation
+const MAX_SPEC_LEN: usize = 22;
+
+#[derive(Clone, Copy)]
 struct Specification {
-    has_modifier: bool,
     has_precision: bool,
     left_justify: bool,
     zero_pad: bool,
@@ -250,12 +184,12 @@ struct Specification {
     precision: usize,
     alternative_form: bool,
     always_sign: bool,
+    space_sign: bool,
 }
 
 impl Default for Specification {
     fn default() -> Self {
         Self {
-            has_modifier: false,
             has_precision: false,
             left_justify: false,
             zero_pad: false,
@@ -263,67 +197,95 @@ impl Default for Specification {
             precision: 6,
             alternative_form: false,
             always_sign: false,
+            space_sign: false,
         }
     }
 }
 
-macro_rules! fmt_with_specifier {
-    ($name:ident, $tr:path, $spec:literal) => {
-        fn $name<W, T>(&self, f: &mut W, value: T) -> std::io::Result<()>
-        where
-            W: std::io::Write,
-            T: $tr,
-        {
-            macro_rules! f {
-                ($params:literal) => {
-                    write!(
-                        f,
-                        concat!("{value:", $params, "width$.precision$", $spec, "}"),
-                        value = value,
-                        width = self.width,
-                        precision = self.precision
-                    )
-                };
+impl Specification {
+    // Parses what's between '%' and the conversion. Widths and precisions
+    // have at most two digits.
+    fn parse(mut s: &[u8], flags: &[u8], takes_precision: bool) -> Option<Self> {
+        fn two_digits(s: &[u8]) -> (usize, &[u8]) {
+            let len = s
+                .iter()
+                .take(2)
+                .take_while(|ch| ch.is_ascii_digit())
+                .count();
+            let n = s[..len]
+                .iter()
+                .fold(0, |n, ch| n * 10 + (ch - b'0') as usize);
+            (n, &s[len..])
+        }
+
+        let mut spec = Self::default();
+        while let Some((&ch, rest)) = s.split_first() {
+            match ch {
+                _ if !flags.contains(&ch) => break,
+                b'-' => spec.left_justify = true,
+                b'+' => spec.always_sign = true,
+                b' ' => spec.space_sign = true,
+                b'#' => spec.alternative_form = true,
+                _ => spec.zero_pad = true,
             }
-            match (
-                self.left_justify,     // < or >
-                self.always_sign,      // +
-                self.alternative_form, // #
-                self.zero_pad,         // 0
-            ) {
-                (true, true, true, true) => f!("<+#0"),
-                (true, true, true, false) => f!("<+#"),
-                (true, true, false, true) => f!("<+0"),
-                (true, true, false, false) => f!("<+"),
-                (true, false, true, true) => f!("<+#0"),
-                (true, false, true, false) => f!("<#"),
-                (true, false, false, true) => f!("<0"),
-                (true, false, false, false) => f!("<"),
-                (false, true, true, true) => f!(">+#0"),
-                (false, true, true, false) => f!(">+#"),
-                (false, true, false, true) => f!(">+0"),
-                (false, true, false, false) => f!(">+"),
-                (false, false, true, true) => f!(">#0"),
-                (false, false, true, false) => f!(">#"),
-                (false, false, false, true) => f!(">0"),
-                (false, false, false, false) => f!(">"),
+            s = rest;
+        }
+        if s.first() == Some(&b'0') {
+            return None;
+        }
+        (spec.width, s) = two_digits(s);
+        if let [b'.', rest @ ..] = s {
+            if !takes_precision {
+                return None;
             }
+            spec.has_precision = true;
+            (spec.precision, s) = two_digits(rest);
         }
-    };
-}
+        s.is_empty().then_some(spec)
+    }
 
-impl Specification {
-    fmt_with_specifier!(fmt_display, std::fmt::Display, "");
-    fmt_with_specifier!(fmt_octal, std::fmt::Octal, "o");
-    fmt_with_specifier!(fmt_lower_hex, std::fmt::LowerHex, "x");
-    fmt_with_specifier!(fmt_upper_hex, std::fmt::UpperHex, "X");
-    fmt_with_specifier!(fmt_lower_exp, std::fmt::LowerExp, "e");
-    fmt_with_specifier!(fmt_upper_exp, std::fmt::UpperExp, "E");
-    fmt_with_specifier!(fmt_ptr, std::fmt::Pointer, "p");
+    // sprintf("%d") and friends. Integers other than %d and %i are written as
+    // unsigned.
+    fn fmt_integer<W>(&self, f: &mut W, value: Integer, specifier: u8) -> crate::io::Result<()>
+    where
+        W: crate::io::Write,
+    {
+        let mut digits = match specifier {
+            b'd' | b'i' => value.unsigned_abs().to_string(),
+            b'o' => format!("{:o}", value as u64),
+            b'x' => format!("{:x}", value as u64),
+            b'X' => format!("{:X}", value as u64),
+            _ => (value as u64).to_string(),
+        };
+        if self.has_precision {
+            if self.precision == 0 && value == 0 {
+                digits.clear();
+            } else if digits.len() < self.precision {
+                digits.insert_str(0, &"0".repeat(self.precision - digits.len()));
+            }
+        }
+        let prefix = match specifier {
+            b'o' if self.alternative_form && !digits.starts_with('0') => "0",
+            b'x' if self.alternative_form && value != 0 => "0x",
+            b'X' if self.alternative_form && value != 0 => "0X",
+            _ => "",
+        };
+        let sign = if value < 0 && matches!(specifier, b'd' | b'i') {
+            "-"
+        } else {
+            ""
+        };
+        let s = format!("{sign}{prefix}{digits}");
+        Self {
+            zero_pad: self.zero_pad && !self.has_precision,
+            ..*self
+        }
+        .fmt_number_bytes(f, s.as_bytes())
+    }
 
-    fn fmt_bytes<W, T>(&self, f: &mut W, value: T) -> std::io::Result<()>
+    fn fmt_bytes<W, T>(&self, f: &mut W, value: T) -> crate::io::Result<()>
     where
-        W: std::io::Write,
+        W: crate::io::Write,
         T: AsRef<[u8]>,
     {
         let s = value.as_ref();
@@ -340,9 +302,38 @@ impl Specification {
         }
         Ok(())
     }
+
+    // Pads a number written by one of the float formatters, adding the
+    // sign and zeros asked for
+    fn fmt_number_bytes<W: crate::io::Write>(&self, f: &mut W, s: &[u8]) -> crate::io::Result<()> {
+        let (sign, digits): (&[u8], _) = match s {
+            [b'-', rest @ ..] => (b"-", rest),
+            _ if self.always_sign => (b"+", s),
+            _ if self.space_sign => (b" ", s),
+            _ => (b"", s),
+        };
+        let (prefix, digits) = if digits.starts_with(b"0x") || digits.starts_with(b"0X") {
+            digits.split_at(2)
+        } else {
+            digits.split_at(0)
+        };
+        let len = sign.len() + prefix.len() + digits.len();
+        let is_finite = digits.first().is_some_and(u8::is_ascii_hexdigit);
+
+        if self.zero_pad && !self.left_justify && is_finite && len < self.width {
+            f.write_all(sign)?;
+            f.write_all(prefix)?;
+            for _ in len..self.width {
+                f.write_all(b"0")?;
+            }
+            f.write_all(digits)
+        } else {
+            self.fmt_bytes(f, [sign, prefix, digits].concat())
+        }
+    }
 }
 
-fn fmt_literal<W: std::io::Write>(f: &mut W, value: Value) -> Result<bool, ErrorKind> {
+fn fmt_literal<W: crate::io::Write>(f: &mut W, value: Value) -> Result<bool, ErrorKind> {
     match value {
         Value::Nil | Value::Boolean(_) => value.fmt_bytes(f)?,
         Value::Integer(Integer::MIN) => f.write_all(b"0x8000000000000000")?,
@@ -351,7 +342,7 @@ fn fmt_literal<W: std::io::Write>(f: &mut W, value: Value) -> Result<bool, Error
             x if x == Number::INFINITY => f.write_all(b"1e9999")?,
             x if x == Number::NEG_INFINITY => f.write_all(b"-1e9999")?,
             x if x.is_nan() => f.write_all(b"(0/0)")?,
-            _ => sprintf_a(f, x)?,
+            _ => fmt_float_hex(f, x, None, false)?,
         },
         Value::String(s) => {
             f.write_u8(b'"')?;
@@ -375,79 +366,3 @@ fn fmt_literal<W: std::io::Write>(f: &mut W, value: Value) -> Result<bool, Error
     }
     Ok(true)
 }
-
-// sprintf("%.Pg") where P is precision
-fn sprintf_g<W: std::io::Write>(f: &mut W, x: Number, precision: usize) -> std::io::Result<()> {
-    if x == 0.0 {
-        return f.write_all(b"0");
-    }
-
-    let log_x = x.abs().log10();
-    let mut precision = precision - 1;
-    if log_x < -3.0 || (precision as Number) < log_x {
-        return write!(f, "{x:.precision$e}");
-    }
-
-    precision = (precision as isize - log_x.trunc() as isize) as usize;
-    if log_x < 0.0 {
-        precision += 1
-    }
-    let s = format!("{x:.precision$}");
-    let mut s = s.as_str();
-    if s.contains('.') {
-        s = s.trim_end_matches('0').trim_end_matches('.');
-    }
-    f.write_all(s.as_bytes())
-}
-
-// sprintf("%a")
-fn sprintf_a<W: std::io::Write>(f: &mut W, mut x: Number) -> std::io::Result<()> {
-    fn write_digit<W: std::io::Write>(f: &mut W, frac: &mut f64) -> std::io::Result<()> {
-        let digit = *frac as u8;
-        f.write_u8(if digit < 10 {
-            digit + b'0'
-        } else {
-            digit - 10 + b'a'
-        })?;
-        *frac -= digit as Number;
-        Ok(())
-    }
-
-    match x {
-        x if !x.is_finite() => return write!(f, "{x}"),
-        x if x == 0.0 => return write!(f, "{x}x0p+0"), // 0 or -0
-        _ if x < 0.0 => {
-            f.write_all(b"-")?;
-            x = -x;
-        }
-        _ => (),
-    }
-
-    f.write_all(b"0x")?;
-
-    let (mut frac, mut exp) = math::frexp(x);
-    if exp >= Number::MIN_EXP - 1 {
-        frac *= 2.0; // [0.5, 1) -> [1, 2)
-        exp -= 1;
-    } else {
-        // subnormal
-        while exp < Number::MIN_EXP - 1 {
-            frac /= 2.0;
-            exp += 1;
-        }
-    }
-    write_digit(f, &mut frac)?;
-
-    if frac > 0.0 {
-        f.write_u8(b'.')?;
-        loop {
-            frac *= 16.0;
-            write_digit(f, &mut frac)?;
-            if frac <= 0.0 {
-                break;
-            }
-        }
-    }
-
-    write!(f, "p{exp:+}")
-}
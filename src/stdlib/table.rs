@@ -1,6 +1,7 @@
 use super::helpers::{set_functions_to_table, ArgumentsExt};
 use crate::{
     gc::{GcCell, GcContext},
+    prelude::*,
     runtime::{Action, ErrorKind, Vm},
     types::{Integer, Table, Value},
 };
@@ -13,7 +14,9 @@ pub fn load<'gc>(gc: &'gc GcContext, _: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>>
         &mut table,
         &[
             (B("concat"), table_concat),
+            (B("freeze"), table_freeze),
             (B("insert"), table_insert),
+            (B("isfrozen"), table_isfrozen),
             (B("move"), table_move),
             (B("pack"), table_pack),
             (B("remove"), table_remove),
@@ -38,7 +41,7 @@ fn table_concat<'gc>(
     let mut strings = Vec::new();
     for index in i..=j {
         let value = table.get_integer_key(index);
-        if let Some(string) = value.to_string() {
+        if let Some(string) = value.coerce_to_string() {
             strings.push(string.to_vec());
         } else {
             return Err(ErrorKind::Other(format!(
@@ -55,6 +58,16 @@ fn table_concat<'gc>(
         .into()]))
 }
 
+fn table_freeze<'gc>(
+    gc: &'gc GcContext,
+    _: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let table = args.nth(1).as_table()?;
+    table.borrow_mut(gc).freeze();
+    Ok(Action::Return(vec![table.into()]))
+}
+
 fn table_insert<'gc>(
     gc: &'gc GcContext,
     _: &mut Vm<'gc>,
@@ -62,6 +75,7 @@ fn table_insert<'gc>(
 ) -> Result<Action<'gc>, ErrorKind> {
     let table = args.nth(1).as_table()?;
     let mut table = table.borrow_mut(gc);
+    table.check_not_frozen()?;
     let end = table.lua_len().wrapping_add(1);
 
     match *args.without_callee() {
@@ -85,6 +99,16 @@ fn table_insert<'gc>(
     Ok(Action::Return(Vec::new()))
 }
 
+fn table_isfrozen<'gc>(
+    _: &'gc GcContext,
+    _: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let table = args.nth(1).as_table()?;
+    let frozen = table.borrow().is_frozen();
+    Ok(Action::Return(vec![frozen.into()]))
+}
+
 fn table_move<'gc>(
     gc: &'gc GcContext,
     _: &mut Vm<'gc>,
@@ -96,6 +120,7 @@ fn table_move<'gc>(
     let a1 = args.nth(1).as_table()?;
     let a2 = args.nth(5);
     let a2 = if a2.is_present() { a2.as_table()? } else { a1 };
+    a2.borrow().check_not_frozen()?;
 
     if f > e {
         return Ok(Action::Return(vec![a2.into()]));
@@ -158,6 +183,7 @@ fn table_remove<'gc>(
 ) -> Result<Action<'gc>, ErrorKind> {
     let table = args.nth(1).as_table()?;
     let mut table = table.borrow_mut(gc);
+    table.check_not_frozen()?;
     let len = table.lua_len();
 
     let pos = args.nth(2).to_integer_or(len)?;
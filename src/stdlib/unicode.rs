@@ -0,0 +1,121 @@
+use super::helpers::{set_functions_to_table, ArgumentsExt};
+use crate::{
+    gc::{GcCell, GcContext},
+    runtime::{Action, ErrorKind, Vm},
+    types::{Integer, NativeClosure, Table, Value},
+};
+use bstr::B;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+pub fn load<'gc>(gc: &'gc GcContext, _: &mut Vm<'gc>) -> GcCell<'gc, Table<'gc>> {
+    let mut table = Table::new();
+    set_functions_to_table(
+        gc,
+        &mut table,
+        &[
+            (B("graphemes"), unicode_graphemes),
+            (B("lower"), unicode_lower),
+            (B("normalize"), unicode_normalize),
+            (B("upper"), unicode_upper),
+        ],
+    );
+    gc.allocate_cell(table)
+}
+
+fn nth_str(s: &[u8], nth: usize) -> Result<&str, ErrorKind> {
+    std::str::from_utf8(s).map_err(|_| ErrorKind::ArgumentError {
+        nth,
+        message: "invalid UTF-8 string",
+    })
+}
+
+// Returns an iterator over the extended grapheme clusters of the string,
+// yielding the position and contents of each cluster like utf8.codes does
+// for code points
+fn unicode_graphemes<'gc>(
+    gc: &'gc GcContext,
+    _: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let s = args.nth(1);
+    let s = s.to_string()?;
+    let starts: Vec<_> = nth_str(&s, 1)?
+        .grapheme_indices(true)
+        .map(|(i, _)| i)
+        .collect();
+    let s = gc.allocate_string(s.as_ref());
+
+    // The clusters are found up front, and the iterator uses its own copy
+    // of the string so that they stay valid for it
+    let iterate = NativeClosure::with_upvalue(s, move |gc, _, s, args| {
+        let n = args.nth(2).to_integer()?;
+        let k = starts.partition_point(|&start| (start as Integer) < n);
+        let Some(&start) = starts.get(k) else {
+            return Ok(Action::Return(Vec::new()));
+        };
+        let end = starts.get(k + 1).copied().unwrap_or(s.len());
+        Ok(Action::Return(vec![
+            ((start + 1) as Integer).into(),
+            gc.allocate_string(&s[start..end]).into(),
+        ]))
+    });
+    Ok(Action::Return(vec![
+        gc.allocate(iterate).into(),
+        s.into(),
+        0.into(),
+    ]))
+}
+
+fn unicode_lower<'gc>(
+    gc: &'gc GcContext,
+    _: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let s = args.nth(1);
+    let s = s.to_string()?;
+    let lower = nth_str(&s, 1)?.to_lowercase();
+    Ok(Action::Return(vec![gc
+        .allocate_string(lower.into_bytes())
+        .into()]))
+}
+
+fn unicode_upper<'gc>(
+    gc: &'gc GcContext,
+    _: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let s = args.nth(1);
+    let s = s.to_string()?;
+    let upper = nth_str(&s, 1)?.to_uppercase();
+    Ok(Action::Return(vec![gc
+        .allocate_string(upper.into_bytes())
+        .into()]))
+}
+
+fn unicode_normalize<'gc>(
+    gc: &'gc GcContext,
+    _: &mut Vm<'gc>,
+    args: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    let s = args.nth(1);
+    let s = s.to_string()?;
+    let s = nth_str(&s, 1)?;
+    let form = args.nth(2);
+    let form = form.to_string_or(B("NFC"))?;
+    let normalized: String = match form.as_ref() {
+        b"NFC" => s.nfc().collect(),
+        b"NFD" => s.nfd().collect(),
+        b"NFKC" => s.nfkc().collect(),
+        b"NFKD" => s.nfkd().collect(),
+        _ => {
+            return Err(ErrorKind::ArgumentError {
+                nth: 2,
+                message: "invalid normalization form",
+            })
+        }
+    };
+    Ok(Action::Return(vec![gc
+        .allocate_string(normalized.into_bytes())
+        .into()]))
+}
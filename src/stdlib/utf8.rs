@@ -1,6 +1,7 @@
 use super::helpers::{set_functions_to_table, ArgumentsExt};
 use crate::{
     gc::{GcCell, GcContext},
+    prelude::*,
     runtime::{Action, ErrorKind, Vm},
     string,
     types::{Integer, NativeFunction, Table, Value},
@@ -110,7 +111,7 @@ fn utf8_codes<'gc>(
         });
     }
 
-    let lax = args.nth(2).to_boolean().unwrap_or_default();
+    let lax = args.nth(2).is_truthy();
 
     Ok(Action::Return(vec![
         NativeFunction::new(if lax { iterate_lax } else { iterate_strict }).into(),
@@ -128,7 +129,7 @@ fn utf8_codepoint<'gc>(
     let s = s.to_string()?;
     let i = args.nth(2).to_integer_or(1)?;
     let j = args.nth(3).to_integer_or(i)?;
-    let lax = args.nth(4).to_boolean().unwrap_or_default();
+    let lax = args.nth(4).is_truthy();
     if i < 1 {
         return Err(ErrorKind::ArgumentError {
             nth: 2,
@@ -170,7 +171,7 @@ fn utf8_len<'gc>(
     let s = s.to_string()?;
     let i = args.nth(2).to_integer_or(1)?;
     let j = args.nth(3).to_integer_or(-1)?;
-    let lax = args.nth(4).to_boolean().unwrap_or_default();
+    let lax = args.nth(4).is_truthy();
     if i < 1 || (s.len() as Integer) + 1 < i {
         return Err(ErrorKind::ArgumentError {
             nth: 2,
@@ -1,5 +1,6 @@
 use crate::{
     math,
+    prelude::*,
     types::{Integer, Number},
 };
 
@@ -115,6 +116,66 @@ pub fn parse_positive_integer_with_base<S: AsRef<[u8]>>(s: S, base: Integer) ->
     Some(i)
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Numeral {
+    Integer(Integer),
+    Float(Number),
+}
+
+// Converts a numeral like luaO_str2num, for the lexer, tonumber and string
+// coercion alike. Surrounding whitespace and a sign are allowed. Hexadecimal
+// integers wrap around modulo 2^64, decimal integers that don't fit become
+// floats, and "inf" and "nan" aren't numerals.
+pub fn parse_numeral<S: AsRef<[u8]>>(s: S) -> Option<Numeral> {
+    let s = trim_whitespaces(s.as_ref());
+    let (is_negative, s) = match s {
+        [b'-', rest @ ..] => (true, rest),
+        [b'+', rest @ ..] => (false, rest),
+        _ => (false, s),
+    };
+
+    if let Some(hex) = s.strip_prefix(b"0x").or_else(|| s.strip_prefix(b"0X")) {
+        if let Some(i) = parse_positive_integer_with_base(hex, 16) {
+            return Some(Numeral::Integer(if is_negative {
+                i.wrapping_neg()
+            } else {
+                i
+            }));
+        }
+        let x = parse_positive_hex_float(hex)?;
+        return Some(Numeral::Float(if is_negative { -x } else { x }));
+    }
+
+    if !s
+        .first()
+        .is_some_and(|&ch| ch.is_ascii_digit() || ch == b'.')
+    {
+        return None;
+    }
+    if s.iter().all(u8::is_ascii_digit) {
+        let magnitude = s.iter().try_fold(0u64, |acc, &ch| {
+            acc.checked_mul(10)?.checked_add((ch - b'0') as u64)
+        });
+        match magnitude {
+            Some(m) if is_negative && m <= Integer::MIN.unsigned_abs() => {
+                return Some(Numeral::Integer((m as Integer).wrapping_neg()))
+            }
+            Some(m) if !is_negative && m <= Integer::MAX as u64 => {
+                return Some(Numeral::Integer(m as Integer))
+            }
+            _ => (),
+        }
+    }
+    if !s
+        .iter()
+        .all(|&ch| ch.is_ascii_digit() || matches!(ch, b'.' | b'e' | b'E' | b'+' | b'-'))
+    {
+        return None;
+    }
+    let x: Number = core::str::from_utf8(s).ok()?.parse().ok()?;
+    Some(Numeral::Float(if is_negative { -x } else { x }))
+}
+
 pub fn parse_positive_hex_float<S: AsRef<[u8]>>(s: S) -> Option<Number> {
     const MAX_NUM_SIGNIFICANT_DIGITS: usize = 30;
 
@@ -181,7 +242,8 @@ pub fn parse_positive_hex_float<S: AsRef<[u8]>>(s: S) -> Option<Number> {
         if !ch.is_ascii_digit() {
             return None;
         }
-        exp = exp * 10 + (ch - b'0') as i32;
+        // Any exponent this large overflows or underflows the result anyway
+        exp = (exp * 10 + (ch - b'0') as i32).min(1 << 16);
     }
     if is_exp_negative {
         exp = -exp;
@@ -189,3 +251,160 @@ pub fn parse_positive_hex_float<S: AsRef<[u8]>>(s: S) -> Option<Number> {
 
     Some(math::ldexp(mantissa, mantissa_exp + exp))
 }
+
+// Writes "inf", "-inf", "nan" or "-nan" like C's printf
+fn fmt_non_finite<W: crate::io::Write>(f: &mut W, x: Number) -> crate::io::Result<()> {
+    if x.is_sign_negative() {
+        f.write_all(b"-")?;
+    }
+    f.write_all(if x.is_nan() { b"nan" } else { b"inf" })
+}
+
+// sprintf("%.Pg"), or "%#.Pg" if `alternative_form`, in lowercase
+pub fn fmt_float_g<W: crate::io::Write>(
+    f: &mut W,
+    x: Number,
+    precision: usize,
+    alternative_form: bool,
+) -> crate::io::Result<()> {
+    if !x.is_finite() {
+        return fmt_non_finite(f, x);
+    }
+
+    // The exponent depends on how x is rounded, so it's taken from the %e
+    // form with the same number of significant digits
+    let precision = precision.max(1);
+    let fraction_digits = precision - 1;
+    let e_form = format!("{x:.fraction_digits$e}");
+    let (mantissa, exp) = e_form.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+
+    let mut s = if exp < -4 || exp >= precision as i32 {
+        mantissa.to_owned()
+    } else {
+        let fraction_digits = (precision as i32 - 1 - exp) as usize;
+        format!("{x:.fraction_digits$}")
+    };
+    if alternative_form {
+        if !s.contains('.') {
+            s.push('.');
+        }
+    } else if s.contains('.') {
+        s.truncate(s.trim_end_matches('0').trim_end_matches('.').len());
+    }
+    f.write_all(s.as_bytes())?;
+
+    if exp < -4 || exp >= precision as i32 {
+        fmt_exponent(f, exp)?;
+    }
+    Ok(())
+}
+
+// sprintf("%.Pe"), or "%#.Pe" if `alternative_form`, in lowercase
+pub fn fmt_float_e<W: crate::io::Write>(
+    f: &mut W,
+    x: Number,
+    precision: usize,
+    alternative_form: bool,
+) -> crate::io::Result<()> {
+    if !x.is_finite() {
+        return fmt_non_finite(f, x);
+    }
+    let e_form = format!("{x:.precision$e}");
+    let (mantissa, exp) = e_form.split_once('e').unwrap();
+    f.write_all(mantissa.as_bytes())?;
+    if alternative_form && precision == 0 {
+        f.write_all(b".")?;
+    }
+    fmt_exponent(f, exp.parse().unwrap())
+}
+
+// sprintf("%.Pf"), or "%#.Pf" if `alternative_form`
+pub fn fmt_float_f<W: crate::io::Write>(
+    f: &mut W,
+    x: Number,
+    precision: usize,
+    alternative_form: bool,
+) -> crate::io::Result<()> {
+    if !x.is_finite() {
+        return fmt_non_finite(f, x);
+    }
+    write!(f, "{x:.precision$}")?;
+    if alternative_form && precision == 0 {
+        f.write_all(b".")?;
+    }
+    Ok(())
+}
+
+// C writes at least two digits of the exponent, unlike Rust's "{:e}"
+fn fmt_exponent<W: crate::io::Write>(f: &mut W, exp: i32) -> crate::io::Result<()> {
+    let sign = if exp < 0 { '-' } else { '+' };
+    write!(f, "e{sign}{:02}", exp.unsigned_abs())
+}
+
+// sprintf("%a"), or "%.Pa" given a precision, in lowercase. Subnormals are
+// written as "0x0.<digits>p-1022" like glibc does.
+pub fn fmt_float_hex<W: crate::io::Write>(
+    f: &mut W,
+    x: Number,
+    precision: Option<usize>,
+    alternative_form: bool,
+) -> crate::io::Result<()> {
+    const MANTISSA_DIGITS: usize = 13;
+    const MANTISSA_MASK: u64 = (1 << 52) - 1;
+
+    if !x.is_finite() {
+        return fmt_non_finite(f, x);
+    }
+    if x.is_sign_negative() {
+        f.write_all(b"-")?;
+    }
+
+    let bits = x.to_bits();
+    let biased_exp = ((bits >> 52) & 0x7ff) as i32;
+    let mut mantissa = bits & MANTISSA_MASK;
+    let (mut leading, exp) = match (biased_exp, mantissa) {
+        (0, 0) => (0, 0),
+        (0, _) => (0, -1022),
+        _ => (1, biased_exp - 1023),
+    };
+
+    let digits = match precision {
+        Some(precision) if precision < MANTISSA_DIGITS => {
+            // Round half to even, carrying into the leading digit
+            let shift = 4 * (MANTISSA_DIGITS - precision) as u32;
+            let rest = mantissa & ((1 << shift) - 1);
+            let half = 1 << (shift - 1);
+            mantissa >>= shift;
+            let last_digit = if precision == 0 { leading } else { mantissa };
+            if rest > half || (rest == half && last_digit & 1 == 1) {
+                mantissa += 1;
+                if mantissa >> (4 * precision) != 0 {
+                    mantissa &= (1 << (4 * precision)) - 1;
+                    leading += 1;
+                }
+            }
+            precision
+        }
+        _ => MANTISSA_DIGITS,
+    };
+    let mut fraction = if digits == 0 {
+        String::new()
+    } else {
+        format!("{mantissa:0digits$x}")
+    };
+    match precision {
+        Some(precision) => {
+            for _ in fraction.len()..precision {
+                fraction.push('0');
+            }
+        }
+        None => fraction.truncate(fraction.trim_end_matches('0').len()),
+    }
+
+    write!(f, "0x{leading}")?;
+    if !fraction.is_empty() || alternative_form {
+        write!(f, ".{fraction}")?;
+    }
+    write!(f, "p{exp:+}")
+}
@@ -1,32 +1,42 @@
+mod convert;
+mod deep_clone;
+mod detached;
 mod function;
+mod packed;
 mod string;
 mod table;
 mod thread;
 mod user_data;
 
-pub(crate) use function::Upvalue;
+use crate::prelude::*;
+pub use convert::{
+    FromLua, FromLuaMulti, IntoAction, IntoLua, IntoLuaMulti, MultiValue, NativeFn, Variadic,
+};
+pub use deep_clone::DeepCloneError;
+pub use detached::{ConversionError, DetachedValue};
 pub use function::{
-    AbsLineInfo, LineRange, LocalVariable, LuaClosure, LuaClosureProto, NativeClosure,
-    NativeFunction, NativeFunctionPtr, RegisterIndex, UpvalueDescription, UpvalueIndex,
+    AbsLineInfo, Constants, LineRange, LocalVariable, LuaClosure, LuaClosureProto, NativeClosure,
+    NativeFunction, NativeFunctionPtr, NativeModule, RegisterIndex, UpvalueDescription,
+    UpvalueIndex,
 };
+pub(crate) use function::{InlineCaches, Upvalue};
 pub use string::LuaString;
-pub use table::{Table, TableError};
+pub use table::{Table, TableCursor, TableError};
 pub(crate) use thread::ThreadStatus;
 pub use thread::{LuaThread, TracebackFrame};
 pub use user_data::UserData;
 
 use crate::{
     gc::{GarbageCollect, Gc, GcCell, GcContext, Tracer},
+    io::Write,
     number_is_valid_integer,
-    string::{parse_positive_hex_float, parse_positive_integer_with_base, trim_whitespaces},
+    string::{fmt_float_g, fmt_float_hex, parse_numeral, Numeral},
 };
-use bstr::ByteSlice;
-use std::{
+use alloc::borrow::Cow;
+use core::{
     any::Any,
-    borrow::Cow,
     cell::{Ref, RefMut},
-    fmt::Display,
-    io::Write,
+    fmt::{Debug, Display, Write as _},
 };
 
 macro_rules! types {
@@ -60,7 +70,7 @@ types! {
 }
 
 impl Display for Type {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.write_str(self.name())
     }
 }
@@ -68,8 +78,10 @@ impl Display for Type {
 pub type Integer = i64;
 pub type Number = f64;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy, Default)]
+#[cfg_attr(feature = "jit", repr(C, u8))]
 pub enum Value<'gc> {
+    #[default]
     Nil,
     Boolean(bool),
     Integer(Integer),
@@ -83,12 +95,6 @@ pub enum Value<'gc> {
     Thread(GcCell<'gc, LuaThread<'gc>>),
 }
 
-impl Default for Value<'_> {
-    fn default() -> Self {
-        Self::Nil
-    }
-}
-
 impl From<bool> for Value<'_> {
     fn from(x: bool) -> Self {
         Self::Boolean(x)
@@ -107,6 +113,15 @@ impl From<Number> for Value<'_> {
     }
 }
 
+impl From<Numeral> for Value<'_> {
+    fn from(x: Numeral) -> Self {
+        match x {
+            Numeral::Integer(i) => Self::Integer(i),
+            Numeral::Float(x) => Self::Number(x),
+        }
+    }
+}
+
 impl From<NativeFunction> for Value<'_> {
     fn from(x: NativeFunction) -> Self {
         Self::NativeFunction(x)
@@ -176,9 +191,9 @@ impl PartialEq for Value<'_> {
 
 impl Eq for Value<'_> {}
 
-impl std::hash::Hash for Value<'_> {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        std::mem::discriminant(self).hash(state);
+impl core::hash::Hash for Value<'_> {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        core::mem::discriminant(self).hash(state);
         match self {
             Self::Nil => {}
             Self::Boolean(x) => x.hash(state),
@@ -209,13 +224,84 @@ unsafe impl GarbageCollect for Value<'_> {
     }
 }
 
+impl Debug for Value<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.display_debug().fmt(f)
+    }
+}
+
+// Formats a value for diagnostics rather than like tostring does: strings
+// are quoted and escaped, tables show their length and Lua functions where
+// they are defined
+pub struct DisplayDebug<'a, 'gc>(&'a Value<'gc>);
+
+impl Display for DisplayDebug<'_, '_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 {
+            Value::String(x) => {
+                f.write_char('"')?;
+                for chunk in x.as_bytes().utf8_chunks() {
+                    for ch in chunk.valid().chars() {
+                        match ch {
+                            '"' => f.write_str("\\\"")?,
+                            '\\' => f.write_str("\\\\")?,
+                            '\n' => f.write_str("\\n")?,
+                            '\r' => f.write_str("\\r")?,
+                            '\t' => f.write_str("\\t")?,
+                            ch if ch.is_control() => write!(f, "\\u{{{:x}}}", ch as u32)?,
+                            ch => f.write_char(ch)?,
+                        }
+                    }
+                    for byte in chunk.invalid() {
+                        write!(f, "\\x{byte:02x}")?;
+                    }
+                }
+                f.write_char('"')
+            }
+            // The table may be borrowed mutably by whatever is being
+            // diagnosed
+            Value::Table(x) => match x.try_borrow() {
+                Ok(table) => write!(f, "table: {:p} len={}", x.as_ptr(), table.lua_len()),
+                Err(_) => write!(f, "table: {:p}", x.as_ptr()),
+            },
+            Value::LuaClosure(x) => {
+                let source = String::from_utf8_lossy(x.proto.source.as_bytes());
+                let source = crate::chunk_id_from_source(&source);
+                match &x.proto.lines_defined {
+                    LineRange::File => write!(f, "function: {source}"),
+                    LineRange::Lines(lines) => write!(f, "function: {source}:{}", lines.start()),
+                }
+            }
+            Value::NativeFunction(x) => write!(f, "function: native {:p}", x.as_ptr()),
+            Value::NativeClosure(x) => write!(f, "function: native {:p}", x.as_ptr()),
+            value => {
+                let mut bytes = Vec::new();
+                value.fmt_bytes(&mut bytes).map_err(|_| core::fmt::Error)?;
+                f.write_str(&String::from_utf8_lossy(&bytes))
+            }
+        }
+    }
+}
+
 impl<'gc> Value<'gc> {
-    pub fn fmt_bytes(&self, f: &mut impl std::io::Write) -> std::io::Result<()> {
+    pub fn display_debug(&self) -> DisplayDebug<'_, 'gc> {
+        DisplayDebug(self)
+    }
+
+    pub fn fmt_bytes(&self, f: &mut impl crate::io::Write) -> crate::io::Result<()> {
+        self.fmt_bytes_with(f, FloatFormat::default())
+    }
+
+    pub fn fmt_bytes_with(
+        &self,
+        f: &mut impl crate::io::Write,
+        float_format: FloatFormat,
+    ) -> crate::io::Result<()> {
         match self {
             Self::Nil => f.write_all(b"nil"),
             Self::Boolean(x) => write!(f, "{x}"),
             Self::Integer(x) => write!(f, "{x}"),
-            Self::Number(x) => fmt_number(f, *x),
+            Self::Number(x) => float_format.fmt(f, *x),
             Self::NativeFunction(x) => write!(f, "function: {:p}", x.as_ptr()),
             Self::String(x) => f.write_all(x.as_bytes()),
             Self::Table(x) => write!(f, "table: {:p}", x.as_ptr()),
@@ -253,56 +339,38 @@ impl<'gc> Value<'gc> {
         matches!(self, Value::Nil)
     }
 
-    pub fn to_boolean(&self) -> bool {
+    // Only nil and false are falsy, as in conditions
+    pub fn is_truthy(&self) -> bool {
         !matches!(self, Value::Nil | Value::Boolean(false))
     }
 
-    pub fn to_number(&self) -> Option<Number> {
+    // Conversions follow "Coercions and Conversions" in the manual: strings
+    // are read as numerals, and numbers are written like tostring does
+
+    // The number a value converts to in arithmetic, an integer or a float
+    pub fn coerce_to_number(&self) -> Option<Value<'gc>> {
         match self {
-            Self::Number(x) => Some(*x),
-            Self::Integer(x) => Some(*x as Number),
-            Self::String(s) => trim_whitespaces(s).to_str().ok().and_then(parse_number),
+            Self::Integer(_) | Self::Number(_) => Some(*self),
+            Self::String(s) => parse_numeral(s).map(Value::from),
             _ => None,
         }
     }
 
-    pub fn to_number_without_string_coercion(&self) -> Option<Number> {
-        match self {
-            Self::Number(x) => Some(*x),
-            Self::Integer(x) => Some(*x as Number),
-            _ => None,
-        }
+    pub fn coerce_to_float(&self) -> Option<Number> {
+        self.coerce_to_number()?.to_number_without_string_coercion()
     }
 
-    pub fn to_integer(&self) -> Option<Integer> {
-        match self {
-            Self::Number(x) if number_is_valid_integer(*x) => Some(*x as Integer),
-            Self::Integer(x) => Some(*x),
-            Self::String(s) => {
-                let s = trim_whitespaces(s).to_str().ok()?;
-                if let Some(i) = parse_integer(s) {
-                    return Some(i);
-                }
-                if let Some(x) = parse_number(s) {
-                    if number_is_valid_integer(x) {
-                        return Some(x as Integer);
-                    }
-                }
-                None
-            }
-            _ => None,
-        }
+    // Floats convert only if they have an exact integer representation
+    pub fn coerce_to_integer(&self) -> Option<Integer> {
+        self.coerce_to_number()?
+            .to_integer_without_string_coercion()
     }
 
-    pub fn to_integer_without_string_coercion(&self) -> Option<Integer> {
-        match self {
-            Self::Number(x) if number_is_valid_integer(*x) => Some(*x as Integer),
-            Self::Integer(x) => Some(*x),
-            _ => None,
-        }
+    pub fn coerce_to_string(&self) -> Option<Cow<'_, [u8]>> {
+        self.coerce_to_string_with(FloatFormat::default())
     }
 
-    pub fn to_string(&self) -> Option<Cow<[u8]>> {
+    pub fn coerce_to_string_with(&self, float_format: FloatFormat) -> Option<Cow<'_, [u8]>> {
         match self {
             Self::String(x) => Some(Cow::Borrowed(x.as_bytes())),
             Self::Integer(x) => {
@@ -312,13 +380,29 @@ impl<'gc> Value<'gc> {
             }
             Self::Number(x) => {
                 let mut bytes = Vec::new();
-                fmt_number(&mut bytes, *x).ok()?;
+                float_format.fmt(&mut bytes, *x).ok()?;
                 Some(Cow::Owned(bytes))
             }
             _ => None,
         }
     }
 
+    pub fn to_number_without_string_coercion(&self) -> Option<Number> {
+        match self {
+            Self::Number(x) => Some(*x),
+            Self::Integer(x) => Some(*x as Number),
+            _ => None,
+        }
+    }
+
+    pub fn to_integer_without_string_coercion(&self) -> Option<Integer> {
+        match self {
+            Self::Number(x) if number_is_valid_integer(*x) => Some(*x as Integer),
+            Self::Integer(x) => Some(*x),
+            _ => None,
+        }
+    }
+
     pub fn as_table(&self) -> Option<GcCell<'gc, Table<'gc>>> {
         if let Self::Table(x) = self {
             Some(*x)
@@ -327,7 +411,7 @@ impl<'gc> Value<'gc> {
         }
     }
 
-    pub fn borrow_as_table(&self) -> Option<Ref<Table<'gc>>> {
+    pub fn borrow_as_table(&self) -> Option<Ref<'_, Table<'gc>>> {
         if let Self::Table(x) = self {
             Some(x.borrow())
         } else {
@@ -335,7 +419,7 @@ impl<'gc> Value<'gc> {
         }
     }
 
-    pub fn borrow_as_table_mut(&self, gc: &'gc GcContext) -> Option<RefMut<Table<'gc>>> {
+    pub fn borrow_as_table_mut(&self, gc: &'gc GcContext) -> Option<RefMut<'_, Table<'gc>>> {
         if let Self::Table(x) = self {
             Some(x.borrow_mut(gc))
         } else {
@@ -375,7 +459,7 @@ impl<'gc> Value<'gc> {
         }
     }
 
-    pub fn borrow_as_thread(&self) -> Option<Ref<LuaThread<'gc>>> {
+    pub fn borrow_as_thread(&self) -> Option<Ref<'_, LuaThread<'gc>>> {
         if let Self::Thread(x) = self {
             Some(x.borrow())
         } else {
@@ -383,7 +467,7 @@ impl<'gc> Value<'gc> {
         }
     }
 
-    pub fn borrow_as_thread_mut(&self, gc: &'gc GcContext) -> Option<RefMut<LuaThread<'gc>>> {
+    pub fn borrow_as_thread_mut(&self, gc: &'gc GcContext) -> Option<RefMut<'_, LuaThread<'gc>>> {
         if let Self::Thread(x) = self {
             Some(x.borrow_mut(gc))
         } else {
@@ -398,7 +482,7 @@ impl<'gc> Value<'gc> {
         }
     }
 
-    pub fn borrow_as_userdata<T: Any>(&self) -> Option<Ref<T>> {
+    pub fn borrow_as_userdata<T: Any>(&self) -> Option<Ref<'_, T>> {
         if let Self::UserData(ud) = self {
             Ref::filter_map(ud.borrow(), |ud| ud.get()).ok()
         } else {
@@ -431,94 +515,34 @@ impl<'gc> Value<'gc> {
     }
 }
 
-fn parse_integer<S: AsRef<str>>(s: S) -> Option<Integer> {
-    let mut s = s.as_ref();
-    let sign = match s.as_bytes() {
-        [b'+', ..] => {
-            s = &s[1..];
-            true
-        }
-        [b'-', ..] => {
-            s = &s[1..];
-            false
-        }
-        _ => true,
-    };
-    let parsed = match s.as_bytes() {
-        [b'0', b'x' | b'X', ..] => parse_positive_integer_with_base(&s[2..], 16),
-        _ => s.parse().ok(),
-    };
-    match parsed {
-        Some(i) if sign => Some(i),
-        Some(i) => Some(i.wrapping_neg()),
-        None => None,
-    }
+// How floats are turned into strings by tostring, print and concatenation,
+// like LUAI_NUMFFORMAT in the reference implementation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloatFormat {
+    // sprintf("%.Ng"). Lua uses 14 digits, and 17 are enough for any float
+    // to be read back exactly.
+    Significant(usize),
+    // sprintf("%a"), which is always exact
+    Hex,
 }
 
-fn parse_number<S: AsRef<str>>(s: S) -> Option<Number> {
-    let mut s = s.as_ref();
-    let sign = match s.as_bytes() {
-        [b'+', ..] => {
-            s = &s[1..];
-            true
-        }
-        [b'-', ..] => {
-            s = &s[1..];
-            false
-        }
-        _ => true,
-    };
-    let parsed = match s.as_bytes() {
-        [b'0', b'x' | b'X', rest @ ..] => parse_positive_hex_float(rest),
-        s if s.eq_ignore_ascii_case(b"inf")
-            || s.eq_ignore_ascii_case(b"infinity")
-            || s.eq_ignore_ascii_case(b"nan") =>
-        {
-            return None
-        }
-        _ => s.parse().ok(),
-    };
-    match parsed {
-        Some(x) if sign => Some(x),
-        Some(x) => Some(-x),
-        None => None,
+impl Default for FloatFormat {
+    fn default() -> Self {
+        Self::Significant(14)
     }
 }
 
-// sprintf("%.14g") except it does not remove suffix ".0" when x is an integer
-fn fmt_number<W: std::io::Write>(writer: &mut W, x: Number) -> std::io::Result<()> {
-    const PRECISION: usize = 14;
-
-    if x == 0.0 {
-        return writer.write_all(b"0.0");
-    } else if x.is_nan() {
-        if x.is_sign_negative() {
-            writer.write_all(b"-")?;
+impl FloatFormat {
+    // Formats x, adding ".0" when it would look like an integer
+    pub fn fmt<W: crate::io::Write>(self, f: &mut W, x: Number) -> crate::io::Result<()> {
+        let mut s = Vec::new();
+        match self {
+            Self::Significant(precision) => fmt_float_g(&mut s, x, precision, false)?,
+            Self::Hex => fmt_float_hex(&mut s, x, None, false)?,
         }
-        return writer.write_all(b"nan");
-    }
-
-    let mut precision = PRECISION - 1;
-    let log_x = x.abs().log10();
-    if log_x < -3.0 || (precision as Number) < log_x {
-        return write!(writer, "{x:.precision$e}");
-    }
-
-    precision = (precision as isize - log_x.trunc() as isize) as usize;
-    if log_x < 0.0 {
-        precision += 1
-    }
-
-    let s = format!("{x:.precision$}");
-    let mut s = s.as_bytes();
-    if !s.contains(&b'.') {
-        return writer.write_all(s);
-    }
-
-    s = s.trim_end_with(|ch| ch == '0');
-    writer.write_all(s)?;
-    if let Some(b'.') = s.last() {
-        writer.write_all(b"0")?;
+        if s.iter().all(|&ch| ch == b'-' || ch.is_ascii_digit()) {
+            s.extend_from_slice(b".0");
+        }
+        f.write_all(&s)
     }
-    Ok(())
 }
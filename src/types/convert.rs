@@ -0,0 +1,464 @@
+use super::{ConversionError, DetachedValue, Integer, LuaString, Number, Table, Value};
+use crate::{
+    gc::{GcCell, GcContext, MaybeSend},
+    prelude::*,
+    runtime::{Action, ErrorKind},
+    stdlib::helpers::ArgumentsExt,
+    types::NativeClosure,
+};
+use bstr::BString;
+#[cfg(feature = "std")]
+use std::{collections::HashMap, hash::Hash};
+
+// Conversions between Rust and Lua values. They follow the coercions of the
+// standard library's argument checks, e.g. a string containing a number
+// converts to an integer.
+pub trait FromLua<'gc>: Sized {
+    fn from_lua(value: Value<'gc>, gc: &'gc GcContext) -> Result<Self, ConversionError>;
+}
+
+pub trait IntoLua<'gc> {
+    fn into_lua(self, gc: &'gc GcContext) -> Value<'gc>;
+}
+
+// Conversions of argument and result lists. Tuples convert value by value and
+// a single value converts the first one, ignoring the rest.
+pub trait FromLuaMulti<'gc>: Sized {
+    fn from_lua_multi(values: &[Value<'gc>], gc: &'gc GcContext) -> Result<Self, ErrorKind>;
+}
+
+pub trait IntoLuaMulti<'gc> {
+    fn into_lua_multi(self, gc: &'gc GcContext) -> Vec<Value<'gc>>;
+}
+
+// All the remaining values of a list
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Variadic<T>(pub Vec<T>);
+
+pub type MultiValue<'gc> = Variadic<Value<'gc>>;
+
+// Results of native functions. Anything convertible with IntoLuaMulti is
+// returned, and an Action is performed as is, e.g. to make a tail call.
+pub trait IntoAction<'gc> {
+    fn into_action(self, gc: &'gc GcContext) -> Action<'gc>;
+}
+
+impl<'gc, R: IntoLuaMulti<'gc>> IntoAction<'gc> for R {
+    fn into_action(self, gc: &'gc GcContext) -> Action<'gc> {
+        Action::Return(self.into_lua_multi(gc))
+    }
+}
+
+impl<'gc> IntoAction<'gc> for Action<'gc> {
+    fn into_action(self, _: &'gc GcContext) -> Action<'gc> {
+        self
+    }
+}
+
+// Rust functions that can be called from Lua, with arguments converted by
+// FromLuaMulti and results by IntoAction
+pub trait NativeFn<'gc, A> {
+    fn call_native(
+        &self,
+        values: &[Value<'gc>],
+        gc: &'gc GcContext,
+    ) -> Result<Action<'gc>, ErrorKind>;
+}
+
+impl<'gc> NativeClosure<'gc> {
+    pub fn from_fn<F, A>(f: F) -> Self
+    where
+        F: 'static + MaybeSend + NativeFn<'gc, A>,
+    {
+        Self::new(move |gc, _, args| f.call_native(args.without_callee(), gc))
+    }
+}
+
+fn conversion_error(value: Value, to: &'static str) -> ConversionError {
+    ConversionError {
+        from: value.ty().name(),
+        to,
+    }
+}
+
+impl<'gc> FromLua<'gc> for Value<'gc> {
+    fn from_lua(value: Value<'gc>, _: &'gc GcContext) -> Result<Self, ConversionError> {
+        Ok(value)
+    }
+}
+
+impl<'gc> IntoLua<'gc> for Value<'gc> {
+    fn into_lua(self, _: &'gc GcContext) -> Value<'gc> {
+        self
+    }
+}
+
+impl<'gc> FromLua<'gc> for bool {
+    fn from_lua(value: Value<'gc>, _: &'gc GcContext) -> Result<Self, ConversionError> {
+        Ok(value.is_truthy())
+    }
+}
+
+impl<'gc> IntoLua<'gc> for bool {
+    fn into_lua(self, _: &'gc GcContext) -> Value<'gc> {
+        Value::Boolean(self)
+    }
+}
+
+impl<'gc> FromLua<'gc> for Integer {
+    fn from_lua(value: Value<'gc>, _: &'gc GcContext) -> Result<Self, ConversionError> {
+        value
+            .coerce_to_integer()
+            .ok_or_else(|| conversion_error(value, "integer"))
+    }
+}
+
+impl<'gc> IntoLua<'gc> for Integer {
+    fn into_lua(self, _: &'gc GcContext) -> Value<'gc> {
+        Value::Integer(self)
+    }
+}
+
+impl<'gc> FromLua<'gc> for Number {
+    fn from_lua(value: Value<'gc>, _: &'gc GcContext) -> Result<Self, ConversionError> {
+        value
+            .coerce_to_float()
+            .ok_or_else(|| conversion_error(value, "number"))
+    }
+}
+
+impl<'gc> IntoLua<'gc> for Number {
+    fn into_lua(self, _: &'gc GcContext) -> Value<'gc> {
+        Value::Number(self)
+    }
+}
+
+macro_rules! impl_small_integer {
+    ($($ty:ty)*) => {
+        $(
+            impl<'gc> FromLua<'gc> for $ty {
+                fn from_lua(value: Value<'gc>, gc: &'gc GcContext) -> Result<Self, ConversionError> {
+                    let x = Integer::from_lua(value, gc)?;
+                    x.try_into().map_err(|_| ConversionError {
+                        from: "out of range integer",
+                        to: stringify!($ty),
+                    })
+                }
+            }
+
+            impl<'gc> IntoLua<'gc> for $ty {
+                fn into_lua(self, _: &'gc GcContext) -> Value<'gc> {
+                    Value::Integer(self.into())
+                }
+            }
+        )*
+    };
+}
+
+impl_small_integer!(i8 i16 i32 u8 u16 u32);
+
+impl<'gc> FromLua<'gc> for f32 {
+    fn from_lua(value: Value<'gc>, gc: &'gc GcContext) -> Result<Self, ConversionError> {
+        Ok(Number::from_lua(value, gc)? as f32)
+    }
+}
+
+impl<'gc> IntoLua<'gc> for f32 {
+    fn into_lua(self, _: &'gc GcContext) -> Value<'gc> {
+        Value::Number(self.into())
+    }
+}
+
+impl<'gc> FromLua<'gc> for LuaString<'gc> {
+    fn from_lua(value: Value<'gc>, gc: &'gc GcContext) -> Result<Self, ConversionError> {
+        match value {
+            Value::String(s) => Ok(s),
+            _ => value
+                .coerce_to_string()
+                .map(|s| gc.allocate_string(s.as_ref()))
+                .ok_or_else(|| conversion_error(value, "string")),
+        }
+    }
+}
+
+impl<'gc> IntoLua<'gc> for LuaString<'gc> {
+    fn into_lua(self, _: &'gc GcContext) -> Value<'gc> {
+        Value::String(self)
+    }
+}
+
+impl<'gc> FromLua<'gc> for BString {
+    fn from_lua(value: Value<'gc>, _: &'gc GcContext) -> Result<Self, ConversionError> {
+        value
+            .coerce_to_string()
+            .map(|s| s.into_owned().into())
+            .ok_or_else(|| conversion_error(value, "string"))
+    }
+}
+
+impl<'gc> IntoLua<'gc> for BString {
+    fn into_lua(self, gc: &'gc GcContext) -> Value<'gc> {
+        gc.allocate_string(Vec::from(self)).into()
+    }
+}
+
+impl<'gc> IntoLua<'gc> for &[u8] {
+    fn into_lua(self, gc: &'gc GcContext) -> Value<'gc> {
+        gc.allocate_string(self).into()
+    }
+}
+
+impl<'gc> FromLua<'gc> for String {
+    fn from_lua(value: Value<'gc>, gc: &'gc GcContext) -> Result<Self, ConversionError> {
+        String::from_utf8(BString::from_lua(value, gc)?.into()).map_err(|_| ConversionError {
+            from: "string",
+            to: "UTF-8 string",
+        })
+    }
+}
+
+impl<'gc> IntoLua<'gc> for String {
+    fn into_lua(self, gc: &'gc GcContext) -> Value<'gc> {
+        gc.allocate_string(self.into_bytes()).into()
+    }
+}
+
+impl<'gc> IntoLua<'gc> for &str {
+    fn into_lua(self, gc: &'gc GcContext) -> Value<'gc> {
+        gc.allocate_string(self.as_bytes()).into()
+    }
+}
+
+impl<'gc> FromLua<'gc> for GcCell<'gc, Table<'gc>> {
+    fn from_lua(value: Value<'gc>, _: &'gc GcContext) -> Result<Self, ConversionError> {
+        value
+            .as_table()
+            .ok_or_else(|| conversion_error(value, "table"))
+    }
+}
+
+impl<'gc> IntoLua<'gc> for GcCell<'gc, Table<'gc>> {
+    fn into_lua(self, _: &'gc GcContext) -> Value<'gc> {
+        Value::Table(self)
+    }
+}
+
+impl<'gc> FromLua<'gc> for DetachedValue {
+    fn from_lua(value: Value<'gc>, _: &'gc GcContext) -> Result<Self, ConversionError> {
+        DetachedValue::detach(value).ok_or_else(|| conversion_error(value, "detached value"))
+    }
+}
+
+impl<'gc> IntoLua<'gc> for DetachedValue {
+    fn into_lua(self, gc: &'gc GcContext) -> Value<'gc> {
+        self.attach(gc)
+    }
+}
+
+impl<'gc, T: FromLua<'gc>> FromLua<'gc> for Option<T> {
+    fn from_lua(value: Value<'gc>, gc: &'gc GcContext) -> Result<Self, ConversionError> {
+        match value {
+            Value::Nil => Ok(None),
+            _ => T::from_lua(value, gc).map(Some),
+        }
+    }
+}
+
+impl<'gc, T: IntoLua<'gc>> IntoLua<'gc> for Option<T> {
+    fn into_lua(self, gc: &'gc GcContext) -> Value<'gc> {
+        match self {
+            Some(x) => x.into_lua(gc),
+            None => Value::Nil,
+        }
+    }
+}
+
+// Sequences convert from the values at 1..#t
+impl<'gc, T: FromLua<'gc>> FromLua<'gc> for Vec<T> {
+    fn from_lua(value: Value<'gc>, gc: &'gc GcContext) -> Result<Self, ConversionError> {
+        let table = GcCell::<Table>::from_lua(value, gc)?;
+        let table = table.borrow();
+        (1..=table.lua_len())
+            .map(|i| T::from_lua(table.get_integer_key(i), gc))
+            .collect()
+    }
+}
+
+impl<'gc, T: IntoLua<'gc>> IntoLua<'gc> for Vec<T> {
+    fn into_lua(self, gc: &'gc GcContext) -> Value<'gc> {
+        let mut table = Table::with_capacity(self.len(), 0);
+        for (i, x) in self.into_iter().enumerate() {
+            table.set_integer_key(i as Integer + 1, x.into_lua(gc));
+        }
+        gc.allocate_cell(table).into()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'gc, K, V> FromLua<'gc> for HashMap<K, V>
+where
+    K: FromLua<'gc> + Eq + Hash,
+    V: FromLua<'gc>,
+{
+    fn from_lua(value: Value<'gc>, gc: &'gc GcContext) -> Result<Self, ConversionError> {
+        let table = GcCell::<Table>::from_lua(value, gc)?;
+        let table = table.borrow();
+        let mut map = HashMap::new();
+        let mut key = Value::Nil;
+        while let Some((k, v)) = table
+            .next(key)
+            .map_err(|_| conversion_error(value, "map"))?
+        {
+            map.insert(K::from_lua(k, gc)?, V::from_lua(v, gc)?);
+            key = k;
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'gc, K, V> IntoLua<'gc> for HashMap<K, V>
+where
+    K: IntoLua<'gc>,
+    V: IntoLua<'gc>,
+{
+    fn into_lua(self, gc: &'gc GcContext) -> Value<'gc> {
+        let mut table = Table::with_capacity(0, self.len());
+        for (k, v) in self {
+            // nil and NaN keys can't be stored
+            table.set(k.into_lua(gc), v.into_lua(gc)).ok();
+        }
+        gc.allocate_cell(table).into()
+    }
+}
+
+// `err.from` is used rather than the argument's type, as it may be an element
+// of a table argument that failed to convert
+fn argument_error(values: &[Value], nth: usize, err: ConversionError) -> ErrorKind {
+    ErrorKind::ArgumentTypeError {
+        nth: nth + 1,
+        expected_type: err.to,
+        got_type: values.get(nth).map(|_| err.from),
+    }
+}
+
+impl<'gc, T: FromLua<'gc>> FromLuaMulti<'gc> for T {
+    fn from_lua_multi(values: &[Value<'gc>], gc: &'gc GcContext) -> Result<Self, ErrorKind> {
+        let value = values.first().copied().unwrap_or_default();
+        T::from_lua(value, gc).map_err(|err| argument_error(values, 0, err))
+    }
+}
+
+impl<'gc, T: IntoLua<'gc>> IntoLuaMulti<'gc> for T {
+    fn into_lua_multi(self, gc: &'gc GcContext) -> Vec<Value<'gc>> {
+        vec![self.into_lua(gc)]
+    }
+}
+
+impl<'gc, T: FromLua<'gc>> FromLuaMulti<'gc> for Variadic<T> {
+    fn from_lua_multi(values: &[Value<'gc>], gc: &'gc GcContext) -> Result<Self, ErrorKind> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| T::from_lua(*value, gc).map_err(|err| argument_error(values, i, err)))
+            .collect::<Result<_, _>>()
+            .map(Variadic)
+    }
+}
+
+impl<'gc, T: IntoLua<'gc>> IntoLuaMulti<'gc> for Variadic<T> {
+    fn into_lua_multi(self, gc: &'gc GcContext) -> Vec<Value<'gc>> {
+        self.0.into_iter().map(|x| x.into_lua(gc)).collect()
+    }
+}
+
+// The last element of a tuple can be a Variadic taking the rest of the values
+macro_rules! impl_tuple {
+    ($($name:ident)*) => {
+        impl<'gc, $($name,)* Last> FromLuaMulti<'gc> for ($($name,)* Last,)
+        where
+            $($name: FromLua<'gc>,)*
+            Last: FromLuaMulti<'gc>,
+        {
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn from_lua_multi(values: &[Value<'gc>], gc: &'gc GcContext) -> Result<Self, ErrorKind> {
+                let mut i = 0;
+                $(
+                    let value = values.get(i).copied().unwrap_or_default();
+                    let $name = $name::from_lua(value, gc).map_err(|err| argument_error(values, i, err))?;
+                    i += 1;
+                )*
+                let rest = values.get(i..).unwrap_or_default();
+                let last = Last::from_lua_multi(rest, gc).map_err(|err| match err {
+                    ErrorKind::ArgumentTypeError { nth, expected_type, got_type } => {
+                        ErrorKind::ArgumentTypeError { nth: nth + i, expected_type, got_type }
+                    }
+                    err => err,
+                })?;
+                Ok(($($name,)* last,))
+            }
+        }
+
+        impl<'gc, $($name,)* Last> IntoLuaMulti<'gc> for ($($name,)* Last,)
+        where
+            $($name: IntoLua<'gc>,)*
+            Last: IntoLuaMulti<'gc>,
+        {
+            #[allow(non_snake_case)]
+            fn into_lua_multi(self, gc: &'gc GcContext) -> Vec<Value<'gc>> {
+                let ($($name,)* last,) = self;
+                let mut values = vec![$($name.into_lua(gc),)*];
+                values.extend(last.into_lua_multi(gc));
+                values
+            }
+        }
+
+        impl<'gc, F, R, $($name,)* Last> NativeFn<'gc, ($($name,)* Last,)> for F
+        where
+            F: Fn($($name,)* Last) -> Result<R, ErrorKind>,
+            R: IntoAction<'gc>,
+            ($($name,)* Last,): FromLuaMulti<'gc>,
+        {
+            #[allow(non_snake_case)]
+            fn call_native(
+                &self,
+                values: &[Value<'gc>],
+                gc: &'gc GcContext,
+            ) -> Result<Action<'gc>, ErrorKind> {
+                let ($($name,)* last,) = <($($name,)* Last,)>::from_lua_multi(values, gc)?;
+                Ok(self($($name,)* last)?.into_action(gc))
+            }
+        }
+    };
+}
+
+impl_tuple!();
+impl_tuple!(A);
+impl_tuple!(A B);
+impl_tuple!(A B C);
+impl_tuple!(A B C D);
+impl_tuple!(A B C D E);
+impl_tuple!(A B C D E G);
+impl_tuple!(A B C D E G H);
+
+impl<'gc> FromLuaMulti<'gc> for () {
+    fn from_lua_multi(_: &[Value<'gc>], _: &'gc GcContext) -> Result<Self, ErrorKind> {
+        Ok(())
+    }
+}
+
+impl<'gc> IntoLuaMulti<'gc> for () {
+    fn into_lua_multi(self, _: &'gc GcContext) -> Vec<Value<'gc>> {
+        Vec::new()
+    }
+}
+
+impl<'gc, F, R> NativeFn<'gc, ()> for F
+where
+    F: Fn() -> Result<R, ErrorKind>,
+    R: IntoAction<'gc>,
+{
+    fn call_native(&self, _: &[Value<'gc>], gc: &'gc GcContext) -> Result<Action<'gc>, ErrorKind> {
+        Ok(self()?.into_action(gc))
+    }
+}
@@ -0,0 +1,80 @@
+use super::{Table, Value};
+use crate::{
+    collections::HashMap,
+    gc::{GcCell, GcContext},
+    prelude::*,
+};
+
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("cannot clone a {type_name} value into another heap")]
+pub struct DeepCloneError {
+    pub type_name: &'static str,
+}
+
+impl<'gc> Value<'gc> {
+    // Copies the value into the heap of `gc`, which may be another heap or
+    // this one. Tables are copied with their metatables, and a table
+    // reachable several times, including through a cycle, is copied once.
+    // Native functions are shared; Lua functions, native closures, userdata
+    // and threads can't be cloned.
+    pub fn deep_clone_into<'a>(&self, gc: &'a GcContext) -> Result<Value<'a>, DeepCloneError> {
+        let mut cloner = Cloner {
+            gc,
+            tables: HashMap::default(),
+            pending: Vec::new(),
+        };
+        let value = cloner.clone_value(*self)?;
+        // Tables are filled after being allocated, so that deep structures
+        // don't recurse
+        while let Some((from, to)) = cloner.pending.pop() {
+            let from = from.borrow();
+            if let Some(metatable) = from.metatable() {
+                let metatable = cloner.clone_table(metatable);
+                to.borrow_mut(gc).set_metatable(metatable);
+            }
+            for (key, value) in from.iter() {
+                let key = cloner.clone_value(key)?;
+                let value = cloner.clone_value(value)?;
+                // keys are valid, as they come from a table
+                to.borrow_mut(gc).set(key, value).unwrap();
+            }
+        }
+        Ok(value)
+    }
+}
+
+struct Cloner<'gc, 'a> {
+    gc: &'a GcContext,
+    tables: HashMap<Value<'gc>, GcCell<'a, Table<'a>>>,
+    pending: Vec<(GcCell<'gc, Table<'gc>>, GcCell<'a, Table<'a>>)>,
+}
+
+impl<'gc, 'a> Cloner<'gc, 'a> {
+    fn clone_value(&mut self, value: Value<'gc>) -> Result<Value<'a>, DeepCloneError> {
+        Ok(match value {
+            Value::Nil => Value::Nil,
+            Value::Boolean(x) => Value::Boolean(x),
+            Value::Integer(x) => Value::Integer(x),
+            Value::Number(x) => Value::Number(x),
+            Value::NativeFunction(x) => Value::NativeFunction(x),
+            Value::String(x) => self.gc.allocate_string(x.as_bytes()).into(),
+            Value::Table(table) => self.clone_table(table).into(),
+            _ => {
+                return Err(DeepCloneError {
+                    type_name: value.ty().name(),
+                })
+            }
+        })
+    }
+
+    fn clone_table(&mut self, table: GcCell<'gc, Table<'gc>>) -> GcCell<'a, Table<'a>> {
+        if let Some(cloned) = self.tables.get(&Value::Table(table)) {
+            return *cloned;
+        }
+        let narr = table.borrow().array().len();
+        let cloned = self.gc.allocate_cell(Table::with_capacity(narr, 0));
+        self.tables.insert(Value::Table(table), cloned);
+        self.pending.push((table, cloned));
+        cloned
+    }
+}
@@ -0,0 +1,231 @@
+use super::{Integer, LuaClosure, Number, Table, Value};
+use crate::{binary_chunk, gc::GcContext, prelude::*};
+
+// A copy of a Lua value that lives outside of any heap, so that it can be
+// kept across heap borrows or sent to other threads.
+// Userdata and threads can't be detached, and Lua functions only by
+// detach_with_functions.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DetachedValue {
+    Nil,
+    Boolean(bool),
+    Integer(Integer),
+    Number(Number),
+    String(Vec<u8>),
+    Table(Vec<(DetachedValue, DetachedValue)>),
+    // Binary chunk of a Lua function, as string.dump gives
+    Function(Vec<u8>),
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("cannot convert {from} to {to}")]
+pub struct ConversionError {
+    pub from: &'static str,
+    pub to: &'static str,
+}
+
+impl DetachedValue {
+    // Returns None if `value` is or contains a value that can't be detached,
+    // or a table that contains itself
+    pub fn detach(value: Value) -> Option<Self> {
+        detach(value, false, &mut Vec::new())
+    }
+
+    // Also detaches Lua functions, which lose their upvalues like with
+    // string.dump
+    pub fn detach_with_functions(value: Value) -> Option<Self> {
+        detach(value, true, &mut Vec::new())
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Nil => "nil",
+            Self::Boolean(_) => "boolean",
+            Self::Integer(_) | Self::Number(_) => "number",
+            Self::String(_) => "string",
+            Self::Table(_) => "table",
+            Self::Function(_) => "function",
+        }
+    }
+
+    fn conversion_error(&self, to: &'static str) -> ConversionError {
+        ConversionError {
+            from: self.type_name(),
+            to,
+        }
+    }
+
+    pub fn attach<'gc>(&self, gc: &'gc GcContext) -> Value<'gc> {
+        self.attach_with_env(gc, Value::Nil)
+    }
+
+    // Like attach, with `env` as the first upvalue of functions as load
+    // would set it
+    pub fn attach_with_env<'gc>(&self, gc: &'gc GcContext, env: Value<'gc>) -> Value<'gc> {
+        match self {
+            Self::Nil => Value::Nil,
+            Self::Boolean(x) => Value::Boolean(*x),
+            Self::Integer(x) => Value::Integer(*x),
+            Self::Number(x) => Value::Number(*x),
+            Self::String(x) => gc.allocate_string(x.clone()).into(),
+            Self::Table(entries) => {
+                let mut table = Table::new();
+                for (key, value) in entries {
+                    // entries with a nil or NaN key are dropped, as Lua
+                    // wouldn't be able to see them anyway
+                    table
+                        .set(key.attach_with_env(gc, env), value.attach_with_env(gc, env))
+                        .ok();
+                }
+                gc.allocate_cell(table).into()
+            }
+            Self::Function(binary) => match crate::load(gc, binary, "=?") {
+                Ok(proto) => {
+                    let closure = LuaClosure::with_environment(gc, gc.allocate(proto), env);
+                    gc.allocate(closure).into()
+                }
+                Err(_) => Value::Nil,
+            },
+        }
+    }
+}
+
+impl From<bool> for DetachedValue {
+    fn from(x: bool) -> Self {
+        Self::Boolean(x)
+    }
+}
+
+impl From<Integer> for DetachedValue {
+    fn from(x: Integer) -> Self {
+        Self::Integer(x)
+    }
+}
+
+impl From<Number> for DetachedValue {
+    fn from(x: Number) -> Self {
+        Self::Number(x)
+    }
+}
+
+impl From<&str> for DetachedValue {
+    fn from(x: &str) -> Self {
+        Self::String(x.as_bytes().to_vec())
+    }
+}
+
+impl From<&[u8]> for DetachedValue {
+    fn from(x: &[u8]) -> Self {
+        Self::String(x.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for DetachedValue {
+    fn from(x: Vec<u8>) -> Self {
+        Self::String(x)
+    }
+}
+
+impl From<String> for DetachedValue {
+    fn from(x: String) -> Self {
+        Self::String(x.into_bytes())
+    }
+}
+
+impl TryFrom<DetachedValue> for bool {
+    type Error = ConversionError;
+
+    fn try_from(value: DetachedValue) -> Result<Self, Self::Error> {
+        match value {
+            DetachedValue::Boolean(x) => Ok(x),
+            _ => Err(value.conversion_error("boolean")),
+        }
+    }
+}
+
+impl TryFrom<DetachedValue> for Integer {
+    type Error = ConversionError;
+
+    fn try_from(value: DetachedValue) -> Result<Self, Self::Error> {
+        match value {
+            DetachedValue::Integer(x) => Ok(x),
+            DetachedValue::Number(x) if crate::number_is_valid_integer(x) => Ok(x as Integer),
+            _ => Err(value.conversion_error("integer")),
+        }
+    }
+}
+
+impl TryFrom<DetachedValue> for Number {
+    type Error = ConversionError;
+
+    fn try_from(value: DetachedValue) -> Result<Self, Self::Error> {
+        match value {
+            DetachedValue::Integer(x) => Ok(x as Number),
+            DetachedValue::Number(x) => Ok(x),
+            _ => Err(value.conversion_error("number")),
+        }
+    }
+}
+
+impl TryFrom<DetachedValue> for Vec<u8> {
+    type Error = ConversionError;
+
+    fn try_from(value: DetachedValue) -> Result<Self, Self::Error> {
+        match value {
+            DetachedValue::String(x) => Ok(x),
+            _ => Err(value.conversion_error("string")),
+        }
+    }
+}
+
+impl TryFrom<DetachedValue> for String {
+    type Error = ConversionError;
+
+    fn try_from(value: DetachedValue) -> Result<Self, Self::Error> {
+        match value {
+            DetachedValue::String(x) => String::from_utf8(x).map_err(|_| ConversionError {
+                from: "string",
+                to: "UTF-8 string",
+            }),
+            _ => Err(value.conversion_error("string")),
+        }
+    }
+}
+
+fn detach<'gc>(
+    value: Value<'gc>,
+    with_functions: bool,
+    path: &mut Vec<Value<'gc>>,
+) -> Option<DetachedValue> {
+    Some(match value {
+        Value::Nil => DetachedValue::Nil,
+        Value::Boolean(x) => DetachedValue::Boolean(x),
+        Value::Integer(x) => DetachedValue::Integer(x),
+        Value::Number(x) => DetachedValue::Number(x),
+        Value::String(x) => DetachedValue::String(x.as_bytes().to_vec()),
+        Value::Table(table) => {
+            if path.contains(&value) {
+                return None;
+            }
+            path.push(value);
+            let table = table.borrow();
+            let mut entries = Vec::new();
+            let mut key = Value::Nil;
+            while let Some((k, v)) = table.next(key).ok()? {
+                entries.push((
+                    detach(k, with_functions, path)?,
+                    detach(v, with_functions, path)?,
+                ));
+                key = k;
+            }
+            path.pop();
+            DetachedValue::Table(entries)
+        }
+        Value::LuaClosure(closure) if with_functions => {
+            let mut binary = Vec::new();
+            binary_chunk::dump(&mut binary, &closure.proto).ok()?;
+            DetachedValue::Function(binary)
+        }
+        _ => return None,
+    })
+}
@@ -1,9 +1,14 @@
 use crate::{
-    gc::{GarbageCollect, Gc, GcCell, GcContext, Tracer},
+    gc::{GarbageCollect, Gc, GcCell, GcContext, MaybeSend, ObjectKind, Tracer},
+    prelude::*,
     runtime::{Action, ErrorKind, Instruction, Vm},
-    types::{LuaString, LuaThread, Value},
+    types::{
+        packed::{Payload, Tag},
+        LuaString, LuaThread, Value,
+    },
 };
-use std::{
+use core::{
+    cell::Cell,
     fmt::Debug,
     hash::Hash,
     ops::{Range, RangeInclusive},
@@ -16,7 +21,7 @@ pub type NativeFunctionPtr =
 pub struct NativeFunction(pub(crate) NativeFunctionPtr);
 
 impl Debug for NativeFunction {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_tuple("NativeFunction")
             .field(&self.as_ptr())
             .finish()
@@ -30,7 +35,7 @@ impl PartialEq for NativeFunction {
 }
 
 impl Hash for NativeFunction {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
         self.as_ptr().hash(state);
     }
 }
@@ -45,6 +50,77 @@ impl NativeFunction {
     }
 }
 
+// What the open function of a native module returns. Modules are called
+// through the Rust ABI and share the heap with the host, so they only work
+// with a host built from the same version of this crate, with the same
+// features and compiler; `abi` lets the host reject the others.
+#[repr(C)]
+pub struct NativeModule {
+    abi: u64,
+    open: *const (),
+}
+
+impl NativeModule {
+    pub const ABI: u64 = {
+        // FNV-1a of the crate version and of the layout of the types modules
+        // touch, which differs between feature sets
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        let version = env!("CARGO_PKG_VERSION").as_bytes();
+        let sizes = [
+            core::mem::size_of::<Value<'static>>(),
+            core::mem::size_of::<Vm<'static>>(),
+            core::mem::size_of::<GcContext>(),
+            core::mem::size_of::<ErrorKind>(),
+        ];
+        let mut i = 0;
+        while i < version.len() + sizes.len() {
+            let byte = if i < version.len() {
+                version[i] as u64
+            } else {
+                sizes[i - version.len()] as u64
+            };
+            hash = (hash ^ byte).wrapping_mul(0x100_0000_01b3);
+            i += 1;
+        }
+        hash
+    };
+
+    pub fn new(open: NativeFunctionPtr) -> Self {
+        Self {
+            abi: Self::ABI,
+            open: open as *const (),
+        }
+    }
+
+    // The open function, if the module was built against a compatible host
+    pub fn open_function(&self) -> Option<NativeFunctionPtr> {
+        (self.abi == Self::ABI)
+            .then(|| unsafe { core::mem::transmute::<*const (), NativeFunctionPtr>(self.open) })
+    }
+}
+
+// Exports `open` as the open function of the native module `name`, to be
+// found by require in a library on package.cpath:
+//
+//     fn open_foo<'gc>(
+//         gc: &'gc GcContext,
+//         vm: &mut Vm<'gc>,
+//         args: Vec<Value<'gc>>,
+//     ) -> Result<Action<'gc>, ErrorKind> { ... }
+//
+//     mochi_lua::native_module!("foo", open_foo);
+#[macro_export]
+macro_rules! native_module {
+    ($name:literal, $open:path) => {
+        const _: () = {
+            #[export_name = concat!("mochi_open_", $name)]
+            extern "C" fn open() -> $crate::types::NativeModule {
+                $crate::types::NativeModule::new($open)
+            }
+        };
+    };
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct AbsLineInfo {
     pub pc: u32,
@@ -61,7 +137,7 @@ pub struct LocalVariable<'gc> {
 pub struct LuaClosureProto<'gc> {
     pub max_stack_size: u8,
     pub lines_defined: LineRange,
-    pub constants: Box<[Value<'gc>]>,
+    pub constants: Constants<'gc>,
     pub code: Box<[Instruction]>,
     pub protos: Box<[Gc<'gc, LuaClosureProto<'gc>>]>,
     pub upvalues: Box<[UpvalueDescription]>,
@@ -70,6 +146,10 @@ pub struct LuaClosureProto<'gc> {
     pub abs_line_info: Option<Box<[AbsLineInfo]>>,
     pub line_info: Option<Box<[u8]>>,
     pub local_vars: Option<Box<[LocalVariable<'gc>]>>,
+    pub upvalue_names: Option<Box<[LuaString<'gc>]>>,
+    pub(crate) inline_caches: InlineCaches,
+    #[cfg(feature = "jit")]
+    pub(crate) jit: crate::runtime::JitState,
 }
 
 unsafe impl GarbageCollect for LuaClosureProto<'_> {
@@ -77,6 +157,135 @@ unsafe impl GarbageCollect for LuaClosureProto<'_> {
         self.constants.trace(tracer);
         self.protos.trace(tracer);
         self.source.trace(tracer);
+        if let Some(local_vars) = &self.local_vars {
+            for local_var in local_vars.iter() {
+                local_var.name.trace(tracer);
+            }
+        }
+        self.upvalue_names.trace(tracer);
+    }
+
+    fn object_kind(&self) -> ObjectKind {
+        ObjectKind::Proto
+    }
+}
+
+impl<'gc> LuaClosureProto<'gc> {
+    // Drops line information and local variable names from the function and
+    // the functions it defines
+    pub fn strip_debug_info(&mut self, gc: &'gc GcContext) {
+        self.abs_line_info = None;
+        self.line_info = None;
+        self.local_vars = None;
+        self.upvalue_names = None;
+        for proto in self.protos.iter_mut() {
+            let mut stripped = LuaClosureProto {
+                max_stack_size: proto.max_stack_size,
+                lines_defined: proto.lines_defined.clone(),
+                constants: proto.constants.clone(),
+                code: proto.code.clone(),
+                protos: proto.protos.clone(),
+                upvalues: proto.upvalues.clone(),
+                source: proto.source,
+                abs_line_info: None,
+                line_info: None,
+                local_vars: None,
+                upvalue_names: None,
+                inline_caches: InlineCaches::new(proto.code.len()),
+                #[cfg(feature = "jit")]
+                jit: Default::default(),
+            };
+            stripped.strip_debug_info(gc);
+            *proto = gc.allocate(stripped);
+        }
+    }
+}
+
+// Constants are stored as separate tag and payload arrays, taking 9 bytes
+// per constant instead of 16, and are materialized into Values on access.
+#[derive(Clone, Default)]
+pub struct Constants<'gc> {
+    tags: Box<[Tag]>,
+    payloads: Box<[Payload<'gc>]>,
+}
+
+impl Debug for Constants<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+unsafe impl GarbageCollect for Constants<'_> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for value in self.iter() {
+            value.trace(tracer);
+        }
+    }
+}
+
+impl<'gc> FromIterator<Value<'gc>> for Constants<'gc> {
+    fn from_iter<I: IntoIterator<Item = Value<'gc>>>(iter: I) -> Self {
+        let (tags, payloads): (Vec<_>, Vec<_>) = iter.into_iter().map(Value::into_parts).unzip();
+        Self {
+            tags: tags.into(),
+            payloads: payloads.into(),
+        }
+    }
+}
+
+impl<'gc> From<Vec<Value<'gc>>> for Constants<'gc> {
+    fn from(values: Vec<Value<'gc>>) -> Self {
+        values.into_iter().collect()
+    }
+}
+
+impl<'gc> Constants<'gc> {
+    pub fn len(&self) -> usize {
+        self.tags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<Value<'gc>> {
+        let tag = *self.tags.get(index)?;
+        Some(unsafe { Value::from_parts(tag, self.payloads[index]) })
+    }
+
+    pub fn get_string(&self, index: usize) -> Option<&LuaString<'gc>> {
+        match self.tags.get(index)? {
+            Tag::String => Some(unsafe { &self.payloads[index].string }),
+            _ => None,
+        }
+    }
+
+    // Panics if index is out of bounds, like slice indexing
+    pub fn value(&self, index: usize) -> Value<'gc> {
+        unsafe { Value::from_parts(self.tags[index], self.payloads[index]) }
+    }
+
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = Value<'gc>> + '_ {
+        self.tags
+            .iter()
+            .zip(self.payloads.iter())
+            .map(|(tag, payload)| unsafe { Value::from_parts(*tag, *payload) })
+    }
+}
+
+// One slot hint per instruction for field accesses with a constant key.
+// A hint is the hash bucket the key was last found in. It is checked against
+// the key before use, so a stale hint only costs a regular lookup.
+#[derive(Debug, Clone)]
+pub(crate) struct InlineCaches(Box<[Cell<u32>]>);
+
+impl InlineCaches {
+    pub fn new(code_len: usize) -> Self {
+        Self((0..code_len).map(|_| Cell::new(u32::MAX)).collect())
+    }
+
+    pub fn hint(&self, pc: usize) -> &Cell<u32> {
+        &self.0[pc]
     }
 }
 
@@ -106,6 +315,10 @@ unsafe impl GarbageCollect for LuaClosure<'_> {
         self.proto.trace(tracer);
         self.upvalues.trace(tracer);
     }
+
+    fn object_kind(&self) -> ObjectKind {
+        ObjectKind::Function
+    }
 }
 
 impl<'gc> From<Gc<'gc, LuaClosureProto<'gc>>> for LuaClosure<'gc> {
@@ -117,6 +330,43 @@ impl<'gc> From<Gc<'gc, LuaClosureProto<'gc>>> for LuaClosure<'gc> {
     }
 }
 
+impl<'gc> LuaClosure<'gc> {
+    // Creates a closure of a main chunk, whose only upvalue is its _ENV
+    pub fn with_environment(
+        gc: &'gc GcContext,
+        proto: Gc<'gc, LuaClosureProto<'gc>>,
+        env: Value<'gc>,
+    ) -> Self {
+        // Any other upvalues start as nil, as with functions from string.dump
+        let upvalues = core::iter::once(env)
+            .chain(core::iter::repeat(Value::Nil))
+            .take(proto.upvalues.len().max(1))
+            .map(|value| gc.allocate_cell(value.into()))
+            .collect();
+        Self { proto, upvalues }
+    }
+
+    pub fn proto(&self) -> Gc<'gc, LuaClosureProto<'gc>> {
+        self.proto
+    }
+
+    // Sets the _ENV of a main chunk, which the functions it defines share.
+    // Returns false for other functions, where _ENV isn't necessarily the
+    // first upvalue.
+    pub fn set_environment(&self, gc: &'gc GcContext, env: Value<'gc>) -> bool {
+        if !matches!(self.proto.lines_defined, LineRange::File) {
+            return false;
+        }
+        match self.upvalues.first() {
+            Some(upvalue) => {
+                *upvalue.borrow_mut(gc) = env.into();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
 trait NativeClosureFn<'gc>: GarbageCollect {
     fn call(
         &self,
@@ -128,8 +378,8 @@ trait NativeClosureFn<'gc>: GarbageCollect {
 
 pub struct NativeClosure<'gc>(Box<dyn NativeClosureFn<'gc> + 'gc>);
 
-impl std::fmt::Debug for NativeClosure<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for NativeClosure<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("NativeClosure").finish()
     }
 }
@@ -138,12 +388,17 @@ unsafe impl GarbageCollect for NativeClosure<'_> {
     fn trace(&self, tracer: &mut Tracer) {
         self.0.trace(tracer);
     }
+
+    fn object_kind(&self) -> ObjectKind {
+        ObjectKind::Function
+    }
 }
 
 impl<'gc> NativeClosure<'gc> {
     pub fn new<F>(f: F) -> Self
     where
         F: 'static
+            + MaybeSend
             + Fn(&'gc GcContext, &mut Vm<'gc>, Vec<Value<'gc>>) -> Result<Action<'gc>, ErrorKind>,
     {
         struct SimpleNativeClosure<F>(F);
@@ -174,6 +429,7 @@ impl<'gc> NativeClosure<'gc> {
     pub fn with_upvalue<F, U>(upvalue: U, f: F) -> Self
     where
         F: 'static
+            + MaybeSend
             + Fn(&'gc GcContext, &mut Vm<'gc>, &U, Vec<Value<'gc>>) -> Result<Action<'gc>, ErrorKind>,
         U: 'gc + GarbageCollect,
     {
@@ -247,6 +503,10 @@ unsafe impl GarbageCollect for Upvalue<'_> {
             Self::Closed(value) => value.trace(tracer),
         }
     }
+
+    fn object_kind(&self) -> ObjectKind {
+        ObjectKind::Upvalue
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -0,0 +1,96 @@
+use super::{
+    Integer, LuaClosure, LuaString, LuaThread, NativeClosure, NativeFunction, Number, Table,
+    UserData, Value,
+};
+use crate::gc::{Gc, GcCell};
+
+// Value decomposed into a tag and an untagged payload,
+// for storing values more tightly than the Value enum itself
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Tag {
+    #[default]
+    Nil,
+    Boolean,
+    Integer,
+    Number,
+    NativeFunction,
+    String,
+    Table,
+    LuaClosure,
+    NativeClosure,
+    UserData,
+    Thread,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) union Payload<'gc> {
+    pub nil: (),
+    pub boolean: bool,
+    pub integer: Integer,
+    pub number: Number,
+    pub native_func: NativeFunction,
+    pub string: LuaString<'gc>,
+    pub table: GcCell<'gc, Table<'gc>>,
+    pub lua_closure: Gc<'gc, LuaClosure<'gc>>,
+    pub native_closure: Gc<'gc, NativeClosure<'gc>>,
+    pub user_data: GcCell<'gc, UserData<'gc>>,
+    pub thread: GcCell<'gc, LuaThread<'gc>>,
+}
+
+impl Default for Payload<'_> {
+    fn default() -> Self {
+        Self { nil: () }
+    }
+}
+
+impl<'gc> Value<'gc> {
+    pub(crate) unsafe fn from_parts(tag: Tag, payload: Payload<'gc>) -> Self {
+        match tag {
+            Tag::Nil => Self::Nil,
+            Tag::Boolean => Self::Boolean(payload.boolean),
+            Tag::Integer => Self::Integer(payload.integer),
+            Tag::Number => Self::Number(payload.number),
+            Tag::NativeFunction => Self::NativeFunction(payload.native_func),
+            Tag::String => Self::String(payload.string),
+            Tag::Table => Self::Table(payload.table),
+            Tag::LuaClosure => Self::LuaClosure(payload.lua_closure),
+            Tag::NativeClosure => Self::NativeClosure(payload.native_closure),
+            Tag::UserData => Self::UserData(payload.user_data),
+            Tag::Thread => Self::Thread(payload.thread),
+        }
+    }
+
+    pub(crate) unsafe fn eq_parts(&self, tag: Tag, payload: Payload<'gc>) -> bool {
+        match self {
+            Value::Nil => tag == Tag::Nil,
+            Value::Boolean(b) => tag == Tag::Boolean && *b == payload.boolean,
+            Value::Integer(i) => tag == Tag::Integer && *i == payload.integer,
+            Value::Number(x) => tag == Tag::Number && *x == payload.number,
+            Value::NativeFunction(f) => tag == Tag::NativeFunction && *f == payload.native_func,
+            Value::String(s) => tag == Tag::String && *s == payload.string,
+            Value::Table(t) => tag == Tag::Table && GcCell::ptr_eq(t, &payload.table),
+            Value::LuaClosure(l) => tag == Tag::LuaClosure && Gc::ptr_eq(l, &payload.lua_closure),
+            Value::NativeClosure(n) => {
+                tag == Tag::NativeClosure && Gc::ptr_eq(n, &payload.native_closure)
+            }
+            Value::UserData(u) => tag == Tag::UserData && GcCell::ptr_eq(u, &payload.user_data),
+            Value::Thread(t) => tag == Tag::Thread && GcCell::ptr_eq(t, &payload.thread),
+        }
+    }
+
+    pub(crate) fn into_parts(self) -> (Tag, Payload<'gc>) {
+        match self {
+            Self::Nil => (Tag::Nil, Payload::default()),
+            Self::Boolean(boolean) => (Tag::Boolean, Payload { boolean }),
+            Self::Integer(integer) => (Tag::Integer, Payload { integer }),
+            Self::Number(number) => (Tag::Number, Payload { number }),
+            Self::NativeFunction(native_func) => (Tag::NativeFunction, Payload { native_func }),
+            Self::String(string) => (Tag::String, Payload { string }),
+            Self::Table(table) => (Tag::Table, Payload { table }),
+            Self::LuaClosure(lua_closure) => (Tag::LuaClosure, Payload { lua_closure }),
+            Self::NativeClosure(native_closure) => (Tag::NativeClosure, Payload { native_closure }),
+            Self::UserData(user_data) => (Tag::UserData, Payload { user_data }),
+            Self::Thread(thread) => (Tag::Thread, Payload { thread }),
+        }
+    }
+}
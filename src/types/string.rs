@@ -1,11 +1,11 @@
 use crate::gc::{BoxedString, GarbageCollect, Gc, Tracer};
-use std::{cmp::Ordering, fmt::Write, hash::Hash, ops::Deref, str::Utf8Error};
+use core::{cmp::Ordering, fmt::Write, hash::Hash, ops::Deref, str::Utf8Error};
 
 #[derive(Clone, Copy)]
 pub struct LuaString<'gc>(pub(crate) Gc<'gc, BoxedString>);
 
-impl std::fmt::Debug for LuaString<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Debug for LuaString<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         f.write_char('"')?;
         for ch in self.0.as_bytes() {
             match *ch {
@@ -59,7 +59,7 @@ impl PartialOrd for LuaString<'_> {
 }
 
 impl Hash for LuaString<'_> {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
         self.0.as_ptr().hash(state);
     }
 }
@@ -76,6 +76,6 @@ impl LuaString<'_> {
     }
 
     pub fn as_str(&self) -> Result<&str, Utf8Error> {
-        std::str::from_utf8(self.as_bytes())
+        core::str::from_utf8(self.as_bytes())
     }
 }
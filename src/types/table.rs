@@ -1,13 +1,17 @@
 mod bucket;
 
-use super::{Integer, LuaString, NativeClosure, NativeFunction, Number, Value};
+use super::{ConversionError, FromLua, Integer, IntoLua, LuaString, Value};
 use crate::{
-    gc::{GarbageCollect, GcCell, Tracer},
+    gc::{GarbageCollect, GcCell, GcContext, ObjectKind, Tracer},
     number_is_valid_integer,
+    prelude::*,
 };
 use bucket::Bucket;
+use core::{
+    cell::Cell,
+    hash::{Hash, Hasher},
+};
 use rustc_hash::FxHasher;
-use std::hash::{Hash, Hasher};
 
 #[derive(Debug, Clone, thiserror::Error)]
 pub enum TableError {
@@ -19,6 +23,12 @@ pub enum TableError {
 
     #[error("invalid key to 'next'")]
     InvalidKeyToNext,
+
+    #[error("table resized during traversal")]
+    ResizedDuringTraversal,
+
+    #[error("attempt to modify a frozen table")]
+    Frozen,
 }
 
 #[derive(Clone, Default)]
@@ -29,15 +39,50 @@ pub struct Table<'gc> {
     last_free_bucket: usize,
 
     metatable: Option<GcCell<'gc, Table<'gc>>>,
+
+    // Bumped whenever entries move, which invalidates cursors
+    layout: u32,
+
+    // The border in the array part that lua_len found last, where it's
+    // looked for first, like alimit of the reference implementation
+    border_hint: Cell<usize>,
+
+    // Set by freeze, after which writes through Lua fail. Natives writing
+    // with set_field and set_integer_key are trusted to check it.
+    frozen: bool,
+}
+
+// A position in a traversal of a table, for natives that call into Lua
+// between entries and so can't hold on to `Table::iter`, which borrows the
+// table. A cursor has no references into the table, so it stays valid across
+// garbage collection as long as the table itself is kept alive, e.g. by the
+// continuation that holds both.
+//
+// Entries may be changed or removed while a traversal is suspended, as with
+// `next`. Adding keys may resize the table, after which the cursor fails with
+// `TableError::ResizedDuringTraversal` instead of skipping or repeating
+// entries.
+#[derive(Debug, Clone, Copy)]
+pub struct TableCursor {
+    index: usize,
+    layout: u32,
+}
+
+unsafe impl GarbageCollect for TableCursor {
+    fn needs_trace() -> bool {
+        false
+    }
 }
 
-impl std::fmt::Debug for Table<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for Table<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("Table")
             .field("array", &self.array)
             .field("#buckets", &self.buckets.len())
             .field("last_free_bucket", &self.last_free_bucket)
             .field("metatable", &self.metatable)
+            .field("layout", &self.layout)
+            .field("frozen", &self.frozen)
             .finish()
     }
 }
@@ -57,6 +102,10 @@ unsafe impl GarbageCollect for Table<'_> {
         self.buckets.trace(tracer);
         self.metatable.trace(tracer);
     }
+
+    fn object_kind(&self) -> ObjectKind {
+        ObjectKind::Table
+    }
 }
 
 impl<'gc> Table<'gc> {
@@ -70,6 +119,19 @@ impl<'gc> Table<'gc> {
         table
     }
 
+    // equivalent to lua_createtable(L, narr, nrec)
+    pub fn with_capacity(narr: usize, nrec: usize) -> Self {
+        Self::with_size(narr, nrec)
+    }
+
+    // grows the array part to at least `narr` slots and the hash part to
+    // at least `nrec` buckets, rehashing existing entries only when needed
+    pub fn reserve(&mut self, narr: usize, nrec: usize) {
+        if narr > self.array.len() || nrec > self.buckets.len() {
+            self.resize(narr.max(self.array.len()), nrec.max(self.buckets.len()));
+        }
+    }
+
     pub fn array(&self) -> &[Value<'gc>] {
         &self.array
     }
@@ -112,11 +174,29 @@ impl<'gc> Table<'gc> {
             .unwrap_or_default()
     }
 
+    // get_field that tries the bucket at `hint` first and updates the hint
+    // on a successful lookup
+    pub(crate) fn get_field_hinted(&self, field: LuaString<'gc>, hint: &Cell<u32>) -> Value<'gc> {
+        if let Some(bucket) = self.buckets.get(hint.get() as usize) {
+            if bucket.matches_string(field) {
+                return bucket.value();
+            }
+        }
+        match self.find_string_key_bucket(field) {
+            Some(index) => {
+                hint.set(index as u32);
+                unsafe { self.buckets.get_unchecked(index) }.value()
+            }
+            None => Value::Nil,
+        }
+    }
+
     pub fn set<K, V>(&mut self, key: K, value: V) -> Result<(), TableError>
     where
         K: Into<Value<'gc>>,
         V: Into<Value<'gc>>,
     {
+        self.check_not_frozen()?;
         let mut key = key.into();
         let value = value.into();
         match key {
@@ -178,6 +258,9 @@ impl<'gc> Table<'gc> {
         K: Into<Value<'gc>>,
         V: Into<Value<'gc>>,
     {
+        if self.frozen {
+            return Ok(false);
+        }
         let mut key = key.into();
         match key {
             Value::Nil => return Err(TableError::IndexIsNil),
@@ -209,6 +292,9 @@ impl<'gc> Table<'gc> {
     where
         V: Into<Value<'gc>>,
     {
+        if self.frozen {
+            return false;
+        }
         match self.array.get_mut((i as usize).wrapping_sub(1)) {
             Some(Value::Nil) => return false,
             Some(slot) => {
@@ -227,20 +313,54 @@ impl<'gc> Table<'gc> {
         false
     }
 
-    pub(crate) fn replace_field<V>(&mut self, field: LuaString<'gc>, value: V) -> bool
+    pub(crate) fn replace_field_hinted<V>(
+        &mut self,
+        field: LuaString<'gc>,
+        value: V,
+        hint: &Cell<u32>,
+    ) -> bool
     where
         V: Into<Value<'gc>>,
     {
-        if let Some(index) = self.find_string_key_bucket(field) {
-            let bucket = unsafe { self.buckets.get_unchecked_mut(index) };
-            if bucket.has_value() {
-                bucket.update_or_remove_item(value.into());
-                return true;
-            }
+        if self.frozen {
+            return false;
+        }
+        let index = match self.buckets.get(hint.get() as usize) {
+            Some(bucket) if bucket.matches_string(field) => hint.get() as usize,
+            _ => match self.find_string_key_bucket(field) {
+                Some(index) => {
+                    hint.set(index as u32);
+                    index
+                }
+                None => return false,
+            },
+        };
+        let bucket = unsafe { self.buckets.get_unchecked_mut(index) };
+        if bucket.has_value() {
+            bucket.update_or_remove_item(value.into());
+            return true;
         }
         false
     }
 
+    // Makes writes through Lua, including rawset, fail from now on. Entries
+    // that are tables themselves aren't frozen.
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    pub fn check_not_frozen(&self) -> Result<(), TableError> {
+        if self.frozen {
+            Err(TableError::Frozen)
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn metatable(&self) -> Option<GcCell<'gc, Table<'gc>>> {
         self.metatable
     }
@@ -252,19 +372,13 @@ impl<'gc> Table<'gc> {
         self.metatable = metatable.into();
     }
 
+    // A border: a non-negative integer n such that t[n] is non-nil (or n is
+    // zero) and t[n + 1] is nil, which is what the length operator returns
+    // without a __len metamethod. A table with holes has several borders, and
+    // any of them may be returned.
     pub fn lua_len(&self) -> Integer {
         if let Some(Value::Nil) = self.array.last() {
-            let mut i = 0;
-            let mut j = self.array.len();
-            while j - i > 1 {
-                let m = (j - i) / 2 + i;
-                if !self.array[m - 1].is_nil() {
-                    i = m;
-                } else {
-                    j = m;
-                }
-            }
-            return i as Integer;
+            return self.array_border() as Integer;
         }
         if self.buckets.is_empty() {
             return self.array.len() as Integer;
@@ -298,6 +412,93 @@ impl<'gc> Table<'gc> {
         i
     }
 
+    // Border of the array part, whose last slot is nil. Lengths mostly
+    // change by one between calls, as when appending with t[#t + 1] = v or
+    // removing with t[#t] = nil, so the slots around the previous border are
+    // checked before searching.
+    fn array_border(&self) -> usize {
+        let array = &self.array;
+        let is_border = |n: usize| (n == 0 || !array[n - 1].is_nil()) && array[n].is_nil();
+        let hint = self.border_hint.get().min(array.len() - 1);
+        let border = if is_border(hint) {
+            hint
+        } else if hint + 1 < array.len() && is_border(hint + 1) {
+            hint + 1
+        } else if hint > 0 && is_border(hint - 1) {
+            hint - 1
+        } else {
+            // Binary search between i, which is zero or has a value at i - 1,
+            // and j, which has none at j - 1
+            let (mut i, mut j) = if hint > 0 && array[hint - 1].is_nil() {
+                (0, hint)
+            } else {
+                (hint, array.len())
+            };
+            while j - i > 1 {
+                let m = (j - i) / 2 + i;
+                if array[m - 1].is_nil() {
+                    j = m;
+                } else {
+                    i = m;
+                }
+            }
+            i
+        };
+        self.border_hint.set(border);
+        border
+    }
+
+    // Number of entries up to the border, as the length operator without
+    // metamethods would return
+    pub fn len(&self) -> usize {
+        self.lua_len() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    // Entries in the order `next` visits them. Garbage is only collected
+    // outside of `GcHeap::with`, so the iterator can't observe a collection,
+    // but it can't be kept across calls into Lua either; use a cursor for that.
+    pub fn iter(&self) -> impl Iterator<Item = (Value<'gc>, Value<'gc>)> + '_ {
+        let array = self
+            .array
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| !value.is_nil())
+            .map(|(i, value)| (Value::Integer(i as Integer + 1), *value));
+        let buckets = self
+            .buckets
+            .iter()
+            .filter(|bucket| bucket.has_value())
+            .map(|bucket| (bucket.key(), bucket.value()));
+        array.chain(buckets)
+    }
+
+    // Values at 1, 2, ... up to the first nil, as ipairs visits them
+    pub fn sequence_values(&self) -> impl Iterator<Item = Value<'gc>> + '_ {
+        (1..)
+            .map(|i| self.get_integer_key(i))
+            .take_while(|value| !value.is_nil())
+    }
+
+    pub fn get_as<K, V>(&self, gc: &'gc GcContext, key: K) -> Result<V, ConversionError>
+    where
+        K: IntoLua<'gc>,
+        V: FromLua<'gc>,
+    {
+        V::from_lua(self.get(key.into_lua(gc)), gc)
+    }
+
+    pub fn set_as<K, V>(&mut self, gc: &'gc GcContext, key: K, value: V) -> Result<(), TableError>
+    where
+        K: IntoLua<'gc>,
+        V: IntoLua<'gc>,
+    {
+        self.set(key.into_lua(gc), value.into_lua(gc))
+    }
+
     pub fn next(&self, key: Value<'gc>) -> Result<Option<(Value<'gc>, Value<'gc>)>, TableError> {
         let next_array_index = match key {
             Value::Nil => Some(0),
@@ -326,6 +527,39 @@ impl<'gc> Table<'gc> {
         Ok(None)
     }
 
+    pub fn cursor(&self) -> TableCursor {
+        TableCursor {
+            index: 0,
+            layout: self.layout,
+        }
+    }
+
+    // The entry at the cursor, in the order `next` visits them, moving the
+    // cursor past it
+    pub fn next_at(
+        &self,
+        cursor: &mut TableCursor,
+    ) -> Result<Option<(Value<'gc>, Value<'gc>)>, TableError> {
+        if cursor.layout != self.layout {
+            return Err(TableError::ResizedDuringTraversal);
+        }
+        debug_assert!(cursor.index <= self.array.len() + self.buckets.len());
+
+        while let Some(value) = self.array.get(cursor.index) {
+            cursor.index += 1;
+            if !value.is_nil() {
+                return Ok(Some((Value::Integer(cursor.index as Integer), *value)));
+            }
+        }
+        while let Some(bucket) = self.buckets.get(cursor.index - self.array.len()) {
+            cursor.index += 1;
+            if bucket.has_value() {
+                return Ok(Some((bucket.key(), bucket.value())));
+            }
+        }
+        Ok(None)
+    }
+
     unsafe fn set_new_hashtable_key(&mut self, key: Value<'gc>, value: Value<'gc>) {
         if self.buckets.is_empty() {
             self.rehash(key);
@@ -480,7 +714,7 @@ impl<'gc> Table<'gc> {
         let mut num_positive = 0;
 
         // array part
-        match self.array.get(0) {
+        match self.array.first() {
             Some(Value::Nil) => (),
             Some(_) => {
                 bins[0] = 1;
@@ -557,6 +791,7 @@ impl<'gc> Table<'gc> {
         } else {
             0
         };
+        self.layout = self.layout.wrapping_add(1);
         let old_buckets: Vec<_> = self
             .buckets
             .drain(..)
@@ -1,16 +1,19 @@
 use super::{LineRange, Upvalue, Value};
 use crate::{
-    gc::{GarbageCollect, GcCell, GcContext, Tracer},
+    gc::{GarbageCollect, GcCell, GcContext, ObjectKind, Tracer},
+    prelude::*,
     runtime::{ErrorKind, Frame},
 };
-use std::{collections::BTreeMap, fmt::Display};
+use core::fmt::Display;
 
 #[derive(Default)]
 pub struct LuaThread<'gc> {
     pub(crate) status: ThreadStatus,
     pub(crate) stack: Vec<Value<'gc>>,
     pub(crate) frames: Vec<Frame<'gc>>,
-    pub(crate) open_upvalues: BTreeMap<usize, GcCell<'gc, Upvalue<'gc>>>,
+    // Sorted by stack index, so the upvalues of the innermost frames are at
+    // the end
+    pub(crate) open_upvalues: Vec<(usize, GcCell<'gc, Upvalue<'gc>>)>,
 }
 
 unsafe impl GarbageCollect for LuaThread<'_> {
@@ -19,10 +22,14 @@ unsafe impl GarbageCollect for LuaThread<'_> {
         self.frames.trace(tracer);
         self.open_upvalues.trace(tracer);
     }
+
+    fn object_kind(&self) -> ObjectKind {
+        ObjectKind::Thread
+    }
 }
 
-impl std::fmt::Debug for LuaThread<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for LuaThread<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("LuaThread")
             .field("status", &self.status)
             .field("frames", &self.frames)
@@ -43,25 +50,60 @@ impl<'gc> LuaThread<'gc> {
         };
     }
 
+    // Like luaL_traceback, only the first and last levels of a deep stack
+    // are kept, with a TracebackFrame::Skipped in between
     pub fn traceback(&self) -> Vec<TracebackFrame> {
-        self.frames
-            .iter()
-            .enumerate()
-            .rev()
-            .map(|(i, frame)| match frame {
-                Frame::Lua(frame) => {
-                    let value = self.stack[frame.bottom];
-                    let proto = value.as_lua_closure().unwrap().proto;
-                    TracebackFrame::Lua {
-                        source: String::from_utf8_lossy(&proto.source).to_string(),
-                        line: proto.get_currentline(frame),
-                        lines_defined: proto.lines_defined.clone(),
-                    }
+        const LEVELS1: usize = 10;
+        const LEVELS2: usize = 11;
+
+        let levels = self.frames.iter().enumerate().rev();
+        let num_levels = self.frames.len();
+        if num_levels <= LEVELS1 + LEVELS2 {
+            return levels
+                .map(|(i, frame)| self.traceback_frame(i, frame))
+                .collect();
+        }
+        let skipped = num_levels - LEVELS1 - LEVELS2;
+        let mut traceback: Vec<_> = levels
+            .clone()
+            .take(LEVELS1)
+            .map(|(i, frame)| self.traceback_frame(i, frame))
+            .collect();
+        traceback.push(TracebackFrame::Skipped(skipped));
+        traceback.extend(
+            levels
+                .skip(LEVELS1 + skipped)
+                .map(|(i, frame)| self.traceback_frame(i, frame)),
+        );
+        traceback
+    }
+
+    fn traceback_frame(&self, i: usize, frame: &Frame<'gc>) -> TracebackFrame {
+        match frame {
+            Frame::Lua(frame) => {
+                let value = self.stack[frame.bottom];
+                let proto = value.as_lua_closure().unwrap().proto;
+                // Named by the call in the calling function, if there's one
+                let func = i
+                    .checked_sub(1)
+                    .and_then(|i| self.frames[i].as_lua())
+                    .and_then(|caller| {
+                        let value = self.stack[caller.bottom];
+                        let proto = value.as_lua_closure().unwrap().proto;
+                        proto
+                            .funcname_from_code(caller.last_pc())
+                            .map(|x| (x.kind, x.name.to_string()))
+                    });
+                TracebackFrame::Lua {
+                    source: String::from_utf8_lossy(&proto.source).to_string(),
+                    line: proto.get_currentline(frame),
+                    lines_defined: proto.lines_defined.clone(),
+                    func,
                 }
-                Frame::Native { .. } => {
-                    let func = if let Some(frame) =
-                        self.frames[..i].iter().rev().find_map(Frame::as_lua)
-                    {
+            }
+            Frame::Native { .. } => {
+                let func =
+                    if let Some(frame) = self.frames[..i].iter().rev().find_map(Frame::as_lua) {
                         let value = self.stack[frame.bottom];
                         let proto = value.as_lua_closure().unwrap().proto;
                         proto
@@ -70,61 +112,82 @@ impl<'gc> LuaThread<'gc> {
                     } else {
                         None
                     };
-                    TracebackFrame::Native { func }
-                }
-                _ => TracebackFrame::Native { func: None },
-            })
-            .collect()
+                TracebackFrame::Native { func }
+            }
+            _ => TracebackFrame::Native { func: None },
+        }
+    }
+
+    pub(crate) fn find_upvalue(
+        &mut self,
+        gc: &'gc GcContext,
+        thread: GcCell<'gc, LuaThread<'gc>>,
+        index: usize,
+    ) -> GcCell<'gc, Upvalue<'gc>> {
+        let i = self.open_upvalues.partition_point(|(x, _)| *x < index);
+        match self.open_upvalues.get(i) {
+            Some((x, upvalue)) if *x == index => *upvalue,
+            _ => {
+                let upvalue = gc.allocate_cell(Upvalue::Open { thread, index });
+                self.open_upvalues.insert(i, (index, upvalue));
+                upvalue
+            }
+        }
     }
 
     pub(crate) fn close_upvalues(&mut self, gc: &'gc GcContext, boundary: usize) {
-        for (_, upvalue) in self.open_upvalues.split_off(&boundary) {
+        let i = self.open_upvalues.partition_point(|(x, _)| *x < boundary);
+        for (_, upvalue) in self.open_upvalues.drain(i..) {
             let mut upvalue = upvalue.borrow_mut(gc);
             if let Upvalue::Open { index, .. } = *upvalue {
-                *upvalue = Upvalue::Closed(self.stack[index]);
+                *upvalue = Upvalue::Closed(self.stack.get(index).copied().unwrap_or_default());
             }
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub(crate) enum ThreadStatus {
+    #[default]
     Resumable,
     Unresumable,
     Error(ErrorKind),
 }
 
-impl Default for ThreadStatus {
-    fn default() -> Self {
-        Self::Resumable
-    }
-}
-
 #[derive(Debug)]
 pub enum TracebackFrame {
     Lua {
         source: String,
         line: Option<u32>,
         lines_defined: LineRange,
+        // Kind of name, such as "local" or "method", and the name
+        func: Option<(&'static str, String)>,
     },
     Native {
         func: Option<String>,
     },
+    // Number of levels left out of a deep traceback
+    Skipped(usize),
 }
 
 impl Display for TracebackFrame {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Self::Lua {
                 source,
                 line,
                 lines_defined,
+                func,
             } => {
                 let source = crate::chunk_id_from_source(source);
                 let line = line.map(|n| n.to_string()).unwrap_or_else(|| "?".into());
-                match &lines_defined {
-                    LineRange::File => write!(f, "{source}:{line}: in main chunk"),
-                    LineRange::Lines(range) => {
+                match (&lines_defined, func) {
+                    (LineRange::File, _) => write!(f, "{source}:{line}: in main chunk"),
+                    (_, Some(("global", name))) => {
+                        write!(f, "{source}:{line}: in function '{name}'")
+                    }
+                    (_, Some((kind, name))) => write!(f, "{source}:{line}: in {kind} '{name}'"),
+                    (LineRange::Lines(range), None) => {
                         write!(
                             f,
                             "{source}:{line}: in function <{source}:{}>",
@@ -135,6 +198,7 @@ impl Display for TracebackFrame {
             }
             Self::Native { func: Some(fname) } => write!(f, "[C]: in function '{fname}'"),
             Self::Native { func: None } => f.write_str("[C]: in function"),
+            Self::Skipped(levels) => write!(f, "...\t(skipping {levels} levels)"),
         }
     }
 }
@@ -1,6 +1,9 @@
 use super::Table;
-use crate::gc::{GarbageCollect, GcCell, Tracer};
-use std::any::Any;
+use crate::{
+    gc::{GarbageCollect, GcCell, MaybeSend, ObjectKind, Tracer},
+    prelude::*,
+};
+use core::any::Any;
 
 #[derive(Debug)]
 pub struct UserData<'gc> {
@@ -12,10 +15,14 @@ unsafe impl GarbageCollect for UserData<'_> {
     fn trace(&self, tracer: &mut Tracer) {
         self.metatable.trace(tracer);
     }
+
+    fn object_kind(&self) -> ObjectKind {
+        ObjectKind::UserData
+    }
 }
 
 impl<'gc> UserData<'gc> {
-    pub fn new<T: Any>(data: T) -> Self {
+    pub fn new<T: Any + MaybeSend>(data: T) -> Self {
         Self {
             data: Box::new(data),
             metatable: None,
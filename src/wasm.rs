@@ -0,0 +1,92 @@
+// Entry points for running Lua from JavaScript, e.g. in an in-browser
+// playground. Build the crate as a cdylib for wasm32-unknown-unknown with the
+// "wasm" feature and without the default ones, e.g.
+// `cargo rustc --release --target wasm32-unknown-unknown --no-default-features --features wasm --crate-type cdylib`,
+// then generate the JS glue with wasm-bindgen.
+//
+// The io library and the os functions that need a host (execute, exit,
+// getenv, remove and rename) aren't available on this target, and os.clock
+// gives wall-clock time.
+use crate::{
+    lua::Lua,
+    runtime::Action,
+    stdlib::helpers::{fmt_bytes_limited, ArgumentsExt},
+    types::{DetachedValue, NativeClosure},
+};
+use bstr::B;
+use js_sys::{Array, Object, Reflect};
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+// Runs `source` in a new state, as an expression if it is one like the REPL
+// does, and returns its results: undefined for none, the value for one and an
+// array for more. Tables become arrays if their keys are 1 to n and objects
+// otherwise. print writes to console.log, and errors are thrown as strings.
+#[wasm_bindgen]
+pub fn eval(source: &str) -> Result<JsValue, JsValue> {
+    let mut lua = Lua::new();
+    lua.with(|gc, vm| {
+        let print = NativeClosure::new(|_, vm, args| {
+            let mut line = Vec::new();
+            for (i, value) in args.without_callee().iter().enumerate() {
+                if i > 0 {
+                    line.push(b'\t');
+                }
+                fmt_bytes_limited(*value, &mut line, vm)?;
+            }
+            log(&String::from_utf8_lossy(&line));
+            Ok(Action::Return(Vec::new()))
+        });
+        vm.globals()
+            .borrow_mut(gc)
+            .set_field(gc.allocate_string(B("print")), gc.allocate(print));
+    });
+    let mut results = lua
+        .load(source)
+        .name("=playground")
+        .eval_multi()
+        .map_err(|err| JsValue::from_str(&err.to_string()))?;
+    Ok(match results.len() {
+        0 => JsValue::UNDEFINED,
+        1 => to_js(&results.pop().unwrap()),
+        _ => results.iter().map(to_js).collect::<Array>().into(),
+    })
+}
+
+fn to_js(value: &DetachedValue) -> JsValue {
+    match value {
+        DetachedValue::Nil => JsValue::NULL,
+        DetachedValue::Boolean(b) => JsValue::from_bool(*b),
+        DetachedValue::Integer(i) => JsValue::from_f64(*i as f64),
+        DetachedValue::Number(x) => JsValue::from_f64(*x),
+        DetachedValue::String(s) => JsValue::from_str(&String::from_utf8_lossy(s)),
+        DetachedValue::Table(entries) => {
+            let is_sequence = entries.iter().all(|(key, _)| {
+                matches!(key, DetachedValue::Integer(i) if (1..=entries.len() as i64).contains(i))
+            });
+            if is_sequence {
+                let array = Array::new_with_length(entries.len() as u32);
+                for (key, value) in entries {
+                    if let DetachedValue::Integer(i) = key {
+                        array.set(*i as u32 - 1, to_js(value));
+                    }
+                }
+                array.into()
+            } else {
+                let object = Object::new();
+                for (key, value) in entries {
+                    // Keys are converted to property keys like JS does
+                    Reflect::set(&object, &to_js(key), &to_js(value)).unwrap();
+                }
+                object.into()
+            }
+        }
+        // eval_multi doesn't detach functions
+        DetachedValue::Function(_) => JsValue::UNDEFINED,
+    }
+}
@@ -0,0 +1,102 @@
+// Heaps with their own GcAllocator, which sees every object allocated and
+// freed. Allocations it refuses are raised as errors at the next GC step.
+use mochi_lua::{
+    gc::{GcAllocator, GcHeap, ObjectKind, SystemAllocator},
+    runtime::{Runtime, RuntimeError},
+};
+use std::{
+    alloc::Layout,
+    sync::{
+        atomic::{AtomicIsize, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+#[derive(Default)]
+struct Counters {
+    live_bytes: AtomicIsize,
+    tables: AtomicUsize,
+    // Allocations left before one fails, if positive
+    fail_after: AtomicIsize,
+}
+
+#[derive(Clone, Default)]
+struct CountingAllocator(Arc<Counters>);
+
+unsafe impl GcAllocator for CountingAllocator {
+    fn allocate(&self, layout: Layout, kind: ObjectKind) -> *mut u8 {
+        if self.0.fail_after.fetch_sub(1, Ordering::Relaxed) == 1 {
+            return std::ptr::null_mut();
+        }
+        self.0
+            .live_bytes
+            .fetch_add(layout.size() as isize, Ordering::Relaxed);
+        if kind == ObjectKind::Table {
+            self.0.tables.fetch_add(1, Ordering::Relaxed);
+        }
+        SystemAllocator.allocate(layout, kind)
+    }
+
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout, kind: ObjectKind) {
+        self.0
+            .live_bytes
+            .fetch_sub(layout.size() as isize, Ordering::Relaxed);
+        SystemAllocator.deallocate(ptr, layout, kind);
+    }
+}
+
+fn run(runtime: &mut Runtime, source: &'static str) -> Result<(), RuntimeError> {
+    runtime.execute(|gc, vm| {
+        let closure = vm.borrow().load(gc, source, "=test")?;
+        Ok(gc.allocate(closure).into())
+    })
+}
+
+fn runtime(allocator: &CountingAllocator) -> Runtime {
+    let mut runtime = Runtime::with_heap(GcHeap::with_allocator(allocator.clone()));
+    runtime
+        .heap()
+        .with(|gc, vm| vm.borrow_mut(gc).load_stdlib(gc));
+    runtime
+}
+
+#[test]
+fn allocations_are_accounted() {
+    let allocator = CountingAllocator::default();
+    let mut runtime = runtime(&allocator);
+    let tables = allocator.0.tables.load(Ordering::Relaxed);
+    run(&mut runtime, "for i = 1, 100 do local t = {} end").unwrap();
+    assert_eq!(allocator.0.tables.load(Ordering::Relaxed), tables + 100);
+    assert!(allocator.0.live_bytes.load(Ordering::Relaxed) > 0);
+
+    drop(runtime);
+    assert_eq!(allocator.0.live_bytes.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn refused_allocations_raise_errors_at_the_next_step() {
+    let allocator = CountingAllocator::default();
+    let mut runtime = runtime(&allocator);
+    run(
+        &mut runtime,
+        "function allocate() local t = {} for i = 1, 1000 do t[i] = {} end end",
+    )
+    .unwrap();
+
+    allocator.0.fail_after.store(500, Ordering::Relaxed);
+    run(
+        &mut runtime,
+        r#"
+local ok, err = pcall(allocate)
+assert(not ok and err == "not enough memory", err)
+allocate()"#,
+    )
+    .unwrap();
+
+    allocator.0.fail_after.store(1, Ordering::Relaxed);
+    let err = run(&mut runtime, "allocate()").unwrap_err();
+    assert_eq!(err.to_string().lines().next(), Some("not enough memory"));
+
+    drop(runtime);
+    assert_eq!(allocator.0.live_bytes.load(Ordering::Relaxed), 0);
+}
@@ -0,0 +1,136 @@
+// Integer arithmetic wraps around and float arithmetic follows IEEE 754, as
+// in the reference implementation
+use mochi_lua::{lua::Lua, Error};
+
+fn eval_all(lua: &mut Lua, sources: &[&str]) -> Vec<String> {
+    sources
+        .iter()
+        .map(|source| {
+            lua.load(format!("return tostring({source})"))
+                .eval()
+                .unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn integers_wrap_around() {
+    let mut lua = Lua::new();
+    let results = eval_all(
+        &mut lua,
+        &[
+            "math.maxinteger + 1 == math.mininteger",
+            "math.mininteger - 1 == math.maxinteger",
+            "math.maxinteger * 2",
+            "-math.mininteger",
+            "math.mininteger // -1",
+            "math.mininteger % -1",
+            "math.fmod(math.mininteger, -1)",
+            "math.abs(math.mininteger)",
+        ],
+    );
+    assert_eq!(
+        results,
+        [
+            "true",
+            "true",
+            "-2",
+            "-9223372036854775808",
+            "-9223372036854775808",
+            "0",
+            "0",
+            "-9223372036854775808",
+        ]
+    );
+}
+
+#[test]
+fn float_division_by_zero() {
+    let mut lua = Lua::new();
+    let results = eval_all(
+        &mut lua,
+        &[
+            "1 / 0",
+            "-1 / 0",
+            "0 / 0 ~= 0 / 0",
+            "1 // 0.0",
+            "-1 // 0.0",
+            "1 % math.huge",
+        ],
+    );
+    assert_eq!(results, ["inf", "-inf", "true", "inf", "-inf", "1.0"]);
+}
+
+#[test]
+fn integer_division_by_zero() {
+    let mut lua = Lua::new();
+    let messages: Vec<String> = [
+        "return 1 // 0",
+        "return 1 % 0",
+        "local zero = 0\nreturn 1 // zero",
+        "local zero = 0\nreturn 1 % zero",
+    ]
+    .into_iter()
+    .map(|source| match lua.load(source).name("=t").exec() {
+        Err(Error::Runtime(err)) => err.kind.to_string(),
+        result => panic!("unexpected {result:?}"),
+    })
+    .collect();
+    assert_eq!(
+        messages,
+        [
+            "t:1: attempt to perform 'n//0'",
+            "t:1: attempt to perform 'n%0'",
+            "t:2: attempt to perform 'n//0'",
+            "t:2: attempt to perform 'n%0'",
+        ]
+    );
+}
+
+#[test]
+fn immediate_operands_are_not_negated_for_metamethods() {
+    let mut lua = Lua::new();
+    let results = eval_all(
+        &mut lua,
+        &[
+            r#""10" - 1"#,
+            r#""1" - -128"#,
+            "setmetatable({}, {__sub = function(_, b) return b end}) - 3",
+            "setmetatable({}, {__shl = function(_, b) return b end}) << 3",
+        ],
+    );
+    assert_eq!(results, ["9", "129", "3", "3"]);
+}
+
+#[test]
+fn operands_are_kept_across_calls() {
+    let mut lua = Lua::new();
+    let results = eval_all(
+        &mut lua,
+        &[
+            "math.floor(math.abs(-1) + math.abs(2))",
+            "tostring(1) .. string.rep('a', 2)",
+            "-math.abs(-2) * math.abs(-3)",
+            "(math.abs(-1) and math.abs(2)) - math.abs(5)",
+            "math.abs(1) < math.abs(2)",
+        ],
+    );
+    assert_eq!(results, ["3", "1aa", "-6", "-3", "true"]);
+}
+
+#[test]
+fn comparisons_with_nan_are_false() {
+    let mut lua = Lua::new();
+    lua.load("nan, big = 0/0, math.maxinteger - 1")
+        .exec()
+        .unwrap();
+    let results = eval_all(
+        &mut lua,
+        &[
+            "big < nan or big <= nan or big > nan or big >= nan",
+            "nan < big or nan <= big or nan > big or nan >= big",
+            "nan == nan",
+        ],
+    );
+    assert_eq!(results, ["false", "false", "false"]);
+}
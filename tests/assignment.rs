@@ -0,0 +1,21 @@
+// Assignments to several targets, where every value is evaluated before any
+// target is assigned
+use mochi_lua::lua::Lua;
+
+#[test]
+fn extra_call_results_are_kept_until_assigned() {
+    let mut lua = Lua::new();
+    let result: String = lua
+        .load(
+            r#"
+            Z = {}
+            function f() return 1, 2, 3 end
+            Z.a, b, c = f"x"
+            local t = {}
+            t.x, t.y, t.z = f()
+            return table.concat({Z.a, b, c, t.x, t.y, t.z}, ' ')"#,
+        )
+        .eval()
+        .unwrap();
+    assert_eq!(result, "1 2 3 1 2 3");
+}
@@ -0,0 +1,114 @@
+// Native functions that wait on futures, which needs the "async" feature
+#![cfg(feature = "async")]
+use mochi_lua::{lua::Lua, runtime::ErrorKind};
+use std::{
+    future::Future,
+    pin::{pin, Pin},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Wake, Waker},
+    thread,
+    time::Duration,
+};
+
+// Pending until the value is set from elsewhere
+#[derive(Clone, Default)]
+struct Slot(Arc<Mutex<(Option<i64>, Option<Waker>)>>);
+
+impl Slot {
+    fn set(&self, value: i64) {
+        let mut slot = self.0.lock().unwrap();
+        slot.0 = Some(value);
+        if let Some(waker) = slot.1.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Future for Slot {
+    type Output = i64;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<i64> {
+        let mut slot = self.0.lock().unwrap();
+        match slot.0 {
+            Some(value) => Poll::Ready(value),
+            None => {
+                slot.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn scripts_wait_without_blocking_the_executor() {
+    let slots: Vec<_> = (0..2).map(|_| Slot::default()).collect();
+    let mut lua = Lua::new();
+    let registered = slots.clone();
+    lua.register_async("get", move |i: i64| {
+        let slot = registered[i as usize].clone();
+        async move { Ok(slot.await * 10) }
+    });
+
+    let wakes = Arc::new(CountingWaker::default());
+    let waker = Waker::from(wakes.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(lua.load("return get(0) + get(1)").eval_async::<i64>());
+    assert!(future.as_mut().poll(&mut cx).is_pending());
+    slots[0].set(1);
+    assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+    assert!(future.as_mut().poll(&mut cx).is_pending());
+    slots[1].set(2);
+    assert!(matches!(future.as_mut().poll(&mut cx), Poll::Ready(Ok(30))));
+}
+
+#[test]
+fn blocking_calls_wait_for_futures() {
+    let mut lua = Lua::new();
+    lua.register_async("double", |n: i64| async move { Ok(n * 2) });
+    lua.register_async("later", |n: i64| {
+        let slot = Slot::default();
+        let setter = slot.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            setter.set(n);
+        });
+        async move { Ok(slot.await) }
+    });
+    lua.register_async("fail", |message: String| async move {
+        Err::<(), _>(ErrorKind::other(message))
+    });
+
+    let n: i64 = lua.load("double(later(21))").eval().unwrap();
+    assert_eq!(n, 42);
+
+    lua.load(
+        r#"
+local co = coroutine.wrap(function(n)
+    coroutine.yield(later(n))
+    return double(n)
+end)
+assert(co(4) == 4 and co() == 8)
+local ok, message = pcall(fail, "boom")
+assert(not ok and message == "boom")
+function f() return later(1) end"#,
+    )
+    .exec()
+    .unwrap();
+
+    let err = lua.call::<_, _, i64>("f", ()).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("attempt to await across a C-call boundary"));
+}
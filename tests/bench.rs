@@ -0,0 +1,29 @@
+// The benchmark workloads, run small enough to check that they pass
+use mochi_lua::bench::{self, Workload};
+
+#[test]
+fn workloads_pass() {
+    for workload in bench::WORKLOADS {
+        let size = match workload.name {
+            "fib" => 15,
+            "binary-trees" => 6,
+            _ => 100,
+        };
+        let small = Workload { size, ..*workload };
+        if let Err(err) = bench::run(&small) {
+            panic!("{}: {err}", workload.name);
+        }
+    }
+}
+
+#[test]
+fn broken_workloads_fail() {
+    let broken = Workload {
+        name: "broken",
+        source: "local n = ... if n ~= 1 then error('wrong size') end",
+        size: 2,
+    };
+    let err = bench::run(&broken).unwrap_err();
+    assert!(err.to_string().contains("broken:1: wrong size"), "{err}");
+    assert!(bench::workload("nbody").is_some());
+}
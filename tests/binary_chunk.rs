@@ -0,0 +1,185 @@
+// Binary chunks as written by binary_chunk::dump and read back by
+// binary_chunk::load
+use mochi_lua::{
+    binary_chunk::{self, ByteOrder, DeserializeError, DumpOptions},
+    gc::GcHeap,
+    lua::Lua,
+    types::DetachedValue,
+};
+
+const SOURCE: &str = "local t = {1, 2.5, 'three'}
+return function() return t end";
+
+fn dumped() -> Vec<u8> {
+    GcHeap::new().with(|gc, _| {
+        let proto = mochi_lua::load(gc, SOURCE, "@chunk.lua").unwrap();
+        let mut chunk = Vec::new();
+        binary_chunk::dump(&mut chunk, &proto).unwrap();
+        chunk
+    })
+}
+
+fn load(chunk: &[u8]) -> Result<(), DeserializeError> {
+    GcHeap::new().with(|gc, _| binary_chunk::load(gc, &mut &chunk[..]).map(|_| ()))
+}
+
+#[test]
+fn truncated_chunks_are_rejected() {
+    let chunk = dumped();
+    assert!(load(&chunk).is_ok());
+    for len in 0..chunk.len() {
+        let result = load(&chunk[..len]);
+        assert!(
+            matches!(result, Err(DeserializeError::Truncated)),
+            "{len} bytes: {result:?}"
+        );
+    }
+
+    let mut lua = Lua::new();
+    let message: String = lua
+        .load(
+            "local chunk = string.dump(function() return 1 end)
+            return select(2, load(chunk:sub(1, 40)))",
+        )
+        .eval()
+        .unwrap();
+    assert_eq!(message, "bad binary format (truncated chunk)");
+}
+
+#[test]
+fn header_mismatches_are_rejected() {
+    let chunk = dumped();
+    let with_byte = |index: usize, byte: u8| {
+        let mut chunk = chunk.clone();
+        chunk[index] = byte;
+        load(&chunk).unwrap_err()
+    };
+
+    assert!(matches!(with_byte(1, b'X'), DeserializeError::BadMagic));
+    assert!(matches!(
+        with_byte(4, 0x52),
+        DeserializeError::UnsupportedVersion
+    ));
+    assert!(matches!(
+        with_byte(5, 1),
+        DeserializeError::UnsupportedFormat
+    ));
+    // LUAC_DATA catches chunks mangled by text conversions
+    assert!(matches!(with_byte(10, b'\n'), DeserializeError::Corrupted));
+    for (index, byte, name) in [
+        (12, 8, "Instruction"),
+        (13, 2, "lua_Integer"),
+        (14, 16, "lua_Number"),
+    ] {
+        let err = with_byte(index, byte);
+        assert!(
+            matches!(err, DeserializeError::SizeMismatch(size) if size == name),
+            "{name}: {err:?}"
+        );
+    }
+    assert!(matches!(
+        with_byte(16, 0x12),
+        DeserializeError::IntegerFormatMismatch
+    ));
+    assert!(matches!(
+        with_byte(30, 0x41),
+        DeserializeError::FloatFormatMismatch
+    ));
+    assert_eq!(
+        with_byte(0, 0).to_string(),
+        "bad binary format (not a binary chunk)"
+    );
+}
+
+#[test]
+fn debug_info_is_loaded() {
+    GcHeap::new().with(|gc, _| {
+        let chunk = dumped();
+        let proto = binary_chunk::load(gc, &mut chunk.as_slice()).unwrap();
+        assert_eq!(proto.source.as_ref(), b"@chunk.lua");
+        assert_eq!(proto.local_vars.as_ref().unwrap()[0].name.as_ref(), b"t");
+        let child = &proto.protos[0];
+        assert!(child.line_info.is_some());
+        let names = child.upvalue_names.as_ref().unwrap();
+        assert_eq!(names[0].as_ref(), b"t");
+    });
+}
+
+fn dump_with(source: &str, options: &DumpOptions) -> Vec<u8> {
+    GcHeap::new().with(|gc, _| {
+        let proto = mochi_lua::load(gc, source, "@chunk.lua").unwrap();
+        let mut chunk = Vec::new();
+        binary_chunk::dump_with_options(&mut chunk, &proto, options).unwrap();
+        chunk
+    })
+}
+
+fn run(chunk: &[u8]) -> Result<Vec<DetachedValue>, String> {
+    Lua::new()
+        .load(chunk)
+        .eval_multi()
+        .map_err(|err| err.to_string().lines().next().unwrap().to_owned())
+}
+
+#[test]
+fn dump_options_round_trip() {
+    let source = "local t = {1, 2.5, 'three', 1 << 62, -0.0}
+        return #t, t[2], t[3], t[4], 1 / t[5]";
+    let expected = run(source.as_bytes()).unwrap();
+    let failing = "local x\nreturn x.y";
+
+    let native = dump_with(source, &DumpOptions::default());
+    for byte_order in [ByteOrder::Native, ByteOrder::Little, ByteOrder::Big] {
+        for strip in [false, true] {
+            let options = DumpOptions { strip, byte_order };
+            let chunk = dump_with(source, &options);
+            assert_eq!(run(&chunk).unwrap(), expected, "{options:?}");
+            assert_eq!(chunk.len() < native.len(), strip, "{options:?}");
+
+            let err = run(&dump_with(failing, &options)).unwrap_err();
+            if strip {
+                assert!(!err.contains("chunk.lua"), "{err}");
+            } else {
+                assert!(
+                    err.starts_with("chunk.lua:2: attempt to index a nil value (local 'x')"),
+                    "{err}"
+                );
+            }
+        }
+    }
+    // LUAC_INT is written in the chosen byte order
+    let little = dump_with(source, &DumpOptions::default());
+    let big = dump_with(
+        source,
+        &DumpOptions {
+            byte_order: ByteOrder::Big,
+            ..Default::default()
+        },
+    );
+    assert_eq!(little[15], 0x78);
+    assert_eq!(big[22], 0x78);
+}
+
+#[test]
+fn string_dump_strips_debug_info() {
+    let mut lua = Lua::new();
+    let results = lua
+        .load(
+            "local function f(a) local b = a * 2 if b > 100 then error('too big') end return b end
+            local full, stripped = string.dump(f), string.dump(f, true)
+            return #stripped < #full, load(stripped)(21),
+                select(2, pcall(load(full), 60)), select(2, pcall(load(stripped), 60))",
+        )
+        .name("=chunk")
+        .eval_multi()
+        .unwrap();
+    assert_eq!(
+        results,
+        [
+            DetachedValue::Boolean(true),
+            DetachedValue::Integer(42),
+            DetachedValue::String(b"chunk:1: too big".to_vec()),
+            DetachedValue::String(b"too big".to_vec()),
+        ]
+    );
+}
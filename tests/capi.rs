@@ -0,0 +1,437 @@
+// The C API, called the way a C host would, which needs the "capi" feature
+#![cfg(feature = "capi")]
+use mochi_lua::capi::*;
+use std::ffi::{c_int, CStr};
+
+unsafe fn to_str<'a>(state: *mut lua_State, idx: c_int) -> &'a str {
+    CStr::from_ptr(lua_tolstring(state, idx, std::ptr::null_mut()))
+        .to_str()
+        .unwrap()
+}
+
+unsafe fn run(state: *mut lua_State, code: &CStr) {
+    assert_eq!(luaL_loadstring(state, code.as_ptr()), LUA_OK);
+    assert_eq!(lua_pcallk(state, 0, 0, 0, 0, None), LUA_OK, "{}", {
+        to_str(state, -1)
+    });
+}
+
+unsafe extern "C-unwind" fn add(state: *mut lua_State) -> c_int {
+    let a = luaL_checkinteger(state, 1);
+    let b = luaL_optinteger(state, 2, 1);
+    lua_pushinteger(state, a + b);
+    1
+}
+
+unsafe extern "C-unwind" fn counter(state: *mut lua_State) -> c_int {
+    let n = lua_tointegerx(state, lua_upvalueindex(1), std::ptr::null_mut()) + 1;
+    lua_pushinteger(state, n);
+    lua_copy(state, -1, lua_upvalueindex(1));
+    1
+}
+
+unsafe extern "C-unwind" fn fail(state: *mut lua_State) -> c_int {
+    lua_pushstring(state, c"failed".as_ptr());
+    lua_error(state)
+}
+
+#[test]
+fn c_functions_are_called_from_lua() {
+    unsafe {
+        let state = luaL_newstate();
+        luaL_openlibs(state);
+        lua_createtable(state, 0, 2);
+        let functions = [
+            luaL_Reg {
+                name: c"add".as_ptr(),
+                func: Some(add),
+            },
+            luaL_Reg {
+                name: c"fail".as_ptr(),
+                func: Some(fail),
+            },
+            luaL_Reg {
+                name: std::ptr::null(),
+                func: None,
+            },
+        ];
+        luaL_setfuncs(state, functions.as_ptr(), 0);
+        lua_setglobal(state, c"c".as_ptr());
+        lua_pushinteger(state, 10);
+        lua_pushcclosure(state, counter, 1);
+        lua_setglobal(state, c"counter".as_ptr());
+        assert_eq!(lua_gettop(state), 0);
+
+        run(
+            state,
+            cr#"
+assert(c.add(1, 2) == 3 and c.add(41) == 42)
+assert(counter() == 11 and counter() == 12)
+local ok, message = pcall(c.add, "x")
+assert(not ok and message:find("bad argument #1 (number expected, got string)", 1, true))
+ok, message = pcall(c.add, 1.5)
+assert(not ok and message:find("number has no integer representation", 1, true))
+ok, message = pcall(c.fail)
+assert(not ok and message == "failed")"#,
+        );
+
+        lua_getglobal(state, c"c".as_ptr());
+        lua_getfield(state, -1, c"add".as_ptr());
+        lua_pushinteger(state, 20);
+        lua_pushinteger(state, 22);
+        lua_callk(state, 2, 1, 0, None);
+        assert_eq!(lua_tointegerx(state, -1, std::ptr::null_mut()), 42);
+        lua_settop(state, 0);
+        lua_close(state);
+    }
+}
+
+// Uses what the checks return the way C code does, which is only safe if
+// they don't return after an error
+unsafe extern "C-unwind" fn describe(state: *mut lua_State) -> c_int {
+    let name = CStr::from_ptr(luaL_checklstring(state, 1, std::ptr::null_mut()));
+    let count = luaL_checkinteger(state, 2);
+    let mut len = 0;
+    let separator = luaL_optlstring(state, 3, c", ".as_ptr(), &mut len);
+    let separator = std::slice::from_raw_parts(separator.cast::<u8>(), len);
+    let mut result = Vec::new();
+    for i in 0..count {
+        if i > 0 {
+            result.extend_from_slice(separator);
+        }
+        result.extend_from_slice(name.to_bytes());
+    }
+    if count > 3 {
+        lua_pushstring(state, c"too many".as_ptr());
+        lua_error(state);
+    }
+    lua_pushlstring(state, result.as_ptr().cast(), result.len());
+    1
+}
+
+// Calls its first argument with more arguments than are on the stack
+unsafe extern "C-unwind" fn call_too_many(state: *mut lua_State) -> c_int {
+    lua_callk(state, lua_gettop(state), 0, 0, None);
+    0
+}
+
+unsafe extern "C-unwind" fn pcall_too_many(state: *mut lua_State) -> c_int {
+    lua_pcallk(state, 5, 0, 0, 0, None)
+}
+
+#[test]
+fn stack_underflows_raise_errors() {
+    unsafe {
+        let state = luaL_newstate();
+        luaL_openlibs(state);
+        lua_pushcclosure(state, call_too_many, 0);
+        lua_setglobal(state, c"call_too_many".as_ptr());
+        lua_pushcclosure(state, pcall_too_many, 0);
+        lua_setglobal(state, c"pcall_too_many".as_ptr());
+
+        run(
+            state,
+            cr#"
+local ok, message = pcall(call_too_many, print, 1)
+assert(not ok and message == "not enough elements in the stack")
+ok, message = pcall(pcall_too_many, print)
+assert(not ok and message == "not enough elements in the stack")"#,
+        );
+        lua_close(state);
+    }
+}
+
+#[test]
+fn errors_do_not_return() {
+    unsafe {
+        let state = luaL_newstate();
+        luaL_openlibs(state);
+        lua_pushcclosure(state, describe, 0);
+        lua_setglobal(state, c"describe".as_ptr());
+
+        run(
+            state,
+            cr#"
+assert(describe("a", 3) == "a, a, a" and describe("b", 2, "") == "bb")
+local ok, message = pcall(describe, {}, 2)
+assert(not ok and message:find("bad argument #1 (string expected, got table)", 1, true))
+ok, message = pcall(describe, "a", "x")
+assert(not ok and message:find("bad argument #2 (number expected, got string)", 1, true))
+ok, message = pcall(describe, "a", 1, {})
+assert(not ok and message:find("bad argument #3 (string expected, got table)", 1, true))
+ok, message = pcall(describe, "a", 4)
+assert(not ok and message == "too many")
+assert(select('#', pcall(describe, "a", 4)) == 2)"#,
+        );
+        lua_close(state);
+    }
+}
+
+#[test]
+fn stack_and_tables() {
+    unsafe {
+        let state = luaL_newstate();
+        lua_pushinteger(state, 1);
+        lua_pushnumber(state, 2.5);
+        lua_pushstring(state, c"three".as_ptr());
+        lua_pushboolean(state, 1);
+        lua_pushnil(state);
+        assert_eq!(lua_gettop(state), 5);
+        assert_eq!(lua_type(state, 2), LUA_TNUMBER);
+        assert_eq!(lua_type(state, -3), LUA_TSTRING);
+        assert_eq!(lua_type(state, 6), LUA_TNONE);
+        assert_eq!(lua_isinteger(state, 1), 1);
+        assert_eq!(lua_isinteger(state, 2), 0);
+        assert_eq!(lua_toboolean(state, -1), 0);
+        assert_eq!(lua_absindex(state, -1), 5);
+
+        // Numbers are converted in place
+        assert_eq!(to_str(state, 2), "2.5");
+        assert_eq!(lua_type(state, 2), LUA_TSTRING);
+
+        lua_rotate(state, 1, 1);
+        assert_eq!(lua_type(state, 1), LUA_TNIL);
+        assert_eq!(lua_tointegerx(state, 2, std::ptr::null_mut()), 1);
+        lua_settop(state, 0);
+
+        lua_createtable(state, 0, 0);
+        for i in 1..=3 {
+            lua_pushinteger(state, i * 10);
+            lua_rawseti(state, 1, i);
+        }
+        lua_pushstring(state, c"value".as_ptr());
+        lua_setfield(state, 1, c"key".as_ptr());
+        assert_eq!(lua_rawlen(state, 1), 3);
+        assert_eq!(luaL_len(state, 1), 3);
+        assert_eq!(lua_geti(state, 1, 2), LUA_TNUMBER);
+        assert_eq!(lua_tointegerx(state, -1, std::ptr::null_mut()), 20);
+        lua_settop(state, 1);
+
+        let mut sum = 0;
+        let mut count = 0;
+        lua_pushnil(state);
+        while lua_next(state, 1) != 0 {
+            if lua_isinteger(state, -1) == 1 {
+                sum += lua_tointegerx(state, -1, std::ptr::null_mut());
+            }
+            count += 1;
+            lua_settop(state, -2);
+        }
+        assert_eq!((sum, count), (60, 4));
+
+        lua_pushstring(state, c"a".as_ptr());
+        lua_pushinteger(state, 1);
+        lua_pushnumber(state, 0.5);
+        lua_concat(state, 3);
+        assert_eq!(to_str(state, -1), "a10.5");
+        lua_settop(state, 0);
+
+        // References reuse freed slots
+        lua_createtable(state, 0, 0);
+        lua_pushstring(state, c"x".as_ptr());
+        let x = luaL_ref(state, 1);
+        lua_pushstring(state, c"y".as_ptr());
+        let y = luaL_ref(state, 1);
+        assert_eq!((x, y), (1, 2));
+        lua_pushnil(state);
+        assert_eq!(luaL_ref(state, 1), LUA_REFNIL);
+        luaL_unref(state, 1, x);
+        lua_pushstring(state, c"z".as_ptr());
+        assert_eq!(luaL_ref(state, 1), x);
+        lua_rawgeti(state, 1, y as lua_Integer);
+        assert_eq!(to_str(state, -1), "y");
+        lua_close(state);
+    }
+}
+
+#[test]
+fn the_heap_is_collected_during_calls() {
+    unsafe {
+        let state = luaL_newstate();
+        luaL_openlibs(state);
+        // Allocates far more than the heap holds at any time, if it's
+        // collected meanwhile
+        let code = c"
+            local peak = 0
+            for i = 1, 200000 do
+                local t = {i, tostring(i)}
+                if i % 1000 == 0 and collectgarbage('count') > peak then
+                    peak = collectgarbage('count')
+                end
+            end
+            return peak";
+        for protected in [true, false] {
+            assert_eq!(luaL_loadstring(state, code.as_ptr()), LUA_OK);
+            if protected {
+                assert_eq!(lua_pcallk(state, 0, 1, 0, 0, None), LUA_OK);
+            } else {
+                lua_callk(state, 0, 1, 0, None);
+            }
+            let peak_kb = lua_tonumberx(state, -1, std::ptr::null_mut());
+            assert!(peak_kb < 4096.0, "{peak_kb} KB");
+            lua_settop(state, 0);
+        }
+        lua_close(state);
+    }
+}
+
+#[test]
+fn strings_stay_valid_while_on_the_stack() {
+    unsafe {
+        let state = luaL_newstate();
+        lua_pushstring(state, c"kept".as_ptr());
+        let kept = lua_tolstring(state, 1, std::ptr::null_mut());
+        // Strings pushed and popped above a value the host keeps
+        for i in 0..1000 {
+            let s = std::ffi::CString::new(format!("string {i}")).unwrap();
+            let pushed = lua_pushstring(state, s.as_ptr());
+            lua_pushinteger(state, i);
+            let converted = lua_tolstring(state, -1, std::ptr::null_mut());
+            assert_eq!(CStr::from_ptr(pushed), s.as_c_str());
+            assert_eq!(CStr::from_ptr(converted).to_str().unwrap(), i.to_string());
+            lua_settop(state, 1);
+        }
+        assert_eq!(CStr::from_ptr(kept), c"kept");
+        assert_eq!(lua_tolstring(state, 1, std::ptr::null_mut()), kept);
+
+        // A slot that holds another string hands out that one
+        lua_pushstring(state, c"other".as_ptr());
+        lua_copy(state, 2, 1);
+        assert_eq!(to_str(state, 1), "other");
+        lua_close(state);
+    }
+}
+
+unsafe extern "C-unwind" fn new_point(state: *mut lua_State) -> c_int {
+    let x = luaL_checknumber(state, 1);
+    let y = luaL_checknumber(state, 2);
+    let point = lua_newuserdatauv(state, 16, 0).cast::<[f64; 2]>();
+    *point = [x, y];
+    luaL_setmetatable(state, c"Point".as_ptr());
+    1
+}
+
+unsafe extern "C-unwind" fn point_index(state: *mut lua_State) -> c_int {
+    let point = luaL_checkudata(state, 1, c"Point".as_ptr()).cast::<[f64; 2]>();
+    let i = match CStr::from_ptr(luaL_checklstring(state, 2, std::ptr::null_mut())).to_bytes() {
+        b"x" => 0,
+        b"y" => 1,
+        _ => return luaL_argerror(state, 2, c"no such field".as_ptr()),
+    };
+    lua_pushnumber(state, (*point)[i]);
+    1
+}
+
+#[test]
+fn userdata_and_metatables() {
+    unsafe {
+        let state = luaL_newstate();
+        luaL_openlibs(state);
+        assert_eq!(luaL_newmetatable(state, c"Point".as_ptr()), 1);
+        lua_pushcclosure(state, point_index, 0);
+        lua_setfield(state, -2, c"__index".as_ptr());
+        lua_settop(state, 0);
+        assert_eq!(luaL_newmetatable(state, c"Point".as_ptr()), 0);
+        lua_settop(state, 0);
+        lua_pushcclosure(state, new_point, 0);
+        lua_setglobal(state, c"Point".as_ptr());
+
+        run(
+            state,
+            cr#"
+local p = Point(1, 2.5)
+assert(p.x == 1 and p.y == 2.5)
+local ok, message = pcall(function() return p.z end)
+assert(not ok and message:find("no such field", 1, true))
+ok, message = pcall(getmetatable(p).__index, {}, "x")
+assert(not ok and message:find("Point expected, got table", 1, true))
+point = p"#,
+        );
+
+        lua_getglobal(state, c"point".as_ptr());
+        assert!(!luaL_testudata(state, 1, c"Point".as_ptr()).is_null());
+        assert!(luaL_testudata(state, 1, c"Other".as_ptr()).is_null());
+        assert_eq!(lua_rawlen(state, 1), 16);
+        assert_eq!(lua_getfield(state, 1, c"y".as_ptr()), LUA_TNUMBER);
+        assert_eq!(lua_tonumberx(state, -1, std::ptr::null_mut()), 2.5);
+        assert_eq!(luaL_getmetafield(state, 1, c"__name".as_ptr()), LUA_TSTRING);
+        assert_eq!(to_str(state, -1), "Point");
+        luaL_tolstring(state, 1, std::ptr::null_mut());
+        assert!(to_str(state, -1).starts_with("Point: 0x"));
+        lua_close(state);
+    }
+}
+
+#[test]
+fn loading_and_protected_calls() {
+    unsafe {
+        let state = luaL_newstate();
+        luaL_openlibs(state);
+        assert_eq!(
+            luaL_loadstring(state, c"return 1 +".as_ptr()),
+            LUA_ERRSYNTAX
+        );
+        lua_settop(state, 0);
+
+        let chunk = b"return ...";
+        let status = luaL_loadbufferx(
+            state,
+            chunk.as_ptr().cast(),
+            chunk.len(),
+            c"=chunk".as_ptr(),
+            c"b".as_ptr(),
+        );
+        assert_eq!(status, LUA_ERRSYNTAX);
+        assert_eq!(
+            to_str(state, -1),
+            "attempt to load a text chunk (mode is 'b')"
+        );
+        lua_settop(state, 0);
+
+        assert_eq!(luaL_loadstring(state, c"return ...".as_ptr()), LUA_OK);
+        lua_pushinteger(state, 1);
+        lua_pushinteger(state, 2);
+        assert_eq!(lua_pcallk(state, 2, LUA_MULTRET, 0, 0, None), LUA_OK);
+        assert_eq!(lua_gettop(state), 2);
+        lua_settop(state, 0);
+
+        // Errors go through the message handler
+        run(state, c"function handler(m) return 'handled: ' .. m end");
+        lua_getglobal(state, c"handler".as_ptr());
+        luaL_loadstring(state, c"error('boom', 0)".as_ptr());
+        assert_eq!(lua_pcallk(state, 0, 0, 1, 0, None), LUA_ERRRUN);
+        assert_eq!(to_str(state, -1), "handled: boom");
+        lua_settop(state, 0);
+
+        luaL_loadstring(state, c"error({})".as_ptr());
+        assert_eq!(lua_pcallk(state, 0, 0, 0, 0, None), LUA_ERRRUN);
+        assert_eq!(lua_type(state, -1), LUA_TTABLE);
+        lua_settop(state, 0);
+
+        let status = luaL_loadfilex(state, c"/nonexistent.lua".as_ptr(), std::ptr::null());
+        assert_eq!(status, LUA_ERRFILE);
+        assert!(to_str(state, -1).starts_with("cannot open /nonexistent.lua"));
+        lua_settop(state, 0);
+
+        // Metamethods apply outside of raw accesses
+        run(
+            state,
+            cr#"
+t = setmetatable({}, {
+    __index = function(_, k) return k * 2 end,
+    __len = function() return 7 end,
+    __lt = function() return true end,
+})"#,
+        );
+        lua_getglobal(state, c"t".as_ptr());
+        lua_geti(state, 1, 21);
+        assert_eq!(lua_tointegerx(state, -1, std::ptr::null_mut()), 42);
+        lua_rawgeti(state, 1, 21);
+        assert_eq!(lua_type(state, -1), LUA_TNIL);
+        assert_eq!(luaL_len(state, 1), 7);
+        assert_eq!(lua_compare(state, 1, 1, LUA_OPLT), 1);
+        assert_eq!(lua_rawequal(state, 1, 1), 1);
+        assert_eq!(lua_compare(state, 2, 3, LUA_OPEQ), 0);
+        lua_close(state);
+    }
+}
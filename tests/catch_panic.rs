@@ -0,0 +1,43 @@
+// Panics inside the interpreter, turned into errors by the "catch-panic"
+// feature
+#![cfg(feature = "catch-panic")]
+use mochi_lua::{
+    lua::Lua,
+    runtime::{ErrorKind, OpCode},
+    Error,
+};
+
+#[test]
+fn internal_errors_locate_the_instruction() {
+    let mut lua = Lua::new();
+    lua.register("boom", |n: i64| -> Result<i64, ErrorKind> {
+        panic!("boom {n}")
+    });
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = lua
+        .load("local x = 1\nlocal y = x + 1\nboom(y)")
+        .name("=chunk")
+        .exec();
+    let _ = std::panic::take_hook();
+
+    let err = match result {
+        Err(Error::Runtime(err)) => err,
+        result => panic!("unexpected {result:?}"),
+    };
+    let ErrorKind::Internal(internal) = &err.kind else {
+        panic!("unexpected {:?}", err.kind);
+    };
+    assert_eq!(internal.message, "boom 2");
+    assert_eq!(internal.opcode, Some(OpCode::Call));
+    assert_eq!(internal.pc, Some(6));
+    assert_eq!(internal.source.as_deref(), Some("chunk"));
+    assert_eq!(
+        err.kind.to_string(),
+        "internal error: boom 2 (at CALL, pc 6 in chunk)"
+    );
+
+    // The interrupted state is thrown away, and the rest of the VM stays
+    // usable
+    let n: i64 = lua.load("return 1 + 2").eval().unwrap();
+    assert_eq!(n, 3);
+}
@@ -0,0 +1,127 @@
+use bstr::B;
+use mochi_lua::{
+    channel::{self, Channel},
+    lua::Lua,
+    types::DetachedValue,
+};
+use std::time::Duration;
+
+fn lua_with_channel(channel: &Channel) -> Lua {
+    let mut lua = Lua::new();
+    lua.with(|gc, vm| {
+        channel::open(gc, vm);
+        let channel = channel.to_lua(gc, vm);
+        vm.globals()
+            .borrow_mut(gc)
+            .set_field(gc.allocate_string(B("ch")), channel);
+    });
+    lua
+}
+
+#[test]
+fn states_ping_pong() {
+    let mut lua = lua_with_channel(&Channel::new());
+    let n: i64 = lua
+        .load(
+            r#"
+local back = channel.new()
+local worker = channel.spawn(function(inbox, outbox)
+    while true do
+        local n = inbox:receive()
+        if n == nil then return "done" end
+        outbox:send(n + 1)
+    end
+end, ch, back)
+local n = 0
+for _ = 1, 100 do
+    ch:send(n)
+    n = back:receive()
+end
+ch:send(nil)
+local ok, result = worker:join()
+assert(ok and result == "done")
+assert(not pcall(worker.join, worker))
+return n"#,
+        )
+        .eval()
+        .unwrap();
+    assert_eq!(n, 100);
+}
+
+#[test]
+fn tables_and_functions_are_copied() {
+    let mut lua = lua_with_channel(&Channel::new());
+    lua.load(
+        r#"
+local t = {1, 2.5, "x", nested = {deep = {true}}}
+ch:send(t, function(a, b) return a * b end)
+local u, f = ch:receive()
+assert(u ~= t and u.nested ~= t.nested)
+assert(u[1] == 1 and math.type(u[2]) == "float" and u[3] == "x")
+assert(u.nested.deep[1] == true)
+assert(f(6, 7) == 42)
+
+local ok, sum = channel.spawn(function(t)
+    local sum = 0
+    for _, v in ipairs(t) do sum = sum + v end
+    return sum
+end, {1, 2, 3}):join()
+assert(ok and sum == 6)
+
+local ok, message = channel.spawn(function() error("boom", 0) end):join()
+assert(not ok and message:find("boom"))
+assert(not pcall(ch.send, ch, coroutine.create(print)))
+assert(not pcall(channel.spawn, print))"#,
+    )
+    .exec()
+    .unwrap();
+}
+
+#[test]
+fn rust_and_lua_share_channels() {
+    let inbox = Channel::new();
+    let outbox = Channel::new();
+    let worker = {
+        let (inbox, outbox) = (inbox.clone(), outbox.clone());
+        channel::spawn(move |lua| {
+            lua.with(|gc, vm| {
+                let inbox = inbox.to_lua(gc, vm);
+                let outbox = outbox.to_lua(gc, vm);
+                let globals = vm.globals();
+                let mut globals = globals.borrow_mut(gc);
+                globals.set_field(gc.allocate_string(B("inbox")), inbox);
+                globals.set_field(gc.allocate_string(B("outbox")), outbox);
+            });
+            lua.load("local s = inbox:receive() outbox:send(s:upper())")
+                .exec()
+                .unwrap();
+        })
+    };
+    inbox.send(vec![DetachedValue::String(b"hello".to_vec())]);
+    worker.join().unwrap();
+    assert!(inbox.is_empty());
+    assert!(matches!(
+        outbox.try_receive().as_deref(),
+        Some([DetachedValue::String(s)]) if s == b"HELLO"
+    ));
+    assert!(outbox.receive_timeout(Duration::from_millis(10)).is_none());
+
+    let mut lua = lua_with_channel(&outbox);
+    lua.load("assert(ch:len() == 0 and select('#', ch:receive(0.01)) == 0)")
+        .exec()
+        .unwrap();
+}
+
+#[test]
+fn dumped_functions_with_upvalues_load() {
+    let mut lua = Lua::new();
+    lua.load(
+        r#"
+local a, b = 1, 2
+local f = load(string.dump(function() return a, b end))
+local x, y = f()
+assert(x == _G and y == nil)"#,
+    )
+    .exec()
+    .unwrap();
+}
@@ -0,0 +1,73 @@
+// Loading files through a ChunkCache
+use mochi_lua::{
+    chunk_cache::ChunkCache,
+    runtime::{Runtime, RuntimeError},
+    CompileOptions,
+};
+use std::{fs, path::Path};
+
+fn run(cache: &ChunkCache, path: &Path, options: &CompileOptions) -> Result<(), RuntimeError> {
+    let mut runtime = Runtime::new();
+    runtime
+        .heap()
+        .with(|gc, vm| vm.borrow_mut(gc).load_stdlib(gc));
+    runtime.execute(|gc, vm| {
+        let proto = cache.load_file(gc, path, options)?;
+        Ok(gc.allocate(vm.borrow().load_proto(gc, proto)).into())
+    })
+}
+
+fn cached_chunks(cache: &ChunkCache) -> Vec<Vec<u8>> {
+    let mut chunks: Vec<_> = fs::read_dir(cache.dir())
+        .unwrap()
+        .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+        .collect();
+    chunks.sort();
+    chunks
+}
+
+#[test]
+fn chunks_are_cached_by_source_and_options() {
+    let dir = std::env::temp_dir().join(format!("mochi-chunk-cache-{}", std::process::id()));
+    let script = dir.join("script.lua");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(&script, "local x = 1\nerror('failed with ' .. x)").unwrap();
+    let cache = ChunkCache::new(dir.join("cache"));
+    let options = CompileOptions::default();
+
+    let err = run(&cache, &script, &options).unwrap_err();
+    let chunks = cached_chunks(&cache);
+    assert_eq!(chunks.len(), 1);
+    assert!(chunks[0].starts_with(b"\x1bLua"));
+    // The cached chunk keeps the chunk name and lines
+    let cached_err = run(&cache, &script, &options).unwrap_err();
+    assert_eq!(cached_err.to_string(), err.to_string());
+    assert!(
+        err.to_string().contains("script.lua:2: failed with 1"),
+        "{err}"
+    );
+    assert_eq!(cached_chunks(&cache), chunks);
+
+    // Changed sources and options are compiled again
+    fs::write(&script, "local x = 2\nerror('failed with ' .. x)").unwrap();
+    let err = run(&cache, &script, &options).unwrap_err();
+    assert!(err.to_string().contains("failed with 2"), "{err}");
+    let strip = CompileOptions {
+        strip: true,
+        ..Default::default()
+    };
+    run(&cache, &script, &strip).unwrap_err();
+    assert_eq!(cached_chunks(&cache).len(), 3);
+
+    // A chunk that doesn't load is replaced
+    let chunks = cached_chunks(&cache);
+    for entry in fs::read_dir(cache.dir()).unwrap() {
+        fs::write(entry.unwrap().path(), b"\x1bLua garbage").unwrap();
+    }
+    run(&cache, &script, &strip).unwrap_err();
+    assert!(cached_chunks(&cache)
+        .iter()
+        .any(|chunk| chunks.contains(chunk)));
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,101 @@
+// <const> locals, which can't be assigned to and whose literal values are
+// compiled into the instructions using them
+use mochi_lua::{disasm::disassemble, gc::GcHeap, lua::Lua, types::DetachedValue};
+
+fn compile_error(source: &str) -> String {
+    Lua::new().load(source).exec().unwrap_err().to_string()
+}
+
+fn listing(source: &str) -> String {
+    GcHeap::new().with(|gc, _| {
+        let proto = mochi_lua::load(gc, source, "=chunk").unwrap();
+        let mut listing = Vec::new();
+        disassemble(&mut listing, &proto, false).unwrap();
+        for child in proto.protos.iter() {
+            disassemble(&mut listing, child, false).unwrap();
+        }
+        String::from_utf8(listing).unwrap()
+    })
+}
+
+#[test]
+fn const_locals_cannot_be_assigned() {
+    for source in [
+        "local x <const> = 1; x = 2",
+        "local x <const> = {}; local y; y, x = 1, 2",
+        "local x <const> = 1; local function f() x = 2 end",
+        "local x <const> = 1; for i = 1, 2 do x = i end",
+        "local a, x <const> = 1, 2; x = a",
+    ] {
+        let err = compile_error(source);
+        assert!(
+            err.contains("attempt to assign to const variable 'x'"),
+            "{source}: {err}"
+        );
+    }
+
+    let err = compile_error("local x <foo> = 1");
+    assert!(err.contains("unknown attribute 'foo'"), "{err}");
+
+    // Only the variable is constant, not the table it holds, and locals
+    // shadowing it can be assigned
+    let mut lua = Lua::new();
+    let source = "local t <const> = {}
+        t.x = 1
+        do local t = 2; t = t + 1 end
+        return t.x";
+    assert_eq!(lua.load(source).eval::<i64>().unwrap(), 1);
+}
+
+#[test]
+fn literal_values_are_propagated() {
+    let results = Lua::new()
+        .load(
+            "local n <const>, s <const>, f <const> = 10, 'key', 2.5
+            local t = {key = 'value'}
+            local function g() return n * f end
+            return n + 1, t[s], s .. s, g(), -n",
+        )
+        .eval_multi()
+        .unwrap();
+    assert_eq!(
+        results,
+        [
+            DetachedValue::Integer(11),
+            DetachedValue::String(b"value".to_vec()),
+            DetachedValue::String(b"keykey".to_vec()),
+            DetachedValue::Number(25.0),
+            DetachedValue::Integer(-10),
+        ]
+    );
+
+    // The constants take no registers and aren't captured as upvalues. Like
+    // the reference implementation, only the last of several locals declared
+    // together can be a compile-time constant.
+    let code = listing(
+        "local n <const> = 10
+        local s <const> = 'key'
+        local t = {}
+        return t[s] + n, function() return n end",
+    );
+    assert!(code.contains("GETFIELD"), "{code}");
+    assert!(code.contains("ADDI"), "{code}");
+    assert!(!code.contains("GETUPVAL"), "{code}");
+
+    // A const initialised with anything else is an ordinary local
+    let code = listing("local t <const> = {} return function() return t end");
+    assert!(code.contains("GETUPVAL"), "{code}");
+}
+
+#[test]
+fn calls_keep_their_arguments() {
+    let result: String = Lua::new()
+        .load(
+            "local function f(p, q, r) return p .. q .. tostring(r) end
+            local s, a = 'x', 'y'
+            return f(s:upper(), a, 1 == 1)",
+        )
+        .eval()
+        .unwrap();
+    assert_eq!(result, "Xytrue");
+}
@@ -0,0 +1,263 @@
+// Conversions between strings and numbers, which follow the Lua numeral
+// grammar, and where they happen implicitly
+use mochi_lua::lua::Lua;
+
+fn tostring_all(lua: &mut Lua, exprs: &[&str]) -> Vec<String> {
+    exprs
+        .iter()
+        .map(|expr| {
+            lua.load(format!("return tostring({expr})"))
+                .name("=t")
+                .eval()
+                .unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn tonumber_reads_numerals() {
+    let mut lua = Lua::new();
+    let results = tostring_all(
+        &mut lua,
+        &[
+            r#"tonumber("0x10")"#,
+            r#"tonumber("  42  ")"#,
+            r#"tonumber("1e3")"#,
+            r#"tonumber(" -0x1.8p1 ")"#,
+            r#"tonumber(".5")"#,
+            r#"tonumber("0xffffffffffffffff")"#,
+            r#"tonumber("9223372036854775808")"#,
+            r#"tonumber("1e")"#,
+            r#"tonumber("0x")"#,
+            r#"tonumber("1 2")"#,
+            r#"tonumber("inf")"#,
+            r#"tonumber("")"#,
+        ],
+    );
+    assert_eq!(
+        results,
+        [
+            "16",
+            "42",
+            "1000.0",
+            "-3.0",
+            "0.5",
+            "-1",
+            "9.2233720368548e+18",
+            "nil",
+            "nil",
+            "nil",
+            "nil",
+            "nil",
+        ]
+    );
+}
+
+#[test]
+fn huge_hex_exponents_overflow_to_inf_or_zero() {
+    let mut lua = Lua::new();
+    let results = tostring_all(
+        &mut lua,
+        &[
+            "0xcp3333333333333",
+            "0x1p-3333333333333",
+            r#"tonumber("0x1p99999999999999999999")"#,
+            r#"tonumber("-0x.8p-99999999999999999999")"#,
+        ],
+    );
+    assert_eq!(results, ["inf", "0.0", "inf", "-0.0"]);
+}
+
+#[test]
+fn tonumber_with_base() {
+    let mut lua = Lua::new();
+    let results = tostring_all(
+        &mut lua,
+        &[
+            r#"tonumber("10", 2)"#,
+            r#"tonumber("ff", 16)"#,
+            r#"tonumber("zz", 36)"#,
+            r#"tonumber("  -7  ", 8)"#,
+            r#"tonumber("8", 8)"#,
+            r#"tonumber("1.0", 10)"#,
+            r#"tonumber("ffffffffffffffff", 16)"#,
+            r#"select(2, pcall(tonumber, "1", 37))"#,
+            r#"select(2, pcall(tonumber, 10, 16))"#,
+        ],
+    );
+    assert_eq!(
+        results,
+        [
+            "2",
+            "255",
+            "1295",
+            "-7",
+            "nil",
+            "nil",
+            "-1",
+            "bad argument #2 (base out of range)",
+            "bad argument #1 (string expected, got number)",
+        ]
+    );
+}
+
+#[test]
+fn arithmetic_converts_strings() {
+    let mut lua = Lua::new();
+    let results = tostring_all(
+        &mut lua,
+        &[
+            r#""10" + 1"#,
+            r#""10" - 1"#,
+            r#""3.0" + 1"#,
+            r#""0x10" * "2""#,
+            r#"-"2""#,
+            r#""7" // "2""#,
+            r#""2" ^ 2"#,
+            r#"math.type(" 10 " + 0)"#,
+            r#"select(2, pcall(function() return "7" // "0" end))"#,
+            r#"select(2, pcall(function() return 3.5 & 1 end))"#,
+            r#"select(2, pcall(function() return "abc" + 1 end))"#,
+        ],
+    );
+    assert_eq!(
+        results,
+        [
+            "11",
+            "9",
+            "4.0",
+            "32",
+            "-2",
+            "3",
+            "4.0",
+            "integer",
+            "t:1: attempt to perform 'n//0'",
+            "t:1: number has no integer representation",
+            "t:1: attempt to perform arithmetic on a string value (constant 'abc')",
+        ]
+    );
+}
+
+#[test]
+fn comparisons_loops_and_bitwise_operations_do_not_convert_strings() {
+    let mut lua = Lua::new();
+    let results = tostring_all(
+        &mut lua,
+        &[
+            r#""10" == 10"#,
+            r#"pcall(function() return "10" < 5 end)"#,
+            r#"pcall(function() for i = "1", 2 do end end)"#,
+            r#"select(2, pcall(function() return "3" & 1 end))"#,
+            r#"select(2, pcall(function() return 1.5 | "3" end))"#,
+        ],
+    );
+    assert_eq!(
+        results,
+        [
+            "false",
+            "false",
+            "false",
+            "t:1: attempt to perform bitwise operation on a string value (constant '3')",
+            "t:1: attempt to perform bitwise operation on a string value (constant '3')",
+        ]
+    );
+}
+
+#[test]
+fn library_arguments_are_converted() {
+    let mut lua = Lua::new();
+    let results = tostring_all(
+        &mut lua,
+        &[
+            r#"string.rep("x", "3")"#,
+            r#"string.rep("x", 2.0)"#,
+            r#"string.sub(12345, 2, "3")"#,
+            r#"string.format("%d %s", "10", 1.0)"#,
+            r#"select(2, pcall(string.rep, "x", 2.5))"#,
+            r#"select(2, pcall(string.rep, "x", "y"))"#,
+        ],
+    );
+    assert_eq!(
+        results,
+        [
+            "xxx",
+            "xx",
+            "23",
+            "10 1.0",
+            "bad argument #2 (number has no integer representation)",
+            "bad argument #2 (integer expected, got string)",
+        ]
+    );
+}
+
+#[test]
+fn floats_print_with_14_digits() {
+    let mut lua = Lua::new();
+    let results = tostring_all(
+        &mut lua,
+        &[
+            "0.1 + 0.2",
+            "1e30",
+            "100.0",
+            "-0.0",
+            "2^53",
+            "2^63",
+            "1/0",
+            "math.pi",
+            "1e-5",
+            "5e-324",
+        ],
+    );
+    assert_eq!(
+        results,
+        [
+            "0.3",
+            "1e+30",
+            "100.0",
+            "-0.0",
+            "9.007199254741e+15",
+            "9.2233720368548e+18",
+            "inf",
+            "3.1415926535898",
+            "1e-05",
+            "4.9406564584125e-324",
+        ]
+    );
+}
+
+#[test]
+fn format_follows_printf() {
+    let mut lua = Lua::new();
+    let results = tostring_all(
+        &mut lua,
+        &[
+            r#"string.format("%5d|%-5d|%05d|%+d|% d", 42, 42, -42, 5, 5)"#,
+            r#"string.format("%.3d|%.0d|%x|%#x|%#06X|%#o", 7, 0, 255, 255, 255, 8)"#,
+            r#"string.format("%x", -1)"#,
+            r#"string.format("%e|%.3E|%.0e|%#.0e", 12345.678, 0.00012, 5, 5)"#,
+            r#"string.format("%.3f|%08.2f|%#.0f|%f", 1/3, -2.5, 2, 1/0)"#,
+            r#"string.format("%g|%g|%.14g|%#g|%G", 1e20, 0.0001, 0.1 + 0.2, 1, 1e-20)"#,
+            r#"string.format("%a|%A|%.3a", 1, 0.5, 3.14)"#,
+            r#"select(2, pcall(string.format, "%100d", 1))"#,
+            r#"select(2, pcall(string.format, "%#d", 1))"#,
+            r#"select(2, pcall(string.format, "%ld", 1))"#,
+            r#"select(2, pcall(string.format, "%d", 1.5))"#,
+        ],
+    );
+    assert_eq!(
+        results,
+        [
+            "   42|42   |-0042|+5| 5",
+            "007||ff|0xff|0X00FF|010",
+            "ffffffffffffffff",
+            "1.234568e+04|1.200E-04|5e+00|5.e+00",
+            "0.333|-0002.50|2.|inf",
+            "1e+20|0.0001|0.3|1.00000|1E-20",
+            "0x1p+0|0X1P-1|0x1.91fp+1",
+            "invalid conversion specification: '%100d'",
+            "invalid conversion specification: '%#d'",
+            "invalid conversion '%l' to 'format'",
+            "bad argument #2 (number has no integer representation)",
+        ]
+    );
+}
@@ -0,0 +1,74 @@
+// Counting the lines run, which needs the "coverage" feature
+#![cfg(feature = "coverage")]
+use mochi_lua::{coverage::Coverage, runtime::Runtime};
+
+const SOURCE: &str = r#"local function classify(x)
+  if x > 2 then
+    return "big"
+  end
+  return "small"
+end
+
+for i = 1, 3 do classify(i) end
+local unused = function()
+  print("never")
+end"#;
+
+fn run(source: &'static str) -> Coverage {
+    let mut runtime = Runtime::new();
+    runtime.heap().with(|gc, vm| {
+        let mut vm = vm.borrow_mut(gc);
+        vm.load_stdlib(gc);
+        vm.start_coverage();
+    });
+    runtime
+        .execute(|gc, vm| {
+            let closure = vm.borrow().load(gc, source, "@scripts/classify.lua")?;
+            Ok(gc.allocate(closure).into())
+        })
+        .unwrap();
+    runtime
+        .heap()
+        .with(|gc, vm| vm.borrow_mut(gc).stop_coverage())
+        .unwrap()
+}
+
+#[test]
+fn lines_run() {
+    let coverage = run(SOURCE);
+    let lines: Vec<_> = coverage.files["scripts/classify.lua"]
+        .iter()
+        .map(|(line, hits)| (*line, *hits))
+        .collect();
+    // A loop on a single line counts each iteration
+    assert_eq!(
+        lines,
+        [
+            (1, 1),
+            (2, 3),
+            (3, 1),
+            (5, 2),
+            (8, 3),
+            (9, 1),
+            (10, 0),
+            (11, 0)
+        ]
+    );
+}
+
+#[test]
+fn reports() {
+    let coverage = run("local x = 1\nif x > 1 then\n  x = 2\nend\nreturn x");
+    let mut lcov = Vec::new();
+    coverage.write_lcov(&mut lcov).unwrap();
+    assert_eq!(
+        String::from_utf8(lcov).unwrap(),
+        "TN:\nSF:scripts/classify.lua\nDA:1,1\nDA:2,1\nDA:3,0\nDA:5,1\nLH:3\nLF:4\nend_of_record\n"
+    );
+    let mut lua = Vec::new();
+    coverage.write_lua(&mut lua).unwrap();
+    assert_eq!(
+        String::from_utf8(lua).unwrap(),
+        "return {\n  [\"scripts/classify.lua\"] = { [1] = 1, [2] = 1, [3] = 0, [5] = 1, },\n}\n"
+    );
+}
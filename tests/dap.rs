@@ -0,0 +1,263 @@
+// A Debug Adapter Protocol session driven by a client on another thread,
+// which needs the "dap" feature
+#![cfg(feature = "dap")]
+use mochi_lua::{
+    dap::{read_message, write_message, Connection, DapHandler},
+    runtime::Runtime,
+    types::Value,
+};
+use serde_json::{json, Value as Json};
+use std::{
+    io::{self, BufReader, Read, Write},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+// One direction of an in-memory connection
+struct PipeReader {
+    receiver: Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+struct PipeWriter(Sender<Vec<u8>>);
+
+fn pipe() -> (PipeWriter, PipeReader) {
+    let (sender, receiver) = mpsc::channel();
+    let reader = PipeReader {
+        receiver,
+        buffer: Vec::new(),
+    };
+    (PipeWriter(sender), reader)
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buffer.is_empty() {
+            match self.receiver.recv() {
+                Ok(bytes) => self.buffer = bytes,
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.buffer.len());
+        buf[..n].copy_from_slice(&self.buffer[..n]);
+        self.buffer.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(buf.to_vec())
+            .map_err(|_| io::ErrorKind::BrokenPipe)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Client {
+    writer: PipeWriter,
+    reader: BufReader<PipeReader>,
+    seq: i64,
+}
+
+impl Client {
+    fn request(&mut self, command: &str, arguments: Json) -> Json {
+        self.seq += 1;
+        let request = json!({
+            "seq": self.seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        });
+        write_message(&mut self.writer, &request).unwrap();
+        let response = self.next_message(|message| message["type"] == "response");
+        assert_eq!(response["request_seq"], self.seq);
+        assert_eq!(response["success"], true, "{response}");
+        response["body"].clone()
+    }
+
+    // Skips other events before it
+    fn event(&mut self, event: &str) -> Json {
+        loop {
+            let message = self.next_message(|message| message["type"] == "event");
+            if message["event"] == event {
+                return message["body"].clone();
+            }
+        }
+    }
+
+    // Skips output events
+    fn next_message(&mut self, expected: impl Fn(&Json) -> bool) -> Json {
+        loop {
+            let message = read_message(&mut self.reader).unwrap().unwrap();
+            if message["event"] != "output" {
+                assert!(expected(&message), "{message}");
+                return message;
+            }
+        }
+    }
+}
+
+const SOURCE: &str = r#"local function add(a, b)
+  local sum = a + b
+  return sum
+end
+
+local x = add(1, 2)
+local n = 0
+while not done do
+  n = n + 1
+end
+result = x + n"#;
+
+// Runs the script under a session with the client, returning `result`
+fn debug(script: impl FnOnce(&mut Client) + Send + 'static) -> i64 {
+    let (client_writer, server_reader) = pipe();
+    let (server_writer, client_reader) = pipe();
+    let client = thread::spawn(move || {
+        let mut client = Client {
+            writer: client_writer,
+            reader: BufReader::new(client_reader),
+            seq: 0,
+        };
+        client.request("initialize", json!({}));
+        client.event("initialized");
+        script(&mut client);
+        client.event("terminated");
+        client.request("disconnect", json!({}));
+    });
+
+    let connection = Connection::new(server_reader, server_writer);
+    let mut runtime = Runtime::new();
+    runtime.heap().with(|gc, vm| {
+        let mut vm = vm.borrow_mut(gc);
+        vm.load_stdlib(gc);
+        vm.attach_debugger(DapHandler::new(connection.clone()));
+        let launch = connection.configure(&mut vm).unwrap();
+        assert_eq!(launch.program.as_deref(), Some("scripts/test.lua"));
+    });
+    runtime
+        .execute(|gc, vm| {
+            let closure = vm.borrow().load(gc, SOURCE, "@scripts/test.lua")?;
+            Ok(gc.allocate(closure).into())
+        })
+        .unwrap();
+    connection.finish(0);
+    client.join().unwrap();
+
+    runtime.heap().with(|gc, vm| {
+        let result = vm
+            .borrow()
+            .globals()
+            .borrow()
+            .get_field(gc.allocate_string(b"result".as_slice()));
+        match result {
+            Value::Integer(i) => i,
+            value => panic!("{value:?}"),
+        }
+    })
+}
+
+#[test]
+fn breakpoints_and_variables() {
+    let result = debug(|client| {
+        client.request(
+            "setBreakpoints",
+            json!({ "source": { "path": "/home/user/scripts/test.lua" }, "breakpoints": [{ "line": 3 }] }),
+        );
+        client.request("launch", json!({ "program": "scripts/test.lua" }));
+        client.request("configurationDone", json!({}));
+
+        let stopped = client.event("stopped");
+        assert_eq!(stopped["reason"], "breakpoint");
+        let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+        let frames = trace["stackFrames"].as_array().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0]["name"], "add");
+        assert_eq!(frames[0]["line"], 3);
+        assert_eq!(frames[1]["line"], 6);
+        assert_eq!(frames[1]["source"]["path"], "scripts/test.lua");
+
+        let scopes = client.request("scopes", json!({ "frameId": 1 }));
+        let locals = scopes["scopes"][0]["variablesReference"].clone();
+        let vars = client.request("variables", json!({ "variablesReference": locals }));
+        let vars: Vec<_> = vars["variables"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|var| {
+                format!(
+                    "{}={}",
+                    var["name"].as_str().unwrap(),
+                    var["value"].as_str().unwrap()
+                )
+            })
+            .collect();
+        assert_eq!(vars, ["a=1", "b=2", "sum=3"]);
+
+        let result = client.request(
+            "evaluate",
+            json!({ "expression": "{ sum * 10 }", "frameId": 1 }),
+        );
+        assert_eq!(result["type"], "table");
+        let fields = client.request(
+            "variables",
+            json!({ "variablesReference": result["variablesReference"] }),
+        );
+        assert_eq!(fields["variables"][0]["value"], "30");
+
+        client.request(
+            "evaluate",
+            json!({ "expression": "sum = 100", "frameId": 1 }),
+        );
+        client.request(
+            "evaluate",
+            json!({ "expression": "done = true", "frameId": 1 }),
+        );
+        client.request("stepOut", json!({ "threadId": 1 }));
+        assert_eq!(client.event("stopped")["reason"], "step");
+        client.request("continue", json!({ "threadId": 1 }));
+    });
+    assert_eq!(result, 100);
+}
+
+#[test]
+fn pause_running_script() {
+    let result = debug(|client| {
+        client.request(
+            "launch",
+            json!({ "program": "scripts/test.lua", "stopOnEntry": true }),
+        );
+        client.request("configurationDone", json!({}));
+        assert_eq!(client.event("stopped")["reason"], "entry");
+        client.request("continue", json!({ "threadId": 1 }));
+
+        // Breakpoints can be set while the script runs
+        client.request(
+            "setBreakpoints",
+            json!({ "source": { "path": "test.lua" }, "breakpoints": [{ "line": 11 }] }),
+        );
+        // The pause can land before the script reaches the loop
+        loop {
+            client.request("pause", json!({ "threadId": 1 }));
+            assert_eq!(client.event("stopped")["reason"], "pause");
+            let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+            let line = trace["stackFrames"][0]["line"].as_i64().unwrap();
+            if (8..=9).contains(&line) {
+                break;
+            }
+            assert!(line < 8, "{line}");
+            client.request("continue", json!({ "threadId": 1 }));
+        }
+        client.request("evaluate", json!({ "expression": "done = true" }));
+        client.request("continue", json!({ "threadId": 1 }));
+        assert_eq!(client.event("stopped")["reason"], "breakpoint");
+        client.request("evaluate", json!({ "expression": "n = 0" }));
+        client.request("continue", json!({ "threadId": 1 }));
+    });
+    assert_eq!(result, 3);
+}
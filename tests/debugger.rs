@@ -0,0 +1,162 @@
+// Breakpoints, stepping and inspecting frames, which needs the "debugger"
+// feature
+#![cfg(feature = "debugger")]
+use mochi_lua::{
+    debugger::{DebugHandler, Debugger, Resume, Stop, StopReason},
+    gc::{GcContext, MaybeSend},
+    runtime::{Runtime, Vm},
+    types::Value,
+};
+use std::sync::{Arc, Mutex};
+
+struct Handler<F>(F);
+
+impl<F> DebugHandler for Handler<F>
+where
+    F: MaybeSend + for<'gc> FnMut(&'gc GcContext, &mut Vm<'gc>, &Stop) -> Resume,
+{
+    fn stopped<'gc>(&mut self, gc: &'gc GcContext, vm: &mut Vm<'gc>, stop: &Stop) -> Resume {
+        (self.0)(gc, vm, stop)
+    }
+}
+
+const SOURCE: &str = r#"local scale = 10
+local function add(a, b)
+  local sum = a + b
+  return sum * scale
+end
+
+local total = 0
+for i = 1, 3 do
+  total = add(total, i)
+end
+result = total"#;
+
+fn run<F>(setup: impl FnOnce(&mut Debugger), handler: F) -> Value<'static>
+where
+    F: 'static + MaybeSend + for<'gc> FnMut(&'gc GcContext, &mut Vm<'gc>, &Stop) -> Resume,
+{
+    let mut runtime = Runtime::new();
+    runtime.heap().with(|gc, vm| {
+        let mut vm = vm.borrow_mut(gc);
+        vm.load_stdlib(gc);
+        setup(vm.attach_debugger(Handler(handler)));
+    });
+    runtime
+        .execute(|gc, vm| {
+            let closure = vm.borrow().load(gc, SOURCE, "@scripts/test.lua")?;
+            Ok(gc.allocate(closure).into())
+        })
+        .unwrap();
+    runtime.heap().with(|gc, vm| {
+        let result = vm
+            .borrow()
+            .globals()
+            .borrow()
+            .get_field(gc.allocate_string(b"result".as_slice()));
+        match result {
+            Value::Integer(i) => Value::Integer(i),
+            _ => Value::Nil,
+        }
+    })
+}
+
+#[test]
+fn breakpoints() {
+    let stops = Arc::new(Mutex::new(Vec::new()));
+    let recorded = stops.clone();
+    let result = run(
+        |debugger| {
+            debugger.add_breakpoint("test.lua", 4);
+            let id = debugger.add_breakpoint("other.lua", 3);
+            assert!(debugger.remove_breakpoint(id));
+        },
+        move |_, vm, stop| {
+            let frames = vm.debug_frames();
+            assert_eq!(frames.len(), 2);
+            assert_eq!(frames[0].name.as_deref(), Some("add"));
+            assert_eq!(frames[1].name.as_deref(), Some("main chunk"));
+            assert_eq!(frames[1].line, Some(9));
+            let locals: Vec<_> = vm
+                .debug_locals(0)
+                .into_iter()
+                .map(|(name, value)| format!("{name}={value:?}"))
+                .collect();
+            recorded
+                .lock()
+                .unwrap()
+                .push((stop.reason, stop.source.clone(), stop.line, locals));
+            Resume::Continue
+        },
+    );
+    assert_eq!(result, Value::Integer(1230));
+
+    let stops = stops.lock().unwrap();
+    assert_eq!(stops.len(), 3);
+    assert_eq!(stops[0].0, StopReason::Breakpoint(1));
+    assert_eq!(stops[0].1, "scripts/test.lua");
+    assert_eq!(stops[0].2, 4);
+    assert_eq!(stops[0].3, ["a=0", "b=1", "sum=1"]);
+    assert_eq!(stops[2].3, ["a=120", "b=3", "sum=123"]);
+}
+
+#[test]
+fn stepping() {
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let recorded = lines.clone();
+    let mut steps =
+        [Resume::Step; 4]
+            .into_iter()
+            .chain([Resume::Next, Resume::Step, Resume::Finish]);
+    run(
+        |debugger| debugger.set_resume(Resume::Step),
+        move |_, _, stop| {
+            recorded.lock().unwrap().push(stop.line);
+            steps.next().unwrap_or(Resume::Continue)
+        },
+    );
+    // Next steps over the first call of add, and the second is stepped into
+    // and finished, stopping at the third
+    assert_eq!(*lines.lock().unwrap(), [1, 2, 7, 8, 9, 9, 3, 9]);
+}
+
+#[test]
+fn evaluate_in_frame() {
+    let printed = Arc::new(Mutex::new(Vec::new()));
+    let recorded = printed.clone();
+    let mut stops = 0;
+    let result = run(
+        |debugger| {
+            debugger.add_breakpoint("scripts/test.lua", 4);
+        },
+        move |gc, vm, _| {
+            stops += 1;
+            let mut values = vm.debug_evaluate(gc, 0, "sum, scale, i, seen").unwrap();
+            values.extend(vm.debug_evaluate(gc, 1, "i").unwrap());
+            let values: Vec<_> = values.iter().map(|value| format!("{value:?}")).collect();
+            recorded.lock().unwrap().push(values.join(" "));
+
+            // Names that aren't locals or upvalues are globals
+            vm.debug_evaluate(gc, 0, "sum = sum + 1; seen = (seen or 0) + 1")
+                .unwrap();
+            // The upvalue is the local of the caller
+            match stops {
+                1 => vm.debug_evaluate(gc, 1, "scale = 2").unwrap(),
+                2 => vm.debug_evaluate(gc, 0, "scale = 1").unwrap(),
+                _ => Vec::new(),
+            };
+            assert_eq!(vm.debug_upvalues(0)[0].0, "scale");
+
+            let err = vm.debug_evaluate(gc, 0, "sum +").unwrap_err();
+            assert!(err.to_string().contains("syntax error"), "{err}");
+            let err = vm.debug_evaluate(gc, 0, "error('boom')").unwrap_err();
+            assert!(err.to_string().contains("boom"), "{err}");
+            Resume::Continue
+        },
+    );
+    assert_eq!(
+        *printed.lock().unwrap(),
+        ["1 10 nil nil 1", "6 2 nil 1 2", "10 1 nil 2 3"]
+    );
+    assert_eq!(result, Value::Integer(11));
+}
@@ -0,0 +1,94 @@
+// Copying values from one heap into another with Value::deep_clone_into
+use mochi_lua::lua::Lua;
+
+fn clone_global(from: &mut Lua, to: &mut Lua, name: &str) -> Result<(), String> {
+    from.with(|gc, vm| {
+        let value = vm
+            .globals()
+            .borrow()
+            .get_field(gc.allocate_string(name.as_bytes()));
+        to.with(|gc, vm| {
+            let value = value.deep_clone_into(gc).map_err(|err| err.to_string())?;
+            vm.globals()
+                .borrow_mut(gc)
+                .set_field(gc.allocate_string(name.as_bytes()), value);
+            Ok(())
+        })
+    })
+}
+
+#[test]
+fn tables_are_cloned_with_cycles_and_metatables() {
+    let mut scratch = Lua::new();
+    scratch
+        .load(
+            "local shared = {'shared'}
+            local mt = {__index = {fallback = true}, name = 'mt'}
+            config = setmetatable({
+                name = 'server',
+                port = 8080,
+                ratio = 0.5,
+                enabled = true,
+                list = {1, 2, 3, nil, 5},
+                a = shared,
+                b = shared,
+                [shared] = 'key',
+            }, mt)
+            config.self = config",
+        )
+        .exec()
+        .unwrap();
+    let mut lua = Lua::new();
+    clone_global(&mut scratch, &mut lua, "config").unwrap();
+    // The copy doesn't change with the original
+    scratch
+        .load("config.name = 'changed' config.a[1] = 'changed'")
+        .exec()
+        .unwrap();
+
+    let result: String = lua
+        .load(
+            "local c = config
+            assert(c.self == c and c.a == c.b and c[c.a] == 'key')
+            assert(getmetatable(c).name == 'mt' and c.fallback)
+            assert(c.list[5] == 5 and c.list[4] == nil and math.type(c.port) == 'integer')
+            return table.concat({c.name, c.port, c.ratio, tostring(c.enabled), c.a[1]}, ' ')",
+        )
+        .eval()
+        .unwrap();
+    assert_eq!(result, "server 8080 0.5 true shared");
+}
+
+#[test]
+fn functions_are_not_cloned() {
+    let mut scratch = Lua::new();
+    scratch
+        .load("config = {print = print, nested = {f = function() end}}")
+        .exec()
+        .unwrap();
+    let mut lua = Lua::new();
+    let err = clone_global(&mut scratch, &mut lua, "config").unwrap_err();
+    assert_eq!(err, "cannot clone a function value into another heap");
+
+    // Native functions don't belong to a heap
+    scratch.load("config.nested = nil").exec().unwrap();
+    clone_global(&mut scratch, &mut lua, "config").unwrap();
+    let same: bool = lua.load("return config.print == print").eval().unwrap();
+    assert!(same);
+}
+
+#[test]
+fn deep_structures_are_cloned() {
+    let mut scratch = Lua::new();
+    scratch
+        .load("list = nil for i = 1, 100000 do list = {next = list, i = i} end")
+        .exec()
+        .unwrap();
+    let mut lua = Lua::new();
+    clone_global(&mut scratch, &mut lua, "list").unwrap();
+    let sum: i64 = lua
+        .load("local sum = 0 while list do sum = sum + list.i list = list.next end return sum")
+        .eval()
+        .unwrap();
+    assert_eq!(sum, 5000050000);
+}
@@ -0,0 +1,268 @@
+// Error messages, tracebacks and error values as scripts and the host see them
+use mochi_lua::{lua::Lua, types::DetachedValue, Error};
+
+#[test]
+fn pcall_returns_error_objects() {
+    let mut lua = Lua::new();
+    let ok: bool = lua
+        .load(
+            r#"
+            local t = {code = 404}
+            local ok, err = pcall(error, t)
+            local ok2, err2 = xpcall(error, function(err) return err.code end, t)
+            local co = coroutine.create(function() error(t) end)
+            local ok3, err3 = coroutine.resume(co)
+            return not ok and err == t and not ok2 and err2 == 404 and not ok3 and err3 == t
+                and select(2, pcall(error, 42)) == 42 and select(2, pcall(error)) == nil
+            "#,
+        )
+        .eval()
+        .unwrap();
+    assert!(ok);
+}
+
+#[test]
+fn host_gets_error_objects() {
+    let mut lua = Lua::new();
+    let err = match lua.load("error({code = 404})").exec() {
+        Err(Error::Runtime(err)) => err,
+        result => panic!("unexpected {result:?}"),
+    };
+    assert_eq!(err.kind.to_string(), "(error object is a table value)");
+    assert_eq!(
+        lua.error_value(&err),
+        Some(DetachedValue::Table(vec![(
+            DetachedValue::String(b"code".to_vec()),
+            DetachedValue::Integer(404)
+        )]))
+    );
+}
+
+#[test]
+fn error_objects_are_released() {
+    let mut lua = Lua::new();
+    lua.load("for _ = 1, 100 do pcall(error, {}) end pcall(error, {})")
+        .exec()
+        .unwrap();
+    let count = lua.with(|gc, vm| {
+        let error_objects = vm
+            .registry()
+            .borrow()
+            .get_field(gc.allocate_string(b"_ERROROBJECTS".as_slice()));
+        match error_objects {
+            mochi_lua::types::Value::Table(t) => t.borrow().iter().count(),
+            _ => panic!("no error objects table"),
+        }
+    });
+    assert!(count <= 1, "{count} error objects left");
+}
+
+#[test]
+fn messages_have_positions() {
+    let mut lua = Lua::new();
+    let messages: Vec<String> = [
+        "local t = {}\nreturn t.x.y",
+        "local function f() error('lvl1') end\nf()",
+        "local function f() error('lvl2', 2) end\n\nf()",
+        "error('lvl0', 0)",
+        "\n\nassert(false)",
+        "string.rep()",
+    ]
+    .into_iter()
+    .map(|source| match lua.load(source).name("=t").exec() {
+        Err(Error::Runtime(err)) => err.kind.to_string(),
+        result => panic!("unexpected {result:?}"),
+    })
+    .collect();
+    assert_eq!(
+        messages,
+        [
+            "t:2: attempt to index a nil value (field 'x')",
+            "t:1: lvl1",
+            "t:3: lvl2",
+            "lvl0",
+            "t:3: assertion failed!",
+            "t:1: bad argument #1 (string expected, got no value)",
+        ]
+    );
+}
+
+#[test]
+fn type_errors_name_variables() {
+    let mut lua = Lua::new();
+    let messages: Vec<String> = [
+        "return socket.connect",
+        "local cfg\nreturn cfg.port",
+        "local cfg = {}\nreturn cfg.server.port",
+        "local a\nreturn 1 + a",
+        "local a, b = 1\nreturn a .. b",
+        "local up\nreturn (function() return up[1] end)()",
+        "undefined()",
+        "local a = {}\na:m()",
+        "return (1)()",
+    ]
+    .into_iter()
+    .map(|source| match lua.load(source).name("=t").exec() {
+        Err(Error::Runtime(err)) => err.kind.to_string(),
+        result => panic!("unexpected {result:?}"),
+    })
+    .collect();
+    assert_eq!(
+        messages,
+        [
+            "t:1: attempt to index a nil value (global 'socket')",
+            "t:2: attempt to index a nil value (local 'cfg')",
+            "t:2: attempt to index a nil value (field 'server')",
+            "t:2: attempt to perform arithmetic on a nil value (local 'a')",
+            "t:2: attempt to concatenate a nil value (local 'b')",
+            "t:2: attempt to index a nil value (upvalue 'up')",
+            "t:1: attempt to call a nil value (global 'undefined')",
+            "t:2: attempt to call a nil value (method 'm')",
+            "t:1: attempt to call a number value",
+        ]
+    );
+}
+
+#[test]
+fn traceback_names_functions() {
+    let mut lua = Lua::new();
+    let source = "
+        local obj = {}
+        function obj:method() error('x') end
+        local function f() obj:method() end
+        function g() f() end
+        g()";
+    let err = match lua.load(source).name("=t").exec() {
+        Err(Error::Runtime(err)) => err,
+        result => panic!("unexpected {result:?}"),
+    };
+    let traceback: Vec<_> = err.traceback.iter().map(|f| f.to_string()).collect();
+    assert_eq!(
+        traceback[..5],
+        [
+            "[C]: in function 'error'",
+            "t:3: in method 'method'",
+            "t:4: in upvalue 'f'",
+            "t:5: in function 'g'",
+            "t:6: in main chunk",
+        ]
+    );
+}
+
+#[test]
+fn stack_overflow_is_catchable() {
+    let mut lua = Lua::new();
+    let message: String = lua
+        .load(
+            "local function f() return 1 + f() end
+            local ok, err = pcall(f)
+            assert(not ok)
+            return err",
+        )
+        .name("=t")
+        .eval()
+        .unwrap();
+    assert_eq!(message, "t:1: stack overflow");
+
+    // The stack is usable again after unwinding
+    let sum: i64 = lua
+        .load("local function f(n) if n == 0 then return 0 end return n + f(n - 1) end return f(1000)")
+        .eval()
+        .unwrap();
+    assert_eq!(sum, 500500);
+}
+
+#[test]
+fn pcall_inside_deep_recursion() {
+    let mut lua = Lua::new();
+    lua.load(
+        "depth = 0
+        local function f()
+            depth = depth + 1
+            local ok, err = pcall(f)
+            if not ok then message = err end
+        end
+        f()",
+    )
+    .exec()
+    .unwrap();
+    let depth: i64 = lua.globals().get("depth").unwrap();
+    let message: String = lua.globals().get("message").unwrap();
+    assert!(depth > 1000);
+    assert!(message.ends_with("stack overflow"), "{message}");
+}
+
+#[test]
+fn c_call_limit() {
+    let mut lua = Lua::new();
+    lua.with(|_, vm| vm.set_c_call_limit(10));
+    lua.load(
+        "depth = 0
+        local function f()
+            depth = depth + 1
+            coroutine.wrap(f)()
+        end
+        message = select(2, pcall(f))",
+    )
+    .exec()
+    .unwrap();
+    let depth: i64 = lua.globals().get("depth").unwrap();
+    let message: String = lua.globals().get("message").unwrap();
+    assert_eq!(depth, 10);
+    assert_eq!(message, "C stack overflow");
+}
+
+#[test]
+fn deep_traceback_is_truncated() {
+    let mut lua = Lua::new();
+    match lua
+        .load("local function f(n) if n == 0 then error('x') end f(n - 1) end f(100)")
+        .exec()
+    {
+        Err(Error::Runtime(err)) => {
+            assert_eq!(err.traceback.len(), 22);
+            assert_eq!(err.traceback[10].to_string(), "...\t(skipping 83 levels)");
+        }
+        result => panic!("unexpected {result:?}"),
+    }
+}
+
+#[test]
+fn error_levels_prefix_positions() {
+    let mut lua = Lua::new();
+    let messages = lua
+        .load(
+            "local function f(level) error('boom', level) end
+            local function g(level)
+                f(level)
+            end
+            local function message(level) return select(2, pcall(g, level)) end
+            local t = {}
+            return message(1), message(2), message(3), message(0), message(100),
+                select(2, pcall(error, t, 1)) == t",
+        )
+        .name("=chunk")
+        .eval_multi()
+        .unwrap();
+    let string = |s: &str| DetachedValue::String(s.as_bytes().to_vec());
+    assert_eq!(
+        messages,
+        [
+            string("chunk:1: boom"),
+            string("chunk:3: boom"),
+            // g was called by pcall, which has no position
+            string("boom"),
+            string("boom"),
+            string("boom"),
+            DetachedValue::Boolean(true),
+        ]
+    );
+
+    // Uncaught errors keep the position they were raised with
+    let err = lua
+        .load("local function f() error('up', 2) end\nf()")
+        .name("=chunk")
+        .exec()
+        .unwrap_err();
+    assert!(err.to_string().starts_with("chunk:2: up"), "{err}");
+}
@@ -0,0 +1,105 @@
+// Events published through an EventBus by the host and by scripts, and
+// delivered to both when the runtime pumps them
+use mochi_lua::{events::EventBus, lua::Lua, types::DetachedValue};
+use std::sync::{Arc, Mutex};
+
+fn open(channels: &[&str]) -> (Lua, EventBus) {
+    let mut lua = Lua::new();
+    let bus = EventBus::new();
+    for channel in channels {
+        bus.register_channel(channel);
+    }
+    lua.with(|gc, vm| bus.open(gc, vm));
+    lua.load("got = ''").exec().unwrap();
+    (lua, bus)
+}
+
+fn got(lua: &mut Lua) -> String {
+    lua.load("return got").eval().unwrap()
+}
+
+#[test]
+fn subscribe_publish_unsubscribe() {
+    let (mut lua, bus) = open(&["tick"]);
+    let heard = Arc::new(Mutex::new(Vec::new()));
+    let host = heard.clone();
+    bus.listen("tick", move |event| {
+        host.lock().unwrap().push(event.args.clone())
+    })
+    .unwrap();
+
+    let id: i64 = lua
+        .load(
+            "local id = events.subscribe('tick', function(n, s) got = got .. n .. s .. ' ' end)
+            events.publish('tick', 1, 'a')
+            return id",
+        )
+        .eval()
+        .unwrap();
+    bus.publish(
+        "tick",
+        vec![
+            DetachedValue::Integer(2),
+            DetachedValue::String(b"b".to_vec()),
+        ],
+    )
+    .unwrap();
+    // Nothing is delivered until the events are pumped
+    assert_eq!(got(&mut lua), "");
+    assert_eq!(bus.num_queued(), 2);
+    assert_eq!(lua.runtime().pump_events(&bus).unwrap(), 2);
+    assert_eq!(got(&mut lua), "1a 2b ");
+    assert_eq!(heard.lock().unwrap().len(), 2);
+
+    let unsubscribed = lua
+        .load(format!(
+            "return events.unsubscribe({id}), events.unsubscribe({id})"
+        ))
+        .eval_multi()
+        .unwrap();
+    assert_eq!(
+        unsubscribed,
+        [DetachedValue::Boolean(true), DetachedValue::Boolean(false)]
+    );
+    bus.publish("tick", vec![DetachedValue::Integer(3)])
+        .unwrap();
+    assert_eq!(lua.runtime().pump_events(&bus).unwrap(), 1);
+    assert_eq!(got(&mut lua), "1a 2b ");
+    // Host listeners don't unsubscribe with scripts
+    assert_eq!(heard.lock().unwrap().len(), 3);
+}
+
+#[test]
+fn reopening_keeps_subscriptions() {
+    let (mut lua, bus) = open(&["tick"]);
+    lua.load("events.subscribe('tick', function(n) got = got .. n end)")
+        .exec()
+        .unwrap();
+    lua.with(|gc, vm| bus.open(gc, vm));
+    bus.publish("tick", vec![DetachedValue::Integer(1)])
+        .unwrap();
+    lua.runtime().pump_events(&bus).unwrap();
+    assert_eq!(got(&mut lua), "1");
+}
+
+#[test]
+fn unknown_channels_and_arguments_are_rejected() {
+    let (mut lua, bus) = open(&["tick"]);
+    for source in ["events.subscribe('tock', print)", "events.publish('tock')"] {
+        let err = lua.load(source).exec().unwrap_err().to_string();
+        assert!(err.contains("unknown event channel 'tock'"), "{err}");
+    }
+    assert!(bus.publish("tock", Vec::new()).is_err());
+    assert!(bus.listen("tock", |_| ()).is_err());
+
+    let err = lua
+        .load("events.publish('tick', 1, print)")
+        .exec()
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.contains("bad argument #3 (value cannot be sent as an event argument)"),
+        "{err}"
+    );
+    assert_eq!(bus.num_queued(), 0);
+}
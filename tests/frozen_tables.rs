@@ -0,0 +1,66 @@
+// Tables frozen with table.freeze or Table::freeze
+use mochi_lua::{lua::Lua, types::DetachedValue};
+
+fn error_of(lua: &mut Lua, source: &str) -> String {
+    let err = lua.load(source).exec().unwrap_err().to_string();
+    err.lines().next().unwrap().to_owned()
+}
+
+#[test]
+fn writes_to_frozen_tables_fail() {
+    let mut lua = Lua::new();
+    lua.load(
+        "t = table.freeze({1, 2, x = 1, inner = {}})
+        assert(table.isfrozen(t) and not table.isfrozen(t.inner))
+        t.inner.y = 1",
+    )
+    .exec()
+    .unwrap();
+
+    for source in [
+        "t.x = 2",
+        "t.new = 1",
+        "t[1] = 0",
+        "local k = 'x' t[k] = 2",
+        "t[3] = 3",
+        "rawset(t, 'x', 2)",
+        "table.insert(t, 3)",
+        "table.remove(t)",
+        "table.move({1}, 1, 1, 1, t)",
+        "setmetatable(t, {})",
+    ] {
+        let err = error_of(&mut lua, source);
+        assert!(
+            err.ends_with("attempt to modify a frozen table"),
+            "{source}: {err}"
+        );
+    }
+    let unchanged: String = lua
+        .load("return t[1] .. t[2] .. t.x .. #t .. tostring(t.new) .. t.inner.y")
+        .eval()
+        .unwrap();
+    assert_eq!(unchanged, "1212nil1");
+}
+
+#[test]
+fn frozen_environments_are_shared() {
+    let mut lua = Lua::new();
+    lua.with(|gc, vm| vm.globals().borrow_mut(gc).freeze());
+    let err = error_of(&mut lua, "print = nil");
+    assert!(err.ends_with("attempt to modify a frozen table"), "{err}");
+    // __newindex doesn't get around it
+    let err = error_of(
+        &mut lua,
+        "setmetatable(_ENV, {__newindex = function() end})",
+    );
+    assert!(err.ends_with("attempt to modify a frozen table"), "{err}");
+
+    let results = lua
+        .load("local x = 1 x = x + 1 return x, _ENV.print == print")
+        .eval_multi()
+        .unwrap();
+    assert_eq!(
+        results,
+        [DetachedValue::Integer(2), DetachedValue::Boolean(true)]
+    );
+}
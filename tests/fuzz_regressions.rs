@@ -0,0 +1,121 @@
+// Minimized inputs that crashed the fuzz targets under fuzz/, which should
+// now do what the fix of each one expects
+use mochi_lua::{
+    binary_chunk,
+    gc::GcHeap,
+    runtime::{self, Runtime},
+    sandbox::Sandbox,
+    CompileOptions,
+};
+use std::{collections::BTreeMap, path::Path};
+
+// The inputs for `target` by their file stem, which have to be the ones in
+// `expected`
+fn regressions<T>(target: &str, expected: &[(&str, T)]) -> BTreeMap<String, Vec<u8>> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fuzz/regressions")
+        .join(target);
+    let inputs: BTreeMap<_, _> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let stem = path.file_stem().unwrap().to_str().unwrap().to_owned();
+            (stem, std::fs::read(&path).unwrap())
+        })
+        .collect();
+    let mut names: Vec<_> = expected.iter().map(|(name, _)| *name).collect();
+    names.sort();
+    assert_eq!(inputs.keys().collect::<Vec<_>>(), names);
+    inputs
+}
+
+#[test]
+fn compile() {
+    // None for inputs that compile
+    let expected = [
+        ("assignment", None),
+        ("attribute", None),
+        ("empty", None),
+        ("goto", Some("no visible label 'continue' for goto")),
+        ("hex_exponent", None),
+        ("label", None),
+    ];
+    let inputs = regressions("compile", &expected);
+    for (name, error) in expected {
+        let source = &inputs[name];
+        let mut heap = GcHeap::new();
+        heap.with(|gc, _| {
+            for optimize in [false, true] {
+                let options = CompileOptions {
+                    optimize,
+                    ..Default::default()
+                };
+                let proto = match mochi_lua::load_with_options(gc, source, "=fuzz", &options) {
+                    Ok(proto) => proto,
+                    Err(err) => {
+                        assert_eq!(Some(err.to_string().as_str()), error, "{name}");
+                        continue;
+                    }
+                };
+                assert_eq!(error, None, "{name} compiled");
+                if let Err(err) = runtime::verify(&proto) {
+                    panic!("{name}: {err}");
+                }
+                let mut chunk = Vec::new();
+                binary_chunk::dump(&mut chunk, &proto).unwrap();
+                if let Err(err) = mochi_lua::load(gc, &chunk, "=fuzz") {
+                    panic!("{name}: {err}");
+                }
+            }
+        });
+    }
+}
+
+#[test]
+fn chunk() {
+    // The message of the error each input now fails with, either loading or
+    // running
+    let expected = [
+        (
+            "arith_string_constant",
+            "bad binary format (number constant expected at pc 2)",
+        ),
+        ("call_without_top", "stack top is not set"),
+        (
+            "concat_one_value",
+            "bad binary format (invalid operand at pc 4)",
+        ),
+        ("forloop_without_forprep", "'for' loop is not initialized"),
+        // Gets past NEWTABLE, whose size hint is capped
+        (
+            "newtable_size",
+            "attempt to call a nil value (global 'pa@rs')",
+        ),
+        // The upvalue above the stack reads as nil
+        (
+            "upvalue_above_call",
+            "attempt to get length of a nil value (upvalue 't')",
+        ),
+    ];
+    let inputs = regressions("chunk", &expected);
+    for (name, message) in expected {
+        let chunk = &inputs[name];
+        let mut runtime = Runtime::new();
+        let sandbox = Sandbox {
+            fuel: Some(100_000),
+            ..Default::default()
+        };
+        runtime
+            .heap()
+            .with(|gc, vm| sandbox.apply(gc, &mut vm.borrow_mut(gc)));
+        let err = runtime
+            .execute(|gc, vm| {
+                let proto = binary_chunk::load(gc, &mut &chunk[..])?;
+                Ok(gc.allocate(vm.borrow().load_proto(gc, proto)).into())
+            })
+            .unwrap_err();
+        let err = err.to_string();
+        let first_line = err.lines().next().unwrap();
+        assert!(first_line.ends_with(message), "{name}: {err}");
+    }
+}
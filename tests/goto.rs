@@ -0,0 +1,104 @@
+// goto and labels, and the upvalues of locals in the blocks they leave
+use mochi_lua::lua::Lua;
+
+fn eval(source: &str) -> String {
+    Lua::new().load(source).eval().unwrap()
+}
+
+fn compile_error(source: &str) -> String {
+    Lua::new().load(source).exec().unwrap_err().to_string()
+}
+
+#[test]
+fn goto_continue() {
+    let source = "
+        local s = ''
+        for i = 1, 6 do
+            if i % 2 == 0 then goto continue end
+            s = s .. i
+            ::continue::
+        end
+        local i = 0
+        while i < 6 do
+            i = i + 1
+            if i % 3 == 0 then goto continue end
+            s = s .. i
+            ::continue::
+        end
+        repeat
+            local done = true
+            if done then goto continue end
+            s = s .. 'never'
+            ::continue::
+        until done
+        return s";
+    assert_eq!(eval(source), "1351245");
+}
+
+#[test]
+fn backward_gotos_create_fresh_upvalues() {
+    let source = "
+        local fs, i = {}, 1
+        ::top::
+        local x = i * 10
+        fs[i] = function() return x end
+        i = i + 1
+        if i <= 3 then goto top end
+        return fs[1]() .. ' ' .. fs[2]() .. ' ' .. fs[3]()";
+    assert_eq!(eval(source), "10 20 30");
+}
+
+#[test]
+fn blocks_close_upvalues_on_exit() {
+    let source = "
+        local fs = {}
+        for i = 1, 3 do
+            local x = i
+            fs[i] = function() x = x + 1 return x end
+        end
+        do
+            local y = 'y'
+            fs[4] = function() return y end
+        end
+        local y = 'shadowed'
+        -- Each closure keeps the local of its own iteration
+        return fs[1]() .. fs[1]() .. fs[2]() .. fs[3]() .. fs[4]()";
+    assert_eq!(eval(source), "2334y");
+}
+
+#[test]
+fn jumping_into_the_scope_of_a_local() {
+    let err = compile_error(
+        "
+        goto skip
+        local x = 1
+        ::skip::
+        print(x)",
+    );
+    assert!(
+        err.contains("goto 'skip' jumps into the scope of local 'x'"),
+        "{err}"
+    );
+
+    // unless the label is the last statement of the block
+    let source = "
+        do
+            goto done
+            local x = 1
+            ::done::
+        end
+        return 'ok'";
+    assert_eq!(eval(source), "ok");
+}
+
+#[test]
+fn labels_are_unique_in_their_function() {
+    let err = compile_error("::a:: ::a::");
+    assert!(err.contains("label 'a' already defined"), "{err}");
+    let err = compile_error("::a:: do ::a:: end");
+    assert!(err.contains("label 'a' already defined"), "{err}");
+
+    // Labels of other functions don't clash
+    let source = "::a:: local f = function() ::a:: return 'ok' end return f()";
+    assert_eq!(eval(source), "ok");
+}
@@ -0,0 +1,120 @@
+// Code hot enough to be compiled should give the same results, and use the
+// same fuel, as when it's interpreted
+#![cfg(feature = "jit")]
+use mochi_lua::lua::Lua;
+
+// Returns what `source` returns, as a string, and the fuel it used
+fn run(source: &str, jit: bool) -> (String, u64) {
+    let mut lua = Lua::new();
+    lua.with(|_, vm| {
+        vm.set_jit_enabled(jit);
+        vm.set_fuel(u64::MAX >> 2);
+    });
+    let result = match lua.load(source).eval::<String>() {
+        Ok(result) => result,
+        Err(err) => format!("error: {err}"),
+    };
+    let used = lua.with(|_, vm| (u64::MAX >> 2) - vm.fuel().unwrap());
+    (result, used)
+}
+
+fn assert_same_with_jit(source: &str) {
+    let interpreted = run(source, false);
+    let compiled = run(source, true);
+    assert_eq!(compiled, interpreted, "{source}");
+}
+
+#[test]
+fn hot_loops() {
+    assert_same_with_jit(
+        "local s = 0
+        for i = 1, 5000 do s = s + i * 3 % 7 end
+        local x = 0.0
+        for i = 10, 0, -0.25 do x = x + i end
+        local n, steps = 27, 0
+        while n ~= 1 do
+            if n % 2 == 0 then n = n // 2 else n = 3 * n + 1 end
+            steps = steps + 1
+        end
+        local t = {}
+        for i = 1, 3000 do t[i] = i & 0xff ~ 0x0f end
+        return s .. ' ' .. x .. ' ' .. steps .. ' ' .. t[3000]",
+    );
+}
+
+#[test]
+fn calls() {
+    assert_same_with_jit(
+        "local function add(a, b) return a + b end
+        local function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end
+        local s = 0
+        for i = 1, 3000 do s = add(s, i) end
+        return s .. ' ' .. fib(20)",
+    );
+}
+
+#[test]
+fn arithmetic_edge_cases() {
+    assert_same_with_jit(
+        "local out = {}
+        local values = {0, -0.0, 1, -1, 7, -7, 2.5, -2.5, 1/0, -1/0, 0/0,
+            math.maxinteger, math.mininteger}
+        for _ = 1, 200 do
+            out = {}
+            for i = 1, #values do
+                for j = 1, #values do
+                    local a, b = values[i], values[j]
+                    out[#out + 1] = tostring(a + b) .. tostring(a - b) .. tostring(a * b)
+                        .. tostring(a / b) .. tostring(a < b) .. tostring(a <= b)
+                        .. tostring(a > b) .. tostring(a >= b) .. tostring(a == b)
+                        .. tostring(-a) .. tostring(not a)
+                    if math.type(b) == 'float' or b ~= 0 then
+                        out[#out + 1] = tostring(a % b) .. tostring(a // b)
+                    end
+                end
+            end
+        end
+        return table.concat(out, ',')",
+    );
+}
+
+#[test]
+fn types_changing_in_hot_code() {
+    assert_same_with_jit(
+        "local s = 0
+        for i = 1, 4000 do
+            if i == 2000 then s = s + 0.5 end
+            if i == 3000 then s = tostring(s) end
+            s = s + 1
+        end
+        local ok, err = pcall(function()
+            local x = 0
+            for i = 1, 3000 do
+                if i == 2500 then x = {} end
+                x = x + 1
+            end
+        end)
+        return tostring(s) .. ' ' .. tostring(ok) .. ' ' .. err",
+    );
+}
+
+#[test]
+fn fuel_runs_out_at_the_same_instruction() {
+    for fuel in [10_000, 123_457] {
+        let results: Vec<_> = [false, true]
+            .into_iter()
+            .map(|jit| {
+                let mut lua = Lua::new();
+                lua.with(|_, vm| {
+                    vm.set_jit_enabled(jit);
+                    vm.set_fuel(fuel);
+                });
+                lua.load("n = 0 while true do n = n + 1 end")
+                    .exec()
+                    .unwrap_err();
+                lua.globals().get::<_, i64>("n").unwrap()
+            })
+            .collect();
+        assert_eq!(results[0], results[1]);
+    }
+}
@@ -0,0 +1,166 @@
+// Chunks loaded from pieces: load with a reader function, and the loaders
+// that read from an io::Read
+use mochi_lua::{
+    binary_chunk,
+    gc::GcHeap,
+    lua::Lua,
+    types::{DetachedValue, Value},
+    CompileOptions,
+};
+use std::io::{self, Read};
+
+fn eval(source: &str) -> String {
+    Lua::new().load(source).eval().unwrap()
+}
+
+// A reader function returning the pieces of `chunk` `size` bytes at a time
+const READER: &str = "
+    local function reader(chunk, size)
+        local i = 1
+        return function()
+            local piece = chunk:sub(i, i + size - 1)
+            i = i + size
+            return piece
+        end
+    end";
+
+#[test]
+fn load_reads_pieces_until_nil_or_empty() {
+    for size in [1, 3, 100] {
+        let source = format!(
+            "{READER}
+            local f = load(reader('local a = ... return a .. \"!\"', {size}))
+            return f('hi')"
+        );
+        assert_eq!(eval(&source), "hi!", "{size}");
+    }
+
+    let source = "
+        local pieces = {'return ', 1, 0, ' + 2.5', nil}
+        local i = 0
+        local f = load(function() i = i + 1 return pieces[i] end)
+        return tostring(f())";
+    assert_eq!(eval(source), "12.5");
+    // A reader with nothing to read loads an empty chunk
+    assert_eq!(eval("return type(load(function() end))"), "function");
+}
+
+#[test]
+fn load_reads_binary_chunks_and_uses_the_environment() {
+    let source = format!(
+        "{READER}
+        local chunk = string.dump(function() return x end)
+        local f = load(reader(chunk, 2), 'dumped', 'b', {{x = 'from env'}})
+        return f()"
+    );
+    assert_eq!(eval(&source), "from env");
+
+    let source = format!(
+        "{READER}
+        local chunk = string.dump(function() end)
+        return select(2, load(reader(chunk, 2), 'dumped', 't'))"
+    );
+    assert_eq!(
+        eval(&source),
+        "attempt to load a binary chunk (mode is 't')"
+    );
+}
+
+#[test]
+fn load_fails_on_reader_errors() {
+    let result = |source: &str| eval(&format!("{READER} return select(2, {source})"));
+    assert_eq!(
+        result("load(function() return {} end)"),
+        "reader function must return a string"
+    );
+    let err = result("load(function() error('no more') end)");
+    assert!(err.ends_with("no more"), "{err}");
+    // Syntax errors are reported with the chunk name
+    let err = result("load(reader('return +', 2))");
+    assert!(err.starts_with("(load):1:"), "{err}");
+    let err = result("load(reader('return +', 2), '=pieces')");
+    assert!(err.starts_with("pieces:1:"), "{err}");
+}
+
+// Hands out `chunk` a few bytes per read, counting what was read
+struct Trickle<'a> {
+    chunk: &'a [u8],
+    read: usize,
+}
+
+impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(3).min(self.chunk.len() - self.read);
+        buf[..len].copy_from_slice(&self.chunk[self.read..self.read + len]);
+        self.read += len;
+        Ok(len)
+    }
+}
+
+// Loads `chunk` with load_from_reader and runs it, returning its results and
+// how many bytes were read
+fn run_from_reader(
+    chunk: &[u8],
+    options: &CompileOptions,
+) -> (Result<Vec<DetachedValue>, String>, usize) {
+    let mut lua = Lua::new();
+    let mut reader = Trickle { chunk, read: 0 };
+    let loaded = lua.with(|gc, vm| {
+        let proto = mochi_lua::load_from_reader_with_options(gc, &mut reader, "=reader", options)
+            .map_err(|err| err.to_string())?;
+        let closure = gc.allocate(vm.load_proto(gc, proto));
+        vm.globals()
+            .borrow_mut(gc)
+            .set_field(gc.allocate_string(&b"chunk"[..]), Value::from(closure));
+        Ok(())
+    });
+    let result = loaded.and_then(|()| {
+        lua.load("return chunk()")
+            .eval_multi()
+            .map_err(|err| err.to_string().lines().next().unwrap().to_owned())
+    });
+    (result, reader.read)
+}
+
+#[test]
+fn load_from_reader_reads_text_and_binary_chunks() {
+    let source = b"local t = {}\nfor i = 1, 10 do t[i] = i * i end\nreturn t[10], 'done'";
+    let expected = vec![
+        DetachedValue::Integer(100),
+        DetachedValue::String(b"done".to_vec()),
+    ];
+    let options = CompileOptions::default();
+    let (result, read) = run_from_reader(source, &options);
+    assert_eq!(result.unwrap(), expected);
+    assert_eq!(read, source.len());
+
+    let binary = GcHeap::new().with(|gc, _| {
+        let proto = mochi_lua::load(gc, source, "=chunk").unwrap();
+        let mut chunk = Vec::new();
+        binary_chunk::dump(&mut chunk, &proto).unwrap();
+        chunk
+    });
+    assert_eq!(run_from_reader(&binary, &options).0.unwrap(), expected);
+
+    // Options apply as with the other loaders
+    let failing = b"local x\nreturn x.y";
+    let (result, _) = run_from_reader(failing, &options);
+    assert!(result.unwrap_err().starts_with("reader:2:"));
+    let stripped = CompileOptions {
+        strip: true,
+        ..Default::default()
+    };
+    let (result, _) = run_from_reader(failing, &stripped);
+    assert!(!result.unwrap_err().contains("reader:2:"));
+}
+
+#[test]
+fn load_from_reader_stops_at_syntax_errors() {
+    // The chunk is compiled as it's read, so nothing past the error is read
+    let mut chunk = b"local a = 1\nreturn a + )\n".to_vec();
+    chunk.resize(1 << 20, b' ');
+    let (result, read) = run_from_reader(&chunk, &CompileOptions::default());
+    let err = result.unwrap_err();
+    assert!(err.starts_with("reader:"), "{err}");
+    assert!(read < 1 << 16, "read {read} bytes");
+}
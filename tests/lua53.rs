@@ -0,0 +1,78 @@
+// Chunks precompiled by luac 5.3, which are translated as they're loaded.
+// Each lua53/*.luac holds the code luac 5.3 generates for the .lua file next
+// to it, stripped of debug info except for debug_info.luac, and must run to
+// the same results as the source does.
+use mochi_lua::{lua::Lua, types::DetachedValue};
+use std::path::Path;
+
+fn run(name: &str, mode: &str) -> Vec<DetachedValue> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lua53");
+    let extension = if mode == "b" { "luac" } else { "lua" };
+    let chunk = std::fs::read(dir.join(name).with_extension(extension)).unwrap();
+    let mut lua = Lua::new();
+    lua.globals().set("chunk", DetachedValue::String(chunk));
+    lua.load(format!(
+        "return assert(load(chunk, '@{name}.lua', '{mode}'))('p', 'q')"
+    ))
+    .eval_multi()
+    .unwrap()
+}
+
+fn assert_results(name: &str, expected: &[DetachedValue]) {
+    assert_eq!(run(name, "b"), expected, "{name}.luac");
+    assert_eq!(run(name, "t"), expected, "{name}.lua");
+}
+
+fn string(s: &str) -> DetachedValue {
+    DetachedValue::String(s.as_bytes().to_vec())
+}
+
+#[test]
+fn jumps() {
+    assert_results("jumps", &[string("-1-1011"), DetachedValue::Integer(3)]);
+}
+
+#[test]
+fn for_loops() {
+    assert_results(
+        "for_loops",
+        &[DetachedValue::Integer(997), DetachedValue::Integer(11)],
+    );
+}
+
+#[test]
+fn closures_and_upvalues() {
+    assert_results(
+        "closures",
+        &[DetachedValue::Integer(16), DetachedValue::Integer(114)],
+    );
+}
+
+#[test]
+fn varargs() {
+    use DetachedValue::{Integer, Nil};
+    assert_results(
+        "varargs",
+        &[
+            Integer(2),
+            Integer(3),
+            Integer(1),
+            Integer(2),
+            string("p"),
+            string("q"),
+            Nil,
+            Integer(3),
+        ],
+    );
+}
+
+#[test]
+fn lines_and_locals() {
+    assert_results(
+        "debug_info",
+        &[
+            string("debug_info.lua:3: attempt to index a nil value (field 'field')"),
+            string("debug_info.lua:2: attempt to get length of a nil value (local 't')"),
+        ],
+    );
+}
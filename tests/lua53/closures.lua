@@ -0,0 +1,14 @@
+local function counter(start)
+  local n = start
+  return function(step)
+    n = n + step
+    return n
+  end
+end
+local c = counter(10)
+c(1)
+local adders = {}
+for i = 1, 3 do
+  adders[i] = function(x) return x + i end
+end
+return c(5), adders[1](10) + adders[3](100)
@@ -0,0 +1,7 @@
+local function check(t)
+  local n = #t
+  return t.field.x + n
+end
+local _, err1 = pcall(check, {})
+local _, err2 = pcall(check)
+return err1, err2
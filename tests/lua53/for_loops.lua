@@ -0,0 +1,16 @@
+local sum = 0
+for i = 1, 10 do
+  sum = sum + i
+end
+for i = 10, 1, -3 do
+  sum = sum * 2 + i
+end
+local keys = 0
+for k, v in pairs({a = 1, b = 2, c = 3}) do
+  keys = keys + v
+end
+for _, v in ipairs({5, 6, 7}) do
+  if v == 6 then break end
+  keys = keys + v
+end
+return sum, keys
@@ -0,0 +1,19 @@
+local function sign(n)
+  if n < 0 then
+    return -1
+  elseif n == 0 then
+    return 0
+  end
+  return 1
+end
+local s = ""
+local i = -2
+while i <= 2 do
+  s = s .. sign(i)
+  i = i + 1
+end
+local j = 0
+repeat
+  j = j + 1
+until j >= 3 or s == ""
+return s, j
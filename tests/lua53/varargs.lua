@@ -0,0 +1,14 @@
+local function count(...)
+  return select("#", ...)
+end
+local function pack(...)
+  local t = {...}
+  return t, ...
+end
+local function first(a, ...)
+  local b, c = ...
+  return a, b, c
+end
+local t, x, y = pack(1, 2, 3)
+local a, b, c = first(...)
+return count(nil, nil), #t, x, y, a, b, c, count(pack(4, 5))
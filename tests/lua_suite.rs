@@ -0,0 +1,160 @@
+// The test suite of the reference interpreter, which needs the "lua-tests"
+// feature. It isn't downloaded: extract lua-5.4.6-tests.tar.gz from
+// https://www.lua.org/tests/ and point MOCHI_LUA_TESTS at it, or the test is
+// skipped. Files that are known to fail are listed in
+// lua_suite/expected_failures.txt, along with the hash of the suite they
+// were found with; running with MOCHI_LUA_TESTS_BLESS=1 rewrites the list
+// from the results.
+#![cfg(feature = "lua-tests")]
+use mochi_lua::{remote::RemoteModules, runtime::Runtime};
+use std::{
+    collections::BTreeSet,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+};
+
+const SUITE: &str = "lua-5.4.6-tests";
+
+// The files all.lua runs, in its order
+const FILES: &[&str] = &[
+    "gc.lua",
+    "db.lua",
+    "calls.lua",
+    "strings.lua",
+    "literals.lua",
+    "tpack.lua",
+    "attrib.lua",
+    "gengc.lua",
+    "locals.lua",
+    "constructs.lua",
+    "code.lua",
+    "big.lua",
+    "cstack.lua",
+    "nextvar.lua",
+    "pm.lua",
+    "utf8.lua",
+    "api.lua",
+    "events.lua",
+    "vararg.lua",
+    "closure.lua",
+    "coroutine.lua",
+    "goto.lua",
+    "errors.lua",
+    "math.lua",
+    "sort.lua",
+    "bitwise.lua",
+    "verybig.lua",
+    "files.lua",
+];
+
+// Enough for the slowest files, while stopping ones that loop forever
+const FUEL: u64 = 2_000_000_000;
+
+fn suite_dir() -> Option<PathBuf> {
+    std::env::var_os("MOCHI_LUA_TESTS").map(PathBuf::from)
+}
+
+// Hash of the files that are run, which pins the list of expected failures
+// to the suite it was made with
+fn suite_integrity(dir: &Path) -> Result<String, String> {
+    let mut contents = Vec::new();
+    for file in FILES {
+        let path = dir.join(file);
+        contents.extend_from_slice(file.as_bytes());
+        contents.push(0);
+        contents.extend(std::fs::read(&path).map_err(|err| format!("{path:?}: {err}"))?);
+    }
+    Ok(RemoteModules::integrity(&contents))
+}
+
+// Runs a file like all.lua does, with the tests that aren't portable or take
+// long turned off
+fn run(file: &str) -> Result<(), String> {
+    let mut runtime = Runtime::new();
+    runtime.heap().with(|gc, vm| {
+        let mut vm = vm.borrow_mut(gc);
+        vm.load_stdlib(gc);
+        vm.set_fuel(FUEL);
+    });
+    runtime
+        .execute(|gc, vm| {
+            let vm = vm.borrow();
+            for flag in ["_port", "_soft", "_nomsg"] {
+                vm.globals()
+                    .borrow_mut(gc)
+                    .set_field(gc.allocate_string(flag.as_bytes()), true);
+            }
+            Ok(gc.allocate(vm.load_file(gc, file)?).into())
+        })
+        .map_err(|err| err.to_string())
+}
+
+#[test]
+fn lua_suite() {
+    let Some(dir) = suite_dir() else {
+        eprintln!(
+            "skipped: set MOCHI_LUA_TESTS to the extracted {SUITE} from https://www.lua.org/tests/"
+        );
+        return;
+    };
+    let integrity = suite_integrity(&dir).unwrap();
+
+    let expected_path =
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lua_suite/expected_failures.txt");
+    let expected = std::fs::read_to_string(&expected_path).unwrap();
+    let pinned = expected
+        .lines()
+        .find_map(|line| line.strip_prefix("# suite "))
+        .map(str::to_owned);
+    let expected: BTreeSet<_> = expected
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+
+    // The files load others relative to the suite
+    std::env::set_current_dir(&dir).unwrap();
+    panic::set_hook(Box::new(|_| {}));
+    let mut failures = BTreeSet::new();
+    for file in FILES {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| run(file)))
+            .unwrap_or_else(|_| Err("panicked".to_owned()));
+        if let Err(err) = result {
+            let first_line = err.lines().next().unwrap_or_default();
+            eprintln!("{file}: {first_line}");
+            failures.insert(*file);
+        }
+    }
+    let _ = panic::take_hook();
+    eprintln!(
+        "{SUITE}: {} of {} files pass",
+        FILES.len() - failures.len(),
+        FILES.len()
+    );
+
+    if std::env::var_os("MOCHI_LUA_TESTS_BLESS").is_some() {
+        let mut list = format!(
+            "# Files of the reference test suite that fail under mochi, one per line\n\
+             # suite {integrity}\n"
+        );
+        for file in &failures {
+            list.push_str(file);
+            list.push('\n');
+        }
+        std::fs::write(&expected_path, list).unwrap();
+        return;
+    }
+    match pinned {
+        Some(pinned) => assert_eq!(
+            pinned, integrity,
+            "{dir:?} isn't the suite {expected_path:?} was made with"
+        ),
+        None => {
+            panic!("no results recorded in {expected_path:?}; run with MOCHI_LUA_TESTS_BLESS=1")
+        }
+    }
+    for file in expected.difference(&failures) {
+        eprintln!("{file} passes now, and can be removed from {expected_path:?}");
+    }
+    let unexpected: Vec<_> = failures.difference(&expected).collect();
+    assert!(unexpected.is_empty(), "unexpected failures: {unexpected:?}");
+}
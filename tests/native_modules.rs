@@ -0,0 +1,47 @@
+// Native modules exported with native_module!, and where require looks for
+// them
+use mochi_lua::{
+    gc::GcContext,
+    lua::Lua,
+    runtime::{Action, ErrorKind, Vm},
+    types::{NativeFunction, NativeModule, Value},
+};
+
+fn open_answer<'gc>(
+    _: &'gc GcContext,
+    _: &mut Vm<'gc>,
+    _: Vec<Value<'gc>>,
+) -> Result<Action<'gc>, ErrorKind> {
+    Ok(Action::Return(vec![42.into()]))
+}
+
+mochi_lua::native_module!("answer", open_answer);
+
+extern "C" {
+    #[link_name = "mochi_open_answer"]
+    fn mochi_open_answer() -> NativeModule;
+}
+
+#[test]
+fn native_module_exports_a_c_function() {
+    let module = unsafe { mochi_open_answer() };
+    let function = module.open_function().unwrap();
+    assert_eq!(
+        NativeFunction::new(function),
+        NativeFunction::new(open_answer)
+    );
+}
+
+#[test]
+fn cpath_ignores_the_reference_implementation() {
+    std::env::set_var("LUA_CPATH", "/usr/lib/lua/5.4/?.so");
+    let mut lua = Lua::new();
+    let cpath: String = lua.load("return package.cpath").eval().unwrap();
+    assert!(!cpath.contains("lib/lua/"), "{cpath}");
+    assert!(cpath.ends_with("?.so") || cpath.ends_with("?.dll"));
+
+    std::env::set_var("MOCHI_CPATH", "mods/?.so;;");
+    let mut lua = Lua::new();
+    let cpath: String = lua.load("return package.cpath").eval().unwrap();
+    assert!(cpath.starts_with("mods/?.so;"), "{cpath}");
+}
@@ -0,0 +1,45 @@
+// The crate without the "std" feature, run with
+// `cargo test --no-default-features --test no_std`
+#![cfg(not(feature = "std"))]
+use mochi_lua::runtime::Runtime;
+
+fn run(source: &'static str) -> Result<(), String> {
+    let mut runtime = Runtime::new();
+    runtime
+        .execute(|gc, vm| {
+            let mut vm = vm.borrow_mut(gc);
+            vm.load_stdlib(gc);
+            let closure = vm.load(gc, source, "=test")?;
+            Ok(gc.allocate(closure).into())
+        })
+        .map_err(|err| err.to_string())
+}
+
+#[test]
+fn reduced_standard_library() {
+    run(r##"
+assert(io == nil and os == nil and package == nil and require == nil)
+assert(print == nil and dofile == nil and loadfile == nil)
+assert(string.format("%5.2f|%d|%q", math.pi, 42, "a\n") == " 3.14|42|\"a\\\n\"")
+assert(("abc"):rep(2, "-"):upper() == "ABC-ABC")
+assert(math.sqrt(16) == 4.0 and math.floor(-1.5) == -2 and math.type(1 // 1) == "integer")
+assert(math.abs(math.sin(math.pi / 2) - 1) < 1e-12 and math.log(8, 2) == 3.0)
+local t = {1, 2, 4}
+table.insert(t, 3, 3)
+assert(table.concat(t, ",") == "1,2,3,4" and select("#", table.unpack(t)) == 4)
+assert(utf8.char(0x3042) == "\u{3042}")
+local co = coroutine.wrap(function(a) return coroutine.yield(a + 1) * 2 end)
+assert(co(1) == 2 and co(5) == 10)
+assert(select("#", pcall(error, {})) == 2)
+assert(load("return 1 + 1")() == 2)"##)
+    .unwrap();
+}
+
+#[test]
+fn errors_without_std() {
+    let err = run("local t = nil; return t.x").unwrap_err();
+    assert!(err.contains("attempt to index a nil value"), "{err}");
+
+    let err = run("return 1 +").unwrap_err();
+    assert!(err.contains("unexpected symbol near <eof>"), "{err}");
+}
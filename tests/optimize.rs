@@ -0,0 +1,138 @@
+// Code of optimized functions, as listed by the disassembler
+use mochi_lua::{
+    disasm::disassemble,
+    gc::GcHeap,
+    runtime::{self, Instruction, OpCode},
+    types::{LuaClosureProto, Value},
+    CompileOptions,
+};
+
+// The instructions of `proto`, without their pc
+fn instructions(proto: &LuaClosureProto) -> Vec<String> {
+    let mut listing = Vec::new();
+    disassemble(&mut listing, proto, false).unwrap();
+    String::from_utf8(listing)
+        .unwrap()
+        .lines()
+        .filter_map(|line| line.strip_prefix('\t'))
+        .map(|line| {
+            let (_, insn) = line.split_once('\t').unwrap();
+            let (op, operands) = insn.split_once('\t').unwrap();
+            format!("{} {operands}", op.trim_end())
+                .trim_end()
+                .to_owned()
+        })
+        .collect()
+}
+
+fn compile(source: &str, optimize: bool) -> Vec<String> {
+    GcHeap::new().with(|gc, _| {
+        let options = CompileOptions {
+            optimize,
+            ..Default::default()
+        };
+        let proto = mochi_lua::load_with_options(gc, source, "=chunk", &options).unwrap();
+        instructions(&proto)
+    })
+}
+
+fn optimized(source: &str) -> Vec<String> {
+    compile(source, true)
+}
+
+#[test]
+fn arithmetic_on_constants_is_folded() {
+    assert_eq!(
+        optimized("local x = 3 * 5 + 2 return x"),
+        ["VARARGPREP 0", "LOADI 0 17", "RETURN 0 2 1"]
+    );
+    // Results that don't fit in LOADI are added to the constants
+    assert_eq!(
+        optimized("local x = 3 * 100000 + 0.5 return x"),
+        ["VARARGPREP 0", "LOADK 0 2", "RETURN 0 2 1"]
+    );
+    assert_eq!(
+        optimized("local x = -2.5 return x"),
+        ["VARARGPREP 0", "LOADK 0 1", "RETURN 0 2 1"]
+    );
+}
+
+#[test]
+fn errors_nan_and_zero_floats_are_not_folded() {
+    for source in [
+        "local x = 7 // 0 return x",
+        "local x = 7 % 0 return x",
+        "local x = 0.5 - 0.5 return x",
+        "local x = 0.0 * 2 return x",
+    ] {
+        assert_eq!(optimized(source), compile(source, false), "{source}");
+    }
+    // Folding stops at the operation giving NaN
+    assert_eq!(
+        optimized("local x = 1 / 0 * 0 return x"),
+        [
+            "VARARGPREP 0",
+            "LOADK 0 1",
+            "MULK 0 0 0",
+            "MMBINK 0 0 8 0",
+            "RETURN 0 2 1"
+        ]
+    );
+}
+
+#[test]
+fn constant_loads_become_immediate() {
+    let load_k = |a: u32, bx: u32| Instruction(OpCode::LoadK as u32 | a << 7 | bx << 15);
+    let listing = GcHeap::new().with(|gc, _| {
+        let mut proto = mochi_lua::load(gc, "local a, b, c, d", "=chunk").unwrap();
+        proto.constants = [
+            Value::Integer(7),
+            Value::Number(-3.0),
+            Value::Number(0.5),
+            Value::Integer(100000),
+        ]
+        .into_iter()
+        .collect();
+        proto.code = [
+            load_k(0, 0),
+            load_k(1, 1),
+            load_k(2, 2),
+            load_k(3, 3),
+            Instruction(OpCode::Return0 as u32),
+        ]
+        .into();
+        runtime::optimize(gc, &mut proto);
+        instructions(&proto)
+    });
+    assert_eq!(
+        listing,
+        [
+            "LOADI 0 7",
+            "LOADF 1 -3",
+            "LOADK 2 2",
+            "LOADK 3 3",
+            "RETURN0"
+        ]
+    );
+}
+
+#[test]
+fn jump_chains_are_shortened() {
+    // break jumps to the loop's exit, which jumps back to the loop's start
+    let source = "while true do if f() then break end end";
+    let code = compile(source, false);
+    assert_eq!(code[4..7], ["JMP 1", "JMP 1", "JMP -6"]);
+    assert_eq!(optimized(source)[4..7], ["JMP -4", "JMP 1", "JMP -6"]);
+}
+
+#[test]
+fn overwritten_loadnils_are_removed() {
+    let source = "local a; local b = a; a = 1; local c; c = 2; return b, c";
+    let code = compile(source, false);
+    assert_eq!(code[1..3], ["LOADNIL 0 0", "MOVE 1 0"]);
+    assert_eq!(code[5..8], ["LOADNIL 2 0", "LOADI 3 2", "MOVE 2 3"]);
+    // The LOADNIL of a, which b reads, is kept
+    let mut expected = code.clone();
+    expected.remove(5);
+    assert_eq!(optimized(source), expected);
+}
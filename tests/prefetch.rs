@@ -0,0 +1,70 @@
+// Modules compiled ahead of require by prefetch_modules, which must load as
+// if require had compiled the file itself
+use mochi_lua::{chunk_cache::ChunkCache, lua::Lua, CompileOptions};
+use std::{fs, path::Path};
+
+// Error position and environment of the module
+const MODULE: &str = "local ok, err = pcall(function() error('boom') end)
+return err .. ' ' .. type(_ENV)";
+
+// Requires the module in `dir` once it's prefetched, and overwritten so that
+// only the prefetched chunk returns the result
+fn require(dir: &Path, options: CompileOptions, cache: Option<ChunkCache>) -> String {
+    let module = dir.join("m.lua");
+    fs::write(&module, MODULE).unwrap();
+    let mut lua = Lua::new();
+    lua.load(format!("package.path = [[{}/?.lua]]", dir.display()))
+        .exec()
+        .unwrap();
+    lua.with(|gc, vm| {
+        vm.set_compile_options(options);
+        vm.set_chunk_cache(cache);
+        vm.prefetch_modules(gc, ["m"]);
+    });
+    fs::write(&module, "error('not prefetched')").unwrap();
+    lua.load("return require('m')").eval().unwrap()
+}
+
+fn prefetches() -> bool {
+    // Prefetching does nothing on a single core
+    std::thread::available_parallelism().map_or(1, usize::from) > 1
+}
+
+#[test]
+fn prefetched_modules_use_the_compile_options() {
+    if !prefetches() {
+        return;
+    }
+    let dir = std::env::temp_dir().join(format!("mochi-prefetch-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let module = dir.join("m.lua");
+    let result = require(&dir, CompileOptions::default(), None);
+    assert_eq!(result, format!("{}:1: boom table", module.display()));
+
+    let options = CompileOptions {
+        strip: true,
+        env_name: b"E".to_vec(),
+        ..Default::default()
+    };
+    assert_eq!(require(&dir, options, None), "boom nil");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn prefetched_modules_are_cached() {
+    if !prefetches() {
+        return;
+    }
+    let dir = std::env::temp_dir().join(format!("mochi-prefetch-cache-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let cache = ChunkCache::new(dir.join("cache"));
+
+    let module = dir.join("m.lua");
+    let result = require(&dir, CompileOptions::default(), Some(cache.clone()));
+    assert_eq!(result, format!("{}:1: boom table", module.display()));
+    assert_eq!(fs::read_dir(cache.dir()).unwrap().count(), 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
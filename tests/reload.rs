@@ -0,0 +1,93 @@
+// Reloading modules after their files change
+use mochi_lua::lua::Lua;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const COUNTER: &str = r#"
+local M = {}
+local count = 0
+function M.bump() count = count + 1 return count end
+function M.describe() return "count " .. count end
+M.old = true
+return M"#;
+
+fn module_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mochi-reload-{test}-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("counter.lua"), COUNTER).unwrap();
+    dir
+}
+
+fn lua(dir: &Path) -> Lua {
+    let mut lua = Lua::new();
+    let path = dir.join("?.lua");
+    lua.load(format!("package.path = {:?}", path.to_str().unwrap()))
+        .exec()
+        .unwrap();
+    lua.load("counter = require 'counter' counter.bump() counter.bump()")
+        .exec()
+        .unwrap();
+    lua
+}
+
+fn eval(lua: &mut Lua, source: &str) -> String {
+    lua.load(source).eval().unwrap()
+}
+
+#[test]
+fn reload_replaces_the_module() {
+    let dir = module_dir("replace");
+    let mut lua = lua(&dir);
+    fs::write(
+        dir.join("counter.lua"),
+        COUNTER.replace("\"count \"", "\"bumped \""),
+    )
+    .unwrap();
+    lua.reload_module("counter").unwrap();
+
+    // The module starts over, and the old table is left as it was
+    assert_eq!(
+        eval(&mut lua, "return require('counter').describe()"),
+        "bumped 0"
+    );
+    assert_eq!(eval(&mut lua, "return counter.describe()"), "count 2");
+
+    // A module that fails to load leaves the previous one loaded
+    fs::write(dir.join("counter.lua"), "error('broken')").unwrap();
+    let err = lua.reload_module("counter").unwrap_err();
+    assert!(err.to_string().contains("broken"), "{err}");
+    assert_eq!(
+        eval(&mut lua, "return require('counter').describe()"),
+        "bumped 0"
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn patch_keeps_state_and_identity() {
+    let dir = module_dir("patch");
+    let mut lua = lua(&dir);
+    let patched = COUNTER
+        .replace("\"count \"", "\"bumped \"")
+        .replace("M.old = true", "M.new = true");
+    fs::write(dir.join("counter.lua"), patched).unwrap();
+    lua.patch_module("counter").unwrap();
+
+    assert_eq!(
+        eval(
+            &mut lua,
+            "assert(require('counter') == counter) counter.bump() return counter.describe()"
+        ),
+        "bumped 3"
+    );
+    assert_eq!(
+        eval(
+            &mut lua,
+            "return tostring(counter.old) .. ' ' .. tostring(counter.new)"
+        ),
+        "nil true"
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,148 @@
+// Modules fetched by require through RemoteModules, which must match the
+// integrity hash they're pinned to whether they're fetched or cached
+use mochi_lua::{lua::Lua, remote::RemoteModules};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+const BASE_URL: &str = "https://modules.example/lua/";
+const URL: &str = "https://modules.example/lua/greet.lua";
+const MODULE: &str = "return 'hello from ' .. ...";
+
+// Serves `contents` at URL, counting the requests made for it
+fn serve(contents: &'static str, fetches: &Arc<AtomicUsize>) -> RemoteModules {
+    let fetches = fetches.clone();
+    let mut remote = RemoteModules::new(move |url| {
+        if url != URL {
+            return Ok(None);
+        }
+        fetches.fetch_add(1, Ordering::SeqCst);
+        Ok(Some(contents.as_bytes().to_vec()))
+    });
+    remote.add_base_url(BASE_URL).unwrap();
+    remote
+}
+
+fn require(remote: RemoteModules) -> Result<String, String> {
+    let mut lua = Lua::new();
+    lua.with(|_, vm| vm.set_remote_modules(Some(remote)));
+    lua.load("return require('greet')")
+        .eval::<String>()
+        .map_err(|err| err.to_string())
+}
+
+#[test]
+fn integrity_known_answers() {
+    let cases = [
+        ("", "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="),
+        ("abc", "ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0="),
+        (
+            "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+            "JI1qYdIGOLjlwCaTDD5gOaM85Flk/yFn9uzt1BnbBsE=",
+        ),
+    ];
+    for (input, digest) in cases {
+        assert_eq!(
+            RemoteModules::integrity(input.as_bytes()),
+            format!("sha256-{digest}"),
+            "{input:?}"
+        );
+    }
+
+    // Messages that end at, and just past, where the length has to go in a
+    // block of its own, and one that spans many blocks
+    let cases = [
+        (55, "n0OQ+NMMLdkuyfCVtl4rmumwqSWlJY4kHJ8ekQ9zQxg="),
+        (56, "s1Q5pKxvCUi21vnjxq8PX1kM4g8b3nCQ73lwaG7Gc4o="),
+        (64, "/+BU/nrgy23GXDr5th1SCfQ5hR20PQulmXM33xVGaOs="),
+        (1_000_000, "zcduXJkU+5KBocfihNc+Z/GAmkiklyAOBG05zMcRLNA="),
+    ];
+    for (len, digest) in cases {
+        assert_eq!(
+            RemoteModules::integrity(&vec![b'a'; len]),
+            format!("sha256-{digest}"),
+            "{len} bytes"
+        );
+    }
+}
+
+#[test]
+fn pinned_modules_are_loaded() {
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let mut remote = serve(MODULE, &fetches);
+    remote
+        .pin(URL, &RemoteModules::integrity(MODULE.as_bytes()))
+        .unwrap();
+    assert_eq!(require(remote).unwrap(), "hello from greet");
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn pin_mismatches_are_rejected() {
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let mut remote = serve("return 'tampered'", &fetches);
+    remote
+        .pin(URL, &RemoteModules::integrity(MODULE.as_bytes()))
+        .unwrap();
+    let err = require(remote).unwrap_err();
+    assert!(
+        err.contains(&format!("error loading module 'greet' from '{URL}'")),
+        "{err}"
+    );
+    assert!(
+        err.contains(&format!(
+            "integrity check failed (expected {}, got {})",
+            RemoteModules::integrity(MODULE.as_bytes()),
+            RemoteModules::integrity(b"return 'tampered'")
+        )),
+        "{err}"
+    );
+
+    // Nothing is fetched for a module that isn't pinned
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let err = require(serve(MODULE, &fetches)).unwrap_err();
+    assert!(err.contains(&format!("no pinned module '{URL}'")), "{err}");
+    assert_eq!(fetches.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn tampered_cache_entries_are_fetched_again() {
+    let dir = std::env::temp_dir().join(format!("mochi-remote-cache-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let pinned = |fetches: &Arc<AtomicUsize>| {
+        let mut remote = serve(MODULE, fetches);
+        remote
+            .pin(URL, &RemoteModules::integrity(MODULE.as_bytes()))
+            .unwrap()
+            .cache_dir(&dir);
+        remote
+    };
+
+    let fetches = Arc::new(AtomicUsize::new(0));
+    assert_eq!(require(pinned(&fetches)).unwrap(), "hello from greet");
+    assert_eq!(require(pinned(&fetches)).unwrap(), "hello from greet");
+    // The second require was served from the cache
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+    let cached = cached_files(&dir);
+    assert_eq!(cached.len(), 1);
+    fs::write(&cached[0], "return 'tampered'").unwrap();
+    assert_eq!(require(pinned(&fetches)).unwrap(), "hello from greet");
+    assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    // and the fetched copy replaced the tampered one
+    assert_eq!(fs::read_to_string(&cached[0]).unwrap(), MODULE);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn cached_files(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect()
+}
@@ -0,0 +1,146 @@
+// Code inserted into compiled protos with CodeRewriter, which must leave the
+// behavior and debug info of the original instructions as they were
+use mochi_lua::{
+    gc::GcContext,
+    lua::Lua,
+    runtime::{rewrite_protos, CodeRewriter, Instruction, OpCode},
+    types::{DetachedValue, LuaClosureProto, Value},
+};
+
+fn abc(op: OpCode, a: usize, b: usize, c: usize) -> Instruction {
+    Instruction(op as u32 | (a as u32) << 7 | (b as u32) << 16 | (c as u32) << 24)
+}
+
+// Loads `source`, rewrites its main proto with `f` and runs it
+fn run<F>(source: &str, f: F) -> (Result<Vec<DetachedValue>, String>, Lua)
+where
+    F: for<'gc> FnOnce(&'gc GcContext, &mut LuaClosureProto<'gc>),
+{
+    let mut lua = Lua::new();
+    lua.with(|gc, vm| {
+        let mut proto = mochi_lua::load(gc, source, "=chunk").unwrap();
+        f(gc, &mut proto);
+        let closure = gc.allocate(vm.load_proto(gc, proto));
+        vm.globals()
+            .borrow_mut(gc)
+            .set_field(gc.allocate_string(&b"chunk"[..]), Value::from(closure));
+    });
+    let result = lua
+        .load("return chunk()")
+        .eval_multi()
+        .map_err(|err| err.to_string().lines().next().unwrap().to_owned());
+    (result, lua)
+}
+
+// Where jumps and fallthrough land, as the code before rewriting sees it
+fn jump_targets(code: &[Instruction]) -> Vec<usize> {
+    let mut targets = Vec::new();
+    for (pc, insn) in code.iter().enumerate() {
+        let next = pc as isize + 1;
+        match insn.opcode() {
+            OpCode::Jmp => targets.push(next + insn.sj() as isize),
+            OpCode::ForLoop => targets.push(next - insn.bx() as isize),
+            _ => continue,
+        }
+    }
+    let mut targets: Vec<_> = targets.into_iter().map(|pc| pc as usize).collect();
+    targets.sort();
+    targets.dedup();
+    targets
+}
+
+#[test]
+fn insertions_run_whenever_their_instruction_is_reached() {
+    let source = "hits = 0
+        local s = 0
+        for i = 1, 10 do
+            s = s + i
+        end
+        local n = 0
+        while n < 5 do
+            n = n + 1
+        end
+        return s, n, hits";
+    let (result, _) = run(source, |_, proto| {
+        let hits = proto
+            .constants
+            .iter()
+            .position(|k| matches!(k, Value::String(s) if s.as_bytes() == b"hits"))
+            .unwrap();
+        // hits = hits + 1, in a register of its own
+        let r = proto.max_stack_size as usize;
+        let increment = [
+            abc(OpCode::GetTabUp, r, 0, hits),
+            abc(OpCode::AddI, r, r, 1 + 127),
+            abc(OpCode::MmBinI, r, 1 + 127, 6),
+            abc(OpCode::SetTabUp, 0, hits, r),
+        ];
+
+        let mut rewriter = CodeRewriter::new();
+        rewriter.ensure_stack_size(r as u8 + 1);
+        let targets = jump_targets(&proto.code);
+        // The first instruction of the for loop's body, the test of the
+        // while loop and where the while loop exits to
+        assert_eq!(targets.len(), 3);
+        for pc in &targets[..2] {
+            assert!(CodeRewriter::can_insert_before(&proto.code, *pc));
+            rewriter.insert_before(*pc, increment);
+        }
+        rewriter.apply(proto).unwrap();
+    });
+    use DetachedValue::Integer;
+    // 10 iterations of the for loop, and the while loop's test run 6 times
+    assert_eq!(result.unwrap(), [Integer(55), Integer(5), Integer(10 + 6)]);
+}
+
+#[test]
+fn lines_and_locals_stay_with_their_instructions() {
+    let source = "local last
+        local errors = {}
+        for i = 1, 2 do
+            local t
+            errors[i] = select(2, pcall(function() return t.x end))
+        end
+        errors[3] = select(2, pcall(function()
+            local v
+            return v.y
+        end))
+        result = table.concat(errors, '|')
+        return last.z";
+    let run = |rewrite: bool| {
+        let (result, mut lua) = run(source, |gc, proto| {
+            if !rewrite {
+                return;
+            }
+            // A no-op before every instruction that can have one, in every
+            // function
+            rewrite_protos(gc, proto, &mut |proto, rewriter| {
+                let r = proto.max_stack_size as usize;
+                rewriter.ensure_stack_size(r as u8 + 1);
+                for pc in 0..proto.code.len() {
+                    if CodeRewriter::can_insert_before(&proto.code, pc) {
+                        rewriter.insert_before(pc, [abc(OpCode::Move, r, r, 0)]);
+                    }
+                }
+            })
+            .unwrap();
+        });
+        let errors: String = lua.globals().get("result").unwrap();
+        (result, errors)
+    };
+
+    let (result, errors) = run(true);
+    assert_eq!(
+        result.clone().unwrap_err(),
+        "chunk:12: attempt to index a nil value (local 'last')"
+    );
+    assert_eq!(
+        errors.split('|').collect::<Vec<_>>(),
+        [
+            "chunk:5: attempt to index a nil value (upvalue 't')",
+            "chunk:5: attempt to index a nil value (upvalue 't')",
+            "chunk:9: attempt to index a nil value (local 'v')",
+        ]
+    );
+    assert_eq!(run(false), (result, errors));
+}
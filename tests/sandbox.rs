@@ -0,0 +1,196 @@
+// Known ways for untrusted scripts to get out of a sandbox, each of which
+// should fail under the Sandbox preset
+use mochi_lua::{
+    lua::Lua,
+    sandbox::{Sandbox, StdlibBuilder},
+};
+
+fn sandbox_limits() -> Sandbox {
+    Sandbox {
+        stdlib: StdlibBuilder::safe(),
+        fuel: Some(5_000_000),
+        memory_limit: Some(8 << 20),
+        output_limit: Some(1024),
+    }
+}
+
+fn sandbox() -> Lua {
+    Lua::sandboxed(&sandbox_limits())
+}
+
+fn assert_fails_with(lua: &mut Lua, source: &str, message: &str) {
+    match lua.load(source).exec() {
+        Ok(()) => panic!("{source:?} didn't fail"),
+        Err(err) => assert!(
+            err.to_string().contains(message),
+            "{source:?} failed with {err}, expected {message:?}"
+        ),
+    }
+}
+
+#[test]
+fn unsafe_functions_are_removed() {
+    let mut lua = sandbox();
+    for name in [
+        "io",
+        "os",
+        "package",
+        "require",
+        "debug",
+        "dofile",
+        "loadfile",
+        "collectgarbage",
+        "string.dump",
+        "('').dump",
+    ] {
+        let is_nil: bool = lua.load(format!("{name} == nil")).eval().unwrap();
+        assert!(is_nil, "{name} is available");
+    }
+}
+
+#[test]
+fn binary_chunks_are_rejected() {
+    let mut lua = sandbox();
+    for mode in ["nil", "'b'", "'bt'"] {
+        let message: String = lua
+            .load(format!(
+                "local f, err = load('\\27Lua\\84\\0\\25\\147\\r\\n\\26\\n', nil, {mode}) return err"
+            ))
+            .eval()
+            .unwrap();
+        assert!(
+            message.contains("attempt to load a binary chunk"),
+            "{message}"
+        );
+    }
+    let result: i64 = lua.load("return load('return 1 + 1')()").eval().unwrap();
+    assert_eq!(result, 2);
+}
+
+#[test]
+fn loaded_chunks_only_see_the_sandbox() {
+    let mut lua = sandbox();
+    let escaped: bool = lua
+        .load("return load('return io or os or require')() ~= nil")
+        .eval()
+        .unwrap();
+    assert!(!escaped);
+    let escaped: bool = lua
+        .load("return load('return io', nil, 't', {io = false})() ~= false")
+        .eval()
+        .unwrap();
+    assert!(!escaped);
+}
+
+#[test]
+fn string_metatable_is_hidden() {
+    let mut lua = sandbox();
+    let metatable: bool = lua.load("getmetatable('')").eval().unwrap();
+    assert!(!metatable);
+    assert_fails_with(&mut lua, "getmetatable('').__index = {}", "index a boolean");
+    assert_fails_with(&mut lua, "setmetatable('', {})", "table expected");
+    // Methods still work through the hidden metatable
+    let upper: String = lua.load("('abc'):upper()").eval().unwrap();
+    assert_eq!(upper, "ABC");
+}
+
+#[test]
+fn read_only_globals_cant_be_assigned() {
+    let mut lua = Lua::sandboxed(&Sandbox {
+        stdlib: StdlibBuilder::safe().read_only_globals(true),
+        ..sandbox_limits()
+    });
+    assert_fails_with(&mut lua, "print = nil", "read-only global 'print'");
+    assert_fails_with(&mut lua, "x = 1", "read-only global 'x'");
+    assert_fails_with(&mut lua, "_G.print = nil", "read-only global 'print'");
+    assert_fails_with(&mut lua, "setmetatable(_G, nil)", "protected metatable");
+    let count: i64 = lua
+        .load("local n = 0 for k in pairs(_G) do n = n + 1 end return n")
+        .eval()
+        .unwrap();
+    assert!(count > 10, "{count}");
+    // Libraries are still usable, and locals are unaffected
+    let len: i64 = lua
+        .load("local s = 'abc' return string.len(s)")
+        .eval()
+        .unwrap();
+    assert_eq!(len, 3);
+}
+
+#[test]
+fn infinite_loops_run_out_of_fuel() {
+    assert_fails_with(
+        &mut sandbox(),
+        "while true do end",
+        "execution budget exceeded",
+    );
+    assert_fails_with(
+        &mut sandbox(),
+        "while true do pcall(function() while true do end end) end",
+        "execution budget exceeded",
+    );
+}
+
+#[test]
+fn deep_recursion_overflows_the_stack() {
+    // The compiler doesn't emit tail calls, so this recurses
+    assert_fails_with(
+        &mut sandbox(),
+        "local function f() return f() end f()",
+        "stack overflow",
+    );
+}
+
+#[test]
+fn coroutines_run_out_of_fuel() {
+    assert_fails_with(
+        &mut sandbox(),
+        "local co = coroutine.wrap(function() while true do end end) co()",
+        "execution budget exceeded",
+    );
+    assert_fails_with(
+        &mut sandbox(),
+        "local co = coroutine.wrap(function() while true do coroutine.yield() end end)
+         while true do co() end",
+        "execution budget exceeded",
+    );
+    // The error stays caught inside the coroutine, but the fuel is gone
+    assert_fails_with(
+        &mut sandbox(),
+        "local co = coroutine.create(function() while true do end end)
+         local ok = coroutine.resume(co)
+         while true do end",
+        "execution budget exceeded",
+    );
+}
+
+#[test]
+fn memory_is_limited() {
+    assert_fails_with(
+        &mut sandbox(),
+        "local t = {} while true do t[#t + 1] = {} end",
+        "not enough memory",
+    );
+    assert_fails_with(
+        &mut sandbox(),
+        "local s = 'x' while true do s = s .. s end",
+        "not enough memory",
+    );
+    assert_fails_with(
+        &mut sandbox(),
+        "local t = {} while true do t[#t + 1] = coroutine.create(print) end",
+        "not enough memory",
+    );
+    // Garbage doesn't count towards the limit
+    let mut lua = sandbox();
+    lua.load("for i = 1, 100000 do local t = {i, tostring(i)} end")
+        .exec()
+        .unwrap();
+}
+
+#[test]
+fn output_is_truncated() {
+    let mut lua = sandbox();
+    let len: i64 = lua.load("#tostring(('x'):rep(1e6))").eval().unwrap();
+    assert!(len < 1100, "{len}");
+}
@@ -0,0 +1,59 @@
+// Heaps moved between threads, which needs the "send" feature
+#![cfg(feature = "send")]
+use mochi_lua::{gc::GcHeap, lua::Lua};
+use std::thread;
+
+#[test]
+fn lua_moves_to_another_thread() {
+    let mut lua = Lua::new();
+    lua.register("double", |n: i64| Ok(n * 2));
+    lua.load("n = double(1)").exec().unwrap();
+
+    let mut lua = thread::spawn(move || {
+        lua.load("n = double(n) collectgarbage()").exec().unwrap();
+        lua
+    })
+    .join()
+    .unwrap();
+    let n: i64 = lua.globals().get("n").unwrap();
+    assert_eq!(n, 4);
+}
+
+#[test]
+fn registry_keys_stay_with_the_host() {
+    let mut heap = GcHeap::new();
+    let key =
+        heap.with(|gc, _| gc.create_registry_value(gc.allocate_string(b"x".as_slice()).into()));
+
+    let worker = thread::spawn(move || {
+        heap.full_gc();
+        heap
+    });
+    let mut heap = worker.join().unwrap();
+    let value = heap.with(|gc, _| gc.registry_value(&key).coerce_to_string().unwrap().to_vec());
+    assert_eq!(value, b"x");
+
+    drop(key);
+    thread::spawn(move || heap.full_gc()).join().unwrap();
+}
+
+#[test]
+fn heaps_run_on_a_pool_of_workers() {
+    let workers: Vec<_> = (0..4)
+        .map(|i| {
+            let mut lua = Lua::new();
+            lua.globals().set("i", i);
+            thread::spawn(move || {
+                lua.load("local s = 0 for j = 1, 1000 do s = s + i * j end result = s")
+                    .exec()
+                    .unwrap();
+                lua
+            })
+        })
+        .collect();
+    for (i, worker) in workers.into_iter().enumerate() {
+        let mut lua = worker.join().unwrap();
+        let result: i64 = lua.globals().get("result").unwrap();
+        assert_eq!(result, i as i64 * 500500);
+    }
+}
@@ -0,0 +1,80 @@
+// Traversals of tables with TableCursor, which must visit what `next` visits
+// and fail once the table is resized under them
+use mochi_lua::types::{Table, TableError, Value};
+
+fn table(len: i64) -> Table<'static> {
+    let mut table = Table::new();
+    for i in 1..=len {
+        table.set(i, i * 10).unwrap();
+    }
+    for i in 0..len {
+        table.set(Value::Number(i as f64 + 0.5), true).unwrap();
+    }
+    table
+}
+
+fn next_keys<'gc>(table: &Table<'gc>) -> Vec<Value<'gc>> {
+    let mut keys = Vec::new();
+    let mut key = Value::Nil;
+    while let Some((k, _)) = table.next(key).unwrap() {
+        keys.push(k);
+        key = k;
+    }
+    keys
+}
+
+#[test]
+fn cursors_visit_entries_in_next_order() {
+    let table = table(8);
+    let mut cursor = table.cursor();
+    let mut keys = Vec::new();
+    while let Some((key, value)) = table.next_at(&mut cursor).unwrap() {
+        assert_eq!(table.get(key), value);
+        keys.push(key);
+    }
+    assert_eq!(keys.len(), 16);
+    assert_eq!(keys, next_keys(&table));
+    // and stay at the end
+    assert!(table.next_at(&mut cursor).unwrap().is_none());
+}
+
+#[test]
+fn entries_can_change_during_traversal() {
+    let mut table = table(8);
+    let mut cursor = table.cursor();
+    let mut visited = 0;
+    while let Some((key, _)) = table.next_at(&mut cursor).unwrap() {
+        visited += 1;
+        // Clearing and overwriting existing keys doesn't move entries
+        match key {
+            Value::Integer(i) if i % 2 == 0 => table.set(key, Value::Nil).unwrap(),
+            _ => table.set(key, false).unwrap(),
+        }
+    }
+    assert_eq!(visited, 16);
+    assert_eq!(next_keys(&table).len(), 12);
+}
+
+#[test]
+fn cursors_fail_after_a_resize() {
+    let mut table = table(4);
+    let mut cursor = table.cursor();
+    table.next_at(&mut cursor).unwrap().unwrap();
+
+    // New keys fill free buckets, then rehash
+    let mut key = 100;
+    let err = loop {
+        table.set(key, key).unwrap();
+        key += 1;
+        match table.next_at(&mut cursor) {
+            Ok(Some(_)) => continue,
+            Ok(None) => panic!("traversal ended without noticing the resize"),
+            Err(err) => break err,
+        }
+    };
+    assert!(matches!(err, TableError::ResizedDuringTraversal));
+    assert_eq!(err.to_string(), "table resized during traversal");
+    // until traversed again from the start
+    let mut cursor = table.cursor();
+    assert!(table.next_at(&mut cursor).unwrap().is_some());
+}
@@ -0,0 +1,60 @@
+// The length of tables is a border, whichever way their entries were set
+use mochi_lua::{
+    lua::Lua,
+    types::{Integer, Table, Value},
+};
+
+fn assert_border(table: &Table, step: usize) {
+    let n = table.lua_len();
+    assert!(
+        n == 0 || !table.get_integer_key(n).is_nil(),
+        "step {step}: t[{n}] is nil"
+    );
+    assert!(
+        table.get_integer_key(n + 1).is_nil(),
+        "step {step}: t[{}] is not nil",
+        n + 1
+    );
+}
+
+#[test]
+fn length_is_a_border() {
+    let mut table = Table::new();
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    for step in 0..20_000 {
+        // xorshift, to keep the sequence reproducible
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let len = table.lua_len();
+        let (key, value) = match state % 8 {
+            0..=2 => (len + 1, Value::Integer(1)),
+            3 => (len, Value::Nil),
+            4 => ((state >> 8) as Integer % (len + 2) + 1, Value::Nil),
+            _ => (
+                (state >> 8) as Integer % (len * 2 + 2) + 1,
+                Value::Integer(1),
+            ),
+        };
+        table.set(key, value).unwrap();
+        assert_border(&table, step);
+    }
+}
+
+#[test]
+fn length_consumers_agree() {
+    let mut lua = Lua::new();
+    let result: String = lua
+        .load(
+            "local t = {}
+            for i = 1, 100 do t[#t + 1] = i end
+            for i = 1, 40 do t[#t] = nil end
+            table.insert(t, 'last')
+            local count = 0
+            for _ in ipairs(t) do count = count + 1 end
+            return #t .. ' ' .. select('#', table.unpack(t)) .. ' ' .. count .. ' ' .. t[#t]",
+        )
+        .eval()
+        .unwrap();
+    assert_eq!(result, "61 61 61 last");
+}
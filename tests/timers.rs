@@ -0,0 +1,104 @@
+// mochi.sleep and mochi.timer, which pause the runtime and call back into it
+// without blocking the host thread
+use mochi_lua::lua::Lua;
+use std::time::{Duration, Instant};
+
+fn start(lua: &mut Lua, source: &'static str) {
+    lua.runtime()
+        .start(move |gc, vm| {
+            let closure = vm.borrow().load(gc, source, "=chunk")?;
+            Ok(gc.allocate(closure).into())
+        })
+        .unwrap();
+}
+
+#[test]
+fn sleep_pauses_the_runtime() {
+    let mut lua = Lua::new();
+    start(&mut lua, "done = false mochi.sleep(0.05) done = true");
+    let started = Instant::now();
+    let slice = lua.runtime().run_for(1000).unwrap();
+    assert!(!slice.finished);
+    // run_for returns right away while the runtime is asleep
+    let wake_time = lua.runtime().wake_time().unwrap();
+    assert!(wake_time >= started + Duration::from_millis(40));
+    let slice = lua.runtime().run_for(1000).unwrap();
+    assert_eq!(slice.instructions, 0);
+    assert!(!slice.finished);
+    assert!(started.elapsed() < Duration::from_millis(40));
+
+    std::thread::sleep(wake_time.saturating_duration_since(Instant::now()));
+    assert!(lua.runtime().run_for(1000).unwrap().finished);
+    assert_eq!(lua.runtime().wake_time(), None);
+    assert!(lua.load("return done").eval::<bool>().unwrap());
+
+    // Chunks run to completion wait for the sleep
+    let started = Instant::now();
+    lua.load("mochi.sleep(0.02)").exec().unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(20));
+}
+
+#[test]
+fn timers_repeat_until_their_callback_returns_false() {
+    let mut lua = Lua::new();
+    lua.load("n = 0 mochi.timer(0.01, function() n = n + 1 return n < 3 end)")
+        .exec()
+        .unwrap();
+    assert!(lua.runtime().next_timer().is_some());
+    // Nothing is due yet
+    assert_eq!(lua.runtime().run_timers().unwrap(), 0);
+
+    let started = Instant::now();
+    lua.runtime().run_remaining_timers().unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(20));
+    assert_eq!(lua.runtime().next_timer(), None);
+    assert_eq!(lua.load("return n").eval::<i64>().unwrap(), 3);
+}
+
+#[test]
+fn timers_run_while_sleeping() {
+    let mut lua = Lua::new();
+    let ticks: i64 = lua
+        .load(
+            "local ticks = 0
+            mochi.timer(0.01, function() ticks = ticks + 1 return ticks < 2 end)
+            mochi.sleep(0.05)
+            return ticks",
+        )
+        .eval()
+        .unwrap();
+    assert_eq!(ticks, 2);
+}
+
+#[test]
+fn invalid_sleeps_and_timers() {
+    let mut lua = Lua::new();
+    for (source, message) in [
+        ("mochi.sleep(-1)", "bad argument #1 (invalid duration)"),
+        (
+            "mochi.timer(0, print)",
+            "bad argument #1 (interval must be positive)",
+        ),
+    ] {
+        let err = lua.load(source).exec().unwrap_err().to_string();
+        assert!(err.contains(message), "{source}: {err}");
+    }
+
+    // A failing timer is removed, and its error returned. Callbacks can't
+    // sleep, as they run on top of the runtime.
+    for (callback, message) in [
+        ("error('tick failed')", "tick failed"),
+        (
+            "mochi.sleep(1)",
+            "attempt to sleep across a C-call boundary",
+        ),
+    ] {
+        lua.load(format!("mochi.timer(0.001, function() {callback} end)"))
+            .exec()
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        let err = lua.runtime().run_timers().unwrap_err().to_string();
+        assert!(err.contains(message), "{err}");
+        assert_eq!(lua.runtime().next_timer(), None);
+    }
+}
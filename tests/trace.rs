@@ -0,0 +1,76 @@
+// Instructions logged through tracing, which needs the "trace" feature
+#![cfg(feature = "trace")]
+use mochi_lua::runtime::Runtime;
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Metadata, Subscriber,
+};
+
+#[derive(Default)]
+struct Events(Arc<Mutex<Vec<String>>>);
+
+struct Line(String);
+
+impl Visit for Line {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.push_str(&format!(" {}={value:?}", field.name()));
+    }
+}
+
+impl Subscriber for Events {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.target().starts_with("mochi::vm")
+    }
+
+    fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+        span::Id::from_u64(1)
+    }
+
+    fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut line = Line(event.metadata().target().to_owned());
+        event.record(&mut line);
+        self.0.lock().unwrap().push(line.0);
+    }
+
+    fn enter(&self, _: &span::Id) {}
+
+    fn exit(&self, _: &span::Id) {}
+}
+
+#[test]
+fn instructions_are_logged() {
+    let events = Events::default();
+    let lines = events.0.clone();
+    tracing::subscriber::with_default(events, || {
+        let mut runtime = Runtime::new();
+        runtime
+            .execute(|gc, vm| {
+                let closure = vm.borrow().load(gc, "local x = 41 return x + 1", "=test")?;
+                Ok(gc.allocate(closure).into())
+            })
+            .unwrap();
+    });
+
+    let lines = lines.lock().unwrap();
+    assert_eq!(
+        lines[..3],
+        [
+            "mochi::vm::VARARGPREP message=VARARGPREP pc=0 operands=0 ra=nil registers=3",
+            "mochi::vm::LOADI message=LOADI pc=1 operands=0 41 ra=nil registers=3",
+            "mochi::vm::ADDI message=ADDI pc=2 operands=1 0 1 ra=nil registers=3",
+        ]
+    );
+    // MMBINI is skipped when the addition succeeds
+    assert_eq!(
+        lines[3],
+        "mochi::vm::RETURN message=RETURN pc=4 operands=1 2 1 ra=42 registers=3"
+    );
+}
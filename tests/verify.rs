@@ -0,0 +1,175 @@
+// Protos with code edited to break one of the invariants that verify checks,
+// which must be rejected with the error for it
+use mochi_lua::{
+    gc::GcHeap,
+    runtime::{self, Instruction, OpCode, VerifyError},
+    types::{LuaClosureProto, UpvalueDescription},
+};
+
+const OFFSET_SJ: i32 = (1 << 24) - 1;
+
+fn abc(op: OpCode, a: usize, b: usize, c: usize) -> Instruction {
+    Instruction(op as u32 | (a as u32) << 7 | (b as u32) << 16 | (c as u32) << 24)
+}
+
+fn abx(op: OpCode, a: usize, bx: usize) -> Instruction {
+    Instruction(op as u32 | (a as u32) << 7 | (bx as u32) << 15)
+}
+
+fn sj(op: OpCode, offset: i32) -> Instruction {
+    Instruction(op as u32 | ((offset + OFFSET_SJ) as u32) << 7)
+}
+
+// Compiles `source`, edits its main proto with `f` and verifies it
+fn verify<F>(source: &str, f: F) -> Result<(), VerifyError>
+where
+    F: FnOnce(&mut LuaClosureProto),
+{
+    let mut heap = GcHeap::new();
+    heap.with(|gc, _| {
+        let mut proto = mochi_lua::load(gc, source, "=chunk").unwrap();
+        runtime::verify(&proto).unwrap();
+        f(&mut proto);
+        runtime::verify(&proto)
+    })
+}
+
+fn verify_code(code: Vec<Instruction>) -> Result<(), VerifyError> {
+    verify(
+        "local a, b = ...; return function() return a end",
+        |proto| proto.code = code.into(),
+    )
+}
+
+#[test]
+fn out_of_range_operands() {
+    let result = verify("local a, b = ...", |proto| {
+        let r = proto.max_stack_size as usize;
+        proto.code = [abc(OpCode::Move, r, 0, 0), abc(OpCode::Return0, 0, 0, 0)].into();
+    });
+    assert!(matches!(result, Err(VerifyError::RegisterOutOfRange(0))));
+
+    let result = verify("return 'a', 'b'", |proto| {
+        let k = proto.constants.len();
+        proto.code = [abx(OpCode::LoadK, 0, k), abc(OpCode::Return0, 0, 0, 0)].into();
+    });
+    assert!(matches!(result, Err(VerifyError::ConstantOutOfRange(0))));
+
+    let result = verify("return x", |proto| {
+        let u = proto.upvalues.len();
+        proto.code = [
+            abc(OpCode::GetUpval, 0, u, 0),
+            abc(OpCode::Return0, 0, 0, 0),
+        ]
+        .into();
+    });
+    assert!(matches!(result, Err(VerifyError::UpvalueOutOfRange(0))));
+
+    let result = verify_code(vec![
+        abx(OpCode::Closure, 0, 1),
+        abc(OpCode::Return0, 0, 0, 0),
+    ]);
+    assert!(matches!(result, Err(VerifyError::ProtoOutOfRange(0))));
+}
+
+#[test]
+fn jumps_must_land_on_an_instruction() {
+    // Into the EXTRAARG of NEWTABLE
+    let result = verify_code(vec![
+        sj(OpCode::Jmp, 1),
+        abc(OpCode::NewTable, 0, 0, 0),
+        abx(OpCode::ExtraArg, 0, 0),
+        abc(OpCode::Return0, 0, 0, 0),
+    ]);
+    assert!(matches!(result, Err(VerifyError::JumpOutOfRange(0))));
+
+    for offset in [1, 5, -3] {
+        let result = verify_code(vec![
+            abc(OpCode::Move, 0, 1, 0),
+            sj(OpCode::Jmp, offset),
+            abc(OpCode::Return0, 0, 0, 0),
+        ]);
+        assert!(
+            matches!(result, Err(VerifyError::JumpOutOfRange(1))),
+            "{offset}: {result:?}"
+        );
+    }
+    // The last instruction can be jumped to
+    let result = verify_code(vec![sj(OpCode::Jmp, 0), abc(OpCode::Return0, 0, 0, 0)]);
+    assert!(result.is_ok(), "{result:?}");
+}
+
+#[test]
+fn tests_must_be_followed_by_a_jump() {
+    let result = verify_code(vec![
+        abc(OpCode::Test, 0, 0, 0),
+        abc(OpCode::Move, 0, 1, 0),
+        abc(OpCode::Return0, 0, 0, 0),
+    ]);
+    assert!(matches!(result, Err(VerifyError::MissingJump(0))));
+
+    let result = verify_code(vec![
+        abc(OpCode::Move, 0, 1, 0),
+        abc(OpCode::Lt, 0, 1, 0),
+        abc(OpCode::Return0, 0, 0, 0),
+    ]);
+    assert!(matches!(result, Err(VerifyError::MissingJump(1))));
+}
+
+#[test]
+fn metamethod_fallbacks_must_follow_arithmetic() {
+    let result = verify_code(vec![
+        abc(OpCode::MmBin, 0, 1, 6),
+        abc(OpCode::Return0, 0, 0, 0),
+    ]);
+    assert!(matches!(result, Err(VerifyError::MisplacedInstruction(0))));
+
+    let result = verify_code(vec![
+        abc(OpCode::Move, 0, 1, 0),
+        abc(OpCode::MmBin, 0, 1, 6),
+        abc(OpCode::Return0, 0, 0, 0),
+    ]);
+    assert!(matches!(result, Err(VerifyError::MisplacedInstruction(1))));
+
+    let result = verify_code(vec![
+        abc(OpCode::Add, 0, 0, 1),
+        abc(OpCode::MmBin, 0, 1, 6),
+        abc(OpCode::Return0, 0, 0, 0),
+    ]);
+    assert!(result.is_ok(), "{result:?}");
+}
+
+#[test]
+fn code_must_end_with_a_return() {
+    let result = verify_code(vec![abc(OpCode::Move, 0, 1, 0)]);
+    assert!(matches!(result, Err(VerifyError::MissingReturn)));
+
+    let result = verify_code(Vec::new());
+    assert!(matches!(result, Err(VerifyError::EmptyCode)));
+}
+
+#[test]
+fn upvalues_must_refer_to_the_enclosing_function() {
+    // The closure captures b, above the registers left in the main function
+    let source = "local a, b = ...; return function() return b end";
+    let result = verify(source, |proto| {
+        assert!(matches!(
+            proto.protos[0].upvalues[0],
+            UpvalueDescription::Register(_)
+        ));
+        proto.code = [abc(OpCode::Return0, 0, 0, 0)].into();
+        proto.max_stack_size = 1;
+    });
+    assert!(matches!(result, Err(VerifyError::InvalidUpvalue(0))));
+
+    // The closure captures _ENV from the main function, which is left without
+    // upvalues
+    let result = verify("return function() return x end", |proto| {
+        assert!(matches!(
+            proto.protos[0].upvalues[0],
+            UpvalueDescription::Upvalue(_)
+        ));
+        proto.upvalues = Box::new([]);
+    });
+    assert!(matches!(result, Err(VerifyError::InvalidUpvalue(0))));
+}
@@ -0,0 +1,62 @@
+// The heap verifier, which needs the "verify-heap" feature
+#![cfg(feature = "verify-heap")]
+use mochi_lua::{gc::GcHeap, runtime::Runtime, types::Table};
+
+#[test]
+fn collecting_keeps_invariants() {
+    let mut runtime = Runtime::new();
+    runtime.heap().with(|gc, vm| {
+        let mut vm = vm.borrow_mut(gc);
+        vm.load_stdlib(gc);
+        gc.set_step_size(0);
+    });
+    runtime
+        .execute(|gc, vm| {
+            let closure = vm.borrow().load(
+                gc,
+                "local keep = {}
+                for i = 1, 2000 do
+                    local t = {i, tostring(i), {nested = i}}
+                    local co = coroutine.wrap(function(x)
+                        local y = coroutine.yield(x)
+                        return {y, t}
+                    end)
+                    co(i)
+                    keep[i % 31] = {t, co, setmetatable({}, {__index = t})}
+                    collectgarbage('step')
+                end",
+                "=test",
+            )?;
+            Ok(gc.allocate(closure).into())
+        })
+        .unwrap();
+    runtime.heap().full_gc();
+    runtime.heap().verify();
+}
+
+#[test]
+#[should_panic(expected = "heap verification failed in the Propagate phase: \
+                           mochi_lua::types::table::Table<'_> (black) refers to \
+                           mochi_lua::types::table::Table<'_> (white)")]
+fn missing_write_barrier_is_caught() {
+    let mut heap = GcHeap::new();
+    let table = heap.with(|gc, vm| {
+        let table = gc.allocate_cell(Table::new());
+        vm.borrow()
+            .registry()
+            .borrow_mut(gc)
+            .set_field(gc.allocate_string(b"table".as_slice()), table);
+        gc.set_step_size(0);
+        table.as_ptr() as usize
+    });
+    heap.full_gc();
+    for _ in 0..1000 {
+        heap.with(|gc, _| {
+            // Writes to the table without borrow_mut, which would have made
+            // it gray again
+            let table = unsafe { &mut *(table as *mut Table) };
+            table.set_integer_key(1, gc.allocate_cell(Table::new()));
+        });
+        heap.step();
+    }
+}